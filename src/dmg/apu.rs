@@ -0,0 +1,842 @@
+// Audio Processing Unit.
+// Four channels: two square waves (the first one with a frequency sweep), a 32 sample wave
+// channel and a noise generator. A frame sequencer running at 512Hz clocks their length
+// counters, volume envelopes and the sweep.
+// Registers and channel state are always kept up to date, but samples are only mixed while
+// something is listening (see set_sample_rate).
+// See PanDocs: https://gbdev.io/pandocs/Audio.html
+
+use std::mem;
+
+use super::state::{StateError, StateReader, StateWriter};
+use super::config::{AccuracyLevel, AudioOutput};
+use super::console::Model;
+use super::apu_log::{ApuLog, ApuWrite};
+#[cfg(feature = "fixed-point-audio")]
+pub use super::apu_fixed::{FixedSample as AudioSample, Mixer};
+
+pub const CPU_CLOCK: u32 = 4_194_304;
+const FRAME_SEQUENCER_PERIOD: u32 = CPU_CLOCK / 512;
+
+// Registers live in regs, indexed from 0xFF10
+const NR10: usize = 0x00;
+const NR30: usize = 0x0A;
+const NR50: usize = 0x14;
+const NR51: usize = 0x15;
+const NR52: usize = 0x16;
+const WAVE_RAM: usize = 0x20;
+const REGS_SIZE: usize = 0x30;
+
+const DUTY: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+// Wave channel volume codes as right shifts: mute, 100%, 50%, 25%
+const WAVE_SHIFTS: [u8; 4] = [4, 0, 1, 2];
+
+// How much charge the output capacitors keep per cycle (PanDocs). The CGB's let go faster.
+#[cfg(not(feature = "fixed-point-audio"))]
+const DMG_CHARGE_FACTOR: f32 = 0.999_958;
+#[cfg(not(feature = "fixed-point-audio"))]
+const CGB_CHARGE_FACTOR: f32 = 0.998_943;
+// A DAC switched off drifts to 0 instead of dropping there, over a couple of milliseconds
+#[cfg(not(feature = "fixed-point-audio"))]
+const DAC_FADE_FACTOR: f32 = 0.999_9;
+
+// Bits that always read back as 1, for 0xFF10 - 0xFF2F
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 - NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21 - NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 - NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41 - NR44
+    0x00, 0x00, 0x70,             // NR50 - NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+];
+
+// One output sample. Values are roughly -1.0 to 1.0. With the fixed-point-audio feature, samples
+// are integers instead, see apu_fixed.rs.
+#[cfg(not(feature = "fixed-point-audio"))]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct AudioSample {
+    pub left: f32,
+    pub right: f32,
+    // Each channel on its own, before panning and master volume
+    pub channels: [f32; 4],
+}
+
+// What a channel is playing, for visualizers, see Apu::snapshot
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ChannelSnapshot {
+    pub playing: bool,          // on, with its DAC on
+    pub frequency: f32,         // of the tone in Hz. For noise, LFSR steps per second.
+    pub volume: u8,             // 0 - 15. The wave channel's level comes out as 15, 7 or 3.
+    pub duty: Option<u8>,       // squares: steps out of 8 that are high
+    pub lfsr_width: Option<u8>, // noise: 15 or 7 bits
+    pub left: bool,             // panned left, see NR51
+    pub right: bool,
+    pub output: u8,             // DAC input right now, 0 - 15
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ApuSnapshot {
+    pub powered: bool,
+    pub channels: [ChannelSnapshot; 4],
+    pub wave_ram: [u8; 16],
+}
+
+#[derive(Debug, Default, Clone)]
+struct Channel {
+    enabled: bool,
+    length: u16,      // counts down to 0, then the channel turns off
+    timer: i32,       // cycles until the next duty / wave / noise step
+    position: u8,     // duty step for squares, sample index for wave
+    volume: u8,       // current envelope volume
+    envelope_timer: u8,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Sweep {
+    enabled: bool,
+    timer: u8,
+    shadow: u16,
+}
+
+// What the mixer gets from the APU for one sample
+#[derive(Debug, Copy, Clone)]
+pub struct MixInput {
+    pub dacs: [Option<u8>; 4], // each channel's DAC input, 0 - 15, None with the DAC off
+    pub panning: u8,           // NR51
+    pub volume: u8,            // NR50
+    pub output: AudioOutput,
+    pub filter: bool,          // take the DC offset out, see AccuracyLevel
+}
+
+// Removes the DC offset the DACs leave behind, like the capacitor on real hardware
+#[cfg(not(feature = "fixed-point-audio"))]
+#[derive(Debug, Default, Clone)]
+struct HighPass {
+    capacitor: f32,
+}
+
+#[cfg(not(feature = "fixed-point-audio"))]
+impl HighPass {
+    fn filter(&mut self, input: f32, charge: f32) -> f32 {
+        let out = input - self.capacitor;
+        self.capacitor = input - out * charge;
+        out
+    }
+}
+
+// Turns DAC inputs into samples. Without the fixed-point-audio feature, in floating point.
+#[cfg(not(feature = "fixed-point-audio"))]
+#[derive(Debug, Default, Clone)]
+pub struct Mixer {
+    charge: f32,
+    fade: f32,
+    filters: [HighPass; 6], // left, right, then one per channel, skipped when fast
+    dac_outputs: [f32; 4],  // last analog output of each DAC
+}
+
+#[cfg(not(feature = "fixed-point-audio"))]
+impl Mixer {
+    // The per cycle factors, raised to the cycles between two samples
+    pub fn set_rate(&mut self, model: Model, rate: u32) {
+        let charge_factor = match model {
+            Model::Dmg => DMG_CHARGE_FACTOR,
+            Model::Cgb => CGB_CHARGE_FACTOR,
+        };
+        let cycles = CPU_CLOCK as f32 / rate as f32;
+        self.charge = charge_factor.powf(cycles);
+        self.fade = DAC_FADE_FACTOR.powf(cycles);
+    }
+
+    pub fn mix(&mut self, input: &MixInput) -> AudioSample {
+        // Digital 0 comes out as +1 and 15 as -1, so even a silent channel with its DAC on adds
+        // a DC offset, which the high-pass filters then take out slowly, as on hardware
+        let mut channels = [0.0; 4];
+        for (n, out) in channels.iter_mut().enumerate() {
+            *out = match input.dacs[n] {
+                Some(digital) => 1.0 - digital as f32 / 7.5,
+                None => self.dac_outputs[n] * self.fade,
+            };
+            self.dac_outputs[n] = *out;
+        }
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (n, &out) in channels.iter().enumerate() {
+            if input.panning & (0x10 << n) != 0 {
+                left += out;
+            }
+            if input.panning & (1 << n) != 0 {
+                right += out;
+            }
+        }
+        left *= (((input.volume >> 4) & 0x07) + 1) as f32 / 32.0;
+        right *= ((input.volume & 0x07) + 1) as f32 / 32.0;
+        if input.output == AudioOutput::Speaker {
+            left = (left + right) / 2.0;
+            right = left;
+        }
+
+        if !input.filter {
+            return AudioSample { left, right, channels };
+        }
+        let charge = self.charge;
+        let (outputs, stems) = self.filters.split_at_mut(2);
+        for (out, filter) in channels.iter_mut().zip(stems.iter_mut()) {
+            *out = filter.filter(*out, charge);
+        }
+        AudioSample {
+            left: outputs[0].filter(left, charge),
+            right: outputs[1].filter(right, charge),
+            channels,
+        }
+    }
+}
+
+pub struct Apu {
+    regs: [u8; REGS_SIZE],
+    channels: [Channel; 4],
+    sweep: Sweep,
+    lfsr: u16,
+    frame_step: u8,
+    frame_cycles: u32,
+
+    // Output only, not part of save states
+    sample_rate: Option<u32>,
+    sample_cycles: u64,
+    mixer: Mixer,
+    model: Model,
+    output: AudioOutput,
+    accuracy: AccuracyLevel,
+    samples: Vec<AudioSample>,
+    log: Option<ApuLog>, // see apu_log.rs
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        let mut apu = Apu {
+            regs: [0; REGS_SIZE],
+            channels: Default::default(),
+            sweep: Sweep::default(),
+            lfsr: 0x7FFF,
+            frame_step: 0,
+            frame_cycles: 0,
+            sample_rate: None,
+            sample_cycles: 0,
+            mixer: Mixer::default(),
+            model: Model::Dmg,
+            output: AudioOutput::default(),
+            accuracy: AccuracyLevel::default(),
+            samples: Vec::new(),
+            log: None,
+        };
+        // Powered on, full volume and every channel on both sides, as the boot ROM leaves it
+        apu.regs[NR52] = 0x80;
+        apu.regs[NR50] = 0x77;
+        apu.regs[NR51] = 0xF3;
+        apu
+    }
+
+    pub fn set_accuracy(&mut self, accuracy: AccuracyLevel) {
+        self.accuracy = accuracy;
+    }
+
+    // Registers and channels back to power on. Output goes on as it was: the sample rate,
+    // samples not yet taken and the log are kept.
+    pub fn reset(&mut self) {
+        let mut apu = Apu::new();
+        apu.sample_rate = self.sample_rate;
+        apu.sample_cycles = self.sample_cycles;
+        apu.mixer = mem::take(&mut self.mixer);
+        apu.model = self.model;
+        apu.output = self.output;
+        apu.accuracy = self.accuracy;
+        apu.samples = mem::take(&mut self.samples);
+        apu.log = self.log.take();
+        *self = apu;
+    }
+
+    // The high-pass filter differs between models, see DMG_CHARGE_FACTOR
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.update_filter_rates();
+    }
+
+    // Speaker mixes left and right down to mono. Channel stems are never panned.
+    pub fn set_output(&mut self, output: AudioOutput) {
+        self.output = output;
+    }
+
+    // Start mixing samples at rate per second, or stop with None
+    pub fn set_sample_rate(&mut self, rate: Option<u32>) {
+        self.sample_rate = rate;
+        self.sample_cycles = 0;
+        self.samples.clear();
+        self.update_filter_rates();
+    }
+
+    fn update_filter_rates(&mut self) {
+        if let Some(rate) = self.sample_rate {
+            self.mixer.set_rate(self.model, rate);
+        }
+    }
+
+    // Bits per sample, 8 - 24. Samples go from -(2^(bits - 1) - 1) to 2^(bits - 1) - 1.
+    #[cfg(feature = "fixed-point-audio")]
+    pub fn set_sample_bits(&mut self, bits: u32) {
+        self.mixer.set_output_bits(bits);
+    }
+
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    // Samples mixed since the last clear_samples
+    pub fn samples(&self) -> &[AudioSample] {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+
+    // Starts recording register writes, over any log already running
+    pub fn start_log(&mut self) {
+        // Power first, then what the channels are set to (without retriggering them), then
+        // the wave
+        let mut writes = vec![ApuWrite { cycle: 0, addr: 0xFF26, val: self.regs[NR52] }];
+        for index in (NR10..WAVE_RAM).filter(|&index| index != NR52) {
+            let val = if index < NR50 && index % 5 == 4 { self.regs[index] & 0x7F } else { self.regs[index] };
+            writes.push(ApuWrite { cycle: 0, addr: 0xFF10 + index as u16, val });
+        }
+        for index in WAVE_RAM..REGS_SIZE {
+            writes.push(ApuWrite { cycle: 0, addr: 0xFF10 + index as u16, val: self.regs[index] });
+        }
+        self.log = Some(ApuLog { writes, cycles: 0 });
+    }
+
+    // Stops recording and hands over the log, if one was running
+    pub fn take_log(&mut self) -> Option<ApuLog> {
+        self.log.take()
+    }
+
+    fn powered(&self) -> bool {
+        self.regs[NR52] & 0x80 != 0
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        let index = (addr - 0xFF10) as usize;
+        match index {
+            NR52 => {
+                let status = self.channels.iter().enumerate()
+                    .filter(|(_, ch)| ch.enabled)
+                    .fold(0, |bits, (n, _)| bits | (1 << n));
+                (self.regs[NR52] & 0x80) | READ_MASKS[NR52] | status
+            }
+            WAVE_RAM..= 0x2F => self.regs[index],
+            _ => self.regs[index] | READ_MASKS[index],
+        }
+    }
+
+    // PCM12 and PCM34 (0xFF76, 0xFF77) only exist on the CGB
+    pub fn has_pcm_registers(&self) -> bool {
+        self.model == Model::Cgb
+    }
+
+    // What each channel feeds its DAC right now, low nibble first: PCM12 has channels 1 and 2,
+    // PCM34 channels 3 and 4. Read only.
+    pub fn read_pcm(&self, addr: u16) -> u8 {
+        let first = if addr == 0xFF76 { 0 } else { 2 };
+        self.digital(first) | self.digital(first + 1) << 4
+    }
+
+    // Every channel's DAC input, 0 - 15, for visualizers. Available on every model.
+    pub fn channel_outputs(&self) -> [u8; 4] {
+        [self.digital(0), self.digital(1), self.digital(2), self.digital(3)]
+    }
+
+    // Settings and state of every channel right now, for visualizers
+    pub fn snapshot(&self) -> ApuSnapshot {
+        let mut snapshot = ApuSnapshot { powered: self.regs[NR52] & 0x80 != 0, ..ApuSnapshot::default() };
+        for (n, channel) in snapshot.channels.iter_mut().enumerate() {
+            // Steps per cycle of the tone
+            let steps = match n {
+                0 | 1 => 8,
+                2 => 32,
+                _ => 1,
+            };
+            let nr43 = self.regs[0x12];
+            *channel = ChannelSnapshot {
+                playing: self.channels[n].enabled && self.dac_enabled(n),
+                frequency: self.period(n).map_or(0.0, |period| CPU_CLOCK as f32 / (period * steps) as f32),
+                volume: match n {
+                    2 => 15 >> WAVE_SHIFTS[((self.regs[0x0C] >> 5) & 0x03) as usize],
+                    _ => self.channels[n].volume,
+                },
+                duty: match n {
+                    0 | 1 => Some(DUTY[(self.regs[Self::base(n) + 1] >> 6) as usize].count_ones() as u8),
+                    _ => None,
+                },
+                lfsr_width: if n == 3 { Some(if nr43 & 0x08 != 0 { 7 } else { 15 }) } else { None },
+                left: self.regs[NR51] & (0x10 << n) != 0,
+                right: self.regs[NR51] & (1 << n) != 0,
+                output: self.digital(n),
+            };
+        }
+        snapshot.wave_ram.copy_from_slice(&self.regs[WAVE_RAM..WAVE_RAM + 16]);
+        snapshot
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        if let Some(ref mut log) = self.log {
+            log.writes.push(ApuWrite { cycle: log.cycles, addr, val });
+        }
+        let index = (addr - 0xFF10) as usize;
+        if index == NR52 {
+            self.write_power(val);
+            return;
+        }
+        // Only wave RAM can be written while the APU is off
+        if !self.powered() && index < WAVE_RAM {
+            return;
+        }
+        self.regs[index] = val;
+
+        let n = index / 5;
+        match (n, index % 5) {
+            _ if index >= NR50 => {}
+            (0, 0) => {} // NR10, read when the sweep clocks
+            (2, 0) if val & 0x80 == 0 => self.channels[2].enabled = false,
+            (2, 1) => self.channels[2].length = 256 - val as u16,
+            (_, 1) => self.channels[n].length = 64 - (val & 0x3F) as u16,
+            (_, 2) if !self.dac_enabled(n) => self.channels[n].enabled = false,
+            (_, 4) if val & 0x80 != 0 => self.trigger(n),
+            _ => {}
+        }
+    }
+
+    fn write_power(&mut self, val: u8) {
+        if val & 0x80 == 0 && self.powered() {
+            // Powering off clears every register but wave RAM
+            for reg in self.regs[NR10..WAVE_RAM].iter_mut() {
+                *reg = 0;
+            }
+            self.channels = Default::default();
+            self.sweep = Sweep::default();
+        } else if val & 0x80 != 0 && !self.powered() {
+            self.frame_step = 0;
+            self.frame_cycles = 0;
+        }
+        self.regs[NR52] = val & 0x80;
+    }
+
+    fn base(n: usize) -> usize {
+        n * 5
+    }
+
+    fn dac_enabled(&self, n: usize) -> bool {
+        if n == 2 {
+            self.regs[NR30] & 0x80 != 0
+        } else {
+            self.regs[Self::base(n) + 2] & 0xF8 != 0
+        }
+    }
+
+    fn length_enabled(&self, n: usize) -> bool {
+        self.regs[Self::base(n) + 4] & 0x40 != 0
+    }
+
+    fn frequency(&self, n: usize) -> u16 {
+        let base = Self::base(n);
+        self.regs[base + 3] as u16 | ((self.regs[base + 4] as u16 & 0x07) << 8)
+    }
+
+    fn set_frequency(&mut self, n: usize, freq: u16) {
+        let base = Self::base(n);
+        self.regs[base + 3] = freq as u8;
+        self.regs[base + 4] = (self.regs[base + 4] & !0x07) | ((freq >> 8) as u8 & 0x07);
+    }
+
+    // Cycles between steps of channel n, None if it never steps
+    fn period(&self, n: usize) -> Option<u32> {
+        match n {
+            0 | 1 => Some((2048 - self.frequency(n) as u32) * 4),
+            2 => Some((2048 - self.frequency(n) as u32) * 2),
+            _ => {
+                let nr43 = self.regs[0x12];
+                let shift = nr43 >> 4;
+                if shift >= 14 {
+                    None
+                } else {
+                    Some(NOISE_DIVISORS[(nr43 & 0x07) as usize] << shift)
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self, n: usize) {
+        let nrx2 = self.regs[Self::base(n) + 2];
+        let period = self.period(n).unwrap_or(0) as i32;
+        let enabled = self.dac_enabled(n);
+        let ch = &mut self.channels[n];
+        ch.enabled = enabled;
+        if ch.length == 0 {
+            ch.length = if n == 2 { 256 } else { 64 };
+        }
+        ch.timer = period;
+        ch.volume = nrx2 >> 4;
+        ch.envelope_timer = nrx2 & 0x07;
+        match n {
+            0 => self.trigger_sweep(),
+            2 => ch.position = 0,
+            3 => self.lfsr = 0x7FFF,
+            _ => {}
+        }
+    }
+
+    fn trigger_sweep(&mut self) {
+        let nr10 = self.regs[NR10];
+        let period = (nr10 >> 4) & 0x07;
+        let shift = nr10 & 0x07;
+        self.sweep.shadow = self.frequency(0);
+        self.sweep.timer = if period == 0 { 8 } else { period };
+        self.sweep.enabled = period != 0 || shift != 0;
+        if shift != 0 {
+            self.sweep_frequency();
+        }
+    }
+
+    // Next sweep frequency. Going past 2047 turns channel 1 off.
+    fn sweep_frequency(&mut self) -> u16 {
+        let nr10 = self.regs[NR10];
+        let delta = self.sweep.shadow >> (nr10 & 0x07);
+        let freq = if nr10 & 0x08 != 0 {
+            self.sweep.shadow.wrapping_sub(delta)
+        } else {
+            self.sweep.shadow + delta
+        };
+        if freq > 2047 {
+            self.channels[0].enabled = false;
+        }
+        freq
+    }
+
+    pub fn cycle_flush(&mut self, cycle_count: u32) -> u32 {
+        if let Some(ref mut log) = self.log {
+            log.cycles += cycle_count as u64;
+        }
+        if self.powered() {
+            self.clock_channels(cycle_count);
+
+            self.frame_cycles += cycle_count;
+            while self.frame_cycles >= FRAME_SEQUENCER_PERIOD {
+                self.frame_cycles -= FRAME_SEQUENCER_PERIOD;
+                self.clock_frame_sequencer();
+            }
+        }
+
+        let mut produced = 0;
+        if let Some(rate) = self.sample_rate {
+            self.sample_cycles += cycle_count as u64 * rate as u64;
+            while self.sample_cycles >= CPU_CLOCK as u64 {
+                self.sample_cycles -= CPU_CLOCK as u64;
+                let sample = self.mix();
+                self.samples.push(sample);
+                produced += 1;
+            }
+        }
+        produced
+    }
+
+    fn clock_channels(&mut self, cycle_count: u32) {
+        for n in 0..4 {
+            let period = match self.period(n) {
+                Some(period) => period as i32,
+                None => continue,
+            };
+            self.channels[n].timer -= cycle_count as i32;
+            while self.channels[n].timer <= 0 {
+                self.channels[n].timer += period;
+                match n {
+                    0 | 1 => self.channels[n].position = (self.channels[n].position + 1) & 0x07,
+                    2 => self.channels[n].position = (self.channels[n].position + 1) & 0x1F,
+                    _ => self.clock_lfsr(),
+                }
+            }
+        }
+    }
+
+    fn clock_lfsr(&mut self) {
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        // 7 bit mode
+        if self.regs[0x12] & 0x08 != 0 {
+            self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+        }
+    }
+
+    // Steps 0, 2, 4, 6 clock lengths, 2 and 6 the sweep, 7 the envelopes
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_step;
+        self.frame_step = (step + 1) & 0x07;
+
+        if step & 1 == 0 {
+            for n in 0..4 {
+                let length_enabled = self.length_enabled(n);
+                let ch = &mut self.channels[n];
+                if length_enabled && ch.length > 0 {
+                    ch.length -= 1;
+                    if ch.length == 0 {
+                        ch.enabled = false;
+                    }
+                }
+            }
+        }
+        if step == 2 || step == 6 {
+            self.clock_sweep();
+        }
+        if step == 7 {
+            for &n in &[0, 1, 3] {
+                let nrx2 = self.regs[Self::base(n) + 2];
+                let period = nrx2 & 0x07;
+                let ch = &mut self.channels[n];
+                if period == 0 || ch.envelope_timer == 0 {
+                    continue;
+                }
+                ch.envelope_timer -= 1;
+                if ch.envelope_timer == 0 {
+                    ch.envelope_timer = period;
+                    if nrx2 & 0x08 != 0 && ch.volume < 15 {
+                        ch.volume += 1;
+                    } else if nrx2 & 0x08 == 0 && ch.volume > 0 {
+                        ch.volume -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+        let nr10 = self.regs[NR10];
+        let period = (nr10 >> 4) & 0x07;
+        self.sweep.timer = if period == 0 { 8 } else { period };
+        if self.sweep.enabled && period != 0 {
+            let freq = self.sweep_frequency();
+            if freq <= 2047 && nr10 & 0x07 != 0 {
+                self.sweep.shadow = freq;
+                self.set_frequency(0, freq);
+                // Checked again straight away with the new frequency
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    // Channel n's DAC input, 0 - 15
+    fn digital(&self, n: usize) -> u8 {
+        let ch = &self.channels[n];
+        if !ch.enabled {
+            return 0;
+        }
+        match n {
+            0 | 1 => {
+                let duty = DUTY[(self.regs[Self::base(n) + 1] >> 6) as usize];
+                if duty >> ch.position & 1 != 0 { ch.volume } else { 0 }
+            }
+            2 => {
+                let byte = self.regs[WAVE_RAM + (ch.position / 2) as usize];
+                let sample = if ch.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+                sample >> WAVE_SHIFTS[((self.regs[0x0C] >> 5) & 0x03) as usize]
+            }
+            _ => if self.lfsr & 1 == 0 { ch.volume } else { 0 },
+        }
+    }
+
+    fn mix(&mut self) -> AudioSample {
+        let mut dacs = [None; 4];
+        for (n, dac) in dacs.iter_mut().enumerate() {
+            if self.dac_enabled(n) {
+                *dac = Some(self.digital(n));
+            }
+        }
+        self.mixer.mix(&MixInput {
+            dacs,
+            panning: self.regs[NR51],
+            volume: self.regs[NR50],
+            output: self.output,
+            // Fast leaves the DC offset in, which costs the six filters per sample
+            filter: self.accuracy != AccuracyLevel::Fast,
+        })
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.regs);
+        for ch in self.channels.iter() {
+            state.write_bool(ch.enabled);
+            state.write_u16(ch.length);
+            state.write_u32(ch.timer as u32);
+            state.write_u8(ch.position);
+            state.write_u8(ch.volume);
+            state.write_u8(ch.envelope_timer);
+        }
+        state.write_bool(self.sweep.enabled);
+        state.write_u8(self.sweep.timer);
+        state.write_u16(self.sweep.shadow);
+        state.write_u16(self.lfsr);
+        state.write_u8(self.frame_step);
+        state.write_u32(self.frame_cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.regs, "apu registers")?;
+        for ch in self.channels.iter_mut() {
+            ch.enabled = state.read_bool()?;
+            ch.length = state.read_u16()?;
+            ch.timer = state.read_u32()? as i32;
+            ch.position = state.read_u8()?;
+            ch.volume = state.read_u8()?;
+            ch.envelope_timer = state.read_u8()?;
+        }
+        self.sweep.enabled = state.read_bool()?;
+        self.sweep.timer = state.read_u8()?;
+        self.sweep.shadow = state.read_u16()?;
+        self.lfsr = state.read_u16()?;
+        self.frame_step = state.read_u8()?;
+        self.frame_cycles = state.read_u32()?;
+        Ok(())
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sample values are checked for the integer mixer in apu_fixed.rs
+    #[cfg(not(feature = "fixed-point-audio"))]
+    #[test]
+    fn square_plays_until_length_runs_out() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(Some(44_100));
+        apu.write(0xFF11, 0x80 | 62); // 50% duty, 2 length ticks left
+        apu.write(0xFF12, 0xF0);      // full volume, no envelope
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0xC7);      // trigger with length enabled, ~1kHz
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x01);
+
+        // In instruction sized steps, as the interconnect does
+        for _ in 0..FRAME_SEQUENCER_PERIOD / 4 {
+            apu.cycle_flush(4);
+        }
+        let samples = apu.samples().len();
+        assert!(samples > 0);
+        assert!(apu.samples().iter().any(|s| s.channels[0] > 0.5));
+        assert!(apu.samples().iter().any(|s| s.channels[0] < -0.5));
+
+        for _ in 0..FRAME_SEQUENCER_PERIOD * 3 / 4 {
+            apu.cycle_flush(4);
+        }
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+        assert_eq!(apu.samples().len(), samples * 4);
+    }
+
+    // Channel 1 with its DAC on but silent, i.e. a constant DC offset
+    #[cfg(not(feature = "fixed-point-audio"))]
+    fn dc_offset(model: Model, accuracy: AccuracyLevel) -> Apu {
+        let mut apu = Apu::new();
+        apu.set_model(model);
+        apu.set_accuracy(accuracy);
+        apu.set_sample_rate(Some(44_100));
+        apu.write(0xFF12, 0x08); // volume 0, DAC on
+        for _ in 0..CPU_CLOCK / 100 / 4 {
+            apu.cycle_flush(4);
+        }
+        apu
+    }
+
+    #[cfg(not(feature = "fixed-point-audio"))]
+    #[test]
+    fn pans_for_headphones_and_mixes_down_for_the_speaker() {
+        let mut apu = dc_offset(Model::Dmg, AccuracyLevel::Fast);
+        apu.write(0xFF25, 0x01); // channel 1 on the right only
+        apu.clear_samples();
+        apu.cycle_flush(1024);
+        let sample = *apu.samples().last().unwrap();
+        assert_eq!((sample.left, sample.right), (0.0, 0.25));
+
+        apu.set_output(AudioOutput::Speaker);
+        apu.cycle_flush(1024);
+        let sample = *apu.samples().last().unwrap();
+        assert_eq!((sample.left, sample.right), (0.125, 0.125));
+    }
+
+    #[test]
+    fn pcm_registers_show_channel_outputs() {
+        let mut apu = Apu::new();
+        apu.write(0xFF12, 0xA0); // volume 10
+        apu.write(0xFF14, 0x80);
+        apu.write(0xFF21, 0x50); // volume 5
+        apu.write(0xFF23, 0x80);
+        apu.write(0xFF21, 0x00); // DAC off: channel off, outputs 0
+        assert!(!apu.has_pcm_registers());
+        apu.set_model(Model::Cgb);
+        assert!(apu.has_pcm_registers());
+        // Duty 12.5%: only the first of the eight steps is high
+        assert_eq!(apu.channel_outputs(), [10, 0, 0, 0]);
+        assert_eq!(apu.read_pcm(0xFF76), 0x0A);
+        assert_eq!(apu.read_pcm(0xFF77), 0x00);
+        apu.cycle_flush(2048 * 4);
+        assert_eq!(apu.read_pcm(0xFF76), 0x00);
+    }
+
+    #[test]
+    fn snapshot_describes_channels() {
+        let mut apu = Apu::new();
+        apu.write(0xFF16, 0x80);      // 50% duty
+        apu.write(0xFF17, 0xC0);      // volume 12
+        apu.write(0xFF18, 0x00);
+        apu.write(0xFF19, 0x87);      // trigger, 2048 - 0x700 = 256: 512Hz
+        apu.write(0xFF22, 0x08);      // 7 bit LFSR
+        let snapshot = apu.snapshot();
+        assert!(snapshot.powered);
+        let square = snapshot.channels[1];
+        assert!(square.playing);
+        assert_eq!((square.frequency, square.volume, square.duty), (512.0, 12, Some(4)));
+        assert!(square.left && square.right);
+        assert!(!snapshot.channels[0].playing);
+        assert_eq!(snapshot.channels[3].lfsr_width, Some(7));
+        assert_eq!(snapshot.channels[3].duty, None);
+    }
+
+    #[cfg(not(feature = "fixed-point-audio"))]
+    #[test]
+    fn dac_fades_out_and_filters_follow_the_model() {
+        let mut apu = dc_offset(Model::Dmg, AccuracyLevel::Fast);
+        assert_eq!(apu.samples().last().unwrap().channels[0], 1.0);
+        apu.clear_samples();
+        apu.write(0xFF12, 0x00); // DAC off
+        for _ in 0..CPU_CLOCK / 20 / 4 {
+            apu.cycle_flush(4);
+        }
+        let outputs: Vec<f32> = apu.samples().iter().map(|s| s.channels[0]).collect();
+        assert!(outputs[0] > 0.9);
+        assert!(outputs.windows(2).all(|pair| pair[0] - pair[1] < 0.05 && pair[1] <= pair[0]));
+        assert!(*outputs.last().unwrap() < 0.01);
+
+        // The CGB's capacitor takes the offset out faster
+        let last = |apu: Apu| apu.samples().last().unwrap().channels[0];
+        let dmg = last(dc_offset(Model::Dmg, AccuracyLevel::Balanced));
+        let cgb = last(dc_offset(Model::Cgb, AccuracyLevel::Balanced));
+        assert!(cgb < 0.01 && dmg > 0.1, "dmg {} cgb {}", dmg, cgb);
+    }
+}
@@ -0,0 +1,220 @@
+// Integer audio mixing, for targets without an FPU.
+// With the fixed-point-audio feature the APU mixes with this instead of the floating point mixer
+// in apu.rs, and AudioSample is a FixedSample: whole numbers of a configurable width (see
+// Apu::set_sample_bits), 16 bits unless told otherwise. Nothing from the DACs to the samples
+// touches a float.
+//
+// Channels, panning and master volume add up in i32 accumulators, with 1.0 as UNIT. The
+// high-pass filters and the DAC fade run on factors in 2.30 fixed point, raised to the cycles
+// between two samples by squaring.
+
+use super::apu::{MixInput, CPU_CLOCK};
+use super::config::AudioOutput;
+use super::console::Model;
+
+const UNIT_BITS: u32 = 24;
+const UNIT: i32 = 1 << UNIT_BITS;
+const FRACTION_BITS: u32 = 30;
+const ONE: i64 = 1 << FRACTION_BITS;
+
+// The factors in apu.rs, times 2^30
+const DMG_CHARGE_FACTOR: i64 = 1_073_696_727;
+const CGB_CHARGE_FACTOR: i64 = 1_072_606_879;
+const DAC_FADE_FACTOR: i64 = 1_073_634_450;
+
+pub const DEFAULT_SAMPLE_BITS: u32 = 16;
+
+// One output sample, from -(2^(bits - 1) - 1) to 2^(bits - 1) - 1
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct FixedSample {
+    pub left: i32,
+    pub right: i32,
+    // Each channel on its own, before panning and master volume
+    pub channels: [i32; 4],
+    pub bits: u8,
+}
+
+impl FixedSample {
+    // One of the values as -1.0 to 1.0, for the sinks that want floats
+    pub fn to_float(&self, value: i32) -> f32 {
+        value as f32 / ((1 << (self.bits - 1)) - 1) as f32
+    }
+}
+
+fn multiply(value: i32, factor: i64) -> i32 {
+    ((value as i64 * factor) >> FRACTION_BITS) as i32
+}
+
+// factor^exponent, both in 2.30
+fn power(mut factor: i64, mut exponent: u32) -> i64 {
+    let mut result = ONE;
+    while exponent > 0 {
+        if exponent & 1 != 0 {
+            result = (result * factor) >> FRACTION_BITS;
+        }
+        factor = (factor * factor) >> FRACTION_BITS;
+        exponent >>= 1;
+    }
+    result
+}
+
+#[derive(Debug, Default, Clone)]
+struct HighPass {
+    capacitor: i32,
+}
+
+impl HighPass {
+    fn filter(&mut self, input: i32, charge: i64) -> i32 {
+        let out = input - self.capacitor;
+        self.capacitor = input - multiply(out, charge);
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mixer {
+    charge: i64,
+    fade: i64,
+    filters: [HighPass; 6], // left, right, then one per channel, skipped when fast
+    dac_outputs: [i32; 4],  // last analog output of each DAC, in UNITs
+    bits: u32,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Mixer {
+            charge: 0,
+            fade: 0,
+            filters: Default::default(),
+            dac_outputs: [0; 4],
+            bits: DEFAULT_SAMPLE_BITS,
+        }
+    }
+}
+
+impl Mixer {
+    pub fn set_rate(&mut self, model: Model, rate: u32) {
+        let charge_factor = match model {
+            Model::Dmg => DMG_CHARGE_FACTOR,
+            Model::Cgb => CGB_CHARGE_FACTOR,
+        };
+        let cycles = (CPU_CLOCK + rate / 2) / rate;
+        self.charge = power(charge_factor, cycles);
+        self.fade = power(DAC_FADE_FACTOR, cycles);
+    }
+
+    pub fn set_output_bits(&mut self, bits: u32) {
+        self.bits = bits.clamp(8, 24);
+    }
+
+    // From UNITs to the output width, clipped
+    fn output(&self, value: i32) -> i32 {
+        let max = (1 << (self.bits - 1)) - 1;
+        let shift = UNIT_BITS + 1 - self.bits;
+        // Rounded, so a full UNIT comes out as max rather than max + 1 clipped
+        ((value + (1 << shift >> 1)) >> shift).clamp(-max, max)
+    }
+
+    fn outputs(&self, left: i32, right: i32, channels: [i32; 4]) -> FixedSample {
+        FixedSample {
+            left: self.output(left),
+            right: self.output(right),
+            channels: [
+                self.output(channels[0]),
+                self.output(channels[1]),
+                self.output(channels[2]),
+                self.output(channels[3]),
+            ],
+            bits: self.bits as u8,
+        }
+    }
+
+    pub fn mix(&mut self, input: &MixInput) -> FixedSample {
+        // Digital 0 comes out as +UNIT and 15 as -UNIT, as in the floating point mixer
+        let mut channels = [0; 4];
+        for (n, out) in channels.iter_mut().enumerate() {
+            *out = match input.dacs[n] {
+                Some(digital) => ((15 - 2 * digital as i64) * UNIT as i64 / 15) as i32,
+                None => multiply(self.dac_outputs[n], self.fade),
+            };
+            self.dac_outputs[n] = *out;
+        }
+
+        let mut left = 0;
+        let mut right = 0;
+        for (n, &out) in channels.iter().enumerate() {
+            if input.panning & (0x10 << n) != 0 {
+                left += out;
+            }
+            if input.panning & (1 << n) != 0 {
+                right += out;
+            }
+        }
+        left = left * (((input.volume >> 4) & 0x07) + 1) as i32 / 32;
+        right = right * ((input.volume & 0x07) + 1) as i32 / 32;
+        if input.output == AudioOutput::Speaker {
+            left = (left + right) / 2;
+            right = left;
+        }
+
+        if !input.filter {
+            return self.outputs(left, right, channels);
+        }
+        let charge = self.charge;
+        let (outputs, stems) = self.filters.split_at_mut(2);
+        for (out, filter) in channels.iter_mut().zip(stems.iter_mut()) {
+            *out = filter.filter(*out, charge);
+        }
+        let left = outputs[0].filter(left, charge);
+        let right = outputs[1].filter(right, charge);
+        self.outputs(left, right, channels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(dacs: [Option<u8>; 4], panning: u8, filter: bool) -> MixInput {
+        MixInput { dacs, panning, volume: 0x77, output: AudioOutput::Headphones, filter }
+    }
+
+    #[test]
+    fn powers_match_the_floating_point_factors() {
+        let to_float = |factor: i64| factor as f64 / ONE as f64;
+        let charge = power(DMG_CHARGE_FACTOR, 95);
+        assert!((to_float(charge) - 0.999_958f64.powi(95)).abs() < 1e-6);
+        assert_eq!(power(CGB_CHARGE_FACTOR, 0), ONE);
+    }
+
+    #[test]
+    fn mixes_to_the_output_width() {
+        let mut mixer = Mixer::default();
+        mixer.set_rate(Model::Dmg, 44_100);
+        // One silent channel on the right: +1.0, a quarter of full scale after volume
+        let sample = mixer.mix(&input([Some(0), None, None, None], 0x01, false));
+        assert_eq!(sample.channels, [32767, 0, 0, 0]);
+        assert_eq!((sample.left, sample.right), (0, 8192));
+        let sample = mixer.mix(&input([Some(15), None, None, None], 0x11, false));
+        assert_eq!((sample.left, sample.right), (-8192, -8192));
+
+        mixer.set_output_bits(8);
+        let sample = mixer.mix(&input([Some(0); 4], 0xFF, false));
+        assert_eq!((sample.left, sample.right), (127, 127));
+    }
+
+    #[test]
+    fn filters_take_the_offset_out_and_dacs_fade() {
+        let mut mixer = Mixer::default();
+        mixer.set_rate(Model::Cgb, 44_100);
+        let on = input([Some(0), None, None, None], 0x11, true);
+        let first = mixer.mix(&on);
+        let last = (0..4410).map(|_| mixer.mix(&on)).last().unwrap();
+        assert!(first.left > 8000 && last.left.abs() < 100, "{} {}", first.left, last.left);
+
+        let off = input([None; 4], 0x11, false);
+        let outputs: Vec<i32> = (0..4410).map(|_| mixer.mix(&off).channels[0]).collect();
+        assert!(outputs.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(outputs[0] > 30000 && outputs[4409] < 300);
+    }
+}
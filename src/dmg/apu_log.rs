@@ -0,0 +1,155 @@
+// Sound register logging.
+// While logging, the APU records every write to 0xFF10 - 0xFF3F with the number of cycles since
+// the log started. That is all a chiptune needs to be replayed on another sound chip emulator,
+// so the log can be saved as plain text, or converted to a VGM file that the usual players and
+// archival tools understand. See https://vgmrips.net/wiki/VGM_Specification
+// A log opens with the register values at the time it started, so playback does not depend on
+// what the game did before.
+
+use std::io::{self, BufRead, Write};
+
+use super::apu::CPU_CLOCK;
+
+const VGM_VERSION: u32 = 0x161; // first version with the Game Boy DMG
+const VGM_HEADER_SIZE: usize = 0x100;
+const VGM_SAMPLE_RATE: u64 = 44_100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ApuWrite {
+    pub cycle: u64, // since the log started
+    pub addr: u16,
+    pub val: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApuLog {
+    pub writes: Vec<ApuWrite>,
+    // Length of the log, which goes on past the last write
+    pub cycles: u64,
+}
+
+impl ApuLog {
+    // One write per line: "<cycle> <addr> <value>", the last two in hex.
+    // A final "<cycle> end" line holds the length.
+    pub fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for write in &self.writes {
+            writeln!(out, "{} {:04x} {:02x}", write.cycle, write.addr, write.val)?;
+        }
+        writeln!(out, "{} end", self.cycles)
+    }
+
+    pub fn read_text<R: BufRead>(input: R) -> io::Result<ApuLog> {
+        let invalid = |line: &str| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("bad APU log line \"{}\"", line))
+        };
+
+        let mut log = ApuLog::default();
+        for line in input.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                [cycle, "end"] => log.cycles = cycle.parse().map_err(|_| invalid(&line))?,
+                [cycle, addr, val] => {
+                    let write = parse_write(cycle, addr, val).ok_or_else(|| invalid(&line))?;
+                    log.writes.push(write);
+                }
+                _ => return Err(invalid(&line)),
+            }
+        }
+        Ok(log)
+    }
+
+    pub fn write_vgm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut data = Vec::new();
+        let mut samples_done = 0;
+        for write in &self.writes {
+            samples_done = vgm_wait(&mut data, samples_done, write.cycle);
+            // Game Boy register write: 0xB3, register offset from 0xFF10, value
+            data.extend_from_slice(&[0xB3, (write.addr - 0xFF10) as u8, write.val]);
+        }
+        let total_samples = vgm_wait(&mut data, samples_done, self.cycles);
+        data.push(0x66); // end of sound data
+
+        let mut header = [0u8; VGM_HEADER_SIZE];
+        let mut put = |offset: usize, val: u32| {
+            header[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+        };
+        put(0x00, u32::from_le_bytes(*b"Vgm "));
+        put(0x04, (VGM_HEADER_SIZE + data.len() - 0x04) as u32); // relative to 0x04
+        put(0x08, VGM_VERSION);
+        put(0x18, total_samples as u32);
+        put(0x34, (VGM_HEADER_SIZE - 0x34) as u32); // relative to 0x34
+        put(0x80, CPU_CLOCK);
+
+        out.write_all(&header)?;
+        out.write_all(&data)
+    }
+}
+
+fn parse_write(cycle: &str, addr: &str, val: &str) -> Option<ApuWrite> {
+    Some(ApuWrite {
+        cycle: cycle.parse().ok()?,
+        addr: u16::from_str_radix(addr, 16).ok()?,
+        val: u8::from_str_radix(val, 16).ok()?,
+    })
+}
+
+// Waits from samples_done until cycle, returns the new sample position
+fn vgm_wait(data: &mut Vec<u8>, samples_done: u64, cycle: u64) -> u64 {
+    let target = cycle * VGM_SAMPLE_RATE / CPU_CLOCK as u64;
+    let mut remaining = target.saturating_sub(samples_done);
+    while remaining > 0 {
+        match remaining {
+            735 => data.push(0x62), // one 60Hz frame
+            882 => data.push(0x63), // one 50Hz frame
+            1..= 16 => data.push(0x70 + (remaining - 1) as u8),
+            _ => {
+                let wait = remaining.min(0xFFFF) as u16;
+                data.push(0x61);
+                data.extend_from_slice(&wait.to_le_bytes());
+                remaining -= wait as u64;
+                continue;
+            }
+        }
+        break;
+    }
+    target.max(samples_done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::apu::Apu;
+
+    #[test]
+    fn logs_writes_for_text_and_vgm() {
+        let mut apu = Apu::new();
+        apu.start_log();
+        apu.cycle_flush(100);
+        apu.write(0xFF12, 0xF0);
+        apu.cycle_flush(CPU_CLOCK);
+        apu.write(0xFF30, 0x12);
+        let log = apu.take_log().unwrap();
+        assert!(apu.take_log().is_none());
+
+        // The starting register values come first, all at cycle 0
+        let writes: Vec<_> = log.writes.iter().filter(|w| w.cycle > 0).collect();
+        assert_eq!(writes, [
+            &ApuWrite { cycle: 100, addr: 0xFF12, val: 0xF0 },
+            &ApuWrite { cycle: 100 + CPU_CLOCK as u64, addr: 0xFF30, val: 0x12 },
+        ]);
+        assert_eq!(log.writes[0], ApuWrite { cycle: 0, addr: 0xFF26, val: 0x80 });
+
+        let mut text = Vec::new();
+        log.write_text(&mut text).unwrap();
+        assert_eq!(ApuLog::read_text(&text[..]).unwrap(), log);
+
+        let mut vgm = Vec::new();
+        log.write_vgm(&mut vgm).unwrap();
+        assert_eq!(&vgm[0..4], b"Vgm ");
+        assert_eq!(&vgm[0x18..0x1C], &44_101u32.to_le_bytes()); // one second and 100 cycles
+        assert_eq!(&vgm[0x80..0x84], &CPU_CLOCK.to_le_bytes());
+        assert_eq!(&vgm[vgm.len() - 4..], &[0xB3, 0x20, 0x12, 0x66]);
+    }
+}
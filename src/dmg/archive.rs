@@ -0,0 +1,120 @@
+// Compressed ROMs.
+// People keep their ROMs zipped or gzipped, and drag-and-drop those straight onto emulators.
+// unpack looks at the magic bytes and hands back the ROM inside, or the input untouched if it
+// is not an archive. Decompression needs the `archive` feature.
+
+use std::borrow::Cow;
+use std::io;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+// Entries with these extensions count as ROMs when a zip holds more than one file
+#[cfg(feature = "archive")]
+const ROM_EXTENSIONS: [&str; 3] = ["gb", "gbc", "sgb"];
+
+pub fn unpack(bytes: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    if bytes.starts_with(ZIP_MAGIC) {
+        unzip(bytes).map(Cow::Owned)
+    } else if bytes.starts_with(GZIP_MAGIC) {
+        gunzip(bytes).map(Cow::Owned)
+    } else {
+        Ok(Cow::Borrowed(bytes))
+    }
+}
+
+#[cfg(feature = "archive")]
+fn unzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+    use std::path::Path;
+
+    let mut zip = zip::ZipArchive::new(io::Cursor::new(bytes))?;
+
+    let files: Vec<usize> = (0..zip.len())
+        .filter(|&i| zip.by_index(i).map(|entry| entry.is_file()).unwrap_or(false))
+        .collect();
+
+    // A lone file is the ROM whatever it is called, otherwise look for exactly one ROM
+    let roms: Vec<usize> = if files.len() == 1 {
+        files
+    } else {
+        files.into_iter()
+            .filter(|&i| {
+                let entry = zip.by_index(i).unwrap();
+                let extension = Path::new(entry.name()).extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(|ext| ext.to_ascii_lowercase());
+                matches!(extension, Some(ext) if ROM_EXTENSIONS.contains(&ext.as_str()))
+            })
+            .collect()
+    };
+
+    match roms.as_slice() {
+        [rom] => {
+            let mut entry = zip.by_index(*rom)?;
+            let mut bytes = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut bytes)?;
+            Ok(bytes)
+        }
+        [] => Err(io::Error::new(io::ErrorKind::InvalidData, "zip archive contains no ROM")),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "zip archive contains more than one ROM")),
+    }
+}
+
+#[cfg(feature = "archive")]
+fn gunzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut rom = Vec::new();
+    flate2::read::GzDecoder::new(bytes).read_to_end(&mut rom)?;
+    Ok(rom)
+}
+
+#[cfg(not(feature = "archive"))]
+fn unzip(_bytes: &[u8]) -> io::Result<Vec<u8>> {
+    Err(needs_archive_feature())
+}
+
+#[cfg(not(feature = "archive"))]
+fn gunzip(_bytes: &[u8]) -> io::Result<Vec<u8>> {
+    Err(needs_archive_feature())
+}
+
+#[cfg(not(feature = "archive"))]
+fn needs_archive_feature() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   "compressed ROMs need gbrust built with the `archive` feature")
+}
+
+#[cfg(all(test, feature = "archive"))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn unpacks_single_rom_zip() {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.start_file("readme.txt", options).unwrap();
+        zip.write_all(b"have fun").unwrap();
+        zip.start_file("game.GB", options).unwrap();
+        zip.write_all(b"rom bytes").unwrap();
+        let bytes = zip.finish().unwrap().into_inner();
+
+        assert_eq!(unpack(&bytes).unwrap().as_ref(), b"rom bytes");
+    }
+
+    #[test]
+    fn unpacks_gzip() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(b"rom bytes").unwrap();
+        let bytes = gz.finish().unwrap();
+
+        assert_eq!(unpack(&bytes).unwrap().as_ref(), b"rom bytes");
+    }
+
+    #[test]
+    fn passes_plain_roms_through() {
+        assert!(matches!(unpack(b"\xc3\x0c\x02"), Ok(Cow::Borrowed(_))));
+    }
+}
@@ -0,0 +1,223 @@
+// Importing save states from other emulators, best effort.
+// Reads the BESS ("Best Effort Save State") blocks SameBoy appends to every state it writes, and
+// that other emulators write too. BGB's own format is not documented, so its states only load
+// if they carry BESS blocks. A BESS state ends in
+//
+//     u32   offset of the first block in the file
+//     "BESS"
+//
+// and the blocks are a 4 letter name, a u32 length and the data, up to an END block. What is
+// used: CORE (registers, IO, and where RAM, VRAM, OAM and HRAM are in the file), INFO (title and
+// checksum of the ROM), MBC (MBC register writes to replay) and RTC (MBC3 clock, laid out like
+// the .sav footer, see sav.rs). Everything is little endian.
+// Only the state of the memory and registers comes over: PPU and APU timing, the timer's
+// internal counter and so on start from where the console was.
+
+use std::convert::TryInto;
+
+use super::dmg_cpu::RegisterSnapshot;
+use super::error::StateImportError;
+use super::sav::{Rtc, RtcRegisters};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignState {
+    pub regs: RegisterSnapshot,
+    pub halted: bool,
+    pub ie: u8,
+    pub io: Box<[u8]>, // 0xff00 - 0xff7f
+    pub wram: Box<[u8]>,
+    pub vram: Box<[u8]>,
+    pub cart_ram: Box<[u8]>,
+    pub oam: Box<[u8]>,
+    pub hram: Box<[u8]>,
+    pub mbc_writes: Vec<(u16, u8)>,
+    pub rtc: Option<Rtc>,
+    pub global_checksum: Option<u16>, // from INFO, if there was one
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn slice(&self, offset: usize, len: usize) -> Result<&'a [u8], StateImportError> {
+        offset.checked_add(len)
+            .and_then(|end| self.bytes.get(offset..end))
+            .ok_or(StateImportError::Truncated)
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, StateImportError> {
+        Ok(u32::from_le_bytes(self.slice(offset, 4)?.try_into().unwrap()))
+    }
+
+    // A buffer CORE points to, by its size and offset at descriptor
+    fn buffer(&self, descriptor: &[u8]) -> Result<Box<[u8]>, StateImportError> {
+        let size = u32::from_le_bytes(descriptor[0..4].try_into().unwrap()) as usize;
+        let offset = u32::from_le_bytes(descriptor[4..8].try_into().unwrap()) as usize;
+        Ok(self.slice(offset, size)?.into())
+    }
+}
+
+fn registers(core: &[u8]) -> RegisterSnapshot {
+    let pair = |offset: usize| (core[offset + 1], core[offset]);
+    let (a, f) = pair(0x0A);
+    let (b, c) = pair(0x0C);
+    let (d, e) = pair(0x0E);
+    let (h, l) = pair(0x10);
+    RegisterSnapshot {
+        a, f, b, c, d, e, h, l,
+        pc: u16::from_le_bytes([core[0x08], core[0x09]]),
+        sp: u16::from_le_bytes([core[0x12], core[0x13]]),
+        ime: core[0x14] != 0,
+    }
+}
+
+pub fn parse(bytes: &[u8]) -> Result<ForeignState, StateImportError> {
+    let file = Reader { bytes };
+    if bytes.len() < 8 || &bytes[bytes.len() - 4..] != b"BESS" {
+        return Err(StateImportError::NotBess);
+    }
+    let mut offset = file.u32(bytes.len() - 8)? as usize;
+    let mut state = None;
+    let mut global_checksum = None;
+    let mut mbc_writes = Vec::new();
+    let mut rtc = None;
+    loop {
+        let name = file.slice(offset, 4)?;
+        let len = file.u32(offset + 4)? as usize;
+        let block = file.slice(offset + 8, len)?;
+        match name {
+            b"CORE" => {
+                if block.len() < 0xD0 {
+                    return Err(StateImportError::Truncated);
+                }
+                let major = u16::from_le_bytes([block[0], block[1]]);
+                if major != 1 {
+                    return Err(StateImportError::UnsupportedVersion(major));
+                }
+                if block[4] != b'G' {
+                    let model = String::from_utf8_lossy(&block[4..8]).trim_end().to_string();
+                    return Err(StateImportError::UnsupportedModel(model));
+                }
+                state = Some(ForeignState {
+                    regs: registers(block),
+                    halted: block[0x16] == 1,
+                    ie: block[0x15],
+                    io: block[0x18..0x98].into(),
+                    wram: file.buffer(&block[0x98..0xA0])?,
+                    vram: file.buffer(&block[0xA0..0xA8])?,
+                    cart_ram: file.buffer(&block[0xA8..0xB0])?,
+                    oam: file.buffer(&block[0xB0..0xB8])?,
+                    hram: file.buffer(&block[0xB8..0xC0])?,
+                    mbc_writes: Vec::new(),
+                    rtc: None,
+                    global_checksum: None,
+                });
+            }
+            b"INFO" if block.len() >= 0x12 => global_checksum = Some(u16::from_be_bytes([block[0x10], block[0x11]])),
+            b"MBC " => {
+                mbc_writes = block.chunks_exact(3)
+                    .map(|write| (u16::from_le_bytes([write[0], write[1]]), write[2]))
+                    .collect();
+            }
+            b"RTC " if block.len() >= 0x30 => {
+                rtc = Some(Rtc {
+                    current: RtcRegisters::from_bytes(&block[0..20]),
+                    latched: RtcRegisters::from_bytes(&block[20..40]),
+                    timestamp: u64::from_le_bytes(block[40..48].try_into().unwrap()),
+                });
+            }
+            b"END " => break,
+            _ => {} // NAME, and whatever else gbrust has no use for
+        }
+        offset += 8 + len;
+    }
+    let mut state = state.ok_or(StateImportError::NoCore)?;
+    state.mbc_writes = mbc_writes;
+    state.rtc = rtc;
+    state.global_checksum = global_checksum;
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use super::super::cart::Cart;
+    use super::super::console::Console;
+
+    fn block(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = name.to_vec();
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    // A DMG state at 0x1234 with af 0x01b0, RAM filled with 0x11, VRAM with 0x22, OAM with 0x33
+    // and HRAM with 0x44
+    fn bess_state(global_checksum: u16) -> Vec<u8> {
+        let mut file = Vec::new();
+        let mut descriptors = Vec::new();
+        for &(size, fill) in &[(0x2000, 0x11), (0x2000, 0x22), (0, 0), (0xA0, 0x33), (0x7F, 0x44), (0, 0), (0, 0)] {
+            descriptors.extend((size as u32).to_le_bytes());
+            descriptors.extend((file.len() as u32).to_le_bytes());
+            file.resize(file.len() + size, fill);
+        }
+        let first_block = file.len();
+        let mut core = vec![1, 0, 1, 0];
+        core.extend_from_slice(b"GD  ");
+        for &reg in &[0x1234u16, 0x01B0, 0x0013, 0x00D8, 0x014D, 0xFFFE] {
+            core.extend(reg.to_le_bytes());
+        }
+        core.extend_from_slice(&[1, 0x05, 0, 0]); // ime, ie, running
+        let mut io = vec![0; 0x80];
+        io[0x40] = 0x91; // LCDC
+        io[0x42] = 0x17; // SCY
+        core.extend(io);
+        core.extend(descriptors);
+
+        let mut info = vec![b'T'; 0x10];
+        info.extend(global_checksum.to_be_bytes());
+        file.extend(block(b"NAME", b"SameBoy v0.16"));
+        file.extend(block(b"INFO", &info));
+        file.extend(block(b"CORE", &core));
+        file.extend(block(b"MBC ", &[0x00, 0x20, 0x03]));
+        file.extend(block(b"END ", &[]));
+        file.extend((first_block as u32).to_le_bytes());
+        file.extend_from_slice(b"BESS");
+        file
+    }
+
+    #[test]
+    fn imports_into_a_console() {
+        let mut console = Console::new(Cart::new(fs::read("tetris.gb").unwrap().into_boxed_slice(), None).unwrap());
+        let checksum = console.cart().global_checksum();
+        assert_eq!(console.import_state(&bess_state(checksum ^ 1)), Err(StateImportError::WrongRom));
+
+        console.import_state(&bess_state(checksum)).unwrap();
+        let regs = console.registers();
+        assert_eq!((regs.pc, regs.bc(), regs.hl()), (0x1234, 0x0013, 0x014D));
+        let io = console.io_registers();
+        assert_eq!((io.get(0xFF40), io.get(0xFF42), io.get(0xFFFF)), (0x91, 0x17, 0x05));
+        let memory = console.memory_snapshot();
+        assert_eq!((memory.read(0xC123), memory.read(0x8000), memory.read(0xFE9F), memory.read(0xFF80)),
+                   (0x11, 0x22, 0x33, 0x44));
+    }
+
+    #[test]
+    fn reads_bess_blocks() {
+        let state = parse(&bess_state(0xBEEF)).unwrap();
+        assert_eq!((state.regs.pc, state.regs.af(), state.regs.sp), (0x1234, 0x01B0, 0xFFFE));
+        assert!(state.regs.ime);
+        assert_eq!((state.ie, state.io[0x40]), (0x05, 0x91));
+        assert_eq!((state.wram.len(), state.wram[0x100], state.vram[0]), (0x2000, 0x11, 0x22));
+        assert_eq!((state.oam[0x9F], state.hram[0]), (0x33, 0x44));
+        assert_eq!(state.mbc_writes, [(0x2000, 0x03)]);
+        assert_eq!(state.global_checksum, Some(0xBEEF));
+
+        assert!(matches!(parse(b"not a state"), Err(StateImportError::NotBess)));
+        let mut truncated = bess_state(0);
+        truncated.drain(0x100..0x200);
+        assert!(parse(&truncated).is_err());
+    }
+}
@@ -1,20 +1,52 @@
 // Cartridge file!!
 // Handles all reading files
 // use std::fmt;  Unused for now, removed to not incur the wrath of compiler
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Debug;
+use std::fs;
+use std::path::Path;
 use std::string::String;
+use serde::{Deserialize, Serialize};
+use super::archive;
+use super::header::header_checksum;
+use super::patch;
+use super::romdb::{DatEntry, RomDb, RomHashes};
 use super::mbc::mbc_properties::{MbcType, MbcInfo, RamInfo, Mbc};
+use super::state::{StateError, StateReader, StateWriter};
+use super::error::{BusError, CartError};
+use super::sav::{Rtc, SaveFile};
 
 pub struct Cart {
     program: Box<[u8]>,
-    mbc: Box<Mbc>, // Box because Mbc is a trait, no box = need dynamic typing
+    mbc: Box<dyn Mbc>, // Box because Mbc is a trait, no box = need dynamic typing
+    dat_entry: Option<DatEntry>, // set by identify
+    save_dirty: bool, // RAM or clock written since the battery save was last flushed
 }
 
 #[derive(Debug)]
 pub enum DestinationCode {
     Japanese,
     NonJapanese,
+    Unknown(u8),
+}
+
+// Everything up to and including the global checksum
+const HEADER_END: usize = 0x0150;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+
+// Sizes a cartridge RAM can have, see get_ram_size
+const RAM_SIZES: [u32; 6] = [0, 1024 * 2, 1024 * 8, 1024 * 32, 1024 * 64, 1024 * 128];
+
+// Replaces what the header says about the cartridge hardware, for carts whose header is wrong
+// and would otherwise lose their saves. Looked up by the ROM's SHA-1 (lowercase hex) in the
+// config's cart_overrides.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CartOverride {
+    pub cart_type: Option<u8>, // header code at 0x0147, e.g. 0x03 for MBC1 + RAM + battery
+    pub ram_size: Option<u32>, // in bytes
 }
 
 // will be more in the future
@@ -23,15 +55,115 @@ pub enum CartType {
 }
 
 impl Cart {
-    pub fn new(program: Box<[u8]>, ram: Option<Box<[u8]>>) -> Self {
-        let mbc_info = Cart::get_mbc_info(&program);
-        let boxed_mbc = super::mbc::mbc_properties::new_mbc(mbc_info, ram);
-        Cart {
+    pub fn new(program: Box<[u8]>, ram: Option<Box<[u8]>>) -> Result<Self, CartError> {
+        Cart::with_overrides(program, ram, &BTreeMap::new())
+    }
+
+    // Same as new, with the user's header overrides
+    pub fn with_overrides(program: Box<[u8]>, ram: Option<Box<[u8]>>, overrides: &BTreeMap<String, CartOverride>)
+                          -> Result<Self, CartError> {
+        if program.is_empty() {
+            return Err(CartError::NoRom);
+        }
+        if program.len() < HEADER_END {
+            return Err(CartError::TooSmall(program.len()));
+        }
+        let rom_banks = Cart::rom_size(&program)? as usize / ROM_BANK_SIZE;
+
+        let mbc_info = match Cart::find_override(&program, overrides) {
+            Some(cart_override) => {
+                Cart::overridden_mbc_info(cart_override, program[0x0147], Cart::get_ram_size(&program))?
+            }
+            None => Cart::get_mbc_info(&program)?,
+        };
+        let boxed_mbc = super::mbc::mbc_properties::new_mbc(mbc_info, rom_banks, ram)?;
+        Ok(Cart {
             program: program,
             mbc: boxed_mbc,
+            dat_entry: None,
+            save_dirty: false,
+        })
+    }
+
+    fn find_override(program: &[u8], overrides: &BTreeMap<String, CartOverride>) -> Option<CartOverride> {
+        if overrides.is_empty() {
+            return None; // spares hashing the ROM
         }
+        overrides.get(&RomHashes::of(program).sha1_hex()).copied()
+    }
+
+    // header_ram_size only matters when the RAM size is not overridden
+    fn overridden_mbc_info(cart_override: CartOverride, header_cart_type: u8, header_ram_size: Result<u32, CartError>)
+                           -> Result<MbcInfo, CartError> {
+        info!(target: "gbrust::mbc", "header overridden: {:?}", cart_override);
+        let cart_type = cart_override.cart_type.unwrap_or(header_cart_type);
+        let ram_size = match cart_override.ram_size {
+            Some(size) if RAM_SIZES.contains(&size) => size,
+            Some(size) => return Err(CartError::InvalidRamOverride(size)),
+            None => header_ram_size?,
+        };
+        let ram_info = match ram_size {
+            0 => None,
+            size => Some(RamInfo::new(size, (size / (1024 * 8)).max(1))),
+        };
+        Cart::mbc_info(cart_type, ram_info)
+    }
+
+    // ROM bytes from a file's contents: unzipped (see archive.rs), then patched (see patch.rs)
+    pub fn unpack(bytes: &[u8], patch: Option<&[u8]>) -> Result<Box<[u8]>, CartError> {
+        let program = archive::unpack(bytes)?;
+        Ok(match patch {
+            Some(patch) => patch::apply(&program, &archive::unpack(patch)?)?.into_boxed_slice(),
+            None => program.into_owned().into_boxed_slice(),
+        })
     }
 
+    // Loads a ROM file as-is, or the ROM inside a .zip / .gz (needs the `archive` feature)
+    pub fn from_path<P: AsRef<Path>>(path: P, ram: Option<Box<[u8]>>) -> Result<Self, CartError> {
+        let bytes = fs::read(path)?;
+        Cart::from_bytes(&bytes, ram)
+    }
+
+    // Same as from_path, for ROMs that are already in memory
+    pub fn from_bytes(bytes: &[u8], ram: Option<Box<[u8]>>) -> Result<Self, CartError> {
+        Cart::new(Cart::unpack(bytes, None)?, ram)
+    }
+
+    // Loads a ROM and applies an IPS or BPS patch to it (see patch.rs). Either may be zipped.
+    pub fn from_path_with_patch<P: AsRef<Path>, Q: AsRef<Path>>(path: P, patch_path: Q, ram: Option<Box<[u8]>>)
+                                                                 -> Result<Self, CartError> {
+        let bytes = fs::read(path)?;
+        let patch = fs::read(patch_path)?;
+        Cart::from_bytes_with_patch(&bytes, &patch, ram)
+    }
+
+    pub fn from_bytes_with_patch(bytes: &[u8], patch: &[u8], ram: Option<Box<[u8]>>) -> Result<Self, CartError> {
+        Cart::new(Cart::unpack(bytes, Some(patch))?, ram)
+    }
+
+
+    // CRC32 and SHA-1 of the whole ROM, hashed on every call
+    pub fn hashes(&self) -> RomHashes {
+        RomHashes::of(&self.program)
+    }
+
+    // Looks the ROM up in a No-Intro DAT (see romdb.rs) and remembers what it found
+    pub fn identify(&mut self, db: &RomDb) -> Option<&DatEntry> {
+        self.dat_entry = db.lookup(&self.hashes()).cloned();
+        self.dat_entry.as_ref()
+    }
+
+    pub fn dat_entry(&self) -> Option<&DatEntry> {
+        self.dat_entry.as_ref()
+    }
+
+    // The DAT's name for the game once identified, otherwise the header title
+    pub fn canonical_title(&self) -> String {
+        match self.dat_entry {
+            Some(ref entry) => entry.name.clone(),
+            None => self.get_title(),
+        }
+    }
 
     pub fn get_logo(&self) -> &[u8] {
         let slice = &self.program[0x0104..0x0133];
@@ -40,43 +172,50 @@ impl Cart {
         
 
     pub fn get_title(&self) -> String {     // title lies at 0x0134 - 0x0143
-        let mut title = Vec::new();
-        for i in 0x0134..0x0143 {
-            title.push(self.program[i]);
-        }
-        String::from_utf8(title).unwrap()
+        // Padded with NULs, and not always ASCII on homebrew
+        let title = &self.program[0x0134..0x0143];
+        String::from_utf8_lossy(title).trim_end_matches('\0').to_string()
     }
 
-    pub fn get_mbc_info(program: &Box<[u8]>) -> MbcInfo {
-        let ram_size = Cart::get_ram_size(program);
+    pub fn get_mbc_info(program: &[u8]) -> Result<MbcInfo, CartError> {
+        let ram_size = Cart::get_ram_size(program)?;
         let ram_info = if ram_size == 0 {
             None 
         } else {
             Some(
-                RamInfo::new(ram_size, Cart::ram_bank_count(program))
+                RamInfo::new(ram_size, Cart::ram_bank_count(program)?)
             )
         };
 
-        match program[0x0147] {
-            0x00 => MbcInfo::new(MbcType::None, ram_info, false),
-            0x01 => MbcInfo::new(MbcType::Mbc1, ram_info, false),
-            0x02 => MbcInfo::new(MbcType::Mbc1, ram_info, false),
-            0x03 => MbcInfo::new(MbcType::Mbc1, ram_info, true),
-            0x05 => MbcInfo::new(MbcType::Mbc2, ram_info, false),
-            0x06 => MbcInfo::new(MbcType::Mbc2, ram_info, true),
-            0x0F => MbcInfo::new(MbcType::Mbc3, ram_info, true),
-            0x10 => MbcInfo::new(MbcType::Mbc3, ram_info, true),
-            0x11 => MbcInfo::new(MbcType::Mbc3, ram_info, false),
+        Cart::mbc_info(program[0x0147], ram_info)
+    }
+
+    fn mbc_info(cart_type: u8, ram_info: Option<RamInfo>) -> Result<MbcInfo, CartError> {
+        Ok(match cart_type {
+            0x00 => MbcInfo::new(MbcType::None, cart_type, ram_info, false),
+            0x01 => MbcInfo::new(MbcType::Mbc1, cart_type, ram_info, false),
+            0x02 => MbcInfo::new(MbcType::Mbc1, cart_type, ram_info, false),
+            0x03 => MbcInfo::new(MbcType::Mbc1, cart_type, ram_info, true),
+            0x05 => MbcInfo::new(MbcType::Mbc2, cart_type, ram_info, false),
+            0x06 => MbcInfo::new(MbcType::Mbc2, cart_type, ram_info, true),
+            0x0F => MbcInfo::new(MbcType::Mbc3, cart_type, ram_info, true),
+            0x10 => MbcInfo::new(MbcType::Mbc3, cart_type, ram_info, true),
+            0x11 => MbcInfo::new(MbcType::Mbc3, cart_type, ram_info, false),
             // For mbc5
             //0x00 => MbcInfo::new(MbcType::None, ram_info, false),
             //0x00 => MbcInfo::new(MbcType::None, ram_info, false),
             //0x00 => MbcInfo::new(MbcType::None, ram_info, false),
-            _ => panic!("Haven't developed MBCs yet!"),
-        }
+            _ => return Err(CartError::UnsupportedMbc(cart_type)),
+        })
     }
 
+    // The header was checked in new, so this cannot fail on a constructed cart
     pub fn get_rom_size(&self) -> u32 {
-        match self.program[0x0148] {
+        Cart::rom_size(&self.program).unwrap_or(0)
+    }
+
+    pub(crate) fn rom_size(program: &[u8]) -> Result<u32, CartError> {
+        Ok(match program[0x0148] {
             0x00 => 1024 * 32,
             0x01 => 1024 * 64,
             0x02 => 1024 * 128,
@@ -86,8 +225,27 @@ impl Cart {
             0x06 => 1024 * 1024 * 2,
             0x07 => 1024 * 1024 * 4,
             0x08 => 1024 * 1024 * 8,
-            _ => panic!("Invalid ROM size"),
+            code => return Err(CartError::InvalidRomSize(code)),
+        })
+    }
+
+    // The 16 KiB of ROM bank bank, if the ROM has it
+    pub fn rom_bank_bytes(&self, bank: usize) -> Option<&[u8]> {
+        self.program.chunks(ROM_BANK_SIZE).nth(bank)
+    }
+
+    // Puts bytes over the start of ROM bank bank while the game runs, see hotload.rs. The rest
+    // of the bank keeps what it had.
+    pub fn write_rom_bank(&mut self, bank: usize, bytes: &[u8]) -> Result<(), CartError> {
+        let start = bank * ROM_BANK_SIZE;
+        if start >= self.program.len() {
+            return Err(CartError::NoSuchBank(bank));
+        }
+        if bytes.len() > ROM_BANK_SIZE.min(self.program.len() - start) {
+            return Err(CartError::BankOverflow(bytes.len()));
         }
+        self.program[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
     }
 
     pub fn rom_bank_count(&self) -> u32 {
@@ -99,67 +257,183 @@ impl Cart {
     }
     
     // Do not take in &self as this is needed for initialisation
-    pub fn get_ram_size(program: &Box<[u8]>) -> u32 {
-        match program[0x0149] {
+    pub fn get_ram_size(program: &[u8]) -> Result<u32, CartError> {
+        Cart::ram_size_of(program[0x0149])
+    }
+
+    // The size a RAM size code (0x0149) stands for
+    pub(crate) fn ram_size_of(code: u8) -> Result<u32, CartError> {
+        Ok(match code {
             0 => 0,
             1 => 1024 * 2,
             2 => 1024 * 8,
             3 => 1024 * 32,
             4 => 1024 * 128, // in program
             5 => 1024 * 64,
-            _ => panic!("Unsupported ram size: {:x}", program[0x0149]),
-        }
+            code => return Err(CartError::InvalidRamSize(code)),
+        })
     }
 
     // Do not take in &self as this is needed for initialisation
-    pub fn ram_bank_count(program: &Box<[u8]>) -> u32 {
-        let ram_size = Cart::get_ram_size(program) / 1024; // number of kb
+    pub fn ram_bank_count(program: &[u8]) -> Result<u32, CartError> {
+        let ram_size = Cart::get_ram_size(program)? / 1024; // number of kb
 
         match ram_size {
-            0 => 0,
-            2..=8 => 1,
-            32..=128 => ram_size / 8,
-            _ => panic!("Invalid ram size: {} kb", ram_size),
+            0 => Ok(0),
+            2..=8 => Ok(1),
+            32..=128 => Ok(ram_size / 8),
+            _ => Err(CartError::InvalidRamSize(program[0x0149])),
         }
     }
 
     pub fn get_dest(&self) -> DestinationCode {
-        match self.program[0x014A] {
+        Cart::destination(self.program[0x014A])
+    }
+
+    pub(crate) fn destination(code: u8) -> DestinationCode {
+        match code {
             0 => DestinationCode::Japanese,
             1 => DestinationCode::NonJapanese,
-            _ => panic!("Unknown destination"),
+            code => DestinationCode::Unknown(code),
         }
     }
 
     pub fn check_sum(&self) -> bool {
-        let default = self.program[0x014D];
+        header_checksum(&self.program) == self.program[0x014D]
+    }
+
+    // Big-endian sum of all ROM bytes stored in the header (0x014E - 0x014F).
+    // Not checked by the hardware, but good enough to tell ROMs apart.
+    pub fn global_checksum(&self) -> u16 {
+        ((self.program[0x014E] as u16) << 8) | (self.program[0x014F] as u16)
+    }
 
-        let mut x: i16 = 0;
-        let final_x: u8;
-        for i in 0x0134..0x014C {
-            x = x - (self.program[i] as i16) - 1;
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.mbc.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.save_dirty = true;
+        self.mbc.load_state(state)
+    }
+
+    // Back to power on, keeping the RAM
+    pub fn reset(&mut self) {
+        self.mbc.reset();
+    }
+
+    pub fn rom_bank(&self) -> usize {
+        self.mbc.rom_bank()
+    }
+
+    // ROM bank mapped at addr (0x0000 - 0x7FFF), which for 0x0000 - 0x3FFF is not always bank 0
+    pub fn rom_bank_at(&self, addr: u16) -> usize {
+        self.mbc.rom_bank_at(addr)
+    }
+
+    // Whether gbrust has the MBC a cart type code asks for
+    pub fn is_supported(cart_type: u8) -> bool {
+        Cart::mbc_info(cart_type, None).is_ok()
+    }
+
+    // Header code at 0x0147, see cart_type_name
+    pub fn cart_type(&self) -> u8 {
+        self.program[0x0147]
+    }
+
+    // The hardware a cart type code stands for, as PanDocs lists them
+    pub fn cart_type_name(code: u8) -> &'static str {
+        match code {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0B => "MMM01",
+            0x0C => "MMM01+RAM",
+            0x0D => "MMM01+RAM+BATTERY",
+            0x0F => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1A => "MBC5+RAM",
+            0x1B => "MBC5+RAM+BATTERY",
+            0x1C => "MBC5+RUMBLE",
+            0x1D => "MBC5+RUMBLE+RAM",
+            0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+            0x20 => "MBC6",
+            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            0xFC => "POCKET CAMERA",
+            0xFD => "BANDAI TAMA5",
+            0xFE => "HuC3",
+            0xFF => "HuC1+RAM+BATTERY",
+            _ => "unknown",
         }
-        final_x = ((x as u16) & 0x00FF) as u8;
-        
-        final_x == default
     }
 
-    pub fn read(&self, addr: u16) -> u8 {
+    // In bytes, as the header has it
+    pub fn ram_size(&self) -> u32 {
+        Cart::get_ram_size(&self.program).unwrap_or(0)
+    }
+
+    // MBC3 + TIMER (+ RAM) + BATTERY
+    pub fn has_rtc(&self) -> bool {
+        matches!(self.program[0x0147], 0x0F | 0x10)
+    }
+
+    // The battery save, RAM and clock. The clock is stamped with the time its registers are as
+    // of, for whoever loads it to run it on from. None when there is neither.
+    pub fn save_file(&self) -> Option<SaveFile> {
+        let rtc = self.mbc.rtc().filter(|_| self.has_rtc());
+        let ram = self.mbc.copy_ram();
+        if ram.is_none() && rtc.is_none() {
+            return None;
+        }
+        Some(SaveFile { ram: ram.unwrap_or_default(), rtc })
+    }
+
+    pub fn set_rtc(&mut self, rtc: &Rtc) {
+        self.save_dirty = true;
+        self.mbc.set_rtc(rtc);
+    }
+
+    // Runs the cartridge's clock, if it has one, on by cycles
+    pub fn cycle_flush(&mut self, cycles: u32) {
+        self.mbc.cycle_flush(cycles);
+    }
+
+    // Whether the battery save has changed since mark_saved, so flushing it is worth a write
+    pub fn is_save_dirty(&self) -> bool {
+        self.save_dirty
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.save_dirty = false;
+    }
+
+    pub fn read(&self, addr: u16) -> Result<u8, BusError> {
         // Change to support MBC
         //self.program[addr as usize]
         self.mbc.read_rom(&self.program, addr)
     }
 
-    pub fn write(&mut self, addr: u16, val: u8) {
-        self.mbc.write_rom(addr, val);
+    pub fn write(&mut self, addr: u16, val: u8) -> Result<(), BusError> {
+        self.mbc.write_rom(addr, val)
     }
 
-    pub fn read_ram(&self, addr: u16) -> u8 {
+    pub fn read_ram(&self, addr: u16) -> Result<u8, BusError> {
         self.mbc.read_ram(addr)
     }
 
-    pub fn write_ram(&mut self, addr: u16, val: u8) {
-        self.mbc.write_ram(addr, val);
+    pub fn write_ram(&mut self, addr: u16, val: u8) -> Result<(), BusError> {
+        self.mbc.write_ram(addr, val)?;
+        self.save_dirty = true;
+        Ok(())
     }
 }
 
@@ -168,12 +442,14 @@ impl Debug for Cart {
         write!(f,
                "Cart {{
                     title: {},
+                    canonical_title: {},
                     size: {:?},
                     destination_code: {:?},
                     
                     rom_bank_count: {}
                 }}",
                self.get_title(),
+               self.canonical_title(),
                self.get_rom_size(),
                self.get_dest(),
                //self.mbc,
@@ -181,3 +457,170 @@ impl Debug for Cart {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sav::{unix_time, RtcRegisters};
+    use super::super::apu::CPU_CLOCK;
+
+    fn rom_with_header(cart_type: u8, rom_size: u8, ram_size: u8) -> Box<[u8]> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = cart_type;
+        rom[0x0148] = rom_size;
+        rom[0x0149] = ram_size;
+        rom.into_boxed_slice()
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(Cart::new(vec![].into_boxed_slice(), None), Err(CartError::NoRom)));
+        assert!(matches!(Cart::new(vec![0; 0x100].into_boxed_slice(), None), Err(CartError::TooSmall(0x100))));
+        assert!(matches!(Cart::new(rom_with_header(0xFC, 0, 0), None), Err(CartError::UnsupportedMbc(0xFC))));
+        assert!(matches!(Cart::new(rom_with_header(0, 0x42, 0), None), Err(CartError::InvalidRomSize(0x42))));
+        assert!(matches!(Cart::new(rom_with_header(0x03, 0, 0x09), None), Err(CartError::InvalidRamSize(0x09))));
+        assert!(matches!(Cart::new(rom_with_header(0x03, 0, 0x02), Some(vec![0; 3].into_boxed_slice())),
+                         Err(CartError::RamSizeMismatch { expected: 0x2000, actual: 3 })));
+    }
+
+    #[test]
+    fn save_files_carry_the_clock() {
+        let mut cart = Cart::new(rom_with_header(0x10, 0, 0x03), Some(vec![5; 0x8000].into_boxed_slice())).unwrap();
+        cart.write(0x0000, 0x0A).unwrap(); // RAM and clock enable
+        cart.write(0x4000, 0x0C).unwrap();
+        cart.write_ram(0xA000, 0x40).unwrap(); // halted, so it stays at what is written
+        cart.write(0x4000, 0x08).unwrap(); // seconds
+        cart.write_ram(0xA000, 0x7B).unwrap(); // masked to 0x3b
+        cart.write(0x6000, 0x00).unwrap();
+        cart.write(0x6000, 0x01).unwrap(); // latch
+
+        let save = cart.save_file().unwrap();
+        assert_eq!(save.ram.len(), 0x8000);
+        let rtc = save.rtc.unwrap();
+        assert_eq!((rtc.current.sec, rtc.latched.sec), (0x3B, 0x3B));
+
+        let mut restored = Cart::new(rom_with_header(0x10, 0, 0x03), Some(save.ram.clone())).unwrap();
+        restored.set_rtc(&rtc);
+        assert_eq!(restored.save_file().unwrap().rtc.map(|rtc| rtc.current), Some(rtc.current));
+
+        // Plain MBC3 and ROM only carts have no clock
+        assert_eq!(Cart::new(rom_with_header(0x11, 0, 0), None).unwrap().save_file(), None);
+        assert_eq!(Cart::new(rom_with_header(0x00, 0, 0), None).unwrap().save_file(), None);
+    }
+
+    // The latched clock, sec min hrs days_lo days_hi
+    fn latch(cart: &mut Cart) -> Vec<u8> {
+        cart.write(0x0000, 0x0A).unwrap();
+        cart.write(0x6000, 0x00).unwrap();
+        cart.write(0x6000, 0x01).unwrap();
+        (0x08..=0x0C).map(|register| {
+            cart.write(0x4000, register).unwrap();
+            cart.read_ram(0xA000).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn clocks_run_on_from_their_timestamp() {
+        // Saved 15 seconds ago at 23:59:50 on day 511, so the day counter has overflowed
+        let mut cart = Cart::new(rom_with_header(0x0F, 0, 0), None).unwrap();
+        let current = RtcRegisters { sec: 50, min: 59, hrs: 23, days_lo: 0xFF, days_hi: 0x01 };
+        cart.set_rtc(&Rtc { current, latched: RtcRegisters::default(), timestamp: unix_time() - 15 });
+        let clock = latch(&mut cart);
+        assert!((5..=6).contains(&clock[0]), "{:?}", clock);
+        assert_eq!(clock[1..], [0, 0, 0, 0x80]);
+
+        // Save states keep the clock as it was, with no time made up
+        let registers = |cart: &Cart| cart.save_file().unwrap().rtc.map(|rtc| (rtc.current, rtc.latched));
+        let mut state = StateWriter::new();
+        cart.save_state(&mut state);
+        let state = state.finish();
+        let mut restored = Cart::new(rom_with_header(0x0F, 0, 0), None).unwrap();
+        restored.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+        assert_eq!(registers(&restored), registers(&cart));
+
+        // Halted, no time passes
+        let halted = RtcRegisters { sec: 1, days_hi: 0x40, ..RtcRegisters::default() };
+        cart.set_rtc(&Rtc { current: halted, latched: halted, timestamp: unix_time() - 1000 });
+        assert_eq!(latch(&mut cart), [1, 0, 0, 0, 0x40]);
+
+        // A clock with no time runs on from where it is
+        cart.set_rtc(&Rtc { current: RtcRegisters::default(), latched: RtcRegisters::default(), timestamp: 0 });
+        assert_eq!(latch(&mut cart)[0], 0);
+    }
+
+    #[test]
+    fn clocks_run_on_emulated_time() {
+        let mut cart = Cart::new(rom_with_header(0x0F, 0, 0), None).unwrap();
+        cart.set_rtc(&Rtc { current: RtcRegisters::default(), latched: RtcRegisters::default(), timestamp: 0 });
+        cart.cycle_flush(CPU_CLOCK - 1);
+        assert_eq!(latch(&mut cart)[0], 0);
+        cart.cycle_flush(1);
+        cart.cycle_flush(CPU_CLOCK * 2);
+        assert_eq!(latch(&mut cart)[0], 3);
+
+        // Halted, or for a second after the game sets the seconds, it does not tick
+        cart.write(0x4000, 0x0C).unwrap();
+        cart.write_ram(0xA000, 0x40).unwrap();
+        cart.cycle_flush(CPU_CLOCK * 5);
+        assert_eq!(latch(&mut cart)[0], 3);
+        cart.write(0x4000, 0x0C).unwrap();
+        cart.write_ram(0xA000, 0x00).unwrap();
+        cart.cycle_flush(CPU_CLOCK / 2);
+        cart.write(0x4000, 0x08).unwrap();
+        cart.write_ram(0xA000, 10).unwrap();
+        cart.cycle_flush(CPU_CLOCK - 1);
+        assert_eq!(latch(&mut cart)[0], 10);
+    }
+
+    #[test]
+    fn overrides_fix_wrong_headers() {
+        // Claims to have no RAM, but really is MBC1 with 8KB of battery RAM
+        let rom = rom_with_header(0x00, 0, 0);
+        let mut overrides = BTreeMap::new();
+        overrides.insert(RomHashes::of(&rom).sha1_hex(), CartOverride { cart_type: Some(0x03), ram_size: Some(0x2000) });
+        let mut cart = Cart::with_overrides(rom.clone(), Some(vec![7; 0x2000].into_boxed_slice()), &overrides).unwrap();
+        cart.write(0x0000, 0x0A).unwrap(); // RAM enable
+        assert_eq!(cart.read_ram(0xA000), Ok(7));
+
+        overrides.values_mut().for_each(|o| o.ram_size = Some(3));
+        assert!(matches!(Cart::with_overrides(rom, None, &overrides), Err(CartError::InvalidRamOverride(3))));
+    }
+
+    #[test]
+    fn large_mbc1_carts_bank_the_first_16k_in_mode_1() {
+        // Every bank starts with its number
+        let banked = |size: usize, size_code: u8| {
+            let mut rom = vec![0; size];
+            for (bank, bytes) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+                bytes[0] = bank as u8;
+            }
+            rom[0x0147] = 0x01;
+            rom[0x0148] = size_code;
+            Cart::new(rom.into_boxed_slice(), None).unwrap()
+        };
+
+        let mut cart = banked(0x10_0000, 0x05); // 1 MiB
+        cart.write(0x4000, 0x01).unwrap(); // bits 5-6 of the bank
+        assert_eq!((cart.rom_bank_at(0x0000), cart.read(0x0000)), (0x00, Ok(0x00)));
+        assert_eq!((cart.rom_bank_at(0x4000), cart.read(0x4000)), (0x21, Ok(0x21)));
+        cart.write(0x6000, 0x01).unwrap(); // mode 1
+        assert_eq!((cart.rom_bank_at(0x0000), cart.read(0x0000)), (0x20, Ok(0x20)));
+        assert_eq!((cart.rom_bank_at(0x3FFF), cart.rom_bank_at(0x7FFF)), (0x20, 0x21));
+
+        // Smaller carts bank RAM with the same bits, and keep bank 0 there
+        let mut cart = banked(0x8_0000, 0x04); // 512 KiB
+        cart.write(0x4000, 0x01).unwrap();
+        cart.write(0x6000, 0x01).unwrap();
+        assert_eq!((cart.rom_bank_at(0x0000), cart.read(0x0000)), (0x00, Ok(0x00)));
+        assert_eq!((cart.rom_bank_at(0x4000), cart.read(0x4000)), (0x01, Ok(0x01)));
+    }
+
+    #[test]
+    fn out_of_range_banks_are_errors() {
+        // 32KB dump that claims 64KB: bank 3 does not exist
+        let mut cart = Cart::new(rom_with_header(0x01, 0x01, 0), None).unwrap();
+        cart.write(0x2000, 3).unwrap();
+        assert!(matches!(cart.read(0x4000), Err(BusError::RomOutOfRange { addr: 0x4000, .. })));
+        assert_eq!(cart.read_ram(0xA000), Err(BusError::NoExternalRam { addr: 0xA000 }));
+    }
+}
@@ -0,0 +1,167 @@
+// Finding the first frame where two runs of the same game part ways, to check that a change to
+// the core does not change what games do.
+// Two consoles can run side by side in this process, e.g. at two accuracy levels, with
+// compare_consoles. To compare two builds, one build writes a trace and the other runs against
+// it with compare_trace. Both runs get their input from the same movie (see movie.rs).
+// A trace has one line per frame, all numbers hex except the frame:
+//
+//     frame crc pc sp af bc de hl ime
+//
+// crc is the CRC-32 of the frame's pixels, and the registers are as the frame ended. Unlike the
+// hashes check_state uses, it is the same on every build and platform.
+
+use std::fmt;
+
+use super::console::{Console, Frame, VideoSink};
+use super::dmg_cpu::RegisterSnapshot;
+use super::error::TraceParseError;
+use super::movie::Movie;
+use super::patch::crc32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameRecord {
+    pub frame: u64,
+    pub crc: u32,
+    pub regs: RegisterSnapshot,
+}
+
+impl fmt::Display for FrameRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.regs;
+        write!(f, "{} {:08x} {:04x} {:04x} {:04x} {:04x} {:04x} {:04x} {}",
+               self.frame, self.crc, r.pc, r.sp, r.af(), r.bc(), r.de(), r.hl(), r.ime as u8)
+    }
+}
+
+impl FrameRecord {
+    pub fn parse(line: &str) -> Option<FrameRecord> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() != 9 {
+            return None;
+        }
+        let hex = |word: &str| u16::from_str_radix(word, 16).ok();
+        let pair = |word: &str| hex(word).map(|value| ((value >> 8) as u8, value as u8));
+        let (a, f) = pair(words[4])?;
+        let (b, c) = pair(words[5])?;
+        let (d, e) = pair(words[6])?;
+        let (h, l) = pair(words[7])?;
+        let ime = match words[8] {
+            "0" => false,
+            "1" => true,
+            _ => return None,
+        };
+        Some(FrameRecord {
+            frame: words[0].parse().ok()?,
+            crc: u32::from_str_radix(words[1], 16).ok()?,
+            regs: RegisterSnapshot { a, f, b, c, d, e, h, l, sp: hex(words[3])?, pc: hex(words[2])?, ime },
+        })
+    }
+}
+
+// Where two runs first differ
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub left: FrameRecord,
+    pub right: FrameRecord,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = if self.left.crc != self.right.crc { "picture" } else { "CPU state" };
+        writeln!(f, "{} differs from frame {}", what, self.left.frame)?;
+        writeln!(f, "        frame crc      pc   sp   af   bc   de   hl   ime")?;
+        writeln!(f, "left:   {}", self.left)?;
+        write!(f, "right:  {}", self.right)
+    }
+}
+
+// CRC32 of the frame's pixels, also the hashes reference.rs checks frames against
+pub(crate) struct CrcSink(pub(crate) u32);
+
+impl VideoSink for CrcSink {
+    fn frame_available(&mut self, frame: &Frame) {
+        let bytes: Vec<u8> = frame.rows().flatten().flat_map(|pixel| pixel.to_le_bytes()).collect();
+        self.0 = crc32(&bytes);
+    }
+}
+
+// Runs frame `frame` of the movie
+pub fn run_frame(console: &mut Console, movie: &Movie, frame: u64) -> FrameRecord {
+    movie.apply(frame, console);
+    let mut sink = CrcSink(0);
+    console.advance_frame(&mut sink);
+    FrameRecord { frame, crc: sink.0, regs: console.registers() }
+}
+
+pub fn trace(console: &mut Console, movie: &Movie, frames: u64) -> Vec<FrameRecord> {
+    (0..frames).map(|frame| run_frame(console, movie, frame)).collect()
+}
+
+pub fn trace_to_text(trace: &[FrameRecord]) -> String {
+    trace.iter().map(|record| format!("{}\n", record)).collect()
+}
+
+pub fn parse_trace(text: &str) -> Result<Vec<FrameRecord>, TraceParseError> {
+    text.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| FrameRecord::parse(line).ok_or(TraceParseError(index + 1)))
+        .collect()
+}
+
+// Runs both for up to `frames` frames, stopping at the first that differs
+pub fn compare_consoles(left: &mut Console, right: &mut Console, movie: &Movie, frames: u64)
+                        -> Option<Mismatch> {
+    (0..frames).find_map(|frame| {
+        let (left, right) = (run_frame(left, movie, frame), run_frame(right, movie, frame));
+        if left != right { Some(Mismatch { left, right }) } else { None }
+    })
+}
+
+// Runs console for as long as the trace goes, as the left side, stopping at the first frame
+// that differs
+pub fn compare_trace(console: &mut Console, movie: &Movie, trace: &[FrameRecord]) -> Option<Mismatch> {
+    trace.iter().find_map(|&right| {
+        let left = run_frame(console, movie, right.frame);
+        if left != right { Some(Mismatch { left, right }) } else { None }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use super::super::cart::Cart;
+    use super::super::config::AccuracyLevel;
+
+    fn tetris() -> Console {
+        let rom = fs::read("tetris.gb").unwrap().into_boxed_slice();
+        Console::new(Cart::new(rom, None).unwrap())
+    }
+
+    #[test]
+    fn traces_round_trip_and_match() {
+        let movie = Movie::parse("0\n100 start\n106").unwrap();
+        let recorded = trace(&mut tetris(), &movie, 150);
+        let text = trace_to_text(&recorded);
+        assert_eq!(parse_trace(&text).unwrap(), recorded);
+        assert_eq!(compare_trace(&mut tetris(), &movie, &recorded), None);
+        assert_eq!(parse_trace("0 zz"), Err(TraceParseError(1)));
+    }
+
+    #[test]
+    fn finds_the_first_different_frame() {
+        let movie = Movie::parse("0\n100 start\n106").unwrap();
+        let mut recorded = trace(&mut tetris(), &movie, 150);
+        recorded[120].regs.a ^= 1;
+        let mismatch = compare_trace(&mut tetris(), &movie, &recorded).unwrap();
+        assert_eq!(mismatch.left.frame, 120);
+        assert_eq!(mismatch.to_string().lines().next(), Some("CPU state differs from frame 120"));
+
+        // Fast only changes how audio is filtered, cycle accurate changes when things happen
+        let (mut fast, mut balanced, mut accurate) = (tetris(), tetris(), tetris());
+        fast.set_accuracy(AccuracyLevel::Fast);
+        accurate.set_accuracy(AccuracyLevel::CycleAccurate);
+        assert_eq!(compare_consoles(&mut fast, &mut balanced, &movie, 150), None);
+        assert!(compare_consoles(&mut tetris(), &mut accurate, &movie, 150).is_some());
+    }
+}
@@ -0,0 +1,224 @@
+// ROM compatibility reports.
+// For tracking how much of a ROM collection runs as the emulator improves: check_dir boots every
+// ROM in a directory headless and resilient (see Console::set_resilient), with no buttons
+// pressed, and sorts each by how far it got:
+//
+//     ok       the screen shows a picture, and the picture changes
+//     frozen   it shows a picture, but never another
+//     blank    the screen never shows anything but one colour
+//     failed   the ROM would not load, or the console gave up on it
+//
+// report_text writes a line per ROM and a count of each, in file name order, so reports from
+// two versions diff cleanly. report_json has the same for scripts.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+
+use super::config::EmuConfig;
+use super::console::{Console, Frame, FrameSinks, VideoSink};
+use super::header::json_string;
+
+// What check_dir picks up, compressed or not (see archive.rs)
+const ROM_EXTENSIONS: [&str; 5] = ["gb", "gbc", "sgb", "zip", "gz"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompatStatus {
+    Ok,
+    Frozen,
+    Blank,
+    Failed,
+}
+
+impl CompatStatus {
+    const ALL: [CompatStatus; 4] = [CompatStatus::Ok, CompatStatus::Frozen, CompatStatus::Blank, CompatStatus::Failed];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CompatStatus::Ok => "ok",
+            CompatStatus::Frozen => "frozen",
+            CompatStatus::Blank => "blank",
+            CompatStatus::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for CompatStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatResult {
+    pub name: String,
+    pub status: CompatStatus,
+    pub frames: u64,                // run, fewer than asked for when it failed
+    pub first_picture: Option<u64>, // the first frame that was not blank
+    pub reason: Option<String>,     // why it failed
+}
+
+// Keeps track of what the screen did
+#[derive(Default)]
+struct PictureSink {
+    frame: u64,
+    first_picture: Option<u64>,
+    last_picture: Option<u64>, // hash
+    changed: bool,
+}
+
+impl VideoSink for PictureSink {
+    fn frame_available(&mut self, frame: &Frame) {
+        self.frame += 1;
+        let first = frame.pixel(0, 0);
+        if frame.rows().all(|row| row.iter().all(|&pixel| pixel == first)) {
+            return;
+        }
+        let mut hasher = DefaultHasher::new();
+        frame.rows().for_each(|row| row.hash(&mut hasher));
+        let hash = hasher.finish();
+        self.first_picture.get_or_insert(self.frame);
+        self.changed |= self.last_picture.is_some_and(|last| last != hash);
+        self.last_picture = Some(hash);
+    }
+}
+
+// Runs rom (a ROM file's contents) for frames frames
+pub fn check_rom(name: &str, rom: &[u8], frames: u64, config: &EmuConfig) -> CompatResult {
+    let mut result = CompatResult {
+        name: name.to_string(),
+        status: CompatStatus::Failed,
+        frames: 0,
+        first_picture: None,
+        reason: None,
+    };
+    let mut console = match Console::builder().rom(rom.into()).config(config.clone()).resilient(true).build() {
+        Ok(console) => console,
+        Err(e) => {
+            result.reason = Some(e.to_string());
+            return result;
+        }
+    };
+    let mut pictures = PictureSink::default();
+    while result.frames < frames && console.failure().is_none() {
+        let batch = (frames - result.frames).min(u32::MAX as u64) as u32;
+        let mut sinks = FrameSinks { video: Some(&mut pictures), audio: None };
+        result.frames += console.run_frames(batch, &mut sinks) as u64;
+    }
+    result.first_picture = pictures.first_picture;
+    result.status = match console.failure() {
+        Some(failure) => {
+            result.reason = Some(failure.to_string());
+            CompatStatus::Failed
+        }
+        None if pictures.first_picture.is_none() => CompatStatus::Blank,
+        None if !pictures.changed => CompatStatus::Frozen,
+        None => CompatStatus::Ok,
+    };
+    result
+}
+
+// Checks every ROM in dir, not looking in subdirectories, in file name order
+pub fn check_dir<P: AsRef<Path>>(dir: P, frames: u64, config: &EmuConfig) -> io::Result<Vec<CompatResult>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+        if path.is_file() && matches!(extension, Some(ext) if ROM_EXTENSIONS.contains(&ext.as_str())) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let mut results = Vec::new();
+    for path in paths {
+        let name = path.file_name().unwrap().to_string_lossy();
+        info!(target: "gbrust::compat", "checking {}", name);
+        results.push(match fs::read(&path) {
+            Ok(rom) => check_rom(&name, &rom, frames, config),
+            Err(e) => CompatResult {
+                name: name.to_string(),
+                status: CompatStatus::Failed,
+                frames: 0,
+                first_picture: None,
+                reason: Some(e.to_string()),
+            },
+        });
+    }
+    Ok(results)
+}
+
+fn summary(results: &[CompatResult]) -> Vec<(CompatStatus, usize)> {
+    CompatStatus::ALL.iter()
+        .map(|&status| (status, results.iter().filter(|result| result.status == status).count()))
+        .collect()
+}
+
+// A line per ROM, "<status> <first picture> <name>", then the counts
+pub fn report_text(results: &[CompatResult]) -> String {
+    let mut report = String::new();
+    for result in results {
+        let first_picture = result.first_picture.map_or("-".to_string(), |frame| frame.to_string());
+        report.push_str(&format!("{:<7} {:>6}  {}", result.status, first_picture, result.name));
+        if let Some(ref reason) = result.reason {
+            report.push_str(&format!(": {}", reason));
+        }
+        report.push('\n');
+    }
+    let counts: Vec<String> = summary(results).iter().map(|(status, count)| format!("{} {}", count, status)).collect();
+    report.push_str(&format!("{} ROMs: {}\n", results.len(), counts.join(", ")));
+    report
+}
+
+pub fn report_json(results: &[CompatResult]) -> String {
+    let roms: Vec<String> = results.iter()
+        .map(|result| {
+            format!("{{\"name\":{},\"status\":\"{}\",\"frames\":{},\"first_picture\":{},\"reason\":{}}}",
+                    json_string(&result.name), result.status, result.frames,
+                    result.first_picture.map_or("null".to_string(), |frame| frame.to_string()),
+                    result.reason.as_deref().map_or("null".to_string(), json_string))
+        })
+        .collect();
+    let counts: Vec<String> = summary(results).iter().map(|(status, count)| format!("\"{}\":{}", status, count)).collect();
+    format!("{{\"roms\":[{}],\"summary\":{{{}}}}}", roms.join(","), counts.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_asm::{Asm, R8};
+
+    #[test]
+    fn sorts_roms_by_how_far_they_get() {
+        let dir = std::env::temp_dir().join(format!("gbrust-compat-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("tetris.gb", dir.join("a tetris.gb")).unwrap();
+        fs::write(dir.join("b blank.gb"), Asm::new().label("loop").jr("loop").assemble()).unwrap();
+        fs::write(dir.join("c locked up.gbc"), Asm::new().ld_r_n(R8::A, 0x42).bytes(&[0xD3]).assemble()).unwrap();
+        fs::write(dir.join("d tiny.gb"), [0; 16]).unwrap();
+        fs::write(dir.join("notes.txt"), "not a ROM").unwrap();
+        let config = EmuConfig { crash_dir: Some(dir.join("crashes")), ..EmuConfig::default() };
+
+        let results = check_dir(&dir, 200, &config).unwrap();
+        let statuses: Vec<(&str, CompatStatus, u64)> = results.iter()
+            .map(|result| (result.name.as_str(), result.status, result.frames))
+            .collect();
+        assert_eq!(statuses, [
+            ("a tetris.gb", CompatStatus::Ok, 200),
+            ("b blank.gb", CompatStatus::Blank, 200),
+            ("c locked up.gbc", CompatStatus::Failed, 0),
+            ("d tiny.gb", CompatStatus::Failed, 0),
+        ]);
+        assert!(results[0].first_picture.is_some());
+
+        let text = report_text(&results);
+        assert!(text.contains("failed       -  c locked up.gbc: illegal opcode $d3 at 00:0152\n"));
+        assert!(text.ends_with("4 ROMs: 1 ok, 0 frozen, 1 blank, 2 failed\n"));
+        let json = report_json(&results);
+        assert!(json.contains("{\"name\":\"b blank.gb\",\"status\":\"blank\",\"frames\":200,\"first_picture\":null,\"reason\":null}"));
+        assert!(json.ends_with("\"summary\":{\"ok\":1,\"frozen\":0,\"blank\":1,\"failed\":2}}"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+// Emulator settings.
+// Everything a user would expect to stick between runs: which model to emulate, how accurate
+// (and slow) to be, screen colors, audio rate, keys and where saves go. Stored as TOML, and
+// every field has a default so a config file only needs the settings the user changed.
+// Games can have their own profile on top, keyed by the global checksum in the ROM header
+// (4 lowercase hex digits), which the console builder applies when the ROM loads.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use super::console::Model;
+use super::cart::CartOverride;
+use super::error::ConfigError;
+use super::meminit::MemoryInit;
+
+// Trades accuracy for speed, for the whole machine at once. Subsystems check this to decide
+// whether to model the expensive hardware quirks:
+//   Fast           Balanced, minus the APU's high-pass filters (output keeps its DC offset)
+//   Balanced       every subsystem, no quirks beyond what games commonly rely on (the DMG's
+//                  STAT write bug, see Ppu::write)
+//   CycleAccurate  adds open bus reads and OAM DMA bus conflicts (bus), the OAM bug on 16 bit
+//                  inc/dec and reads and writes on their own machine cycle (CPU) and STAT
+//                  interrupt blocking (PPU)
+// Blargg's cpu_instrs passes 04, 05, 06 and 10 at every level, see test_roms.rs.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccuracyLevel {
+    Fast,
+    #[default]
+    Balanced,
+    CycleAccurate,
+}
+
+// The names used in the config file, for command lines
+impl FromStr for AccuracyLevel {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "fast" => Ok(AccuracyLevel::Fast),
+            "balanced" => Ok(AccuracyLevel::Balanced),
+            "cycle-accurate" => Ok(AccuracyLevel::CycleAccurate),
+            _ => Err(format!("unknown accuracy \"{}\", expected fast, balanced or cycle-accurate", name)),
+        }
+    }
+}
+
+// Where the sound is heard. The console's speaker is mono, so Speaker mixes both sides down to
+// one; Headphones keeps NR51's panning, as the headphone jack does.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioOutput {
+    #[default]
+    Headphones,
+    Speaker,
+}
+
+// What the console does while the frontend's window is in the background, see
+// Console::set_background. The sound stops either way.
+//   Pause     nothing runs until the window is back
+//   Throttle  one frame runs for every BACKGROUND_THROTTLE the frontend asks for
+//   Run       runs as usual, only silent
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundMode {
+    #[default]
+    Pause,
+    Throttle,
+    Run,
+}
+
+pub const BACKGROUND_THROTTLE: u64 = 4;
+
+// The four DMG shades, lightest first, as 0xAARRGGBB. Written as "#rrggbb" strings in TOML.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct Palette(pub [u32; 4]);
+
+impl Default for Palette {
+    // The green-ish screen of the original DMG
+    fn default() -> Self {
+        Palette([0xFFE0_F8D0, 0xFF88_C070, 0xFF27_5046, 0xFF08_1820])
+    }
+}
+
+impl TryFrom<Vec<String>> for Palette {
+    type Error = String;
+
+    fn try_from(colors: Vec<String>) -> Result<Self, Self::Error> {
+        if colors.len() != 4 {
+            return Err(format!("palette needs 4 colors, got {}", colors.len()));
+        }
+        let mut palette = [0; 4];
+        for (shade, color) in palette.iter_mut().zip(&colors) {
+            let hex = color.trim_start_matches('#');
+            let rgb = match u32::from_str_radix(hex, 16) {
+                Ok(rgb) if hex.len() == 6 => rgb,
+                _ => return Err(format!("invalid color \"{}\", expected #rrggbb", color)),
+            };
+            *shade = 0xFF00_0000 | rgb;
+        }
+        Ok(Palette(palette))
+    }
+}
+
+impl From<Palette> for Vec<String> {
+    fn from(palette: Palette) -> Self {
+        palette.0.iter().map(|color| format!("#{:06x}", color & 0x00FF_FFFF)).collect()
+    }
+}
+
+// Key names are left to the frontend to interpret (minifb key names for the bundled one)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub up: String,
+    pub down: String,
+    pub left: String,
+    pub right: String,
+    pub a: String,
+    pub b: String,
+    pub start: String,
+    pub select: String,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            up: "Up".to_string(),
+            down: "Down".to_string(),
+            left: "Left".to_string(),
+            right: "Right".to_string(),
+            a: "Z".to_string(),
+            b: "X".to_string(),
+            start: "Enter".to_string(),
+            select: "RightShift".to_string(),
+        }
+    }
+}
+
+// Settings one game gets instead of the usual ones. Only what is given changes. Keybindings
+// replace the whole set, keys not listed get their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameProfile {
+    pub accuracy: Option<AccuracyLevel>,
+    pub palette: Option<Palette>,
+    pub lcd_persistence: Option<u8>,
+    pub overclock: Option<u32>,
+    pub memory_init: Option<MemoryInit>,
+    pub keybindings: Option<KeyBindings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmuConfig {
+    pub model: Model,
+    pub accuracy: AccuracyLevel,
+    pub palette: Palette,
+    // Percentage of the last frame blended into the next, 0 to turn off. See persistence.rs
+    pub lcd_persistence: u8,
+    // Extra CPU cycles per scanline (the hardware runs 456), 0 for none. Cuts slowdown in games
+    // that lag, see Cpu::set_overclock
+    pub overclock: u32,
+    // Runs hot ROM code compiled, for fast-forward. Needs the jit feature, see jit.rs
+    pub jit: bool,
+    // What RAM holds at power on, for games that read it before writing. See meminit.rs
+    pub memory_init: MemoryInit,
+    pub audio_sample_rate: u32,
+    pub audio_output: AudioOutput,
+    pub background: BackgroundMode,
+    pub keybindings: KeyBindings,
+    // Where .sav files go. None keeps them next to the ROM.
+    pub save_dir: Option<PathBuf>,
+    // No-Intro DAT to check ROMs against when they load, see romdb.rs
+    pub rom_database: Option<PathBuf>,
+    // Where crash reports go, see crash.rs. None writes them to the working directory.
+    pub crash_dir: Option<PathBuf>,
+    // Whether the CPU locking up writes a crash report. Off spares keeping the instruction
+    // trace for them, for the last bit of speed.
+    pub crash_reports: bool,
+    // For gbrust run: save states the game's progress next to its .sav on the way out and every
+    // autosave_interval seconds (0 for only on the way out), for --resume. Consoles built from
+    // the config do not autosave, see Console::set_autosave_path.
+    pub autosave: bool,
+    pub autosave_interval: u32,
+    // Fixes for carts with a wrong header, by the ROM's SHA-1, see CartOverride
+    pub cart_overrides: BTreeMap<String, CartOverride>,
+    // Per-game settings, by global checksum, see GameProfile
+    pub profiles: BTreeMap<String, GameProfile>,
+}
+
+impl Default for EmuConfig {
+    fn default() -> Self {
+        EmuConfig {
+            model: Model::Dmg,
+            accuracy: AccuracyLevel::default(),
+            palette: Palette::default(),
+            lcd_persistence: 0,
+            overclock: 0,
+            jit: false,
+            memory_init: MemoryInit::default(),
+            audio_sample_rate: 44_100,
+            audio_output: AudioOutput::default(),
+            background: BackgroundMode::default(),
+            keybindings: KeyBindings::default(),
+            save_dir: None,
+            rom_database: None,
+            crash_dir: None,
+            crash_reports: true,
+            autosave: true,
+            autosave_interval: 60,
+            cart_overrides: BTreeMap::new(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl EmuConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<EmuConfig, ConfigError> {
+        let text = fs::read_to_string(path)?;
+        EmuConfig::from_toml(&text)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        fs::write(path, self.to_toml()?)?;
+        Ok(())
+    }
+
+    pub fn from_toml(text: &str) -> Result<EmuConfig, ConfigError> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    // The settings the game with this global checksum runs with
+    pub fn for_game(&self, global_checksum: u16) -> EmuConfig {
+        let mut config = self.clone();
+        if let Some(profile) = self.profiles.get(&profile_key(global_checksum)) {
+            if let Some(accuracy) = profile.accuracy {
+                config.accuracy = accuracy;
+            }
+            if let Some(palette) = profile.palette {
+                config.palette = palette;
+            }
+            if let Some(lcd_persistence) = profile.lcd_persistence {
+                config.lcd_persistence = lcd_persistence;
+            }
+            if let Some(overclock) = profile.overclock {
+                config.overclock = overclock;
+            }
+            if let Some(memory_init) = profile.memory_init {
+                config.memory_init = memory_init;
+            }
+            if let Some(ref keybindings) = profile.keybindings {
+                config.keybindings = keybindings.clone();
+            }
+        }
+        config
+    }
+
+    // The game's profile, created empty if it has none. Save the config to keep changes.
+    pub fn profile_mut(&mut self, global_checksum: u16) -> &mut GameProfile {
+        self.profiles.entry(profile_key(global_checksum)).or_default()
+    }
+
+    // Where the battery save for rom_path lives under this config
+    pub fn save_path(&self, rom_path: &Path) -> PathBuf {
+        match (&self.save_dir, rom_path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name).with_extension("sav"),
+            _ => rom_path.with_extension("sav"),
+        }
+    }
+
+    // Where the autosave for rom_path lives, beside the battery save
+    pub fn autosave_path(&self, rom_path: &Path) -> PathBuf {
+        self.save_path(rom_path).with_extension("autosave")
+    }
+}
+
+fn profile_key(global_checksum: u16) -> String {
+    format!("{:04x}", global_checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_toml() {
+        let config = EmuConfig {
+            accuracy: AccuracyLevel::CycleAccurate,
+            palette: Palette([0xFFFF_FFFF, 0xFFAA_AAAA, 0xFF55_5555, 0xFF00_0000]),
+            keybindings: KeyBindings { a: "Space".to_string(), ..KeyBindings::default() },
+            save_dir: Some(PathBuf::from("saves")),
+            memory_init: MemoryInit::Seeded(1234),
+            ..EmuConfig::default()
+        };
+
+        let text = config.to_toml().unwrap();
+        assert!(text.contains("accuracy = \"cycle-accurate\""));
+        assert_eq!(EmuConfig::from_toml("memory_init = { seeded = 1234 }").unwrap().memory_init,
+                   config.memory_init);
+        assert_eq!(EmuConfig::from_toml(&text).unwrap(), config);
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let config = EmuConfig::from_toml("palette = [\"#ffffff\", \"#aaaaaa\", \"#555555\", \"#000000\"]").unwrap();
+        assert_eq!(config.palette.0[1], 0xFFAA_AAAA);
+        assert_eq!(config.keybindings, KeyBindings::default());
+        assert!(EmuConfig::from_toml("palette = [\"#ffffff\"]").is_err());
+    }
+
+    #[test]
+    fn profiles_apply_to_their_game_only() {
+        let config = EmuConfig::from_toml("[profiles.3bd6]\naccuracy = \"fast\"\noverclock = 456\n\n[profiles.3bd6.keybindings]\na = \"Space\"").unwrap();
+        let game = config.for_game(0x3BD6);
+        assert_eq!(game.accuracy, AccuracyLevel::Fast);
+        assert_eq!(game.overclock, 456);
+        assert_eq!(game.keybindings.a, "Space");
+        assert_eq!(game.keybindings.b, KeyBindings::default().b);
+        assert_eq!(game.palette, config.palette);
+        assert_eq!(config.for_game(0x1234).accuracy, AccuracyLevel::Balanced);
+
+        let mut saved = EmuConfig::default();
+        saved.profile_mut(0x3BD6).accuracy = Some(AccuracyLevel::Fast);
+        let text = saved.to_toml().unwrap();
+        assert_eq!(EmuConfig::from_toml(&text).unwrap().for_game(0x3BD6).accuracy, AccuracyLevel::Fast);
+    }
+}
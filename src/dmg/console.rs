@@ -1,12 +1,121 @@
-use super::dmg_cpu::Cpu;
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::io;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use super::dmg_cpu::{Cpu, CpuFault, RegisterSnapshot, VectorTrap};
+use super::crash::{self, CrashReport};
+use super::debugger::{self, BankedAddr, BreakOn, DebugOpcode, StackFrame, StepEnd, StepHistory, STEP_FRAME_LIMIT};
+use super::symbols::Symbols;
+use super::profile::Profile;
+use super::disasm::{self, Instruction};
+use super::memmap::{self, Region};
+use super::memview::{MemorySnapshot, MemoryView};
+use super::meminit::MemoryInit;
+use super::sav::{RtcFooter, SaveFile};
+use super::ioregs::IoSnapshot;
+use super::input_macro::InputMacro;
 use super::interconnect::Interconnect;
-pub use super::gamepad::{InputEvent,Gamepad,Button,ButtonState};
+use super::serial::SerialDevice;
+use super::interrupts::InterruptScript;
+use super::timing::TimingMismatch;
+use super::pipeline::RenderThread;
+use super::state::{Divergence, StateError, StateReader, StateWriter};
+use super::error::{AutosaveError, BusError, CartError, JitError, RunFailure, SaveFileError, StateImportError};
+use super::bess;
+use super::config::{AccuracyLevel, AudioOutput, BackgroundMode, EmuConfig, Palette, BACKGROUND_THROTTLE};
+use super::stats::FrameStats;
+use super::apu::{ApuSnapshot, AudioSample};
+use super::apu_log::ApuLog;
+use super::overlay::{Overlay, OverlaySink};
+use super::persistence::{LcdPersistence, PersistenceSink};
+use super::ppu::{Layers, ScanlineRegs};
+use super::timeline::PpuTimeline;
+use super::vram_log::VramLog;
+use super::events;
+use super::gfx::VramSnapshot;
+use super::watch::{WatchChange, WatchExpr, WatchHandle, WatchList};
+use super::poke::{Poke, PokeSchedule};
+use super::hotload::HotLoader;
+pub use super::gamepad::{InputEvent,Gamepad,Button,ButtonState,InputPoller,JoypadRead};
+pub use super::frame::{Frame, FramePool, PixelFormat};
 
 pub use super::cart::Cart;
+use super::romdb::RomDb;
+use super::remote::{Command, ConsoleHandle, Remote, RemoteSink};
+
+const BOOT_ROM_SIZE: usize = 0x100;
+// The hardware runs 59.73, near enough for spacing autosaves
+pub const AUTOSAVE_FRAMES_PER_SECOND: u64 = 60;
+
+// Hardware model being emulated. Only the original Gameboy's features are, so Cgb runs games
+// as a Gameboy Color would in DMG mode, without colour or double speed. For now it only changes
+// how the sound is filtered, what RAM holds at power on, the STAT write bug (DMG only) and which
+// sprite wins where sprites overlap (see Ppu::render_sprites and OPRI at 0xff6c). Games made for
+// the CGB also get its second VRAM bank, tile attributes and priority rules, see
+// Ppu::set_cgb_mode, and the fast serial clock.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
 
 // Trait for objects that receive video data, and then render video to display video frames.
 pub trait VideoSink {
-    fn frame_available(&mut self, frame: &Box<[u32]>);
+    fn frame_available(&mut self, frame: &Frame);
+}
+
+// Swallows frames, for headless runs and for when the render thread delivers the real ones
+pub(crate) struct NoVideo;
+
+impl VideoSink for NoVideo {
+    fn frame_available(&mut self, _frame: &Frame) {}
+}
+
+// Keeps the last frame, packed
+pub(crate) struct LastFrame(pub(crate) Option<Box<[u32]>>);
+
+impl VideoSink for LastFrame {
+    fn frame_available(&mut self, frame: &Frame) {
+        self.0 = Some(frame.to_packed());
+    }
+}
+
+// Trait for objects that receive audio. Gets everything the APU mixed during a frame, once the
+// frame is done, at the config's audio_sample_rate.
+pub trait AudioSink {
+    fn samples_available(&mut self, samples: &[AudioSample]);
+}
+
+// Where Console::run_frames sends what the frames make. None drops it; to not even draw the
+// frames, see Console::set_rendering.
+#[derive(Default)]
+pub struct FrameSinks<'a> {
+    pub video: Option<&'a mut dyn VideoSink>,
+    pub audio: Option<&'a mut dyn AudioSink>,
+}
+
+// Hashes every frame on its way to the sink, for Console::check_state
+struct FrameHasher<'a> {
+    hashes: Vec<u64>,
+    video_sink: &'a mut dyn VideoSink,
+}
+
+impl<'a> VideoSink for FrameHasher<'a> {
+    fn frame_available(&mut self, frame: &Frame) {
+        let mut hasher = DefaultHasher::new();
+        for row in frame.rows() {
+            row.hash(&mut hasher);
+        }
+        self.hashes.push(hasher.finish());
+        self.video_sink.frame_available(frame);
+    }
 }
 
 // FrameHandler: A struct that contains any ???
@@ -25,14 +134,320 @@ impl<'a> FrameHandler<'a> {
 }
 
 impl<'a> VideoSink for FrameHandler<'a> {
-    fn frame_available(&mut self, frame: &Box<[u32]>) {
+    fn frame_available(&mut self, frame: &Frame) {
         self.video_sink.frame_available(frame);
         self.frame_available = true;
     }
 }
 
+// What a vblank callback gets to see: the frame number and the memory bus, as the CPU sees it
+pub struct Vblank<'a> {
+    frame: u64,
+    interconnect: &'a mut Interconnect,
+}
+
+impl<'a> Vblank<'a> {
+    // Frames completed since power on, counting this one
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Without side effects, see Interconnect::peek
+    pub fn read(&mut self, addr: u16) -> u8 {
+        self.interconnect.peek(addr)
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        self.interconnect.write(addr, val)
+    }
+
+    pub fn apu_snapshot(&self) -> ApuSnapshot {
+        self.interconnect.apu.snapshot()
+    }
+}
+
+pub type VblankCallback = Box<dyn FnMut(&mut Vblank) + Send>;
+
+pub type ScanlineHook = Box<dyn FnMut(&ScanlineRegs) + Send>;
+
+// Called with where an LD D,D debug message came from and its text, see debugger.rs
+pub type DebugMessageHook = Box<dyn FnMut(BankedAddr, &str) + Send>;
+
+// Gives a seed for every power cycle, see Console::set_entropy_hook
+pub type EntropyHook = Box<dyn FnMut() -> u64 + Send>;
+
+// Returned by on_vblank, to remove the callback again
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VblankHandle(u64);
+
+// What Console::reset clears. Neither touches cartridge RAM.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResetKind {
+    // Registers and IO only: work RAM, high RAM, VRAM and OAM keep their contents, as when the
+    // reset line is pulled on a running console
+    Soft,
+    // Everything, as when switched off and on again. The frame count starts over too.
+    PowerCycle,
+}
+
+// Single entry point for setting up a console:
+//
+//     let mut console = Console::builder()
+//         .rom_path("tetris.gb")
+//         .config(EmuConfig::load("gbrust.toml")?)
+//         .build()?;
+//     console.run_frame(&mut video_sink);
+pub struct ConsoleBuilder {
+    rom: Option<Box<[u8]>>,
+    rom_path: Option<PathBuf>,
+    save_ram: Option<Box<[u8]>>,
+    boot_rom: Option<Box<[u8]>>,
+    boot_rom_path: Option<PathBuf>,
+    patch_path: Option<PathBuf>,
+    config: EmuConfig,
+    threaded_rendering: bool,
+    resilient: bool,
+}
+
+impl ConsoleBuilder {
+    fn new() -> ConsoleBuilder {
+        ConsoleBuilder {
+            rom: None,
+            rom_path: None,
+            save_ram: None,
+            boot_rom: None,
+            boot_rom_path: None,
+            patch_path: None,
+            config: EmuConfig::default(),
+            threaded_rendering: false,
+            resilient: false,
+        }
+    }
+
+    // ROM file to load, possibly zipped (see archive.rs). Battery RAM is picked up from the .sav
+    // file next to it (or in the config's save_dir), if there is one and save_ram was not given.
+    pub fn rom_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.rom_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    // ROM already in memory. Takes precedence over rom_path.
+    pub fn rom(mut self, rom: Box<[u8]>) -> Self {
+        self.rom = Some(rom);
+        self
+    }
+
+    // IPS or BPS patch to apply to the ROM as it is loaded, see patch.rs
+    pub fn patch_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.patch_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    // Battery save to start with, as read from a .sav, RTC footer or not (see sav.rs)
+    pub fn save_ram(mut self, ram: Box<[u8]>) -> Self {
+        self.save_ram = Some(ram);
+        self
+    }
+
+    // Runs the 256 byte boot ROM (Nintendo logo scroll) before the cartridge.
+    // Without one, the console starts at 0x0100 with the registers the boot ROM leaves behind.
+    pub fn boot_rom(mut self, boot_rom: Box<[u8]>) -> Self {
+        self.boot_rom = Some(boot_rom);
+        self
+    }
+
+    pub fn boot_rom_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.boot_rom_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    // Replaces all settings, so call it before model() and friends
+    pub fn config(mut self, config: EmuConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn model(mut self, model: Model) -> Self {
+        self.config.model = model;
+        self
+    }
+
+    pub fn accuracy(mut self, accuracy: AccuracyLevel) -> Self {
+        self.config.accuracy = accuracy;
+        self
+    }
+
+    pub fn threaded_rendering(mut self, enabled: bool) -> Self {
+        self.threaded_rendering = enabled;
+        self
+    }
+
+    // See Console::set_resilient
+    pub fn resilient(mut self, enabled: bool) -> Self {
+        self.resilient = enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<Console, CartError> {
+        let mut save_ram = self.save_ram;
+        let mut save_path = None;
+        let rom = match (self.rom, self.rom_path) {
+            (Some(rom), _) => rom.into_vec(),
+            (None, Some(path)) => {
+                if save_ram.is_none() {
+                    save_ram = read_save(&self.config, &path)?;
+                }
+                save_path = Some(self.config.save_path(&path));
+                fs::read(path)?
+            }
+            (None, None) => return Err(CartError::NoRom),
+        };
+        let patch = match self.patch_path {
+            Some(path) => Some(fs::read(path)?),
+            None => None,
+        };
+        let cart = load_cart(&rom, patch.as_deref(), save_ram, &self.config)?;
+        let config = self.config.for_game(cart.global_checksum());
+
+        let boot_rom = match (self.boot_rom, self.boot_rom_path) {
+            (Some(boot_rom), _) => Some(boot_rom),
+            (None, Some(path)) => Some(fs::read(path)?.into_boxed_slice()),
+            (None, None) => None,
+        };
+
+        let mut console = Console::new(cart);
+        console.set_palette(config.palette);
+        console.set_lcd_persistence(config.lcd_persistence);
+        console.set_overclock(config.overclock);
+        if let Err(e) = console.set_jit(config.jit) {
+            warn!(target: "gbrust::jit", "{}, interpreting", e);
+        }
+        console.set_accuracy(config.accuracy);
+        console.set_model(config.model);
+        console.set_memory_init(config.memory_init);
+        console.cpu.interconnect.init_memory();
+        console.set_audio_output(config.audio_output);
+        console.set_crash_reports(config.crash_reports);
+        console.config = config;
+        console.save_path = save_path;
+
+        if let Some(boot_rom) = boot_rom {
+            if boot_rom.len() != BOOT_ROM_SIZE {
+                return Err(CartError::InvalidBootRom(boot_rom.len()));
+            }
+            console.cpu.interconnect.set_boot_rom(boot_rom);
+            console.cpu.start_from_boot_rom();
+        }
+
+        console.set_threaded_rendering(self.threaded_rendering);
+        console.set_resilient(self.resilient);
+        Ok(console)
+    }
+}
+
+// The battery save for the ROM at rom_path, if there is one
+fn read_save(config: &EmuConfig, rom_path: &Path) -> Result<Option<Box<[u8]>>, CartError> {
+    let path = config.save_path(rom_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read(path)?.into_boxed_slice()))
+}
+
+// A cart from a ROM file's contents, patched if there is a patch, with its battery save
+fn load_cart(rom: &[u8], patch: Option<&[u8]>, save_ram: Option<Box<[u8]>>, config: &EmuConfig)
+             -> Result<Cart, CartError> {
+    let program = Cart::unpack(rom, patch)?;
+    // Saves from other emulators may end in a clock, see sav.rs
+    let save = match save_ram {
+        Some(bytes) => Some(SaveFile::parse(&bytes)?),
+        None => None,
+    };
+    let (ram, rtc) = match save {
+        Some(SaveFile { ram, rtc }) => (Some(ram).filter(|ram| !ram.is_empty()), rtc),
+        None => (None, None),
+    };
+    let mut cart = Cart::with_overrides(program, ram, &config.cart_overrides)?;
+    if let Some(ref rtc) = rtc {
+        cart.set_rtc(rtc);
+    }
+    if let Some(ref path) = config.rom_database {
+        identify(&mut cart, path);
+    }
+    Ok(cart)
+}
+
+// Reports how the ROM matches the DAT at path. Problems with the DAT are only warned about, the
+// ROM loads regardless.
+fn identify(cart: &mut Cart, path: &Path) {
+    let db = match RomDb::load(path) {
+        Ok(db) => db,
+        Err(e) => {
+            warn!(target: "gbrust::romdb", "could not read ROM database {}: {}", path.display(), e);
+            return;
+        }
+    };
+    match cart.identify(&db) {
+        Some(entry) if entry.bad_dump => {
+            warn!(target: "gbrust::romdb", "{} is a known bad dump, expect problems", entry.name)
+        }
+        Some(entry) => info!(target: "gbrust::romdb", "verified good dump: {}", entry.name),
+        None => warn!(target: "gbrust::romdb", "ROM not found in {} (sha1 {}), it may be a bad dump or a hack",
+                      path.display(), cart.hashes().sha1_hex()),
+    }
+}
+
+// A console owns all of its state, with no globals shared between instances, so any number can
+// run side by side (link cables, batch runs), each on its own thread if need be. Everything
+// handed to it from outside (sinks, hooks, callbacks) must therefore be Send too.
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Console>();
+};
+
 pub struct Console {
     cpu: Cpu,
+    config: EmuConfig,
+    paused: bool,
+    frame_count: u64,
+    last_frame_stats: FrameStats,
+    watches: WatchList,
+    pokes: PokeSchedule, // see poke.rs
+    hotload: HotLoader,
+    overlay: Option<Overlay>,
+    persistence: Option<LcdPersistence>,
+    scanline_hook: Option<ScanlineHook>,
+    debug_message_hook: Option<DebugMessageHook>,
+    entropy_hook: Option<EntropyHook>,
+    entropy_seed: Option<u64>, // the last power cycle's
+    audio_sink: Option<Box<dyn AudioSink + Send>>,
+    vblank_callbacks: Vec<(VblankHandle, VblankCallback)>,
+    next_vblank_handle: u64,
+    step_history: Option<StepHistory>,
+    breakpoints: Vec<BankedAddr>,
+    break_conditions: Vec<BreakOn>,
+    symbols: Symbols,
+    profile: Option<Profile>,
+    breakpoint_hit: Option<BankedAddr>,
+    remote: Option<Remote>,
+    // Buttons for the coming frames, from queued input macros, and whether one is playing
+    queued_input: VecDeque<u8>,
+    playing_input: bool,
+    memory_view: Option<MemoryView>, // the console's own, for publishing, see memview.rs
+    save_path: Option<PathBuf>, // where flush_save writes the battery save
+    autosave_path: Option<PathBuf>,
+    autosave_frames: u64, // between autosaves, 0 for none
+    frames_since_autosave: u64,
+    background: bool,
+    background_frames: u64, // run_frame calls since going to the background
+    crash_reported: bool, // for the CPU's fault, if it has one
+    resilient: bool,
+    failure: Option<RunFailure>, // why a resilient console stopped, for good
+    cpu_bus_error: Option<BusError>, // the first the CPU made, not counting peeks from outside
+    crash_bundle: Option<PathBuf>, // the last crash report written
+    event_log: Option<Box<dyn io::Write + Send>>, // a line per frame, see events.rs
+    // Declared after cpu so the PPU's event queue is dropped before we wait for the worker
+    render_thread: Option<RenderThread>,
 }
 
 impl Console {
@@ -40,25 +455,2356 @@ impl Console {
         let interconnect = Interconnect::new(cart);
         Console {
             cpu: Cpu::new(interconnect),
+            config: EmuConfig::default(),
+            paused: false,
+            frame_count: 0,
+            last_frame_stats: FrameStats::default(),
+            watches: WatchList::default(),
+            pokes: PokeSchedule::default(),
+            hotload: HotLoader::default(),
+            overlay: None,
+            persistence: None,
+            scanline_hook: None,
+            debug_message_hook: None,
+            entropy_hook: None,
+            entropy_seed: None,
+            audio_sink: None,
+            vblank_callbacks: Vec::new(),
+            next_vblank_handle: 0,
+            step_history: None,
+            breakpoints: Vec::new(),
+            break_conditions: Vec::new(),
+            symbols: Symbols::default(),
+            profile: None,
+            breakpoint_hit: None,
+            remote: None,
+            queued_input: VecDeque::new(),
+            playing_input: false,
+            memory_view: None,
+            save_path: None,
+            autosave_path: None,
+            autosave_frames: 0,
+            frames_since_autosave: 0,
+            background: false,
+            background_frames: 0,
+            crash_reported: false,
+            resilient: false,
+            failure: None,
+            cpu_bus_error: None,
+            crash_bundle: None,
+            event_log: None,
+            render_thread: None,
         }
     }
 
-    pub fn run_for_one_frame(&mut self, video_sink: &mut dyn VideoSink) {
-        let mut frame_handler = FrameHandler::new(video_sink);
-        while !frame_handler.frame_available {
-            self.cpu.step(&mut frame_handler);
+    pub fn builder() -> ConsoleBuilder {
+        ConsoleBuilder::new()
+    }
+
+    pub fn model(&self) -> Model {
+        self.config.model
+    }
+
+    pub fn set_model(&mut self, model: Model) {
+        self.config.model = model;
+        self.cpu.interconnect.apu.set_model(model);
+        self.cpu.interconnect.ppu.set_model(model);
+        // Games flagged for the CGB in the header get its VRAM bank and tile attributes
+        let cgb_game = self.cart().read(0x0143).is_ok_and(|flag| flag & 0x80 != 0);
+        self.cpu.interconnect.ppu.set_cgb_mode(model == Model::Cgb && cgb_game);
+        self.cpu.interconnect.serial.set_cgb_mode(model == Model::Cgb && cgb_game);
+        self.cpu.interconnect.set_memory_init(self.config.memory_init, model);
+    }
+
+    // What RAM holds from the next power cycle on, see meminit.rs
+    pub fn set_memory_init(&mut self, init: MemoryInit) {
+        self.config.memory_init = init;
+        self.cpu.interconnect.set_memory_init(init, self.config.model);
+    }
+
+    // What every sound channel is playing, see ApuSnapshot. Taken between frames, or from a
+    // vblank callback, it gives a visualizer one snapshot per frame.
+    pub fn apu_snapshot(&self) -> ApuSnapshot {
+        self.cpu.interconnect.apu.snapshot()
+    }
+
+    // What each sound channel feeds its DAC right now, 0 - 15, e.g. for a visualizer. The
+    // same values a CGB shows in PCM12 and PCM34.
+    pub fn channel_outputs(&self) -> [u8; 4] {
+        self.cpu.interconnect.apu.channel_outputs()
+    }
+
+    // Mono speaker or stereo headphones, see AudioOutput
+    pub fn set_audio_output(&mut self, output: AudioOutput) {
+        self.config.audio_output = output;
+        self.cpu.interconnect.apu.set_output(output);
+    }
+
+    // Width of the integer samples, see apu_fixed.rs
+    #[cfg(feature = "fixed-point-audio")]
+    pub fn set_sample_bits(&mut self, bits: u32) {
+        self.cpu.interconnect.apu.set_sample_bits(bits);
+    }
+
+    pub fn accuracy(&self) -> AccuracyLevel {
+        self.config.accuracy
+    }
+
+    // Switches every subsystem to this accuracy level at once, see AccuracyLevel
+    pub fn set_accuracy(&mut self, accuracy: AccuracyLevel) {
+        self.config.accuracy = accuracy;
+        self.cpu.interconnect.set_accuracy(accuracy);
+    }
+
+    // Settings in effect, with the game's profile applied
+    pub fn config(&self) -> &EmuConfig {
+        &self.config
+    }
+
+    // Takes effect from the next scanline drawn
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.config.palette = palette;
+        self.cpu.interconnect.ppu.set_palette(palette.0);
+        self.refresh_render_thread();
+    }
+
+    // Blends this percentage of the last frame into every frame, see persistence.rs. 0 turns it
+    // off. Applied before the overlay, so the HUD does not smear.
+    pub fn set_lcd_persistence(&mut self, percent: u8) {
+        self.config.lcd_persistence = percent;
+        self.persistence = if percent > 0 { Some(LcdPersistence::new(percent)) } else { None };
+    }
+
+    // Gives the CPU this many extra cycles per scanline, with video and sound at their usual
+    // pace. 456 doubles the CPU speed. 0 turns it off.
+    pub fn set_overclock(&mut self, cycles: u32) {
+        self.config.overclock = cycles;
+        self.cpu.set_overclock(cycles);
+    }
+
+    // Compiles the ROM code the game runs most to native code, see jit.rs. Much faster, a little
+    // less accurate: interrupts wait for the end of a block. Breakpoints inside a block are
+    // passed over.
+    pub fn set_jit(&mut self, enabled: bool) -> Result<(), JitError> {
+        self.cpu.set_jit(enabled)?;
+        self.config.jit = enabled;
+        Ok(())
+    }
+
+    // Stops drawing scanlines, or starts again. The PPU keeps its timing and interrupts, so the
+    // game runs the same, but frames keep showing the last picture drawn. For runs nobody
+    // watches, drawing is a good part of the time a frame takes.
+    pub fn set_rendering(&mut self, enabled: bool) {
+        self.cpu.interconnect.ppu.set_rendering(enabled);
+    }
+
+    // Checks every instruction's cycles against the table in timing.rs, logging each opcode
+    // that is off the first time. For working on the CPU, it slows emulation down.
+    pub fn set_timing_check(&mut self, enabled: bool) {
+        self.cpu.set_timing_check(enabled);
+    }
+
+    // Opcodes found off so far, first occurrence only
+    pub fn timing_mismatches(&self) -> &[TimingMismatch] {
+        self.cpu.timing_mismatches()
+    }
+
+    // Hides the background, window or sprites for debugging, from the next scanline drawn
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.cpu.interconnect.ppu.set_layers(layers);
+        self.refresh_render_thread();
+    }
+
+    pub fn layers(&self) -> Layers {
+        self.cpu.interconnect.ppu.layers()
+    }
+
+    // The render worker draws with its own copy of the PPU. Start a fresh one after changing
+    // anything the copy needs to know about.
+    fn refresh_render_thread(&mut self) {
+        if self.render_thread.is_some() {
+            self.set_threaded_rendering(false);
+            self.set_threaded_rendering(true);
         }
     }
-    
-    pub fn handle_event(&mut self, input_event: InputEvent) {
-        self.cpu.interconnect.gamepad.handle_event(input_event);
+
+    pub fn cart(&self) -> &Cart {
+        &self.cpu.interconnect.cart
+    }
+
+    // Puts a file over ROM bank bank, and loads it again whenever it changes, see hotload.rs
+    pub fn map_rom_bank_file<P: Into<PathBuf>>(&mut self, bank: usize, path: P) -> Result<(), CartError> {
+        self.hotload.map_file(&mut self.cpu.interconnect.cart, bank, path.into())?;
+        self.cpu.rom_changed();
+        Ok(())
+    }
+
+    // Puts bytes over ROM bank bank, from its start
+    pub fn map_rom_bank(&mut self, bank: usize, bytes: &[u8]) -> Result<(), CartError> {
+        self.hotload.map(&mut self.cpu.interconnect.cart, bank, bytes)?;
+        self.cpu.rom_changed();
+        Ok(())
     }
 
-    /* TODO: implement copy_ram in cart?
-        pub fn copy_cart_ram(&self) -> Option<Box<[u8]>> {
-            self.cpu.interconnect.cart.copy_ram()
+    // Puts the ROM's own bytes back in bank, and stops watching its file
+    pub fn unmap_rom_bank(&mut self, bank: usize) -> Result<(), CartError> {
+        if self.hotload.unmap(&mut self.cpu.interconnect.cart, bank)? {
+            self.cpu.rom_changed();
         }
-    */
-}
+        Ok(())
+    }
+
+    // Every kind of bad cartridge access the game has made so far, first occurrence only
+    pub fn bus_errors(&self) -> &[BusError] {
+        self.cpu.interconnect.bus_errors()
+    }
+
+    // Moves scanline drawing onto a worker thread (see pipeline.rs). Frames then reach the
+    // video sink one call late, which is fine for fast-forward and batch runs.
+    pub fn set_threaded_rendering(&mut self, enabled: bool) {
+        if enabled {
+            if self.render_thread.is_none() {
+                self.render_thread = Some(RenderThread::spawn(&mut self.cpu.interconnect.ppu));
+            }
+        } else {
+            // Stop feeding the worker before waiting for it to finish
+            self.cpu.interconnect.ppu.set_event_queue(None);
+            self.render_thread = None;
+        }
+    }
+
+    // Nothing runs while paused: the CPU, PPU and timers are all driven from here, so they stay
+    // frozen until resume() or one of the advance_* calls.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.breakpoint_hit = None;
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // For frontends to call when their window loses focus (true) and gets it back (false). In
+    // the background the sound stops, and run_frame pauses or slows down as the config's
+    // background setting says, see BackgroundMode. Frame advance and stepping still work.
+    pub fn set_background(&mut self, background: bool) {
+        self.background = background;
+        self.background_frames = 0;
+    }
+
+    pub fn is_background(&self) -> bool {
+        self.background
+    }
+
+    pub fn set_background_mode(&mut self, mode: BackgroundMode) {
+        self.config.background = mode;
+    }
+
+    // Whether run_frame should skip this frame for being in the background
+    fn held_back(&mut self) -> bool {
+        if !self.background {
+            return false;
+        }
+        self.background_frames += 1;
+        match self.config.background {
+            BackgroundMode::Pause => true,
+            BackgroundMode::Throttle => self.background_frames % BACKGROUND_THROTTLE != 0,
+            BackgroundMode::Run => false,
+        }
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Runs callback once per frame, when the PPU enters vblank and right after the frame
+    // went to the video sink. Callbacks run in the order they were added.
+    pub fn on_vblank<F>(&mut self, callback: F) -> VblankHandle
+        where F: FnMut(&mut Vblank) + Send + 'static {
+        let handle = VblankHandle(self.next_vblank_handle);
+        self.next_vblank_handle += 1;
+        self.vblank_callbacks.push((handle, Box::new(callback)));
+        handle
+    }
+
+    pub fn remove_vblank(&mut self, handle: VblankHandle) {
+        self.vblank_callbacks.retain(|(h, _)| *h != handle);
+    }
+
+    // Writes poke at the vblank that ends frame (frame_count counts from 1), see poke.rs
+    pub fn schedule_poke(&mut self, frame: u64, poke: Poke) {
+        self.pokes.at(frame, poke);
+    }
+
+    // Writes poke at every vblank from now on, like a GameShark code
+    pub fn poke_every_frame(&mut self, poke: Poke) {
+        self.pokes.every_frame(poke);
+    }
+
+    pub fn clear_pokes(&mut self) {
+        self.pokes.clear();
+    }
+
+    // Counters for the last completed frame
+    pub fn last_frame_stats(&self) -> &FrameStats {
+        &self.last_frame_stats
+    }
+
+    // Watches are checked once per frame, see watch.rs
+    pub fn watch(&mut self, expr: WatchExpr) -> WatchHandle {
+        self.watches.add(expr, &mut self.cpu)
+    }
+
+    pub fn unwatch(&mut self, handle: WatchHandle) {
+        self.watches.remove(handle);
+    }
+
+    pub fn watches(&self) -> &WatchList {
+        &self.watches
+    }
+
+    // Watched values that changed during the last completed frame
+    pub fn watch_changes(&self) -> &[WatchChange] {
+        self.watches.changes()
+    }
+
+    // Debug HUD drawn over every frame from run_frame / advance_frame (see overlay.rs)
+    pub fn set_overlay(&mut self, overlay: Option<Overlay>) {
+        self.overlay = overlay;
+    }
+
+    pub fn overlay_mut(&mut self) -> Option<&mut Overlay> {
+        self.overlay.as_mut()
+    }
+
+    fn watch_lines(&self) -> Vec<String> {
+        self.watches.values()
+            .map(|(_, expr, value)| format!("{} {:04X}", expr, value))
+            .collect()
+    }
+
+    fn vblank(&mut self) {
+        let mut audio_sink = self.audio_sink.take();
+        match audio_sink {
+            Some(ref mut sink) if !self.background => self.end_frame(Some(&mut **sink)),
+            _ => self.end_frame(None),
+        }
+        self.audio_sink = audio_sink;
+        self.watches.update(&mut self.cpu);
+        if self.hotload.poll(&mut self.cpu.interconnect.cart) {
+            self.cpu.rom_changed();
+        }
+        if let Some(view) = self.memory_view.take().filter(MemoryView::is_watched) {
+            view.publish(self.memory_snapshot());
+            self.memory_view = Some(view);
+        }
+        self.frames_since_autosave += 1;
+        if self.autosave_frames > 0 && self.frames_since_autosave >= self.autosave_frames {
+            self.frames_since_autosave = 0;
+            // A locked up game is not worth resuming, keep the last good autosave
+            if self.fault().is_none() {
+                if let Err(e) = self.write_autosave() {
+                    warn!(target: "gbrust::save", "could not autosave: {}", e);
+                }
+            }
+        }
+    }
+
+    // What ends every frame, run_frames' included
+    fn end_frame(&mut self, audio_sink: Option<&mut dyn AudioSink>) {
+        self.frame_count += 1;
+        if let Some(sink) = audio_sink {
+            sink.samples_available(self.cpu.interconnect.apu.samples());
+        }
+        self.cpu.interconnect.apu.clear_samples();
+        self.last_frame_stats = mem::take(&mut self.cpu.interconnect.stats);
+        for poke in self.pokes.due(self.frame_count) {
+            self.cpu.interconnect.write(poke.addr, poke.val);
+        }
+        let mut vblank = Vblank {
+            frame: self.frame_count,
+            interconnect: &mut self.cpu.interconnect,
+        };
+        for (_, callback) in self.vblank_callbacks.iter_mut() {
+            callback(&mut vblank);
+        }
+        if let Some(ref mut log) = self.event_log {
+            let line = events::frame_json(self.frame_count, &self.cpu.interconnect.take_events());
+            if let Err(e) = writeln!(log, "{}", line) {
+                warn!(target: "gbrust::events", "could not write the event log, stopping it: {}", e);
+                self.set_event_log(None);
+            }
+        }
+        if let Some(fault) = self.cpu.fault() {
+            self.report_crash(&fault.to_string());
+        }
+    }
+
+    fn report_crash(&mut self, reason: &str) {
+        if self.config.crash_reports && !self.crash_reported {
+            self.crash_reported = true;
+            if let Err(e) = self.write_crash_report(reason) {
+                error!(target: "gbrust::crash", "could not write a crash report: {}", e);
+            }
+        }
+    }
+
+    // For batch runs of many ROMs, where one broken game must not take the rest down with it.
+    // A resilient console gives up at the first sign of trouble: the CPU locking up (see
+    // CpuFault), a bus error (see BusError) or the emulator itself panicking. It stops where it
+    // is, mid-frame, writes a crash report, and from then on runs no more frames; run_frames
+    // returns early and run_frame and advance_frame return the stats of what they ran, as at a
+    // breakpoint.
+    // failure() says why. Otherwise faults lock up the game and bus errors read 0xFF, as on
+    // hardware, and panics unwind to the caller.
+    pub fn set_resilient(&mut self, enabled: bool) {
+        self.resilient = enabled;
+    }
+
+    pub fn is_resilient(&self) -> bool {
+        self.resilient
+    }
+
+    // Why a resilient console stopped. A failed console stays failed, make a new one.
+    pub fn failure(&self) -> Option<&RunFailure> {
+        self.failure.as_ref()
+    }
+
+    fn fail(&mut self, failure: RunFailure) {
+        self.report_crash(&failure.to_string());
+        self.failure = Some(failure);
+    }
+
+    // True once the game has done something a resilient console gives up on
+    fn check_failure(&mut self) -> bool {
+        let failure = match (self.cpu.fault(), self.cpu_bus_error) {
+            (Some(fault), _) => RunFailure::Fault(fault),
+            (None, Some(err)) => RunFailure::Bus(err),
+            (None, None) => return false,
+        };
+        self.fail(failure);
+        true
+    }
+
+    // Set once the CPU has locked up, see CpuFault. The console keeps running frames, with the
+    // screen as the game left it.
+    pub fn fault(&self) -> Option<CpuFault> {
+        self.cpu.fault()
+    }
+
+    // Whether the console writes crash reports, and keeps the trace of the last instructions
+    // for them. On by default, see EmuConfig::crash_reports.
+    pub fn set_crash_reports(&mut self, enabled: bool) {
+        self.config.crash_reports = enabled;
+        self.cpu.keep_recent_instructions(enabled);
+    }
+
+    // What a crash report of the console as it is now holds, see crash.rs. The trace is empty
+    // with crash reports off.
+    pub fn crash_report(&mut self, reason: &str) -> CrashReport {
+        let recent: Vec<(RegisterSnapshot, usize)> = self.cpu.recent_instructions().iter().copied().collect();
+        let interconnect = &mut self.cpu.interconnect;
+        let trace = recent.iter().map(|(regs, rom_bank)| {
+            let instruction = disasm::decode(regs.pc, *rom_bank, |addr| interconnect.peek(addr));
+            format!("{:<36} {}", instruction.to_string(), crash::register_text(regs))
+        }).collect();
+        CrashReport {
+            reason: reason.to_string(),
+            title: self.cart().get_title(),
+            hashes: self.cart().hashes(),
+            frame: self.frame_count,
+            registers: self.cpu.registers(),
+            trace,
+            memory: (0..=0xFFFF).map(|addr| self.cpu.interconnect.peek(addr)).collect(),
+            state: self.save_state(),
+        }
+    }
+
+    // Writes a crash report bundle to the config's crash_dir, for when a frontend finds the
+    // console in a state it should not be in. Illegal opcodes are reported without asking,
+    // unless crash reports are off.
+    pub fn write_crash_report(&mut self, reason: &str) -> io::Result<PathBuf> {
+        let dir = self.config.crash_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        let bundle = self.crash_report(reason).write_bundle(&dir)?;
+        error!(target: "gbrust::crash", "{}, crash report written to {}", reason, bundle.display());
+        self.crash_bundle = Some(bundle.clone());
+        Ok(bundle)
+    }
+
+    // Writes what happens in every frame to log, a JSON line as each frame ends, see events.rs.
+    // Pass None to stop.
+    pub fn set_event_log(&mut self, log: Option<Box<dyn io::Write + Send>>) {
+        self.cpu.interconnect.record_events(log.is_some());
+        self.event_log = log;
+    }
+
+    // Where the last crash report went
+    pub fn crash_bundle(&self) -> Option<&Path> {
+        self.crash_bundle.as_deref()
+    }
+
+    // Memory as of the last VBlank for other threads, updated every frame until every view is
+    // dropped, see memview.rs
+    pub fn memory_view(&mut self) -> MemoryView {
+        if self.memory_view.is_none() {
+            self.memory_view = Some(MemoryView::new(self.memory_snapshot()));
+        }
+        self.memory_view.clone().unwrap()
+    }
+
+    // The whole address space, without disturbing the game
+    pub fn memory_snapshot(&mut self) -> MemorySnapshot {
+        let rom_bank = self.cart().rom_bank();
+        let interconnect = &mut self.cpu.interconnect;
+        MemorySnapshot::new(self.frame_count, rom_bank, |addr| interconnect.peek(addr))
+    }
+
+    // Remote control for other threads, see remote.rs. Handles can be cloned, and all of them
+    // talk to this console.
+    pub fn handle(&mut self) -> ConsoleHandle {
+        self.remote.get_or_insert_with(Remote::new).handle()
+    }
+
+    // Serves everything ConsoleHandles asked for since the last call. run_frame calls this
+    // before every frame, frontends driving the console with advance_* alone must call it
+    // themselves.
+    pub fn process_commands(&mut self) {
+        let mut remote = match self.remote.take() {
+            Some(remote) => remote,
+            None => return,
+        };
+        for command in remote.commands.try_iter() {
+            match command {
+                Command::Pause => self.pause(),
+                Command::Resume => self.resume(),
+                Command::Step if self.paused => remote.steps += 1,
+                Command::Step => {}
+                Command::Input(input_event) => self.handle_event(input_event),
+                Command::Screenshot(reply) => {
+                    let _ = reply.send(remote.last_frame.clone());
+                }
+                Command::Subscribe(subscriber) => remote.subscribers.push(subscriber),
+                Command::ReadMemory { addr, len, reply } => {
+                    let bytes = (0..len)
+                        .map(|offset| self.cpu.interconnect.peek(addr.wrapping_add(offset as u16)))
+                        .collect();
+                    let _ = reply.send(bytes);
+                }
+                Command::WriteMemory { addr, bytes } => {
+                    self.forget_steps();
+                    for (offset, val) in bytes.into_iter().enumerate() {
+                        self.cpu.interconnect.write(addr.wrapping_add(offset as u16), val);
+                    }
+                }
+                #[cfg(feature = "server")]
+                Command::Debug(operation) => operation(self),
+                Command::SubscribeStops(subscriber) => remote.stop_subscribers.push(subscriber),
+            }
+        }
+        if !self.paused {
+            remote.steps = 0;
+        }
+        self.remote = Some(remote);
+    }
 
+    // Passes video_sink to run, with a copy of every frame kept for screenshots once a handle
+    // was handed out
+    fn with_remote_sink<R>(&mut self, video_sink: &mut dyn VideoSink,
+                           run: fn(&mut Console, &mut dyn VideoSink) -> R) -> R {
+        match self.remote.take() {
+            Some(mut remote) => {
+                let result = run(self, &mut RemoteSink { remote: &mut remote, video_sink });
+                self.remote = Some(remote);
+                result
+            }
+            None => run(self, video_sink),
+        }
+    }
+
+    // Runs until the next frame is done and returns what happened in it. Does nothing while
+    // paused, and returns empty stats, unless a ConsoleHandle asked for a step.
+    pub fn run_frame(&mut self, video_sink: &mut dyn VideoSink) -> FrameStats {
+        self.process_commands();
+        let step = self.paused && self.remote.as_mut().is_some_and(Remote::take_step);
+        if !step && (self.paused || self.held_back()) {
+            return FrameStats::default();
+        }
+        let stats = self.advance_frame(video_sink);
+        if let (Some(at), Some(remote)) = (self.breakpoint_hit, self.remote.as_mut()) {
+            remote.stopped(at);
+        }
+        stats
+    }
+
+    // Runs n frames back to back, for throughput: training agents, brute-forcing inputs. Runs
+    // paused or not, and skips what run_frame does between frames for a person watching: no
+    // overlay, LCD persistence, remote control, watches or memory view updates. The console's
+    // own audio sink is passed over for sinks.audio. Queued input, vblank callbacks and crash
+    // reports work as usual. Stops early at a breakpoint; returns the frames run.
+    pub fn run_frames(&mut self, n: u32, sinks: &mut FrameSinks) -> u32 {
+        self.forget_steps();
+        self.breakpoint_hit = None;
+        let mut no_video = NoVideo;
+        for frame in 0..n {
+            self.play_queued_input();
+            let video_sink: &mut dyn VideoSink = match sinks.video {
+                Some(ref mut sink) => &mut **sink,
+                None => &mut no_video,
+            };
+            if !self.run_until_frame(video_sink) {
+                self.take_partial_stats();
+                return frame;
+            }
+            match sinks.audio {
+                Some(ref mut sink) => self.end_frame(Some(&mut **sink)),
+                None => self.end_frame(None),
+            }
+        }
+        n
+    }
+
+    // Frame advance: runs exactly one frame, paused or not. Stops early, pausing, when a
+    // breakpoint is reached, and then returns the stats of the part it ran. Those are not counted
+    // again when the frame resumes.
+    pub fn advance_frame(&mut self, video_sink: &mut dyn VideoSink) -> FrameStats {
+        self.with_remote_sink(video_sink, Console::advance_frame_to)
+    }
+
+    fn advance_frame_to(&mut self, video_sink: &mut dyn VideoSink) -> FrameStats {
+        self.play_queued_input();
+        self.forget_steps();
+        self.breakpoint_hit = None;
+        let _frame = trace_span!(target: "gbrust::cpu", "frame").entered();
+        let mut overlay = self.overlay.take();
+        let mut persistence = self.persistence.take();
+        let mut overlay_sink;
+        let video_sink: &mut dyn VideoSink = match overlay {
+            Some(ref mut overlay) => {
+                let lines = overlay.lines(self.cart().rom_bank(), &self.watch_lines());
+                overlay_sink = OverlaySink::new(overlay, lines, video_sink);
+                &mut overlay_sink
+            }
+            None => video_sink,
+        };
+        let frame_done = match persistence {
+            Some(ref mut persistence) => self.run_until_frame(&mut PersistenceSink::new(persistence, video_sink)),
+            None => self.run_until_frame(video_sink),
+        };
+        self.overlay = overlay;
+        self.persistence = persistence;
+        if !frame_done {
+            return self.take_partial_stats();
+        }
+        self.vblank();
+        self.last_frame_stats.clone()
+    }
+
+    // Stats of a frame cut short. Taking them keeps them out of the stats of the resumed frame.
+    fn take_partial_stats(&mut self) -> FrameStats {
+        mem::take(&mut self.cpu.interconnect.stats)
+    }
 
+    // False when stopped at a breakpoint, or failed when resilient
+    fn run_until_frame(&mut self, video_sink: &mut dyn VideoSink) -> bool {
+        if self.failure.is_some() {
+            return false;
+        }
+        if !self.resilient {
+            return self.run_until_frame_unguarded(video_sink);
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| self.run_until_frame_unguarded(video_sink))) {
+            Ok(frame_done) => frame_done,
+            Err(payload) => {
+                let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                self.fail(RunFailure::Panic(message));
+                false
+            }
+        }
+    }
+
+    fn run_until_frame_unguarded(&mut self, video_sink: &mut dyn VideoSink) -> bool {
+        if self.render_thread.is_some() {
+            let mut no_video = NoVideo;
+            let mut frame_handler = FrameHandler::new(&mut no_video);
+            let frame_done = self.run_until_frame_or_break(&mut frame_handler);
+            if frame_done {
+                self.deliver_rendered_frame(video_sink);
+            }
+            frame_done
+        } else {
+            self.run_until_frame_or_break(&mut FrameHandler::new(video_sink))
+        }
+    }
+
+    fn run_until_frame_or_break(&mut self, frame_handler: &mut FrameHandler) -> bool {
+        if self.resilient && self.check_failure() {
+            return false;
+        }
+        // The first instruction always runs, so resuming from a breakpoint gets past it
+        self.step(frame_handler);
+        while !frame_handler.frame_available {
+            if self.at_breakpoint() || (self.resilient && self.check_failure()) {
+                return false;
+            }
+            self.step(frame_handler);
+        }
+        true
+    }
+
+    fn at_breakpoint(&mut self) -> bool {
+        if self.breakpoints.is_empty() && self.break_conditions.is_empty() {
+            return false;
+        }
+        let here = self.pc();
+        let dispatch = self.cpu.last_dispatch();
+        if !self.breakpoints.iter().any(|breakpoint| breakpoint.matches(here))
+            && !self.break_conditions.iter().any(|condition| condition.matches(dispatch)) {
+            return false;
+        }
+        debug!(target: "gbrust::cpu", "breakpoint at {}", here);
+        self.breakpoint_hit = Some(here);
+        self.paused = true;
+        true
+    }
+
+    // Stops running frames, and pauses, right before the instruction at addr. Single steps
+    // with advance_instruction do not stop.
+    pub fn add_breakpoint(&mut self, addr: BankedAddr) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: BankedAddr) {
+        self.breakpoints.retain(|&breakpoint| breakpoint != addr);
+    }
+
+    pub fn breakpoints(&self) -> &[BankedAddr] {
+        &self.breakpoints
+    }
+
+    // Stops running frames, and pauses, once an instruction or interrupt enters code through a
+    // vector the condition names, see debugger.rs. breakpoint_hit is then the vector.
+    pub fn add_break_condition(&mut self, condition: BreakOn) {
+        if !self.break_conditions.contains(&condition) {
+            self.break_conditions.push(condition);
+        }
+    }
+
+    pub fn remove_break_condition(&mut self, condition: BreakOn) {
+        self.break_conditions.retain(|&other| other != condition);
+    }
+
+    pub fn break_conditions(&self) -> &[BreakOn] {
+        &self.break_conditions
+    }
+
+    // Labels to name addresses with, see symbols.rs
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Keeps a shadow call stack from now on, see debugger.rs. Pass false to stop.
+    pub fn track_calls(&mut self, enabled: bool) {
+        self.cpu.track_calls(enabled);
+    }
+
+    // The functions being run, innermost first, named from the symbols. Empty when calls are
+    // not tracked.
+    pub fn call_stack(&self) -> Vec<StackFrame> {
+        let frames = self.cpu.call_stack().map_or(&[][..], |call_stack| call_stack.frames());
+        frames.iter().rev().map(|frame| StackFrame { name: self.symbols.label(frame.target), ..frame.clone() }).collect()
+    }
+
+    // Counts the cycles spent in each function from now on, see profile.rs. Tracks calls, and
+    // keeps tracking them after the profile stops.
+    pub fn start_profiling(&mut self) {
+        self.track_calls(true);
+        self.profile = Some(Profile::default());
+    }
+
+    pub fn stop_profiling(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    // Where the last frame stopped, if it was at a breakpoint. Cleared on resume.
+    pub fn breakpoint_hit(&self) -> Option<BankedAddr> {
+        self.breakpoint_hit
+    }
+
+    // The next instruction to run, with its ROM bank
+    pub fn pc(&self) -> BankedAddr {
+        BankedAddr::resolve(self.cpu.registers().pc, self.cart().rom_bank())
+    }
+
+    // Where addr is with the current ROM bank mapped
+    pub fn region(&self, addr: u16) -> Region {
+        Region::of(addr, self.cart().rom_bank())
+    }
+
+    // len bytes from addr on, as mapped now, with every row labelled (see memmap.rs). Reading
+    // them has no side effects.
+    pub fn hexdump(&mut self, addr: u16, len: usize) -> String {
+        let bytes: Vec<u8> = (0..len)
+            .map(|offset| self.cpu.interconnect.peek(addr.wrapping_add(offset as u16)))
+            .collect();
+        memmap::hexdump(addr, &bytes, self.cart().rom_bank())
+    }
+
+    // count instructions from addr on, as mapped now, see disasm.rs. Reading them has no side
+    // effects.
+    pub fn disassemble(&mut self, addr: u16, count: usize) -> Vec<Instruction> {
+        let rom_bank = self.cart().rom_bank();
+        let interconnect = &mut self.cpu.interconnect;
+        let mut addr = addr;
+        (0..count).map(|_| {
+            let instruction = disasm::decode(addr, rom_bank, |addr| interconnect.peek(addr));
+            addr = addr.wrapping_add(instruction.bytes.len() as u16);
+            instruction
+        }).collect()
+    }
+
+    // Every IO register, as the CPU would read it but without side effects, see ioregs.rs
+    pub fn io_registers(&mut self) -> IoSnapshot {
+        let interconnect = &mut self.cpu.interconnect;
+        IoSnapshot::new(|addr| interconnect.peek(addr))
+    }
+
+    // VRAM, OAM and the registers to draw them with, see gfx.rs
+    pub fn vram_snapshot(&self) -> VramSnapshot {
+        self.cpu.interconnect.ppu.vram_snapshot()
+    }
+    
+    // Raises and silences interrupts for tests, see interrupts.rs. None takes the script out.
+    #[doc(hidden)]
+    pub fn set_interrupt_script(&mut self, script: Option<InterruptScript>) {
+        self.cpu.interconnect.interrupts.set_script(script);
+    }
+
+    #[doc(hidden)]
+    pub fn interrupt_script(&self) -> Option<&InterruptScript> {
+        self.cpu.interconnect.interrupts.script()
+    }
+
+    // Runs a single instruction (plus the interrupt it may trigger), paused or not. Returns the
+    // cycles it took. If it finished a frame, the frame is passed on as usual, without the
+    // debug overlay.
+    pub fn advance_instruction(&mut self, video_sink: &mut dyn VideoSink) -> u32 {
+        self.with_remote_sink(video_sink, Console::advance_instruction_to)
+    }
+
+    fn advance_instruction_to(&mut self, video_sink: &mut dyn VideoSink) -> u32 {
+        if let Some(mut history) = self.step_history.take() {
+            let state = if history.needs_checkpoint() { Some(self.save_state()) } else { None };
+            history.record(self.cpu.registers(), self.frame_count, state);
+            self.step_history = Some(history);
+        }
+        let (cycles, frame_done) = if self.render_thread.is_some() {
+            let mut no_video = NoVideo;
+            let mut frame_handler = FrameHandler::new(&mut no_video);
+            let cycles = self.step(&mut frame_handler);
+            if frame_handler.frame_available {
+                self.deliver_rendered_frame(video_sink);
+            }
+            (cycles, frame_handler.frame_available)
+        } else {
+            let mut frame_handler = FrameHandler::new(video_sink);
+            (self.step(&mut frame_handler), frame_handler.frame_available)
+        };
+        if frame_done {
+            self.vblank();
+        }
+        cycles
+    }
+
+    // Journals instructions run by advance_instruction so they can be undone with step_back,
+    // up to limit of them (see debugger.rs). Pass None to stop.
+    pub fn record_steps(&mut self, limit: Option<usize>) {
+        self.step_history = limit.map(StepHistory::new);
+    }
+
+    pub fn step_history(&self) -> Option<&StepHistory> {
+        self.step_history.as_ref()
+    }
+
+    // Undoes the last instruction run by advance_instruction. False when there is nothing left
+    // to undo. Vblank callbacks and video output are not replayed.
+    pub fn step_back(&mut self) -> bool {
+        let mut history = match self.step_history.take() {
+            Some(history) => history,
+            None => return false,
+        };
+        let done = match history.rewind() {
+            Some(rewind) => {
+                self.load_state_unchecked(rewind.state).expect("Own save state does not load back");
+                self.frame_count = rewind.frame_count;
+                let mut no_video = NoVideo;
+                for _ in 0..rewind.replay {
+                    let mut frame_handler = FrameHandler::new(&mut no_video);
+                    self.cpu.step(&mut frame_handler);
+                    if frame_handler.frame_available {
+                        self.frame_count += 1;
+                    }
+                }
+                self.cpu.interconnect.ppu.take_scanlines();
+                self.refresh_render_thread();
+                true
+            }
+            None => false,
+        };
+        self.step_history = Some(history);
+        done
+    }
+
+    // Runs a CALL or RST through to its return, any other instruction as advance_instruction
+    // does. Stops early at a breakpoint, see debugger.rs.
+    pub fn step_over(&mut self, video_sink: &mut dyn VideoSink) -> StepEnd {
+        let start = self.cpu.registers();
+        match debugger::call_length(self.cpu.interconnect.peek(start.pc)) {
+            Some(length) => {
+                let return_to = start.pc.wrapping_add(length);
+                self.step_until(video_sink, |regs, _| regs.pc == return_to && regs.sp >= start.sp)
+            }
+            None => {
+                self.advance_instruction(video_sink);
+                StepEnd::Done
+            }
+        }
+    }
+
+    // Runs until the function PC is in returns, stopping early at a breakpoint
+    pub fn step_out(&mut self, video_sink: &mut dyn VideoSink) -> StepEnd {
+        let sp = self.cpu.registers().sp;
+        self.step_until(video_sink, |regs, opcode| debugger::is_return(opcode) && regs.sp > sp)
+    }
+
+    // Steps until done says so, given the registers after an instruction and its opcode
+    fn step_until(&mut self, video_sink: &mut dyn VideoSink, done: impl Fn(&RegisterSnapshot, u8) -> bool) -> StepEnd {
+        self.breakpoint_hit = None;
+        let last_frame = self.frame_count + STEP_FRAME_LIMIT;
+        loop {
+            let opcode = self.cpu.interconnect.peek(self.cpu.registers().pc);
+            self.advance_instruction(video_sink);
+            if done(&self.cpu.registers(), opcode) {
+                return StepEnd::Done;
+            }
+            if self.at_breakpoint() {
+                return StepEnd::Breakpoint;
+            }
+            if self.frame_count >= last_frame {
+                return StepEnd::Limit;
+            }
+        }
+    }
+
+    fn forget_steps(&mut self) {
+        if let Some(ref mut history) = self.step_history {
+            history.clear();
+        }
+    }
+
+    pub fn registers(&self) -> RegisterSnapshot {
+        self.cpu.registers()
+    }
+
+    fn deliver_rendered_frame(&mut self, video_sink: &mut dyn VideoSink) {
+        if let Some(ref thread) = self.render_thread {
+            if let Some(frame) = thread.latest_frame() {
+                video_sink.frame_available(&Frame::dmg(&frame));
+                thread.recycle(frame);
+            }
+        }
+    }
+
+    fn step(&mut self, video_sink: &mut dyn VideoSink) -> u32 {
+        let seen = self.cpu.interconnect.bus_errors().len();
+        let cycles = self.cpu.step(video_sink);
+        if self.cpu_bus_error.is_none() {
+            self.cpu_bus_error = self.cpu.interconnect.bus_errors().get(seen).copied();
+        }
+        if let Some(ref mut profile) = self.profile {
+            profile.add(self.cpu.call_stack().map_or(&[], |call_stack| call_stack.frames()), cycles);
+        }
+        if let Some(ref mut hook) = self.scanline_hook {
+            for regs in self.cpu.interconnect.ppu.take_scanlines() {
+                hook(&regs);
+            }
+        }
+        if self.cpu.last_dispatch().debug == Some(DebugOpcode::Message) {
+            self.debug_message();
+        }
+        cycles
+    }
+
+    fn debug_message(&mut self) {
+        let regs = self.cpu.registers();
+        let at = BankedAddr::resolve(regs.pc.wrapping_sub(1), self.cart().rom_bank());
+        let interconnect = &mut self.cpu.interconnect;
+        let message = debugger::debug_message(|addr| interconnect.peek(addr), &regs);
+        info!(target: "gbrust::debug", "{}: {}", at, message);
+        if let Some(ref mut hook) = self.debug_message_hook {
+            hook(at, &message);
+        }
+    }
+
+    // Calls hook with every LD D,D debug message the game sends. Pass None to stop.
+    pub fn set_debug_message_hook(&mut self, hook: Option<DebugMessageHook>) {
+        self.debug_message_hook = hook;
+    }
+
+    // Calls hook at the start of every visible scanline with the registers that shape it. The
+    // hook runs right after the instruction during which the line started. Pass None to stop.
+    pub fn set_scanline_hook(&mut self, hook: Option<ScanlineHook>) {
+        self.cpu.interconnect.ppu.record_scanlines(hook.is_some());
+        self.scanline_hook = hook;
+    }
+
+    // Frame advance that also records when the PPU changed modes and requested STAT interrupts
+    // during the frame, see timeline.rs. The timeline starts where the last frame ended, in
+    // VBlank, and stops early at a breakpoint.
+    pub fn record_timeline(&mut self, video_sink: &mut dyn VideoSink) -> PpuTimeline {
+        self.cpu.interconnect.ppu.record_timeline(true);
+        self.advance_frame(video_sink);
+        let timeline = self.cpu.interconnect.ppu.take_timeline();
+        self.cpu.interconnect.ppu.record_timeline(false);
+        timeline
+    }
+
+    // Frame advance that also records every VRAM write the CPU makes during the frame, with the
+    // instruction that made it, see vram_log.rs. Like record_timeline, it starts where the last
+    // frame ended and stops early at a breakpoint.
+    pub fn record_vram_writes(&mut self, video_sink: &mut dyn VideoSink) -> VramLog {
+        let start = self.cpu.interconnect.ppu.clock();
+        self.cpu.interconnect.record_vram_writes(true);
+        self.advance_frame(video_sink);
+        let writes = self.cpu.interconnect.take_vram_writes();
+        self.cpu.interconnect.record_vram_writes(false);
+        VramLog { start, end: self.cpu.interconnect.ppu.clock(), writes }
+    }
+
+    // Sends the APU's output to sink once per frame (see wav.rs for ripping it to a file).
+    // Samples are only mixed while a sink is attached. Pass None to stop.
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn AudioSink + Send>>) {
+        let rate = sink.as_ref().map(|_| self.config.audio_sample_rate);
+        self.cpu.interconnect.apu.set_sample_rate(rate);
+        self.audio_sink = sink;
+    }
+
+    // Detaches the audio sink and hands it back
+    pub fn take_audio_sink(&mut self) -> Option<Box<dyn AudioSink + Send>> {
+        self.cpu.interconnect.apu.set_sample_rate(None);
+        self.audio_sink.take()
+    }
+
+    // Records every sound register write from now on, for replaying the music elsewhere (see
+    // apu_log.rs)
+    pub fn start_audio_log(&mut self) {
+        self.cpu.interconnect.apu.start_log();
+    }
+
+    pub fn stop_audio_log(&mut self) -> Option<ApuLog> {
+        self.cpu.interconnect.apu.take_log()
+    }
+
+    // Traps RST targets and interrupt vectors to a host callback, see Cpu::set_vector_trap
+    pub fn set_vector_trap(&mut self, trap: Option<VectorTrap>) {
+        self.cpu.set_vector_trap(trap);
+    }
+
+    pub fn handle_event(&mut self, input_event: InputEvent) {
+        self.forget_steps();
+        self.cpu.interconnect.gamepad.handle_event(input_event);
+    }
+
+    // Sub-frame input: poller is called whenever the game reads the joypad, and what it returns
+    // is applied before the game sees the buttons. Lower latency than handle_event between
+    // frames, and replays that poll from a recording stay exact. Pass None to stop.
+    pub fn set_input_poller(&mut self, poller: Option<InputPoller>) {
+        self.cpu.interconnect.gamepad.set_poller(poller);
+    }
+
+    // Link cable, other end: clocks byte in when the game waits for a transfer on the external
+    // clock, and returns the byte it sent. None, with nothing changed, when the game is not
+    // waiting.
+    pub fn serial_exchange(&mut self, byte: u8) -> Option<u8> {
+        let interconnect = &mut self.cpu.interconnect;
+        interconnect.serial.exchange(byte, &mut interconnect.interrupts)
+    }
+
+    // Plugs a device into the link port, at any time, returning the one it replaces. It gets
+    // every byte the game sends with its own clock, see serial.rs. A transfer in progress
+    // finishes with the new device.
+    pub fn attach_serial(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        self.cpu.interconnect.serial.attach(device)
+    }
+
+    // Unplugs the link port's device. With nothing plugged in the game receives 0xFF.
+    pub fn detach_serial(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.cpu.interconnect.serial.detach()
+    }
+
+    pub fn has_serial_device(&self) -> bool {
+        self.cpu.interconnect.serial.is_attached()
+    }
+
+    // Keeps what the game sends over the link cable with its own clock, for take_serial_output.
+    // Test ROMs print their results this way.
+    pub fn record_serial_output(&mut self, enabled: bool) {
+        self.cpu.interconnect.serial.record_output(enabled);
+    }
+
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.cpu.interconnect.serial.take_output()
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.handle_event(InputEvent::new(button, ButtonState::Down));
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.handle_event(InputEvent::new(button, ButtonState::Up));
+    }
+
+    // Holds exactly the buttons in mask (see Button::mask) and releases the others
+    pub fn set_buttons(&mut self, mask: u8) {
+        for &button in Button::ALL.iter() {
+            if mask & button.mask() != 0 {
+                self.press(button);
+            } else {
+                self.release(button);
+            }
+        }
+    }
+
+    // Plays input_macro after whatever is queued already, see input_macro.rs
+    pub fn queue_input(&mut self, input_macro: &InputMacro) {
+        self.queued_input.extend(input_macro.frames());
+    }
+
+    // Frames of queued input not played yet
+    pub fn queued_input(&self) -> usize {
+        self.queued_input.len()
+    }
+
+    // Drops the queued input. Buttons are let go on the next frame.
+    pub fn clear_queued_input(&mut self) {
+        self.queued_input.clear();
+    }
+
+    fn play_queued_input(&mut self) {
+        match self.queued_input.pop_front() {
+            Some(buttons) => {
+                self.set_buttons(buttons);
+                self.playing_input = true;
+            }
+            None if self.playing_input => {
+                self.set_buttons(0);
+                self.playing_input = false;
+            }
+            None => {}
+        }
+    }
+
+    // Starts the game over right away, between two instructions. Settings, hooks, breakpoints
+    // and held buttons stay as they are, and so does cartridge RAM, which has its own battery.
+    pub fn reset(&mut self, kind: ResetKind) {
+        let seed = match kind {
+            ResetKind::PowerCycle => self.entropy_hook.as_mut().map(|hook| hook()),
+            ResetKind::Soft => None,
+        };
+        self.reset_seeded(kind, seed);
+    }
+
+    // A power cycle with seed's RAM and divider phase, as the entropy hook gave it, to replay a
+    // run. The hook is not called.
+    pub fn power_on_seeded(&mut self, seed: u64) {
+        self.reset_seeded(ResetKind::PowerCycle, Some(seed));
+    }
+
+    fn reset_seeded(&mut self, kind: ResetKind, seed: Option<u64>) {
+        self.forget_steps();
+        self.breakpoint_hit = None;
+        self.crash_reported = false;
+        self.cpu.reset(kind == ResetKind::Soft);
+        if kind == ResetKind::PowerCycle {
+            self.frame_count = 0;
+            self.entropy_seed = seed;
+            if let Some(seed) = seed {
+                self.cpu.interconnect.perturb(seed);
+            }
+        }
+        self.refresh_render_thread();
+    }
+
+    // For fuzzing and reinforcement learning, where every run should start a little
+    // differently: from now on every power cycle calls hook for a seed, and fills RAM and sets
+    // the divider's phase from it instead of the config's memory_init. Games that take their
+    // random numbers from either then play out differently. The seed is kept (entropy_seed), so
+    // a run can be replayed with power_on_seeded, or a movie carrying it (see Movie::set_seed).
+    pub fn set_entropy_hook<F>(&mut self, hook: F)
+        where F: FnMut() -> u64 + Send + 'static {
+        self.entropy_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_entropy_hook(&mut self) {
+        self.entropy_hook = None;
+    }
+
+    // What the last power cycle was seeded with, None if it was not
+    pub fn entropy_seed(&self) -> Option<u64> {
+        self.entropy_seed
+    }
+
+    // Snapshot of the whole machine, see state.rs for the format
+    pub fn save_state(&self) -> Box<[u8]> {
+        let mut state = StateWriter::new();
+        state.write_u16(self.cart().global_checksum());
+        self.cpu.save_state(&mut state);
+        state.finish()
+    }
+
+    // Restores a snapshot taken by save_state. On error the console is left as it was.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        self.forget_steps();
+        self.crash_reported = false;
+        let backup = self.save_state();
+        if let Err(e) = self.load_state_unchecked(state) {
+            self.load_state_unchecked(&backup).expect("Own save state does not load back");
+            return Err(e);
+        }
+
+        self.refresh_render_thread();
+        Ok(())
+    }
+
+    // Loads a save state from another emulator, best effort, see bess.rs. Registers and memory
+    // come over, the rest carries on from this console's state.
+    pub fn import_state(&mut self, state: &[u8]) -> Result<(), StateImportError> {
+        let state = bess::parse(state)?;
+        if state.global_checksum.is_some_and(|checksum| checksum != self.cart().global_checksum()) {
+            return Err(StateImportError::WrongRom);
+        }
+        self.forget_steps();
+        self.cpu.interconnect.load_foreign(&state);
+        self.cpu.set_registers(state.regs);
+        self.cpu.set_halted(state.halted);
+        self.refresh_render_thread();
+        Ok(())
+    }
+
+    fn load_state_unchecked(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(state)?;
+        if state.read_u16()? != self.cart().global_checksum() {
+            return Err(StateError::WrongRom);
+        }
+        self.cpu.load_state(&mut state)
+    }
+
+    // Self-check for save states: saves, runs frames, reloads and runs them again, and fails if
+    // the second run differs in any frame or in the state it ends in. Catches fields a
+    // component forgot to save. Only the second run is shown on video_sink, but vblank
+    // callbacks and the audio sink see both.
+    pub fn check_state(&mut self, frames: usize, video_sink: &mut dyn VideoSink) -> Result<(), Divergence> {
+        let state = self.save_state();
+        let frame_count = self.frame_count;
+        let first = self.hash_frames(frames, &mut NoVideo);
+        let first_end = self.save_state();
+
+        self.load_state(&state).map_err(Divergence::Load)?;
+        self.frame_count = frame_count;
+        let second = self.hash_frames(frames, video_sink);
+        if let Some(frame) = first.iter().zip(second.iter()).position(|(a, b)| a != b) {
+            return Err(Divergence::Frame(frame));
+        }
+        let second_end = self.save_state();
+        match first_end.iter().zip(second_end.iter()).position(|(a, b)| a != b) {
+            Some(byte) => Err(Divergence::State(byte)),
+            None if first_end.len() != second_end.len() => {
+                Err(Divergence::State(first_end.len().min(second_end.len())))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn hash_frames(&mut self, frames: usize, video_sink: &mut dyn VideoSink) -> Vec<u64> {
+        let mut hasher = FrameHasher { hashes: Vec::new(), video_sink };
+        for _ in 0..frames {
+            self.advance_frame(&mut hasher);
+        }
+        hasher.hashes
+    }
+
+    // Cartridge RAM and clock as a .sav other emulators can read, see sav.rs
+    pub fn save_file(&self) -> Option<SaveFile> {
+        self.cart().save_file()
+    }
+
+    // Where flush_save writes. The builder sets it to the .sav that goes with rom_path.
+    pub fn save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    pub fn set_save_path(&mut self, path: Option<PathBuf>) {
+        self.save_path = path;
+    }
+
+    // Writes the battery save to save_path, with a 48 byte clock footer for carts that have a
+    // clock. Does nothing without a save path, for carts with nothing to save, or when the game
+    // has not written to its RAM or clock since the last flush.
+    pub fn flush_save(&mut self) -> Result<(), SaveFileError> {
+        if !self.cart().is_save_dirty() {
+            return Ok(());
+        }
+        if let (Some(path), Some(save)) = (&self.save_path, self.save_file()) {
+            save.save(path, RtcFooter::Bytes48)?;
+            self.cpu.interconnect.cart.mark_saved();
+        }
+        Ok(())
+    }
+
+    // Swaps the cartridge, as if pulled out and another put in with the power off: the old
+    // cart's battery save is flushed first, then the console powers back on with the new one.
+    // Settings, hooks and watches stay; breakpoints, symbols and mapped banks, which point into
+    // the old game, do not.
+    // The new cart has no save path until set_save_path. If the flush fails, the old cart stays
+    // in.
+    pub fn load_rom(&mut self, cart: Cart) -> Result<(), SaveFileError> {
+        self.flush_save()?;
+        self.cpu.interconnect.swap_cart(cart);
+        self.set_model(self.config.model); // the new game may or may not be made for the CGB
+        self.cpu_bus_error = None;
+        self.save_path = None;
+        self.autosave_path = None;
+        self.breakpoints.clear();
+        self.symbols = Symbols::default();
+        self.hotload = HotLoader::default();
+        self.reset(ResetKind::PowerCycle);
+        Ok(())
+    }
+
+    // load_rom for a ROM file, e.g. one dropped on the window. Its battery save is picked up and
+    // flushed to as with ConsoleBuilder::rom_path.
+    // A console that was autosaving carries on, beside the new game's .sav.
+    pub fn load_rom_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), CartError> {
+        let path = path.as_ref();
+        let save_ram = read_save(&self.config, path)?;
+        let cart = load_cart(&fs::read(path)?, None, save_ram, &self.config)?;
+        let autosaving = self.autosave_path.is_some();
+        self.load_rom(cart)?;
+        self.save_path = Some(self.config.save_path(path));
+        if autosaving {
+            self.autosave_path = Some(self.config.autosave_path(path));
+        }
+        Ok(())
+    }
+
+    // Where write_autosave writes and load_autosave reads. None, so no autosaves, unless set;
+    // gbrust run sets it beside the .sav when the config's autosave is on.
+    pub fn autosave_path(&self) -> Option<&Path> {
+        self.autosave_path.as_deref()
+    }
+
+    pub fn set_autosave_path(&mut self, path: Option<PathBuf>) {
+        self.autosave_path = path;
+    }
+
+    // Frames between the autosaves taken at vblank, 0 for none, the default. See
+    // AUTOSAVE_FRAMES_PER_SECOND for the config's autosave_interval.
+    pub fn set_autosave_interval(&mut self, frames: u64) {
+        self.autosave_frames = frames;
+        self.frames_since_autosave = 0;
+    }
+
+    // Save states the console to autosave_path, for a frontend to call on the way out. The
+    // state goes to a temporary file first, so a crash part way through leaves the last one.
+    // Does nothing without an autosave path.
+    pub fn write_autosave(&mut self) -> io::Result<()> {
+        let path = match self.autosave_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let partial = path.with_extension("autosave.tmp");
+        fs::write(&partial, self.save_state())?;
+        fs::rename(&partial, &path)
+    }
+
+    // Picks up where the last session left off, for run --resume. Returns false if there is
+    // none, leaving the console as it is.
+    pub fn load_autosave(&mut self) -> Result<bool, AutosaveError> {
+        let path = match self.autosave_path {
+            Some(ref path) if path.exists() => path.clone(),
+            _ => return Ok(false),
+        };
+        self.load_state(&fs::read(path)?)?;
+        self.frames_since_autosave = 0;
+        Ok(true)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use super::super::interrupts::Interrupt;
+    use super::super::debugger::CallKind;
+    use super::super::compare;
+    use super::super::movie::Movie;
+
+    fn tetris() -> Cart {
+        let rom = fs::read("tetris.gb").unwrap().into_boxed_slice();
+        Cart::new(rom, None).unwrap()
+    }
+
+    fn run_frames(console: &mut Console, frames: usize) -> Box<[u32]> {
+        let mut sink = LastFrame(None);
+        for _ in 0..frames {
+            console.run_frame(&mut sink);
+        }
+        sink.0.unwrap()
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut console = Console::builder().rom_path("tetris.gb").build().unwrap();
+        run_frames(&mut console, 200);
+        console.press(Button::Start);
+
+        let state = console.save_state();
+        let first = run_frames(&mut console, 60);
+        assert_eq!(console.load_state(&state), Ok(()));
+        let second = run_frames(&mut console, 60);
+        assert!(first == second);
+
+        // A failed load leaves the console untouched
+        let before = console.save_state();
+        assert_eq!(console.load_state(b"GBRS"), Err(StateError::BadMagic));
+        assert_eq!(console.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+        assert!(console.save_state() == before);
+    }
+
+    #[test]
+    fn check_state_replays_identically() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 100);
+        let mut sink = LastFrame(None);
+        assert_eq!(console.check_state(30, &mut sink), Ok(()));
+        assert!(sink.0.is_some());
+        assert_eq!(console.frame_count(), 130);
+    }
+
+    #[test]
+    fn breakpoints_stop_frames() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 10);
+        let here = console.pc();
+        console.add_breakpoint(here);
+
+        let mut sink = LastFrame(None);
+        console.run_frame(&mut sink);
+        assert!(console.is_paused());
+        assert_eq!(console.breakpoint_hit(), Some(here));
+        assert_eq!(console.pc(), here);
+
+        // Resuming gets past the breakpoint
+        console.remove_breakpoint(here);
+        console.resume();
+        assert!(console.run_frame(&mut sink).instructions > 0);
+        assert!(!console.is_paused());
+    }
+
+    #[test]
+    fn steps_over_and_out_of_calls() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 10);
+        let mut sink = LastFrame(None);
+        while console.cpu.interconnect.peek(console.registers().pc) != 0xCD {
+            console.advance_instruction(&mut sink);
+        }
+        let call = console.registers();
+        let before = console.save_state();
+
+        assert_eq!(console.step_over(&mut sink), StepEnd::Done);
+        assert_eq!((console.registers().pc, console.registers().sp), (call.pc + 3, call.sp));
+
+        // Into the call, then out of it
+        console.load_state(&before).unwrap();
+        console.advance_instruction(&mut sink);
+        assert_eq!(console.registers().sp, call.sp - 2);
+        assert_eq!(console.step_out(&mut sink), StepEnd::Done);
+        assert_eq!((console.registers().pc, console.registers().sp), (call.pc + 3, call.sp));
+    }
+
+    #[test]
+    fn call_stack_names_frames() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 10);
+        let mut sink = LastFrame(None);
+        console.track_calls(true);
+        while console.cpu.interconnect.peek(console.registers().pc) != 0xCD {
+            console.advance_instruction(&mut sink);
+        }
+        let call = console.registers();
+        let depth = console.call_stack().len();
+        console.advance_instruction(&mut sink);
+        let target = console.pc();
+        let mut symbols = Symbols::default();
+        symbols.insert(target, "Callee");
+        console.set_symbols(symbols);
+
+        let frame = &console.call_stack()[0];
+        assert_eq!((frame.kind, frame.target, frame.name.as_deref()), (CallKind::Call, target, Some("Callee")));
+        assert_eq!((frame.return_to.addr, frame.sp), (call.pc + 3, call.sp - 2));
+        assert_eq!(console.call_stack().len(), depth + 1);
+        console.step_out(&mut sink);
+        assert_eq!(console.call_stack().len(), depth);
+    }
+
+    #[test]
+    fn profiles_add_up_to_the_frames() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 10);
+        let mut sink = LastFrame(None);
+        console.start_profiling();
+        let cycles: u64 = (0..5).map(|_| console.run_frame(&mut sink).cycles).sum();
+        let profile = console.stop_profiling().unwrap();
+        assert_eq!(profile.total_cycles(), cycles);
+
+        let mut symbols = Symbols::default();
+        symbols.insert(BankedAddr::new(0, 0x0040), "VBlankVector");
+        let folded = profile.to_folded(&symbols);
+        assert!(folded.lines().any(|line| line.starts_with("top;VBlankVector ")), "{}", folded);
+        assert!(console.stop_profiling().is_none());
+    }
+
+    #[test]
+    fn break_conditions_stop_at_vectors() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 10);
+        let mut sink = LastFrame(None);
+
+        console.add_break_condition("irq:40".parse().unwrap());
+        console.run_frame(&mut sink);
+        assert_eq!(console.breakpoint_hit(), Some(BankedAddr::new(0, 0x40)));
+        console.remove_break_condition(BreakOn::Interrupt(Interrupt::VBlank));
+        console.resume();
+
+        console.add_break_condition(BreakOn::Rst(0x28));
+        console.run_frame(&mut sink);
+        assert_eq!(console.breakpoint_hit(), Some(BankedAddr::new(0, 0x28)));
+        assert_eq!(console.break_conditions(), [BreakOn::Rst(0x28)]);
+    }
+
+    #[test]
+    fn vram_snapshots_show_the_title_screen() {
+        use super::super::ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 300);
+        let vram = console.vram_snapshot();
+        assert_eq!(vram.banks(), 1);
+        // Drawn from the background map, the screen is in the top left of it at SCX = SCY = 0
+        let map = vram.map(vram.bg_map_addr());
+        let frame = run_frames(&mut console, 1);
+        for y in 0..DISPLAY_HEIGHT {
+            assert_eq!(map.pixels[y * 256..y * 256 + DISPLAY_WIDTH], frame[y * DISPLAY_WIDTH..(y + 1) * DISPLAY_WIDTH]);
+        }
+    }
+
+    #[test]
+    fn debug_opcodes_break_and_send_messages() {
+        use std::sync::{Arc, Mutex};
+
+        let mut rom = vec![0; 0x8000];
+        let code = [
+            0x40, // ld b,b
+            0x52, 0x18, 0x09, 0x64, 0x64, 0x00, 0x00, b'a', b'=', b'%', b'A', b'%', // ld d,d "a=%A%"
+            0x52, // ld d,d without text
+            0x18, 0xFE, // jr -2
+        ];
+        rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+        let mut console = Console::new(Cart::new(rom.into_boxed_slice(), None).unwrap());
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let log = messages.clone();
+        console.set_debug_message_hook(Some(Box::new(move |at, message: &str| {
+            log.lock().unwrap().push((at, message.to_string()))
+        })));
+        let mut sink = LastFrame(None);
+
+        console.add_break_condition("ldbb".parse().unwrap());
+        console.run_frame(&mut sink);
+        assert_eq!(console.breakpoint_hit(), Some(BankedAddr::new(0, 0x101)));
+        assert!(messages.lock().unwrap().is_empty());
+        console.resume();
+        console.run_frame(&mut sink);
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages[0], (BankedAddr::new(0, 0x101), "a=01".to_string()));
+        assert_eq!(messages[1].0, BankedAddr::new(0, 0x10D));
+        assert!(messages[1].1.starts_with("A=01 "), "{}", messages[1].1);
+    }
+
+    #[test]
+    fn pause_freezes_everything() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 10);
+
+        console.pause();
+        let before = console.save_state();
+        let mut sink = LastFrame(None);
+        for _ in 0..5 {
+            console.run_frame(&mut sink);
+        }
+        assert!(sink.0.is_none());
+        assert!(console.save_state() == before);
+
+        assert!(console.advance_instruction(&mut sink) > 0);
+        assert!(console.save_state() != before);
+        assert!(console.is_paused());
+
+        console.advance_frame(&mut sink);
+        assert!(sink.0.is_some());
+    }
+
+    #[test]
+    fn frame_stats_add_up() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 60);
+
+        let stats = console.run_frame(&mut LastFrame(None));
+        assert!(stats.instructions > 1000);
+        assert!(stats.cycles >= stats.instructions);
+        assert_eq!(stats.interrupt_count(Interrupt::VBlank), 1);
+        assert_eq!(&stats, console.last_frame_stats());
+
+        console.pause();
+        assert_eq!(console.run_frame(&mut LastFrame(None)), FrameStats::default());
+    }
+
+    #[test]
+    fn frames_cut_short_hand_out_their_stats() {
+        let frame_11 = || {
+            let mut console = Console::new(tetris());
+            run_frames(&mut console, 10);
+            console
+        };
+        let whole = frame_11().advance_frame(&mut NoVideo);
+        let mut stepped = frame_11();
+        for _ in 0..500 {
+            stepped.advance_instruction(&mut NoVideo);
+        }
+
+        let mut console = frame_11();
+        console.add_breakpoint(stepped.pc());
+        let partial = console.advance_frame(&mut NoVideo);
+        assert!(console.breakpoint_hit().is_some());
+        assert!(partial.instructions > 0);
+        console.remove_breakpoint(stepped.pc());
+        let rest = console.advance_frame(&mut NoVideo);
+        assert_eq!(partial.instructions + rest.instructions, whole.instructions);
+        assert_eq!(partial.cycles + rest.cycles, whole.cycles);
+        assert_eq!(&rest, console.last_frame_stats());
+    }
+
+    #[test]
+    fn audio_sink_gets_a_frame_of_samples() {
+        use std::sync::{Arc, Mutex};
+
+        struct Count(Arc<Mutex<Vec<usize>>>);
+        impl AudioSink for Count {
+            fn samples_available(&mut self, samples: &[AudioSample]) {
+                self.0.lock().unwrap().push(samples.len());
+            }
+        }
+
+        let counts = Arc::new(Mutex::new(Vec::new()));
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 60);
+        console.set_audio_sink(Some(Box::new(Count(counts.clone()))));
+        let stats: Vec<_> = (0..3).map(|_| console.run_frame(&mut LastFrame(None))).collect();
+
+        // 44100Hz at ~59.7 frames a second
+        let counts = counts.lock().unwrap();
+        assert_eq!(counts.len(), 3);
+        for (&count, stats) in counts.iter().zip(&stats) {
+            assert!((735..= 740).contains(&count), "{:?}", counts);
+            assert_eq!(stats.audio_samples, count as u64);
+        }
+    }
+
+    #[test]
+    fn background_pauses_throttles_or_silences() {
+        use std::sync::{Arc, Mutex};
+
+        struct Count(Arc<Mutex<usize>>);
+        impl AudioSink for Count {
+            fn samples_available(&mut self, _samples: &[AudioSample]) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+
+        let deliveries = Arc::new(Mutex::new(0));
+        let mut console = Console::new(tetris());
+        console.set_audio_sink(Some(Box::new(Count(deliveries.clone()))));
+        let frames_run = |console: &mut Console, calls: usize| {
+            let before = console.frame_count();
+            (0..calls).for_each(|_| { console.run_frame(&mut LastFrame(None)); });
+            console.frame_count() - before
+        };
+        console.set_background(true);
+        assert_eq!(frames_run(&mut console, 8), 0);
+        console.advance_frame(&mut LastFrame(None));
+        assert_eq!(console.frame_count(), 1);
+
+        console.set_background_mode(BackgroundMode::Throttle);
+        assert_eq!(frames_run(&mut console, 8), 2);
+        console.set_background_mode(BackgroundMode::Run);
+        assert_eq!(frames_run(&mut console, 8), 8);
+        assert_eq!(*deliveries.lock().unwrap(), 0);
+
+        console.set_background(false);
+        assert_eq!(frames_run(&mut console, 2), 2);
+        assert_eq!(*deliveries.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn watches_report_changes() {
+        let mut console = Console::new(tetris());
+        let ly = console.watch(WatchExpr::Byte(0xFF44));
+        let pc = console.watch("pc".parse().unwrap());
+        let div = console.watch("0xff04".parse().unwrap());
+        console.unwatch(div);
+
+        run_frames(&mut console, 1);
+        let changes = console.watch_changes().to_vec();
+        // LY is back to the same line every vblank, the PC has moved on
+        assert!(changes.iter().any(|change| change.handle == pc && change.old == 0x0100));
+        assert!(changes.iter().all(|change| change.handle != div));
+
+        run_frames(&mut console, 1);
+        assert!(console.watch_changes().iter().all(|change| change.handle != ly));
+    }
+
+    #[test]
+    fn overlay_only_touches_frames_when_enabled() {
+        let mut plain = Console::new(tetris());
+        let mut hud = Console::new(tetris());
+        hud.set_overlay(Some(Overlay::new()));
+        hud.watch(WatchExpr::Byte(0xFF44));
+
+        let plain_frame = run_frames(&mut plain, 30);
+        let hud_frame = run_frames(&mut hud, 30);
+        assert!(plain_frame != hud_frame);
+        // Emulation itself is unaffected
+        assert!(plain.save_state() == hud.save_state());
+
+        hud.set_overlay(None);
+        assert!(run_frames(&mut plain, 1) == run_frames(&mut hud, 1));
+    }
+
+    #[test]
+    fn hidden_layers_are_blank() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 200);
+        let lightest = console.config().palette.0[0];
+
+        console.set_layers(Layers { background: false, window: false, sprites: false });
+        let frame = run_frames(&mut console, 1);
+        assert!(frame.iter().all(|&pixel| pixel == lightest));
+
+        console.set_layers(Layers::default());
+        let frame = run_frames(&mut console, 1);
+        assert!(frame.iter().any(|&pixel| pixel != lightest));
+    }
+
+    #[test]
+    fn scanline_hook_sees_every_line() {
+        use std::sync::{Arc, Mutex};
+
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 2);
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = lines.clone();
+        console.set_scanline_hook(Some(Box::new(move |regs: &ScanlineRegs| log.lock().unwrap().push(regs.ly))));
+        run_frames(&mut console, 2);
+        console.set_scanline_hook(None);
+        run_frames(&mut console, 1);
+
+        // Two frames: the end of one (from line 0 up) and the next, up to vblank
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 2 * 144);
+        assert!(lines.iter().take(144).copied().eq(0..144));
+    }
+
+    #[test]
+    fn timeline_shows_the_lyc_interrupt_line() {
+        use super::super::timeline::{StatCause, TimelineEvent};
+
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 2);
+        console.cpu.interconnect.write(0xFF45, 0x40);
+        console.cpu.interconnect.write(0xFF41, 0x40);
+        let timeline = console.record_timeline(&mut LastFrame(None));
+
+        assert_eq!(timeline.start_mode, 1);
+        assert!((70224..70224 + 24).contains(&(timeline.end - timeline.start)));
+        let oam_searches = timeline.events.iter()
+            .filter(|event| matches!(event, TimelineEvent::Mode { mode: 2, .. }))
+            .count();
+        // Lines 0 to 143, and the extra OAM search this PPU runs for line 144
+        assert_eq!(oam_searches, 145);
+        let stats: Vec<_> = timeline.events.iter()
+            .filter_map(|event| match *event {
+                TimelineEvent::Stat { ly, cause, .. } => Some((ly, cause)),
+                _ => None,
+            })
+            .collect();
+        // Writing STAT in VBlank fires the DMG's STAT write bug first
+        assert_eq!(stats, [(145, StatCause::StatWrite), (0x40, StatCause::LyCoincidence)]);
+        assert!(timeline.to_json().contains("\"ly\":64,\"stat\":\"lyc\""));
+
+        // Recording stops with the frame
+        console.run_frame(&mut LastFrame(None));
+        assert!(console.cpu.interconnect.ppu.take_timeline().events.is_empty());
+    }
+
+    #[test]
+    fn vram_writes_come_with_the_code_that_made_them() {
+        let mut console = Console::new(tetris());
+        // Tetris clears VRAM, then loads its tiles, with the LCD off during the second frame
+        assert!(console.record_vram_writes(&mut LastFrame(None)).writes.is_empty());
+        let log = console.record_vram_writes(&mut LastFrame(None));
+        assert!(log.end - log.start > 70224);
+        assert!(log.writes.len() > 0x2000);
+        assert!(log.writes.windows(2).all(|pair| pair[0].cycle <= pair[1].cycle));
+        let first = log.writes[0];
+        assert_eq!((first.addr, first.pc.bank), (0x9FFF, Some(0)));
+        assert_eq!(console.cpu.interconnect.read(first.pc.addr), 0x32); // ld (hl-), a
+        assert!(log.tiles().len() > 100);
+
+        console.run_frame(&mut LastFrame(None));
+        assert!(console.cpu.interconnect.take_vram_writes().is_empty());
+    }
+
+    #[test]
+    fn input_poller_answers_joypad_reads() {
+        use std::sync::{Arc, Mutex};
+
+        let mut console = Console::new(tetris());
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let log = reads.clone();
+        console.set_input_poller(Some(Box::new(move |read: &JoypadRead| {
+            log.lock().unwrap().push(*read);
+            vec![InputEvent::new(Button::Start, ButtonState::Down)]
+        })));
+        run_frames(&mut console, 2);
+
+        let reads = reads.lock().unwrap();
+        assert!(!reads.is_empty());
+        assert!(reads.windows(2).all(|pair| pair[0].cycle <= pair[1].cycle));
+        // Start is held from the first poll on
+        console.set_input_poller(None);
+        console.cpu.interconnect.write(0xFF00, 0x10);
+        assert_eq!(console.cpu.interconnect.read(0xFF00) & 0x08, 0);
+    }
+
+    #[test]
+    fn overclock_runs_more_cpu_cycles_per_frame() {
+        let mut normal = Console::new(tetris());
+        let mut fast = Console::new(tetris());
+        fast.set_overclock(456);
+        run_frames(&mut normal, 10);
+        run_frames(&mut fast, 10);
+
+        // Frames still take as long for the PPU, the CPU just gets twice the cycles
+        let (normal, fast) = (normal.advance_frame(&mut NoVideo), fast.advance_frame(&mut NoVideo));
+        assert!((70224..70224 + 24).contains(&normal.cycles));
+        assert!((2 * 70224..2 * 70224 + 48).contains(&fast.cycles));
+    }
+
+    #[test]
+    fn queued_input_plays_frame_by_frame() {
+        let mut console = Console::new(tetris());
+        console.queue_input(&InputMacro::new().press(Button::A, 2).wait(1));
+        console.queue_input(&InputMacro::new().press(Button::Start, 1));
+        let mut held = Vec::new();
+        for _ in 0..6 {
+            console.advance_frame(&mut NoVideo);
+            console.cpu.interconnect.write(0xFF00, 0x10); // buttons
+            held.push(!console.cpu.interconnect.read(0xFF00) & 0x0F);
+        }
+        assert_eq!(held, [0x01, 0x01, 0x00, 0x08, 0x00, 0x00]);
+        assert_eq!(console.queued_input(), 0);
+    }
+
+    #[test]
+    fn memory_views_update_between_frames() {
+        let mut console = Console::new(tetris());
+        let view = console.memory_view();
+        let before = view.snapshot();
+        console.cpu.interconnect.write(0xC000, 0x42);
+        assert_eq!(view.snapshot().read(0xC000), 0x00);
+
+        let viewer = std::thread::spawn(move || view);
+        run_frames(&mut console, 2);
+        let view = viewer.join().unwrap();
+        let after = view.snapshot();
+        assert_eq!((before.frame(), after.frame()), (0, 2));
+        let wram: Vec<u8> = (0xC000..0xE000).map(|addr| console.cpu.interconnect.peek(addr)).collect();
+        assert_eq!(after.range(0xC000, 0x2000), wram);
+        assert_ne!(before.range(0xC000, 0x2000), wram);
+        assert_eq!(after.read(0x0104), 0xCE); // the logo
+        assert!(console.bus_errors().is_empty());
+
+        drop(view);
+        run_frames(&mut console, 1);
+        assert!(console.memory_view.is_none());
+    }
+
+    #[test]
+    fn swapping_carts_flushes_the_old_save() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x03; // MBC1 + RAM + battery
+        rom[0x0149] = 0x02;
+        let cart = Cart::new(rom.into_boxed_slice(), Some(vec![0x5A; 0x2000].into_boxed_slice())).unwrap();
+        let mut console = Console::new(cart);
+        let path = std::env::temp_dir().join(format!("gbrust-swap-{}.sav", std::process::id()));
+        console.set_save_path(Some(path.clone()));
+        run_frames(&mut console, 3);
+        console.add_breakpoint(BankedAddr::unbanked(0x0150));
+
+        // Nothing written to the RAM, so nothing to flush
+        console.flush_save().unwrap();
+        assert!(!path.exists());
+
+        console.cpu.interconnect.write(0x0000, 0x0A);
+        console.cpu.interconnect.write(0xA000, 0xA5);
+        console.load_rom(tetris()).unwrap();
+        let mut expected = vec![0x5A; 0x2000];
+        expected[0] = 0xA5;
+        assert_eq!(fs::read(&path).unwrap(), expected);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(console.cart().get_title(), "TETRIS");
+        assert_eq!((console.registers().pc, console.frame_count()), (0x0100, 0));
+        assert!(console.breakpoints().is_empty() && console.save_path().is_none());
+        run_frames(&mut console, 3);
+        assert!(console.bus_errors().is_empty());
+    }
+
+    struct SharedLog(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn event_logs_have_a_line_per_frame() {
+        use std::sync::{Arc, Mutex};
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 60);
+        console.set_event_log(Some(Box::new(SharedLog(log.clone()))));
+        console.press(Button::Start);
+        run_frames(&mut console, 3);
+        console.set_event_log(None);
+        run_frames(&mut console, 1);
+
+        let text = String::from_utf8(log.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("{\"frame\":61,\"events\":[{"));
+        assert!(lines[0].contains("\"type\":\"input\",\"held\":[\"start\"]"));
+        assert!(lines.iter().all(|line| line.contains("\"name\":\"vblank\"") && line.contains("\"type\":\"dma\"")));
+        assert!(!lines[1].contains("input"));
+    }
+
+    #[test]
+    fn second_consoles_plug_into_the_link_port() {
+        use std::sync::{Arc, Mutex};
+
+        let mut console = Console::new(tetris());
+        let other = Arc::new(Mutex::new(Console::new(tetris())));
+        run_frames(&mut console, 30);
+        assert!(console.attach_serial(Box::new(other.clone())).is_none());
+        {
+            let mut other = other.lock().unwrap();
+            other.cpu.interconnect.write(0xFF01, 0x99);
+            other.cpu.interconnect.write(0xFF02, 0x80); // waiting on the external clock
+        }
+        console.cpu.interconnect.write(0xFF01, 0x42);
+        console.cpu.interconnect.write(0xFF02, 0x81);
+        console.advance_frame(&mut NoVideo);
+        let mut other = other.lock().unwrap();
+        assert_eq!((other.cpu.interconnect.read(0xFF01), other.cpu.interconnect.read(0xFF02)), (0x42, 0x7E));
+        assert!(console.detach_serial().is_some() && !console.has_serial_device());
+    }
+
+    #[test]
+    fn autosaves_resume_where_the_game_was() {
+        // Only when asked to, not for every console with a ROM file
+        assert_eq!(Console::builder().rom_path("tetris.gb").build().unwrap().autosave_path(), None);
+
+        let path = std::env::temp_dir().join(format!("gbrust-{}.autosave", std::process::id()));
+        let mut console = Console::new(tetris());
+        console.set_autosave_path(Some(path.clone()));
+        assert!(!console.load_autosave().unwrap());
+
+        // Every 10 frames, so the last one is from frame 20
+        console.set_autosave_interval(10);
+        run_frames(&mut console, 20);
+        let autosaved = console.save_state();
+        run_frames(&mut console, 5);
+        assert_eq!(fs::read(&path).unwrap(), &autosaved[..]);
+
+        let mut resumed = Console::new(tetris());
+        resumed.set_autosave_path(Some(path.clone()));
+        assert!(resumed.load_autosave().unwrap());
+        assert_eq!(resumed.save_state(), autosaved);
+        console.write_autosave().unwrap();
+        assert!(resumed.load_autosave().unwrap());
+        assert_eq!(resumed.save_state(), console.save_state());
+        assert!(!path.with_extension("autosave.tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resilient_consoles_stop_at_the_first_error() {
+        use super::super::test_asm::{Asm, R8};
+        let dir = std::env::temp_dir().join(format!("gbrust-resilient-test-{}", std::process::id()));
+        let config = EmuConfig { crash_dir: Some(dir.clone()), ..EmuConfig::default() };
+        let resilient = |rom: Box<[u8]>| {
+            Console::builder().rom(rom).config(config.clone()).resilient(true).build().unwrap()
+        };
+        let mut sinks = FrameSinks { video: None, audio: None };
+
+        let mut locked_up = resilient(Asm::new().ld_r_n(R8::A, 0x42).bytes(&[0xD3]).assemble());
+        assert_eq!(locked_up.run_frames(10, &mut sinks), 0);
+        assert_eq!(locked_up.failure(), locked_up.fault().map(RunFailure::Fault).as_ref());
+        assert_eq!(locked_up.failure().unwrap().to_string(), "illegal opcode $d3 at 00:0152");
+        assert!(locked_up.crash_bundle().is_some());
+        // and stays stopped
+        assert_eq!(locked_up.run_frames(10, &mut sinks), 0);
+        assert_eq!(locked_up.advance_frame(&mut NoVideo), FrameStats::default());
+        assert_eq!(locked_up.frame_count(), 0);
+
+        let mut no_ram = resilient(Asm::new().ld_a_mem(0xA000).assemble());
+        assert_eq!(no_ram.run_frames(10, &mut sinks), 0);
+        assert_eq!(no_ram.failure(), Some(&RunFailure::Bus(BusError::NoExternalRam { addr: 0xA000 })));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut panicking = Console::new(tetris());
+        panicking.config.crash_dir = Some(dir.clone());
+        panicking.set_resilient(true);
+        run_frames(&mut panicking, 10);
+        panicking.set_scanline_hook(Some(Box::new(|_: &ScanlineRegs| panic!("hook failed"))));
+        assert_eq!(panicking.run_frames(10, &mut sinks), 0);
+        assert_eq!(panicking.failure(), Some(&RunFailure::Panic("hook failed".to_string())));
+        assert_eq!(panicking.frame_count(), 10);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reset_starts_the_game_over() {
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 30);
+        console.cpu.interconnect.write(0xC000, 0x42);
+        console.reset(ResetKind::Soft);
+        assert_eq!(console.registers().pc, 0x0100);
+        assert_eq!(console.cpu.interconnect.read(0xC000), 0x42);
+        assert_eq!(console.frame_count(), 30);
+
+        // Runs exactly as a console that was never used
+        console.reset(ResetKind::PowerCycle);
+        let mut fresh = Console::new(tetris());
+        assert_eq!(console.save_state(), fresh.save_state());
+        run_frames(&mut console, 30);
+        run_frames(&mut fresh, 30);
+        assert_eq!(console.save_state(), fresh.save_state());
+    }
+
+    #[test]
+    fn ram_comes_up_with_the_configured_pattern() {
+        let build = |memory_init, model| {
+            let config = EmuConfig { memory_init, model, ..EmuConfig::default() };
+            Console::builder().rom(fs::read("tetris.gb").unwrap().into_boxed_slice())
+                .config(config).build().unwrap()
+        };
+        let wram = |console: &mut Console| console.memory_snapshot().range(0xC000, 0x2000);
+        let mut console = build(MemoryInit::Hardware, Model::Cgb);
+        assert_eq!(wram(&mut console)[0x08..0x10], [0xFF; 8]);
+        let mut seeded = build(MemoryInit::Seeded(1), Model::Dmg);
+        let power_on = wram(&mut seeded);
+        assert_eq!(power_on, wram(&mut build(MemoryInit::Seeded(1), Model::Dmg)));
+        assert!(power_on.iter().any(|&byte| byte != 0));
+
+        // A power cycle fills it again
+        seeded.cpu.interconnect.write(0xC000, power_on[0] ^ 0xFF);
+        seeded.reset(ResetKind::PowerCycle);
+        assert_eq!(wram(&mut seeded), power_on);
+    }
+
+    #[test]
+    fn entropy_hook_varies_power_on_and_seeds_replay() {
+        let mut console = Console::new(tetris());
+        let mut next = 0;
+        console.set_entropy_hook(move || {
+            next += 0x0123_4567_89AB_CDEF;
+            next
+        });
+        console.reset(ResetKind::PowerCycle);
+        let first = (console.entropy_seed().unwrap(), console.save_state());
+        console.reset(ResetKind::PowerCycle);
+        assert_ne!(console.save_state(), first.1);
+        assert_ne!(console.cpu.interconnect.read(0xFF04), 0); // DIV's phase
+        console.reset(ResetKind::Soft);
+        assert_eq!(console.entropy_seed(), Some(0x0246_8ACF_1357_9BDE));
+
+        let mut replay = Console::new(tetris());
+        replay.power_on_seeded(first.0);
+        assert_eq!(replay.save_state(), first.1);
+
+        // A movie carrying the seed powers on with it
+        let mut movie = Movie::parse("0\n100 start\n106").unwrap();
+        movie.set_seed(Some(first.0));
+        console.reset(ResetKind::PowerCycle);
+        let recorded = compare::trace(&mut console, &movie, 1);
+        assert_eq!(recorded, compare::trace(&mut Console::new(tetris()), &movie, 1));
+    }
+
+    #[test]
+    fn swapped_carts_bring_their_own_cgb_mode() {
+        let cart = |cgb_flag: u8| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x0143] = cgb_flag;
+            Cart::new(rom.into_boxed_slice(), None).unwrap()
+        };
+        // Only CGB games can read back SC's fast clock bit
+        let cgb_mode = |console: &mut Console| {
+            console.cpu.interconnect.write(0xFF02, 0x00);
+            console.cpu.interconnect.peek(0xFF02) & 0x02 == 0
+        };
+        let mut console = Console::new(cart(0x00));
+        console.set_model(Model::Cgb);
+        assert!(!cgb_mode(&mut console));
+        console.load_rom(cart(0x80)).unwrap();
+        assert!(cgb_mode(&mut console));
+        console.load_rom(cart(0x00)).unwrap();
+        assert!(!cgb_mode(&mut console));
+    }
+
+    #[test]
+    fn mapped_banks_show_through_the_bus() {
+        let mut console = Console::new(tetris());
+        let original = console.cpu.interconnect.read(0x4001);
+        console.map_rom_bank(1, &[0xAB]).unwrap();
+        assert_eq!(console.cpu.interconnect.read(0x4000), 0xAB);
+        assert_eq!(console.cpu.interconnect.read(0x4001), original);
+        assert!(matches!(console.map_rom_bank(2, &[0]), Err(CartError::NoSuchBank(2))));
+        console.unmap_rom_bank(1).unwrap();
+        assert_ne!(console.cpu.interconnect.read(0x4000), 0xAB);
+
+        // Mappings go with the cart they were made in
+        console.map_rom_bank(1, &[0xAB]).unwrap();
+        let mut other = vec![0x22; 0x8000];
+        other[0x0147..0x014A].copy_from_slice(&[0, 0, 0]); // ROM only, 32 KiB, no RAM
+        console.load_rom(Cart::new(other.into_boxed_slice(), None).unwrap()).unwrap();
+        console.unmap_rom_bank(1).unwrap();
+        assert_eq!(console.cpu.interconnect.peek(0x4000), 0x22);
+    }
+
+    #[test]
+    fn pokes_land_at_the_end_of_their_frame() {
+        use std::sync::{Arc, Mutex};
+
+        // Past the frames where Tetris clears RAM
+        let mut console = Console::new(tetris());
+        run_frames(&mut console, 10);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        console.on_vblank(move |vblank| {
+            let values = (vblank.frame(), vblank.read(0xD400), vblank.read(0xD401));
+            log.lock().unwrap().push(values);
+        });
+        console.schedule_poke(13, Poke { addr: 0xD400, val: 0x42 });
+        console.poke_every_frame("01a501d4".parse().unwrap());
+        run_frames(&mut console, 4);
+        assert_eq!(*seen.lock().unwrap(), [(11, 0, 0xA5), (12, 0, 0xA5), (13, 0x42, 0xA5), (14, 0x42, 0xA5)]);
+
+        console.clear_pokes();
+        console.cpu.interconnect.write(0xD401, 0);
+        run_frames(&mut console, 1);
+        assert_eq!(seen.lock().unwrap()[4], (15, 0x42, 0));
+    }
+
+    #[test]
+    fn vblank_callbacks_run_once_per_frame() {
+        use std::sync::{Arc, Mutex};
+
+        let mut console = Console::new(tetris());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let handle = console.on_vblank(move |vblank| {
+            // Still at the very start of vblank
+            assert!(vblank.read(0xFF44) >= 144);
+            log.lock().unwrap().push(vblank.frame());
+        });
+
+        run_frames(&mut console, 3);
+        console.remove_vblank(handle);
+        run_frames(&mut console, 1);
+
+        assert_eq!(*seen.lock().unwrap(), vec![1, 2, 3]);
+        assert_eq!(console.frame_count(), 4);
+    }
+
+    #[test]
+    fn boot_rom_hands_over_to_cart() {
+        let rom = fs::read("tetris.gb").unwrap();
+        let mut console = Console::builder()
+            .rom(rom.clone().into_boxed_slice())
+            .boot_rom_path("dmg_boot.bin")
+            .build()
+            .unwrap();
+
+        assert_eq!(console.cpu.interconnect.read(0x0000), 0x31); // LD SP, $FFFE
+        run_frames(&mut console, 600);
+        assert_eq!(console.cpu.interconnect.read(0x0000), rom[0]);
+    }
+
+    #[test]
+    fn builder_applies_game_profile() {
+        let mut config = EmuConfig::default();
+        config.profile_mut(tetris().global_checksum()).accuracy = Some(AccuracyLevel::Fast);
+        let console = Console::builder().config(config.clone()).rom_path("tetris.gb").build().unwrap();
+        assert_eq!(console.accuracy(), AccuracyLevel::Fast);
+
+        config.profiles.clear();
+        let console = Console::builder().config(config).rom_path("tetris.gb").build().unwrap();
+        assert_eq!(console.accuracy(), AccuracyLevel::Balanced);
+    }
+
+    #[test]
+    fn consoles_run_side_by_side() {
+        let run = || {
+            let mut console = Console::new(tetris());
+            run_frames(&mut console, 30);
+            console.press(Button::Start);
+            run_frames(&mut console, 60)
+        };
+        let threads: Vec<_> = (0..2).map(|_| std::thread::spawn(run)).collect();
+        let frames: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert!(frames[0] == frames[1]);
+        assert!(frames[0] == run());
+    }
+
+    #[test]
+    fn handle_drives_console_on_another_thread() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use super::super::error::Disconnected;
+
+        let mut console = Console::new(tetris());
+        let handle = console.handle();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let emulation = std::thread::spawn(move || {
+            let mut sink = LastFrame(None);
+            while !stop_flag.load(Ordering::SeqCst) {
+                console.run_frame(&mut sink);
+            }
+            console
+        });
+
+        let frame = loop {
+            if let Some(frame) = handle.screenshot().unwrap() {
+                break frame;
+            }
+        };
+        assert_eq!(frame.len(), 160 * 144);
+        // Frames nobody collects do not pile up
+        let frames = handle.frames().unwrap();
+        assert_eq!(frames.recv().unwrap().len(), 160 * 144);
+        // Nintendo logo in the header
+        let rom = fs::read("tetris.gb").unwrap();
+        assert_eq!(handle.read_memory(0x0104, 8).unwrap(), &rom[0x0104..0x010C]);
+
+        handle.pause().unwrap();
+        let before = handle.read_memory(0xC000, 0x2000).unwrap();
+        let after = handle.read_memory(0xC000, 0x2000).unwrap();
+        assert!(before == after);
+
+        stop.store(true, Ordering::SeqCst);
+        let console = emulation.join().unwrap();
+        assert!(console.is_paused());
+        drop(console);
+        assert_eq!(handle.resume(), Err(Disconnected));
+        assert!(frames.try_iter().count() <= 1);
+    }
+
+    #[test]
+    fn batches_run_like_single_frames() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let mut single = Console::new(tetris());
+        let mut batched = Console::new(tetris());
+        let vblanks = Arc::new(AtomicUsize::new(0));
+        let counter = vblanks.clone();
+        batched.on_vblank(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let single_frame = run_frames(&mut single, 200);
+        let mut batched_frame = LastFrame(None);
+        let ran = batched.run_frames(200, &mut FrameSinks { video: Some(&mut batched_frame), audio: None });
+        assert_eq!(ran, 200);
+        assert_eq!((batched.frame_count(), vblanks.load(Ordering::SeqCst)), (200, 200));
+        assert_eq!(batched.registers(), single.registers());
+        assert!(batched_frame.0.unwrap() == single_frame);
+
+        // Not drawn, the game runs the same
+        let mut undrawn = Console::new(tetris());
+        undrawn.set_rendering(false);
+        let mut blank = LastFrame(None);
+        undrawn.run_frames(200, &mut FrameSinks { video: Some(&mut blank), audio: None });
+        assert_eq!(undrawn.registers(), single.registers());
+        assert!(blank.0.unwrap().iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn threaded_rendering_matches_inline() {
+        let mut inline = Console::new(tetris());
+        let mut threaded = Console::new(tetris());
+        threaded.set_threaded_rendering(true);
+
+        let mut inline_frame = LastFrame(None);
+        let mut threaded_frame = LastFrame(None);
+        for _ in 0..120 {
+            inline.run_frame(&mut inline_frame);
+            threaded.run_frame(&mut threaded_frame);
+        }
+
+        // Let the worker catch up with the last frame
+        threaded.cpu.interconnect.ppu.set_event_queue(None);
+        let last = threaded.render_thread.take().unwrap().finish();
+        let last = last.or(threaded_frame.0).unwrap();
+        assert!(last == inline_frame.0.unwrap());
+    }
+}
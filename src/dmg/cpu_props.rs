@@ -0,0 +1,203 @@
+// Property tests for the CPU.
+// Random streams of loads, ALU operations, SCF and CCF run from random registers, one instruction
+// at a time, and after every instruction what holds whatever the operands are is checked:
+//
+//     every instruction   moves pc on by its length, leaves the low nibble of F at 0
+//     ld                  copies the value and leaves F alone
+//     and                 sets H, resets N and C, Z from the result
+//     cp                  leaves A alone, sets N, Z and C from the comparison
+//     scf, ccf            leave Z alone, reset N and H, set or flip C
+//
+// The streams never load into H or L, so (hl) stays in work RAM. On a failure proptest shrinks
+// the stream to the shortest that still fails and prints it.
+
+use proptest::prelude::*;
+
+use super::dmg_cpu::{Cpu, RegisterSnapshot};
+use super::test_asm::{self, Alu, Asm, R16, R8};
+
+const ZF: u8 = 0x80;
+const NF: u8 = 0x40;
+const HF: u8 = 0x20;
+const CF: u8 = 0x10;
+
+// Registers the streams load into
+const DESTINATIONS: [R8; 6] = [R8::B, R8::C, R8::D, R8::E, R8::HlInd, R8::A];
+
+const ALU_OPS: [Alu; 8] = [Alu::Add, Alu::Adc, Alu::Sub, Alu::Sbc, Alu::And, Alu::Xor, Alu::Or, Alu::Cp];
+
+#[derive(Debug, Copy, Clone)]
+enum Op {
+    LdRR(R8, R8),
+    LdRN(R8, u8),
+    Alu(Alu, R8),
+    AluN(Alu, u8),
+    Scf,
+    Ccf,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Start {
+    af: u16,
+    bc: u16,
+    de: u16,
+    hl: u16,
+}
+
+fn emit(asm: Asm, op: Op) -> Asm {
+    match op {
+        Op::LdRR(to, from) => asm.ld_r_r(to, from),
+        Op::LdRN(to, n) => asm.ld_r_n(to, n),
+        Op::Alu(alu, r) => asm.alu(alu, r),
+        Op::AluN(alu, n) => asm.alu_n(alu, n),
+        Op::Scf => asm.bytes(&[0x37]),
+        Op::Ccf => asm.bytes(&[0x3F]),
+    }
+}
+
+fn value(cpu: &mut Cpu, r: R8) -> u8 {
+    let regs = cpu.registers();
+    match r {
+        R8::B => regs.b,
+        R8::C => regs.c,
+        R8::D => regs.d,
+        R8::E => regs.e,
+        R8::H => regs.h,
+        R8::L => regs.l,
+        R8::HlInd => cpu.interconnect.read(regs.hl()),
+        R8::A => regs.a,
+    }
+}
+
+fn flag(f: u8, flag: u8) -> bool {
+    f & flag != 0
+}
+
+// The CPU at start's registers, ready to run ops one by one, and where each op is
+fn load(start: Start, ops: &[Op]) -> (Cpu, Vec<u16>) {
+    let mut asm = Asm::new()
+        .di()
+        .ld_rr_nn(R16::SP, 0xDFFF)
+        .ld_rr_nn(R16::BC, start.af)
+        .push(R16::BC)
+        .pop(R16::SP) // af
+        .ld_rr_nn(R16::BC, start.bc)
+        .ld_rr_nn(R16::DE, start.de)
+        .ld_rr_nn(R16::HL, start.hl);
+    let mut addrs = Vec::new();
+    for &op in ops {
+        addrs.push(asm.pc());
+        asm = emit(asm, op);
+    }
+    addrs.push(asm.pc());
+    let mut cpu = asm.cpu();
+    test_asm::run_to(&mut cpu, addrs[0]);
+    (cpu, addrs)
+}
+
+fn check(op: Op, before: RegisterSnapshot, operand: u8, after: RegisterSnapshot, loaded: u8)
+         -> Result<(), TestCaseError> {
+    let (f, was) = (after.f, before.f);
+    match op {
+        Op::LdRR(..) | Op::LdRN(..) => {
+            prop_assert_eq!(loaded, operand);
+            prop_assert_eq!(f, was, "ld changed the flags");
+        }
+        Op::Alu(Alu::And, _) | Op::AluN(Alu::And, _) => {
+            prop_assert_eq!(f, HF | if after.a == 0 { ZF } else { 0 }, "and sets H only, and Z");
+        }
+        Op::Alu(Alu::Cp, _) | Op::AluN(Alu::Cp, _) => {
+            prop_assert_eq!(after.a, before.a, "cp changed A");
+            prop_assert!(flag(f, NF));
+            prop_assert_eq!(flag(f, ZF), before.a == operand);
+            prop_assert_eq!(flag(f, CF), before.a < operand);
+        }
+        Op::Scf => prop_assert_eq!(f, (was & ZF) | CF),
+        Op::Ccf => prop_assert_eq!(f, (was & ZF) | (!was & CF)),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn run_ops(start: Start, ops: &[Op]) -> Result<(), TestCaseError> {
+    let (mut cpu, addrs) = load(start, ops);
+    for (i, &op) in ops.iter().enumerate() {
+        let before = cpu.registers();
+        let operand = match op {
+            Op::LdRR(_, r) | Op::Alu(_, r) => value(&mut cpu, r),
+            Op::LdRN(_, n) | Op::AluN(_, n) => n,
+            Op::Scf | Op::Ccf => 0,
+        };
+        test_asm::step(&mut cpu);
+        let after = cpu.registers();
+        let loaded = match op {
+            Op::LdRR(to, _) | Op::LdRN(to, _) => value(&mut cpu, to),
+            _ => 0,
+        };
+        prop_assert_eq!(after.pc, addrs[i + 1], "{:?} at {:04x} is the wrong length", op, addrs[i]);
+        prop_assert_eq!(after.f & 0x0F, 0, "{:?} set the low nibble of F", op);
+        check(op, before, operand, after, loaded).map_err(|e| {
+            TestCaseError::fail(format!("{:?} at {:04x}, from {:?}: {}", op, addrs[i], before, e))
+        })?;
+    }
+    Ok(())
+}
+
+fn r8() -> impl Strategy<Value = R8> {
+    prop::sample::select(R8::ALL.to_vec())
+}
+
+fn destination() -> impl Strategy<Value = R8> {
+    prop::sample::select(DESTINATIONS.to_vec())
+}
+
+fn alu() -> impl Strategy<Value = Alu> {
+    prop::sample::select(ALU_OPS.to_vec())
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (destination(), r8())
+            .prop_filter("ld (hl),(hl) is halt", |&(to, from)| !(to == R8::HlInd && from == R8::HlInd))
+            .prop_map(|(to, from)| Op::LdRR(to, from)),
+        (destination(), any::<u8>()).prop_map(|(to, n)| Op::LdRN(to, n)),
+        (alu(), r8()).prop_map(|(alu, r)| Op::Alu(alu, r)),
+        (alu(), any::<u8>()).prop_map(|(alu, n)| Op::AluN(alu, n)),
+        Just(Op::Scf),
+        Just(Op::Ccf),
+    ]
+}
+
+fn start() -> impl Strategy<Value = Start> {
+    (any::<u16>(), any::<u16>(), any::<u16>(), 0xC000..0xD000u16)
+        .prop_map(|(af, bc, de, hl)| Start { af, bc, de, hl })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every ld r,r' and ld r,n, with each register holding a different value
+    #[test]
+    fn ld_matrix() {
+        let start = Start { af: 0x88A0, bc: 0x0102, de: 0x0304, hl: 0xC006 };
+        for &to in R8::ALL.iter() {
+            for &from in R8::ALL.iter() {
+                if to == R8::HlInd && from == R8::HlInd {
+                    continue;
+                }
+                // (hl) starts as 0x77
+                let ops = [Op::LdRN(R8::HlInd, 0x77), Op::LdRR(to, from)];
+                run_ops(start, &ops).unwrap_or_else(|e| panic!("ld {:?},{:?}: {}", to, from, e));
+            }
+            run_ops(start, &[Op::LdRN(to, 0x5A)]).unwrap_or_else(|e| panic!("ld {:?},n: {}", to, e));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn flag_invariants_hold(start in start(), ops in prop::collection::vec(op(), 1..64)) {
+            run_ops(start, &ops)?;
+        }
+    }
+}
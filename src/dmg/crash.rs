@@ -0,0 +1,145 @@
+// Crash reports, for attaching to bug reports.
+// When the CPU locks up on an opcode that does not exist (see CpuFault), or a frontend finds
+// something wrong and calls Console::write_crash_report, a bundle is written to crash_dir (see
+// EmuConfig), in a directory of its own named after the time and frame:
+//
+//     report.txt     what happened, the ROM's title and hashes, the registers, and the last
+//                    TRACE_LEN instructions disassembled with the registers they started from
+//     memory.bin     0x0000 - 0xffff as the CPU saw it
+//     crash.state    a save state, to load and look around in the debugger
+//
+// The CPU keeps the registers of the last TRACE_LEN instructions all along, so the trace is
+// there without tracing having been turned on.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::dmg_cpu::RegisterSnapshot;
+use super::romdb::RomHashes;
+use super::sav::unix_time;
+
+pub const TRACE_LEN: usize = 64;
+
+// Registers before each of the last instructions, and the ROM bank mapped, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct RecentInstructions {
+    entries: VecDeque<(RegisterSnapshot, usize)>,
+}
+
+impl RecentInstructions {
+    pub fn record(&mut self, registers: RegisterSnapshot, rom_bank: usize) {
+        if self.entries.len() == TRACE_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((registers, rom_bank));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(RegisterSnapshot, usize)> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CrashReport {
+    pub reason: String,
+    pub title: String,
+    pub hashes: RomHashes,
+    pub frame: u64,
+    pub registers: RegisterSnapshot,
+    pub trace: Vec<String>, // oldest first
+    pub memory: Box<[u8]>,
+    pub state: Box<[u8]>,
+}
+
+pub fn register_text(r: &RegisterSnapshot) -> String {
+    format!("pc={:04x} sp={:04x} af={:04x} bc={:04x} de={:04x} hl={:04x} ime={}",
+            r.pc, r.sp, r.af(), r.bc(), r.de(), r.hl(), r.ime as u8)
+}
+
+impl CrashReport {
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "gbrust {} crash report", env!("CARGO_PKG_VERSION")).unwrap();
+        writeln!(text, "reason    {}", self.reason).unwrap();
+        writeln!(text, "rom       {}", self.title).unwrap();
+        writeln!(text, "crc32     {:08x}", self.hashes.crc32).unwrap();
+        writeln!(text, "sha1      {}", self.hashes.sha1_hex()).unwrap();
+        writeln!(text, "frame     {}", self.frame).unwrap();
+        writeln!(text, "registers {}", register_text(&self.registers)).unwrap();
+        writeln!(text, "\nlast {} instructions:", self.trace.len()).unwrap();
+        for line in &self.trace {
+            writeln!(text, "{}", line).unwrap();
+        }
+        text
+    }
+
+    // Writes the bundle to a new directory in dir and returns where
+    pub fn write_bundle(&self, dir: &Path) -> io::Result<PathBuf> {
+        let bundle = dir.join(format!("gbrust-crash-{}-{}", unix_time(), self.frame));
+        fs::create_dir_all(&bundle)?;
+        fs::write(bundle.join("report.txt"), self.to_text())?;
+        fs::write(bundle.join("memory.bin"), &self.memory)?;
+        fs::write(bundle.join("crash.state"), &self.state)?;
+        Ok(bundle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::config::EmuConfig;
+    use super::super::console::{Console, NoVideo};
+    use super::super::test_asm::{Asm, R8};
+
+    #[test]
+    fn illegal_opcodes_write_a_bundle() {
+        let dir = std::env::temp_dir().join(format!("gbrust-crash-test-{}", std::process::id()));
+        let config = EmuConfig { crash_dir: Some(dir.clone()), ..EmuConfig::default() };
+        let rom = Asm::new()
+            .ld_r_n(R8::A, 0x42)
+            .bytes(&[0xD3])
+            .assemble();
+        let mut console = Console::builder().rom(rom.clone()).config(config).build().unwrap();
+        for _ in 0..2 {
+            console.run_frame(&mut NoVideo);
+        }
+
+        let fault = console.fault().unwrap();
+        assert_eq!(fault.to_string(), "illegal opcode $d3 at 00:0152");
+        assert_eq!(console.registers().pc, 0x0152); // locked up
+        let bundle = console.crash_bundle().unwrap().to_path_buf();
+        let report = fs::read_to_string(bundle.join("report.txt")).unwrap();
+        assert!(report.contains("reason    illegal opcode $d3 at 00:0152\n"));
+        assert!(report.contains("00:0150  3e 42     ld a,$42"));
+        assert_eq!(fs::read(bundle.join("memory.bin")).unwrap().len(), 0x10000);
+        let mut fresh = Console::builder().rom(rom).build().unwrap();
+        fresh.load_state(&fs::read(bundle.join("crash.state")).unwrap()).unwrap();
+        assert_eq!(fresh.registers(), console.registers());
+        fs::remove_dir_all(&dir).unwrap();
+
+        // One bundle per crash
+        console.run_frame(&mut NoVideo);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn crash_reports_can_be_turned_off() {
+        let dir = std::env::temp_dir().join(format!("gbrust-crash-off-test-{}", std::process::id()));
+        let config = EmuConfig { crash_dir: Some(dir.clone()), crash_reports: false, ..EmuConfig::default() };
+        let rom = Asm::new().ld_r_n(R8::A, 0x42).bytes(&[0xD3]).assemble();
+        let mut console = Console::builder().rom(rom).config(config).build().unwrap();
+        console.run_frame(&mut NoVideo);
+        assert!(console.fault().is_some());
+        assert!(console.crash_bundle().is_none());
+        assert!(!dir.exists());
+        // Nor is the trace kept for one
+        assert!(console.crash_report("asked for").trace.is_empty());
+    }
+}
@@ -0,0 +1,802 @@
+// Debug Adapter Protocol, needs the `server` feature.
+// Lets editors debug a ROM with their own debugger UI: VS Code (through an extension that
+// points its debug type at gbrust), nvim-dap, Emacs dape and anything else that speaks DAP.
+// Messages are JSON with a Content-Length header, over stdio (`gbrust dap`, which opens the
+// game's window on launch) or TCP (`gbrust run --dap ADDR`, which attaches to the game running).
+//
+// A ROM has no source lines to put breakpoints on, so breakpoints are function breakpoints
+// named by .sym label ("PlayerUpdate"), address ("01:4abc", "c000") or break condition
+// ("irq:48", see debugger.rs), or instruction breakpoints from the disassembly view. The
+// "Interrupts" and "RST" exception filters break on every interrupt taken and every RST. The
+// game is one thread, "CPU"; its stack frames come from the shadow call stack, named from the
+// symbols, and each has two scopes: the CPU registers and the IO registers, decoded. LD D,D
+// debug messages come out as output.
+//
+// Launch arguments: program (the ROM, for `gbrust dap`), symbols (a .sym file, by default the
+// one next to the ROM) and stopOnEntry. Attach takes the same but ignores program. A launched
+// game waits for configurationDone, so breakpoints set beforehand catch its first instructions.
+// Disconnecting takes the session's breakpoints away again and lets an attached game run on.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use super::console::{Console, NoVideo};
+use super::debugger::{BankedAddr, BreakOn, StepEnd, DEFAULT_STEP_HISTORY};
+use super::json::Json;
+use super::remote::ConsoleHandle;
+use super::server::base64;
+use super::symbols::Symbols;
+
+// Longer messages, or header lines, than any client sends are taken as garbage
+const MAX_MESSAGE: usize = 0x10000;
+const MAX_HEADER_LINE: u64 = 0x400;
+const THREAD_ID: f64 = 1.0;
+// Variable references: the two scopes, then one per IO register with fields
+const REGISTERS: usize = 1;
+const IO_REGISTERS: usize = 2;
+const IO_FIELDS: usize = 100;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LaunchArgs {
+    pub program: Option<PathBuf>,
+    pub symbols: Option<PathBuf>,
+    pub stop_on_entry: bool,
+}
+
+// Hands a session its console on launch or attach (the bool says which: true for launch)
+pub type Connect = Box<dyn FnMut(&LaunchArgs, bool) -> Result<ConsoleHandle, String> + Send>;
+
+// DAP over TCP for a running console, with a thread per connection
+pub struct DapServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl DapServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, handle: ConsoleHandle) -> io::Result<DapServer> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let acceptor = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!(target: "gbrust::dap", "accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    let served = stream.try_clone().and_then(|out| {
+                        serve(BufReader::new(stream), Box::new(out), Box::new(move |_: &LaunchArgs, _| Ok(handle.clone())))
+                    });
+                    if let Err(e) = served {
+                        debug!(target: "gbrust::dap", "connection dropped: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(DapServer { addr, stop, acceptor: Some(acceptor) })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for DapServer {
+    fn drop(&mut self) {
+        // The acceptor only looks at the flag when a connection comes in, so make one
+        self.stop.store(true, Ordering::SeqCst);
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let _ = TcpStream::connect(wake);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+// Runs one session until the client disconnects or hangs up
+pub fn serve<R: BufRead>(mut input: R, output: Box<dyn Write + Send>, connect: Connect) -> io::Result<()> {
+    let mut session = Session {
+        out: Arc::new(Output { writer: Mutex::new(output), seq: AtomicU64::new(1) }),
+        connect,
+        handle: None,
+        launched: false,
+        stop_on_entry: false,
+        function_breakpoints: Vec::new(),
+        instruction_breakpoints: Vec::new(),
+        exception_breakpoints: Vec::new(),
+    };
+    while let Some(message) = read_message(&mut input)? {
+        if message.get("type").and_then(Json::as_str) != Some("request") {
+            continue;
+        }
+        let command = message.get("command").and_then(Json::as_str).unwrap_or_default().to_string();
+        let args = message.get("arguments").cloned().unwrap_or(Json::Object(BTreeMap::new()));
+        let result = session.request(&command, &args);
+        session.out.respond(&message, &command, result.as_ref().map(|(body, _)| body))?;
+        match result {
+            Ok((_, Some(event))) => session.out.event(event.0, event.1)?,
+            _ if command == "disconnect" => return Ok(()),
+            _ => {}
+        }
+    }
+    session.disconnect();
+    Ok(())
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Json>> {
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.by_ref().take(MAX_HEADER_LINE).read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.len() as u64 == MAX_HEADER_LINE && !line.ends_with('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "header line too long"));
+        }
+        let header = line.trim();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.filter(|&length| length <= MAX_MESSAGE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    let text = String::from_utf8(body).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not UTF-8"))?;
+    Json::parse(&text).map(Some).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not JSON"))
+}
+
+// The writing side, shared with the thread passing on stops
+struct Output {
+    writer: Mutex<Box<dyn Write + Send>>,
+    seq: AtomicU64,
+}
+
+impl Output {
+    fn send(&self, fields: Vec<(&str, Json)>) -> io::Result<()> {
+        let mut fields = fields;
+        fields.push(("seq", Json::Number(self.seq.fetch_add(1, Ordering::SeqCst) as f64)));
+        let body = Json::object(fields).to_string();
+        let mut writer = self.writer.lock().unwrap();
+        write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        writer.flush()
+    }
+
+    fn respond(&self, request: &Json, command: &str, result: Result<&Json, &String>) -> io::Result<()> {
+        let mut fields = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("command", Json::from(command)),
+            ("success", Json::Bool(result.is_ok())),
+        ];
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => fields.push(("body", body.clone())),
+            Err(message) => fields.push(("message", Json::String(message.clone()))),
+        }
+        self.send(fields)
+    }
+
+    fn event(&self, event: &str, body: Json) -> io::Result<()> {
+        let mut fields = vec![("type", Json::from("event")), ("event", Json::from(event))];
+        if body != Json::Null {
+            fields.push(("body", body));
+        }
+        self.send(fields)
+    }
+
+    fn stopped(&self, reason: &'static str) -> io::Result<()> {
+        let (event, body) = stopped(reason);
+        self.event(event, body)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Break {
+    At(BankedAddr),
+    On(BreakOn),
+}
+
+// A response body, and an event to follow it
+type Reply = (Json, Option<(&'static str, Json)>);
+
+struct Session {
+    out: Arc<Output>,
+    connect: Connect,
+    handle: Option<ConsoleHandle>,
+    launched: bool,
+    stop_on_entry: bool,
+    function_breakpoints: Vec<Break>,
+    instruction_breakpoints: Vec<Break>,
+    exception_breakpoints: Vec<Break>,
+}
+
+impl Session {
+    fn request(&mut self, command: &str, args: &Json) -> Result<Reply, String> {
+        let body = match command {
+            "initialize" => return Ok((capabilities(), Some(("initialized", Json::Null)))),
+            "launch" | "attach" => {
+                self.start(args, command == "launch")?;
+                Json::Null
+            }
+            "configurationDone" => {
+                let (stop, launched) = (self.stop_on_entry, self.launched);
+                self.on_console(move |console| {
+                    if stop {
+                        console.pause();
+                    } else if launched {
+                        console.resume();
+                    }
+                })?;
+                return Ok((Json::Null, if stop { Some(stopped("entry")) } else { None }));
+            }
+            "disconnect" => {
+                self.disconnect();
+                Json::Null
+            }
+            "threads" => Json::object(vec![("threads", Json::Array(vec![Json::object(vec![
+                ("id", Json::Number(THREAD_ID)),
+                ("name", Json::from("CPU")),
+            ])]))]),
+            "setBreakpoints" => {
+                let lines = match args.get("breakpoints") {
+                    Some(Json::Array(breakpoints)) => breakpoints.len(),
+                    _ => 0,
+                };
+                let unverified = Json::object(vec![
+                    ("verified", Json::Bool(false)),
+                    ("message", Json::from("ROMs have no source lines, use function breakpoints")),
+                ]);
+                Json::object(vec![("breakpoints", Json::Array(vec![unverified; lines]))])
+            }
+            "setFunctionBreakpoints" => {
+                let names: Vec<String> = array(args, "breakpoints").iter()
+                    .filter_map(|breakpoint| breakpoint.get("name").and_then(Json::as_str).map(str::to_string))
+                    .collect();
+                let resolved = self.on_console(move |console| {
+                    names.iter().map(|name| resolve(console.symbols(), name)).collect::<Vec<_>>()
+                })?;
+                let breaks: Vec<Break> = resolved.iter().flatten().copied().collect();
+                let old = std::mem::replace(&mut self.function_breakpoints, breaks.clone());
+                self.replace_breaks(old, breaks)?;
+                breakpoints_body(&resolved)
+            }
+            "setInstructionBreakpoints" => {
+                let resolved: Vec<Option<Break>> = array(args, "breakpoints").iter().map(|breakpoint| {
+                    let reference = breakpoint.get("instructionReference").and_then(Json::as_str)?;
+                    let offset = breakpoint.get("offset").and_then(Json::as_f64).unwrap_or(0.0) as i32;
+                    let addr = reference.parse::<BankedAddr>().ok()?;
+                    Some(Break::At(BankedAddr::unbanked(addr.addr.wrapping_add(offset as u16))))
+                }).collect();
+                let breaks: Vec<Break> = resolved.iter().flatten().copied().collect();
+                let old = std::mem::replace(&mut self.instruction_breakpoints, breaks.clone());
+                self.replace_breaks(old, breaks)?;
+                breakpoints_body(&resolved)
+            }
+            "setExceptionBreakpoints" => {
+                let mut breaks = Vec::new();
+                for filter in array(args, "filters") {
+                    match filter.as_str() {
+                        Some("irq") => breaks.push(Break::On(BreakOn::AnyInterrupt)),
+                        Some("rst") => breaks.extend((0..8).map(|n| Break::On(BreakOn::Rst(n * 8)))),
+                        _ => {}
+                    }
+                }
+                let old = std::mem::replace(&mut self.exception_breakpoints, breaks.clone());
+                self.replace_breaks(old, breaks)?;
+                Json::Null
+            }
+            "continue" => {
+                self.on_console(|console| console.resume())?;
+                Json::object(vec![("allThreadsContinued", Json::Bool(true))])
+            }
+            "pause" => {
+                self.on_console(|console| console.pause())?;
+                return Ok((Json::Null, Some(stopped("pause"))));
+            }
+            "next" | "stepIn" | "stepOut" | "stepBack" => {
+                let command = command.to_string();
+                let end = self.on_console(move |console| match command.as_str() {
+                    "next" => console.step_over(&mut NoVideo),
+                    "stepOut" => console.step_out(&mut NoVideo),
+                    "stepBack" => {
+                        console.step_back();
+                        StepEnd::Done
+                    }
+                    _ => {
+                        console.advance_instruction(&mut NoVideo);
+                        StepEnd::Done
+                    }
+                })?;
+                let reason = if end == StepEnd::Breakpoint { "breakpoint" } else { "step" };
+                return Ok((Json::Null, Some(stopped(reason))));
+            }
+            "stackTrace" => self.on_console(stack_trace)?,
+            "scopes" => {
+                let scope = |name: &str, reference: usize| Json::object(vec![
+                    ("name", Json::from(name)),
+                    ("variablesReference", Json::Number(reference as f64)),
+                    ("expensive", Json::Bool(false)),
+                ]);
+                Json::object(vec![("scopes", Json::Array(vec![
+                    scope("Registers", REGISTERS),
+                    scope("IO registers", IO_REGISTERS),
+                ]))])
+            }
+            "variables" => {
+                let reference = args.get("variablesReference").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+                let variables = self.on_console(move |console| variables(console, reference))?;
+                Json::object(vec![("variables", Json::Array(variables))])
+            }
+            "readMemory" => {
+                let start = memory_reference(args)?;
+                let offset = args.get("offset").and_then(Json::as_f64).unwrap_or(0.0) as i32;
+                let count = (args.get("count").and_then(Json::as_f64).unwrap_or(0.0) as usize).min(0x10000);
+                let addr = start.wrapping_add(offset as u16);
+                let handle = self.handle()?;
+                let bytes = handle.read_memory(addr, count).map_err(|e| e.to_string())?;
+                Json::object(vec![
+                    ("address", Json::String(format!("0x{:04x}", addr))),
+                    ("data", Json::String(base64(&bytes))),
+                ])
+            }
+            "disassemble" => {
+                let start = memory_reference(args)?;
+                // No further than the address space is long, either way
+                let offset = (args.get("instructionOffset").and_then(Json::as_f64).unwrap_or(0.0) as i64)
+                    .clamp(-0x10000, 0x10000);
+                let count = (args.get("instructionCount").and_then(Json::as_f64).unwrap_or(0.0) as usize).min(0x1000);
+                let instructions = self.on_console(move |console| disassemble(console, start, offset, count))?;
+                Json::object(vec![("instructions", Json::Array(instructions))])
+            }
+            _ => return Err(format!("{} is not supported", command)),
+        };
+        Ok((body, None))
+    }
+
+    fn handle(&self) -> Result<&ConsoleHandle, String> {
+        self.handle.as_ref().ok_or_else(|| "launch or attach first".to_string())
+    }
+
+    fn on_console<R, F>(&self, f: F) -> Result<R, String>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Console) -> R + Send + 'static,
+    {
+        self.handle()?.with_console(f).map_err(|e| e.to_string())
+    }
+
+    fn start(&mut self, args: &Json, launch: bool) -> Result<(), String> {
+        let path = |key: &str| args.get(key).and_then(Json::as_str).map(PathBuf::from);
+        let launch_args = LaunchArgs {
+            program: path("program"),
+            symbols: path("symbols"),
+            stop_on_entry: args.get("stopOnEntry") == Some(&Json::Bool(true)),
+        };
+        let handle = (self.connect)(&launch_args, launch)?;
+        let symbols = match launch_args.symbols {
+            Some(ref path) => Some(Symbols::load(path).map_err(|e| format!("{}: {}", path.display(), e))?),
+            None => None,
+        };
+
+        // Stops while running come from the console, and are passed on by a thread of their
+        // own, which ends at the first one after the session, or with the console
+        let stops = handle.stops().map_err(|e| e.to_string())?;
+        let out = self.out.clone();
+        thread::spawn(move || {
+            for _ in stops {
+                if out.stopped("breakpoint").is_err() {
+                    return;
+                }
+            }
+        });
+
+        self.handle = Some(handle);
+        self.launched = launch;
+        self.stop_on_entry = launch_args.stop_on_entry;
+        let out = self.out.clone();
+        self.on_console(move |console| {
+            if let Some(symbols) = symbols {
+                console.set_symbols(symbols);
+            }
+            console.set_debug_message_hook(Some(Box::new(move |at, message: &str| {
+                let _ = out.event("output", Json::object(vec![
+                    ("category", Json::from("stdout")),
+                    ("output", Json::String(format!("{}: {}\n", at, message))),
+                ]));
+            })));
+            console.track_calls(true);
+            if console.step_history().is_none() {
+                console.record_steps(Some(DEFAULT_STEP_HISTORY));
+            }
+            if launch {
+                console.pause();
+            }
+        })
+    }
+
+    // Takes away the breaks in old and puts in new
+    fn replace_breaks(&self, old: Vec<Break>, new: Vec<Break>) -> Result<(), String> {
+        self.on_console(move |console| {
+            for &old in &old {
+                match old {
+                    Break::At(addr) => console.remove_breakpoint(addr),
+                    Break::On(condition) => console.remove_break_condition(condition),
+                }
+            }
+            for &new in &new {
+                match new {
+                    Break::At(addr) => console.add_breakpoint(addr),
+                    Break::On(condition) => console.add_break_condition(condition),
+                }
+            }
+        })
+    }
+
+    fn disconnect(&mut self) {
+        let mut breaks = Vec::new();
+        breaks.append(&mut self.function_breakpoints);
+        breaks.append(&mut self.instruction_breakpoints);
+        breaks.append(&mut self.exception_breakpoints);
+        let _ = self.replace_breaks(breaks, Vec::new());
+        let _ = self.on_console(|console| {
+            console.set_debug_message_hook(None);
+            console.resume();
+        });
+        self.handle = None;
+    }
+}
+
+fn stopped(reason: &'static str) -> (&'static str, Json) {
+    ("stopped", Json::object(vec![
+        ("reason", Json::from(reason)),
+        ("threadId", Json::Number(THREAD_ID)),
+        ("allThreadsStopped", Json::Bool(true)),
+    ]))
+}
+
+fn capabilities() -> Json {
+    let filter = |filter: &str, label: &str| Json::object(vec![
+        ("filter", Json::from(filter)),
+        ("label", Json::from(label)),
+        ("default", Json::Bool(false)),
+    ]);
+    Json::object(vec![
+        ("supportsConfigurationDoneRequest", Json::Bool(true)),
+        ("supportsFunctionBreakpoints", Json::Bool(true)),
+        ("supportsInstructionBreakpoints", Json::Bool(true)),
+        ("supportsStepBack", Json::Bool(true)),
+        ("supportsReadMemoryRequest", Json::Bool(true)),
+        ("supportsDisassembleRequest", Json::Bool(true)),
+        ("exceptionBreakpointFilters", Json::Array(vec![filter("irq", "Interrupts"), filter("rst", "RST")])),
+    ])
+}
+
+fn array<'a>(args: &'a Json, key: &str) -> &'a [Json] {
+    match args.get(key) {
+        Some(Json::Array(items)) => items,
+        _ => &[],
+    }
+}
+
+// A label first, since labels like "add" would read as addresses too
+fn resolve(symbols: &Symbols, name: &str) -> Option<Break> {
+    let name = name.trim();
+    symbols.find(name).map(Break::At)
+        .or_else(|| name.parse().ok().map(Break::On))
+        .or_else(|| name.parse().ok().map(Break::At))
+}
+
+fn breakpoints_body(resolved: &[Option<Break>]) -> Json {
+    let breakpoints = resolved.iter().map(|resolved| match resolved {
+        Some(Break::At(addr)) => Json::object(vec![
+            ("verified", Json::Bool(true)),
+            ("instructionReference", Json::String(format!("0x{:04x}", addr.addr))),
+        ]),
+        Some(Break::On(_)) => Json::object(vec![("verified", Json::Bool(true))]),
+        None => Json::object(vec![
+            ("verified", Json::Bool(false)),
+            ("message", Json::from("not a label, address or break condition")),
+        ]),
+    }).collect();
+    Json::object(vec![("breakpoints", Json::Array(breakpoints))])
+}
+
+fn memory_reference(args: &Json) -> Result<u16, String> {
+    args.get("memoryReference").and_then(Json::as_str)
+        .and_then(|reference| reference.parse::<BankedAddr>().ok())
+        .map(|addr| addr.addr)
+        .ok_or_else(|| "invalid memoryReference".to_string())
+}
+
+fn frame(id: usize, name: String, at: BankedAddr) -> Json {
+    Json::object(vec![
+        ("id", Json::Number(id as f64)),
+        ("name", Json::String(name)),
+        ("line", Json::Number(0.0)),
+        ("column", Json::Number(0.0)),
+        ("instructionPointerReference", Json::String(format!("0x{:04x}", at.addr))),
+    ])
+}
+
+// The innermost frame is where PC is, each one out where the call in it returns to
+fn stack_trace(console: &mut Console) -> Json {
+    let name = |at: BankedAddr| console.symbols().label(at).unwrap_or_else(|| at.to_string());
+    let pc = console.pc();
+    let mut frames = vec![frame(0, name(pc), pc)];
+    for (id, call) in console.call_stack().iter().enumerate() {
+        frames.push(frame(id + 1, name(call.return_to), call.return_to));
+    }
+    Json::object(vec![
+        ("totalFrames", Json::Number(frames.len() as f64)),
+        ("stackFrames", Json::Array(frames)),
+    ])
+}
+
+fn variable(name: &str, value: String, reference: usize) -> Json {
+    Json::object(vec![
+        ("name", Json::from(name)),
+        ("value", Json::String(value)),
+        ("variablesReference", Json::Number(reference as f64)),
+    ])
+}
+
+fn variables(console: &mut Console, reference: usize) -> Vec<Json> {
+    match reference {
+        REGISTERS => {
+            let regs = console.registers();
+            let byte = |name: &str, val: u8| variable(name, format!("0x{:02x}", val), 0);
+            let flags: String = [(0x80, 'Z'), (0x40, 'N'), (0x20, 'H'), (0x10, 'C')].iter()
+                .map(|&(mask, flag)| if regs.f & mask != 0 { flag } else { '-' })
+                .collect();
+            vec![
+                byte("A", regs.a), byte("F", regs.f), byte("B", regs.b), byte("C", regs.c),
+                byte("D", regs.d), byte("E", regs.e), byte("H", regs.h), byte("L", regs.l),
+                variable("SP", format!("0x{:04x}", regs.sp), 0),
+                variable("PC", console.pc().to_string(), 0),
+                variable("flags", flags, 0),
+                variable("IME", regs.ime.to_string(), 0),
+            ]
+        }
+        IO_REGISTERS => console.io_registers().decode().iter().enumerate().map(|(index, register)| {
+            let fields = if register.fields.is_empty() { 0 } else { IO_FIELDS + index };
+            variable(register.name, format!("0x{:02x}", register.raw), fields)
+        }).collect(),
+        _ if reference >= IO_FIELDS => match console.io_registers().decode().get(reference - IO_FIELDS) {
+            Some(register) => register.fields.iter().map(|(name, value)| variable(name, value.clone(), 0)).collect(),
+            None => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+// count instructions, starting offset instructions away from start. Instructions before start
+// are found by decoding from a little way back, which lines up with the real ones in practice.
+fn disassemble(console: &mut Console, start: u16, offset: i64, count: usize) -> Vec<Json> {
+    let mut instructions = Vec::new();
+    let mut from = start;
+    let mut count = count;
+    if offset < 0 {
+        let back = (-offset as usize).min(count);
+        let before: Vec<_> = console.disassemble(start.saturating_sub(3 * back as u16), 3 * back)
+            .into_iter().filter(|instruction| instruction.at.addr < start).collect();
+        let found = &before[before.len().saturating_sub(back)..];
+        for _ in found.len()..back {
+            instructions.push(Json::object(vec![
+                ("address", Json::from("0x0000")),
+                ("instruction", Json::from("??")),
+                ("presentationHint", Json::from("invalid")),
+            ]));
+        }
+        instructions.extend(found.iter().map(instruction));
+        count -= back;
+    } else if offset > 0 {
+        let skipped = console.disassemble(start, offset as usize + 1);
+        from = skipped.last().map_or(start, |instruction| instruction.at.addr);
+    }
+    instructions.extend(console.disassemble(from, count).iter().map(instruction));
+    instructions
+}
+
+fn instruction(instruction: &super::disasm::Instruction) -> Json {
+    let bytes: Vec<String> = instruction.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Json::object(vec![
+        ("address", Json::String(format!("0x{:04x}", instruction.at.addr))),
+        ("instructionBytes", Json::String(bytes.join(" "))),
+        ("instruction", Json::String(instruction.text.clone())),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::mpsc::{channel, Receiver};
+    use super::super::cart::Cart;
+
+    // The client's end of a session over channels
+    struct Client {
+        requests: std::sync::mpsc::Sender<u8>,
+        messages: Receiver<Json>,
+        seq: u32,
+    }
+
+    impl Client {
+        fn send(&mut self, command: &str, arguments: &str) {
+            self.seq += 1;
+            let body = format!("{{\"seq\":{},\"type\":\"request\",\"command\":\"{}\",\"arguments\":{}}}",
+                               self.seq, command, arguments);
+            for byte in format!("Content-Length: {}\r\n\r\n{}", body.len(), body).bytes() {
+                self.requests.send(byte).unwrap();
+            }
+        }
+
+        fn next(&mut self) -> Json {
+            self.messages.recv_timeout(std::time::Duration::from_secs(30)).unwrap()
+        }
+
+        // The response to command, and the events that came before it
+        fn call(&mut self, command: &str, arguments: &str) -> (Json, Vec<Json>) {
+            self.send(command, arguments);
+            let mut events = Vec::new();
+            loop {
+                let message = self.next();
+                if message.get("type") == Some(&Json::from("response")) {
+                    assert_eq!(message.get("command"), Some(&Json::from(command)));
+                    return (message, events);
+                }
+                events.push(message);
+            }
+        }
+    }
+
+    // Bytes from a channel, so the session can block on them
+    struct ChannelReader(Receiver<u8>);
+
+    impl Read for ChannelReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.recv() {
+                Ok(byte) if !buf.is_empty() => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    // Messages out of the session, parsed
+    struct ChannelWriter(Vec<u8>, std::sync::mpsc::Sender<Json>);
+
+    impl Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            let mut reader = &self.0[..];
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                let _ = self.1.send(message);
+                self.0 = reader.to_vec();
+                reader = &self.0[..];
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn body(response: &Json) -> &Json {
+        response.get("body").unwrap()
+    }
+
+    #[test]
+    fn debugs_a_launched_rom() {
+        let rom = fs::read("tetris.gb").unwrap();
+        let mut console = Console::new(Cart::new(rom.into_boxed_slice(), None).unwrap());
+        let mut symbols = Symbols::default();
+        symbols.insert(BankedAddr::new(0, 0x017E), "VBlankHandler");
+        console.set_symbols(symbols);
+        let handle = console.handle();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let emulation = thread::spawn(move || {
+            while !stop_flag.load(Ordering::SeqCst) {
+                console.run_frame(&mut NoVideo);
+            }
+        });
+
+        let (requests, input) = channel();
+        let (output, messages) = channel();
+        let session = thread::spawn(move || {
+            let connect: Connect = Box::new(move |args: &LaunchArgs, launch| {
+                assert!(launch && args.program == Some(PathBuf::from("tetris.gb")));
+                Ok(handle.clone())
+            });
+            serve(BufReader::new(ChannelReader(input)), Box::new(ChannelWriter(Vec::new(), output)), connect)
+        });
+        let mut client = Client { requests, messages, seq: 0 };
+
+        let (initialize, _) = client.call("initialize", "{\"adapterID\":\"gbrust\"}");
+        assert_eq!(body(&initialize).get("supportsStepBack"), Some(&Json::Bool(true)));
+        assert_eq!(client.next().get("event"), Some(&Json::from("initialized")));
+        let (launch, _) = client.call("launch", "{\"program\":\"tetris.gb\"}");
+        assert_eq!(launch.get("success"), Some(&Json::Bool(true)));
+
+        let (set, _) = client.call("setFunctionBreakpoints",
+                                   "{\"breakpoints\":[{\"name\":\"VBlankHandler\"},{\"name\":\"Nowhere\"}]}");
+        let verified: Vec<_> = array(body(&set), "breakpoints").iter().map(|b| b.get("verified").cloned()).collect();
+        assert_eq!(verified, [Some(Json::Bool(true)), Some(Json::Bool(false))]);
+        client.call("configurationDone", "{}");
+        let stopped = client.next();
+        assert_eq!(stopped.get("event"), Some(&Json::from("stopped")));
+        assert_eq!(body(&stopped).get("reason"), Some(&Json::from("breakpoint")));
+
+        let (trace, _) = client.call("stackTrace", "{\"threadId\":1}");
+        let frames = array(body(&trace), "stackFrames");
+        assert_eq!(frames[0].get("name"), Some(&Json::from("VBlankHandler")));
+        assert_eq!(frames[0].get("instructionPointerReference"), Some(&Json::from("0x017e")));
+
+        let (registers, _) = client.call("variables", "{\"variablesReference\":1}");
+        let pc = array(body(&registers), "variables").iter().find(|v| v.get("name") == Some(&Json::from("PC"))).cloned();
+        assert_eq!(pc.and_then(|pc| pc.get("value").cloned()), Some(Json::from("00:017e")));
+        let (io, _) = client.call("variables", "{\"variablesReference\":2}");
+        assert!(array(body(&io), "variables").iter().any(|v| v.get("name") == Some(&Json::from("LCDC"))));
+
+        client.call("stepIn", "{\"threadId\":1}");
+        assert_eq!(body(&client.next()).get("reason"), Some(&Json::from("step")));
+        client.call("stepBack", "{\"threadId\":1}");
+        client.next();
+        let (disassembly, _) = client.call("disassemble",
+                                           "{\"memoryReference\":\"0x017e\",\"instructionOffset\":-1,\"instructionCount\":3}");
+        let instructions = array(body(&disassembly), "instructions");
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(instructions[1].get("instruction"), Some(&Json::from("push af")));
+        for offset in ["1000000000000000", "-9223372036854775808"] {
+            let arguments = format!("{{\"memoryReference\":\"0x017e\",\"instructionOffset\":{},\"instructionCount\":2}}", offset);
+            let (far, _) = client.call("disassemble", &arguments);
+            assert_eq!(array(body(&far), "instructions").len(), 2);
+        }
+
+        let (unknown, _) = client.call("goto", "{}");
+        assert_eq!(unknown.get("success"), Some(&Json::Bool(false)));
+        client.call("disconnect", "{}");
+        session.join().unwrap().unwrap();
+        stop.store(true, Ordering::SeqCst);
+        emulation.join().unwrap();
+    }
+
+    #[test]
+    fn messages_stay_small() {
+        let message = |text: String| read_message(&mut io::Cursor::new(text)).map_err(|e| e.kind());
+        let body = "{\"seq\":1}";
+        assert!(matches!(message(format!("Content-Length: {}\r\n\r\n{}", body.len(), body)), Ok(Some(_))));
+        let endless = format!("X-Padding: {}", "a".repeat(2 * MAX_HEADER_LINE as usize));
+        assert_eq!(message(endless), Err(io::ErrorKind::InvalidData));
+        let huge = format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE + 1);
+        assert_eq!(message(huge), Err(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,524 @@
+// Debugger support.
+//
+// Banked addresses: with an MBC, 0x4abc means whatever ROM bank happens to be mapped, so
+// breakpoints and trace output use BankedAddr, which pins ROM addresses to a bank. Written
+// "12:4abc" (bank 0x12, the same as .sym files) or just "4abc" for any bank; 0x and $ prefixes
+// are accepted. Addresses outside ROM have no bank.
+//
+// Break conditions stop where code is entered through a vector rather than at an address: any
+// interrupt being taken ("irq"), the interrupt at one vector ("irq:48"), or an RST to one target
+// ("rst:38"). The machine stops with PC at the vector, before the handler's first instruction.
+//
+// Debug opcodes.
+// Homebrew toolchains use two loads that do nothing as debugger hooks, as BGB and no$gmb read
+// them. LD B,B is a software breakpoint: the "ldbb" break condition stops right after one. LD D,D
+// is a message, logged (target gbrust::debug) and passed to Console::set_debug_message_hook. The
+// text follows it, jumped over:
+//
+//     ld d,d
+//     jr .end
+//     dw $6464, $0000
+//     db "hp=%A% at %HL%"
+//   .end:
+//
+// with registers between percent signs replaced by their values. An LD D,D without the text
+// gives the registers instead. The recompiler does not see debug opcodes.
+//
+// Stepping over and out.
+// Stepping over a CALL or RST runs until PC is back at the instruction after it with SP where it
+// was, so recursion and interrupts taken on the way do not end it early. Stepping out runs until
+// a RET or RETI leaves SP above where it started. Both go instruction by instruction, so they
+// are journaled like single steps and stop at breakpoints.
+//
+// Call stack.
+// With Console::track_calls on, the CPU keeps a shadow of the call stack: a frame for each CALL,
+// RST and interrupt taken, with SP right after the return address went on. A return drops every
+// frame whose return address is now above SP, so code that pops its return address or moves SP
+// itself leaves the shadow right again at the next return. It never goes deeper than
+// MAX_CALL_DEPTH, forgetting the oldest frames, for games that call without ever returning.
+//
+// Reverse stepping.
+// While recording, every instruction run through Console::advance_instruction is journaled with
+// the registers it started from, and every CHECKPOINT_INTERVAL instructions a save state is
+// taken. Undoing an instruction loads the closest checkpoint before it and replays forward up to
+// it, so memory, the PPU, timers and sound all come back exactly, side effects included, which
+// patching back individual bus writes could not promise.
+// Only the last `limit` instructions can be undone. Running whole frames, loading a state or
+// pressing buttons moves the machine in ways the journal does not see, so they clear it.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use super::dmg_cpu::{Dispatch, RegisterSnapshot};
+use super::error::{BankedAddrParseError, BreakOnParseError};
+use super::interrupts::Interrupt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BankedAddr {
+    pub bank: Option<u16>,
+    pub addr: u16,
+}
+
+impl BankedAddr {
+    pub fn new(bank: u16, addr: u16) -> BankedAddr {
+        BankedAddr { bank: Some(bank), addr }
+    }
+
+    // Any bank
+    pub fn unbanked(addr: u16) -> BankedAddr {
+        BankedAddr { bank: None, addr }
+    }
+
+    // Where addr points with rom_bank mapped at 0x4000 - 0x7fff
+    pub fn resolve(addr: u16, rom_bank: usize) -> BankedAddr {
+        match addr {
+            0x0000..=0x3FFF => BankedAddr::new(0, addr),
+            0x4000..=0x7FFF => BankedAddr::new(rom_bank as u16, addr),
+            _ => BankedAddr::unbanked(addr),
+        }
+    }
+
+    // Whether a breakpoint on self stops at here. No bank on self matches every bank.
+    pub fn matches(&self, here: BankedAddr) -> bool {
+        self.addr == here.addr && (self.bank.is_none() || self.bank == here.bank)
+    }
+}
+
+impl FromStr for BankedAddr {
+    type Err = BankedAddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = |part: &str| {
+            let part = part.trim();
+            let digits = part.strip_prefix("0x").or_else(|| part.strip_prefix('$')).unwrap_or(part);
+            u16::from_str_radix(digits, 16).map_err(|_| BankedAddrParseError(s.to_string()))
+        };
+        let lower = s.to_ascii_lowercase();
+        match lower.split_once(':') {
+            Some((bank, addr)) => Ok(BankedAddr::new(hex(bank)?, hex(addr)?)),
+            None => Ok(BankedAddr::unbanked(hex(&lower)?)),
+        }
+    }
+}
+
+impl fmt::Display for BankedAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02x}:{:04x}", bank, self.addr),
+            None => write!(f, "{:04x}", self.addr),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CallKind {
+    Call,
+    Rst,
+    Interrupt(Interrupt),
+}
+
+// One function being run. name is target's label, when symbols are loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub kind: CallKind,
+    pub target: BankedAddr,
+    pub return_to: BankedAddr,
+    pub sp: u16,
+    pub name: Option<String>,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.target)?;
+        if let Some(ref name) = self.name {
+            write!(f, " {}", name)?;
+        }
+        match self.kind {
+            CallKind::Call => write!(f, " (call")?,
+            CallKind::Rst => write!(f, " (rst")?,
+            CallKind::Interrupt(interrupt) => write!(f, " ({:?} interrupt", interrupt)?,
+        }
+        write!(f, ", returns to {})", self.return_to)
+    }
+}
+
+pub const MAX_CALL_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<StackFrame>, // outermost first
+}
+
+impl CallStack {
+    pub fn called(&mut self, kind: CallKind, target: BankedAddr, return_to: BankedAddr, sp: u16) {
+        if self.frames.len() == MAX_CALL_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(StackFrame { kind, target, return_to, sp, name: None });
+    }
+
+    // After a return left SP at sp
+    pub fn returned(&mut self, sp: u16) {
+        while self.frames.last().is_some_and(|frame| frame.sp < sp) {
+            self.frames.pop();
+        }
+    }
+
+    pub fn frames(&self) -> &[StackFrame] {
+        &self.frames
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BreakOn {
+    AnyInterrupt,
+    Interrupt(Interrupt),
+    Rst(u16), // its target, 0x00 - 0x38
+    DebugBreak, // LD B,B
+}
+
+impl BreakOn {
+    // Whether a step that went through dispatch stops here
+    pub fn matches(&self, dispatch: Dispatch) -> bool {
+        match *self {
+            BreakOn::AnyInterrupt => dispatch.interrupt.is_some(),
+            BreakOn::Interrupt(interrupt) => dispatch.interrupt == Some(interrupt),
+            BreakOn::Rst(target) => dispatch.rst == Some(target),
+            BreakOn::DebugBreak => dispatch.debug == Some(DebugOpcode::Break),
+        }
+    }
+}
+
+impl FromStr for BreakOn {
+    type Err = BreakOnParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vector = |part: &str| {
+            let digits = part.strip_prefix("0x").or_else(|| part.strip_prefix('$')).unwrap_or(part);
+            u16::from_str_radix(digits, 16).ok()
+        };
+        let lower = s.trim().to_ascii_lowercase();
+        let condition = match lower.split_once(':') {
+            None if lower == "irq" => Some(BreakOn::AnyInterrupt),
+            None if lower == "ldbb" => Some(BreakOn::DebugBreak),
+            Some(("irq", at)) => vector(at).and_then(Interrupt::from_vector).map(BreakOn::Interrupt),
+            Some(("rst", at)) => vector(at).filter(|&target| target <= 0x38 && target & 0x07 == 0).map(BreakOn::Rst),
+            _ => None,
+        };
+        condition.ok_or_else(|| BreakOnParseError(s.to_string()))
+    }
+}
+
+impl fmt::Display for BreakOn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakOn::AnyInterrupt => write!(f, "irq"),
+            BreakOn::Interrupt(interrupt) => write!(f, "irq:{:02x}", interrupt.vector()),
+            BreakOn::Rst(target) => write!(f, "rst:{:02x}", target),
+            BreakOn::DebugBreak => write!(f, "ldbb"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DebugOpcode {
+    Break,   // LD B,B
+    Message, // LD D,D
+}
+
+impl DebugOpcode {
+    pub fn from_opcode(opcode: u8) -> Option<DebugOpcode> {
+        match opcode {
+            0x40 => Some(DebugOpcode::Break),
+            0x52 => Some(DebugOpcode::Message),
+            _ => None,
+        }
+    }
+}
+
+// The message of the LD D,D right before regs.pc, read with read
+pub fn debug_message<F: FnMut(u16) -> u8>(mut read: F, regs: &RegisterSnapshot) -> String {
+    let at = regs.pc;
+    let header: Vec<u8> = (0..6).map(|offset| read(at.wrapping_add(offset))).collect();
+    if header[0] != 0x18 || header[2..] != [0x64, 0x64, 0x00, 0x00] {
+        return format!("A={:02x} F={:02x} BC={:04x} DE={:04x} HL={:04x} SP={:04x}",
+                       regs.a, regs.f, regs.bc(), regs.de(), regs.hl(), regs.sp);
+    }
+    let len = (header[1] as u16).saturating_sub(4);
+    let bytes: Vec<u8> = (0..len).map(|offset| read(at.wrapping_add(6 + offset))).collect();
+    let text = String::from_utf8_lossy(&bytes);
+
+    // %NAME% for a register, anything else is left as it is
+    let mut message = String::new();
+    let mut rest = &text[..];
+    while let Some(start) = rest.find('%') {
+        message.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('%').and_then(|end| register(&after[..end], regs).map(|value| (end, value))) {
+            Some((end, value)) => {
+                message.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                message.push('%');
+                rest = after;
+            }
+        }
+    }
+    message.push_str(rest);
+    message
+}
+
+// PC is the LD D,D's
+fn register(name: &str, regs: &RegisterSnapshot) -> Option<String> {
+    let byte = |val: u8| Some(format!("{:02x}", val));
+    let word = |val: u16| Some(format!("{:04x}", val));
+    match name.to_ascii_uppercase().as_str() {
+        "A" => byte(regs.a),
+        "F" => byte(regs.f),
+        "B" => byte(regs.b),
+        "C" => byte(regs.c),
+        "D" => byte(regs.d),
+        "E" => byte(regs.e),
+        "H" => byte(regs.h),
+        "L" => byte(regs.l),
+        "AF" => word(regs.af()),
+        "BC" => word(regs.bc()),
+        "DE" => word(regs.de()),
+        "HL" => word(regs.hl()),
+        "SP" => word(regs.sp),
+        "PC" => word(regs.pc.wrapping_sub(1)),
+        _ => None,
+    }
+}
+
+pub const DEFAULT_STEP_HISTORY: usize = 1024;
+// A step over or out gives up after a minute of frames, for calls that never return
+pub const STEP_FRAME_LIMIT: u64 = 60 * 60;
+
+// How a step over or out ended
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StepEnd {
+    Done,       // returned, or was a single instruction
+    Breakpoint, // see Console::breakpoint_hit
+    Limit,      // STEP_FRAME_LIMIT frames went by
+}
+
+// Bytes of a CALL, conditional or not, or an RST, None for any other opcode
+pub fn call_length(opcode: u8) -> Option<u16> {
+    match opcode {
+        0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC => Some(3),
+        _ if opcode & 0xC7 == 0xC7 => Some(1),
+        _ => None,
+    }
+}
+
+// RET, RETI and the conditional RETs
+pub fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8)
+}
+const CHECKPOINT_INTERVAL: u64 = 64;
+
+struct Checkpoint {
+    instruction: u64, // taken right before this instruction
+    frame_count: u64,
+    state: Box<[u8]>,
+}
+
+pub struct StepHistory {
+    limit: usize,
+    // Number of the next instruction, counted from when recording started
+    next: u64,
+    // Registers before each of the last instructions, oldest first
+    registers: VecDeque<RegisterSnapshot>,
+    checkpoints: VecDeque<Checkpoint>,
+}
+
+// Where to go to undo the last instruction: load state, then replay `replay` instructions
+pub struct Rewind<'a> {
+    pub state: &'a [u8],
+    pub frame_count: u64,
+    pub replay: u64,
+}
+
+impl StepHistory {
+    pub fn new(limit: usize) -> StepHistory {
+        StepHistory {
+            limit: limit.max(1),
+            next: 0,
+            registers: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // Instructions that can be undone
+    pub fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty()
+    }
+
+    // Registers before each recorded instruction, oldest first
+    pub fn registers(&self) -> impl Iterator<Item = &RegisterSnapshot> {
+        self.registers.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.registers.clear();
+        self.checkpoints.clear();
+    }
+
+    pub fn needs_checkpoint(&self) -> bool {
+        self.checkpoints.back().map_or(true, |c| c.instruction + CHECKPOINT_INTERVAL <= self.next)
+    }
+
+    // Journals an instruction about to run. state is only needed when needs_checkpoint().
+    pub fn record(&mut self, registers: RegisterSnapshot, frame_count: u64, state: Option<Box<[u8]>>) {
+        if let Some(state) = state {
+            self.checkpoints.push_back(Checkpoint { instruction: self.next, frame_count, state });
+        }
+        self.registers.push_back(registers);
+        self.next += 1;
+
+        if self.registers.len() > self.limit {
+            self.registers.pop_front();
+        }
+        // Keep the newest checkpoint at or before the oldest instruction still journaled
+        let oldest = self.next - self.registers.len() as u64;
+        while self.checkpoints.len() > 1 && self.checkpoints[1].instruction <= oldest {
+            self.checkpoints.pop_front();
+        }
+    }
+
+    // Forgets the last instruction and says how to get back to before it
+    pub fn rewind(&mut self) -> Option<Rewind<'_>> {
+        self.registers.pop_back()?;
+        self.next -= 1;
+        while self.checkpoints.back().is_some_and(|c| c.instruction > self.next) {
+            self.checkpoints.pop_back();
+        }
+        let checkpoint = self.checkpoints.back()?;
+        Some(Rewind {
+            state: &checkpoint.state,
+            frame_count: checkpoint.frame_count,
+            replay: self.next - checkpoint.instruction,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::console::{Console, NoVideo};
+
+    #[test]
+    fn banked_addresses() {
+        assert_eq!("12:4abc".parse(), Ok(BankedAddr::new(0x12, 0x4ABC)));
+        assert_eq!("0x12:$4ABC".parse(), Ok(BankedAddr::new(0x12, 0x4ABC)));
+        assert_eq!("c000".parse(), Ok(BankedAddr::unbanked(0xC000)));
+        assert!("12:".parse::<BankedAddr>().is_err());
+        assert_eq!(BankedAddr::resolve(0x4ABC, 0x12).to_string(), "12:4abc");
+        assert_eq!(BankedAddr::resolve(0xC000, 0x12).to_string(), "c000");
+
+        let here = BankedAddr::resolve(0x4ABC, 0x12);
+        assert!(BankedAddr::new(0x12, 0x4ABC).matches(here));
+        assert!(BankedAddr::unbanked(0x4ABC).matches(here));
+        assert!(!BankedAddr::new(0x13, 0x4ABC).matches(here));
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        assert_eq!(call_length(0xCD), Some(3));
+        assert_eq!(call_length(0xDC), Some(3));
+        assert_eq!(call_length(0xEF), Some(1)); // rst $28
+        assert_eq!(call_length(0xC3), None);
+        assert!(is_return(0xD9) && is_return(0xC0) && !is_return(0xC3));
+    }
+
+    #[test]
+    fn call_stack_follows_sp() {
+        let at = BankedAddr::unbanked;
+        let mut stack = CallStack::default();
+        stack.called(CallKind::Call, at(0x0200), at(0x0153), 0xFFFC);
+        stack.called(CallKind::Rst, at(0x0028), at(0x0205), 0xFFFA);
+        stack.called(CallKind::Interrupt(Interrupt::VBlank), at(0x0040), at(0x0030), 0xFFF8);
+        stack.returned(0xFFFA);
+        assert_eq!(stack.frames().len(), 2);
+        // The RST handler popped its return address and jumped, then the call returned
+        stack.returned(0xFFFE);
+        assert!(stack.frames().is_empty());
+
+        for n in 0..MAX_CALL_DEPTH as u16 + 1 {
+            stack.called(CallKind::Call, at(0x0200), at(n), 0xFFFC - 2 * n);
+        }
+        assert_eq!(stack.frames().len(), MAX_CALL_DEPTH);
+        assert_eq!(stack.frames()[0].return_to, at(1));
+    }
+
+    #[test]
+    fn break_conditions() {
+        assert_eq!("IRQ".parse(), Ok(BreakOn::AnyInterrupt));
+        assert_eq!("irq:0x48".parse(), Ok(BreakOn::Interrupt(Interrupt::LcdStat)));
+        assert_eq!("rst:$38".parse(), Ok(BreakOn::Rst(0x38)));
+        assert!("irq:44".parse::<BreakOn>().is_err());
+        assert!("rst:40".parse::<BreakOn>().is_err());
+        assert_eq!(BreakOn::Interrupt(Interrupt::Joypad).to_string(), "irq:60");
+
+        assert_eq!("ldbb".parse::<BreakOn>().map(|condition| condition.to_string()), Ok("ldbb".to_string()));
+        let both = Dispatch { rst: Some(0x38), interrupt: Some(Interrupt::Timer), debug: None };
+        assert!(BreakOn::AnyInterrupt.matches(both) && BreakOn::Rst(0x38).matches(both));
+        assert!(!BreakOn::Interrupt(Interrupt::VBlank).matches(both));
+        assert!(!BreakOn::AnyInterrupt.matches(Dispatch::default()));
+        let debug_break = Dispatch { debug: DebugOpcode::from_opcode(0x40), ..Dispatch::default() };
+        assert!(BreakOn::DebugBreak.matches(debug_break) && !BreakOn::DebugBreak.matches(both));
+    }
+
+    #[test]
+    fn debug_messages() {
+        let regs = RegisterSnapshot {
+            a: 0x2a, f: 0x80, b: 0, c: 0, d: 0, e: 0, h: 0xc1, l: 0x23, sp: 0xdfff, pc: 0x0201, ime: false,
+        };
+        let mut mem = vec![0u8; 0x300];
+        let text = b"hp=%A% at %hl%, 100% %X%";
+        mem[0x201..0x207].copy_from_slice(&[0x18, text.len() as u8 + 4, 0x64, 0x64, 0x00, 0x00]);
+        mem[0x207..0x207 + text.len()].copy_from_slice(text);
+        assert_eq!(debug_message(|addr| mem[addr as usize], &regs), "hp=2a at c123, 100% %X%");
+        assert_eq!(debug_message(|_| 0, &regs), "A=2a F=80 BC=0000 DE=0000 HL=c123 SP=dfff");
+    }
+
+    #[test]
+    fn step_back_restores_earlier_instructions() {
+        let mut console = Console::builder().rom_path("tetris.gb").build().unwrap();
+        console.run_frame(&mut NoVideo);
+        console.record_steps(Some(200));
+
+        let mut states = Vec::new();
+        for _ in 0..300 {
+            states.push((console.save_state(), console.registers()));
+            console.advance_instruction(&mut NoVideo);
+        }
+        assert_eq!(console.step_history().unwrap().len(), 200);
+        for (state, registers) in states.iter().rev().take(200) {
+            assert!(console.step_back());
+            assert_eq!(console.registers(), *registers);
+            assert!(console.save_state() == *state);
+        }
+        assert!(!console.step_back());
+
+        // Stepping forward again journals from here
+        console.advance_instruction(&mut NoVideo);
+        assert!(console.step_back());
+        assert!(console.save_state() == states[100].0);
+    }
+}
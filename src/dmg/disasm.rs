@@ -0,0 +1,194 @@
+// Disassembler, for the debugger and `gbrust disasm`.
+// Opcodes are decoded by their bit fields, the way PanDocs' opcode tables are laid out:
+//
+//     x = opcode >> 6, y = (opcode >> 3) & 7, z = opcode & 7, p = y >> 1, q = y & 1
+//
+// Syntax is lowercase with $ for hex, like the debugger takes addresses. Relative jumps show
+// where they land rather than the offset, and the ld ($ff00+n) forms show the full address.
+// Opcodes that do not exist come out as "db $xx".
+
+use std::fmt;
+
+use super::debugger::BankedAddr;
+
+const R: [&str; 8] = ["b", "c", "d", "e", "h", "l", "(hl)", "a"];
+const RP: [&str; 4] = ["bc", "de", "hl", "sp"];
+const RP2: [&str; 4] = ["bc", "de", "hl", "af"];
+const CC: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add a,", "adc a,", "sub ", "sbc a,", "and ", "xor ", "or ", "cp "];
+const ROT: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const ACC: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub at: BankedAddr,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        write!(f, "{:<8} {:<9} {}", self.at.to_string(), bytes.join(" "), self.text)
+    }
+}
+
+// Reads the instruction's bytes as it goes
+struct Decoder<F> {
+    addr: u16,
+    read: F,
+    bytes: Vec<u8>,
+}
+
+impl<F: FnMut(u16) -> u8> Decoder<F> {
+    fn u8(&mut self) -> u8 {
+        let byte = (self.read)(self.addr.wrapping_add(self.bytes.len() as u16));
+        self.bytes.push(byte);
+        byte
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.u8(), self.u8()])
+    }
+
+    // Where a relative jump lands
+    fn target(&mut self) -> u16 {
+        let offset = self.u8() as i8;
+        self.addr.wrapping_add(2).wrapping_add(offset as u16)
+    }
+
+    fn sp_offset(&mut self) -> String {
+        let offset = self.u8() as i8;
+        if offset < 0 { format!("-${:02x}", -(offset as i16)) } else { format!("+${:02x}", offset) }
+    }
+
+    fn text(&mut self) -> String {
+        let opcode = self.u8();
+        let (x, y, z) = ((opcode >> 6) as usize, ((opcode >> 3) & 7) as usize, (opcode & 7) as usize);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (0, 0) => match y {
+                0 => "nop".to_string(),
+                1 => format!("ld (${:04x}),sp", self.u16()),
+                2 => {
+                    self.u8();
+                    "stop".to_string()
+                }
+                3 => format!("jr ${:04x}", self.target()),
+                _ => format!("jr {},${:04x}", CC[y - 4], self.target()),
+            },
+            (0, 1) if q == 0 => format!("ld {},${:04x}", RP[p], self.u16()),
+            (0, 1) => format!("add hl,{}", RP[p]),
+            (0, 2) => {
+                let pointer = ["(bc)", "(de)", "(hl+)", "(hl-)"][p];
+                if q == 0 { format!("ld {},a", pointer) } else { format!("ld a,{}", pointer) }
+            }
+            (0, 3) => format!("{} {}", if q == 0 { "inc" } else { "dec" }, RP[p]),
+            (0, 4) => format!("inc {}", R[y]),
+            (0, 5) => format!("dec {}", R[y]),
+            (0, 6) => format!("ld {},${:02x}", R[y], self.u8()),
+            (0, _) => ACC[y].to_string(),
+            (1, 6) if y == 6 => "halt".to_string(),
+            (1, _) => format!("ld {},{}", R[y], R[z]),
+            (2, _) => format!("{}{}", ALU[y], R[z]),
+            (_, 0) => match y {
+                0..=3 => format!("ret {}", CC[y]),
+                4 => format!("ld ($ff{:02x}),a", self.u8()),
+                5 => format!("add sp,{}", self.sp_offset()),
+                6 => format!("ld a,($ff{:02x})", self.u8()),
+                _ => format!("ld hl,sp{}", self.sp_offset()),
+            },
+            (_, 1) if q == 0 => format!("pop {}", RP2[p]),
+            (_, 1) => ["ret", "reti", "jp hl", "ld sp,hl"][p].to_string(),
+            (_, 2) => match y {
+                0..=3 => format!("jp {},${:04x}", CC[y], self.u16()),
+                4 => "ld ($ff00+c),a".to_string(),
+                5 => format!("ld (${:04x}),a", self.u16()),
+                6 => "ld a,($ff00+c)".to_string(),
+                _ => format!("ld a,(${:04x})", self.u16()),
+            },
+            (_, 3) => match y {
+                0 => format!("jp ${:04x}", self.u16()),
+                1 => self.cb(),
+                6 => "di".to_string(),
+                7 => "ei".to_string(),
+                _ => format!("db ${:02x}", opcode),
+            },
+            (_, 4) if y < 4 => format!("call {},${:04x}", CC[y], self.u16()),
+            (_, 5) if q == 0 => format!("push {}", RP2[p]),
+            (_, 5) if p == 0 => format!("call ${:04x}", self.u16()),
+            (_, 6) => format!("{}${:02x}", ALU[y], self.u8()),
+            (_, 7) => format!("rst ${:02x}", y * 8),
+            _ => format!("db ${:02x}", opcode),
+        }
+    }
+
+    // After the 0xcb prefix
+    fn cb(&mut self) -> String {
+        let opcode = self.u8();
+        let (y, z) = (((opcode >> 3) & 7) as usize, (opcode & 7) as usize);
+        match opcode >> 6 {
+            0 => format!("{} {}", ROT[y], R[z]),
+            1 => format!("bit {},{}", y, R[z]),
+            2 => format!("res {},{}", y, R[z]),
+            _ => format!("set {},{}", y, R[z]),
+        }
+    }
+}
+
+// The instruction at addr, reading memory through read. rom_bank is only for labelling.
+pub fn decode<F: FnMut(u16) -> u8>(addr: u16, rom_bank: usize, read: F) -> Instruction {
+    let mut decoder = Decoder { addr, read, bytes: Vec::new() };
+    let text = decoder.text();
+    Instruction { at: BankedAddr::resolve(addr, rom_bank), bytes: decoder.bytes, text }
+}
+
+// count instructions of a ROM dump from start on, with start's bank mapped at 0x4000 - 0x7fff,
+// or bank 1 when start has none or is in bank 0. Bytes past the end of the dump read as 0xff,
+// like an open bus.
+pub fn disassemble_rom(rom: &[u8], start: BankedAddr, count: usize) -> Vec<Instruction> {
+    let bank = start.bank.filter(|_| start.addr >= 0x4000).unwrap_or(1) as usize;
+    let read = |addr: u16| {
+        let offset = match addr {
+            0x0000..=0x3FFF => addr as usize,
+            0x4000..=0x7FFF => bank * 0x4000 + (addr as usize - 0x4000),
+            _ => usize::MAX,
+        };
+        rom.get(offset).copied().unwrap_or(0xFF)
+    };
+    let mut addr = start.addr;
+    (0..count).map(|_| {
+        let instruction = decode(addr, bank, read);
+        addr = addr.wrapping_add(instruction.bytes.len() as u16);
+        instruction
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_every_form() {
+        let rom = [
+            0x00, 0xC3, 0x50, 0x01, 0x20, 0xFE, 0xE0, 0x40, 0xCB, 0x7C, 0x76, 0x41, 0xF8, 0xF0,
+            0x3A, 0xD3, 0xCD, 0x34, 0x12, 0xFE, 0x90, 0xFF,
+        ];
+        let text: Vec<String> = disassemble_rom(&rom, BankedAddr::new(0, 0), 14).iter()
+            .map(|instruction| instruction.text.clone())
+            .collect();
+        assert_eq!(text, [
+            "nop", "jp $0150", "jr nz,$0004", "ld ($ff40),a", "bit 7,h", "halt", "ld b,c",
+            "ld hl,sp-$10", "ld a,(hl-)", "db $d3", "call $1234", "cp $90", "rst $38", "rst $38",
+        ]);
+    }
+
+    #[test]
+    fn reads_the_banked_half_of_the_dump() {
+        let mut rom = vec![0; 0x10000];
+        rom[0x2 * 0x4000 + 0x123] = 0x3E; // ld a,n in bank 2
+        rom[0x2 * 0x4000 + 0x124] = 0x99;
+        let listing = disassemble_rom(&rom, BankedAddr::new(2, 0x4123), 1);
+        assert_eq!(listing[0].to_string(), "02:4123  3e 99     ld a,$99");
+    }
+}
@@ -1,5 +1,15 @@
 use super::interconnect::Interconnect;
 use super::console::VideoSink;
+use super::crash::RecentInstructions;
+use super::debugger::{BankedAddr, CallKind, CallStack, DebugOpcode};
+use super::interrupts::Interrupt;
+use super::events::Event;
+use super::state::{StateError, StateReader, StateWriter};
+use super::timing::{TimingCheck, TimingMismatch};
+use super::error::JitError;
+#[cfg(feature = "jit")]
+use super::jit::{Jit, JitRegs};
+use std::fmt;
 use std::{thread, time};
 
 // Flags
@@ -7,6 +17,8 @@ const ZF: u8 = 0x80; // 0b10000000
 const NF: u8 = 0x40; // 0b01000000
 const HF: u8 = 0x20; // 0b00100000
 const CF: u8 = 0x10; // 0b00010000
+// Bits 0-3 of F do not exist in hardware and always read 0
+const F_MASK: u8 = 0xF0;
 
 // 8-bit Register IDs
 const A_ID: u8 = 0b111;
@@ -29,8 +41,7 @@ const AF_ID: u8 = 0b11;
 /// GB has 8 8-bit registers (including special flag register).
 /// 3 16-bit pair registers, which is a combination from pairing 2 8-bit registers together.
 /// 2 special registers: SP and PC.
-/// 3 Interrupt Registers: IME (master), IE: Interrupt Enable -> Enables interrupts, IF: Interrupt
-///   Flag -> Requests Interrupts
+/// The interrupt registers (IME, IE and IF) live in the InterruptController, see interrupts.rs.
 pub struct Registers {
 	a: u8,      // Accumulator register, done
 	b: u8,      // done
@@ -49,10 +60,40 @@ pub struct Registers {
 	f: u8,      // Special flag register, done
 	sp: u16,    // Stack pointer. SP will start at 65536. Done
 	pc: u16,
+}
+
+// Read-only copy of the registers, for debuggers and watches
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RegisterSnapshot {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+}
+
+impl RegisterSnapshot {
+    pub fn af(&self) -> u16 {
+        ((self.a as u16) << 8) | self.f as u16
+    }
+
+    pub fn bc(&self) -> u16 {
+        ((self.b as u16) << 8) | self.c as u16
+    }
+
+    pub fn de(&self) -> u16 {
+        ((self.d as u16) << 8) | self.e as u16
+    }
 
-	// Registers for interrupt.
-	// IME: 0 -> Disable all Interrupts, 1 -> Enable all Interrupts enabled in IE
-	ime: bool,    // Enable / Disable all interrupts
+    pub fn hl(&self) -> u16 {
+        ((self.h as u16) << 8) | self.l as u16
+    }
 }
 
 impl Registers {
@@ -75,10 +116,62 @@ impl Registers {
             f: 0xB0,
             sp: 0xFFFE,
             pc: 0x0100,
+        }
+    }
 
-            ime: true,
+    // State at power on, before the boot ROM runs
+    pub fn power_on() -> Self {
+        Registers {
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+
+            bc: 0,
+            de: 0,
+            hl: 0,
+
+            f: 0,
+            sp: 0,
+            pc: 0,
         }
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.a);
+        state.write_u8(self.b);
+        state.write_u8(self.c);
+        state.write_u8(self.d);
+        state.write_u8(self.e);
+        state.write_u8(self.h);
+        state.write_u8(self.l);
+        state.write_u16(self.bc);
+        state.write_u16(self.de);
+        state.write_u16(self.hl);
+        state.write_u8(self.f);
+        state.write_u16(self.sp);
+        state.write_u16(self.pc);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.a = state.read_u8()?;
+        self.b = state.read_u8()?;
+        self.c = state.read_u8()?;
+        self.d = state.read_u8()?;
+        self.e = state.read_u8()?;
+        self.h = state.read_u8()?;
+        self.l = state.read_u8()?;
+        self.bc = state.read_u16()?;
+        self.de = state.read_u16()?;
+        self.hl = state.read_u16()?;
+        self.f = state.read_u8()? & F_MASK;
+        self.sp = state.read_u16()?;
+        self.pc = state.read_u16()?;
+        Ok(())
+    }
 }
 
 pub struct Cpu {
@@ -91,15 +184,75 @@ pub struct Cpu {
 	stop_mode: bool,    // true -> enter stop mode
 
 	pub interconnect: Interconnect, // in charge of everything else. Needs to be pub to be accessed by console
+
+	vector_trap: Option<VectorTrap>,
+	dispatch: Dispatch, // vectors the last step went through
+	call_stack: Option<CallStack>, // when tracking calls, see debugger.rs
+
+	timing_check: Option<TimingCheck>, // see timing.rs
+
+	fault: Option<CpuFault>,          // locked up, see CpuFault
+	recent: RecentInstructions,       // for crash reports, see crash.rs
+	keep_recent: bool,                // whether recent is kept, see keep_recent_instructions
+
+	#[cfg(feature = "jit")]
+	jit: Option<Box<Jit>>,            // see jit.rs
+	#[cfg(feature = "jit")]
+	jit_instructions: u64,            // run as compiled blocks
+
+	// Overclock, see clocked_cycles
+	overclock: u32,    // extra cycles per scanline
+	free_cycles: u32,  // left on this scanline
+	line_cycles: u32,  // into this scanline
+}
+
+// Cycles the rest of the machine takes to draw a scanline
+const LINE_CYCLES: u32 = 456;
+
+// Why the CPU stopped for good. The hardware locks up on an opcode that does not exist: nothing
+// runs and no interrupt is taken until the power is cycled, while the PPU and the rest carry on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpuFault {
+    IllegalOpcode { at: BankedAddr, opcode: u8 },
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuFault::IllegalOpcode { at, opcode } => write!(f, "illegal opcode ${:02x} at {}", opcode, at),
+        }
+    }
 }
 
+// What to do after a vector trap ran
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TrapAction {
+    Return,  // act as if the vector held RET (RETI for interrupt vectors)
+    Execute, // run the ROM code at the vector after all
+}
+
+// The vectors one step jumped to, and the debug opcode it ran, for break conditions and debug
+// messages. Both vectors are set when an interrupt is taken right after an RST.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Dispatch {
+    pub rst: Option<u16>,
+    pub interrupt: Option<Interrupt>,
+    pub debug: Option<DebugOpcode>,
+}
+
+// Called with the vector address (0x00 - 0x38 for RST, 0x40 - 0x60 for interrupts) and the CPU,
+// with the return address on top of the stack
+pub type VectorTrap = Box<dyn FnMut(u16, &mut Cpu) -> TrapAction + Send>;
+
 pub enum ProgramCounter { // Each returned ProgramCounter will return number of bytes of instruction, then number of cycles 
     Next(i16, u32),
     Jump(u16, u32),
 }
 
 impl Cpu {
-    pub fn new(interconnect: Interconnect) -> Self {
+    pub fn new(mut interconnect: Interconnect) -> Self {
+        // As the boot ROM leaves it, like the registers
+        interconnect.interrupts.set_master_enabled(true);
         Cpu {
             reg: Registers::new(),
             //mem: [0; 65536],
@@ -108,67 +261,365 @@ impl Cpu {
 
             halt_mode: false,
             stop_mode: false,
+            vector_trap: None,
+            dispatch: Dispatch::default(),
+            call_stack: None,
+            timing_check: None,
+            fault: None,
+            recent: RecentInstructions::default(),
+            keep_recent: true,
+            #[cfg(feature = "jit")]
+            jit: None,
+            #[cfg(feature = "jit")]
+            jit_instructions: 0,
+            overclock: 0,
+            free_cycles: 0,
+            line_cycles: 0,
+        }
+    }
+
+    // Lets the CPU run `cycles` more cycles on every scanline than the hardware allows. The PPU,
+    // APU and timer keep their pace, so games that lag get more done per frame but sound and
+    // look the same. 0 runs at the normal clock.
+    pub fn set_overclock(&mut self, cycles: u32) {
+        self.overclock = cycles;
+        self.free_cycles = 0;
+    }
+
+    // Checks the cycles of every instruction against timing.rs from now on, or stops checking
+    pub fn set_timing_check(&mut self, enabled: bool) {
+        if enabled != self.timing_check.is_some() {
+            self.timing_check = if enabled { Some(TimingCheck::new()) } else { None };
+        }
+    }
+
+    pub fn timing_mismatches(&self) -> &[TimingMismatch] {
+        self.timing_check.as_ref().map_or(&[], |check| check.mismatches())
+    }
+
+    // Runs hot ROM code compiled from now on, see jit.rs, or only interprets again
+    #[cfg(feature = "jit")]
+    pub fn set_jit(&mut self, enabled: bool) -> Result<(), JitError> {
+        if enabled && self.jit.is_none() {
+            self.jit = Some(Box::new(Jit::new()?));
+        } else if !enabled {
+            self.jit = None;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "jit"))]
+    pub fn set_jit(&mut self, enabled: bool) -> Result<(), JitError> {
+        if enabled {
+            return Err(JitError("built without the jit feature".to_string()));
+        }
+        Ok(())
+    }
+
+    // Instructions run as compiled blocks so far
+    #[cfg(feature = "jit")]
+    pub fn jit_instructions(&self) -> u64 {
+        self.jit_instructions
+    }
+
+    // Runs the compiled block at pc, if there is one, and returns its cycles and instructions.
+    // Code outside the ROM is left to the interpreter, as is anything the timing check or the
+    // boot ROM is in the way of.
+    #[cfg(feature = "jit")]
+    fn run_jit(&mut self) -> Option<(u32, u32)> {
+        let pc = self.reg.pc;
+        if pc >= 0x8000 || self.fault.is_some() || self.halt_mode || self.timing_check.is_some()
+            || self.interconnect.boot_rom_mapped() {
+            return None;
+        }
+        let jit = self.jit.as_mut()?;
+        let rom_bank = self.interconnect.cart.rom_bank_at(pc);
+        let interconnect = &mut self.interconnect;
+        let block = jit.block(pc, rom_bank, |addr| interconnect.peek(addr))?;
+        let mut regs = JitRegs::from_snapshot(&self.registers());
+        let cycles = block.run(&mut regs);
+        let ime = self.interconnect.interrupts.master_enabled();
+        self.set_registers(regs.to_snapshot(ime));
+        self.jit_instructions += block.instructions() as u64;
+        Some((cycles, block.instructions()))
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn run_jit(&mut self) -> Option<(u32, u32)> {
+        None
+    }
+
+    // The part of cycles the rest of the machine sees. Overclocked, the first cycles the CPU
+    // runs on every scanline are free: the clock stops for them.
+    fn clocked_cycles(&mut self, cycles: u32) -> u32 {
+        if self.overclock == 0 {
+            return cycles;
+        }
+        let free = cycles.min(self.free_cycles);
+        self.free_cycles -= free;
+        let clocked = cycles - free;
+        self.line_cycles += clocked;
+        while self.line_cycles >= LINE_CYCLES {
+            self.line_cycles -= LINE_CYCLES;
+            self.free_cycles = self.overclock;
+        }
+        clocked
+    }
+
+    // For test harnesses: jumping to an RST target or interrupt vector calls trap instead of
+    // running the ROM there, so CALL/RST/RET and interrupt handling can be tested without
+    // writing handlers into a ROM. Pass None to run vectors normally again.
+    pub fn set_vector_trap(&mut self, trap: Option<VectorTrap>) {
+        self.vector_trap = trap;
+    }
+
+    // Keeps a shadow call stack, from the next call on. Reset and loading a state empty it.
+    pub fn track_calls(&mut self, enabled: bool) {
+        self.call_stack = if enabled { Some(self.call_stack.take().unwrap_or_default()) } else { None };
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_ref()
+    }
+
+    // The return address was just pushed
+    fn called(&mut self, kind: CallKind, target: u16, return_to: u16) {
+        if let Some(ref mut call_stack) = self.call_stack {
+            let rom_bank = self.interconnect.cart.rom_bank();
+            call_stack.called(kind, BankedAddr::resolve(target, rom_bank), BankedAddr::resolve(return_to, rom_bank),
+                              self.reg.sp);
+        }
+    }
+
+    // The return address was just popped
+    fn returned(&mut self) {
+        if let Some(ref mut call_stack) = self.call_stack {
+            call_stack.returned(self.reg.sp);
+        }
+    }
+
+    // Where the last step entered code through a vector
+    pub fn last_dispatch(&self) -> Dispatch {
+        self.dispatch
+    }
+
+    // Cycles taken if a trap handled the vector at PC
+    fn run_vector_trap(&mut self) -> Option<u32> {
+        let vector = self.reg.pc;
+        if vector > 0x60 || vector & 0x07 != 0 {
+            return None;
+        }
+        let mut trap = self.vector_trap.take()?;
+        let action = trap(vector, self);
+        if self.vector_trap.is_none() {
+            self.vector_trap = Some(trap);
+        }
+
+        match action {
+            TrapAction::Execute => None,
+            TrapAction::Return => {
+                let pc_change = if vector >= 0x40 { self.reti() } else { self.ret() };
+                match pc_change {
+                    ProgramCounter::Jump(addr, cycles) => {
+                        self.reg.pc = addr;
+                        Some(cycles)
+                    }
+                    ProgramCounter::Next(..) => unreachable!(),
+                }
+            }
+        }
+    }
+
+    // Clears the registers so execution starts at 0x0000, where the boot ROM is mapped
+    pub fn start_from_boot_rom(&mut self) {
+        self.reg = Registers::power_on();
+        self.interconnect.interrupts.set_master_enabled(false);
+    }
+
+    // Starts over as Cpu::new and the boot ROM, if any, would. See Interconnect::reset for what
+    // keep_memory keeps.
+    pub fn reset(&mut self, keep_memory: bool) {
+        self.interconnect.reset(keep_memory);
+        if !keep_memory {
+            self.stack.iter_mut().for_each(|byte| *byte = 0);
+        }
+        self.reg = Registers::new();
+        self.interconnect.interrupts.set_master_enabled(true);
+        self.halt_mode = false;
+        self.stop_mode = false;
+        self.free_cycles = 0;
+        self.line_cycles = 0;
+        self.fault = None;
+        self.recent.clear();
+        if let Some(ref mut call_stack) = self.call_stack {
+            call_stack.clear();
+        }
+        #[cfg(feature = "jit")]
+        {
+            if let Some(ref mut jit) = self.jit {
+                jit.clear();
+            }
+        }
+        if self.interconnect.boot_rom_mapped() {
+            self.start_from_boot_rom();
+        }
+    }
+
+    // Drops what was compiled from the ROM, after it changed under the CPU (see hotload.rs)
+    pub fn rom_changed(&mut self) {
+        #[cfg(feature = "jit")]
+        {
+            if let Some(ref mut jit) = self.jit {
+                jit.clear();
+            }
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.reg.save_state(state);
+        state.write_bytes(&self.stack);
+        state.write_bool(self.halt_mode);
+        state.write_bool(self.stop_mode);
+        state.write_u32(self.free_cycles);
+        state.write_u32(self.line_cycles);
+        self.interconnect.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.reg.load_state(state)?;
+        state.read_into(&mut self.stack, "stack")?;
+        self.halt_mode = state.read_bool()?;
+        self.stop_mode = state.read_bool()?;
+        self.free_cycles = state.read_u32()?;
+        self.line_cycles = state.read_u32()?;
+        self.fault = None;
+        self.recent.clear();
+        if let Some(ref mut call_stack) = self.call_stack {
+            call_stack.clear();
+        }
+        self.interconnect.load_state(state)
+    }
+
+    pub fn fault(&self) -> Option<CpuFault> {
+        self.fault
+    }
+
+    pub fn recent_instructions(&self) -> &RecentInstructions {
+        &self.recent
+    }
+
+    // Keeps the last instructions for crash reports, at a small cost on every one. Pass false
+    // to stop and forget them.
+    pub fn keep_recent_instructions(&mut self, enabled: bool) {
+        self.keep_recent = enabled;
+        self.recent.clear();
+    }
+
+    // For states from elsewhere, see bess.rs
+    pub fn set_registers(&mut self, regs: RegisterSnapshot) {
+        self.reg.a = regs.a;
+        self.reg.f = regs.f & F_MASK;
+        self.write_to_r16(BC_ID, regs.bc());
+        self.write_to_r16(DE_ID, regs.de());
+        self.write_to_r16(HL_ID, regs.hl());
+        self.reg.sp = regs.sp;
+        self.reg.pc = regs.pc;
+        self.interconnect.interrupts.set_master_enabled(regs.ime);
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halt_mode = halted;
+    }
+
+    pub fn registers(&self) -> RegisterSnapshot {
+        RegisterSnapshot {
+            a: self.reg.a,
+            f: self.reg.f,
+            b: self.reg.b,
+            c: self.reg.c,
+            d: self.reg.d,
+            e: self.reg.e,
+            h: self.reg.h,
+            l: self.reg.l,
+            sp: self.reg.sp,
+            pc: self.reg.pc,
+            ime: self.interconnect.interrupts.master_enabled(),
         }
     }
 
     pub fn step(&mut self, video_sink: &mut dyn VideoSink) -> u32 {
         // elapsed_cycles calculates how many cycles are spent carrying out the instruction and
         // corresponding interrupt (if produced) = time to execute + time to handle interrupt
-//         println!("
-// ======================
-// current pc: 0x{:x}", self.reg.pc);
         //thread::sleep(time::Duration::from_millis(1));
+        self.interconnect.set_instruction_pc(self.reg.pc);
+        self.dispatch = Dispatch::default();
+        // Overclocked cycles are handed out after the instruction, so bus accesses are not timed
+        if self.overclock == 0 {
+            self.interconnect.begin_instruction();
+        }
+        let mut instructions = 1;
         let elapsed_cycles = {
-            self.execute_opcode() + self.handle_interrupt() 
+            let executed = match self.run_vector_trap() {
+                Some(cycles) => cycles,
+                None => match self.run_jit() {
+                    Some((cycles, block_instructions)) => {
+                        instructions = block_instructions;
+                        cycles
+                    }
+                    None => self.execute_opcode(),
+                },
+            };
+            executed + self.handle_interrupt()
         };
-        self.interconnect.cycle_flush(elapsed_cycles, video_sink);
+        let clocked = self.clocked_cycles(elapsed_cycles);
+        self.interconnect.end_instruction(clocked, video_sink);
+        self.interconnect.stats.cycles += elapsed_cycles as u64;
+        self.interconnect.stats.instructions += instructions as u64;
         
         elapsed_cycles        
     }
 
-    // Implement how to handle interrupts, depending on registers IME, IF, IE
+    // Takes the pending interrupt, if IME allows, see interrupts.rs
     pub fn handle_interrupt(&mut self) -> u32 {
-        // int_flags(IF) indicate the interrupt signals requested.
-        // int_enable(IE) indicate which I/O device can send interrupt.
-        // all_ints: I/O devices with enabled interrupt AND sending signal.
-        let all_ints = self.interconnect.int_flags & self.interconnect.int_enable;
+        if self.fault.is_some() {
+            return 0;
+        }
+        let pending = self.interconnect.interrupts.pending();
         // if in halt mode: Any interrupt will cause program to continue. If no interrupt,no change
         if self.halt_mode {
-            self.halt_mode = all_ints == 0;
+            self.halt_mode = pending.is_none();
         }
 
-        // Either: ime = false which means ALL interrupts are disabled OR none of I/O devices
-        // requested / are allowed to request interrupt 
-        if !self.reg.ime || all_ints == 0 {
-            return 0;
-        }
-        
-        // all_ints.trailing_zeros():
-        // identify the first interrupt bit requested. Choose hardware to handle accordingly.
-        let interrupt_bit = all_ints.trailing_zeros();
-        let int_hardware = match interrupt_bit {
-            0 => 0x40,  // VBlank
-            1 => 0x48,  // LCDCStat
-            2 => 0x50,  // Timer Overflow
-            3 => 0x58,  // Serial Transfer Complete
-            4 => 0x60,  // P10-P13 Input Signal
-            _ => panic!("Invalid interrupt! {:x}", interrupt_bit),
+        let interrupt = match pending {
+            Some(interrupt) if self.interconnect.interrupts.master_enabled() => interrupt,
+            _ => return 0,
         };
         
-        // After handling request, reset correspoding bit
-        self.interconnect.int_flags &= 0xff << (interrupt_bit + 1);
-        // reset ime
-        self.reg.ime = false;
+        self.interconnect.stats.interrupts[interrupt.bit() as usize] += 1;
+        self.interconnect.interrupts.acknowledge(interrupt);
+        self.interconnect.log_event(Event::Interrupt(interrupt));
 
         let pc = self.reg.pc;
+        debug!(target: "gbrust::cpu", "{:?} interrupt taken at {}, jumping to 0x{:02x}", interrupt,
+               BankedAddr::resolve(pc, self.interconnect.cart.rom_bank()), interrupt.vector());
         self.push_u16(pc);
-        self.reg.pc = int_hardware as u16;
+        self.reg.pc = interrupt.vector();
+        self.dispatch.interrupt = Some(interrupt);
+        self.called(CallKind::Interrupt(interrupt), interrupt.vector(), pc);
 
         20 // y tho, in PanDoc says 5 machine cycles. TODO: confirm this
     }
 
     pub fn execute_opcode(&mut self) -> u32 {
+        if self.fault.is_some() {
+            return 1;
+        }
+        if self.keep_recent {
+            self.recent.record(self.registers(), self.interconnect.cart.rom_bank());
+        }
         let opcode: u8 = self.interconnect.read(self.reg.pc);
+        let flags = self.reg.f; // as the instruction starts, for the timing check
+        self.dispatch.debug = DebugOpcode::from_opcode(opcode);
         
         let is_aa0: bool = (opcode & 0b0000_1000) == 0; 
         let is_0bb: bool = (opcode & 0b0010_0000) == 0;  
@@ -181,7 +632,8 @@ impl Cpu {
             is_0bb,
         );
 
-        //println!("opcode: 0x{:x}", opcode);
+        trace!(target: "gbrust::cpu", "{}: opcode 0x{:02x}",
+               BankedAddr::resolve(self.reg.pc, self.interconnect.cart.rom_bank()), opcode);
 
         let pc_change = match parts {
             // opcodes starting with 00
@@ -275,10 +727,19 @@ impl Cpu {
             (0b11, _, 0b000, _, true) => self.ret_cc(),   // 0cc
             (0b11, _, 0b111, _, _) => self.rst_n(), 
             
-            // The rest: panik
-            _ => panic!("No such opcode: 0b{:b}", opcode),
+            // The rest do not exist
+            _ => return self.lock_up(opcode),
         };
         
+        if let Some(ref mut check) = self.timing_check {
+            let cycles = match pc_change {
+                ProgramCounter::Next(_, cycles) | ProgramCounter::Jump(_, cycles) => cycles,
+            };
+            let suffix = if opcode == 0xCB { self.interconnect.peek(self.reg.pc.wrapping_add(1)) } else { 0 };
+            let at = BankedAddr::resolve(self.reg.pc, self.interconnect.cart.rom_bank());
+            check.check(opcode, suffix, flags, cycles, at);
+        }
+
         let cycles_taken: u32 = match pc_change {
             ProgramCounter::Next(bytes, cycles) => {
                 let offset: u16;
@@ -289,7 +750,6 @@ impl Cpu {
                     offset = bytes as u16;
                     self.reg.pc = self.reg.pc.wrapping_add(offset);
                 }
-                //println!("Next pc is: {:x}", self.reg.pc);
                 cycles
             },
             ProgramCounter::Jump(addr, cycles) => {
@@ -302,6 +762,16 @@ impl Cpu {
 
     }
 
+    fn lock_up(&mut self, opcode: u8) -> u32 {
+        let fault = CpuFault::IllegalOpcode {
+            at: BankedAddr::resolve(self.reg.pc, self.interconnect.cart.rom_bank()),
+            opcode,
+        };
+        error!(target: "gbrust::cpu", "CPU locked up: {}", fault);
+        self.fault = Some(fault);
+        1
+    }
+
     pub fn execute_bc(&mut self, pc_current: u16) -> ProgramCounter {
         let suffix = self.interconnect.read(pc_current + 1);
         let parts = (
@@ -345,11 +815,6 @@ impl Cpu {
     /// write_to_r8: write content to appropriate 8-bit register based on register ID.
     /// @param r8_id: ID of register
     /// @param content: content to write to register
-    /// # Examples
-    ///
-    /// ```
-    /// assert_eq!(1, 0);
-    /// ```
     pub fn write_to_r8(&mut self, r8_id: u8, content: u8) {
         match r8_id {
             A_ID => self.reg.a = content,
@@ -422,7 +887,6 @@ impl Cpu {
 
     /// get_n: gets 8-bit immediate n right after opcode
     pub fn get_n(&mut self) -> u8 {
-        //println!("immediate = 0x{:x}", self.interconnect.read(self.reg.pc + 1));
         self.interconnect.read(self.reg.pc + 1)
     }
 
@@ -473,31 +937,23 @@ impl Cpu {
 
         match r16_id {
             BC_ID => {
-                //println!("===== Old value of bc: 0x{:x}", self.reg.bc);
                 self.reg.bc = content;
                 self.reg.b = msb;
                 self.reg.c = lsb;
-                //println!("New value: 0x{:x}", self.reg.bc);
             },
             DE_ID => {
-                //println!("===== Old value of de: 0x{:x}", self.reg.de);
                 self.reg.de = content;
                 self.reg.d = msb;
                 self.reg.e = lsb;
-                //println!("New value: 0x{:x}", self.reg.de);
             },
             HL_ID => {
-                //println!("===== Old value of hl: 0x{:x}", self.reg.hl);
                 self.reg.hl = content;
                 self.reg.h = msb;
                 self.reg.l = lsb;
-                //println!("New value: 0x{:x}", self.reg.hl);
             },
             AF_ID => {
-                //println!("===== Old value of af: 0x{:x}", (self.reg.a as u16) << 8 | self.reg.f as u16);
                 self.reg.a = msb;
-                self.reg.f = lsb;
-                //println!("New value: 0x{:x}", (self.reg.a as u16) << 8 | self.reg.f as u16);
+                self.reg.f = lsb & F_MASK;
 
             },
             _ => panic!("Invalid register"),
@@ -568,7 +1024,7 @@ impl Cpu {
     // Reusable code for 8-bit Rotate, Shift instructions
     
     pub fn set_flag(&mut self, flag: u8) {
-        self.reg.f = self.reg.f | flag;
+        self.reg.f = (self.reg.f | flag) & F_MASK;
     }
 
     pub fn reset_flag(&mut self, flag: u8) {
@@ -1656,6 +2112,7 @@ impl Cpu {
 		// reading
 	    let idx: u8 = (self.get_r8_to() & 0b110) >> 1;
 	    let r: u16 = self.read_from_r16(idx).unwrap();
+	    self.interconnect.idu_access(r);
 
 	    // processing
 	    let res: u16 = if r == std::u16::MAX {0} else {r + 1};
@@ -1669,6 +2126,7 @@ impl Cpu {
 		// reading
 	    let idx: u8 = (self.get_r8_to() & 0b110) >> 1;
 	    let r: u16 = self.read_from_r16(idx).unwrap();
+	    self.interconnect.idu_access(r);
 
 	    // processing
 	    let res: u16 = if r == 0 {std::u16::MAX} else {r - 1};
@@ -2130,6 +2588,7 @@ impl Cpu {
     pub fn call_nn(&mut self) -> ProgramCounter {
         let nn = self.get_nn();
         self.push_u16(self.reg.pc + 3); // Push NEXT PC (the one after calling call_nn) onto the stack
+        self.called(CallKind::Call, nn, self.reg.pc + 3);
         
         ProgramCounter::Jump(nn, 6)
     }
@@ -2145,6 +2604,7 @@ impl Cpu {
 
         if cc { // execute function call
             self.push_u16(self.reg.pc + 3);
+            self.called(CallKind::Call, nn, self.reg.pc + 3);
             pc_final = ProgramCounter::Jump(nn, 6);
         } else {
             pc_final = ProgramCounter::Next(3, 3);
@@ -2157,6 +2617,7 @@ impl Cpu {
     /// 1 byte, 4 cycles.
     pub fn ret(&mut self) -> ProgramCounter {
         let pop_val = self.pop_u16();
+        self.returned();
 
         ProgramCounter::Jump(pop_val, 4)
     }
@@ -2170,6 +2631,7 @@ impl Cpu {
 
         if cc {
             let pop_val = self.pop_u16();
+            self.returned();
             pc_final = ProgramCounter::Jump(pop_val, 5);
         } else {
             pc_final = ProgramCounter::Next(1, 2);
@@ -2183,7 +2645,8 @@ impl Cpu {
     /// same as ret, but set register IME.
     pub fn reti(&mut self) -> ProgramCounter {
         let pop_val = self.pop_u16();
-        self.reg.ime = true;
+        self.returned();
+        self.interconnect.interrupts.set_master_enabled(true);
 
         ProgramCounter::Jump(pop_val, 4)
     }
@@ -2213,6 +2676,8 @@ impl Cpu {
         }
 
         let addr = (pc_msb << 8) | pc_lsb;
+        self.dispatch.rst = Some(addr);
+        self.called(CallKind::Rst, addr, self.reg.pc + 1);
 
         ProgramCounter::Jump(addr, 4)
     }
@@ -2239,7 +2704,7 @@ impl Cpu {
     /// EI instruction if any.
     /// 1 byte, 1 cycle
     pub fn di(&mut self) -> ProgramCounter {
-        self.reg.ime = false;
+        self.interconnect.interrupts.set_master_enabled(false);
 
         ProgramCounter::Next(1, 1)
     }
@@ -2247,7 +2712,7 @@ impl Cpu {
     /// ei: schedules interrupt handling to be enabled THE NEXT MACHINE CYCLE
     /// 1 byte, 1 cycle + 1 cycle for EI effect.
     pub fn ei(&mut self) -> ProgramCounter {
-        self.reg.ime = true;
+        self.interconnect.interrupts.set_master_enabled(true);
 
         ProgramCounter::Next(1, 1)
     }
@@ -2344,6 +2809,7 @@ impl Cpu {
 mod tests {
     use super::*;
     use crate::dmg::cart::Cart;
+    use crate::dmg::interrupts::Interrupt;
 
     const AF_DEF: u16 = 0x01B0;
     const BC_DEF: u16 = 0x0013;
@@ -2358,12 +2824,14 @@ mod tests {
 
     fn set_up_cpu() -> Cpu {
         
-        let mut cpu = Cpu::new(Interconnect::new(Cart::new(vec![0; 36452].into_boxed_slice(), Some(vec![0; 65532].into_boxed_slice()))));
+        let mut cpu = Cpu::new(Interconnect::new(Cart::new(vec![0; 36452].into_boxed_slice(), Some(vec![0; 65532].into_boxed_slice())).unwrap()));
 
         cpu.write_to_r16(BC_ID, BC_DEF); // will write to B and C also
         cpu.write_to_r16(DE_ID, DE_DEF);
         cpu.interconnect.write(cpu.reg.hl, MEM_HL_DEF);
         cpu.interconnect.write(cpu.reg.de, MEM_DE_DEF);
+        // Run from work RAM, writes to ROM do not stick
+        cpu.reg.pc = 0xC000;
         
         cpu
     }
@@ -2403,7 +2871,7 @@ mod tests {
         let original_sp = cpu.reg.sp;
         
         set_1byte_op(&mut cpu, 0b11_000_101 | (AF_ID << 4)); // push AF
-        assert_eq!(cpu.interconnect.read(cpu.reg.pc), 0b11_000_101);
+        assert_eq!(cpu.interconnect.read(cpu.reg.pc), 0b11_000_101 | (AF_ID << 4));
         cpu.execute_opcode(); // Stack: AF,          SP: 0xFFFC
         assert_eq!(cpu.reg.sp, original_sp - 2);
         set_1byte_op(&mut cpu, 0b11_000_101 | (BC_ID << 4)); // push BC
@@ -2414,8 +2882,8 @@ mod tests {
         assert_eq!(cpu.reg.sp, 0xFFF8);
 
         set_1byte_op(&mut cpu, 0b11_000_001 | (AF_ID << 4)); // pop AF
-        cpu.execute_opcode(); // cpu.reg.af = original_de
-        assert_eq!(read_af(&cpu), original_de);
+        cpu.execute_opcode(); // cpu.reg.af = original_de, without F's low nibble
+        assert_eq!(read_af(&cpu), original_de & 0xFFF0);
         set_1byte_op(&mut cpu, 0b11_000_001 | (DE_ID << 4)); // pop DE
         cpu.execute_opcode(); // cpu.reg.de = original_bc
         assert_eq!(cpu.reg.de, original_bc);
@@ -2425,4 +2893,124 @@ mod tests {
         
     }
 
+    #[test]
+    fn test_vector_traps() {
+        use std::sync::{Arc, Mutex};
+        use super::super::console::NoVideo;
+
+        let mut cpu = set_up_cpu();
+        let hits = Arc::new(Mutex::new(Vec::new()));
+        let trap_hits = hits.clone();
+        cpu.set_vector_trap(Some(Box::new(move |vector, cpu: &mut Cpu| {
+            let regs = cpu.registers();
+            trap_hits.lock().unwrap().push((vector, regs.sp));
+            TrapAction::Return
+        })));
+
+        set_1byte_op(&mut cpu, 0xEF); // rst 0x28
+        let sp = cpu.reg.sp;
+        cpu.step(&mut NoVideo);
+        assert_eq!(cpu.reg.pc, 0x28);
+        cpu.step(&mut NoVideo);
+        assert_eq!(cpu.reg.pc, 0xC001);
+        assert_eq!(cpu.reg.sp, sp);
+
+        // VBlank interrupt: the trap returns with RETI, turning interrupts back on
+        cpu.interconnect.interrupts.set_master_enabled(true);
+        cpu.interconnect.interrupts.set_enable(0x01);
+        cpu.interconnect.interrupts.request(Interrupt::VBlank);
+        set_1byte_op(&mut cpu, 0x00); // nop
+        cpu.step(&mut NoVideo);
+        assert_eq!(cpu.reg.pc, 0x40);
+        cpu.step(&mut NoVideo);
+        assert_eq!(cpu.reg.pc, 0xC002);
+        assert!(cpu.interconnect.interrupts.master_enabled());
+
+        assert_eq!(*hits.lock().unwrap(), [(0x28, sp - 2), (0x40, sp - 2)]);
+    }
+
+    #[test]
+    fn test_injected_interrupts_wake_halt_by_priority() {
+        use super::super::console::NoVideo;
+        use super::super::interrupts::InterruptScript;
+
+        // 0x76 does not decode to halt yet, so halt mode is set by hand and the CPU runs nops
+        // from work RAM while it waits.
+        // Serial and timer at once: both wake the CPU, the timer is taken first
+        let mut cpu = set_up_cpu();
+        let script = InterruptScript::new()
+            .silence_all()
+            .inject(200, Interrupt::Serial)
+            .inject(200, Interrupt::Timer);
+        cpu.interconnect.interrupts.set_script(Some(script));
+        cpu.interconnect.interrupts.set_master_enabled(true);
+        cpu.interconnect.interrupts.set_enable(Interrupt::Timer.mask() | Interrupt::Serial.mask());
+        cpu.interconnect.interrupts.set_flags(0);
+        cpu.halt_mode = true;
+        while cpu.halt_mode {
+            assert!(cpu.interconnect.interrupts.script().unwrap().cycle() < 300);
+            cpu.step(&mut NoVideo);
+        }
+        assert!(cpu.interconnect.interrupts.script().unwrap().cycle() >= 200);
+        assert_eq!(cpu.reg.pc, Interrupt::Timer.vector());
+        assert_eq!(cpu.interconnect.interrupts.flags(), Interrupt::Serial.mask());
+
+        // With IME off halt mode still ends, without taking anything
+        let mut cpu = set_up_cpu();
+        let script = InterruptScript::new().silence_all().inject(40, Interrupt::VBlank);
+        cpu.interconnect.interrupts.set_script(Some(script));
+        cpu.interconnect.interrupts.set_master_enabled(false);
+        cpu.interconnect.interrupts.set_enable(Interrupt::VBlank.mask());
+        cpu.interconnect.interrupts.set_flags(0);
+        cpu.halt_mode = true;
+        while cpu.halt_mode {
+            assert!(cpu.interconnect.interrupts.script().unwrap().cycle() < 100);
+            cpu.step(&mut NoVideo);
+        }
+        assert!((0xC000..0xC100).contains(&cpu.reg.pc));
+        assert_eq!(cpu.interconnect.interrupts.script().unwrap().raised(), [(40, Interrupt::VBlank)]);
+    }
+
+    #[test]
+    fn test_cycle_accurate_reads_on_their_cycle() {
+        use super::super::config::AccuracyLevel;
+        use super::super::console::NoVideo;
+        use super::super::interrupts::InterruptScript;
+
+        // ldh a, (0x0f) reads IF on its third cycle, after a VBlank raised on that cycle
+        let if_read = |accuracy: AccuracyLevel, raised_at: u64| -> u8 {
+            let mut cpu = set_up_cpu();
+            cpu.interconnect.set_accuracy(accuracy);
+            let script = InterruptScript::new().silence_all().inject(raised_at, Interrupt::VBlank);
+            cpu.interconnect.interrupts.set_script(Some(script));
+            cpu.interconnect.interrupts.set_enable(0);
+            cpu.interconnect.interrupts.set_flags(0);
+            set_2byte_op(&mut cpu, 0xF00F);
+            cpu.step(&mut NoVideo);
+            assert_eq!(cpu.interconnect.interrupts.script().unwrap().cycle(), 3);
+            cpu.reg.a & Interrupt::VBlank.mask()
+        };
+        assert_ne!(if_read(AccuracyLevel::CycleAccurate, 3), 0);
+        assert_eq!(if_read(AccuracyLevel::CycleAccurate, 4), 0);
+        assert_eq!(if_read(AccuracyLevel::Balanced, 1), 0);
+    }
+
+    #[test]
+    fn test_push_pop_af_masks_flags() {
+        let mut cpu = set_up_cpu();
+        cpu.write_to_r16(BC_ID, 0x34FF);
+
+        set_1byte_op(&mut cpu, 0b11_000_101 | (BC_ID << 4)); // push BC
+        cpu.execute_opcode();
+        set_1byte_op(&mut cpu, 0b11_000_001 | (AF_ID << 4)); // pop AF
+        cpu.execute_opcode();
+        assert_eq!(read_af(&cpu), 0x34F0);
+
+        set_1byte_op(&mut cpu, 0b11_000_101 | (AF_ID << 4)); // push AF
+        cpu.execute_opcode();
+        set_1byte_op(&mut cpu, 0b11_000_001 | (DE_ID << 4)); // pop DE
+        cpu.execute_opcode();
+        assert_eq!(cpu.reg.de, 0x34F0);
+    }
+
 }
@@ -0,0 +1,215 @@
+// Errors surfaced to frontends instead of panicking.
+// ConfigError: the settings file cannot be read or written.
+// CartError: the ROM (or what came with it) cannot be turned into a working console. Returned
+//            when loading, so a frontend can show a dialog instead of crashing.
+// PatchError: an IPS/BPS patch cannot be applied to the ROM, see patch.rs.
+// RomDbError: a No-Intro DAT cannot be read, see romdb.rs.
+// Disconnected: a ConsoleHandle outlived its console, see remote.rs.
+// NetplayError: a netplay session broke off, or the two consoles stopped agreeing, see
+//               netplay.rs.
+// MovieError: an input movie cannot be read or written, see movie.rs.
+// PeripheralError: a script for a link port device cannot be read, see peripheral.rs.
+// SymbolError: a .sym file of labels for the debugger cannot be read, see symbols.rs.
+// SaveFileError: a battery save cannot be read or written, see sav.rs.
+// StateImportError: another emulator's save state cannot be imported, see bess.rs.
+// TraceParseError: a frame trace from another build is malformed, see compare.rs.
+// BusError: the running game touched something that does not exist, e.g. a ROM bank past the
+//           end of the ROM. The bus logs each kind once and carries on with 0xFF, like an
+//           open bus would.
+// RunFailure: why a console in resilient mode gave up, see Console::set_resilient.
+// JitError: the recompiler cannot run, because the build leaves it out or the host is not one
+//           Cranelift supports, see jit.rs.
+
+use std::io;
+use thiserror::Error;
+
+use super::dmg_cpu::CpuFault;
+use super::state::StateError;
+
+#[derive(Debug, Error)]
+pub enum CartError {
+    #[error("no ROM given")]
+    NoRom,
+    #[error("ROM is {0} bytes, too small to hold a cartridge header")]
+    TooSmall(usize),
+    #[error("unsupported cartridge type 0x{0:02x}")]
+    UnsupportedMbc(u8),
+    #[error("invalid ROM size code 0x{0:02x} in header")]
+    InvalidRomSize(u8),
+    #[error("invalid RAM size code 0x{0:02x} in header")]
+    InvalidRamSize(u8),
+    #[error("save RAM is {actual} bytes, cartridge expects {expected}")]
+    RamSizeMismatch { expected: usize, actual: usize },
+    #[error("cart override asks for {0} bytes of RAM, which no cartridge has")]
+    InvalidRamOverride(u32),
+    #[error("boot ROM is {0} bytes, expected 256")]
+    InvalidBootRom(usize),
+    #[error("ROM has no bank {0}")]
+    NoSuchBank(usize),
+    #[error("{0} bytes do not fit in a ROM bank")]
+    BankOverflow(usize),
+    #[error("invalid GBS file: {0}")]
+    InvalidGbs(&'static str),
+    #[error("could not patch ROM: {0}")]
+    Patch(#[from] PatchError),
+    #[error("could not read save: {0}")]
+    Save(#[from] SaveFileError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Error, Copy, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    #[error("not an IPS or BPS patch")]
+    UnknownFormat,
+    #[error("patch is truncated")]
+    Truncated,
+    #[error("patch points outside the ROM")]
+    OutOfRange,
+    #[error("patch was made for a different ROM")]
+    WrongSource,
+    #[error("patch or patched ROM fails its checksum")]
+    BadChecksum,
+    #[error("patched ROM would be {0} bytes, more than any cartridge holds")]
+    TooLarge(usize),
+}
+
+#[derive(Debug, Error)]
+pub enum RomDbError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid DAT file: {0}")]
+    Parse(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("could not write config: {0}")]
+    Serialize(#[from] toml::ser::Error),
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("invalid watch expression \"{0}\", expected a register (a, hl, sp...) or an address (0xc000, 0xc000:16)")]
+pub struct WatchParseError(pub String);
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("invalid address \"{0}\", expected bank:address (12:4abc) or an address (c000)")]
+pub struct BankedAddrParseError(pub String);
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("invalid break condition \"{0}\", expected irq, irq:48, rst:38 or ldbb")]
+pub struct BreakOnParseError(pub String);
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("invalid speed \"{0}\", expected 25% - 400%, as 50%, 2x or 0.5")]
+pub struct SpeedParseError(pub String);
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("invalid GameShark code \"{0}\", expected 8 hex digits (01ff47c1)")]
+pub struct GameSharkError(pub String);
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("JIT unavailable: {0}")]
+pub struct JitError(pub String);
+
+#[derive(Debug, Error, Copy, Clone, PartialEq, Eq)]
+#[error("the console is gone")]
+pub struct Disconnected;
+
+#[derive(Debug, Error)]
+pub enum NetplayError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("peer sent an invalid message")]
+    Protocol,
+    #[error("could not load the host's state: {0}")]
+    State(#[from] StateError),
+    #[error("the consoles went out of sync by frame {0}")]
+    Desync(u64),
+}
+
+#[derive(Debug, Error)]
+pub enum MovieError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid movie, line {line}: {reason}")]
+    Parse { line: usize, reason: String },
+}
+
+#[derive(Debug, Error)]
+pub enum PeripheralError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid peripheral script, line {line}: {reason}")]
+    Parse { line: usize, reason: String },
+}
+
+#[derive(Debug, Error)]
+pub enum SymbolError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid symbol file, line {line}: expected \"bank:addr name\"")]
+    Parse { line: usize },
+}
+
+#[derive(Debug, Error)]
+pub enum SaveFileError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("save is {0} bytes, which is neither RAM nor RAM and an RTC footer")]
+    BadLength(usize),
+}
+
+#[derive(Debug, Error)]
+pub enum AutosaveError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("the autosave does not load: {0}")]
+    State(#[from] StateError),
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum StateImportError {
+    #[error("not a BESS save state (SameBoy writes them, BGB's own format is not supported)")]
+    NotBess,
+    #[error("save state is truncated")]
+    Truncated,
+    #[error("save state has no CORE block")]
+    NoCore,
+    #[error("BESS version {0} is not supported")]
+    UnsupportedVersion(u16),
+    #[error("save state is from a \"{0}\" model, gbrust only runs the original Game Boy")]
+    UnsupportedModel(String),
+    #[error("save state is for a different ROM")]
+    WrongRom,
+}
+
+#[derive(Debug, Error, Copy, Clone, PartialEq, Eq)]
+#[error("invalid trace, line {0}")]
+pub struct TraceParseError(pub usize);
+
+#[derive(Debug, Error, Copy, Clone, PartialEq, Eq)]
+pub enum BusError {
+    #[error("read from 0x{addr:04x} is past the end of the ROM (offset 0x{offset:x})")]
+    RomOutOfRange { addr: u16, offset: usize },
+    #[error("access to 0x{addr:04x} but the cartridge has no RAM there")]
+    NoExternalRam { addr: u16 },
+    #[error("RAM bank 0x{0:02x} does not exist")]
+    InvalidRamBank(u8),
+    #[error("write of 0x{val:02x} to 0x{addr:04x} is not handled by the cartridge")]
+    UnhandledCartWrite { addr: u16, val: u8 },
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum RunFailure {
+    #[error("{0}")]
+    Fault(CpuFault),
+    #[error("{0}")]
+    Bus(BusError),
+    #[error("emulator panicked: {0}")]
+    Panic(String),
+}
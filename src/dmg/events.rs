@@ -0,0 +1,102 @@
+// Frame event log.
+// For research tools that study how a game behaves without changing the emulator: while
+// Console::set_event_log has a writer, every frame ends with one JSON line of what happened
+// during it, in order, so line n is frame n:
+//
+//     {"frame":120,"events":[{"cycle":8423590,"ly":144,"type":"interrupt","name":"vblank"}]}
+//
+// cycle is the PPU clock (cycles since power on) and ly the line it was on. The events:
+//
+//     interrupt   taken by the CPU, "name" one of vblank, stat, timer, serial, joypad
+//     dma         an OAM DMA from "source" (hex)
+//     rom_bank    the game mapped another "bank" at 0x4000
+//     serial      a byte "sent" and one "received" (hex), on either clock
+//     input       the buttons "held" changed, as names
+//
+// Input is seen as the game would, so buttons pressed between frames show up at the start of the
+// next one.
+
+use std::fmt::Write;
+
+use super::console::Button;
+use super::interrupts::Interrupt;
+
+const INTERRUPTS: [&str; 5] = ["vblank", "stat", "timer", "serial", "joypad"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    Interrupt(Interrupt),
+    Dma { source: u16 },
+    RomBank(usize),
+    Serial { sent: u8, received: u8 },
+    Input(u8), // by Button::mask
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimedEvent {
+    pub cycle: u64,
+    pub ly: u8,
+    pub event: Event,
+}
+
+impl TimedEvent {
+    fn write_json(&self, json: &mut String) {
+        write!(json, "{{\"cycle\":{},\"ly\":{},", self.cycle, self.ly).unwrap();
+        match self.event {
+            Event::Interrupt(interrupt) => {
+                write!(json, "\"type\":\"interrupt\",\"name\":\"{}\"", INTERRUPTS[interrupt.bit() as usize])
+            }
+            Event::Dma { source } => write!(json, "\"type\":\"dma\",\"source\":\"{:04x}\"", source),
+            Event::RomBank(bank) => write!(json, "\"type\":\"rom_bank\",\"bank\":{}", bank),
+            Event::Serial { sent, received } => {
+                write!(json, "\"type\":\"serial\",\"sent\":\"{:02x}\",\"received\":\"{:02x}\"", sent, received)
+            }
+            Event::Input(held) => {
+                let names: Vec<String> = Button::ALL.iter()
+                    .filter(|button| held & button.mask() != 0)
+                    .map(|button| format!("\"{}\"", button.name()))
+                    .collect();
+                write!(json, "\"type\":\"input\",\"held\":[{}]", names.join(","))
+            }
+        }.unwrap();
+        json.push('}');
+    }
+}
+
+// A frame's line, without the newline
+pub fn frame_json(frame: u64, events: &[TimedEvent]) -> String {
+    let mut json = format!("{{\"frame\":{},\"events\":[", frame);
+    for (i, event) in events.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        event.write_json(&mut json);
+    }
+    json.push_str("]}");
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_a_line_per_frame() {
+        let at = |event| TimedEvent { cycle: 10, ly: 2, event };
+        let events = [
+            at(Event::Interrupt(Interrupt::LcdStat)),
+            at(Event::Dma { source: 0xC100 }),
+            at(Event::RomBank(3)),
+            at(Event::Serial { sent: 0x42, received: 0xFF }),
+            at(Event::Input(Button::A.mask() | Button::Start.mask())),
+        ];
+        assert_eq!(frame_json(7, &[]), "{\"frame\":7,\"events\":[]}");
+        assert_eq!(frame_json(7, &events),
+                   "{\"frame\":7,\"events\":[\
+                    {\"cycle\":10,\"ly\":2,\"type\":\"interrupt\",\"name\":\"stat\"},\
+                    {\"cycle\":10,\"ly\":2,\"type\":\"dma\",\"source\":\"c100\"},\
+                    {\"cycle\":10,\"ly\":2,\"type\":\"rom_bank\",\"bank\":3},\
+                    {\"cycle\":10,\"ly\":2,\"type\":\"serial\",\"sent\":\"42\",\"received\":\"ff\"},\
+                    {\"cycle\":10,\"ly\":2,\"type\":\"input\",\"held\":[\"a\",\"start\"]}]}");
+    }
+}
@@ -0,0 +1,206 @@
+// Many consoles at once, for automated testing of ROM hacks and the like.
+// A Farm runs a batch of jobs, each a ROM, a movie of the buttons to press and how many frames to
+// run, on a pool of threads. Every job gets a console of its own built from the farm's config,
+// and comes back with a FrameRecord (picture CRC and registers, see compare.rs) for every frame
+// and the values of the farm's probes, watch expressions (see watch.rs) read at the end of every
+// frame:
+//
+//     let runs = Farm::new()
+//         .threads(8)
+//         .probe("0xc0a0".parse()?)
+//         .run(jobs);
+//
+// Results come back in the order the jobs went in, whatever order they finished in. The core is
+// deterministic, so the same job gives the same results on any thread.
+//
+// For batches of ROMs that may be broken, Farm::resilient runs every console resilient (see
+// Console::set_resilient): a job whose game locks up, touches what is not there or panics the
+// emulator stops there, with the frames it got through and why it failed, and the rest carry on.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+
+use super::compare::{self, FrameRecord};
+use super::config::EmuConfig;
+use super::console::Console;
+use super::error::{CartError, RunFailure};
+use super::movie::Movie;
+use super::watch::WatchExpr;
+
+#[derive(Debug, Clone)]
+pub struct FarmJob {
+    pub name: String,
+    pub rom: Arc<[u8]>, // shared, for jobs that run one ROM with different inputs
+    pub movie: Movie,
+    pub frames: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FarmRun {
+    pub name: String,
+    pub frames: Vec<FrameRecord>,
+    pub probes: Vec<Vec<u16>>, // by frame, then in the order the probes were added
+    pub failure: Option<RunFailure>, // why a resilient run stopped short
+}
+
+pub struct Farm {
+    threads: usize,
+    probes: Vec<WatchExpr>,
+    config: EmuConfig,
+    resilient: bool,
+}
+
+impl Default for Farm {
+    fn default() -> Self {
+        Farm::new()
+    }
+}
+
+impl Farm {
+    // As many threads as the machine has cores
+    pub fn new() -> Farm {
+        Farm {
+            threads: thread::available_parallelism().map_or(1, |cores| cores.get()),
+            probes: Vec::new(),
+            config: EmuConfig::default(),
+            resilient: false,
+        }
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn probe(mut self, expr: WatchExpr) -> Self {
+        self.probes.push(expr);
+        self
+    }
+
+    pub fn config(mut self, config: EmuConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn resilient(mut self, enabled: bool) -> Self {
+        self.resilient = enabled;
+        self
+    }
+
+    // Runs every job, and returns what each did, or why its ROM would not load, in job order
+    pub fn run(&self, jobs: Vec<FarmJob>) -> Vec<Result<FarmRun, CartError>> {
+        let next = AtomicUsize::new(0);
+        let (results, finished) = channel();
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(jobs.len()) {
+                let results = results.clone();
+                let (jobs, next) = (&jobs, &next);
+                scope.spawn(move || {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        let job = match jobs.get(index) {
+                            Some(job) => job,
+                            None => break,
+                        };
+                        let _ = results.send((index, self.run_job(job)));
+                    }
+                });
+            }
+        });
+        drop(results);
+        let mut runs: Vec<Option<Result<FarmRun, CartError>>> = jobs.iter().map(|_| None).collect();
+        for (index, run) in finished {
+            runs[index] = Some(run);
+        }
+        runs.into_iter().map(Option::unwrap).collect()
+    }
+
+    fn run_job(&self, job: &FarmJob) -> Result<FarmRun, CartError> {
+        let mut console = Console::builder()
+            .rom(job.rom.to_vec().into_boxed_slice())
+            .config(self.config.clone())
+            .resilient(self.resilient)
+            .build()?;
+        for &probe in &self.probes {
+            console.watch(probe);
+        }
+        let mut run = FarmRun { name: job.name.clone(), frames: Vec::new(), probes: Vec::new(), failure: None };
+        for frame in 0..job.frames {
+            let record = compare::run_frame(&mut console, &job.movie, frame);
+            if let Some(failure) = console.failure() {
+                run.failure = Some(failure.clone());
+                break;
+            }
+            run.frames.push(record);
+            run.probes.push(console.watches().values().map(|(_, _, value)| value).collect());
+        }
+        Ok(run)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn runs_jobs_in_parallel_like_one_at_a_time() {
+        let rom: Arc<[u8]> = fs::read("tetris.gb").unwrap().into();
+        let job = |name: &str, movie: &str| FarmJob {
+            name: name.to_string(),
+            rom: rom.clone(),
+            movie: Movie::parse(movie).unwrap(),
+            frames: 110,
+        };
+        let jobs = vec![
+            job("idle", "0"),
+            job("start", "0\n100 start\n106"),
+            job("select", "0\n100 select\n106"),
+            FarmJob { rom: vec![0; 16].into(), ..job("broken", "0") },
+        ];
+        let probe: WatchExpr = "0xffe1".parse().unwrap(); // the game's state
+        let runs = Farm::new().threads(3).probe(probe).run(jobs.clone());
+
+        assert!(matches!(runs[3], Err(CartError::TooSmall(16))));
+        for (job, run) in jobs.iter().zip(&runs).take(3) {
+            let run = run.as_ref().unwrap();
+            assert_eq!(run.name, job.name);
+            let mut console = Console::builder().rom(rom.to_vec().into_boxed_slice()).build().unwrap();
+            console.watch(probe);
+            assert_eq!(run.frames, compare::trace(&mut console, &job.movie, job.frames));
+            assert_eq!(run.probes.len(), 110);
+            assert_eq!(run.probes[109], [console.watches().values().next().unwrap().2]);
+        }
+        assert!(runs[0].as_ref().unwrap().frames != runs[1].as_ref().unwrap().frames);
+    }
+
+    #[test]
+    fn resilient_farms_carry_on_past_broken_games() {
+        use super::super::test_asm::Asm;
+        let dir = std::env::temp_dir().join(format!("gbrust-farm-test-{}", std::process::id()));
+        let job = |name: &str, rom: Vec<u8>| FarmJob {
+            name: name.to_string(),
+            rom: rom.into(),
+            movie: Movie::parse("0").unwrap(),
+            frames: 30,
+        };
+        let jobs = vec![
+            job("tetris", fs::read("tetris.gb").unwrap()),
+            job("locked up", Asm::new().nop().bytes(&[0xDD]).assemble().into_vec()),
+        ];
+        let config = EmuConfig { crash_dir: Some(dir.clone()), ..EmuConfig::default() };
+        // Tetris has no cart RAM, but only the game touching it is an error, not looking at it
+        let no_ram: WatchExpr = "0xa000".parse().unwrap();
+        let runs = Farm::new().threads(2).config(config).resilient(true).probe(no_ram).run(jobs);
+
+        let tetris = runs[0].as_ref().unwrap();
+        assert_eq!((tetris.frames.len(), &tetris.failure), (30, &None));
+        assert_eq!(tetris.probes[29], [0xFF]);
+        let locked_up = runs[1].as_ref().unwrap();
+        assert!(locked_up.frames.is_empty());
+        assert_eq!(locked_up.failure.as_ref().unwrap().to_string(), "illegal opcode $dd at 00:0151");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+// DMG-07, the 4 player adapter.
+// Up to four Game Boys plug into the adapter, which drives the clock for all of them: every byte
+// period it swaps one byte with each, at the same time. Games such as F-1 Race, Faceball 2000 and
+// Wave Race use it. FourPlayerAdapter plays the adapter for consoles in the same process:
+//
+//     let mut adapter = FourPlayerAdapter::new();
+//     adapter.run_frame(&mut consoles, &mut sinks);
+//
+// The adapter talks in two phases. In the ping phase it sends packets of 4 bytes, 0xFE then
+// three STAT bytes: the players that answered the last ping in the upper nibble (bit 4 player 1
+// ... bit 7 player 4) and the player's own number in the low bits. Each Game Boy answers 0x88,
+// 0x88, then its RATE and SIZE. When player 1 answers a whole packet with 0xAA, the adapter sends
+// 0xCC four times and moves on to the transmission phase. There, every round is 4 * SIZE bytes
+// long: each Game Boy sends its SIZE byte packet at the start of the round, while the adapter
+// sends everyone all four packets of the round before, player 1's first. Player 1 sending a
+// packet of 0xFF goes back to pinging.
+// Bytes go out every BYTE_PERIOD cycles in both phases. The real adapter paces the transmission
+// phase by RATE, which is kept but not followed.
+// See PanDocs: https://gbdev.io/pandocs/Four_Player_Adapter.html
+
+use super::console::{Console, NoVideo, VideoSink};
+
+const PLAYERS: usize = 4;
+// About 2ms, plenty for a game's serial interrupt handler
+pub const BYTE_PERIOD: u32 = 8192;
+const FRAME_CYCLES: u32 = 70224;
+
+const PING: u8 = 0xFE;
+const ACK: u8 = 0x88;
+const START: u8 = 0xAA;
+const STARTING: u8 = 0xCC;
+const RESTART: u8 = 0xFF;
+
+// What the adapter is plugged into
+pub trait LinkPort {
+    // Sends byte, and returns the byte received, or None if the other end was not ready
+    fn exchange(&mut self, byte: u8) -> Option<u8>;
+}
+
+impl LinkPort for Console {
+    fn exchange(&mut self, byte: u8) -> Option<u8> {
+        self.serial_exchange(byte)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Phase {
+    Ping,
+    Starting, // sending 0xCC
+    Transmission,
+}
+
+pub struct FourPlayerAdapter {
+    phase: Phase,
+    position: usize, // byte in the current packet or round
+    connected: u8,   // STAT's upper nibble
+    acked: u8,       // players that answered both 0x88 of the ping in progress
+    starting: bool,  // player 1 answered 0xAA to every byte so far
+    rate: u8,
+    size: usize,
+    round: Vec<u8>,    // being collected, SIZE bytes per player
+    previous: Vec<u8>, // sent out in the current round
+    ran: Vec<u32>,     // cycles each console ran past the last exchange
+    cycles: u32,       // since the last exchange
+}
+
+impl Default for FourPlayerAdapter {
+    fn default() -> Self {
+        FourPlayerAdapter::new()
+    }
+}
+
+impl FourPlayerAdapter {
+    pub fn new() -> FourPlayerAdapter {
+        FourPlayerAdapter {
+            phase: Phase::Ping,
+            position: 0,
+            connected: 0,
+            acked: 0,
+            starting: true,
+            rate: 0,
+            size: 1,
+            round: Vec::new(),
+            previous: Vec::new(),
+            ran: Vec::new(),
+            cycles: 0,
+        }
+    }
+
+    // Players that answered the last ping, bit 0 player 1
+    pub fn connected(&self) -> u8 {
+        self.connected >> 4
+    }
+
+    pub fn in_transmission(&self) -> bool {
+        self.phase == Phase::Transmission
+    }
+
+    // RATE and SIZE as player 1 set them in the ping phase
+    pub fn rate(&self) -> u8 {
+        self.rate
+    }
+
+    pub fn packet_size(&self) -> usize {
+        self.size
+    }
+
+    // Swaps one byte with every port, port 0 being player 1. Up to four ports are used.
+    pub fn exchange(&mut self, ports: &mut [&mut dyn LinkPort]) {
+        let players = ports.len().min(PLAYERS);
+        let mut received = [None; PLAYERS];
+        for (player, port) in ports.iter_mut().take(players).enumerate() {
+            received[player] = port.exchange(self.send(player));
+        }
+        self.receive(&received);
+    }
+
+    // The byte for player, 0 to 3
+    fn send(&self, player: usize) -> u8 {
+        match self.phase {
+            Phase::Ping if self.position == 0 => PING,
+            Phase::Ping => self.connected | (player as u8 + 1),
+            Phase::Starting => STARTING,
+            Phase::Transmission => self.previous[self.position],
+        }
+    }
+
+    fn receive(&mut self, received: &[Option<u8>; PLAYERS]) {
+        match self.phase {
+            Phase::Ping => {
+                let first = received[0];
+                self.starting &= first == Some(START);
+                match self.position {
+                    0 | 1 => {
+                        if self.position == 0 {
+                            self.acked = 0x0F;
+                        }
+                        for (player, &byte) in received.iter().enumerate() {
+                            if byte != Some(ACK) {
+                                self.acked &= !(1 << player);
+                            }
+                        }
+                    }
+                    // Only from a player 1 that is answering the ping
+                    _ if self.acked & 1 == 0 => {}
+                    2 => self.rate = first.unwrap_or(self.rate),
+                    _ => self.size = first.map_or(self.size, |size| (size as usize).max(1)),
+                }
+                self.position += 1;
+                if self.position == 4 {
+                    self.position = 0;
+                    if self.starting {
+                        self.phase = Phase::Starting;
+                    } else {
+                        self.connected = self.acked << 4;
+                    }
+                    self.starting = true;
+                }
+            }
+            Phase::Starting => {
+                self.position += 1;
+                if self.position == 4 {
+                    self.position = 0;
+                    self.phase = Phase::Transmission;
+                    self.round = vec![0; PLAYERS * self.size];
+                    self.previous = vec![0; PLAYERS * self.size];
+                }
+            }
+            Phase::Transmission => {
+                if self.position < self.size {
+                    for (player, &byte) in received.iter().enumerate() {
+                        self.round[player * self.size + self.position] = byte.unwrap_or(0);
+                    }
+                }
+                self.position += 1;
+                if self.position == self.round.len() {
+                    self.position = 0;
+                    if self.round[..self.size].iter().all(|&byte| byte == RESTART) {
+                        self.phase = Phase::Ping;
+                    } else {
+                        self.previous.copy_from_slice(&self.round);
+                    }
+                }
+            }
+        }
+    }
+
+    // Runs every console for a frame's worth of cycles, exchanging a byte every BYTE_PERIOD.
+    // consoles[0] is player 1, and sinks go with consoles in the same order. Consoles past the
+    // last sink run all the same, with their frames dropped.
+    pub fn run_frame(&mut self, consoles: &mut [Console], sinks: &mut [&mut dyn VideoSink]) {
+        self.ran.resize(consoles.len(), 0);
+        let mut no_video = NoVideo;
+        let mut left = FRAME_CYCLES;
+        while left > 0 {
+            let slice = (BYTE_PERIOD - self.cycles).min(left);
+            for (i, (console, ran)) in consoles.iter_mut().zip(self.ran.iter_mut()).enumerate() {
+                let sink: &mut dyn VideoSink = match sinks.get_mut(i) {
+                    Some(sink) => &mut **sink,
+                    None => &mut no_video,
+                };
+                while *ran < slice {
+                    *ran += console.advance_instruction(sink);
+                }
+                *ran -= slice;
+            }
+            left -= slice;
+            self.cycles += slice;
+            if self.cycles == BYTE_PERIOD {
+                self.cycles = 0;
+                let mut ports: Vec<&mut dyn LinkPort> =
+                    consoles.iter_mut().map(|console| console as &mut dyn LinkPort).collect();
+                self.exchange(&mut ports);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use super::super::cart::Cart;
+
+    // Answers with a script, then 0x00, and keeps what it got
+    struct Script {
+        answers: Vec<u8>,
+        got: Vec<u8>,
+    }
+
+    impl Script {
+        fn new(answers: &[u8]) -> Script {
+            Script { answers: answers.to_vec(), got: Vec::new() }
+        }
+    }
+
+    impl LinkPort for Script {
+        fn exchange(&mut self, byte: u8) -> Option<u8> {
+            let answer = self.answers.get(self.got.len()).copied().unwrap_or(0);
+            self.got.push(byte);
+            Some(answer)
+        }
+    }
+
+    fn exchange(adapter: &mut FourPlayerAdapter, one: &mut Script, two: &mut Script, times: usize) {
+        for _ in 0..times {
+            adapter.exchange(&mut [one as &mut dyn LinkPort, two as &mut dyn LinkPort]);
+        }
+    }
+
+    #[test]
+    fn pings_then_relays_packets() {
+        let mut adapter = FourPlayerAdapter::new();
+        let mut one = Script::new(&[
+            0x88, 0x88, 0x10, 0x02, // ping: RATE 0x10, SIZE 2
+            0xAA, 0xAA, 0xAA, 0xAA, // start
+            0x00, 0x00, 0x00, 0x00, // during 0xCC
+            0x11, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x13, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        let mut two = Script::new(&[
+            0x88, 0x88, 0x10, 0x02,
+            0x88, 0x88, 0x10, 0x02,
+            0x00, 0x00, 0x00, 0x00,
+            0x21, 0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x23, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+
+        exchange(&mut adapter, &mut one, &mut two, 8);
+        assert_eq!(one.got[..4], [0xFE, 0x01, 0x01, 0x01]);
+        // Both answered the first ping
+        assert_eq!(two.got[4..], [0xFE, 0x32, 0x32, 0x32]);
+        assert_eq!((adapter.connected(), adapter.rate(), adapter.packet_size()), (0b11, 0x10, 2));
+
+        exchange(&mut adapter, &mut one, &mut two, 4);
+        assert_eq!(one.got[8..], [0xCC; 4]);
+        assert!(adapter.in_transmission());
+
+        // The first round has nothing to relay yet, the second relays the first
+        exchange(&mut adapter, &mut one, &mut two, 16);
+        assert_eq!(one.got[12..20], [0; 8]);
+        assert_eq!(two.got[20..], [0x11, 0x12, 0x21, 0x22, 0, 0, 0, 0]);
+
+        exchange(&mut adapter, &mut one, &mut two, 8);
+        assert_eq!(one.got[28..], [0x13, 0x14, 0x23, 0x24, 0, 0, 0, 0]);
+        assert!(!adapter.in_transmission());
+    }
+
+    #[test]
+    fn consoles_run_side_by_side() {
+        // Tetris never arms the external clock, so nobody answers the pings
+        let rom = fs::read("tetris.gb").unwrap().into_boxed_slice();
+        let mut consoles: Vec<Console> =
+            (0..3).map(|_| Console::new(Cart::new(rom.clone(), None).unwrap())).collect();
+        // The third console has no sink and runs anyway
+        let (mut one, mut two) = (NoVideo, NoVideo);
+        let mut sinks: [&mut dyn VideoSink; 2] = [&mut one, &mut two];
+        let mut adapter = FourPlayerAdapter::new();
+        for _ in 0..10 {
+            adapter.run_frame(&mut consoles, &mut sinks);
+        }
+        // All ran the same cycles, and nothing came over the link to set them apart
+        let state = consoles[0].save_state();
+        assert!(consoles[0].frame_count() > 0);
+        assert!(consoles.iter().all(|console| console.save_state() == state));
+        assert_eq!(adapter.connected(), 0);
+    }
+}
@@ -0,0 +1,153 @@
+// Frames as handed to a VideoSink.
+// A Frame borrows the pixels and says how to read them: width and height in pixels, stride (the
+// distance between the starts of two rows, in pixels, at least width) and the pixel format. The
+// DMG screen is always 160x144 with no padding, but sinks should go by the frame rather than
+// assume that, so bigger screens (Super Game Boy borders, 256x224) can come later.
+// A Frame only lives for the call to frame_available. Sinks that hand the pixels on as they are
+// (to a window, or a texture upload) should use packed(), which only copies frames with padding.
+// Sinks that keep frames around can copy them into buffers from a FramePool and recycle the
+// buffers when done, rather than allocate one per frame.
+
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+
+use super::ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+// Spare buffers a FramePool keeps at most
+const POOL_SIZE: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixelFormat {
+    // One u32 per pixel, 0xAARRGGBB. Alpha is always 0xFF.
+    Argb8888,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub format: PixelFormat,
+    pub pixels: &'a [u32],
+}
+
+impl<'a> Frame<'a> {
+    // A full DMG screen
+    pub fn dmg(pixels: &'a [u32]) -> Frame<'a> {
+        Frame::new(DISPLAY_WIDTH, DISPLAY_HEIGHT, DISPLAY_WIDTH, pixels)
+    }
+
+    pub fn new(width: usize, height: usize, stride: usize, pixels: &'a [u32]) -> Frame<'a> {
+        assert!(stride >= width, "stride is shorter than a row");
+        assert!(height == 0 || pixels.len() >= stride * (height - 1) + width, "not enough pixels");
+        Frame { width, height, stride, format: PixelFormat::Argb8888, pixels }
+    }
+
+    pub fn row(&self, y: usize) -> &'a [u32] {
+        let start = y * self.stride;
+        &self.pixels[start..start + self.width]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u32]> + '_ {
+        (0..self.height).map(move |y| self.row(y))
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.row(y)[x]
+    }
+
+    // width * height pixels, without padding
+    pub fn to_packed(&self) -> Box<[u32]> {
+        if self.stride == self.width {
+            return self.pixels[..self.width * self.height].into();
+        }
+        self.rows().flatten().copied().collect()
+    }
+
+    // As to_packed, borrowing the pixels when there is no padding to take out
+    pub fn packed(&self) -> Cow<'a, [u32]> {
+        if self.stride == self.width {
+            return Cow::Borrowed(&self.pixels[..self.width * self.height]);
+        }
+        Cow::Owned(self.rows().flatten().copied().collect())
+    }
+}
+
+// Frame buffers to reuse. Clones share the buffers, so one thread can fill them and another hand
+// them back.
+#[derive(Debug, Clone, Default)]
+pub struct FramePool {
+    free: Arc<Mutex<Vec<Box<[u32]>>>>,
+}
+
+impl FramePool {
+    pub fn new() -> FramePool {
+        FramePool::default()
+    }
+
+    // The frame's pixels, packed, in a spare buffer if there is one of the size
+    pub fn copy(&self, frame: &Frame) -> Box<[u32]> {
+        let len = frame.width * frame.height;
+        let spare = {
+            let mut free = self.free.lock().unwrap();
+            free.iter().position(|buffer| buffer.len() == len).map(|i| free.swap_remove(i))
+        };
+        let mut buffer = spare.unwrap_or_else(|| vec![0; len].into_boxed_slice());
+        if len > 0 {
+            for (to, row) in buffer.chunks_exact_mut(frame.width).zip(frame.rows()) {
+                to.copy_from_slice(row);
+            }
+        }
+        buffer
+    }
+
+    // Hands a buffer back for copy to reuse
+    pub fn recycle(&self, buffer: Box<[u32]>) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < POOL_SIZE {
+            free.push(buffer);
+        }
+    }
+
+    pub fn spare(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_skip_padding() {
+        let pixels = [1, 2, 0, 3, 4, 0, 5, 6];
+        let frame = Frame::new(2, 3, 3, &pixels);
+        assert_eq!(frame.row(1), [3, 4]);
+        assert_eq!(frame.pixel(1, 2), 6);
+        assert_eq!(&*frame.to_packed(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(&*frame.packed(), [1, 2, 3, 4, 5, 6]);
+        assert!(matches!(Frame::new(3, 2, 3, &pixels).packed(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn pools_reuse_recycled_buffers() {
+        let pool = FramePool::new();
+        let pixels = [1, 2, 0, 3, 4, 0];
+        let first = pool.copy(&Frame::new(2, 2, 3, &pixels));
+        assert_eq!(&*first, [1, 2, 3, 4]);
+        let address = first.as_ptr();
+        pool.clone().recycle(first);
+        assert_eq!(pool.spare(), 1);
+
+        // Only a buffer of the right size is reused
+        assert_eq!(pool.copy(&Frame::new(1, 1, 1, &pixels)).len(), 1);
+        let second = pool.copy(&Frame::new(2, 2, 2, &[5, 6, 7, 8]));
+        assert_eq!((&*second, second.as_ptr()), (&[5, 6, 7, 8][..], address));
+        assert_eq!(pool.spare(), 0);
+
+        for _ in 0..POOL_SIZE + 2 {
+            pool.recycle(vec![0; 4].into_boxed_slice());
+        }
+        assert_eq!(pool.spare(), POOL_SIZE);
+    }
+}
@@ -1,6 +1,7 @@
-use super::Interrupts;
+use super::interrupts::InterruptController;
+use super::state::{StateError, StateReader, StateWriter};
 
-#[derive(Debug)]
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
 pub enum ButtonState {
     Up,
     Down,
@@ -30,6 +31,42 @@ pub enum Button {
 // Bit 1 - P11 Input Left  or Button B (0=Pressed) (Read Only)
 // Bit 0 - P10 Input Right or Button A (0=Pressed) (Read Only)
 impl Button {
+    // In bit order for mask
+    pub const ALL: [Button; 8] = [
+        Button::Up,
+        Button::Down,
+        Button::Left,
+        Button::Right,
+        Button::A,
+        Button::B,
+        Button::Start,
+        Button::Select,
+    ];
+
+    // The button's bit when all eight are packed in a byte (bit 0 up ... bit 7 select), as
+    // netplay and movies do
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        use self::Button::*;
+        match self {
+            Up => "up",
+            Down => "down",
+            Left => "left",
+            Right => "right",
+            A => "a",
+            B => "b",
+            Start => "start",
+            Select => "select",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Button> {
+        Button::ALL.iter().copied().find(|button| button.name().eq_ignore_ascii_case(name))
+    }
+
     fn flag(&mut self) -> u8 {
         use self::Button::*;
         match self {
@@ -54,12 +91,33 @@ impl InputEvent {
             state: state,
         }
     }
+
+    pub fn button(&self) -> Button {
+        self.button
+    }
+
+    pub fn state(&self) -> ButtonState {
+        self.state
+    }
+}
+
+// When the game read JOYP, for an input poller. select is the group(s) it asked for, as written
+// to JOYP: bit 4 clear for directions, bit 5 clear for buttons.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct JoypadRead {
+    pub ly: u8,
+    pub cycle: u64,
+    pub select: u8,
 }
 
+// Called every time the game reads JOYP, the events it returns are applied before the read
+pub type InputPoller = Box<dyn FnMut(&JoypadRead) -> Vec<InputEvent> + Send>;
+
 pub struct Gamepad {
     direction_keys: u8,
     button_keys: u8,
     port: u8,
+    poller: Option<InputPoller>,
 }
 
 impl Gamepad {
@@ -72,9 +130,23 @@ impl Gamepad {
 
             // Bits: unused, unused, direction, button
             port: 0b1111_0000, 
+            poller: None,
         }
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.direction_keys);
+        state.write_u8(self.button_keys);
+        state.write_u8(self.port);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.direction_keys = state.read_u8()?;
+        self.button_keys = state.read_u8()?;
+        self.port = state.read_u8()?;
+        Ok(())
+    }
+
     pub fn read(&mut self) -> u8 {
         // Expected output: 0b0000_xxxx
         // xxxx indicates the buttons pressed
@@ -96,13 +168,47 @@ impl Gamepad {
         input
     }
 
+    // The buttons held, by Button::mask
+    pub fn held(&self) -> u8 {
+        let mut held = 0;
+        for &(mut button) in Button::ALL.iter() {
+            let keys = match button {
+                Button::Up | Button::Down | Button::Left | Button::Right => self.direction_keys,
+                _ => self.button_keys,
+            };
+            if keys & button.flag() == 0 {
+                held |= button.mask();
+            }
+        }
+        held
+    }
+
+    // Deselects both groups, as at power on. Buttons stay as they are, the player still holds them.
+    pub fn reset(&mut self) {
+        self.port = 0b1111_0000;
+    }
+
+    pub fn set_poller(&mut self, poller: Option<InputPoller>) {
+        self.poller = poller;
+    }
+
+    // Asks the poller, if any, for input right before a read. ly and cycle are the PPU's.
+    pub fn poll(&mut self, ly: u8, cycle: u64) {
+        if let Some(ref mut poller) = self.poller {
+            let events = poller(&JoypadRead { ly, cycle, select: self.port & 0b0011_0000 });
+            for event in events {
+                self.handle_event(event);
+            }
+        }
+    }
+
     pub fn write(&mut self, val: u8) {
         // Sets which set of buttons will be read
         self.port = val & 0b0011_0000
     }
 
-    pub fn cycle_flush(&mut self, _cycle_count: u32) -> Interrupts {
-        Interrupts::empty()
+    // Never requests the joypad interrupt yet
+    pub fn cycle_flush(&mut self, _cycle_count: u32, _interrupts: &mut InterruptController) {
     }
 
     pub fn handle_event(&mut self, mut event: InputEvent) {
@@ -0,0 +1,300 @@
+// GBS music player.
+// A .gbs file is a game's sound driver and music ripped out of the ROM: a header with the init and
+// play routine addresses, followed by the code to load at load_address. There is no game to run
+// it, so GbsFile::rom wraps it in a small cartridge of our own:
+//   - RST vectors jump to load_address + vector, as the format asks
+//   - the VBlank or timer interrupt (whichever the header picks) calls play
+//   - 0x0150 sets up the stack, sound and timer, calls init with the song number in A, and
+//     then idles while the interrupts drive play
+// Banked data past 0x4000 is mapped through MBC1, as it sits in the file.
+// GbsPlayer runs that cartridge on a Console and restarts it for every song change.
+// Format: https://ocremix.org/info/GBS_Format_Specification
+
+use std::mem;
+
+use super::console::{Console, Frame, NoVideo, VideoSink};
+use super::config::EmuConfig;
+use super::error::CartError;
+use super::overlay::draw_text;
+use super::ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use super::stats::FrameStats;
+
+const HEADER_SIZE: usize = 0x70;
+const MIN_LOAD_ADDRESS: u16 = 0x400; // below that is our driver
+const MAX_ROM_SIZE: usize = 2 * 1024 * 1024; // MBC1 limit
+const DRIVER: usize = 0x150;
+
+#[derive(Debug, Clone)]
+pub struct GbsFile {
+    pub song_count: u8,
+    pub first_song: u8, // 0 based, the header stores it 1 based
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    code: Box<[u8]>,
+}
+
+impl GbsFile {
+    pub fn is_gbs(bytes: &[u8]) -> bool {
+        bytes.starts_with(b"GBS")
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<GbsFile, CartError> {
+        if !GbsFile::is_gbs(bytes) {
+            return Err(CartError::InvalidGbs("missing GBS magic"));
+        }
+        if bytes.len() <= HEADER_SIZE {
+            return Err(CartError::InvalidGbs("file too short"));
+        }
+        if bytes[3] != 1 {
+            return Err(CartError::InvalidGbs("unsupported version"));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let text = |offset: usize| {
+            String::from_utf8_lossy(&bytes[offset..offset + 32]).trim_end_matches('\0').to_string()
+        };
+        let gbs = GbsFile {
+            song_count: bytes[4],
+            first_song: bytes[5].saturating_sub(1),
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+            code: bytes[HEADER_SIZE..].into(),
+        };
+
+        if gbs.song_count == 0 {
+            return Err(CartError::InvalidGbs("no songs"));
+        }
+        if gbs.load_address < MIN_LOAD_ADDRESS {
+            return Err(CartError::InvalidGbs("load address below 0x400"));
+        }
+        if gbs.load_address as usize + gbs.code.len() > MAX_ROM_SIZE {
+            return Err(CartError::InvalidGbs("too big for MBC1"));
+        }
+        Ok(gbs)
+    }
+
+    // Played from the timer interrupt instead of VBlank
+    pub fn timer_driven(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    // A cartridge that plays song (0 based), see the top of the file
+    pub fn rom(&self, song: u8) -> Box<[u8]> {
+        let end = self.load_address as usize + self.code.len();
+        let size = end.next_power_of_two().max(0x8000);
+        let mut rom = vec![0; size];
+        rom[self.load_address as usize..end].copy_from_slice(&self.code);
+
+        for vector in (0x00..0x40).step_by(8) {
+            let target = self.load_address + vector as u16;
+            rom[vector..vector + 3].copy_from_slice(&jp(target));
+        }
+        let [play_lo, play_hi] = self.play_address.to_le_bytes();
+        let play_handler = [0xCD, play_lo, play_hi, 0xD9]; // call play, reti
+        for vector in (0x40..=0x60).step_by(8) {
+            rom[vector] = 0xD9; // reti
+        }
+        let vector = if self.timer_driven() { 0x50 } else { 0x40 };
+        rom[vector..vector + 4].copy_from_slice(&play_handler);
+
+        // Header: entry point, MBC1 with RAM, ROM size
+        rom[0x100] = 0x00;
+        rom[0x101..0x104].copy_from_slice(&jp(DRIVER as u16));
+        for (dest, src) in rom[0x134..0x143].iter_mut().zip(self.title.bytes()) {
+            *dest = src;
+        }
+        rom[0x147] = 0x02;
+        rom[0x148] = (size / 0x8000).trailing_zeros() as u8;
+        rom[0x149] = 0x02;
+
+        let [sp_lo, sp_hi] = self.stack_pointer.to_le_bytes();
+        let [init_lo, init_hi] = self.init_address.to_le_bytes();
+        let interrupt = if self.timer_driven() { 0x04 } else { 0x01 };
+        let driver = [
+            0xF3,                         // di
+            0x31, sp_lo, sp_hi,           // ld sp, stack_pointer
+            0x3E, 0x0A, 0xEA, 0x00, 0x00, // enable cartridge RAM
+            0x3E, 0x80, 0xE0, 0x26,       // sound on
+            0x3E, 0x77, 0xE0, 0x24,       // full volume
+            0x3E, 0xFF, 0xE0, 0x25,       // every channel on both sides
+            0x3E, 0x80, 0xE0, 0x40,       // LCD on, nothing shown, for VBlank
+            0x3E, self.timer_modulo, 0xE0, 0x06,
+            0x3E, self.timer_control & 0x07, 0xE0, 0x07,
+            0x3E, interrupt, 0xE0, 0xFF,  // IE
+            0xAF, 0xE0, 0x0F,             // clear IF
+            0x3E, song, 0xCD, init_lo, init_hi, // call init with the song in A
+            0xFB,                         // ei
+            0x76, 0x18, 0xFD,             // halt, jr back to halt
+        ];
+        rom[DRIVER..DRIVER + driver.len()].copy_from_slice(&driver);
+        rom.into_boxed_slice()
+    }
+}
+
+fn jp(addr: u16) -> [u8; 3] {
+    let [lo, hi] = addr.to_le_bytes();
+    [0xC3, lo, hi]
+}
+
+pub struct GbsPlayer {
+    gbs: GbsFile,
+    config: EmuConfig,
+    song: u8,
+    console: Console,
+}
+
+impl GbsPlayer {
+    // Starts playing the file's first song
+    pub fn new(gbs: GbsFile, config: EmuConfig) -> Result<GbsPlayer, CartError> {
+        let song = gbs.first_song.min(gbs.song_count - 1);
+        let console = GbsPlayer::console(&gbs, &config, song)?;
+        Ok(GbsPlayer { gbs, config, song, console })
+    }
+
+    fn console(gbs: &GbsFile, config: &EmuConfig, song: u8) -> Result<Console, CartError> {
+        Console::builder()
+            .config(config.clone())
+            .rom(gbs.rom(song))
+            .build()
+    }
+
+    pub fn gbs(&self) -> &GbsFile {
+        &self.gbs
+    }
+
+    // 0 based
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    // The console running the driver, e.g. to attach an audio sink. Replaced on song changes,
+    // but the audio sink carries over.
+    pub fn console_mut(&mut self) -> &mut Console {
+        &mut self.console
+    }
+
+    // Restarts on another song, wrapping around at either end
+    pub fn play(&mut self, song: u8) -> Result<(), CartError> {
+        let song = song % self.gbs.song_count;
+        let console = GbsPlayer::console(&self.gbs, &self.config, song)?;
+        let mut old = mem::replace(&mut self.console, console);
+        self.console.set_audio_sink(old.take_audio_sink());
+        self.song = song;
+        Ok(())
+    }
+
+    pub fn next_song(&mut self) -> Result<(), CartError> {
+        self.play(self.song.wrapping_add(1) % self.gbs.song_count)
+    }
+
+    pub fn prev_song(&mut self) -> Result<(), CartError> {
+        self.play(self.song.checked_sub(1).unwrap_or(self.gbs.song_count - 1))
+    }
+
+    // Plays one frame's worth of music, and shows what is playing
+    pub fn run_frame(&mut self, video_sink: &mut dyn VideoSink) -> FrameStats {
+        let stats = self.console.run_frame(&mut NoVideo);
+        video_sink.frame_available(&Frame::dmg(&self.info_screen()));
+        stats
+    }
+
+    fn info_screen(&self) -> Box<[u32]> {
+        let palette = self.config.palette.0;
+        let mut frame = vec![palette[0]; DISPLAY_WIDTH * DISPLAY_HEIGHT].into_boxed_slice();
+        let lines = [
+            self.gbs.title.to_uppercase(),
+            self.gbs.author.to_uppercase(),
+            self.gbs.copyright.to_uppercase(),
+            String::new(),
+            format!("SONG {}/{}", self.song as u32 + 1, self.gbs.song_count),
+            String::new(),
+            "< PREV      NEXT >".to_string(),
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(&mut frame, 4, 16 + i * 12, line, palette[3]);
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::watch::WatchExpr;
+
+    // init stores the song in 0xC001, play counts its calls in 0xC000
+    fn test_gbs() -> Vec<u8> {
+        let mut gbs = vec![0; HEADER_SIZE];
+        gbs[..4].copy_from_slice(b"GBS\x01");
+        gbs[4] = 3; // songs
+        gbs[5] = 2; // first song
+        gbs[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes()); // load
+        gbs[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes()); // init
+        gbs[0x0A..0x0C].copy_from_slice(&0x0404u16.to_le_bytes()); // play
+        gbs[0x0C..0x0E].copy_from_slice(&0xFFFEu16.to_le_bytes()); // stack
+        gbs[0x10..0x14].copy_from_slice(b"Test");
+        gbs.extend_from_slice(&[
+            0xEA, 0x01, 0xC0, 0xC9,             // init: ld (0xC001), a; ret
+            0xFA, 0x00, 0xC0, 0x3C, 0xEA, 0x00, 0xC0, 0xC9, // play: inc (0xC000); ret
+        ]);
+        gbs
+    }
+
+    fn memory(player: &mut GbsPlayer, addr: u16) -> u16 {
+        let console = player.console_mut();
+        console.watch(WatchExpr::Byte(addr));
+        console.watches().values().last().unwrap().2
+    }
+
+    #[test]
+    fn plays_songs_from_vblank() {
+        let gbs = GbsFile::parse(&test_gbs()).unwrap();
+        assert_eq!(gbs.title, "Test");
+        assert!(!gbs.timer_driven());
+        let mut player = GbsPlayer::new(gbs, EmuConfig::default()).unwrap();
+        assert_eq!(player.song(), 1);
+
+        for _ in 0..10 {
+            player.run_frame(&mut NoVideo);
+        }
+        assert_eq!(memory(&mut player, 0xC001), 1);
+        assert!((9..= 10).contains(&memory(&mut player, 0xC000)));
+
+        player.next_song().unwrap();
+        player.next_song().unwrap();
+        assert_eq!(player.song(), 0);
+        player.run_frame(&mut NoVideo);
+        assert_eq!(memory(&mut player, 0xC001), 0);
+        player.prev_song().unwrap();
+        assert_eq!(player.song(), 2);
+
+        // Past 128 songs, wrapping back to the last
+        let mut many = test_gbs();
+        many[4] = 200;
+        many[5] = 1;
+        let mut player = GbsPlayer::new(GbsFile::parse(&many).unwrap(), EmuConfig::default()).unwrap();
+        player.prev_song().unwrap();
+        assert_eq!(player.song(), 199);
+        player.next_song().unwrap();
+        assert_eq!(player.song(), 0);
+        player.play(150).unwrap();
+        player.prev_song().unwrap();
+        assert_eq!(player.song(), 149);
+
+        assert!(GbsFile::parse(b"GBS\x01").is_err());
+    }
+}
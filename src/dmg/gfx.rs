@@ -0,0 +1,264 @@
+// Graphics dumps.
+// For pulling a game's graphics out, or checking what the PPU had to work with when a frame
+// looks wrong, without a GUI: Console::vram_snapshot copies VRAM, OAM and the registers that say
+// how to read them, at any moment, and VramSnapshot draws them:
+//
+//     tile_sheet   the 384 tiles of a VRAM bank, 16 to a row, color ids as the 4 shades (no palette)
+//     map          a 32x32 tile map (0x9800 or 0x9c00) as 256x256, with the tile data LCDC
+//                  selects and BGP, and on the CGB each tile's bank and flips
+//     sprites      the 40 OAM entries, 8 to a row, 8x16 each (8x8 sprites fill the top half), with
+//                  their OBP and flips, color 0 in the lightest shade
+//
+// save_pngs writes them all to a directory. The PNGs are uncompressed, see encode_png.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::patch::crc32;
+use super::ppu::decode_tile_row;
+
+const TILES_PER_BANK: usize = 384;
+const TILE_BYTES: usize = 16;
+const BANK_SIZE: usize = 0x2000;
+
+// Pixels are 0xAARRGGBB, alpha ignored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+impl Image {
+    fn new(width: usize, height: usize, fill: u32) -> Image {
+        Image { width, height, pixels: vec![fill; width * height] }
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        encode_png(self.width, self.height, &self.pixels)
+    }
+
+    // An 8x8 tile at x, y. rows gives a row's color ids (see decode_tile_row).
+    fn draw_tile<F: Fn(usize) -> u64>(&mut self, x: usize, y: usize, rows: F, shades: [u32; 4], flip_x: bool, flip_y: bool) {
+        for row in 0..8 {
+            let ids = rows(if flip_y { 7 - row } else { row });
+            for col in 0..8 {
+                let id = ids >> (8 * if flip_x { 7 - col } else { col }) & 0xFF;
+                self.pixels[(y + row) * self.width + x + col] = shades[id as usize];
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VramSnapshot {
+    pub vram: Box<[u8]>, // 0x8000 - 0x9fff, then bank 1 of it on the CGB
+    pub oam: [u8; 0xA0],
+    pub lcdc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub cgb: bool,
+    pub shades: [u32; 4], // the frontend's colors, lightest first
+}
+
+impl VramSnapshot {
+    pub fn banks(&self) -> usize {
+        self.vram.len() / BANK_SIZE
+    }
+
+    pub fn bg_map_addr(&self) -> u16 {
+        if self.lcdc & 0x08 != 0 { 0x9C00 } else { 0x9800 }
+    }
+
+    pub fn window_map_addr(&self) -> u16 {
+        if self.lcdc & 0x40 != 0 { 0x9C00 } else { 0x9800 }
+    }
+
+    fn byte(&self, bank: usize, addr: u16) -> u8 {
+        self.vram[bank * BANK_SIZE + (addr - 0x8000) as usize]
+    }
+
+    fn tile_row(&self, bank: usize, tile_addr: u16, row: usize) -> u64 {
+        let addr = tile_addr + row as u16 * 2;
+        decode_tile_row(self.byte(bank, addr), self.byte(bank, addr + 1))
+    }
+
+    // 128x192
+    pub fn tile_sheet(&self, bank: usize) -> Image {
+        let mut image = Image::new(128, 192, self.shades[0]);
+        for tile in 0..TILES_PER_BANK {
+            let addr = 0x8000 + (tile * TILE_BYTES) as u16;
+            image.draw_tile(tile % 16 * 8, tile / 16 * 8, |row| self.tile_row(bank, addr, row), self.shades, false, false);
+        }
+        image
+    }
+
+    pub fn map(&self, map_addr: u16) -> Image {
+        let shades = self.palette(self.bgp);
+        let mut image = Image::new(256, 256, self.shades[0]);
+        for index in 0..32 * 32 {
+            let entry = map_addr + index as u16;
+            let tile = self.byte(0, entry);
+            let attributes = if self.cgb { self.byte(1, entry) } else { 0 };
+            let addr = if self.lcdc & 0x10 != 0 {
+                0x8000 + tile as u16 * TILE_BYTES as u16
+            } else {
+                0x8800 + (tile as i8 as i16 + 128) as u16 * TILE_BYTES as u16
+            };
+            let bank = (attributes >> 3 & 0x01) as usize;
+            image.draw_tile(index % 32 * 8, index / 32 * 8, |row| self.tile_row(bank, addr, row),
+                            shades, attributes & 0x20 != 0, attributes & 0x40 != 0);
+        }
+        image
+    }
+
+    // 64x80
+    pub fn sprites(&self) -> Image {
+        let tall = self.lcdc & 0x04 != 0;
+        let mut image = Image::new(64, 80, self.shades[0]);
+        for (index, entry) in self.oam.chunks(4).enumerate() {
+            let (tile, attributes) = (entry[2], entry[3]);
+            let mut shades = self.palette(if attributes & 0x10 != 0 { self.obp1 } else { self.obp0 });
+            shades[0] = self.shades[0];
+            let bank = if self.cgb { (attributes >> 3 & 0x01) as usize } else { 0 };
+            let (flip_x, flip_y) = (attributes & 0x20 != 0, attributes & 0x40 != 0);
+            // Flipped vertically, the two tiles of a tall sprite swap places too
+            let tiles = match (tall, flip_y) {
+                (false, _) => vec![tile],
+                (true, false) => vec![tile & 0xFE, tile | 0x01],
+                (true, true) => vec![tile | 0x01, tile & 0xFE],
+            };
+            for (half, &tile) in tiles.iter().enumerate() {
+                let addr = 0x8000 + tile as u16 * TILE_BYTES as u16;
+                image.draw_tile(index % 8 * 8, index / 8 * 16 + half * 8, |row| self.tile_row(bank, addr, row),
+                                shades, flip_x, flip_y);
+            }
+        }
+        image
+    }
+
+    // The shades a palette register maps color ids 0 - 3 to
+    fn palette(&self, register: u8) -> [u32; 4] {
+        [0, 1, 2, 3].map(|id| self.shades[(register >> (id * 2)) as usize & 0x03])
+    }
+
+    // tiles.png (and tiles1.png on the CGB), bg.png, window.png and sprites.png in dir, which is
+    // created if need be. Returns the files written.
+    pub fn save_pngs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::create_dir_all(dir)?;
+        let mut images = vec![("tiles.png", self.tile_sheet(0))];
+        if self.banks() > 1 {
+            images.push(("tiles1.png", self.tile_sheet(1)));
+        }
+        images.push(("bg.png", self.map(self.bg_map_addr())));
+        images.push(("window.png", self.map(self.window_map_addr())));
+        images.push(("sprites.png", self.sprites()));
+        images.into_iter().map(|(name, image)| {
+            let path = dir.join(name);
+            fs::write(&path, image.to_png())?;
+            Ok(path)
+        }).collect()
+    }
+}
+
+// 24 bit RGB PNG with stored (uncompressed) deflate blocks. Bigger than it could be, but it
+// saves pulling in zlib for debugging output.
+pub fn encode_png(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+    let mut rows = Vec::with_capacity(height * (1 + width * 3));
+    for row in pixels.chunks(width) {
+        rows.push(0); // no filter
+        for &pixel in row {
+            rows.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+        }
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = rows.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8); // last block flag, stored
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&rows).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit RGB, no interlacing
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut png, b"IHDR", &header);
+    png_chunk(&mut png, b"IDAT", &zlib);
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHADES: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+    // Tile 1 has color id 3 in its top left pixel and 1 in the rest of its top row
+    fn snapshot() -> VramSnapshot {
+        let mut vram = vec![0; BANK_SIZE].into_boxed_slice();
+        vram[0x10] = 0xFF;
+        vram[0x11] = 0x80;
+        vram[0x1800] = 1; // top left of the map at 0x9800
+        let mut oam = [0; 0xA0];
+        oam[2] = 1;
+        oam[3] = 0x20; // flipped horizontally
+        VramSnapshot { vram, oam, lcdc: 0x91, bgp: 0xE4, obp0: 0xE4, obp1: 0, cgb: false, shades: SHADES }
+    }
+
+    #[test]
+    fn draws_tiles_maps_and_sprites() {
+        let vram = snapshot();
+        let tiles = vram.tile_sheet(0);
+        assert_eq!((tiles.width, tiles.height), (128, 192));
+        assert_eq!(tiles.pixels[8..11], [SHADES[3], SHADES[1], SHADES[1]]);
+        assert_eq!(tiles.pixels[0], SHADES[0]);
+
+        // BGP 0x1b reverses the shades
+        let map = VramSnapshot { bgp: 0x1B, ..vram.clone() }.map(vram.bg_map_addr());
+        assert_eq!((map.pixels[0], map.pixels[1], map.pixels[256 + 1]), (SHADES[0], SHADES[2], SHADES[3]));
+
+        let sprites = vram.sprites();
+        assert_eq!((sprites.width, sprites.height), (64, 80));
+        assert_eq!(sprites.pixels[6..8], [SHADES[1], SHADES[3]]);
+    }
+
+    #[test]
+    fn pngs_decode() {
+        let image = snapshot().tile_sheet(0);
+        let png = image.to_png();
+        let mut reader = ::png::Decoder::new(&png[..]).read_info().unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgb).unwrap();
+        assert_eq!((info.width, info.height), (128, 192));
+        assert_eq!(rgb[8 * 3..9 * 3], [0, 0, 0]);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
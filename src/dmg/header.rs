@@ -0,0 +1,237 @@
+// Cartridge reports, for `gbrust info` and bug reports: what the header says, checked against
+// the ROM itself, and what gbrust makes of it. Built from the ROM bytes alone, so carts gbrust
+// cannot run yet get a report too.
+//
+// Text is one field per line:
+//
+//     title            TETRIS
+//     type             00 ROM ONLY
+//     header checksum  0a, ok
+//
+// and JSON one object with the same fields, numbers as numbers and hashes as hex strings.
+// The save is an estimate from the cartridge type: battery backed RAM of the size the header
+// gives (MBC2 has 512 nibbles of its own), and a clock on MBC3 + TIMER and HuC3.
+
+use std::fmt::Write;
+
+use super::cart::{Cart, DestinationCode};
+use super::error::CartError;
+use super::romdb::{DatEntry, RomHashes};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CgbSupport {
+    No,
+    Compatible, // runs on both, with colours on a CGB
+    Only,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SaveEstimate {
+    pub ram: u32, // bytes
+    pub rtc: bool,
+}
+
+#[derive(Debug)]
+pub struct CartReport {
+    pub title: String,
+    pub cart_type: u8,
+    pub supported: bool, // whether gbrust emulates the cartridge hardware
+    pub rom_size: Option<u32>, // None for an invalid size code, as are the rest
+    pub ram_size: Option<u32>,
+    pub file_size: usize,
+    pub save: Option<SaveEstimate>,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub destination: DestinationCode,
+    pub licensee: String, // two hex digits, or the two letters of the new licensee code
+    pub version: u8,
+    pub header_checksum: u8,
+    pub header_checksum_ok: bool,
+    pub global_checksum: u16,
+    pub global_checksum_ok: bool,
+    pub hashes: RomHashes,
+    pub dat_entry: Option<DatEntry>, // if looked up in a ROM database
+}
+
+// What the boot ROM checks 0x014d against
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..=0x014C].iter().fold(0u8, |x, &byte| x.wrapping_sub(byte).wrapping_sub(1))
+}
+
+// What 0x014e - 0x014f should hold: every other byte of the ROM added up
+fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter().enumerate()
+        .filter(|&(offset, _)| offset != 0x014E && offset != 0x014F)
+        .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+}
+
+fn estimate_save(cart_type: u8, ram_size: Option<u32>) -> Option<SaveEstimate> {
+    let battery = matches!(cart_type, 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFC..=0xFF);
+    if !battery {
+        return None;
+    }
+    let ram = match cart_type {
+        0x05 | 0x06 => 512,
+        _ => ram_size.unwrap_or(0),
+    };
+    Some(SaveEstimate { ram, rtc: matches!(cart_type, 0x0F | 0x10 | 0xFE) })
+}
+
+fn size_text(bytes: Option<u32>) -> String {
+    match bytes {
+        Some(0) => "none".to_string(),
+        Some(bytes) if bytes >= 1024 => format!("{} KiB", bytes / 1024),
+        Some(bytes) => format!("{} bytes", bytes),
+        None => "invalid".to_string(),
+    }
+}
+
+pub(crate) fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_number(number: Option<u32>) -> String {
+    number.map_or("null".to_string(), |number| number.to_string())
+}
+
+impl CartReport {
+    pub fn new(rom: &[u8]) -> Result<CartReport, CartError> {
+        if rom.len() < 0x0150 {
+            return Err(CartError::TooSmall(rom.len()));
+        }
+        let cart_type = rom[0x0147];
+        let rom_size = Cart::rom_size(rom).ok();
+        let ram_size = Cart::ram_size_of(rom[0x0149]).ok();
+        let licensee = match rom[0x014B] {
+            0x33 => String::from_utf8_lossy(&rom[0x0144..0x0146]).into_owned(),
+            code => format!("{:02x}", code),
+        };
+        let stored_global = u16::from_be_bytes([rom[0x014E], rom[0x014F]]);
+        Ok(CartReport {
+            title: String::from_utf8_lossy(&rom[0x0134..0x0143]).trim_end_matches('\0').to_string(),
+            cart_type,
+            supported: Cart::is_supported(cart_type),
+            rom_size,
+            ram_size,
+            file_size: rom.len(),
+            save: estimate_save(cart_type, ram_size),
+            cgb: match rom[0x0143] {
+                0xC0 => CgbSupport::Only,
+                flag if flag & 0x80 != 0 => CgbSupport::Compatible,
+                _ => CgbSupport::No,
+            },
+            sgb: rom[0x0146] == 0x03,
+            destination: Cart::destination(rom[0x014A]),
+            licensee,
+            version: rom[0x014C],
+            header_checksum: rom[0x014D],
+            header_checksum_ok: header_checksum(rom) == rom[0x014D],
+            global_checksum: stored_global,
+            global_checksum_ok: global_checksum(rom) == stored_global,
+            hashes: RomHashes::of(rom),
+            dat_entry: None,
+        })
+    }
+
+    fn save_text(&self) -> String {
+        match self.save {
+            None => "none".to_string(),
+            Some(SaveEstimate { ram: 0, rtc: true }) => "clock".to_string(),
+            Some(SaveEstimate { ram, rtc }) => {
+                format!("{} battery RAM{}", size_text(Some(ram)), if rtc { " and clock" } else { "" })
+            }
+        }
+    }
+
+    fn cgb_name(&self) -> &'static str {
+        match self.cgb {
+            CgbSupport::No => "no",
+            CgbSupport::Compatible => "compatible",
+            CgbSupport::Only => "only",
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let ok = |ok: bool| if ok { "ok" } else { "bad" };
+        let mut lines = vec![("title", self.title.clone())];
+        if let Some(ref entry) = self.dat_entry {
+            lines.push(("name", format!("{}{}", entry.name, if entry.bad_dump { " (bad dump)" } else { "" })));
+        }
+        lines.extend(vec![
+            ("type", format!("{:02x} {}", self.cart_type, Cart::cart_type_name(self.cart_type))),
+            ("supported", if self.supported { "yes" } else { "no" }.to_string()),
+            ("rom", format!("{}, file {}", size_text(self.rom_size), size_text(Some(self.file_size as u32)))),
+            ("ram", size_text(self.ram_size)),
+            ("save", self.save_text()),
+            ("cgb", self.cgb_name().to_string()),
+            ("sgb", if self.sgb { "yes" } else { "no" }.to_string()),
+            ("destination", format!("{:?}", self.destination)),
+            ("licensee", self.licensee.clone()),
+            ("version", self.version.to_string()),
+            ("header checksum", format!("{:02x}, {}", self.header_checksum, ok(self.header_checksum_ok))),
+            ("global checksum", format!("{:04x}, {}", self.global_checksum, ok(self.global_checksum_ok))),
+            ("crc32", format!("{:08x}", self.hashes.crc32)),
+            ("sha1", self.hashes.sha1_hex()),
+        ]);
+        lines.iter().map(|(name, value)| format!("{:<16} {}\n", name, value)).collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let save = match self.save {
+            Some(save) => format!("{{\"ram\":{},\"rtc\":{}}}", save.ram, save.rtc),
+            None => "null".to_string(),
+        };
+        let name = self.dat_entry.as_ref().map_or("null".to_string(), |entry| json_string(&entry.name));
+        format!("{{\"title\":{},\"name\":{},\"cart_type\":{},\"cart_type_name\":{},\"supported\":{},\
+                 \"rom_size\":{},\"ram_size\":{},\"file_size\":{},\"save\":{},\"cgb\":\"{}\",\"sgb\":{},\
+                 \"destination\":\"{:?}\",\"licensee\":{},\"version\":{},\"header_checksum\":{},\
+                 \"header_checksum_ok\":{},\"global_checksum\":{},\"global_checksum_ok\":{},\
+                 \"crc32\":\"{:08x}\",\"sha1\":\"{}\"}}",
+                json_string(&self.title), name, self.cart_type, json_string(Cart::cart_type_name(self.cart_type)),
+                self.supported, json_number(self.rom_size), json_number(self.ram_size), self.file_size, save,
+                self.cgb_name(), self.sgb, self.destination, json_string(&self.licensee), self.version,
+                self.header_checksum, self.header_checksum_ok, self.global_checksum, self.global_checksum_ok,
+                self.hashes.crc32, self.hashes.sha1_hex())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn reports_on_tetris_and_unsupported_carts() {
+        let tetris = fs::read("tetris.gb").unwrap();
+        let report = CartReport::new(&tetris).unwrap();
+        let text = report.to_text();
+        assert!(text.starts_with("title            TETRIS\ntype             00 ROM ONLY\n"));
+        assert!(text.contains("rom              32 KiB, file 32 KiB\n"));
+        assert!(text.contains("header checksum  0a, ok\nglobal checksum  16bf, ok\n"));
+        assert!(report.to_json().starts_with("{\"title\":\"TETRIS\",\"name\":null,\"cart_type\":0,"));
+
+        // MBC5 + RAM + battery, for the CGB, with a title that needs escaping
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0137].copy_from_slice(b"A\"B");
+        rom[0x0143] = 0xC0;
+        rom[0x0147] = 0x1B;
+        rom[0x0149] = 0x03;
+        let report = CartReport::new(&rom).unwrap();
+        assert!(!report.supported && !report.header_checksum_ok);
+        assert_eq!(report.cgb, CgbSupport::Only);
+        assert_eq!(report.save, Some(SaveEstimate { ram: 32 * 1024, rtc: false }));
+        assert!(report.to_text().contains("save             32 KiB battery RAM\n"));
+        assert!(report.to_json().contains("\"title\":\"A\\\"B\""));
+        assert!(CartReport::new(&[0; 0x100]).is_err());
+    }
+}
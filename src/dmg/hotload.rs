@@ -0,0 +1,170 @@
+// Hot loading ROM banks.
+// For homebrew and ROM hack development: a HotLoader puts the contents of a host file, or bytes
+// from memory, over a ROM bank while the game runs, and loads the file again whenever it
+// changes on disk, so new code or graphics show up without rebuilding the ROM. The console
+// checks the files once per frame (see Console::map_rom_bank_file), and unmapping a bank puts
+// the ROM's own bytes back.
+// A file fills its bank from the start, the rest of the bank keeps the ROM's bytes. A file that
+// cannot be read, or has grown past the bank, leaves the bank as it was until the next change.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::cart::Cart;
+use super::error::CartError;
+
+#[derive(Debug)]
+struct MappedBank {
+    bank: usize,
+    file: Option<PathBuf>,
+    modified: Option<SystemTime>, // when the file was last loaded
+    original: Box<[u8]>,          // the ROM's own bytes
+}
+
+impl MappedBank {
+    // The ROM's own bytes with bytes over the start
+    fn load(&self, cart: &mut Cart, bytes: &[u8]) -> Result<(), CartError> {
+        if bytes.len() > self.original.len() {
+            return Err(CartError::BankOverflow(bytes.len()));
+        }
+        let mut contents = self.original.to_vec();
+        contents[..bytes.len()].copy_from_slice(bytes);
+        cart.write_rom_bank(self.bank, &contents)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HotLoader {
+    banks: Vec<MappedBank>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+impl HotLoader {
+    // Maps file over bank, and loads it now
+    pub fn map_file(&mut self, cart: &mut Cart, bank: usize, file: PathBuf) -> Result<(), CartError> {
+        let modified = modified(&file);
+        self.map(cart, bank, &fs::read(&file)?)?;
+        let mapped = self.banks.iter_mut().find(|mapped| mapped.bank == bank).unwrap();
+        mapped.file = Some(file);
+        mapped.modified = modified;
+        Ok(())
+    }
+
+    // Puts bytes over bank, once
+    pub fn map(&mut self, cart: &mut Cart, bank: usize, bytes: &[u8]) -> Result<(), CartError> {
+        if !self.banks.iter().any(|mapped| mapped.bank == bank) {
+            let original = cart.rom_bank_bytes(bank).ok_or(CartError::NoSuchBank(bank))?.into();
+            cart.write_rom_bank(bank, bytes)?;
+            self.banks.push(MappedBank { bank, file: None, modified: None, original });
+            return Ok(());
+        }
+        let mapped = self.banks.iter_mut().find(|mapped| mapped.bank == bank).unwrap();
+        mapped.load(cart, bytes)?;
+        mapped.file = None;
+        Ok(())
+    }
+
+    // Puts the ROM's own bytes back in bank. False if it was not mapped. Fails, leaving the bank
+    // unmapped, if cart is not the ROM the bank was mapped in.
+    pub fn unmap(&mut self, cart: &mut Cart, bank: usize) -> Result<bool, CartError> {
+        match self.banks.iter().position(|mapped| mapped.bank == bank) {
+            Some(index) => {
+                let mapped = self.banks.remove(index);
+                cart.write_rom_bank(bank, &mapped.original)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.banks.is_empty()
+    }
+
+    // Loads the files changed since they were last loaded. True if any bank changed.
+    pub fn poll(&mut self, cart: &mut Cart) -> bool {
+        let mut changed = false;
+        for mapped in self.banks.iter_mut() {
+            let file = match mapped.file.clone() {
+                Some(file) => file,
+                None => continue,
+            };
+            let modified = modified(&file);
+            if modified.is_none() || modified == mapped.modified {
+                continue;
+            }
+            mapped.modified = modified;
+            let loaded = fs::read(&file).map_err(CartError::from).and_then(|bytes| mapped.load(cart, &bytes));
+            match loaded {
+                Ok(()) => {
+                    info!(target: "gbrust::hotload", "reloaded bank {} from {}", mapped.bank, file.display());
+                    changed = true;
+                }
+                Err(e) => warn!(target: "gbrust::hotload", "could not reload {}: {}", file.display(), e),
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::Duration;
+
+    // MBC1 with no RAM
+    fn rom_with_banks(banks: usize) -> Box<[u8]> {
+        let mut rom = vec![0x11; banks * 0x4000];
+        rom[0x0147] = 0x01; // MBC1
+        rom[0x0148] = (banks / 2).trailing_zeros() as u8; // 32 KiB << n
+        rom[0x0149] = 0x00; // no RAM
+        rom.into_boxed_slice()
+    }
+
+    fn cart() -> Cart {
+        Cart::new(rom_with_banks(4), None).unwrap()
+    }
+
+    #[test]
+    fn files_reload_when_they_change() {
+        let path = env::temp_dir().join(format!("gbrust-hotload-{}.bin", std::process::id()));
+        fs::write(&path, [1, 2, 3]).unwrap();
+        let mut cart = cart();
+        let mut loader = HotLoader::default();
+        loader.map_file(&mut cart, 2, path.clone()).unwrap();
+        assert_eq!(cart.rom_bank_bytes(2).unwrap()[..4], [1, 2, 3, 0x11]);
+        assert!(!loader.poll(&mut cart));
+
+        // Shorter, and later, so the change shows on filesystems with coarse timestamps
+        fs::write(&path, [4]).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(2);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(loader.poll(&mut cart));
+        assert_eq!(cart.rom_bank_bytes(2).unwrap()[..4], [4, 0x11, 0x11, 0x11]);
+
+        assert!(loader.unmap(&mut cart, 2).unwrap());
+        assert!(cart.rom_bank_bytes(2).unwrap().iter().all(|&byte| byte == 0x11));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn banks_must_exist_and_fit() {
+        let mut cart = cart();
+        let mut loader = HotLoader::default();
+        assert!(matches!(loader.map(&mut cart, 4, &[0]), Err(CartError::NoSuchBank(4))));
+        assert!(matches!(loader.map(&mut cart, 1, &[0; 0x4001]), Err(CartError::BankOverflow(0x4001))));
+        assert!(loader.is_empty());
+        assert!(!loader.unmap(&mut cart, 1).unwrap());
+
+        // Unmapping into a smaller ROM than the bank came from
+        loader.map(&mut cart, 3, &[0]).unwrap();
+        let mut small = Cart::new(rom_with_banks(2), None).unwrap();
+        assert!(matches!(loader.unmap(&mut small, 3), Err(CartError::NoSuchBank(3))));
+        assert!(loader.is_empty());
+    }
+}
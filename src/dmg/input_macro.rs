@@ -0,0 +1,69 @@
+// Scripted input, for getting through menus in tests and bots without a recording:
+//
+//     let start_game = InputMacro::new().press(Button::A, 2).wait(30).press(Button::Start, 1);
+//     console.queue_input(&start_game);
+//
+// A macro is the buttons to hold on each of its frames. Queued macros play one after the other,
+// a frame's worth at the start of every frame the console runs. While one plays it decides every
+// button, and once the queue runs dry all of them are let go.
+
+use super::gamepad::Button;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputMacro {
+    frames: Vec<u8>, // buttons held on each frame, see Button::mask
+}
+
+impl InputMacro {
+    pub fn new() -> InputMacro {
+        InputMacro::default()
+    }
+
+    pub fn press(self, button: Button, frames: usize) -> InputMacro {
+        self.hold(&[button], frames)
+    }
+
+    // Several buttons at once, e.g. A + B + Start + Select
+    pub fn hold(mut self, buttons: &[Button], frames: usize) -> InputMacro {
+        let mask = buttons.iter().fold(0, |mask, button| mask | button.mask());
+        self.frames.resize(self.frames.len() + frames, mask);
+        self
+    }
+
+    // Nothing held
+    pub fn wait(self, frames: usize) -> InputMacro {
+        self.hold(&[], frames)
+    }
+
+    pub fn then(mut self, other: &InputMacro) -> InputMacro {
+        self.frames.extend_from_slice(&other.frames);
+        self
+    }
+
+    pub fn frames(&self) -> &[u8] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_frame_by_frame() {
+        let tap = InputMacro::new().press(Button::A, 2).wait(1);
+        let combo = tap.clone().hold(&[Button::Start, Button::Select], 1).then(&tap);
+        let (a, both) = (Button::A.mask(), Button::Start.mask() | Button::Select.mask());
+        assert_eq!(combo.frames(), &[a, a, 0, both, a, a, 0]);
+        assert_eq!(combo.len(), 7);
+        assert!(InputMacro::new().is_empty());
+    }
+}
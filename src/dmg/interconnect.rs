@@ -1,23 +1,67 @@
 use super::ppu::Ppu;
 use super::cart::Cart;
 use super::timer::Timer;
+use super::serial::Serial;
+use super::interrupts::InterruptController;
+use super::apu::Apu;
 use super::gamepad::Gamepad;
-use super::console::VideoSink;
+use super::console::{Frame, VideoSink};
+use super::state::{StateError, StateReader, StateWriter};
+use super::error::BusError;
+use super::stats::FrameStats;
+use super::config::AccuracyLevel;
+use super::memmap;
+use super::bess::ForeignState;
+use super::console::Model;
+use super::meminit::{self, MemoryInit, Region};
+use super::debugger::BankedAddr;
+use super::vram_log::VramWrite;
+use super::events::{Event, TimedEvent};
+use std::mem;
 
 const RAM_SIZE: usize = 32 * 1024; // Memory for the last 32KB as first 32KB is for ROM
 const ZERO_PAGE: usize = 0x7f;
+// OAM DMA copies a byte every 4 cycles
+const DMA_CYCLES: u32 = 160 * 4;
 
 pub struct Interconnect {
     pub cart: Cart,
-    ppu: Ppu,
+    pub ppu: Ppu,
     ram: Box<[u8]>,      
     zero_page: Box<[u8]>,
     ppu_dma: u8, // DMA Transfer and Start Address, 0xFF46
-    pub int_enable: u8,
-    pub int_flags: u8,
+    pub interrupts: InterruptController, // IF, IE and IME
     pub gamepad: Gamepad,
     timer: Timer,
-    // TODO: Sound Processing unit
+    pub serial: Serial,
+    pub apu: Apu,
+    boot_rom: Option<Box<[u8]>>, // Mapped over 0x0000 - 0x00FF until 0xFF50 is written
+    boot_rom_mapped: bool,
+    bus_errors: Vec<BusError>, // one of each kind, so a misbehaving game cannot flood the log
+    pub stats: FrameStats, // for the frame in progress, see stats.rs
+    accuracy: AccuracyLevel,
+    // Only tracked when cycle accurate, see read()
+    last_bus: u8,       // last value read or written
+    dma_cycles: Option<u32>, // cycles into the OAM DMA in progress
+    // Only while an instruction runs cycle accurate, see begin_instruction
+    instruction_cycles: Option<u32>, // of the instruction, already run by the rest of the machine
+    frame_ready: bool,               // the PPU finished a frame during the instruction
+    // What RAM holds at power on, see meminit.rs
+    memory_init: MemoryInit,
+    model: Model,
+    instruction_pc: u16, // of the instruction running, see set_instruction_pc
+    vram_log: Option<Vec<VramWrite>>, // only while recording, see vram_log.rs
+    events: Option<Vec<TimedEvent>>, // only while recording, see events.rs
+    logged_held: u8, // the buttons held as the event log last had them
+}
+
+// Notes a finished frame without drawing it, for frames that finish mid-instruction
+struct FrameLatch(bool);
+
+impl VideoSink for FrameLatch {
+    fn frame_available(&mut self, _frame: &Frame) {
+        self.0 = true;
+    }
 }
 
 impl Interconnect {
@@ -26,26 +70,225 @@ impl Interconnect {
         Interconnect {
             cart: cart,
             ppu: Ppu::new(),
-            // spu: spu
             timer: Timer::new(),
+            serial: Serial::new(),
+            apu: Apu::new(),
             ram: vec![0; RAM_SIZE].into_boxed_slice(),
             zero_page: vec![0; ZERO_PAGE].into_boxed_slice(),
             ppu_dma: 0,
-            int_enable: 0,
-            int_flags: 0,
+            interrupts: InterruptController::new(),
             gamepad: Gamepad::new(),
+            boot_rom: None,
+            boot_rom_mapped: false,
+            bus_errors: Vec::new(),
+            stats: FrameStats::default(),
+            accuracy: AccuracyLevel::default(),
+            last_bus: 0xFF,
+            dma_cycles: None,
+            instruction_cycles: None,
+            frame_ready: false,
+            memory_init: MemoryInit::default(),
+            model: Model::Dmg,
+            instruction_pc: 0,
+            vram_log: None,
+            events: None,
+            logged_held: 0,
         }
     }
 
+    pub fn accuracy(&self) -> AccuracyLevel {
+        self.accuracy
+    }
+
+    pub fn set_accuracy(&mut self, accuracy: AccuracyLevel) {
+        self.accuracy = accuracy;
+        self.ppu.set_accuracy(accuracy);
+        self.apu.set_accuracy(accuracy);
+    }
+
+    // 16 bit inc/dec put the register on the address bus, which trips the OAM bug when it
+    // points into OAM (see Ppu::corrupt_oam). Only modelled when cycle accurate.
+    pub fn idu_access(&mut self, addr: u16) {
+        if self.accuracy == AccuracyLevel::CycleAccurate {
+            self.ppu.corrupt_oam(addr);
+        }
+    }
+
+    pub fn bus_errors(&self) -> &[BusError] {
+        &self.bus_errors
+    }
+
+    // Bad cartridge accesses read as open bus (0xFF) and writes are dropped. Each kind of error
+    // is reported the first time it happens only.
+    fn bus_error(&mut self, err: BusError) {
+        let seen = self.bus_errors.iter()
+            .any(|e| mem::discriminant(e) == mem::discriminant(&err));
+        if !seen {
+            warn!(target: "gbrust::mbc", "{} (further errors of this kind are ignored)", err);
+            self.bus_errors.push(err);
+        }
+    }
+
+    fn read_cart(&mut self, result: Result<u8, BusError>) -> u8 {
+        result.unwrap_or_else(|err| {
+            self.bus_error(err);
+            self.open_bus(0xFF)
+        })
+    }
+
+    // What reading nothing gives: whatever was last on the bus when cycle accurate, otherwise
+    // a fixed value
+    fn open_bus(&self, default: u8) -> u8 {
+        if self.accuracy == AccuracyLevel::CycleAccurate {
+            self.last_bus
+        } else {
+            default
+        }
+    }
+
+    // While OAM DMA runs it owns the bus, and the CPU can only use HRAM and I/O. Reads
+    // elsewhere see the byte the DMA is copying, and writes are lost.
+    fn dma_conflict(&self, addr: u16) -> Option<u16> {
+        match self.dma_cycles {
+            Some(cycles) if addr < 0xFF00 => Some(((self.ppu_dma as u16) << 8) + (cycles / 4) as u16),
+            _ => None,
+        }
+    }
+
+    pub fn set_boot_rom(&mut self, boot_rom: Box<[u8]>) {
+        self.boot_rom = Some(boot_rom);
+        self.boot_rom_mapped = true;
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    // Takes effect at the next power on, or init_memory
+    pub fn set_memory_init(&mut self, init: MemoryInit, model: Model) {
+        self.memory_init = init;
+        self.model = model;
+    }
+
+    // Work RAM, high RAM and VRAM as at power on
+    pub fn init_memory(&mut self) {
+        let (init, model) = (self.memory_init, self.model);
+        meminit::fill(init, model, Region::Wram, &mut self.ram[..0x2000]);
+        meminit::fill(init, model, Region::Hram, &mut self.zero_page);
+        meminit::fill(init, model, Region::Vram, self.ppu.vram_mut());
+    }
+
+    // Power-on RAM and divider phase from seed instead, see Console::set_entropy_hook
+    pub fn perturb(&mut self, seed: u64) {
+        let (init, model) = (MemoryInit::Seeded(seed), self.model);
+        meminit::fill(init, model, Region::Wram, &mut self.ram[..0x2000]);
+        meminit::fill(init, model, Region::Hram, &mut self.zero_page);
+        meminit::fill(init, model, Region::Vram, self.ppu.vram_mut());
+        self.timer.set_divider((seed >> 48) as u16);
+    }
+
+    // Puts another cartridge in, forgetting the old one's bus errors. Reset after.
+    pub fn swap_cart(&mut self, cart: Cart) -> Cart {
+        self.bus_errors.clear();
+        mem::replace(&mut self.cart, cart)
+    }
+
+    // Everything back to power on, with the boot ROM mapped again if there is one. Cartridge RAM
+    // is kept, work RAM, high RAM, VRAM and OAM only if keep_memory.
+    pub fn reset(&mut self, keep_memory: bool) {
+        self.cart.reset();
+        self.ppu.reset(keep_memory);
+        if !keep_memory {
+            self.init_memory();
+        }
+        self.ppu_dma = 0;
+        let script = self.interrupts.script().cloned();
+        self.interrupts = InterruptController::new();
+        self.interrupts.set_script(script);
+        self.gamepad.reset();
+        self.timer = Timer::new();
+        self.serial.reset();
+        self.apu.reset();
+        self.boot_rom_mapped = self.boot_rom.is_some();
+        self.last_bus = 0xFF;
+        self.dma_cycles = None;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.cart.save_state(state);
+        self.ppu.save_state(state);
+        state.write_bytes(&self.ram);
+        state.write_bytes(&self.zero_page);
+        state.write_u8(self.ppu_dma);
+        self.interrupts.save_state(state);
+        self.gamepad.save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.apu.save_state(state);
+        state.write_bool(self.boot_rom_mapped);
+        state.write_u8(self.last_bus);
+        state.write_bool(self.dma_cycles.is_some());
+        state.write_u32(self.dma_cycles.unwrap_or(0));
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cart.load_state(state)?;
+        self.ppu.load_state(state)?;
+        state.read_into(&mut self.ram, "ram")?;
+        state.read_into(&mut self.zero_page, "zero page")?;
+        self.ppu_dma = state.read_u8()?;
+        self.interrupts.load_state(state)?;
+        self.gamepad.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.apu.load_state(state)?;
+        // Only map the boot ROM back in if we actually have one
+        self.boot_rom_mapped = state.read_bool()? && self.boot_rom.is_some();
+        self.last_bus = state.read_u8()?;
+        let dma_active = state.read_bool()?;
+        let dma_cycles = state.read_u32()?;
+        self.dma_cycles = if dma_active { Some(dma_cycles) } else { None };
+        Ok(())
+    }
+
     pub fn read(&mut self, addr: u16) -> u8 {
+        self.bus_cycle();
+        if self.accuracy != AccuracyLevel::CycleAccurate {
+            return self.read_mapped(addr);
+        }
+        let val = match self.dma_conflict(addr) {
+            Some(dma_addr) => self.read_mapped(dma_addr),
+            None => self.read_mapped(addr),
+        };
+        self.last_bus = val;
+        val
+    }
+
+    // Reads without taking a bus cycle, for looking at memory from outside the running program.
+    // Bad cartridge accesses are not reported and P1 does not poll the input.
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x00FF if self.boot_rom_mapped => self.read_mapped(addr),
+            0x0000..=0x7FFF => self.cart.read(addr).unwrap_or(0xFF),
+            0xA000..=0xBFFF => self.cart.read_ram(addr).unwrap_or(0xFF),
+            0xFF00 => self.gamepad.read(),
+            _ => self.read_mapped(addr),
+        }
+    }
+
+    fn read_mapped(&mut self, addr: u16) -> u8 {
         match addr {
             // For more information: http://gameboy.mongenel.com/dmg/asmmemmap.html
-            0x0000..= 0x7fff => self.cart.read(addr), // Cartridge ROM
+            // Boot ROM, until the boot ROM unmaps itself
+            0x0000..= 0x00ff if self.boot_rom_mapped => {
+                self.boot_rom.as_ref().map_or(0xff, |boot_rom| boot_rom[addr as usize])
+            }
+            0x0000..= 0x7fff => { let val = self.cart.read(addr); self.read_cart(val) } // Cartridge ROM
             0x8000..= 0x9fff => self.ppu.read(addr), // Picture Processing Unit
-            0xa000..= 0xbfff => self.cart.read_ram(addr), // Cartridge swappable RAM, CHECK AGAIN
+            0xa000..= 0xbfff => { let val = self.cart.read_ram(addr); self.read_cart(val) } // Cartridge swappable RAM, CHECK AGAIN
             0xc000..= 0xdfff => self.ram[(addr - 0xc000) as usize], // Internal RAM
             // Might cause problems in GBC implementation but for DMG should be ok
-            0xe000..= 0xfdff => self.read(addr - 0xe000 + 0xc000), 
+            0xe000..= 0xfdff => self.read_mapped(addr - 0xe000 + 0xc000), 
             // Echo memory. Just copies over 0xc000..oxcfff
 
             // PPU addresses
@@ -60,6 +303,7 @@ impl Interconnect {
             0xFEA0..= 0xFEFF | 0xFF68 | 0xFF69 => {
                         self.ppu.read(addr)
             }
+            0xFF6C if self.ppu.has_opri() => self.ppu.read(addr),
 
             // Unused memory
             0xfea0..= 0xfeff => 0,
@@ -67,10 +311,13 @@ impl Interconnect {
             // 0xFF00 - 0xFF7F: Hardware I/O Registers
             // Details http://marc.rawer.de/Gameboy/Docs/GBCPUman.pdf pg35
             // 0xFF00: Gamepad (TODO)
-            0xff00 => self.gamepad.read(),
+            0xff00 => {
+                self.gamepad.poll(self.ppu.ly(), self.ppu.clock());
+                self.gamepad.read()
+            }
 
             // 0xFF01 - 0xFF02: serial I/O, used for linking up to other gameboy
-            0xff01..= 0xff02 => 0,
+            0xff01..= 0xff02 => self.serial.read(addr),
             
             // 0xFF04: DIV/Divider Register, incremented 16384 times a second.
             //         Needs to be implemented in timer.
@@ -83,13 +330,14 @@ impl Interconnect {
             // 0xFF08 - 0xFFOE unused
 
             // 0xFFOF - IF / Interrupt Flag
-            0xff0f => self.int_flags,
+            0xff0f => self.interrupts.flags(),
 
             // 0xFFFF - IE / Interupt Enable
-            0xffff => self.int_enable,
+            0xffff => self.interrupts.enable(),
 
-            // 0xFF10 - 0xFF3F: SPU (Not implemented yet)
-            0xff10..= 0xff3f => 0,
+            // 0xFF10 - 0xFF3F: APU registers and wave RAM
+            0xff10..= 0xff3f => self.apu.read(addr),
+            0xff76..= 0xff77 if self.apu.has_pcm_registers() => self.apu.read_pcm(addr),
 
             // http://marc.rawer.de/Gameboy/Docs/GBCPUman.pdf pg 55
             0xff46 => self.ppu_dma,
@@ -98,18 +346,38 @@ impl Interconnect {
             // 0xff4d => 0, 
             0xff80..= 0xfffe => self.zero_page[(addr - 0xff80) as usize],
             
-            _ => 0 //panic!("Read: addr not in range: 0x{:x}", addr),
+            _ => self.open_bus(0) //panic!("Read: addr not in range: 0x{:x}", addr),
         }
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
+        self.bus_cycle();
+        if self.accuracy == AccuracyLevel::CycleAccurate {
+            self.last_bus = val;
+            if self.dma_conflict(addr).is_some() {
+                return;
+            }
+        }
+        if let 0xFF00..=0xFF7F | 0xFFFF = addr {
+            trace!(target: "gbrust::io", "write 0x{:02x} to {:04x} ({})",
+                   val, addr, memmap::label(addr, self.cart.rom_bank()));
+        }
         match addr {
             // Cartridge rom
-            0x0000..= 0x7FFF => self.cart.write(addr, val),
+            0x0000..= 0x7FFF => {
+                let bank = self.cart.rom_bank();
+                if let Err(err) = self.cart.write(addr, val) { self.bus_error(err) }
+                if self.events.is_some() && self.cart.rom_bank() != bank {
+                    self.log_event(Event::RomBank(self.cart.rom_bank()));
+                }
+            }
             // character ram (basically tile data)
-            0x8000..= 0x9FFF => self.ppu.write(addr, val),
+            0x8000..= 0x9FFF => {
+                self.log_vram_write(addr, val);
+                self.ppu.write(addr, val);
+            }
             // Cartridge RAM to switch, now not available
-            0xA000..= 0xBFFF => self.cart.write_ram(addr, val),
+            0xA000..= 0xBFFF => if let Err(err) = self.cart.write_ram(addr, val) { self.bus_error(err) },
             // Internal RAM (bank 0)
             0xC000..= 0xCFFF => self.ram[(addr - 0xc000) as usize] = val,
             // Internal RAM (Now fixed, will become switchable
@@ -119,21 +387,22 @@ impl Interconnect {
 
             0xFF00 => self.gamepad.write(val),
 
-            // Reserved memory for serial I/O Port
-            0xFF01..= 0xFF02 => {},
+            // Serial I/O Port
+            0xFF01..= 0xFF02 => self.serial.write(addr, val),
 
             //0xFF04..= 0xFF07 =>self.timer.write(addr, val),
             0xFF04..= 0xFF07 => self.timer.write(addr, val),
 
             // Serial Interrupt
-            0xFF0F => self.int_flags = val,
+            0xFF0F => self.interrupts.set_flags(val),
             
-            //0xFF10..= 0xFF3F => self.spu.write(addr, val),
-            0xFF10..=0xFF3F => {},
+            // Sound registers and wave RAM
+            0xFF10..= 0xFF3F => self.apu.write(addr, val),
             
             // DMA Transfer, val is start address of DMA Transfer
             0xFF46 => {
                 self.ppu_dma = val;
+                self.log_event(Event::Dma { source: (val as u16) << 8 });
                 self.ppu_dma_transfer()
             }
 
@@ -146,6 +415,11 @@ impl Interconnect {
             0xFEA0..= 0xFEFF | 0xFF68 | 0xFF69 => {
                         self.ppu.write(addr, val);
             }
+            0xFF6C if self.ppu.has_opri() => self.ppu.write(addr, val),
+
+            // Writing a non-zero value unmaps the boot ROM for good
+            0xFF50 if val != 0 => self.boot_rom_mapped = false,
+            0xFF50 => {},
 
             // Speedswitch TODO, not implemented yet. Uses unused mem.
             // 0xFF4D => {},
@@ -156,25 +430,163 @@ impl Interconnect {
             // Set hwram
             0xFF80..= 0xFFFE => self.zero_page[(addr - 0xFF80) as usize] = val,
             // Set interrupt enable flag 
-            0xFFFF => self.int_enable = val,
+            0xFFFF => self.interrupts.set_enable(val),
             _ => {} // panic!("Write: addr not in range!! 0x{:x} - val: 0x{:x}", addr, val),
         }
     }
     
-    pub fn cycle_flush(&mut self, cycle_count: u32, video_sink: &mut dyn VideoSink) {
-        // Obtain Interrupts object from ppu_ints, timer_ints, gamepad_ints. These will be
-        // interrupts that are requested.
-        let ppu_ints = self.ppu.cycle_flush(cycle_count, video_sink);
-        let timer_ints = self.timer.cycle_flush(cycle_count);
-        let gamepad_ints = self.gamepad.cycle_flush(cycle_count);
+    // Memory and IO registers from another emulator's state, see bess.rs. Sizes that differ from
+    // gbrust's are cut or left short. Devices keep their internal timing.
+    pub fn load_foreign(&mut self, state: &ForeignState) {
+        fn copy(to: &mut [u8], from: &[u8]) {
+            let len = to.len().min(from.len());
+            to[..len].copy_from_slice(&from[..len]);
+        }
+        copy(&mut self.ram[..0x2000], &state.wram);
+        copy(&mut self.zero_page, &state.hram);
+        for (offset, &val) in state.vram.iter().take(0x2000).enumerate() {
+            self.ppu.write(0x8000 + offset as u16, val);
+        }
+        for (offset, &val) in state.oam.iter().take(0xA0).enumerate() {
+            self.ppu.write(0xFE00 + offset as u16, val);
+        }
+
+        // Cartridge RAM goes in bank by bank through the MBC, then the MBC gets its registers
+        // back as they were. Carts without banking refuse the bank switches, which is fine.
+        let _ = self.cart.write(0x0000, 0x0A);
+        let _ = self.cart.write(0x6000, 0x01);
+        for (bank, chunk) in state.cart_ram.chunks(0x2000).enumerate() {
+            let _ = self.cart.write(0x4000, bank as u8);
+            for (offset, &val) in chunk.iter().enumerate() {
+                let _ = self.cart.write_ram(0xA000 + offset as u16, val);
+            }
+        }
+        self.cart.reset();
+        for &(addr, val) in state.mbc_writes.iter().filter(|&&(addr, _)| addr < 0x8000) {
+            let _ = self.cart.write(addr, val);
+        }
+        if let Some(ref rtc) = state.rtc {
+            self.cart.set_rtc(rtc);
+        }
+
+        let io = |addr: u16| state.io[(addr - 0xFF00) as usize];
+        self.gamepad.write(io(0xFF00));
+        for addr in (0xFF05..=0xFF07).chain(0xFF40..=0xFF4B) {
+            match addr {
+                0xFF44 => {} // LY is the PPU's
+                0xFF46 => self.ppu_dma = io(addr),
+                0xFF05..=0xFF07 => self.timer.write(addr, io(addr)),
+                _ => self.ppu.write(addr, io(addr)),
+            }
+        }
+        // Power first, and no channel is triggered
+        self.apu.write(0xFF26, io(0xFF26));
+        for addr in 0xFF10..=0xFF3F {
+            let val = match addr {
+                0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => io(addr) & 0x7F,
+                0xFF26 => continue,
+                _ => io(addr),
+            };
+            self.apu.write(addr, val);
+        }
+        self.interrupts.set_flags(io(0xFF0F));
+        self.interrupts.set_enable(state.ie);
+        self.boot_rom_mapped = self.boot_rom_mapped && io(0xFF50) == 0;
+        self.dma_cycles = None;
+    }
+
+    // The CPU says where each instruction starts, so writes can be traced back to it
+    pub fn set_instruction_pc(&mut self, pc: u16) {
+        self.instruction_pc = pc;
+    }
+
+    // Starts (or stops) recording VRAM writes, see vram_log.rs
+    pub fn record_vram_writes(&mut self, enabled: bool) {
+        self.vram_log = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_vram_writes(&mut self) -> Vec<VramWrite> {
+        self.vram_log.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    // Starts (or stops) recording events, see events.rs
+    pub fn record_events(&mut self, enabled: bool) {
+        self.events = if enabled { Some(Vec::new()) } else { None };
+        self.serial.take_transfer();
+        self.logged_held = self.gamepad.held();
+    }
+
+    pub fn take_events(&mut self) -> Vec<TimedEvent> {
+        self.events.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    pub fn log_event(&mut self, event: Event) {
+        if let Some(ref mut events) = self.events {
+            events.push(TimedEvent { cycle: self.ppu.clock(), ly: self.ppu.ly(), event });
+        }
+    }
 
-        //println!("Carrying out ints");
+    fn log_vram_write(&mut self, addr: u16, val: u8) {
+        if let Some(ref mut log) = self.vram_log {
+            let pc = BankedAddr::resolve(self.instruction_pc, self.cart.rom_bank());
+            log.push(VramWrite { cycle: self.ppu.clock(), ly: self.ppu.ly(), addr, val, pc });
+        }
+    }
+
+    // Cycle accurate, the rest of the machine catches up with the CPU at every bus access it makes
+    // instead of running after the instruction, so reads and writes land on their own machine
+    // cycle. Every access takes a cycle, the opcode fetch included, and end_instruction runs
+    // the cycles left over. Otherwise the whole instruction runs in end_instruction.
+    pub fn begin_instruction(&mut self) {
+        if self.accuracy == AccuracyLevel::CycleAccurate {
+            self.instruction_cycles = Some(0);
+        }
+    }
+
+    fn bus_cycle(&mut self) {
+        if let Some(cycles) = self.instruction_cycles {
+            self.instruction_cycles = Some(cycles + 1);
+            let mut latch = FrameLatch(false);
+            self.cycle_flush(1, &mut latch);
+            self.frame_ready |= latch.0;
+        }
+    }
 
-        // summarize all requested interrupts
-        let all_interrupts = ppu_ints | timer_ints | gamepad_ints;
+    // cycles is what the instruction took in all. A frame finished during the instruction
+    // reaches video_sink now, the PPU is in VBlank and leaves it alone until then.
+    pub fn end_instruction(&mut self, cycles: u32, video_sink: &mut dyn VideoSink) {
+        let run = self.instruction_cycles.take().unwrap_or(0);
+        if cycles > run {
+            self.cycle_flush(cycles - run, video_sink);
+        }
+        if mem::take(&mut self.frame_ready) {
+            video_sink.frame_available(&Frame::dmg(self.ppu.framebuffer()));
+        }
+    }
 
-        // send all requested interrupts. .bits is a bitflags-supported method
-        self.int_flags |= all_interrupts.bits;
+    pub fn cycle_flush(&mut self, cycle_count: u32, video_sink: &mut dyn VideoSink) {
+        // Devices request their interrupts from the controller as they go
+        self.ppu.cycle_flush(cycle_count, video_sink, &mut self.interrupts);
+        self.timer.cycle_flush(cycle_count, &mut self.interrupts);
+        self.serial.cycle_flush(cycle_count, &mut self.interrupts);
+        self.gamepad.cycle_flush(cycle_count, &mut self.interrupts);
+        self.cart.cycle_flush(cycle_count);
+        if self.events.is_some() {
+            if let Some((sent, received)) = self.serial.take_transfer() {
+                self.log_event(Event::Serial { sent, received });
+            }
+            let held = self.gamepad.held();
+            if held != self.logged_held {
+                self.logged_held = held;
+                self.log_event(Event::Input(held));
+            }
+        }
+        self.stats.audio_samples += self.apu.cycle_flush(cycle_count) as u64;
+        self.interrupts.advance_script(cycle_count);
+        if let Some(cycles) = self.dma_cycles {
+            let cycles = cycles + cycle_count;
+            self.dma_cycles = if cycles < DMA_CYCLES { Some(cycles) } else { None };
+        }
     }
 
     fn ppu_dma_transfer(&mut self) {
@@ -188,15 +600,64 @@ impl Interconnect {
 
         let dma_start = (self.ppu_dma as u16) << 8;
         let dma_end = dma_start | 0x009f; //127, size of DMA
+        debug!(target: "gbrust::dma", "OAM DMA from 0x{:04x}", dma_start);
+        self.stats.dma_transfers += 1;
 
         // OAM_SIZE in ppu is the address for OAM, 0x100
         let mut oam = [0; super::ppu::OAM_SIZE];
 
         for a in dma_start..dma_end {
-            oam[(a - dma_start) as usize] = self.read(a)
+            oam[(a - dma_start) as usize] = self.read_mapped(a)
         }
 
         // just sets OAM memory
         self.ppu.oam_dma_transfer(oam);
+
+        // The copy above is instant, but the bus stays busy for the real duration
+        if self.accuracy == AccuracyLevel::CycleAccurate {
+            self.dma_cycles = Some(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::console::NoVideo;
+
+    // 32KB ROM only cartridge, no external RAM
+    fn interconnect(accuracy: AccuracyLevel) -> Interconnect {
+        let cart = Cart::new(vec![0; 0x8000].into_boxed_slice(), None).unwrap();
+        let mut interconnect = Interconnect::new(cart);
+        interconnect.set_accuracy(accuracy);
+        interconnect
+    }
+
+    #[test]
+    fn open_bus_and_dma_conflicts_when_cycle_accurate() {
+        let mut balanced = interconnect(AccuracyLevel::Balanced);
+        balanced.write(0xC000, 0x42);
+        assert_eq!(balanced.read(0xC000), 0x42);
+        assert_eq!(balanced.read(0xA000), 0xFF);
+
+        let mut accurate = interconnect(AccuracyLevel::CycleAccurate);
+        accurate.write(0xC000, 0x42);
+        assert_eq!(accurate.read(0xC000), 0x42);
+        assert_eq!(accurate.read(0xA000), 0x42);
+
+        for i in 0..0xA0 {
+            accurate.write(0xC100 + i, i as u8);
+        }
+        accurate.write(0xFF80, 0x99);
+        accurate.write(0xFF46, 0xC1);
+        assert_eq!(accurate.read(0xC000), 0x00);
+        accurate.cycle_flush(8, &mut NoVideo);
+        assert_eq!(accurate.read(0xC000), 0x02);
+        assert_eq!(accurate.read(0xFF80), 0x99); // HRAM is still there
+        accurate.write(0xC000, 0x11);            // lost
+
+        accurate.cycle_flush(DMA_CYCLES, &mut NoVideo);
+        assert_eq!(accurate.read(0xC000), 0x42);
+        assert_eq!(accurate.read(0xFE05), 0x05);
     }
 }
@@ -0,0 +1,243 @@
+// Interrupt controller.
+// Owns the three pieces of interrupt state: IF (0xFF0F, what was requested), IE (0xFFFF, what
+// may be taken) and IME, the CPU's master switch set by EI/DI/RETI. Devices request interrupts
+// here as they flush their cycles, and the CPU asks for the pending one between instructions.
+// IME only matters for taking an interrupt: any pending interrupt still wakes the CPU from HALT.
+//
+// For tests, an InterruptScript raises chosen interrupts at chosen cycles and can silence the
+// devices, so interrupt priority and HALT can be tested without getting the timer, serial port
+// or PPU to fire at the right moment. It is public for other crates' test harnesses but hidden
+// from the docs, and never part of save states.
+
+use super::state::{StateError, StateReader, StateWriter};
+
+// In priority order, and in bit order in IF/IE. New sources go into ALL, which everything else
+// (priority, vectors, pending) follows.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    pub const ALL: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LcdStat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    pub fn bit(self) -> u8 {
+        self as u8
+    }
+
+    // Its bit in IF and IE
+    pub fn mask(self) -> u8 {
+        1 << self.bit()
+    }
+
+    // Where the CPU jumps to take it
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * self.bit() as u16
+    }
+
+    pub fn from_vector(vector: u16) -> Option<Interrupt> {
+        Interrupt::ALL.iter().copied().find(|interrupt| interrupt.vector() == vector)
+    }
+}
+
+#[doc(hidden)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InterruptScript {
+    cycle: u64, // cycles run since the script was installed
+    injections: Vec<(u64, Interrupt)>, // still to come, by cycle
+    silenced: u8, // sources whose device requests are dropped, by mask
+    raised: Vec<(u64, Interrupt)>, // every request that reached IF, with its cycle
+}
+
+impl InterruptScript {
+    pub fn new() -> InterruptScript {
+        InterruptScript::default()
+    }
+
+    // Raises interrupt once cycle cycles have run, at the end of the instruction that gets there
+    pub fn inject(mut self, cycle: u64, interrupt: Interrupt) -> InterruptScript {
+        let index = self.injections.partition_point(|&(at, _)| at <= cycle);
+        self.injections.insert(index, (cycle, interrupt));
+        self
+    }
+
+    // Drops requests from the device behind interrupt. Injections still go through.
+    pub fn silence(mut self, interrupt: Interrupt) -> InterruptScript {
+        self.silenced |= interrupt.mask();
+        self
+    }
+
+    pub fn silence_all(self) -> InterruptScript {
+        Interrupt::ALL.iter().fold(self, |script, &interrupt| script.silence(interrupt))
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    // Requests that were let through, injected or not, in order. Device requests get the cycle
+    // the instruction they came during started at.
+    pub fn raised(&self) -> &[(u64, Interrupt)] {
+        &self.raised
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InterruptController {
+    ime: bool,
+    enable: u8,
+    flags: u8,
+    script: Option<Box<InterruptScript>>,
+}
+
+impl InterruptController {
+    // At power on: nothing requested, nothing enabled
+    pub fn new() -> InterruptController {
+        InterruptController::default()
+    }
+
+    pub fn request(&mut self, interrupt: Interrupt) {
+        if let Some(ref mut script) = self.script {
+            if script.silenced & interrupt.mask() != 0 {
+                return;
+            }
+            script.raised.push((script.cycle, interrupt));
+        }
+        self.flags |= interrupt.mask();
+    }
+
+    #[doc(hidden)]
+    pub fn set_script(&mut self, script: Option<InterruptScript>) {
+        self.script = script.map(Box::new);
+    }
+
+    #[doc(hidden)]
+    pub fn script(&self) -> Option<&InterruptScript> {
+        self.script.as_deref()
+    }
+
+    // Moves the script on by cycles, raising what is due
+    pub fn advance_script(&mut self, cycles: u32) {
+        let script = match self.script {
+            Some(ref mut script) => script,
+            None => return,
+        };
+        script.cycle += cycles as u64;
+        let due = script.injections.partition_point(|&(at, _)| at <= script.cycle);
+        for (_, interrupt) in script.injections.drain(..due) {
+            script.raised.push((script.cycle, interrupt));
+            self.flags |= interrupt.mask();
+        }
+    }
+
+    // Everything requested and enabled, highest priority first. Does not look at IME.
+    pub fn pending_all(&self) -> impl Iterator<Item = Interrupt> {
+        let pending = self.flags & self.enable;
+        Interrupt::ALL.iter().copied().filter(move |interrupt| pending & interrupt.mask() != 0)
+    }
+
+    // The one the CPU takes next
+    pub fn pending(&self) -> Option<Interrupt> {
+        self.pending_all().next()
+    }
+
+    // The CPU took interrupt: its request is cleared, and so is IME until the handler turns it
+    // back on
+    pub fn acknowledge(&mut self, interrupt: Interrupt) {
+        self.flags &= !interrupt.mask();
+        self.ime = false;
+    }
+
+    pub fn master_enabled(&self) -> bool {
+        self.ime
+    }
+
+    pub fn set_master_enabled(&mut self, enabled: bool) {
+        self.ime = enabled;
+    }
+
+    // IF, 0xFF0F
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+    }
+
+    // IE, 0xFFFF
+    pub fn enable(&self) -> u8 {
+        self.enable
+    }
+
+    pub fn set_enable(&mut self, enable: u8) {
+        self.enable = enable;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ime);
+        state.write_u8(self.enable);
+        state.write_u8(self.flags);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ime = state.read_bool()?;
+        self.enable = state.read_u8()?;
+        self.flags = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_follows_priority_and_enable() {
+        let mut interrupts = InterruptController::new();
+        interrupts.request(Interrupt::Joypad);
+        interrupts.request(Interrupt::Timer);
+        assert_eq!(interrupts.pending(), None);
+
+        interrupts.request(Interrupt::VBlank);
+        interrupts.set_enable(0x1E);
+        assert_eq!(interrupts.pending_all().collect::<Vec<_>>(), [Interrupt::Timer, Interrupt::Joypad]);
+        assert_eq!(interrupts.pending(), Some(Interrupt::Timer));
+        assert_eq!(Interrupt::Timer.vector(), 0x50);
+
+        interrupts.set_master_enabled(true);
+        interrupts.acknowledge(Interrupt::Timer);
+        assert!(!interrupts.master_enabled());
+        assert_eq!(interrupts.pending(), Some(Interrupt::Joypad));
+        assert_eq!(interrupts.flags(), 0x11);
+    }
+
+    #[test]
+    fn scripts_inject_and_silence() {
+        let mut interrupts = InterruptController::new();
+        let script = InterruptScript::new()
+            .inject(100, Interrupt::Serial)
+            .inject(50, Interrupt::VBlank)
+            .silence(Interrupt::VBlank);
+        interrupts.set_script(Some(script));
+        interrupts.request(Interrupt::VBlank);
+        assert_eq!(interrupts.flags(), 0);
+
+        interrupts.advance_script(60);
+        interrupts.request(Interrupt::Timer);
+        assert_eq!(interrupts.flags(), Interrupt::VBlank.mask() | Interrupt::Timer.mask());
+        interrupts.advance_script(60);
+        assert_eq!(interrupts.script().unwrap().raised(),
+                   [(60, Interrupt::VBlank), (60, Interrupt::Timer), (120, Interrupt::Serial)]);
+    }
+}
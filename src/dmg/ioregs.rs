@@ -0,0 +1,225 @@
+// IO registers spelled out, for state dumps that make sense without PanDocs open.
+// A snapshot holds the raw value of every IO register, as the CPU would read it. decode turns
+// each into its fields, e.g. LCDC 0x91 becomes lcd=on window_map=9800 ... bg=on. Registers
+// without fields (SCY, LY, ...) just show their value.
+//
+// Text is one register per line:
+//
+//     LCDC ff40 = 91  lcd=on window_map=9800 window=off tiles=8000 bg_map=9800 obj_size=8x8 obj=off bg=on
+//
+// and JSON an object per register, in the same order, with the raw value and the fields as
+// strings: {"LCDC":{"raw":145,"lcd":"on",...},...}
+
+use std::fmt::Write;
+
+use super::memmap::register_name;
+
+// In the order they are dumped
+const DECODED: [u16; 23] = [
+    0xFF40, 0xFF41, 0xFF42, 0xFF43, 0xFF44, 0xFF45, 0xFF46, 0xFF47, 0xFF48, 0xFF49, 0xFF4A, 0xFF4B,
+    0xFF00, 0xFF02, 0xFF04, 0xFF05, 0xFF06, 0xFF07, 0xFF0F, 0xFFFF,
+    0xFF24, 0xFF25, 0xFF26,
+];
+
+const INTERRUPTS: [&str; 5] = ["vblank", "stat", "timer", "serial", "joypad"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedRegister {
+    pub name: &'static str,
+    pub addr: u16,
+    pub raw: u8,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoSnapshot {
+    regs: [u8; 0x80], // 0xff00 - 0xff7f
+    ie: u8,
+}
+
+impl IoSnapshot {
+    // read is called once for every IO register and IE
+    pub fn new<F: FnMut(u16) -> u8>(mut read: F) -> IoSnapshot {
+        let mut regs = [0; 0x80];
+        for (offset, reg) in regs.iter_mut().enumerate() {
+            *reg = read(0xFF00 + offset as u16);
+        }
+        IoSnapshot { regs, ie: read(0xFFFF) }
+    }
+
+    pub fn get(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF00..=0xFF7F => self.regs[(addr - 0xFF00) as usize],
+            0xFFFF => self.ie,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn decode(&self) -> Vec<DecodedRegister> {
+        DECODED.iter().map(|&addr| {
+            let raw = self.get(addr);
+            DecodedRegister { name: register_name(addr).unwrap(), addr, raw, fields: fields(addr, raw) }
+        }).collect()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for reg in self.decode() {
+            write!(text, "{:<4} {:04x} = {:02x}", reg.name, reg.addr, reg.raw).unwrap();
+            if !reg.fields.is_empty() {
+                text.push(' ');
+            }
+            for (name, value) in &reg.fields {
+                write!(text, " {}={}", name, value).unwrap();
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{");
+        for (i, reg) in self.decode().iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(json, "\"{}\":{{\"raw\":{}", reg.name, reg.raw).unwrap();
+            for (name, value) in &reg.fields {
+                write!(json, ",\"{}\":\"{}\"", name, value).unwrap();
+            }
+            json.push('}');
+        }
+        json.push('}');
+        json
+    }
+}
+
+fn on_off(val: u8, bit: u8) -> String {
+    if val & (1 << bit) != 0 { "on" } else { "off" }.to_string()
+}
+
+fn pick(val: u8, bit: u8, clear: &str, set: &str) -> String {
+    if val & (1 << bit) != 0 { set } else { clear }.to_string()
+}
+
+// Interrupts whose bits are set, by name
+fn interrupts(val: u8) -> String {
+    let names: Vec<&str> = INTERRUPTS.iter().enumerate()
+        .filter(|&(bit, _)| val & (1 << bit) != 0)
+        .map(|(_, &name)| name)
+        .collect();
+    if names.is_empty() { "none".to_string() } else { names.join(",") }
+}
+
+// Shades 0 (lightest) - 3 for colours 0 - 3
+fn palette(val: u8) -> Vec<(&'static str, String)> {
+    ["c0", "c1", "c2", "c3"].iter().enumerate()
+        .map(|(colour, &name)| (name, ((val >> (colour * 2)) & 0b11).to_string()))
+        .collect()
+}
+
+fn fields(addr: u16, val: u8) -> Vec<(&'static str, String)> {
+    match addr {
+        0xFF40 => vec![
+            ("lcd", on_off(val, 7)),
+            ("window_map", pick(val, 6, "9800", "9c00")),
+            ("window", on_off(val, 5)),
+            ("tiles", pick(val, 4, "8800", "8000")),
+            ("bg_map", pick(val, 3, "9800", "9c00")),
+            ("obj_size", pick(val, 2, "8x8", "8x16")),
+            ("obj", on_off(val, 1)),
+            ("bg", on_off(val, 0)),
+        ],
+        0xFF41 => {
+            let mode = ["hblank", "vblank", "oam", "transfer"][(val & 0b11) as usize];
+            let sources: Vec<&str> = [(3, "hblank"), (4, "vblank"), (5, "oam"), (6, "lyc")].iter()
+                .filter(|&&(bit, _)| val & (1 << bit) != 0)
+                .map(|&(_, name)| name)
+                .collect();
+            vec![
+                ("mode", mode.to_string()),
+                ("ly_eq_lyc", pick(val, 2, "no", "yes")),
+                ("interrupts", if sources.is_empty() { "none".to_string() } else { sources.join(",") }),
+            ]
+        }
+        0xFF47..=0xFF49 => palette(val),
+        0xFF00 => {
+            let pressed = |names: [&str; 4]| -> String {
+                let held: Vec<&str> = (0..4).filter(|bit| val & (1 << bit) == 0).map(|bit| names[bit])
+                    .collect();
+                if held.is_empty() { "none".to_string() } else { held.join(",") }
+            };
+            let directions = val & 0x10 == 0;
+            let buttons = val & 0x20 == 0;
+            let select = match (directions, buttons) {
+                (true, true) => "both",
+                (true, false) => "directions",
+                (false, true) => "buttons",
+                (false, false) => "none",
+            };
+            let held = match (directions, buttons) {
+                (true, false) => pressed(["right", "left", "up", "down"]),
+                (false, true) => pressed(["a", "b", "select", "start"]),
+                _ => pressed(["0", "1", "2", "3"]),
+            };
+            vec![("select", select.to_string()), ("held", held)]
+        }
+        0xFF02 => vec![
+            ("transfer", pick(val, 7, "idle", "active")),
+            ("clock", pick(val, 0, "external", "internal")),
+        ],
+        0xFF07 => {
+            let hz = [4096, 262144, 65536, 16384][(val & 0b11) as usize];
+            vec![("timer", on_off(val, 2)), ("frequency", format!("{}Hz", hz))]
+        }
+        0xFF0F => vec![("requested", interrupts(val))],
+        0xFFFF => vec![("enabled", interrupts(val))],
+        0xFF24 => vec![
+            ("left_volume", ((val >> 4) & 0b111).to_string()),
+            ("right_volume", (val & 0b111).to_string()),
+        ],
+        0xFF25 => {
+            let side = |shift: u8| -> String {
+                let channels: Vec<String> = (0..4)
+                    .filter(|ch| val & (1 << (ch + shift)) != 0)
+                    .map(|ch| (ch + 1).to_string())
+                    .collect();
+                if channels.is_empty() { "none".to_string() } else { channels.join(",") }
+            };
+            vec![("left", side(4)), ("right", side(0))]
+        }
+        0xFF26 => vec![
+            ("sound", on_off(val, 7)),
+            ("ch1", on_off(val, 0)),
+            ("ch2", on_off(val, 1)),
+            ("ch3", on_off(val, 2)),
+            ("ch4", on_off(val, 3)),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(values: &[(u16, u8)]) -> IoSnapshot {
+        IoSnapshot::new(|addr| values.iter().find(|&&(at, _)| at == addr).map_or(0, |&(_, val)| val))
+    }
+
+    #[test]
+    fn spells_out_registers() {
+        let io = snapshot(&[(0xFF40, 0x91), (0xFF07, 0x05), (0xFF26, 0x83), (0xFFFF, 0x05), (0xFF44, 0x90)]);
+        let text = io.to_text();
+        assert!(text.contains("LCDC ff40 = 91  lcd=on window_map=9800 window=off tiles=8000 bg_map=9800 \
+                               obj_size=8x8 obj=off bg=on\n"), "{}", text);
+        assert!(text.contains("TAC  ff07 = 05  timer=on frequency=262144Hz\n"));
+        assert!(text.contains("NR52 ff26 = 83  sound=on ch1=on ch2=on ch3=off ch4=off\n"));
+        assert!(text.contains("IE   ffff = 05  enabled=vblank,timer\n"));
+        assert!(text.contains("LY   ff44 = 90\n"));
+
+        let json = io.to_json();
+        assert!(json.starts_with("{\"LCDC\":{\"raw\":145,\"lcd\":\"on\","));
+        assert!(json.contains("\"LY\":{\"raw\":144}"));
+    }
+}
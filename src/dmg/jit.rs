@@ -0,0 +1,685 @@
+// Dynamic recompiler, an experiment for fast-forward. Built with the `jit` feature.
+// Runs of ROM code that only work on registers are compiled to native code with Cranelift once
+// they have started HOT_RUNS times, and from then on run in one go instead of an instruction at a
+// time. What gets compiled:
+//
+//     nop, ld r,r', ld r,n, ld rr,nn, inc and dec of r and rr, the ALU ops on A with r or n,
+//     cpl, scf, ccf, and ending a block: jr, jr cc, jp nn, jp cc
+//
+// (hl) stands for memory, so instructions with it are left to the interpreter along with
+// everything else that touches the bus, as are blocks that would be a single instruction.
+// Blocks are keyed by address and the ROM bank mapped there, so switching banks picks other
+// blocks rather than running stale ones, and code run from RAM, which the game may rewrite,
+// always goes to the interpreter. Carts are swapped and the boot ROM unmapped through a reset,
+// which clears the cache.
+// The machine catches up after a whole block, not after each instruction, so interrupts and
+// timer reads can land a few cycles late. Good enough to fast-forward, not to check accuracy
+// against.
+
+use std::collections::HashMap;
+use std::mem;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use super::dmg_cpu::RegisterSnapshot;
+use super::error::JitError;
+use super::timing::expected_cycles;
+
+// Starts before a block is compiled
+const HOT_RUNS: u32 = 8;
+// Instructions in a block at most
+const MAX_BLOCK: usize = 32;
+
+// The registers as compiled code sees them
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct JitRegs {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+impl JitRegs {
+    pub fn from_snapshot(regs: &RegisterSnapshot) -> JitRegs {
+        JitRegs {
+            a: regs.a, f: regs.f, b: regs.b, c: regs.c, d: regs.d, e: regs.e, h: regs.h, l: regs.l,
+            sp: regs.sp,
+            pc: regs.pc,
+        }
+    }
+
+    pub fn to_snapshot(self, ime: bool) -> RegisterSnapshot {
+        RegisterSnapshot {
+            a: self.a, f: self.f, b: self.b, c: self.c, d: self.d, e: self.e, h: self.h, l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+            ime,
+        }
+    }
+}
+
+// Offsets in JitRegs of the registers by their number in opcodes (b c d e h l - a), then F and SP
+const R8_OFFSETS: [i32; 8] = [2, 3, 4, 5, 6, 7, -1, 0];
+const F: usize = 8;
+const SP: usize = 9;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Operand {
+    Reg(u8),
+    Imm(u8),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Op {
+    Nop,
+    Ld(u8, Operand),
+    Ld16(u8, u16), // bc de hl sp
+    Inc(u8),
+    Dec(u8),
+    Inc16(u8),
+    Dec16(u8),
+    Alu(u8, Operand), // add adc sub sbc and xor or cp
+    Cpl,
+    Scf,
+    Ccf,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Exit {
+    Next(u16),
+    Jump { to: u16, cycles: u32 },
+    // cond as in opcodes: nz z nc c
+    Branch { cond: u8, to: u16, taken: u32, next: u16, not_taken: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Block {
+    ops: Vec<Op>,
+    cycles: u32, // of ops
+    exit: Exit,
+}
+
+impl Block {
+    fn instructions(&self) -> u32 {
+        self.ops.len() as u32 + if let Exit::Next(_) = self.exit { 0 } else { 1 }
+    }
+}
+
+// The registers-only instruction at pc, if it is one
+fn decode_op<F: FnMut(u16) -> u8>(pc: u16, read: &mut F) -> Option<(Op, u16)> {
+    let opcode = read(pc);
+    let n = |read: &mut F| read(pc.wrapping_add(1));
+    let (y, z, p) = ((opcode >> 3) & 7, opcode & 7, (opcode >> 4) & 3);
+    let op = match opcode {
+        0x00 => (Op::Nop, 1),
+        0x01 | 0x11 | 0x21 | 0x31 => {
+            let nn = u16::from_le_bytes([read(pc.wrapping_add(1)), read(pc.wrapping_add(2))]);
+            (Op::Ld16(p, nn), 3)
+        }
+        0x03 | 0x13 | 0x23 | 0x33 => (Op::Inc16(p), 1),
+        0x0B | 0x1B | 0x2B | 0x3B => (Op::Dec16(p), 1),
+        0x2F => (Op::Cpl, 1),
+        0x37 => (Op::Scf, 1),
+        0x3F => (Op::Ccf, 1),
+        0x00..=0x3F if y != 6 && z == 4 => (Op::Inc(y), 1),
+        0x00..=0x3F if y != 6 && z == 5 => (Op::Dec(y), 1),
+        0x00..=0x3F if y != 6 && z == 6 => (Op::Ld(y, Operand::Imm(n(read))), 2),
+        0x40..=0x7F if y != 6 && z != 6 => (Op::Ld(y, Operand::Reg(z)), 1),
+        0x80..=0xBF if z != 6 => (Op::Alu(y, Operand::Reg(z)), 1),
+        0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => (Op::Alu(y, Operand::Imm(n(read))), 2),
+        _ => return None,
+    };
+    Some(op)
+}
+
+// The jump at pc that ends a block, if it is one
+fn decode_exit<F: FnMut(u16) -> u8>(pc: u16, read: &mut F) -> Option<Exit> {
+    let opcode = read(pc);
+    let cycles = |taken| expected_cycles(opcode, 0, taken).unwrap();
+    let exit = match opcode {
+        0x18 | 0x20 | 0x28 | 0x30 | 0x38 => {
+            let next = pc.wrapping_add(2);
+            let to = next.wrapping_add(read(pc.wrapping_add(1)) as i8 as u16);
+            if opcode == 0x18 {
+                Exit::Jump { to, cycles: cycles(false) }
+            } else {
+                Exit::Branch { cond: (opcode >> 3) & 3, to, taken: cycles(true), next, not_taken: cycles(false) }
+            }
+        }
+        0xC3 | 0xC2 | 0xCA | 0xD2 | 0xDA => {
+            let to = u16::from_le_bytes([read(pc.wrapping_add(1)), read(pc.wrapping_add(2))]);
+            if opcode == 0xC3 {
+                Exit::Jump { to, cycles: cycles(false) }
+            } else {
+                let next = pc.wrapping_add(3);
+                Exit::Branch { cond: (opcode >> 3) & 3, to, taken: cycles(true), next, not_taken: cycles(false) }
+            }
+        }
+        _ => return None,
+    };
+    Some(exit)
+}
+
+// The block starting at pc, None when it would be less than two instructions. Blocks stay on
+// their side of 0x4000, so they belong to a single bank.
+fn decode_block<F: FnMut(u16) -> u8>(start: u16, mut read: F) -> Option<Block> {
+    let region_end: u32 = if start < 0x4000 { 0x4000 } else { 0x8000 };
+    let mut block = Block { ops: Vec::new(), cycles: 0, exit: Exit::Next(start) };
+    let mut pc = start;
+    while block.ops.len() < MAX_BLOCK && (pc as u32) + 3 <= region_end {
+        if let Some(exit) = decode_exit(pc, &mut read) {
+            block.exit = exit;
+            break;
+        }
+        match decode_op(pc, &mut read) {
+            Some((op, len)) => {
+                block.cycles += expected_cycles(read(pc), 0, false).unwrap();
+                block.ops.push(op);
+                pc = pc.wrapping_add(len);
+                block.exit = Exit::Next(pc);
+            }
+            None => break,
+        }
+    }
+    if block.instructions() < 2 { None } else { Some(block) }
+}
+
+type BlockFn = unsafe extern "C" fn(*mut JitRegs) -> u32;
+
+#[derive(Copy, Clone)]
+pub struct CompiledBlock {
+    code: BlockFn,
+    instructions: u32,
+}
+
+impl CompiledBlock {
+    // Cycles taken, with regs and pc updated
+    pub fn run(&self, regs: &mut JitRegs) -> u32 {
+        // The code only reads and writes the JitRegs it is given
+        unsafe { (self.code)(regs) }
+    }
+
+    pub fn instructions(&self) -> u32 {
+        self.instructions
+    }
+}
+
+enum Entry {
+    Cold(u32), // starts so far
+    Compiled(CompiledBlock),
+    Interpreted, // not a block the JIT can take
+}
+
+pub struct Jit {
+    module: JITModule,
+    context: Context,
+    builder_context: FunctionBuilderContext,
+    blocks: HashMap<(u16, usize), Entry>,
+}
+
+// JITModule holds raw pointers to the code it owns, which nothing else sees
+unsafe impl Send for Jit {}
+
+impl Jit {
+    pub fn new() -> Result<Jit, JitError> {
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false").map_err(|e| JitError(e.to_string()))?;
+        flags.set("is_pic", "false").map_err(|e| JitError(e.to_string()))?;
+        let isa = cranelift_native::builder()
+            .map_err(|e| JitError(e.to_string()))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| JitError(e.to_string()))?;
+        let module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+        Ok(Jit {
+            context: module.make_context(),
+            module,
+            builder_context: FunctionBuilderContext::new(),
+            blocks: HashMap::new(),
+        })
+    }
+
+    // Forgets every block, for when the ROM changes under them. The code stays allocated until
+    // the Jit is dropped.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    // The compiled block at pc with rom_bank mapped, compiling it if it has become hot. read
+    // reads memory without side effects.
+    pub fn block<F: FnMut(u16) -> u8>(&mut self, pc: u16, rom_bank: usize, read: F) -> Option<CompiledBlock> {
+        let entry = self.blocks.entry((pc, rom_bank)).or_insert(Entry::Cold(0));
+        match entry {
+            Entry::Compiled(block) => return Some(*block),
+            Entry::Interpreted => return None,
+            Entry::Cold(runs) if *runs + 1 < HOT_RUNS => {
+                *runs += 1;
+                return None;
+            }
+            Entry::Cold(_) => {}
+        }
+        let compiled = decode_block(pc, read).and_then(|block| match self.compile(&block, pc) {
+            Ok(code) => Some(CompiledBlock { code, instructions: block.instructions() }),
+            Err(e) => {
+                warn!(target: "gbrust::cpu", "JIT could not compile the block at {:04x}: {}", pc, e);
+                None
+            }
+        });
+        self.blocks.insert((pc, rom_bank), compiled.map_or(Entry::Interpreted, Entry::Compiled));
+        compiled
+    }
+
+    fn compile(&mut self, block: &Block, pc: u16) -> Result<BlockFn, JitError> {
+        let pointer = self.module.target_config().pointer_type();
+        self.module.clear_context(&mut self.context);
+        self.context.func.signature.params.push(AbiParam::new(pointer));
+        self.context.func.signature.returns.push(AbiParam::new(types::I32));
+
+        let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let regs = builder.block_params(entry)[0];
+        let mut emitter = Emitter { builder, regs };
+        emitter.load();
+        for &op in &block.ops {
+            emitter.op(op);
+        }
+        match block.exit {
+            Exit::Next(next) => emitter.exit(next, block.cycles),
+            Exit::Jump { to, cycles } => emitter.exit(to, block.cycles + cycles),
+            Exit::Branch { cond, to, taken, next, not_taken } => {
+                let holds = emitter.condition(cond);
+                let taken_block = emitter.builder.create_block();
+                let not_taken_block = emitter.builder.create_block();
+                emitter.builder.ins().brif(holds, taken_block, &[], not_taken_block, &[]);
+                emitter.builder.seal_block(taken_block);
+                emitter.builder.seal_block(not_taken_block);
+                emitter.builder.switch_to_block(taken_block);
+                emitter.exit(to, block.cycles + taken);
+                emitter.builder.switch_to_block(not_taken_block);
+                emitter.exit(next, block.cycles + not_taken);
+            }
+        }
+        emitter.builder.finalize();
+
+        let name = format!("block_{:04x}_{}", pc, self.blocks.len());
+        let id = self.module.declare_function(&name, Linkage::Local, &self.context.func.signature)
+            .map_err(|e| JitError(e.to_string()))?;
+        self.module.define_function(id, &mut self.context).map_err(|e| JitError(e.to_string()))?;
+        self.module.clear_context(&mut self.context);
+        self.module.finalize_definitions().map_err(|e| JitError(e.to_string()))?;
+        let code = self.module.get_finalized_function(id);
+        // Compiled with BlockFn's signature just above
+        Ok(unsafe { mem::transmute::<*const u8, BlockFn>(code) })
+    }
+}
+
+// Builds a block's code. Registers live in variables 0 - 9 (see R8_OFFSETS) as I32s holding 8
+// bits, or 16 for SP.
+struct Emitter<'a> {
+    builder: FunctionBuilder<'a>,
+    regs: Value,
+}
+
+impl<'a> Emitter<'a> {
+    fn var(&self, index: usize) -> Variable {
+        Variable::from_u32(index as u32)
+    }
+
+    fn get(&mut self, index: usize) -> Value {
+        let var = self.var(index);
+        self.builder.use_var(var)
+    }
+
+    fn set(&mut self, index: usize, value: Value) {
+        let var = self.var(index);
+        self.builder.def_var(var, value);
+    }
+
+    fn offset(index: usize) -> i32 {
+        match index {
+            F => 1,
+            SP => 8,
+            _ => R8_OFFSETS[index],
+        }
+    }
+
+    fn load(&mut self) {
+        for index in (0..=SP).filter(|&index| index != 6) {
+            let var = self.var(index);
+            self.builder.declare_var(var, types::I32);
+            let flags = MemFlags::trusted();
+            let value = if index == SP {
+                self.builder.ins().uload16(types::I32, flags, self.regs, Emitter::offset(index))
+            } else {
+                self.builder.ins().uload8(types::I32, flags, self.regs, Emitter::offset(index))
+            };
+            self.set(index, value);
+        }
+    }
+
+    fn exit(&mut self, pc: u16, cycles: u32) {
+        let flags = MemFlags::trusted();
+        for index in (0..=SP).filter(|&index| index != 6) {
+            let value = self.get(index);
+            if index == SP {
+                self.builder.ins().istore16(flags, value, self.regs, Emitter::offset(index));
+            } else {
+                self.builder.ins().istore8(flags, value, self.regs, Emitter::offset(index));
+            }
+        }
+        let pc = self.builder.ins().iconst(types::I32, pc as i64);
+        self.builder.ins().istore16(flags, pc, self.regs, 10);
+        let cycles = self.builder.ins().iconst(types::I32, cycles as i64);
+        self.builder.ins().return_(&[cycles]);
+    }
+
+    fn operand(&mut self, operand: Operand) -> Value {
+        match operand {
+            Operand::Reg(r) => self.get(r as usize),
+            Operand::Imm(n) => self.builder.ins().iconst(types::I32, n as i64),
+        }
+    }
+
+    // A register pair as one 16 bit value, 3 for SP
+    fn get16(&mut self, p: u8) -> Value {
+        if p == 3 {
+            return self.get(SP);
+        }
+        let high = self.get(p as usize * 2);
+        let low = self.get(p as usize * 2 + 1);
+        let high = self.builder.ins().ishl_imm(high, 8);
+        self.builder.ins().bor(high, low)
+    }
+
+    fn set16(&mut self, p: u8, value: Value) {
+        let value = self.builder.ins().band_imm(value, 0xFFFF);
+        if p == 3 {
+            return self.set(SP, value);
+        }
+        let high = self.builder.ins().ushr_imm(value, 8);
+        let low = self.builder.ins().band_imm(value, 0xFF);
+        self.set(p as usize * 2, high);
+        self.set(p as usize * 2 + 1, low);
+    }
+
+    // One flag bit from a comparison
+    fn bit(&mut self, holds: Value, shift: i64) -> Value {
+        let bit = self.builder.ins().uextend(types::I32, holds);
+        self.builder.ins().ishl_imm(bit, shift)
+    }
+
+    fn is_zero(&mut self, value: Value) -> Value {
+        self.builder.ins().icmp_imm(IntCC::Equal, value, 0)
+    }
+
+    // F from the four flags, each a comparison or a constant
+    fn set_flags(&mut self, z: Flag, n: Flag, h: Flag, c: Flag) {
+        let mut f = self.builder.ins().iconst(types::I32, 0);
+        for &(flag, shift) in &[(z, 7), (n, 6), (h, 5), (c, 4)] {
+            let bit = match flag {
+                Flag::Holds(holds) => self.bit(holds, shift),
+                Flag::Set(true) => self.builder.ins().iconst(types::I32, 1 << shift),
+                Flag::Set(false) => continue,
+                Flag::Keep => {
+                    let old = self.get(F);
+                    self.builder.ins().band_imm(old, 1 << shift)
+                }
+            };
+            f = self.builder.ins().bor(f, bit);
+        }
+        self.set(F, f);
+    }
+
+    fn carry(&mut self) -> Value {
+        let f = self.get(F);
+        let c = self.builder.ins().ushr_imm(f, 4);
+        self.builder.ins().band_imm(c, 1)
+    }
+
+    // cond as in opcodes: nz z nc c
+    fn condition(&mut self, cond: u8) -> Value {
+        let f = self.get(F);
+        let mask = if cond < 2 { 0x80 } else { 0x10 };
+        let flag = self.builder.ins().band_imm(f, mask);
+        let cc = if cond & 1 == 0 { IntCC::Equal } else { IntCC::NotEqual };
+        self.builder.ins().icmp_imm(cc, flag, 0)
+    }
+
+    fn op(&mut self, op: Op) {
+        match op {
+            Op::Nop => {}
+            Op::Ld(r, operand) => {
+                let value = self.operand(operand);
+                self.set(r as usize, value);
+            }
+            Op::Ld16(p, nn) => {
+                let value = self.builder.ins().iconst(types::I32, nn as i64);
+                self.set16(p, value);
+            }
+            Op::Inc(r) | Op::Dec(r) => {
+                let old = self.get(r as usize);
+                let inc = matches!(op, Op::Inc(_));
+                let sum = self.builder.ins().iadd_imm(old, if inc { 1 } else { -1 });
+                let value = self.builder.ins().band_imm(sum, 0xFF);
+                self.set(r as usize, value);
+                let z = self.is_zero(value);
+                let low = self.builder.ins().band_imm(old, 0x0F);
+                let h = self.builder.ins().icmp_imm(IntCC::Equal, low, if inc { 0x0F } else { 0 });
+                self.set_flags(Flag::Holds(z), Flag::Set(!inc), Flag::Holds(h), Flag::Keep);
+            }
+            Op::Inc16(p) | Op::Dec16(p) => {
+                let old = self.get16(p);
+                let value = self.builder.ins().iadd_imm(old, if let Op::Inc16(_) = op { 1 } else { -1 });
+                self.set16(p, value);
+            }
+            Op::Alu(alu, operand) => {
+                let value = self.operand(operand);
+                self.alu(alu, value);
+            }
+            Op::Cpl => {
+                let a = self.get(7);
+                let value = self.builder.ins().bxor_imm(a, 0xFF);
+                self.set(7, value);
+                self.set_flags(Flag::Keep, Flag::Set(true), Flag::Set(true), Flag::Keep);
+            }
+            Op::Scf => self.set_flags(Flag::Keep, Flag::Set(false), Flag::Set(false), Flag::Set(true)),
+            Op::Ccf => {
+                let f = self.get(F);
+                let c = self.builder.ins().band_imm(f, 0x10);
+                let not_c = self.is_zero(c);
+                self.set_flags(Flag::Keep, Flag::Set(false), Flag::Set(false), Flag::Holds(not_c));
+            }
+        }
+    }
+
+    fn alu(&mut self, alu: u8, value: Value) {
+        let a = self.get(7);
+        match alu {
+            // add, adc, sub, sbc, cp
+            0 | 1 | 2 | 3 | 7 => {
+                let carry = if alu == 1 || alu == 3 { self.carry() } else { self.builder.ins().iconst(types::I32, 0) };
+                let a_low = self.builder.ins().band_imm(a, 0x0F);
+                let value_low = self.builder.ins().band_imm(value, 0x0F);
+                let add = alu < 2;
+                let (result, low) = if add {
+                    let sum = self.builder.ins().iadd(a, value);
+                    let low = self.builder.ins().iadd(a_low, value_low);
+                    (self.builder.ins().iadd(sum, carry), self.builder.ins().iadd(low, carry))
+                } else {
+                    let difference = self.builder.ins().isub(a, value);
+                    let low = self.builder.ins().isub(a_low, value_low);
+                    (self.builder.ins().isub(difference, carry), self.builder.ins().isub(low, carry))
+                };
+                let byte = self.builder.ins().band_imm(result, 0xFF);
+                let z = self.is_zero(byte);
+                let (h, c) = if add {
+                    (self.builder.ins().icmp_imm(IntCC::UnsignedGreaterThan, low, 0x0F),
+                     self.builder.ins().icmp_imm(IntCC::UnsignedGreaterThan, result, 0xFF))
+                } else {
+                    (self.builder.ins().icmp_imm(IntCC::SignedLessThan, low, 0),
+                     self.builder.ins().icmp_imm(IntCC::SignedLessThan, result, 0))
+                };
+                if alu != 7 {
+                    self.set(7, byte);
+                }
+                self.set_flags(Flag::Holds(z), Flag::Set(!add), Flag::Holds(h), Flag::Holds(c));
+            }
+            // and, xor, or
+            _ => {
+                let result = match alu {
+                    4 => self.builder.ins().band(a, value),
+                    5 => self.builder.ins().bxor(a, value),
+                    _ => self.builder.ins().bor(a, value),
+                };
+                self.set(7, result);
+                let z = self.is_zero(result);
+                self.set_flags(Flag::Holds(z), Flag::Set(false), Flag::Set(alu == 4), Flag::Set(false));
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Flag {
+    Holds(Value),
+    Set(bool),
+    Keep,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    use super::super::console::NoVideo;
+    use super::super::dmg_cpu::Cpu;
+    use super::super::test_asm::{Alu, Asm, Cond, R16, R8};
+
+    // The registers at the end of the program, and the cycles it took
+    fn run(cpu: &mut Cpu, end: u16) -> (RegisterSnapshot, u64) {
+        let mut cycles = 0;
+        while cpu.registers().pc != end {
+            cycles += cpu.step(&mut NoVideo) as u64;
+        }
+        (cpu.registers(), cycles)
+    }
+
+    // Runs program to its end with and without the JIT
+    fn both_ways<P: Fn() -> Asm>(program: P) {
+        let end = program().pc();
+        let mut compiled = program().cpu();
+        compiled.set_jit(true).unwrap();
+        assert_eq!(run(&mut compiled, end), run(&mut program().cpu(), end));
+        assert!(compiled.jit_instructions() > 0);
+    }
+
+    #[test]
+    fn decodes_register_only_blocks() {
+        let rom = Asm::new()
+            .ld_r_n(R8::A, 0x10)
+            .alu(Alu::Add, R8::B)
+            .ld_r_r(R8::HlInd, R8::A) // ends the block
+            .assemble();
+        let block = decode_block(0x0150, |addr| rom[addr as usize]).unwrap();
+        assert_eq!(block.ops, [Op::Ld(7, Operand::Imm(0x10)), Op::Alu(0, Operand::Reg(0))]);
+        assert_eq!((block.cycles, block.exit), (3, Exit::Next(0x0153)));
+        // One instruction is not worth it
+        assert_eq!(decode_block(0x0152, |addr| rom[addr as usize]), None);
+    }
+
+    // A loop the JIT takes whole, run by both and compared instruction for instruction at the
+    // end: ALU flags, 16 bit pairs, carries and both ways out of a conditional jump
+    #[test]
+    fn compiled_blocks_match_the_interpreter() {
+        let program = || {
+            Asm::new()
+                .di()
+                .ld_rr_nn(R16::BC, 0x0F00)
+                .ld_rr_nn(R16::DE, 0xFFFE)
+                .ld_rr_nn(R16::HL, 0x1234)
+                .alu(Alu::Xor, R8::A)
+                .label("loop")
+                .alu_n(Alu::Add, 0x3B)
+                .alu(Alu::Adc, R8::C)
+                .inc(R8::C)
+                .dec(R8::E)
+                .inc16(R16::DE)
+                .dec16(R16::HL)
+                .alu(Alu::Sbc, R8::L)
+                .alu_n(Alu::Cp, 0x80)
+                .ld_r_r(R8::D, R8::A)
+                .alu(Alu::Or, R8::H)
+                .alu_n(Alu::And, 0xF7)
+                .bytes(&[0x2F, 0x3F]) // cpl, ccf
+                .dec(R8::B)
+                .jr_cc(Cond::NZ, "loop")
+                .bytes(&[0x37]) // scf
+                .jp_cc(Cond::C, "done")
+                .nop()
+                .label("done")
+        };
+        both_ways(program);
+    }
+
+    // Register-only instructions the JIT takes, other than those on SP, bytes and all
+    fn instruction() -> impl Strategy<Value = Vec<u8>> {
+        (any::<u8>(), any::<u16>())
+            .prop_filter_map("not for the JIT", |(opcode, nn)| {
+                let bytes = [opcode, nn as u8, (nn >> 8) as u8];
+                match decode_op(0, &mut |addr| bytes[addr as usize]) {
+                    Some((Op::Ld16(3, _), _)) | Some((Op::Inc16(3), _)) | Some((Op::Dec16(3), _)) | None => None,
+                    Some((_, len)) => Some(bytes[..len as usize].to_vec()),
+                }
+            })
+    }
+
+    proptest! {
+        // Random blocks run ten times over, counting in memory so the registers and flags are all
+        // theirs
+        #[test]
+        fn random_blocks_match_the_interpreter(start in any::<[u8; 8]>(),
+                                               block in prop::collection::vec(instruction(), 2..32)) {
+            both_ways(|| {
+                let mut asm = Asm::new()
+                    .di()
+                    .ld_r_n(R8::A, 10)
+                    .ld_mem_a(0xC000)
+                    .ld_rr_nn(R16::SP, 0xDFF0)
+                    .ld_rr_nn(R16::BC, u16::from(start[7]))
+                    .push(R16::BC)
+                    .pop(R16::SP); // f
+                for (&r, &n) in [R8::A, R8::B, R8::C, R8::D, R8::E, R8::H, R8::L].iter().zip(&start) {
+                    asm = asm.ld_r_n(r, n);
+                }
+                asm = asm.label("loop");
+                for instruction in &block {
+                    asm = asm.bytes(instruction);
+                }
+                asm.push(R16::SP) // af
+                    .ld_a_mem(0xC000)
+                    .dec(R8::A)
+                    .ld_mem_a(0xC000)
+                    .jr_cc(Cond::Z, "done")
+                    .pop(R16::SP)
+                    .jr("loop")
+                    .label("done")
+                    .pop(R16::SP)
+            });
+        }
+    }
+}
@@ -0,0 +1,257 @@
+// JSON for the debug protocols (rpc.rs, dap.rs), which need the `server` feature.
+// Just enough to read requests and write replies: numbers are f64, objects keep their keys
+// sorted, and nesting stops at MAX_DEPTH, deeper than any request goes.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+use super::debugger::BankedAddr;
+
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl Json {
+    pub(crate) fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    pub(crate) fn addr(addr: BankedAddr) -> Json {
+        Json::String(addr.to_string())
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.get(key),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<Json, ()> {
+        let mut parser = Parser { text: text.as_bytes(), at: 0 };
+        let value = parser.value(0)?;
+        parser.skip_space();
+        if parser.at == text.len() {
+            Ok(value)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => {
+                let mut quoted = String::with_capacity(s.len() + 2);
+                quoted.push('"');
+                for c in s.chars() {
+                    match c {
+                        '"' => quoted.push_str("\\\""),
+                        '\\' => quoted.push_str("\\\\"),
+                        '\n' => quoted.push_str("\\n"),
+                        c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32)?,
+                        c => quoted.push(c),
+                    }
+                }
+                quoted.push('"');
+                f.write_str(&quoted)
+            }
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { "," } else { "" }, item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    write!(f, "{}{}:{}", if i > 0 { "," } else { "" }, Json::String(key.clone()), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn skip_space(&mut self) {
+        while self.text.get(self.at).is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.at += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_space();
+        if self.text.get(self.at) == Some(&byte) {
+            self.at += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn word(&mut self, word: &str, value: Json) -> Result<Json, ()> {
+        if self.text[self.at..].starts_with(word.as_bytes()) {
+            self.at += word.len();
+            Ok(value)
+        } else {
+            Err(())
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, ()> {
+        if depth > MAX_DEPTH {
+            return Err(());
+        }
+        self.skip_space();
+        match self.text.get(self.at).ok_or(())? {
+            b'n' => self.word("null", Json::Null),
+            b't' => self.word("true", Json::Bool(true)),
+            b'f' => self.word("false", Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                self.at += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        if !self.eat(b',') {
+                            return Err(());
+                        }
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            b'{' => {
+                self.at += 1;
+                let mut fields = BTreeMap::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_space();
+                        let key = self.string()?;
+                        if !self.eat(b':') {
+                            return Err(());
+                        }
+                        fields.insert(key, self.value(depth + 1)?);
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        if !self.eat(b',') {
+                            return Err(());
+                        }
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            _ => {
+                let start = self.at;
+                while self.text.get(self.at).is_some_and(|&byte| byte == b'-' || byte == b'+' || byte == b'.'
+                                                          || byte == b'e' || byte == b'E' || byte.is_ascii_digit()) {
+                    self.at += 1;
+                }
+                let number = std::str::from_utf8(&self.text[start..self.at]).map_err(|_| ())?;
+                number.parse().map(Json::Number).map_err(|_| ())
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ()> {
+        if self.text.get(self.at) != Some(&b'"') {
+            return Err(());
+        }
+        self.at += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = *self.text.get(self.at).ok_or(())?;
+            self.at += 1;
+            match byte {
+                b'"' => return String::from_utf8(bytes).map_err(|_| ()),
+                b'\\' => {
+                    let escaped = *self.text.get(self.at).ok_or(())?;
+                    self.at += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let digits = self.text.get(self.at..self.at + 4).ok_or(())?;
+                            self.at += 4;
+                            let code = u32::from_str_radix(std::str::from_utf8(digits).map_err(|_| ())?, 16)
+                                .map_err(|_| ())?;
+                            // Surrogate pairs are not needed for anything we take
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return Err(()),
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                }
+                _ => bytes.push(byte),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_round_trips() {
+        let text = r#" {"b": [1, -2.5e1, true, null], "a": "x\"é\n", "c": {}} "#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("b"), Some(&Json::Array(vec![Json::Number(1.0), Json::Number(-25.0), Json::Bool(true),
+                                                         Json::Null])));
+        assert_eq!(json.to_string(), "{\"a\":\"x\\\"\u{e9}\\n\",\"b\":[1,-25,true,null],\"c\":{}}");
+        assert_eq!(Json::parse(&json.to_string()), Ok(json));
+        assert!(Json::parse("{\"a\":1,}").is_err());
+        assert!(Json::parse("[1] 2").is_err());
+        assert!(Json::parse(&"[".repeat(100)).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+// Game lists for launchers.
+// scan goes through a directory and returns what a frontend needs to show a list of games: the
+// title, a few header facts and a thumbnail, the screen a few seconds after power on, run
+// headless. Every file that loads as a cartridge counts (zipped ones with the `archive`
+// feature), anything else is skipped, GBS music rips included.
+// Each ROM runs for THUMBNAIL_FRAMES, so large collections take a while. Frontends should keep
+// the manifest around rather than scanning on every start.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::config::EmuConfig;
+use super::console::{Console, LastFrame};
+use super::gbs::GbsFile;
+use super::romdb::RomDb;
+
+// About 5 seconds, past the logo and publisher screens of most games
+pub const THUMBNAIL_FRAMES: usize = 300;
+
+#[derive(Debug, Clone)]
+pub struct GameEntry {
+    pub path: PathBuf,
+    // The DAT's name when the config has a rom_database that knows the game, otherwise the
+    // header title
+    pub title: String,
+    pub global_checksum: u16,
+    pub header_checksum_ok: bool,
+    // 160x144, None if the game kept the screen off the whole time
+    pub thumbnail: Option<Box<[u32]>>,
+}
+
+// Every game in dir (not its subdirectories), sorted by title. Games run with config, and
+// their profile from it.
+pub fn scan<P: AsRef<Path>>(dir: P, config: &EmuConfig) -> io::Result<Vec<GameEntry>> {
+    // Read once here instead of once per ROM by the console builder
+    let mut config = config.clone();
+    let db = match config.rom_database.take() {
+        Some(path) => match RomDb::load(&path) {
+            Ok(db) => Some(db),
+            Err(e) => {
+                warn!(target: "gbrust::romdb", "could not read ROM database {}: {}", path.display(), e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut games = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        match scan_rom(&path, &config, db.as_ref()) {
+            Some(game) => games.push(game),
+            None => debug!(target: "gbrust::launcher", "{} is not a ROM, skipped", path.display()),
+        }
+    }
+    games.sort_by(|a, b| a.title.cmp(&b.title).then_with(|| a.path.cmp(&b.path)));
+    Ok(games)
+}
+
+fn scan_rom(path: &Path, config: &EmuConfig, db: Option<&RomDb>) -> Option<GameEntry> {
+    let rom = fs::read(path).ok()?;
+    if GbsFile::is_gbs(&rom) {
+        return None;
+    }
+    // Battery saves are left alone, thumbnails show a fresh start
+    let mut console = Console::builder()
+        .config(config.clone())
+        .rom(rom.into_boxed_slice())
+        .build()
+        .ok()?;
+
+    let mut sink = LastFrame(None);
+    for _ in 0..THUMBNAIL_FRAMES {
+        console.run_frame(&mut sink);
+    }
+
+    let cart = console.cart();
+    let title = db.and_then(|db| db.lookup(&cart.hashes()))
+        .map(|entry| entry.name.clone())
+        .unwrap_or_else(|| cart.get_title());
+    Some(GameEntry {
+        path: path.to_path_buf(),
+        title,
+        global_checksum: cart.global_checksum(),
+        header_checksum_ok: cart.check_sum(),
+        thumbnail: sink.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_roms_only() {
+        let dir = std::env::temp_dir().join(format!("gbrust-launcher-{}", std::process::id()));
+        fs::create_dir_all(dir.join("subdir")).unwrap();
+        fs::copy("tetris.gb", dir.join("tetris.gb")).unwrap();
+        fs::copy("dmg_boot.bin", dir.join("dmg_boot.bin")).unwrap();
+        fs::write(dir.join("notes.txt"), "not a game").unwrap();
+
+        let games = scan(&dir, &EmuConfig::default());
+        fs::remove_dir_all(&dir).unwrap();
+        let games = games.unwrap();
+
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].title, "TETRIS");
+        assert_eq!(games[0].path, dir.join("tetris.gb"));
+        assert!(games[0].header_checksum_ok);
+        let thumbnail = games[0].thumbnail.as_ref().unwrap();
+        assert!(thumbnail.iter().any(|&pixel| pixel != thumbnail[0]));
+    }
+}
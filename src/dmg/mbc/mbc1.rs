@@ -5,13 +5,21 @@
 // RAM bank no. (0x4000 - 0x5FFF)
 // Banking Mode Select (0x6000 - 0x7FFF)
 // and an external RAM, and a ram offset.
+// On carts of 1 MiB and up, the RAM bank register holds bits 5-6 of the ROM bank instead, for
+// 0x4000 - 0x7FFF always and in mode 1 for 0x0000 - 0x3FFF too, which then maps bank 0x20, 0x40
+// or 0x60.
 
 use super::mbc_properties::Mbc;
 use super::mbc_properties::MbcInfo;
 use super::mbc_properties::RamInfo;
+use super::mbc_properties::read_rom_at;
+use super::super::state::{StateError, StateReader, StateWriter};
+use super::super::error::{BusError, CartError};
 
 const ROM_BASE_ADDR: usize = 0x4000;
 const RAM_BASE_ADDR: usize = 0xA000;
+// 1 MiB
+const LARGE_ROM_BANKS: usize = 64;
 
 pub struct Mbc1 {
     extern_ram_enable: bool,
@@ -20,44 +28,55 @@ pub struct Mbc1 {
     rom_offset: usize,
     ram_offset: usize,
     ram_mode: bool, // mode 0 (false) or mode 1 (true)
+    large_rom: bool, // see the top of the file
     ram: Box<[u8]>,
 }
 
 impl Mbc1 {
-    pub fn new(mbc_info: MbcInfo, ram: Option<Box<[u8]>>) -> Self {
+    pub fn new(mbc_info: MbcInfo, rom_banks: usize, ram: Option<Box<[u8]>>) -> Result<Self, CartError> {
         let ram = if let Some(extern_ram) = mbc_info.ram_info {
-            extern_ram.make_external_ram(ram)
+            extern_ram.make_external_ram(ram)?
         } else {
             vec![0; 0].into_boxed_slice()
         };
 
-        Mbc1 {
+        Ok(Mbc1 {
             extern_ram_enable: false, // default disabled
             rom_bank_num: 0,
             ram_bank_num: 0,
             rom_offset: ROM_BASE_ADDR,
             ram_offset: 0,
             ram_mode: false, // default 0
+            large_rom: rom_banks >= LARGE_ROM_BANKS,
             ram: ram,
+        })
+    }
+
+    fn ram_index(&self, addr: u16) -> Result<usize, BusError> {
+        let index = addr as usize - RAM_BASE_ADDR + self.ram_offset;
+        if index < self.ram.len() {
+            Ok(index)
+        } else {
+            Err(BusError::NoExternalRam { addr })
         }
     }
 
+    // Bits 5-6 of the ROM bank, from the RAM bank register on large carts
+    fn upper_rom_bank(&self) -> usize {
+        if self.large_rom { (self.ram_bank_num as usize) << 5 } else { 0 }
+    }
+
     pub fn update_rom_offset(&mut self) {
         let bank_id = match self.rom_bank_num {
            0 => 1,
-           _ => {
-               match self.rom_bank_num & 0xf0 {
-                   0x20 | 0x40 | 0x60 => self.rom_bank_num | 0x01,
-                   _ => self.rom_bank_num,
-               }
-           }
-        } as usize;
+           _ => self.rom_bank_num as usize,
+        } | self.upper_rom_bank();
 
         self.rom_offset = bank_id * 16 * 1024;
     }
 
     pub fn update_ram_offset(&mut self) {
-        self.ram_offset = if self.ram_mode { // ram banking mode
+        self.ram_offset = if self.ram_mode && !self.large_rom { // ram banking mode
             self.ram_bank_num as usize * 8 * 1024 // 8kb each ram bank, treating RAM as a giant array
         } else { // simple ROM banking mode
             0
@@ -66,33 +85,51 @@ impl Mbc1 {
 }
 
 impl Mbc for Mbc1 {
-    fn read_rom(&self, rom: &Box<[u8]>, addr: u16) -> u8 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> Result<u8, BusError> {
         match addr {
-            0x0000..=0x3FFF => rom[addr as usize],
-            0x4000..=0x7FFF => rom[addr as usize - ROM_BASE_ADDR + self.rom_offset],
-            _ => panic!("Unsupported address"),
+            0x0000..=0x3FFF => read_rom_at(rom, addr, self.rom_bank_at(addr) * 16 * 1024 + addr as usize),
+            0x4000..=0x7FFF => read_rom_at(rom, addr, addr as usize - ROM_BASE_ADDR + self.rom_offset),
+            _ => Err(BusError::RomOutOfRange { addr, offset: addr as usize }),
         }
     }
 
-    fn write_rom(&mut self, addr: u16, content: u8) {
+    fn write_rom(&mut self, addr: u16, content: u8) -> Result<(), BusError> {
         match addr {
             0x0000..=0x1FFF => self.extern_ram_enable = content == 0x0A,
-            0x2000..=0x3FFF => self.rom_bank_num = content & 0x1F,
+            0x2000..=0x3FFF => {
+                self.rom_bank_num = content & 0x1F;
+                debug!(target: "gbrust::mbc", "ROM bank 0x{:02x}", self.rom_bank_num);
+            }
             0x4000..=0x5FFF => self.ram_bank_num = content & 0x03,
             0x6000..=0x7FFF => self.ram_mode = content == 0x01,
-            _ => panic!("Unsupported address 0x{:x}", addr),
+            _ => return Err(BusError::UnhandledCartWrite { addr, val: content }),
         }
         self.update_rom_offset();
         self.update_ram_offset();
+        Ok(())
     }
 
-    fn read_ram(&self, addr: u16) -> u8 {
-        self.ram[addr as usize - RAM_BASE_ADDR + self.ram_offset]
+    fn read_ram(&self, addr: u16) -> Result<u8, BusError> {
+        Ok(self.ram[self.ram_index(addr)?])
     }
 
-    fn write_ram(&mut self, addr: u16, content: u8) {
+    fn write_ram(&mut self, addr: u16, content: u8) -> Result<(), BusError> {
         if self.extern_ram_enable {
-            self.ram[addr as usize - RAM_BASE_ADDR + self.ram_offset] = content;
+            let index = self.ram_index(addr)?;
+            self.ram[index] = content;
+        }
+        Ok(())
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_offset / (16 * 1024)
+    }
+
+    fn rom_bank_at(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF if self.ram_mode => self.upper_rom_bank(),
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank(),
         }
     }
 
@@ -103,4 +140,32 @@ impl Mbc for Mbc1 {
             None 
         }
     }
+
+    fn reset(&mut self) {
+        self.extern_ram_enable = false;
+        self.rom_bank_num = 0;
+        self.ram_bank_num = 0;
+        self.rom_offset = ROM_BASE_ADDR;
+        self.ram_offset = 0;
+        self.ram_mode = false;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.extern_ram_enable);
+        state.write_u8(self.rom_bank_num);
+        state.write_u8(self.ram_bank_num);
+        state.write_bool(self.ram_mode);
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.extern_ram_enable = state.read_bool()?;
+        self.rom_bank_num = state.read_u8()?;
+        self.ram_bank_num = state.read_u8()?;
+        self.ram_mode = state.read_bool()?;
+        state.read_into(&mut self.ram, "mbc1 ram")?;
+        self.update_rom_offset();
+        self.update_ram_offset();
+        Ok(())
+    }
 }
@@ -4,6 +4,9 @@
 use super::Mbc; // trait
 use super::RamInfo; // struct
 use super::MbcInfo; // struct
+use super::super::state::{StateError, StateReader, StateWriter};
+use super::super::error::BusError;
+use super::read_rom_at;
 
 pub struct Mbc2 {
     ram_flag: bool,
@@ -33,17 +36,17 @@ impl Mbc2 {
 }
 
 impl Mbc for Mbc2 {
-    fn read_rom(&self, rom: &Box<[u8]>, addr: u16) -> u8 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> Result<u8, BusError> {
         match addr {
-            0x0000..=0x3FFF => rom[addr as usize],
-            0x4000..=0x7FFF => rom[addr as usize + self.rom_offset],
-            _ => panic!("Unsupported address 0x{:x}", addr),
+            0x0000..=0x3FFF => read_rom_at(rom, addr, addr as usize),
+            0x4000..=0x7FFF => read_rom_at(rom, addr, addr as usize + self.rom_offset),
+            _ => Err(BusError::RomOutOfRange { addr, offset: addr as usize }),
         }   
     }
     
     #[allow(dead_code)]
     // TODO: check logic
-    fn write_rom(&mut self, addr: u16, content: u8) {
+    fn write_rom(&mut self, addr: u16, content: u8) -> Result<(), BusError> {
         match addr {
             0x0000..=0x1FFF => if (addr & 0x0100) == 0 {
                 self.ram_flag = !self.ram_flag; // Depends on content, not address
@@ -58,22 +61,30 @@ impl Mbc for Mbc2 {
                 }
                 self.rom_offset = ((new_rom - 1) as usize * 0x4000 as usize) as usize; // update rom offset. why new_rom - 1? Overflow error
             },
-            _ => panic!("unsupported address 0x{:x}", addr),
+            _ => return Err(BusError::UnhandledCartWrite { addr, val: content }),
         }
+        Ok(())
     }
 
-    fn read_ram(&self, addr: u16) -> u8 {
+    // The 512 nibbles repeat over the whole 0xA000 - 0xBFFF range
+    fn read_ram(&self, addr: u16) -> Result<u8, BusError> {
         if self.ram_flag {
-            self.ram[addr as usize]
+            Ok(self.ram[(addr & 0x01FF) as usize])
         } else {
-            0
+            Ok(0)
         }
     }
 
-    fn write_ram(&mut self, addr: u16, content: u8) {
+    fn write_ram(&mut self, addr: u16, content: u8) -> Result<(), BusError> {
         if self.ram_flag {
-            self.ram[addr as usize] = content;
+            self.ram[(addr & 0x01FF) as usize] = content;
         }
+        Ok(())
+    }
+
+    // read_rom does not take 0x4000 off the address, so this is one more than rom_offset says
+    fn rom_bank(&self) -> usize {
+        self.rom_offset / 0x4000 + 1
     }
 
     fn copy_ram(&self) -> Option<Box<[u8]>> {
@@ -84,4 +95,27 @@ impl Mbc for Mbc2 {
             None
         }
     }
+
+    fn reset(&mut self) {
+        self.ram_flag = true;
+        self.rom_bank_0 = 0;
+        self.rom_bank_1 = 1;
+        self.rom_offset = 0x4000;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_flag);
+        state.write_u8(self.rom_bank_0);
+        state.write_u8(self.rom_bank_1);
+        state.write_u32(self.rom_offset as u32);
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_flag = state.read_bool()?;
+        self.rom_bank_0 = state.read_u8()?;
+        self.rom_bank_1 = state.read_u8()?;
+        self.rom_offset = state.read_u32()? as usize;
+        state.read_into(&mut self.ram, "mbc2 ram")
+    }
 }
@@ -5,9 +5,19 @@
 // Real Time Clock, how it works:
 // RAM Bank: 08  09  0A  0B        0C(bit0)  0C(bit6) 0C(bit7)
 //           Sec Min Hrs Days(lsb) Days(msb) halt     overflow flag, set when 9-bit day counter overflows
+// The clock runs on emulated cycles, so movies, netplay and replays see the same time whenever
+// and however fast they run. Host time only comes in through .sav footers: they carry the unix
+// time they were written, and the time between writing and loading, when the battery would have
+// kept the clock running with the console off, is made up once on loading (see set_rtc). Save
+// states carry the emulated clock as it was.
 
 use super::Mbc;
 use super::MbcInfo;
+use super::super::state::{StateError, StateReader, StateWriter};
+use super::super::error::{BusError, CartError};
+use super::super::sav::{unix_time, Rtc, RtcRegisters};
+use super::super::apu::CPU_CLOCK;
+use super::read_rom_at;
 
 const ROM_BANK_BASE: usize = 0x4000;
 const RAM_BANK_BASE: usize = 0xA000;
@@ -22,10 +32,68 @@ pub struct Timer {
     days_hi: u8, // bit 0: msb of day counter, bit 6: halt, bit 7: day counter overflow
 }
 
+impl Timer {
+    fn registers(&self) -> RtcRegisters {
+        RtcRegisters { sec: self.sec, min: self.min, hrs: self.hrs, days_lo: self.days_lo, days_hi: self.days_hi }
+    }
+
+    // Masked as if the game wrote them
+    fn from_registers(regs: RtcRegisters) -> Timer {
+        Timer {
+            sec: regs.sec & 0x3F,
+            min: regs.min & 0x3F,
+            hrs: regs.hrs & 0x1F,
+            days_lo: regs.days_lo,
+            days_hi: regs.days_hi & 0b1100_0001,
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.days_hi & 0x40 != 0
+    }
+
+    // Runs the clock on, unless halted. Out of range values, which the chip counts up to 63
+    // before wrapping, carry like valid ones.
+    fn advance(&mut self, seconds: u64) {
+        if self.halted() || seconds == 0 {
+            return;
+        }
+        let days = (self.days_hi as u64 & 0x01) << 8 | self.days_lo as u64;
+        let total = days * 86_400 + self.hrs as u64 * 3600 + self.min as u64 * 60 + self.sec as u64 + seconds;
+        let days = total / 86_400;
+        self.sec = (total % 60) as u8;
+        self.min = (total / 60 % 60) as u8;
+        self.hrs = (total / 3600 % 24) as u8;
+        self.days_lo = days as u8;
+        self.days_hi = self.days_hi & 0xC0 | (days >> 8 & 0x01) as u8;
+        if days > 0x1FF {
+            self.days_hi |= 0x80; // stays set until the game clears it
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.sec);
+        state.write_u8(self.min);
+        state.write_u8(self.hrs);
+        state.write_u8(self.days_lo);
+        state.write_u8(self.days_hi);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sec = state.read_u8()?;
+        self.min = state.read_u8()?;
+        self.hrs = state.read_u8()?;
+        self.days_lo = state.read_u8()?;
+        self.days_hi = state.read_u8()?;
+        Ok(())
+    }
+}
+
 pub struct Mbc3 {
     timer_write_only: Timer,
     timer_read_only: Timer,
     timer_latch: bool, // When from false to true, clone timer_write_only to timer_read_only
+    cycles: u32, // into the current second
     extern_ram_enable: bool,
     rom_bank_num: u8,
     ram_bank_num: u8,
@@ -36,9 +104,9 @@ pub struct Mbc3 {
 }
 
 impl Mbc3 {
-    pub fn new(mbc_info: MbcInfo, ram: Option<Box<[u8]>>) -> Self {
+    pub fn new(mbc_info: MbcInfo, ram: Option<Box<[u8]>>) -> Result<Self, CartError> {
         let ram = if let Some(extern_ram) = mbc_info.ram_info {
-            extern_ram.make_external_ram(ram)
+            extern_ram.make_external_ram(ram)?
         } else {
             vec![0; 0].into_boxed_slice()
         };
@@ -51,10 +119,11 @@ impl Mbc3 {
             days_hi: 0,
         };
 
-        Mbc3 {
+        Ok(Mbc3 {
             timer_write_only: timer_std,
             timer_read_only: timer_std,
             timer_latch: false,
+            cycles: 0,
             extern_ram_enable: false, // default disabled
             rom_bank_num: 0,
             ram_bank_num: 0,
@@ -62,6 +131,15 @@ impl Mbc3 {
             ram_offset: 0,
             ram_mode: true, // default true for MBC3
             ram: ram,
+        })
+    }
+
+    fn ram_index(&self, addr: u16) -> Result<usize, BusError> {
+        let index = addr as usize - RAM_BANK_BASE + self.ram_offset;
+        if index < self.ram.len() {
+            Ok(index)
+        } else {
+            Err(BusError::NoExternalRam { addr })
         }
     }
 
@@ -85,19 +163,22 @@ impl Mbc3 {
 }
 
 impl Mbc for Mbc3 {
-    fn read_rom(&self, rom: &Box<[u8]>, addr: u16) -> u8 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> Result<u8, BusError> {
         match addr {
-            0x0000..=0x3FFF => rom[addr as usize],
-            0x4000..=0x7FFF => rom[addr as usize - ROM_BANK_BASE + self.rom_offset],
-            _ => panic!("Unsupported address 0x{:x}", addr),
+            0x0000..=0x3FFF => read_rom_at(rom, addr, addr as usize),
+            0x4000..=0x7FFF => read_rom_at(rom, addr, addr as usize - ROM_BANK_BASE + self.rom_offset),
+            _ => Err(BusError::RomOutOfRange { addr, offset: addr as usize }),
         }
     }
 
     // Addr 0x0000 - 0x1FFF en/disables both RAM and timer
-    fn write_rom(&mut self, addr: u16, content: u8) {
+    fn write_rom(&mut self, addr: u16, content: u8) -> Result<(), BusError> {
         match addr {
             0x0000..=0x1FFF => self.extern_ram_enable = content == 0x0A,
-            0x2000..=0x3FFF => self.rom_bank_num = content & 0x7F,
+            0x2000..=0x3FFF => {
+                self.rom_bank_num = content & 0x7F;
+                debug!(target: "gbrust::mbc", "ROM bank 0x{:02x}", self.rom_bank_num);
+            }
             0x4000..=0x5FFF => self.ram_bank_num = content & 0x0F, // bank number will determine timer register to write to also
             0x6000..=0x7FFF => {
                 if !self.timer_latch && content == 1 {
@@ -105,39 +186,51 @@ impl Mbc for Mbc3 {
                 }
                 self.timer_latch = content == 1;
             },
-            _ => panic!("Unsupported address 0x{:x}", addr),
+            _ => return Err(BusError::UnhandledCartWrite { addr, val: content }),
         }
         self.update_rom_offset();
         self.update_ram_offset();
+        Ok(())
     }
 
     // different from mbc1: might access ram OR RTC Register depending on bank number / RTC
     // register selection
-    fn read_ram(&self, addr: u16) -> u8 {
-        match self.ram_bank_num {
-            0..=3 => self.ram[addr as usize - RAM_BANK_BASE + self.ram_offset],
+    fn read_ram(&self, addr: u16) -> Result<u8, BusError> {
+        Ok(match self.ram_bank_num {
+            0..=3 => self.ram[self.ram_index(addr)?],
             0x08 => self.timer_read_only.sec,
             0x09 => self.timer_read_only.min,
             0x0A => self.timer_read_only.hrs,
             0x0B => self.timer_read_only.days_lo,
             0x0C => self.timer_read_only.days_hi,
-            _ => panic!("unsupported RAM bank 0x{:x}", self.ram_bank_num),
-        }
+            _ => return Err(BusError::InvalidRamBank(self.ram_bank_num)),
+        })
     }
 
     // RAM or timer register depending on bank number / RTC register selection.
-    fn write_ram(&mut self, addr: u16, content: u8) {
+    fn write_ram(&mut self, addr: u16, content: u8) -> Result<(), BusError> {
         if self.extern_ram_enable {
             match self.ram_bank_num {
-                0..=3 => self.ram[addr as usize - RAM_BANK_BASE + self.ram_offset] = content,
-                0x08 => self.timer_write_only.sec = content & 0x3F, // <= 60s
+                0..=3 => {
+                    let index = self.ram_index(addr)?;
+                    self.ram[index] = content;
+                }
+                0x08 => { // restarts the second
+                    self.timer_write_only.sec = content & 0x3F; // <= 60s
+                    self.cycles = 0;
+                }
                 0x09 => self.timer_write_only.min = content & 0x3F, // <= 60m
                 0x0A => self.timer_write_only.hrs = content & 0x1F, // <= 24
                 0x0B => self.timer_write_only.days_lo = content,
                 0x0C => self.timer_write_only.days_hi = content & 0b1100_0001, // extracts day counter, carry bit, halt flag
-                _ => panic!("Unsupported ram bank number 0x{:x}", self.ram_bank_num),
+                _ => return Err(BusError::InvalidRamBank(self.ram_bank_num)),
             }
         }
+        Ok(())
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_offset / (16 * 1024)
     }
 
     fn copy_ram(&self) -> Option<Box<[u8]>> { // Pass RAM over to another hardware to use
@@ -147,4 +240,71 @@ impl Mbc for Mbc3 {
             None 
         }
     }
+
+    fn rtc(&self) -> Option<Rtc> {
+        Some(Rtc {
+            current: self.timer_write_only.registers(),
+            latched: self.timer_read_only.registers(),
+            timestamp: unix_time(),
+        })
+    }
+
+    // Runs the clock on from the time the save was written to now. A clock with no time, or one
+    // from the future, runs on from where it is.
+    fn set_rtc(&mut self, rtc: &Rtc) {
+        self.timer_write_only = Timer::from_registers(rtc.current);
+        self.timer_read_only = Timer::from_registers(rtc.latched);
+        self.cycles = 0;
+        if rtc.timestamp != 0 {
+            self.timer_write_only.advance(unix_time().saturating_sub(rtc.timestamp));
+        }
+    }
+
+    fn cycle_flush(&mut self, cycles: u32) {
+        if self.timer_write_only.halted() {
+            return;
+        }
+        self.cycles += cycles;
+        if self.cycles >= CPU_CLOCK {
+            self.timer_write_only.advance((self.cycles / CPU_CLOCK) as u64);
+            self.cycles %= CPU_CLOCK;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.timer_latch = false;
+        self.extern_ram_enable = false;
+        self.rom_bank_num = 0;
+        self.ram_bank_num = 0;
+        self.rom_offset = ROM_BANK_BASE;
+        self.ram_offset = 0;
+        self.ram_mode = true;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.timer_write_only.save_state(state);
+        self.timer_read_only.save_state(state);
+        state.write_bool(self.timer_latch);
+        state.write_u32(self.cycles);
+        state.write_bool(self.extern_ram_enable);
+        state.write_u8(self.rom_bank_num);
+        state.write_u8(self.ram_bank_num);
+        state.write_bool(self.ram_mode);
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.timer_write_only.load_state(state)?;
+        self.timer_read_only.load_state(state)?;
+        self.timer_latch = state.read_bool()?;
+        self.cycles = state.read_u32()?;
+        self.extern_ram_enable = state.read_bool()?;
+        self.rom_bank_num = state.read_u8()?;
+        self.ram_bank_num = state.read_u8()?;
+        self.ram_mode = state.read_bool()?;
+        state.read_into(&mut self.ram, "mbc3 ram")?;
+        self.update_rom_offset();
+        self.update_ram_offset();
+        Ok(())
+    }
 }
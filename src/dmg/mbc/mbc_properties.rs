@@ -6,6 +6,9 @@ use super::rom_only::RomOnly;
 use super::mbc1::Mbc1;
 use super::mbc2::Mbc2;
 use super::mbc3::Mbc3;
+use super::super::state::{StateError, StateReader, StateWriter};
+use super::super::error::{BusError, CartError};
+use super::super::sav::Rtc;
 //use super::mbc5::Mbc5;
 
 #[derive(Debug)]
@@ -20,26 +23,53 @@ pub enum MbcType { // Should be specified at byte (0x0147) in ROM.
 // MBC should be able to read and write to any bank, given an address.
 // MBC should be able to read and write to RAM as well, to interact with other hardware such as
 // Display Control Registers etc...
-pub trait Mbc {
+// Send, since a console and its cartridge can be moved to another thread
+pub trait Mbc: Send {
     // read / write operations for Mbc
-    fn read_rom(&self, rom: &Box<[u8]>, addr: u16) -> u8;
-    fn write_rom(&mut self, addr: u16, content: u8); // rom is read_only. Write only serves to toggle
+    // Errors mean the game asked for something the cartridge does not have. The bus decides
+    // what to do with them, MBCs should never panic.
+    fn read_rom(&self, rom: &[u8], addr: u16) -> Result<u8, BusError>;
+    fn write_rom(&mut self, addr: u16, content: u8) -> Result<(), BusError>; // rom is read_only. Write only serves to toggle
     // read / write operations interacting with RAM
-    fn read_ram(&self, addr: u16) -> u8;
-    fn write_ram(&mut self, addr: u16, val: u8);
+    fn read_ram(&self, addr: u16) -> Result<u8, BusError>;
+    fn write_ram(&mut self, addr: u16, val: u8) -> Result<(), BusError>;
+    // ROM bank currently mapped at 0x4000 - 0x7FFF
+    fn rom_bank(&self) -> usize;
+    // ROM bank currently mapped at addr, anywhere in 0x0000 - 0x7FFF
+    fn rom_bank_at(&self, addr: u16) -> usize {
+        if addr < 0x4000 { 0 } else { self.rom_bank() }
+    }
     // Return RAM. Read up first
     fn copy_ram(&self) -> Option<Box<[u8]>>; // ????
+    // The clock, running and latched, for cartridges that have one, as of the time now
+    fn rtc(&self) -> Option<Rtc> {
+        None
+    }
+    fn set_rtc(&mut self, _rtc: &Rtc) {}
+    // Runs the clock on by cycles of emulated time
+    fn cycle_flush(&mut self, _cycles: u32) {}
+    // Banking registers back to power on. RAM and the clock keep running on the battery.
+    fn reset(&mut self);
+    // Save states: banking registers and external RAM
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
 }
 
-pub fn new_mbc(mbc_info: MbcInfo, ram: Option<Box<[u8]>>) -> Box<Mbc> {
-    match mbc_info.mbc_type {
+// rom_banks as the header gives them
+pub fn new_mbc(mbc_info: MbcInfo, rom_banks: usize, ram: Option<Box<[u8]>>) -> Result<Box<dyn Mbc>, CartError> {
+    Ok(match mbc_info.mbc_type {
         MbcType::None => Box::new(RomOnly {}),
-        MbcType::Mbc1 => Box::new(Mbc1::new(mbc_info, ram)),
+        MbcType::Mbc1 => Box::new(Mbc1::new(mbc_info, rom_banks, ram)?),
         MbcType::Mbc2 => Box::new(Mbc2::new(mbc_info, ram)),
-        MbcType::Mbc3 => Box::new(Mbc3::new(mbc_info, ram)),
+        MbcType::Mbc3 => Box::new(Mbc3::new(mbc_info, ram)?),
         //MbcType::Mbc5 => Box::new(Mbc5::new(mbc_info, ram)),
-        _ => panic!("Unimplemented MBC"),
-    }
+        MbcType::Mbc5 => return Err(CartError::UnsupportedMbc(mbc_info.cart_type)),
+    })
+}
+
+// Reads a banked ROM byte, for ROM offsets past the end of the dump
+pub fn read_rom_at(rom: &[u8], addr: u16, offset: usize) -> Result<u8, BusError> {
+    rom.get(offset).copied().ok_or(BusError::RomOutOfRange { addr, offset })
 }
 
 // Each MBC should carry following information, can be obtained from :
@@ -50,14 +80,16 @@ pub fn new_mbc(mbc_info: MbcInfo, ram: Option<Box<[u8]>>) -> Box<Mbc> {
 #[derive(Debug)]
 pub struct MbcInfo {
     mbc_type: MbcType,
+    cart_type: u8, // raw header byte (0x0147), for error messages
     pub ram_info: Option<RamInfo>,
     has_battery: bool,
 }
 
 impl MbcInfo {
-    pub fn new(mbc_type: MbcType, ram_info: Option<RamInfo>, has_battery: bool) -> Self {
+    pub fn new(mbc_type: MbcType, cart_type: u8, ram_info: Option<RamInfo>, has_battery: bool) -> Self {
         MbcInfo {
             mbc_type: mbc_type,
+            cart_type,
             ram_info: ram_info,
             has_battery: has_battery,
         }
@@ -83,17 +115,17 @@ impl RamInfo {
 
 
     // Enable external RAM if any exists. If none exists, create a blank external RAM
-    pub fn make_external_ram(&self, saved_ram: Option<Box<[u8]>>) -> Box<[u8]> {
+    pub fn make_external_ram(&self, saved_ram: Option<Box<[u8]>>) -> Result<Box<[u8]>, CartError> {
         match saved_ram {
             Some(extern_ram) => {
                 if extern_ram.len() == self.size as usize { // if exisiting RAM matches the specified RAM size for MBC
-                    extern_ram
+                    Ok(extern_ram)
                 } else {
-                    panic!("Saved_ram size mismatch! MBC expected {}, existing ram has size {}", self.size, extern_ram.len());
+                    Err(CartError::RamSizeMismatch { expected: self.size as usize, actual: extern_ram.len() })
                 }
             }
             // No external RAM => Create a blank one
-            None => vec![0; self.size as usize].into_boxed_slice(),
+            None => Ok(vec![0; self.size as usize].into_boxed_slice()),
         }
     }
 }
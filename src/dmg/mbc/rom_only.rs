@@ -3,28 +3,46 @@
 use super::Mbc; // trait
 use super::RamInfo; // struct
 use super::MbcInfo; // struct
+use super::super::state::{StateError, StateReader, StateWriter};
+use super::super::error::BusError;
 
 pub struct RomOnly {}
 
 impl Mbc for RomOnly {
-    fn read_rom(&self, rom: &Box<[u8]>, addr: u16) -> u8 {
-        rom[addr as usize]
+    fn read_rom(&self, rom: &[u8], addr: u16) -> Result<u8, BusError> {
+        super::read_rom_at(rom, addr, addr as usize)
     }
     
-    #[allow(dead_code)]
-    fn write_rom(&mut self, addr: u16, content: u8) {
+    fn write_rom(&mut self, _addr: u16, _content: u8) -> Result<(), BusError> {
         // does nothing
+        Ok(())
     }
 
-    fn read_ram(&self, addr: u16) -> u8 {
-        0
+    fn read_ram(&self, addr: u16) -> Result<u8, BusError> {
+        Err(BusError::NoExternalRam { addr })
     }
 
-    fn write_ram(&mut self, addr: u16, content: u8) {
+    fn write_ram(&mut self, addr: u16, _content: u8) -> Result<(), BusError> {
+        Err(BusError::NoExternalRam { addr })
+    }
 
+    fn rom_bank(&self) -> usize {
+        1
     }
 
     fn copy_ram(&self) -> Option<Box<[u8]>> {
         None
     }
+
+    fn reset(&mut self) {
+        // no registers
+    }
+
+    fn save_state(&self, _state: &mut StateWriter) {
+        // no registers, no RAM
+    }
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
 }
@@ -0,0 +1,93 @@
+// What work RAM, VRAM and high RAM hold at power on.
+// Real RAM does not come up empty, and some games seed their random numbers from whatever is
+// there, so what they do depends on it. MemoryInit (see EmuConfig) picks what the emulator
+// fills it with:
+//
+//     zero      all zeros, the default
+//     hardware  close to what the model's RAM typically shows. Every unit differs a little, so
+//               these are fixed stand-ins: noise on the DMG, whose RAM comes up close to
+//               random, and on the CGB runs of 8 0x00 and 8 0xff bytes in work RAM with the
+//               rest cleared
+//     seeded    noise from the given seed, to try a game against other power-on contents
+//
+// Every pattern is deterministic, so movies and netplay still line up as long as both sides
+// use the same setting.
+
+use serde::{Deserialize, Serialize};
+
+use super::console::Model;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryInit {
+    #[default]
+    Zero,
+    Hardware,
+    Seeded(u64),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Region {
+    Wram,
+    Vram,
+    Hram,
+}
+
+// For hardware on the DMG, so it gives the same noise every time
+const DMG_SEED: u64 = 0x0D3A_D1B0_0000_0DA7;
+
+// xorshift64*, plenty for filling RAM
+fn noise(seed: u64, memory: &mut [u8]) {
+    let mut state = seed | 1;
+    for byte in memory.iter_mut() {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        *byte = (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8;
+    }
+}
+
+// Fills one region of memory as it would be at power on
+pub fn fill(init: MemoryInit, model: Model, region: Region, memory: &mut [u8]) {
+    // Each region gets a stream of its own
+    let seed = |seed: u64| seed.wrapping_add((region as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    match (init, model, region) {
+        (MemoryInit::Zero, _, _) => memory.iter_mut().for_each(|byte| *byte = 0),
+        (MemoryInit::Hardware, Model::Dmg, _) => noise(seed(DMG_SEED), memory),
+        (MemoryInit::Hardware, Model::Cgb, Region::Wram) => {
+            for (addr, byte) in memory.iter_mut().enumerate() {
+                *byte = if addr & 8 == 0 { 0x00 } else { 0xFF };
+            }
+        }
+        (MemoryInit::Hardware, Model::Cgb, _) => memory.iter_mut().for_each(|byte| *byte = 0),
+        (MemoryInit::Seeded(value), _, _) => noise(seed(value), memory),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(init: MemoryInit, model: Model, region: Region) -> Vec<u8> {
+        let mut memory = vec![0x55; 0x2000];
+        fill(init, model, region, &mut memory);
+        memory
+    }
+
+    #[test]
+    fn patterns_are_deterministic_and_differ() {
+        let wram = |init, model| filled(init, model, Region::Wram);
+        assert!(wram(MemoryInit::Zero, Model::Dmg).iter().all(|&byte| byte == 0));
+        assert_eq!(&wram(MemoryInit::Hardware, Model::Cgb)[..0x10], &[0, 0, 0, 0, 0, 0, 0, 0,
+                   0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert!(filled(MemoryInit::Hardware, Model::Cgb, Region::Hram).iter().all(|&byte| byte == 0));
+
+        let dmg = wram(MemoryInit::Hardware, Model::Dmg);
+        assert_eq!(dmg, wram(MemoryInit::Hardware, Model::Dmg));
+        assert!(dmg.iter().filter(|&&byte| byte == dmg[0]).count() < 0x100);
+        assert_eq!(wram(MemoryInit::Seeded(7), Model::Dmg), wram(MemoryInit::Seeded(7), Model::Cgb));
+        assert_ne!(wram(MemoryInit::Seeded(7), Model::Dmg), wram(MemoryInit::Seeded(8), Model::Dmg));
+        assert_ne!(&filled(MemoryInit::Seeded(7), Model::Dmg, Region::Hram)[..0x7F],
+                   &wram(MemoryInit::Seeded(7), Model::Dmg)[..0x7F]);
+    }
+}
@@ -0,0 +1,183 @@
+// The memory map, for people reading memory: which region an address falls in and what the IO
+// registers are called, and a hexdump that labels every row with them.
+//
+//     ROM 00   0100: 00 c3 50 01 ce ed 66 66 cc 0d 00 0b 03 73 00 83  |..P...ff.....s..|
+//     IO       ff40: 91 00 00 00 00 00 00 fc ff ff 00 00 00 00 00 00  |................|  LCDC=91 STAT=00 ...
+//
+// Rows are 16 bytes on 16 byte boundaries and take the label of their first byte, which is safe
+// since every region starts on one. Rows holding named registers list them with their values.
+
+use std::fmt;
+use std::fmt::Write;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Region {
+    Rom { bank: usize },
+    Vram,
+    CartRam,
+    Wram,
+    Echo,   // mirror of 0xc000 - 0xddff
+    Oam,
+    Unusable,
+    Io,
+    Hram,
+    Ie,
+}
+
+impl Region {
+    // Where addr is with rom_bank mapped at 0x4000 - 0x7fff
+    pub fn of(addr: u16, rom_bank: usize) -> Region {
+        match addr {
+            0x0000..=0x3FFF => Region::Rom { bank: 0 },
+            0x4000..=0x7FFF => Region::Rom { bank: rom_bank },
+            0x8000..=0x9FFF => Region::Vram,
+            0xA000..=0xBFFF => Region::CartRam,
+            0xC000..=0xDFFF => Region::Wram,
+            0xE000..=0xFDFF => Region::Echo,
+            0xFE00..=0xFE9F => Region::Oam,
+            0xFEA0..=0xFEFF => Region::Unusable,
+            0xFF00..=0xFF7F => Region::Io,
+            0xFF80..=0xFFFE => Region::Hram,
+            0xFFFF => Region::Ie,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Region::Rom { bank } => write!(f, "ROM {:02x}", bank),
+            Region::Vram => f.write_str("VRAM"),
+            Region::CartRam => f.write_str("SRAM"),
+            Region::Wram => f.write_str("WRAM"),
+            Region::Echo => f.write_str("ECHO"),
+            Region::Oam => f.write_str("OAM"),
+            Region::Unusable => f.write_str("UNUSED"),
+            Region::Io => f.write_str("IO"),
+            Region::Hram => f.write_str("HRAM"),
+            Region::Ie => f.write_str("IE"),
+        }
+    }
+}
+
+// Names from PanDocs
+pub fn register_name(addr: u16) -> Option<&'static str> {
+    Some(match addr {
+        0xFF00 => "P1",
+        0xFF01 => "SB",
+        0xFF02 => "SC",
+        0xFF04 => "DIV",
+        0xFF05 => "TIMA",
+        0xFF06 => "TMA",
+        0xFF07 => "TAC",
+        0xFF0F => "IF",
+        0xFF10 => "NR10",
+        0xFF11 => "NR11",
+        0xFF12 => "NR12",
+        0xFF13 => "NR13",
+        0xFF14 => "NR14",
+        0xFF16 => "NR21",
+        0xFF17 => "NR22",
+        0xFF18 => "NR23",
+        0xFF19 => "NR24",
+        0xFF1A => "NR30",
+        0xFF1B => "NR31",
+        0xFF1C => "NR32",
+        0xFF1D => "NR33",
+        0xFF1E => "NR34",
+        0xFF20 => "NR41",
+        0xFF21 => "NR42",
+        0xFF22 => "NR43",
+        0xFF23 => "NR44",
+        0xFF24 => "NR50",
+        0xFF25 => "NR51",
+        0xFF26 => "NR52",
+        0xFF40 => "LCDC",
+        0xFF41 => "STAT",
+        0xFF42 => "SCY",
+        0xFF43 => "SCX",
+        0xFF44 => "LY",
+        0xFF45 => "LYC",
+        0xFF46 => "DMA",
+        0xFF47 => "BGP",
+        0xFF48 => "OBP0",
+        0xFF49 => "OBP1",
+        0xFF4A => "WY",
+        0xFF4B => "WX",
+        0xFF50 => "BOOT",
+        0xFF76 => "PCM12",
+        0xFF77 => "PCM34",
+        0xFFFF => "IE",
+        _ => return None,
+    })
+}
+
+// The register name if addr has one, else its region
+pub fn label(addr: u16, rom_bank: usize) -> String {
+    match register_name(addr) {
+        Some(name) => name.to_string(),
+        None => Region::of(addr, rom_bank).to_string(),
+    }
+}
+
+// bytes are memory from start on, wrapping around at 0xffff
+pub fn hexdump(start: u16, bytes: &[u8], rom_bank: usize) -> String {
+    let mut text = String::new();
+    let first_row = start & !0xF;
+    let end = start as usize + bytes.len();
+    let mut row = first_row as usize;
+    while row < end {
+        let addr = row as u16;
+        write!(text, "{:<8} {:04x}:", Region::of(addr, rom_bank).to_string(), addr).unwrap();
+        let mut ascii = String::new();
+        let mut registers = Vec::new();
+        for column in row..row + 16 {
+            let byte = bytes.get(column.wrapping_sub(start as usize)).filter(|_| column >= start as usize);
+            match byte {
+                Some(&byte) => {
+                    write!(text, " {:02x}", byte).unwrap();
+                    ascii.push(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+                    if let Some(name) = register_name(column as u16) {
+                        registers.push(format!("{}={:02x}", name, byte));
+                    }
+                }
+                None => {
+                    text.push_str("   ");
+                    ascii.push(' ');
+                }
+            }
+        }
+        write!(text, "  |{}|", ascii).unwrap();
+        if !registers.is_empty() {
+            write!(text, "  {}", registers.join(" ")).unwrap();
+        }
+        text.push('\n');
+        row += 16;
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_regions_and_registers() {
+        assert_eq!(Region::of(0x4abc, 0x12), Region::Rom { bank: 0x12 });
+        assert_eq!(Region::of(0x3fff, 0x12).to_string(), "ROM 00");
+        assert_eq!(Region::of(0xfe9f, 1), Region::Oam);
+        assert_eq!(label(0xff44, 1), "LY");
+        assert_eq!(label(0xff03, 1), "IO");
+        assert_eq!(label(0xff80, 1), "HRAM");
+    }
+
+    #[test]
+    fn hexdump_pads_partial_rows() {
+        let dump = hexdump(0xff3e, &[0x12, 0x34, 0x91, 0x85], 1);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("IO       ff30:"));
+        assert!(lines[0].ends_with(" 12 34  |              .4|"));
+        assert!(lines[1].ends_with("|..              |  LCDC=91 STAT=85"));
+    }
+}
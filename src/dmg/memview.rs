@@ -0,0 +1,77 @@
+// Memory for GUI panels on other threads, without stopping the emulation to ask.
+// Console::memory_view hands out a MemoryView, and from then on the console copies the whole
+// address space at every VBlank and swaps the copy in. A viewer takes the latest copy with
+// snapshot(), which only locks long enough to clone an Arc, and can keep it for as long as it
+// likes while newer ones come in. Copies are taken between frames, so a viewer never sees
+// memory halfway through an instruction or a frame.
+// Copying stops once every view is dropped.
+
+use std::sync::{Arc, Mutex};
+
+use super::memmap::{self, Region};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemorySnapshot {
+    frame: u64,       // frames run when it was taken
+    rom_bank: usize,  // mapped at 0x4000 - 0x7fff
+    bytes: Box<[u8]>, // 0x0000 - 0xffff
+}
+
+impl MemorySnapshot {
+    // read is called once for every address
+    pub fn new<F: FnMut(u16) -> u8>(frame: u64, rom_bank: usize, read: F) -> MemorySnapshot {
+        let bytes = (0..=0xFFFF).map(read).collect();
+        MemorySnapshot { frame, rom_bank, bytes }
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn rom_bank(&self) -> usize {
+        self.rom_bank
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.bytes[addr as usize]
+    }
+
+    // len bytes from addr on, wrapping around at 0xffff
+    pub fn range(&self, addr: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|offset| self.read(addr.wrapping_add(offset as u16))).collect()
+    }
+
+    pub fn region(&self, addr: u16) -> Region {
+        Region::of(addr, self.rom_bank)
+    }
+
+    // See memmap.rs
+    pub fn hexdump(&self, addr: u16, len: usize) -> String {
+        memmap::hexdump(addr, &self.range(addr, len), self.rom_bank)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryView {
+    latest: Arc<Mutex<Arc<MemorySnapshot>>>,
+}
+
+impl MemoryView {
+    pub(crate) fn new(first: MemorySnapshot) -> MemoryView {
+        MemoryView { latest: Arc::new(Mutex::new(Arc::new(first))) }
+    }
+
+    // The copy from the last VBlank
+    pub fn snapshot(&self) -> Arc<MemorySnapshot> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    pub(crate) fn publish(&self, snapshot: MemorySnapshot) {
+        *self.latest.lock().unwrap() = Arc::new(snapshot);
+    }
+
+    // Whether any view other than this one is left
+    pub(crate) fn is_watched(&self) -> bool {
+        Arc::strong_count(&self.latest) > 1
+    }
+}
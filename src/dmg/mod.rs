@@ -1,12 +1,81 @@
 pub mod dmg_cpu;
 pub mod cart;
+pub mod header;
 pub mod ppu;
 pub mod interconnect;
 pub mod gamepad;
 pub mod console;
 pub mod timer;
+pub mod serial;
+pub mod interrupts;
 pub mod cpu_test;
 pub mod mbc;
+pub mod archive;
+pub mod pipeline;
+pub mod state;
+pub mod error;
+pub mod config;
+pub mod stats;
+pub mod watch;
+pub mod poke;
+pub mod overlay;
+pub mod apu;
+pub mod apu_log;
+#[cfg(feature = "fixed-point-audio")]
+pub mod apu_fixed;
+pub mod wav;
+pub mod speed;
+pub mod gbs;
+pub mod debugger;
+pub mod symbols;
+pub mod profile;
+pub mod memmap;
+pub mod memview;
+pub mod meminit;
+pub mod ioregs;
+pub mod patch;
+pub mod hotload;
+pub mod romdb;
+pub mod remote;
+pub mod launcher;
+pub mod timeline;
+pub mod vram_log;
+pub mod events;
+pub mod gfx;
+pub mod frame;
+pub mod scale;
+pub mod persistence;
+pub mod netplay;
+pub mod four_player;
+pub mod peripheral;
+pub mod movie;
+pub mod sav;
+pub mod bess;
+pub mod input_macro;
+pub mod compare;
+pub mod timing;
+pub mod disasm;
+pub mod crash;
+pub mod farm;
+pub mod compat;
+#[cfg(test)]
+pub mod reference;
+#[cfg(test)]
+pub mod test_roms;
+#[cfg(test)]
+pub mod test_asm;
+#[cfg(test)]
+pub mod cpu_props;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod rpc;
+#[cfg(feature = "server")]
+pub mod dap;
+#[cfg(feature = "server")]
+mod json;
+#[cfg(feature = "jit")]
+pub mod jit;
 
 pub use self::cart::*;
 pub use self::dmg_cpu::*;
@@ -15,13 +84,6 @@ pub use self::interconnect::*;
 pub use self::gamepad::*;
 pub use self::console::*;
 pub use self::timer::*;
-
-bitflags! {
-    pub struct Interrupts: u8 {
-        const INT_VBLANK = 0b0000_0001;
-        const INT_LCDSTAT = 0b0000_0010;
-        const INT_TIMEROVERFLOW = 0b0000_0100;
-        const INT_SERIAL = 0b0000_1000;
-        const INT_JOYPAD = 0b0001_0000;
-    }
-}
+pub use self::interrupts::*;
+pub use self::error::*;
+pub use self::config::*;
@@ -0,0 +1,160 @@
+// Input movies.
+// A movie is the buttons held on every frame of a run, so the run can be played back exactly on
+// a deterministic core. Stored as text, one line for every frame the buttons change, giving the
+// frame and the buttons held from then on:
+//
+//     # title screen, then start
+//     0
+//     120 start
+//     126
+//     300 a right
+//
+// Frames count from where playback starts. Button names are Button::name, in any order and case.
+// Empty lines and lines starting with # are skipped. A run that was powered on with an entropy
+// seed (see Console::set_entropy_hook) starts with it, and is powered on with it again for
+// playback:
+//
+//     seed 1234
+//     0
+
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use super::console::{Button, Console};
+use super::error::MovieError;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Movie {
+    seed: Option<u64>,
+    changes: Vec<(u64, u8)>, // frame, buttons held from then on, by frame
+}
+
+impl Movie {
+    pub fn new() -> Movie {
+        Movie::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        Movie::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        Ok(fs::write(path, self.to_text())?)
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| MovieError::Parse { line: index + 1, reason: reason.to_string() };
+            let mut words = line.split_whitespace();
+            if line.starts_with("seed") {
+                if movie.seed.is_some() || !movie.changes.is_empty() {
+                    return Err(invalid("the seed goes first"));
+                }
+                let seed = words.nth(1).and_then(|word| word.parse().ok());
+                movie.seed = Some(seed.ok_or_else(|| invalid("expected a seed"))?);
+                continue;
+            }
+            let frame: u64 = words.next().unwrap().parse().map_err(|_| invalid("expected a frame number"))?;
+            if movie.changes.last().is_some_and(|&(last, _)| frame <= last) {
+                return Err(invalid("frames must go up"));
+            }
+            let mut buttons = 0;
+            for word in words {
+                let button = Button::from_name(word).ok_or_else(|| invalid(&format!("unknown button \"{}\"", word)))?;
+                buttons |= button.mask();
+            }
+            movie.changes.push((frame, buttons));
+        }
+        Ok(movie)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if let Some(seed) = self.seed {
+            writeln!(text, "seed {}", seed).unwrap();
+        }
+        for &(frame, buttons) in &self.changes {
+            write!(text, "{}", frame).unwrap();
+            for button in Button::ALL.iter().filter(|button| buttons & button.mask() != 0) {
+                write!(text, " {}", button.name()).unwrap();
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    // Frames up to and including the last change
+    pub fn len(&self) -> u64 {
+        self.changes.last().map_or(0, |&(frame, _)| frame + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    // Held on frame, see Button::mask
+    pub fn buttons(&self, frame: u64) -> u8 {
+        match self.changes.binary_search_by_key(&frame, |&(changed, _)| changed) {
+            Ok(index) => self.changes[index].1,
+            Err(0) => 0,
+            Err(index) => self.changes[index - 1].1,
+        }
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    // For recording, see Console::entropy_seed
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    // Adds frame to the end of the movie, for recording. Frames must come in order.
+    pub fn record(&mut self, frame: u64, buttons: u8) {
+        if self.buttons(frame) != buttons || self.changes.is_empty() {
+            self.changes.push((frame, buttons));
+        }
+    }
+
+    // Sets the buttons for frame on console. Call before running the frame. With a seed, frame 0
+    // also powers the console on with it.
+    pub fn apply(&self, frame: u64, console: &mut Console) {
+        if let (0, Some(seed)) = (frame, self.seed) {
+            console.power_on_seeded(seed);
+        }
+        console.set_buttons(self.buttons(frame));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_writes_back() {
+        let text = "# comment\n0\n120 start\n\n126\n300 A right\n";
+        let movie = Movie::parse(text).unwrap();
+        assert_eq!(movie.buttons(119), 0);
+        assert_eq!(movie.buttons(125), Button::Start.mask());
+        assert_eq!(movie.buttons(1000), Button::A.mask() | Button::Right.mask());
+        assert_eq!(movie.len(), 301);
+        assert_eq!(movie.to_text(), "0\n120 start\n126\n300 right a\n");
+        assert_eq!(Movie::parse(&movie.to_text()).unwrap(), movie);
+
+        assert!(matches!(Movie::parse("5 jump"), Err(MovieError::Parse { line: 1, .. })));
+        assert!(matches!(Movie::parse("5\n3"), Err(MovieError::Parse { line: 2, .. })));
+
+        let seeded = Movie::parse("seed 1234\n0 a").unwrap();
+        assert_eq!(seeded.seed(), Some(1234));
+        assert_eq!(Movie::parse(&seeded.to_text()).unwrap(), seeded);
+        assert!(matches!(Movie::parse("0\nseed 1"), Err(MovieError::Parse { line: 2, .. })));
+        assert!(matches!(Movie::parse("seed"), Err(MovieError::Parse { line: 1, .. })));
+    }
+}
@@ -0,0 +1,267 @@
+// Lockstep netplay.
+// Two players, each running their own copy of the same console, over any byte stream (usually a
+// TcpStream). The host sends its save state when the session starts, so both sides begin from the
+// same machine. After that only inputs travel: every frame, each side sends the buttons its
+// player holds and waits for the other side's, and both run the frame with the two combined (a
+// button is down if either player holds it). The core is deterministic, so the consoles stay
+// identical. Every HASH_INTERVAL frames the two compare a checksum of their save state, and a
+// mismatch ends the session with NetplayError::Desync instead of letting the games drift apart.
+// Input is delayed by a few frames (set by the host) so a round trip fits in the delay and
+// neither side stalls waiting on the other.
+// There is no goodbye: when one side hangs up, the other's next run_frame fails with an Io
+// error.
+//
+// Messages, integers little-endian:
+//   hello  "GBNP", delay u8, state length u32, save state   host to guest, once
+//   input  'I', frame u64, buttons u8                        every frame, both ways
+//   hash   'H', frame u64, crc32 of the save state u32       every HASH_INTERVAL frames
+// Buttons are one bit each, see Button::mask.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use super::console::{ButtonState, Console, InputEvent, VideoSink};
+use super::error::NetplayError;
+use super::patch::crc32;
+use super::stats::FrameStats;
+
+const MAGIC: &[u8; 4] = b"GBNP";
+pub const HASH_INTERVAL: u64 = 60;
+pub const DEFAULT_DELAY: u8 = 2;
+// Far more than any save state, whose biggest part is at most 128 KiB of cart RAM. Longer hellos
+// are refused before anything is allocated for them.
+const MAX_STATE_SIZE: usize = 4 * 1024 * 1024;
+
+pub struct Netplay<S: Read + Write> {
+    stream: S,
+    frame: u64,
+    delay: u64,
+    // What the local player held, for the frames up to frame + delay
+    local: VecDeque<u8>,
+    held: u8,
+    // Received ahead of the frame they are for, by frame
+    remote_inputs: VecDeque<(u64, u8)>,
+    remote_hashes: VecDeque<(u64, u32)>,
+}
+
+impl<S: Read + Write> Netplay<S> {
+    // Starts a session from console as it is now
+    pub fn host(mut stream: S, console: &Console, delay: u8) -> Result<Netplay<S>, NetplayError> {
+        let state = console.save_state();
+        stream.write_all(MAGIC)?;
+        stream.write_all(&[delay])?;
+        stream.write_all(&(state.len() as u32).to_le_bytes())?;
+        stream.write_all(&state)?;
+        stream.flush()?;
+        Ok(Netplay::new(stream, delay))
+    }
+
+    // Joins the host's session, loading its state into console, which must run the same ROM
+    pub fn join(mut stream: S, console: &mut Console) -> Result<Netplay<S>, NetplayError> {
+        let mut magic = [0; 4];
+        stream.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(NetplayError::Protocol);
+        }
+        let delay = read_u8(&mut stream)?;
+        let mut len = [0; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_STATE_SIZE {
+            return Err(NetplayError::Protocol);
+        }
+        let mut state = vec![0; len];
+        stream.read_exact(&mut state)?;
+        console.load_state(&state)?;
+        Ok(Netplay::new(stream, delay))
+    }
+
+    fn new(stream: S, delay: u8) -> Netplay<S> {
+        Netplay {
+            stream,
+            frame: 0,
+            delay: delay as u64,
+            local: vec![0; delay as usize].into(),
+            held: 0,
+            remote_inputs: VecDeque::new(),
+            remote_hashes: VecDeque::new(),
+        }
+    }
+
+    // Frames run since the session started
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // The local player's input. It reaches the console after the delay.
+    pub fn handle_event(&mut self, event: InputEvent) {
+        let bit = event.button().mask();
+        match event.state() {
+            ButtonState::Down => self.held |= bit,
+            ButtonState::Up => self.held &= !bit,
+        }
+    }
+
+    // Runs one frame in step with the other side. Blocks until its input for the frame arrives.
+    pub fn run_frame(&mut self, console: &mut Console, video_sink: &mut dyn VideoSink)
+                     -> Result<FrameStats, NetplayError> {
+        self.send_input(self.frame + self.delay, self.held)?;
+        self.local.push_back(self.held);
+
+        let local = self.local.pop_front().unwrap();
+        // Nobody pressed anything during the first delay frames
+        let remote = if self.frame < self.delay { 0 } else { self.remote_input()? };
+        console.set_buttons(local | remote);
+        let stats = console.advance_frame(video_sink);
+        self.frame += 1;
+
+        if self.frame % HASH_INTERVAL == 0 {
+            let hash = crc32(&console.save_state());
+            self.send_hash(hash)?;
+            if self.remote_hash()? != hash {
+                return Err(NetplayError::Desync(self.frame));
+            }
+        }
+        Ok(stats)
+    }
+
+    fn send_input(&mut self, frame: u64, buttons: u8) -> Result<(), NetplayError> {
+        self.stream.write_all(b"I")?;
+        self.stream.write_all(&frame.to_le_bytes())?;
+        self.stream.write_all(&[buttons])?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn send_hash(&mut self, hash: u32) -> Result<(), NetplayError> {
+        self.stream.write_all(b"H")?;
+        self.stream.write_all(&self.frame.to_le_bytes())?;
+        self.stream.write_all(&hash.to_le_bytes())?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn remote_input(&mut self) -> Result<u8, NetplayError> {
+        while self.remote_inputs.is_empty() {
+            self.receive()?;
+        }
+        match self.remote_inputs.pop_front() {
+            Some((frame, buttons)) if frame == self.frame => Ok(buttons),
+            _ => Err(NetplayError::Protocol),
+        }
+    }
+
+    fn remote_hash(&mut self) -> Result<u32, NetplayError> {
+        while self.remote_hashes.is_empty() {
+            self.receive()?;
+        }
+        match self.remote_hashes.pop_front() {
+            Some((frame, hash)) if frame == self.frame => Ok(hash),
+            _ => Err(NetplayError::Protocol),
+        }
+    }
+
+    // Reads one message into its queue. Inputs run ahead of hashes by the delay, so either may
+    // come first.
+    fn receive(&mut self) -> Result<(), NetplayError> {
+        let kind = read_u8(&mut self.stream)?;
+        let mut frame = [0; 8];
+        self.stream.read_exact(&mut frame)?;
+        let frame = u64::from_le_bytes(frame);
+        match kind {
+            b'I' => {
+                let buttons = read_u8(&mut self.stream)?;
+                self.remote_inputs.push_back((frame, buttons));
+            }
+            b'H' => {
+                let mut hash = [0; 4];
+                self.stream.read_exact(&mut hash)?;
+                self.remote_hashes.push_back((frame, u32::from_le_bytes(hash)));
+            }
+            _ => return Err(NetplayError::Protocol),
+        }
+        Ok(())
+    }
+}
+
+fn read_u8<R: Read>(stream: &mut R) -> Result<u8, NetplayError> {
+    let mut byte = [0];
+    stream.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use super::super::cart::Cart;
+    use super::super::console::{Button, NoVideo};
+
+    fn tetris() -> Console {
+        let rom = fs::read("tetris.gb").unwrap().into_boxed_slice();
+        Console::new(Cart::new(rom, None).unwrap())
+    }
+
+    type Outcome = Result<Box<[u8]>, NetplayError>;
+
+    // Host and guest run `frames` frames, the guest pressing start on frame 100. Returns both
+    // consoles' final state, or the first error of each side.
+    fn session(frames: u64, tamper: bool) -> (Outcome, Outcome) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let host = thread::spawn(move || {
+            let mut console = tetris();
+            for _ in 0..30 {
+                console.run_frame(&mut NoVideo);
+            }
+            let (stream, _) = listener.accept().unwrap();
+            let mut netplay = Netplay::host(stream, &console, DEFAULT_DELAY)?;
+            for _ in 0..frames {
+                netplay.run_frame(&mut console, &mut NoVideo)?;
+            }
+            Ok(console.save_state())
+        });
+
+        let guest = (|| {
+            let mut console = tetris();
+            let mut netplay = Netplay::join(TcpStream::connect(addr)?, &mut console)?;
+            if tamper {
+                // One frame ahead of the host
+                console.run_frame(&mut NoVideo);
+            }
+            for frame in 0..frames {
+                if frame == 100 {
+                    netplay.handle_event(InputEvent::new(Button::Start, ButtonState::Down));
+                }
+                netplay.run_frame(&mut console, &mut NoVideo)?;
+            }
+            Ok(console.save_state())
+        })();
+        (host.join().unwrap(), guest)
+    }
+
+    #[test]
+    fn consoles_stay_in_sync() {
+        // Ending on a hash check, so neither side hangs up while the other still reads
+        let (host, guest) = session(2 * HASH_INTERVAL, false);
+        assert_eq!(host.unwrap(), guest.unwrap());
+    }
+
+    #[test]
+    fn desync_is_caught() {
+        let (host, guest) = session(2 * HASH_INTERVAL, true);
+        assert!(matches!(host, Err(NetplayError::Desync(HASH_INTERVAL))));
+        assert!(matches!(guest, Err(NetplayError::Desync(HASH_INTERVAL))));
+    }
+
+    #[test]
+    fn huge_states_are_refused() {
+        let mut hello = MAGIC.to_vec();
+        hello.push(DEFAULT_DELAY);
+        hello.extend_from_slice(&u32::MAX.to_le_bytes());
+        let joined = Netplay::join(std::io::Cursor::new(hello), &mut tetris());
+        assert!(matches!(joined, Err(NetplayError::Protocol)));
+    }
+}
@@ -0,0 +1,217 @@
+// Debug HUD.
+// When enabled on the console, finished frames pass through here before reaching the video
+// sink: a few lines of text (FPS, speed relative to real hardware, mapped ROM bank, watch
+// values) are drawn in the top left corner with a built-in 8x8 font, so every frontend gets the
+// same HUD without any work of its own.
+
+use std::time::Instant;
+
+use super::console::{Frame, VideoSink};
+use super::ppu::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
+
+const GLYPH_SIZE: usize = 8;
+const TEXT_COLOR: u32 = 0xFFFF_FFFF;
+const SHADOW_COLOR: u32 = 0xFF00_0000;
+// Frames per second of real hardware (4194304 Hz / 70224 clocks per frame)
+const HARDWARE_FPS: f64 = 59.73;
+
+// Printable ASCII 0x20 - 0x5F, lowercase is drawn as uppercase. One byte per row, top row first,
+// least significant bit is the leftmost pixel. From the public domain font8x8 by Daniel Hepper.
+const FONT: [[u8; 8]; 64] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00], // '!'
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x36, 0x36, 0x7F, 0x36, 0x7F, 0x36, 0x36, 0x00], // '#'
+    [0x0C, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x0C, 0x00], // '$'
+    [0x00, 0x63, 0x33, 0x18, 0x0C, 0x66, 0x63, 0x00], // '%'
+    [0x1C, 0x36, 0x1C, 0x6E, 0x3B, 0x33, 0x6E, 0x00], // '&'
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // '''
+    [0x18, 0x0C, 0x06, 0x06, 0x06, 0x0C, 0x18, 0x00], // '('
+    [0x06, 0x0C, 0x18, 0x18, 0x18, 0x0C, 0x06, 0x00], // ')'
+    [0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00], // '*'
+    [0x00, 0x0C, 0x0C, 0x3F, 0x0C, 0x0C, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ','
+    [0x00, 0x00, 0x00, 0x3F, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x00], // '.'
+    [0x60, 0x30, 0x18, 0x0C, 0x06, 0x03, 0x01, 0x00], // '/'
+    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00], // '0'
+    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00], // '1'
+    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00], // '2'
+    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00], // '3'
+    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00], // '4'
+    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00], // '5'
+    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00], // '6'
+    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00], // '7'
+    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00], // '8'
+    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00], // '9'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00], // ':'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ';'
+    [0x18, 0x0C, 0x06, 0x03, 0x06, 0x0C, 0x18, 0x00], // '<'
+    [0x00, 0x00, 0x3F, 0x00, 0x00, 0x3F, 0x00, 0x00], // '='
+    [0x06, 0x0C, 0x18, 0x30, 0x18, 0x0C, 0x06, 0x00], // '>'
+    [0x1E, 0x33, 0x30, 0x18, 0x0C, 0x00, 0x0C, 0x00], // '?'
+    [0x3E, 0x63, 0x7B, 0x7B, 0x7B, 0x03, 0x1E, 0x00], // '@'
+    [0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00], // 'A'
+    [0x3F, 0x66, 0x66, 0x3E, 0x66, 0x66, 0x3F, 0x00], // 'B'
+    [0x3C, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3C, 0x00], // 'C'
+    [0x1F, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1F, 0x00], // 'D'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x46, 0x7F, 0x00], // 'E'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x06, 0x0F, 0x00], // 'F'
+    [0x3C, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7C, 0x00], // 'G'
+    [0x33, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x33, 0x00], // 'H'
+    [0x1E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'I'
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E, 0x00], // 'J'
+    [0x67, 0x66, 0x36, 0x1E, 0x36, 0x66, 0x67, 0x00], // 'K'
+    [0x0F, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7F, 0x00], // 'L'
+    [0x63, 0x77, 0x7F, 0x7F, 0x6B, 0x63, 0x63, 0x00], // 'M'
+    [0x63, 0x67, 0x6F, 0x7B, 0x73, 0x63, 0x63, 0x00], // 'N'
+    [0x1C, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1C, 0x00], // 'O'
+    [0x3F, 0x66, 0x66, 0x3E, 0x06, 0x06, 0x0F, 0x00], // 'P'
+    [0x1E, 0x33, 0x33, 0x33, 0x3B, 0x1E, 0x38, 0x00], // 'Q'
+    [0x3F, 0x66, 0x66, 0x3E, 0x36, 0x66, 0x67, 0x00], // 'R'
+    [0x1E, 0x33, 0x07, 0x0E, 0x38, 0x33, 0x1E, 0x00], // 'S'
+    [0x3F, 0x2D, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'T'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3F, 0x00], // 'U'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'V'
+    [0x63, 0x63, 0x63, 0x6B, 0x7F, 0x77, 0x63, 0x00], // 'W'
+    [0x63, 0x63, 0x36, 0x1C, 0x1C, 0x36, 0x63, 0x00], // 'X'
+    [0x33, 0x33, 0x33, 0x1E, 0x0C, 0x0C, 0x1E, 0x00], // 'Y'
+    [0x7F, 0x63, 0x31, 0x18, 0x4C, 0x66, 0x7F, 0x00], // 'Z'
+    [0x1E, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1E, 0x00], // '['
+    [0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x40, 0x00], // '\'
+    [0x1E, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1E, 0x00], // ']'
+    [0x08, 0x1C, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF], // '_'
+];
+
+fn glyph(c: char) -> &'static [u8; 8] {
+    let c = c.to_ascii_uppercase() as usize;
+    match c {
+        0x20..=0x5F => &FONT[c - 0x20],
+        _ => &FONT['?' as usize - 0x20],
+    }
+}
+
+// Draws text with its top left corner at (x, y), clipped to the screen
+pub fn draw_text(frame: &mut [u32], x: usize, y: usize, text: &str, color: u32) {
+    for (i, c) in text.chars().enumerate() {
+        let left = x + i * GLYPH_SIZE;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_SIZE {
+                let (px, py) = (left + col, y + row);
+                if bits & (1 << col) != 0 && px < DISPLAY_WIDTH && py < DISPLAY_HEIGHT {
+                    frame[py * DISPLAY_WIDTH + px] = color;
+                }
+            }
+        }
+    }
+}
+
+// Which lines to show, plus the frame timing needed for FPS and speed
+pub struct Overlay {
+    pub fps: bool,
+    pub speed: bool,
+    pub rom_bank: bool,
+    pub watches: bool,
+    last_frame: Option<Instant>,
+    frame_rate: f64,
+    buffer: Vec<u32>, // the frame with the text on, reused
+}
+
+impl Overlay {
+    pub fn new() -> Overlay {
+        Overlay {
+            fps: true,
+            speed: true,
+            rom_bank: true,
+            watches: true,
+            last_frame: None,
+            frame_rate: 0.0,
+            buffer: Vec::new(),
+        }
+    }
+
+    // Called once per delivered frame. The rate is smoothed so the digits stay readable.
+    fn tick(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_frame {
+            let seconds = now.duration_since(last).as_secs_f64();
+            if seconds > 0.0 {
+                self.frame_rate = self.frame_rate * 0.9 + (1.0 / seconds) * 0.1;
+            }
+        }
+        self.last_frame = Some(now);
+    }
+
+    // Text lines for the HUD, given the mapped ROM bank and the watches as "expr value" pairs
+    pub fn lines(&self, rom_bank: usize, watches: &[String]) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.fps {
+            lines.push(format!("FPS {:.1}", self.frame_rate));
+        }
+        if self.speed {
+            lines.push(format!("SPD {:.0}%", self.frame_rate / HARDWARE_FPS * 100.0));
+        }
+        if self.rom_bank {
+            lines.push(format!("BANK {:02X}", rom_bank));
+        }
+        if self.watches {
+            lines.extend(watches.iter().cloned());
+        }
+        lines
+    }
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Overlay::new()
+    }
+}
+
+// Sits between the PPU and the frontend's sink for one frame
+pub struct OverlaySink<'a> {
+    overlay: &'a mut Overlay,
+    lines: Vec<String>,
+    video_sink: &'a mut dyn VideoSink,
+}
+
+impl<'a> OverlaySink<'a> {
+    pub fn new(overlay: &'a mut Overlay, lines: Vec<String>, video_sink: &'a mut dyn VideoSink) -> Self {
+        OverlaySink {
+            overlay,
+            lines,
+            video_sink,
+        }
+    }
+}
+
+impl<'a> VideoSink for OverlaySink<'a> {
+    fn frame_available(&mut self, frame: &Frame) {
+        self.overlay.tick();
+        let pixels = &mut self.overlay.buffer;
+        pixels.clear();
+        pixels.extend(frame.rows().flatten());
+        for (i, line) in self.lines.iter().enumerate() {
+            let y = 1 + i * (GLYPH_SIZE + 1);
+            draw_text(pixels, 2, y + 1, line, SHADOW_COLOR);
+            draw_text(pixels, 1, y, line, TEXT_COLOR);
+        }
+        self.video_sink.frame_available(&Frame::new(frame.width, frame.height, frame.width, pixels));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_clipped_text() {
+        let mut frame = vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        draw_text(&mut frame, 0, 0, "1", TEXT_COLOR);
+        // Top row of '1' is 0x0C: pixels 2 and 3
+        assert_eq!(&frame[..5], &[0, 0, TEXT_COLOR, TEXT_COLOR, 0]);
+
+        // Off the bottom right corner, must not panic
+        draw_text(&mut frame, DISPLAY_WIDTH - 4, DISPLAY_HEIGHT - 4, "MM", TEXT_COLOR);
+    }
+}
@@ -0,0 +1,261 @@
+// ROM patches.
+// Translations and hacks are shared as patches against the original ROM rather than as ROMs.
+// apply recognizes the two formats they come in by their magic bytes:
+//   - IPS: records of "write these bytes at this offset", optionally run-length encoded
+//   - BPS: copy commands from the source ROM, the patch, or the output so far, with CRC32s of
+//     the source, output and patch to catch the wrong base ROM
+// Formats: https://zerosoft.zophar.net/ips.php and https://www.romhacking.net/documents/746/
+
+use super::error::PatchError;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+const BPS_FOOTER_SIZE: usize = 12;
+// The largest cartridges, MBC5's, hold 8 MiB
+const MAX_TARGET_SIZE: usize = 8 * 1024 * 1024;
+
+pub fn is_patch(bytes: &[u8]) -> bool {
+    bytes.starts_with(IPS_MAGIC) || bytes.starts_with(BPS_MAGIC)
+}
+
+// The patched ROM
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(PatchError::Truncated);
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.take(1)?[0])
+    }
+
+    // Big-endian, as IPS stores everything
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self.take(len)?.iter().fold(0, |acc, &byte| (acc << 8) | byte as usize))
+    }
+
+    // BPS variable length number: 7 bits per byte, last byte has the top bit set
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.byte()?;
+            value = (byte as usize & 0x7F).checked_mul(shift)
+                .and_then(|bits| value.checked_add(bits))
+                .ok_or(PatchError::OutOfRange)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::OutOfRange)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfRange)?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader { bytes: patch, pos: IPS_MAGIC.len() };
+    loop {
+        if reader.take(3)? == IPS_EOF {
+            break;
+        }
+        reader.pos -= 3;
+        let offset = reader.be(3)?;
+        let (len, fill) = match reader.be(2)? {
+            0 => (reader.be(2)?, Some(reader.byte()?)), // run of one value
+            len => (len, None),
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match fill {
+            Some(val) => out[offset..offset + len].iter_mut().for_each(|byte| *byte = val),
+            None => out[offset..offset + len].copy_from_slice(reader.take(len)?),
+        }
+    }
+    // Some patches end with the size to cut the ROM down to
+    if let Ok(size) = reader.be(3) {
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+    let footer = &patch[patch.len() - BPS_FOOTER_SIZE..];
+    let crc = |at: usize| u32::from_le_bytes([footer[at], footer[at + 1], footer[at + 2], footer[at + 3]]);
+    if crc32(&patch[..patch.len() - 4]) != crc(8) {
+        return Err(PatchError::BadChecksum);
+    }
+    if crc32(rom) != crc(0) {
+        return Err(PatchError::WrongSource);
+    }
+
+    let mut reader = Reader { bytes: &patch[..patch.len() - BPS_FOOTER_SIZE], pos: BPS_MAGIC.len() };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.take(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::WrongSource);
+    }
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::TooLarge(target_size));
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    // Moves offset back or forth by a signed BPS number
+    let relative = |reader: &mut Reader, offset: usize| -> Result<usize, PatchError> {
+        let data = reader.number()?;
+        let (back, distance) = (data & 1 != 0, data >> 1);
+        let moved = if back { offset.checked_sub(distance) } else { offset.checked_add(distance) };
+        moved.ok_or(PatchError::OutOfRange)
+    };
+
+    while reader.pos < reader.bytes.len() {
+        let command = reader.number()?;
+        let len = (command >> 2) + 1;
+        if len > target_size - out.len() {
+            return Err(PatchError::OutOfRange);
+        }
+        match command & 3 {
+            0 => {
+                // Same bytes as the source at this position
+                let start = out.len();
+                let bytes = rom.get(start..start + len).ok_or(PatchError::OutOfRange)?;
+                out.extend_from_slice(bytes);
+            }
+            1 => out.extend_from_slice(reader.take(len)?),
+            2 => {
+                source_offset = relative(&mut reader, source_offset)?;
+                let end = source_offset.checked_add(len).ok_or(PatchError::OutOfRange)?;
+                out.extend_from_slice(rom.get(source_offset..end).ok_or(PatchError::OutOfRange)?);
+                source_offset = end;
+            }
+            _ => {
+                // From earlier output, byte by byte since the ranges may overlap
+                target_offset = relative(&mut reader, target_offset)?;
+                for _ in 0..len {
+                    let byte = *out.get(target_offset).ok_or(PatchError::OutOfRange)?;
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size || crc32(&out) != crc(4) {
+        return Err(PatchError::BadChecksum);
+    }
+    Ok(out)
+}
+
+// CRC-32 as in zip and PNG
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bps_number(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let bits = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(bits | 0x80);
+                return;
+            }
+            out.push(bits);
+            value -= 1;
+        }
+    }
+
+    #[test]
+    fn applies_ips_and_bps() {
+        let rom = b"Hello, world!".to_vec();
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0, 0, 7, 0, 5]);
+        ips.extend_from_slice(b"rusty");
+        ips.extend_from_slice(&[0, 0, 13, 0, 0, 0, 3, b'!']); // three more '!'
+        ips.extend_from_slice(b"EOF");
+        assert_eq!(apply(&rom, &ips).unwrap(), b"Hello, rusty!!!!");
+
+        let target = b"Hello, Hello!".to_vec();
+        let mut bps = b"BPS1".to_vec();
+        bps_number(&mut bps, rom.len());
+        bps_number(&mut bps, target.len());
+        bps_number(&mut bps, 0);
+        bps_number(&mut bps, (7 - 1) << 2); // source read "Hello, "
+        bps_number(&mut bps, ((5 - 1) << 2) | 3); // target copy "Hello" from 0
+        bps_number(&mut bps, 0);
+        bps_number(&mut bps, 1); // target read "!", one byte
+        bps.push(b'!');
+        bps.extend_from_slice(&crc32(&rom).to_le_bytes());
+        bps.extend_from_slice(&crc32(&target).to_le_bytes());
+        bps.extend_from_slice(&crc32(&bps).to_le_bytes());
+        assert_eq!(apply(&rom, &bps).unwrap(), target);
+
+        assert_eq!(apply(b"Goodbye", &bps), Err(PatchError::WrongSource));
+        assert_eq!(apply(&rom, b"nope"), Err(PatchError::UnknownFormat));
+        assert_eq!(apply(&rom, &ips[..10]), Err(PatchError::Truncated));
+    }
+
+    #[test]
+    fn rejects_bps_patches_that_run_away() {
+        let rom = b"Hello, world!".to_vec();
+        // The patch's own checksum is right, so only the commands can give it away
+        let bps = |target_size: usize, commands: &[usize]| {
+            let mut bps = b"BPS1".to_vec();
+            bps_number(&mut bps, rom.len());
+            bps_number(&mut bps, target_size);
+            bps_number(&mut bps, 0);
+            commands.iter().for_each(|&number| bps_number(&mut bps, number));
+            bps.extend_from_slice(&crc32(&rom).to_le_bytes());
+            bps.extend_from_slice(&0u32.to_le_bytes());
+            bps.extend_from_slice(&crc32(&bps).to_le_bytes());
+            bps
+        };
+        assert_eq!(apply(&rom, &bps(1 << 40, &[])), Err(PatchError::TooLarge(1 << 40)));
+        // After a byte of its own, a target copy of a terabyte
+        let forever = bps(16, &[1, 0, (((1 << 40) - 1) << 2) | 3, 0]);
+        assert_eq!(apply(&rom, &forever), Err(PatchError::OutOfRange));
+        // A source copy from far past the end of the ROM
+        let far = bps(16, &[(3 << 2) | 2, (1 << 62) << 1]);
+        assert_eq!(apply(&rom, &far), Err(PatchError::OutOfRange));
+    }
+}
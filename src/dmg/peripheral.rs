@@ -0,0 +1,211 @@
+// Scripted peripherals.
+// Link port accessories that only ever say the same few things, such as barcode readers (the
+// Barcode Boy) or the Pocket Sonar, can be played from a script instead of being emulated. A
+// PeripheralScript says what the device does, step by step, and ScriptedDevice plays it on the
+// link port (see Console::attach_serial):
+//
+//     # answer the game's handshake
+//     answer ff ff 10 07
+//     # a second later, send a reading on the device's own clock
+//     wait 4194304
+//     send 02 34 39 30 32 37 37 38 30 30 30 30 30 30 03
+//
+//     answer   replies to the next bytes the game sends on its own clock, whatever they are
+//     send     clocks bytes into the game, each once the game waits for one and at least
+//              SEND_GAP cycles after the step before
+//     wait     does nothing for that many cycles
+//
+// Steps run in order. Bytes the game sends when the script is not at an answer get 0xFF, as do
+// all of them once the script is over. Bytes are hex. Empty lines and lines starting with # are
+// skipped.
+//
+// PeripheralScript::barcodes writes the script for a Barcode Boy swiping each barcode in a list.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+
+use super::error::PeripheralError;
+use super::serial::SerialDevice;
+
+// A byte at the DMG's own serial clock
+pub const SEND_GAP: u32 = 8 * 512;
+const SECOND: u32 = 4_194_304;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Step {
+    Answer(u8),
+    Send(u8),
+    Wait(u32),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeripheralScript {
+    steps: Vec<Step>,
+}
+
+impl PeripheralScript {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<PeripheralScript, PeripheralError> {
+        PeripheralScript::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<PeripheralScript, PeripheralError> {
+        let mut steps = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| PeripheralError::Parse { line: index + 1, reason: reason.to_string() };
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap();
+            let args: Vec<&str> = words.collect();
+            let bytes = || -> Result<Vec<u8>, PeripheralError> {
+                if args.is_empty() {
+                    return Err(invalid("expected bytes"));
+                }
+                args.iter()
+                    .map(|arg| u8::from_str_radix(arg, 16).map_err(|_| invalid(&format!("invalid byte \"{}\"", arg))))
+                    .collect()
+            };
+            match command {
+                "answer" => steps.extend(bytes()?.into_iter().map(Step::Answer)),
+                "send" => steps.extend(bytes()?.into_iter().map(Step::Send)),
+                "wait" => match args[..] {
+                    [cycles] => steps.push(Step::Wait(cycles.parse().map_err(|_| invalid("expected cycles"))?)),
+                    _ => return Err(invalid("expected cycles")),
+                },
+                _ => return Err(invalid(&format!("unknown step \"{}\", expected answer, send or wait", command))),
+            }
+        }
+        Ok(PeripheralScript { steps })
+    }
+
+    // A list of barcodes, one EAN-13 (13 digits) a line, with # comments
+    pub fn load_barcodes<P: AsRef<Path>>(path: P) -> Result<PeripheralScript, PeripheralError> {
+        PeripheralScript::barcodes(&fs::read_to_string(path)?)
+    }
+
+    // The Barcode Boy: it answers the game's handshake, then after a second swipes each barcode,
+    // a second apart. A swipe is 0x02, the digits in ASCII and 0x03, on its own clock.
+    pub fn barcodes(text: &str) -> Result<PeripheralScript, PeripheralError> {
+        let mut steps: Vec<Step> = [0xFF, 0xFF, 0x10, 0x07].iter().map(|&byte| Step::Answer(byte)).collect();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.len() != 13 || !line.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(PeripheralError::Parse { line: index + 1, reason: "expected a 13 digit barcode".to_string() });
+            }
+            steps.push(Step::Wait(SECOND));
+            steps.push(Step::Send(0x02));
+            steps.extend(line.bytes().map(Step::Send));
+            steps.push(Step::Send(0x03));
+        }
+        Ok(PeripheralScript { steps })
+    }
+}
+
+// Plays a PeripheralScript
+#[derive(Debug, Clone)]
+pub struct ScriptedDevice {
+    steps: VecDeque<Step>,
+    elapsed: u32, // cycles since the step before finished
+}
+
+impl ScriptedDevice {
+    pub fn new(script: PeripheralScript) -> ScriptedDevice {
+        ScriptedDevice { steps: script.steps.into(), elapsed: 0 }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    fn next_step(&mut self) {
+        self.steps.pop_front();
+        self.elapsed = 0;
+    }
+}
+
+impl SerialDevice for ScriptedDevice {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        debug!(target: "gbrust::serial", "game sent 0x{:02x}", byte);
+        match self.steps.front() {
+            Some(&Step::Answer(reply)) => {
+                self.next_step();
+                reply
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn tick(&mut self, cycles: u32, waiting: bool) -> Option<u8> {
+        self.elapsed = self.elapsed.saturating_add(cycles);
+        match self.steps.front() {
+            Some(&Step::Wait(wait)) if self.elapsed >= wait => {
+                self.next_step();
+                None
+            }
+            Some(&Step::Send(byte)) if waiting && self.elapsed >= SEND_GAP => {
+                self.next_step();
+                Some(byte)
+            }
+            _ => None,
+        }
+    }
+
+    fn received(&mut self, byte: u8) {
+        debug!(target: "gbrust::serial", "game sent 0x{:02x} back", byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::interrupts::InterruptController;
+    use super::super::serial::Serial;
+
+    #[test]
+    fn parses_scripts() {
+        let script = PeripheralScript::parse("# handshake\nanswer ff 10\n\nwait 100\nsend 02").unwrap();
+        assert_eq!(script.steps, [Step::Answer(0xFF), Step::Answer(0x10), Step::Wait(100), Step::Send(0x02)]);
+        assert!(matches!(PeripheralScript::parse("answer"), Err(PeripheralError::Parse { line: 1, .. })));
+        assert!(matches!(PeripheralScript::parse("send 02\nsend xyz"), Err(PeripheralError::Parse { line: 2, .. })));
+        assert!(matches!(PeripheralScript::parse("wait 1 2"), Err(PeripheralError::Parse { line: 1, .. })));
+        assert!(matches!(PeripheralScript::parse("beep"), Err(PeripheralError::Parse { line: 1, .. })));
+
+        let barcodes = PeripheralScript::barcodes("4902778000000\n").unwrap();
+        assert_eq!(barcodes.steps.len(), 4 + 1 + 15);
+        assert_eq!(barcodes.steps[5..7], [Step::Send(0x02), Step::Send(b'4')]);
+        assert!(matches!(PeripheralScript::barcodes("# codes\n490277800000"), Err(PeripheralError::Parse { line: 2, .. })));
+    }
+
+    #[test]
+    fn plays_scripts_on_the_link_port() {
+        let mut interrupts = InterruptController::new();
+        let mut serial = Serial::new();
+        let script = PeripheralScript::parse("answer 10\nwait 1000\nsend 42 43").unwrap();
+        serial.attach(Box::new(ScriptedDevice::new(script)));
+
+        // The game clocks the handshake
+        serial.write(0xff01, 0x07);
+        serial.write(0xff02, 0x81);
+        serial.cycle_flush(SEND_GAP, &mut interrupts);
+        assert_eq!(serial.read(0xff01), 0x10);
+
+        // Then waits for the device, which sends once the wait is over, a byte's time apart
+        let mut received = Vec::new();
+        serial.write(0xff02, 0x80);
+        for step in 1..=100 {
+            serial.cycle_flush(500, &mut interrupts);
+            if serial.read(0xff02) & 0x80 == 0 {
+                received.push((step * 500, serial.read(0xff01)));
+                serial.write(0xff02, 0x80);
+            }
+        }
+        // In steps of 500 cycles, the first step after 1000 + SEND_GAP, then after SEND_GAP more
+        assert_eq!(received, [(5500, 0x42), (10000, 0x43)]);
+    }
+}
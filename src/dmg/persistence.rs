@@ -0,0 +1,87 @@
+// LCD ghosting.
+// The DMG's LCD is slow: a pixel takes a few frames to fully change shade, so what is on screen
+// is partly the frames before. Some games count on this, flickering sprites every other frame
+// to get transparency or more sprites on a line than the hardware allows. On a modern display
+// that flicker is harsh.
+// LcdPersistence blends what was shown last into every new frame. The factor is the percentage
+// of the old picture kept, 0 (off) to 100. Around 50 looks like the real thing. Since what was
+// shown last is itself a blend, older frames fade out gradually rather than at once.
+
+use super::console::{Frame, VideoSink};
+
+pub struct LcdPersistence {
+    percent: u8,
+    shown: Vec<u32>,
+}
+
+impl LcdPersistence {
+    pub fn new(percent: u8) -> LcdPersistence {
+        LcdPersistence {
+            percent: percent.min(100),
+            shown: Vec::new(),
+        }
+    }
+
+    pub fn percent(&self) -> u8 {
+        self.percent
+    }
+
+    // Blends frame over what was shown last and returns the result, width * height pixels.
+    // The first frame, and the first after a size change, is shown as it is.
+    pub fn apply(&mut self, frame: &Frame) -> &[u32] {
+        if self.shown.len() != frame.width * frame.height {
+            self.shown.clear();
+            self.shown.extend(frame.rows().flatten());
+            return &self.shown;
+        }
+
+        let (old, new) = (self.percent as u32, 100 - self.percent as u32);
+        for (shown, &pixel) in self.shown.iter_mut().zip(frame.rows().flatten()) {
+            let channel = |shift: u32| {
+                let mixed = ((*shown >> shift & 0xFF) * old + (pixel >> shift & 0xFF) * new + 50) / 100;
+                mixed << shift
+            };
+            *shown = (pixel & 0xFF00_0000) | channel(16) | channel(8) | channel(0);
+        }
+        &self.shown
+    }
+}
+
+pub struct PersistenceSink<'a> {
+    persistence: &'a mut LcdPersistence,
+    video_sink: &'a mut dyn VideoSink,
+}
+
+impl<'a> PersistenceSink<'a> {
+    pub fn new(persistence: &'a mut LcdPersistence, video_sink: &'a mut dyn VideoSink) -> Self {
+        PersistenceSink {
+            persistence,
+            video_sink,
+        }
+    }
+}
+
+impl<'a> VideoSink for PersistenceSink<'a> {
+    fn frame_available(&mut self, frame: &Frame) {
+        let pixels = self.persistence.apply(frame);
+        self.video_sink.frame_available(&Frame::new(frame.width, frame.height, frame.width, pixels));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flicker_blends_into_a_middle_shade() {
+        let (white, black) = ([0xFFFF_FFFF], [0xFF00_0000]);
+        let mut persistence = LcdPersistence::new(50);
+        assert_eq!(persistence.apply(&Frame::new(1, 1, 1, &white)), [0xFFFF_FFFF]);
+        assert_eq!(persistence.apply(&Frame::new(1, 1, 1, &black)), [0xFF80_8080]);
+        assert_eq!(persistence.apply(&Frame::new(1, 1, 1, &white)), [0xFFC0_C0C0]);
+
+        let mut off = LcdPersistence::new(0);
+        off.apply(&Frame::new(1, 1, 1, &white));
+        assert_eq!(off.apply(&Frame::new(1, 1, 1, &black)), [0xFF00_0000]);
+    }
+}
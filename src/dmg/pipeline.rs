@@ -0,0 +1,108 @@
+// Threaded rendering pipeline.
+// The CPU thread keeps running the PPU timing (modes, LY, interrupts) but does not draw. Every
+// write that can change the picture is timestamped and pushed into a queue, together with a
+// marker for each scanline. A worker thread owns a replica of the PPU, replays the queue and
+// draws the scanlines, then hands finished frames back. On multicore machines this takes the
+// rendering cost off the CPU thread, which helps fast-forward and batch runs.
+// Finished frames are copied into buffers from a FramePool, which the console hands back with
+// recycle once the sink has seen them, so frames cost no allocation.
+// Audio will get the same treatment once the APU lands.
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use super::frame::{Frame, FramePool};
+use super::ppu::{Ppu, OAM_SIZE};
+
+// cycle: PPU clock (cycles since power on) at which the event happened
+pub enum PpuEvent {
+    Write { cycle: u64, x: Option<u8>, addr: u16, val: u8 }, // x: see Ppu::write_at
+    OamDma { cycle: u64, oam: Box<[u8; OAM_SIZE]> },
+    Scanline { cycle: u64, ly: u8 },
+    Frame { cycle: u64 },
+}
+
+pub struct RenderThread {
+    frames: Receiver<Box<[u32]>>,
+    pool: FramePool,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl RenderThread {
+    // Starts a worker rendering on a copy of ppu, and points ppu's event queue at it.
+    pub fn spawn(ppu: &mut Ppu) -> RenderThread {
+        let (event_tx, event_rx) = channel();
+        let (frame_tx, frame_rx) = channel();
+
+        let mut replica = ppu.clone();
+        replica.set_event_queue(None);
+        replica.record_scanlines(false);
+        replica.record_timeline(false);
+        ppu.set_event_queue(Some(event_tx));
+
+        let pool = FramePool::new();
+        let worker_pool = pool.clone();
+        let worker = thread::spawn(move || RenderThread::run(replica, event_rx, frame_tx, worker_pool));
+
+        RenderThread {
+            frames: frame_rx,
+            pool,
+            worker: Some(worker),
+        }
+    }
+
+    fn run(mut ppu: Ppu, events: Receiver<PpuEvent>, frames: Sender<Box<[u32]>>, pool: FramePool) {
+        // Ends when the CPU side drops the queue
+        for event in events {
+            match event {
+                PpuEvent::Write { x, addr, val, .. } => ppu.write_at(x, addr, val),
+                PpuEvent::OamDma { oam, .. } => ppu.oam_dma_transfer(*oam),
+                PpuEvent::Scanline { ly, .. } => {
+                    ppu.set_ly(ly);
+                    ppu.draw_scanline();
+                }
+                PpuEvent::Frame { .. } => {
+                    if frames.send(pool.copy(&Frame::dmg(ppu.framebuffer()))).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // Most recent frame finished by the worker, if any finished since the last call.
+    // Older frames are dropped, so a slow consumer never falls behind.
+    pub fn latest_frame(&self) -> Option<Box<[u32]>> {
+        let mut latest = None;
+        for frame in self.frames.try_iter() {
+            if let Some(older) = latest.replace(frame) {
+                self.pool.recycle(older);
+            }
+        }
+        latest
+    }
+
+    // Hands a frame from latest_frame back, to be filled again
+    pub fn recycle(&self, frame: Box<[u32]>) {
+        self.pool.recycle(frame);
+    }
+
+    // Waits for the worker to drain its queue and returns the last frame it finished that was
+    // not collected yet. The PPU must have dropped its event queue first.
+    pub fn finish(mut self) -> Option<Box<[u32]>> {
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        self.latest_frame()
+    }
+}
+
+impl Drop for RenderThread {
+    fn drop(&mut self) {
+        // The event queue lives in the PPU, which must have let go of it before we get here,
+        // otherwise the worker never sees the end of the queue.
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,103 @@
+// Scheduled memory writes.
+// For scripted experiments and bug reports that need the same write at the same point of every
+// run: Console::schedule_poke writes a byte at the vblank that ends a given frame (when
+// Console::frame_count reaches it), and Console::poke_every_frame at every vblank, as a
+// GameShark does. Pokes go through the bus like CPU writes, before the vblank callbacks, so a
+// write to 0x2000 - 0x3fff switches ROM banks as it would for the game.
+//
+// Pokes parse from GameShark codes, "01vvaaaa" with the address's low byte first:
+//
+//     console.poke_every_frame("01ff47c1".parse()?); // 0xff to 0xc147
+//
+// The first byte picks a RAM bank on the CGB. Work RAM is not banked here, so it is ignored.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+
+use super::error::GameSharkError;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Poke {
+    pub addr: u16,
+    pub val: u8,
+}
+
+impl FromStr for Poke {
+    type Err = GameSharkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = s.trim();
+        if code.len() != 8 || !code.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(GameSharkError(s.to_string()));
+        }
+        let byte = |at: usize| u8::from_str_radix(&code[at..at + 2], 16).unwrap();
+        Ok(Poke { addr: u16::from_le_bytes([byte(4), byte(6)]), val: byte(2) })
+    }
+}
+
+// As a GameShark code
+impl fmt::Display for Poke {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [low, high] = self.addr.to_le_bytes();
+        write!(f, "01{:02x}{:02x}{:02x}", self.val, low, high)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PokeSchedule {
+    once: BTreeMap<u64, Vec<Poke>>, // by frame
+    every_frame: Vec<Poke>,
+}
+
+impl PokeSchedule {
+    pub fn at(&mut self, frame: u64, poke: Poke) {
+        self.once.entry(frame).or_default().push(poke);
+    }
+
+    pub fn every_frame(&mut self, poke: Poke) {
+        self.every_frame.push(poke);
+    }
+
+    pub fn clear(&mut self) {
+        *self = PokeSchedule::default();
+    }
+
+    // What to write at the end of frame: its one-off pokes, and those for earlier frames that
+    // were scheduled too late, in frame order, then the ones for every frame
+    pub fn due(&mut self, frame: u64) -> Vec<Poke> {
+        let later = self.once.split_off(&(frame + 1));
+        let due = mem::replace(&mut self.once, later);
+        due.into_values().flatten().chain(self.every_frame.iter().copied()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gameshark_codes() {
+        let poke: Poke = "01FF47C1".parse().unwrap();
+        assert_eq!(poke, Poke { addr: 0xC147, val: 0xFF });
+        assert_eq!(poke.to_string(), "01ff47c1");
+        assert!("01ff47c".parse::<Poke>().is_err());
+        assert!("01fg47c1".parse::<Poke>().is_err());
+    }
+
+    #[test]
+    fn pokes_come_due_in_frame_order() {
+        let poke = |val| Poke { addr: 0xC000, val };
+        let mut schedule = PokeSchedule::default();
+        schedule.at(5, poke(2));
+        schedule.at(3, poke(1));
+        schedule.at(5, poke(3));
+        schedule.every_frame(poke(9));
+        assert_eq!(schedule.due(2), [poke(9)]);
+        assert_eq!(schedule.due(5), [poke(1), poke(2), poke(3), poke(9)]);
+        assert_eq!(schedule.due(6), [poke(9)]);
+        schedule.clear();
+        assert!(schedule.due(7).is_empty());
+    }
+}
@@ -1,8 +1,12 @@
-use super::Interrupts;
-use super::console::VideoSink;
-
-const INT_VBLANK: Interrupts = Interrupts::INT_VBLANK;
-const INT_LCDSTAT: Interrupts = Interrupts::INT_LCDSTAT;
+use super::interrupts::{Interrupt, InterruptController};
+use super::console::{Frame, Model, VideoSink};
+use super::config::AccuracyLevel;
+use super::gfx::VramSnapshot;
+use super::pipeline::PpuEvent;
+use super::state::{StateError, StateReader, StateWriter};
+use super::timeline::{PpuTimeline, StatCause, TimelineEvent};
+use std::mem;
+use std::sync::mpsc::Sender;
 
 pub const OAM_SIZE: usize = 0x100; // address for OAM
 const FRAMEBUFFER_SIZE: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT; // address for the full frame,
@@ -26,7 +30,14 @@ const VRAM_CYCLES: u32 = 172;
 const TILE_BYTES: u16 = 16;
 const TILE_BASE_ADDR: u16 = 0x8000;
 
-#[derive(Debug,PartialEq,Eq)]
+// The OAM search picks at most this many sprites for a line
+const SPRITES_PER_LINE: usize = 10;
+// VRAM bank 1 on the CGB, with the tile attributes at the same place as the maps in bank 0
+const VRAM_BANK_SIZE: usize = 0x2000;
+// Cycles into mode 3 before the first pixel comes out, the rest of it is one pixel a cycle
+const PIXEL_DELAY: u32 = VRAM_CYCLES - DISPLAY_WIDTH as u32;
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
 pub struct Color {
     r: u8,
     g: u8,
@@ -34,6 +45,68 @@ pub struct Color {
     a: u8,
 }
 
+impl Color {
+    fn from_argb(argb: u32) -> Color {
+        Color {
+            a: (argb >> 24) as u8,
+            r: (argb >> 16) as u8,
+            g: (argb >> 8) as u8,
+            b: argb as u8,
+        }
+    }
+
+    fn to_argb(self) -> u32 {
+        ((self.a as u32) << 24) | ((self.r as u32) << 16) | ((self.g as u32) << 8) | (self.b as u32)
+    }
+}
+
+// Color id standing for a pixel of a hidden layer, see Layers
+const BLANK: u8 = 4;
+
+// A row of a tile as the color ids (0 - 3) of its 8 pixels, one per byte, the leftmost pixel in
+// the lowest byte. Tiles store a row as two bit planes, the low bits of the 8 ids in one byte and
+// the high bits in the next, leftmost pixel in bit 7. Rather than picking the bits out one pixel
+// at a time, one multiply spreads a plane over the 8 bytes: the copies of the plane it adds up lie
+// 9 bits apart, so none overlap and bit 7 of byte k ends up holding bit 7 - k.
+pub(crate) fn decode_tile_row(lsb: u8, msb: u8) -> u64 {
+    spread_plane(lsb) | spread_plane(msb) << 1
+}
+
+fn spread_plane(plane: u8) -> u64 {
+    ((plane as u64).wrapping_mul(0x8040_2010_0804_0201) & 0x8080_8080_8080_8080) >> 7
+}
+
+// Debug switches for hiding layers, independent of what the game sets in LCDC. Hidden
+// background / window pixels are drawn in the lightest shade.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Layers {
+    pub background: bool,
+    pub window: bool,
+    pub sprites: bool,
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Layers {
+            background: true,
+            window: true,
+            sprites: true,
+        }
+    }
+}
+
+// Registers that shape a scanline, as they were when the line started (entering OAM search).
+// Games change these mid-frame for parallax, wobble and split-screen effects.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ScanlineRegs {
+    pub ly: u8,
+    pub scx: u8,
+    pub scy: u8,
+    pub wx: u8,
+    pub wy: u8,
+    pub lcdc: u8,
+}
+
 const WHITE: Color = Color {
     r: 224,
     g: 248,
@@ -59,7 +132,7 @@ const BLACK: Color = Color {
     a: 255,
 };
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 struct Lcdc {
     lcd_display_enable: bool,
     window_tile_map_display_select: bool,
@@ -96,10 +169,7 @@ impl Lcdc {
         self.bg_window_display_priority = (flags & 0x01) != 0;
     }
 
-    pub fn get_flags(&mut self) -> u8 {
-        // println!("{:?}", self.lcd_display_enable);
-        // let intermediate = + (self.bg_window_tile_data_select as u8) << 4;
-        // println!("{:?}", intermediate);
+    pub fn get_flags(&self) -> u8 {
         let res = ((self.lcd_display_enable as u8) << 7) 
             + ((self.window_tile_map_display_select as u8) << 6)
             + ((self.window_display_enable as u8) << 5)
@@ -112,6 +182,7 @@ impl Lcdc {
     }
 }
 
+#[derive(Clone)]
 struct LCDStat {
     lcd_ly_coincidence_interrupt: bool,
     mode_2_oam_interrupt: bool,
@@ -142,7 +213,7 @@ impl LCDStat {
         //mode_flag read only
     }
 
-    pub fn get_flags(&mut self) -> u8 {
+    pub fn get_flags(&self) -> u8 {
         ((self.lcd_ly_coincidence_interrupt as u8) << 6)
             + ((self.mode_2_oam_interrupt as u8) << 5)
             + ((self.mode_1_vblank_interupt as u8) << 4)
@@ -152,6 +223,7 @@ impl LCDStat {
     }
 }
 
+#[derive(Clone)]
 enum Mode {
     HBlank,
     VBlank,
@@ -169,21 +241,39 @@ impl Mode {
         };
         flag as u8
     }
-/*
-    fn get_mode(code: u8) -> Self {
-        match code {
-            MODE_HBLANK => Mode::Hblank,
-            MODE_VBLANK => Mode::Vblank,
+
+    fn from_flags(code: u8) -> Self {
+        match code & 0b11 {
+            MODE_HBLANK => Mode::HBlank,
+            MODE_VBLANK => Mode::VBlank,
             MODE_OAM => Mode::Oam,
-            MODE_VRAM => Mode::Vram,
-            _ => panic!("Unknown mode code"),
+            _ => Mode::Vram,
         }
     }
-*/
 }
 
 // No definition of trait VideoSink because already defined it in console and imported.
 
+// The window's progress through a frame. Kept by whichever PPU draws (the render worker's when
+// rendering is threaded) and not saved: like the picture, the next frame starts it over.
+#[derive(Debug, Default, Copy, Clone)]
+struct WindowState {
+    triggered: bool, // LY was WY at the start of a line this frame
+    line: u8,        // window rows drawn so far this frame
+    wrap: bool,      // WX was 166 on the last line, so the window takes all of this one
+}
+
+// A write to SCY, SCX or BGP after the line's drawing started, and the pixel it landed on.
+// DISPLAY_WIDTH when it came in HBlank, after the last one. See write_at.
+#[derive(Debug, Copy, Clone)]
+struct LineWrite {
+    x: u8,
+    addr: u16,
+    val: u8,
+    old: u8,
+}
+
+#[derive(Clone)]
 pub struct Ppu {
     lcdc: Lcdc,
     lcdstat: LCDStat,
@@ -203,11 +293,38 @@ pub struct Ppu {
     cycles: u32, // cycles of an interrupt
     mode_cycles: u32,    // keep track of cycles available for each mode
     framebuffer: Box<[u32]>,    // To render images before showing to the screen
+    clock: u64, // total cycles flushed since power on, used to timestamp events
+
+    // When set, scanlines are not drawn here. Writes and scanline markers are sent to a
+    // render worker instead (see pipeline.rs)
+    events: Option<Sender<PpuEvent>>,
 
     // Unimplemented address for DMG, but need to be read and writable
     bgpi: u8,
     bgpd: u8,
     vbk: u8,
+    opri: u8, // CGB object priority mode, 0xFF6C bit 0: 0 by OAM index, 1 by X. See render_sprites
+
+    palette: [Color; 4], // shades 0 - 3, lightest first. Set by the frontend, not the game
+    layers: Layers,
+    rendering: bool, // scanlines are drawn, see set_rendering
+    window: WindowState,
+    line_writes: Vec<LineWrite>, // since this line's mode 3, drawn by draw_scanline
+    // Scanline starts since the last take_scanlines, only recorded when enabled
+    scanlines: Option<Vec<ScanlineRegs>>,
+    // Mode changes and STAT requests since the last take_timeline, only recorded when enabled
+    timeline: Option<PpuTimeline>,
+    accuracy: AccuracyLevel,
+    // The STAT interrupt line, high while any enabled STAT condition holds. Only tracked when
+    // cycle accurate, see request_stat
+    stat_line: bool,
+    stat_write: bool, // a STAT write on the DMG raised the line, see write
+    model: Model,
+    cgb_mode: bool, // see set_cgb_mode
+    // The background and window under the line's pixels, for sprite priority: color ids
+    // (BLANK where hidden) and the CGB attribute's priority bit
+    line_ids: [u8; DISPLAY_WIDTH],
+    line_priority: [bool; DISPLAY_WIDTH],
 }
 
 impl Ppu {
@@ -230,21 +347,212 @@ impl Ppu {
             cycles: 0,
             mode_cycles: 0,
             framebuffer: vec![0; FRAMEBUFFER_SIZE].into_boxed_slice(),
+            clock: 0,
+            events: None,
             bgpi: 0,
             bgpd: 0,
             vbk: 0,
+            opri: 0,
+            palette: [WHITE, LIGHT_GRAY, DARK_GRAY, BLACK],
+            layers: Layers::default(),
+            rendering: true,
+            window: WindowState::default(),
+            line_writes: Vec::new(),
+            scanlines: None,
+            timeline: None,
+            accuracy: AccuracyLevel::default(),
+            stat_line: false,
+            stat_write: false,
+            model: Model::Dmg,
+            cgb_mode: false,
+            line_ids: [0; DISPLAY_WIDTH],
+            line_priority: [false; DISPLAY_WIDTH],
         }
     }
 
+    pub fn set_accuracy(&mut self, accuracy: AccuracyLevel) {
+        self.accuracy = accuracy;
+        self.stat_line = false;
+    }
+
+    // Only the DMG has the STAT write bug (see write), only the CGB OPRI (see render_sprites)
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    // CGB games on the CGB: VBK switches VRAM banks, the background and window take attributes
+    // from bank 1 (tile bank, flips and priority over sprites, not yet the colour palette),
+    // sprites can take tiles from bank 1, and LCDC bit 0 is the master priority instead of
+    // turning the background off. See PanDocs: https://gbdev.io/pandocs/Tile_Maps.html
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+    }
+
+    // Where addr is in VRAM for the CPU, in the bank VBK selects
+    fn vram_index(&self, addr: u16) -> usize {
+        let bank = if self.cgb_mode { (self.vbk & 0x01) as usize } else { 0 };
+        bank * VRAM_BANK_SIZE + (addr - TILE_BASE_ADDR) as usize
+    }
+
+    pub fn has_opri(&self) -> bool {
+        self.model == Model::Cgb
+    }
+
+    // For filling at power on, see meminit.rs
+    pub fn vram_mut(&mut self) -> &mut [u8] {
+        &mut self.vram
+    }
+
+    // Registers back to power on, keeping the frontend's settings and any recording. VRAM and
+    // OAM are cleared unless keep_memory.
+    pub fn reset(&mut self, keep_memory: bool) {
+        let mut ppu = Ppu::new();
+        if keep_memory {
+            ppu.vram = self.vram;
+            ppu.oam = self.oam;
+        }
+        ppu.events = self.events.take();
+        ppu.palette = self.palette;
+        ppu.layers = self.layers;
+        ppu.scanlines = self.scanlines.take();
+        ppu.timeline = self.timeline.take();
+        ppu.accuracy = self.accuracy;
+        ppu.model = self.model;
+        ppu.cgb_mode = self.cgb_mode;
+        *self = ppu;
+    }
+
+    // Hands scanline drawing over to a render worker. Pass None to draw on this thread again.
+    pub fn set_event_queue(&mut self, events: Option<Sender<PpuEvent>>) {
+        self.events = events;
+    }
+
+    fn send_event(&mut self, event: PpuEvent) {
+        let disconnected = match self.events {
+            Some(ref events) => events.send(event).is_err(),
+            None => false,
+        };
+
+        // Worker is gone, go back to drawing ourselves
+        if disconnected {
+            self.events = None;
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.lcdc.get_flags());
+        state.write_u8(self.lcdstat.get_flags());
+        state.write_u8(self.scx);
+        state.write_u8(self.scy);
+        state.write_u8(self.ly);
+        state.write_u8(self.lyc);
+        state.write_u8(self.wy);
+        state.write_u8(self.wx);
+        state.write_u8(self.bgp);
+        state.write_u8(self.obp0);
+        state.write_u8(self.obp1);
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.oam);
+        state.write_u32(self.cycles);
+        state.write_u32(self.mode_cycles);
+        state.write_u64(self.clock);
+        state.write_u8(self.bgpi);
+        state.write_u8(self.bgpd);
+        state.write_u8(self.vbk);
+        state.write_bool(self.stat_line);
+        state.write_u8(self.opri);
+        state.write_bool(self.stat_write);
+    }
+
+    // The framebuffer is not part of the state, it is redrawn within a frame
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.lcdc.set_flags(state.read_u8()?);
+        let stat = state.read_u8()?;
+        self.lcdstat.set_flags(stat);
+        self.lcdstat.coincidence_flag = stat & 0b100 != 0;
+        self.lcdstat.mode_flag = Mode::from_flags(stat);
+        self.scx = state.read_u8()?;
+        self.scy = state.read_u8()?;
+        self.ly = state.read_u8()?;
+        self.lyc = state.read_u8()?;
+        self.wy = state.read_u8()?;
+        self.wx = state.read_u8()?;
+        self.bgp = state.read_u8()?;
+        self.obp0 = state.read_u8()?;
+        self.obp1 = state.read_u8()?;
+        state.read_into(&mut self.vram, "vram")?;
+        state.read_into(&mut self.oam, "oam")?;
+        self.cycles = state.read_u32()?;
+        self.mode_cycles = state.read_u32()?;
+        self.clock = state.read_u64()?;
+        self.bgpi = state.read_u8()?;
+        self.bgpd = state.read_u8()?;
+        self.vbk = state.read_u8()?;
+        self.stat_line = state.read_bool()?;
+        self.opri = state.read_u8()?;
+        self.stat_write = state.read_bool()?;
+        Ok(())
+    }
+
     pub fn write(&mut self, addr: u16, val: u8) {
+        let x = self.drawing_x(addr);
+        self.write_at(x, addr, val);
+    }
+
+    // Where this line's drawing is, for a write to a register it reads. Lines are drawn in one
+    // go at the end of HBlank, so writes from mode 3 on are kept with the pixel they landed on
+    // and the line is split there (BGP stripes, SCX wobble), and writes in HBlank wait for the
+    // next line. Sprite palettes and LCDC take the value they have at the end of HBlank.
+    fn drawing_x(&self, addr: u16) -> Option<u8> {
+        if !matches!(addr, 0xFF42 | 0xFF43 | 0xFF47) || !self.lcdc.lcd_display_enable
+            || !self.rendering || self.ly as usize >= DISPLAY_HEIGHT {
+            return None;
+        }
+        match self.lcdstat.mode_flag {
+            Mode::Vram => {
+                let x = self.mode_cycles.saturating_sub(PIXEL_DELAY).min(DISPLAY_WIDTH as u32);
+                Some(x as u8)
+            }
+            Mode::HBlank => Some(DISPLAY_WIDTH as u8),
+            _ => None,
+        }
+    }
+
+    // A write, landing on pixel x of the line being drawn if any (see drawing_x). The render
+    // worker replays writes with the x worked out here, as it does not run the timing.
+    pub fn write_at(&mut self, x: Option<u8>, addr: u16, val: u8) {
+        if self.events.is_some() {
+            self.send_event(PpuEvent::Write { cycle: self.clock, x, addr, val });
+        }
+        if let Some(x) = x {
+            let old = self.read(addr);
+            self.line_writes.push(LineWrite { x, addr, val, old });
+        }
+
         match addr {
             0x8000..=0x9fff => { // tile data
-                let addr = addr - TILE_BASE_ADDR; // TILE_BASE_ADDR = 0x8000
-                self.vram[addr as usize] = val;
+                let index = self.vram_index(addr);
+                self.vram[index] = val;
             },
             0xFE00..=0xFEFF => self.oam[(addr - 0xFE00) as usize] = val,
-            0xFF40 => self.lcdc.set_flags(val),
-            0xFF41 => self.lcdstat.set_flags(val),
+            0xFF40 => {
+                if self.lcdc.lcd_display_enable != (val & 0x80 != 0) {
+                    debug!(target: "gbrust::ppu", "LCD {} at ly {}", if val & 0x80 != 0 { "on" } else { "off" }, self.ly);
+                }
+                self.lcdc.set_flags(val)
+            }
+            0xFF41 => {
+                // On the DMG, for a cycle the write enables every source, as if it were 0xFF, so
+                // any of HBlank, VBlank or LY=LYC holding fires the interrupt. Games such as Road
+                // Rash and Legend of Zerd depend on it. Not in Fast.
+                if self.model == Model::Dmg && self.accuracy != AccuracyLevel::Fast
+                    && self.lcdc.lcd_display_enable {
+                    let holds = self.lcdstat.coincidence_flag
+                        || matches!(self.lcdstat.mode_flag, Mode::HBlank | Mode::VBlank);
+                    self.stat_write = holds;
+                }
+                self.lcdstat.set_flags(val)
+            }
             0xFF42 => self.scy = val,
             0xFF43 => self.scx = val,
             0xFF44 => {} // self.ly = val, read-only
@@ -259,16 +567,14 @@ impl Ppu {
             0xFF68 => self.bgpi = val,
             0xFF69 => self.bgpd = val,
             0xFF4F => self.vbk = val,
+            0xFF6C => self.opri = val & 0x01,
             _ => panic!("Unsupported address to write to: 0x{:x}", addr),
         }
     }
 
     pub fn read(&mut self, addr: u16) -> u8 {
         match addr {
-            0x8000..=0x9fff => { // tile data
-                let addr = addr - TILE_BASE_ADDR;
-                self.vram[addr as usize]
-            }, 
+            0x8000..=0x9fff => self.vram[self.vram_index(addr)], // tile data
             0xFE00..=0xFEFF => self.oam[(addr - 0xFE00) as usize],
             0xFF40 => self.lcdc.get_flags(),
             0xFF41 => self.lcdstat.get_flags(),
@@ -285,6 +591,7 @@ impl Ppu {
             0xFF68 => self.bgpi,
             0xFF69 => self.bgpd,
             0xFF4F => self.vbk,
+            0xFF6C => 0xFE | self.opri,
             _ => panic!("Unsupported address to read: 0x{:x}", addr),
         }
     }
@@ -292,32 +599,41 @@ impl Ppu {
     // Cycle_flush: Function to generate interrupt signals. 2 types of interrupt signals available
     // for LCD Screen: VBlank Interrupt and LCDCStat interrupt. In each cycle_flush, conditions to
     // request these interrupts are checked and will be requested if satisfied
-    pub fn cycle_flush(&mut self, cycle_count: u32, video_sink: &mut dyn VideoSink) -> Interrupts {
-        let mut interrupt = Interrupts::empty();
+    pub fn cycle_flush(&mut self, cycle_count: u32, video_sink: &mut dyn VideoSink,
+                       interrupts: &mut InterruptController) {
         self.mode_cycles += cycle_count;  
+        self.clock += cycle_count as u64;
         
+        if mem::take(&mut self.stat_write) {
+            self.request_stat(StatCause::StatWrite, interrupts);
+        }
         if self.lcdc.lcd_display_enable {
-            interrupt = match self.lcdstat.mode_flag {
-                Mode::HBlank => self.hblank_flush(cycle_count, video_sink),
-                Mode::VBlank => self.vblank_flush(cycle_count),
+            let mode = self.lcdstat.mode_flag.get_flags();
+            match self.lcdstat.mode_flag {
+                Mode::HBlank => self.hblank_flush(cycle_count, video_sink, interrupts),
+                Mode::VBlank => self.vblank_flush(cycle_count, interrupts),
                 Mode::Oam => self.oam_flush(cycle_count),
                 Mode::Vram => self.vram_flush(cycle_count),
-            };
+            }
+            if self.lcdstat.mode_flag.get_flags() != mode {
+                let mode = self.lcdstat.mode_flag.get_flags();
+                self.timeline_event(TimelineEvent::Mode { cycle: self.clock, ly: self.ly, mode });
+            }
+            if self.accuracy == AccuracyLevel::CycleAccurate {
+                self.stat_line = self.stat_conditions();
+            }
         } else {
             if self.mode_cycles >= CLKS_SCREEN_REFRESH {
                 self.mode_cycles -= CLKS_SCREEN_REFRESH;
             }
         }
-
-        interrupt
     }
 
     // Functions to invoke, assuming seld.lcdc.lcd_display_enable = true
     
     // Flush during hblank period
-    pub fn hblank_flush(&mut self, cycle_count: u32, video_sink: &mut dyn VideoSink) -> Interrupts {
-        let mut interrupt = Interrupts::empty(); // interrupt = 0x00
-
+    pub fn hblank_flush(&mut self, cycle_count: u32, video_sink: &mut dyn VideoSink,
+                        interrupts: &mut InterruptController) {
         // Add cycle_count to LCD Clock (cycle)
         self.cycles += cycle_count;
         
@@ -330,15 +646,19 @@ impl Ppu {
             // Conditions to request LCDSTAT interrupt
             self.lcdstat.coincidence_flag = self.ly == self.lyc; // Update coincidence flag by checking ly == lyc
             if self.lcdstat.lcd_ly_coincidence_interrupt && self.lcdstat.coincidence_flag {
-                interrupt |= INT_LCDSTAT;
+                self.request_stat(StatCause::LyCoincidence, interrupts);
             }
             
             self.lcdstat.mode_flag = if self.ly == 144 {
-                video_sink.frame_available(&self.framebuffer);
-                interrupt |= INT_VBLANK;
+                if self.events.is_some() {
+                    self.send_event(PpuEvent::Frame { cycle: self.clock });
+                }
+                trace!(target: "gbrust::ppu", "frame done at cycle {}", self.clock);
+                video_sink.frame_available(&Frame::dmg(&self.framebuffer));
+                interrupts.request(Interrupt::VBlank);
                 
                 if self.lcdstat.mode_1_vblank_interupt {
-                    interrupt |= INT_LCDSTAT;
+                    self.request_stat(StatCause::VBlank, interrupts);
                 }
                 
                 self.cycles = 0;
@@ -346,20 +666,26 @@ impl Ppu {
                 Mode::VBlank
             } else {
                 if self.lcdstat.mode_0_hblank_interrupt {
-                    interrupt |= INT_LCDSTAT;
+                    self.request_stat(StatCause::HBlank, interrupts);
+                }
+                trace!(target: "gbrust::ppu", "scanline {}", self.ly);
+                if self.rendering {
+                    if self.events.is_some() {
+                        self.send_event(PpuEvent::Scanline { cycle: self.clock, ly: self.ly });
+                    } else {
+                        self.draw_scanline();
+                    }
                 }
-                self.draw_scanline();
                 Mode::Oam
             };
             self.ly += 1;
+            if let Mode::Oam = self.lcdstat.mode_flag {
+                self.scanline_started();
+            }
         }
-
-        interrupt
     }
 
-    pub fn vblank_flush(&mut self, cycle_count: u32) -> Interrupts {
-        let mut interrupt = Interrupts::empty(); // interrupt = 0x00
-
+    pub fn vblank_flush(&mut self, cycle_count: u32, interrupts: &mut InterruptController) {
         // Add cycle_count to LCD Clock (cycle)
         self.cycles += cycle_count;
         
@@ -372,25 +698,24 @@ impl Ppu {
             // Check for conditions to set LCDStat interrupt
             self.lcdstat.coincidence_flag = self.ly == self.lyc;
             if self.lcdstat.coincidence_flag && self.lcdstat.lcd_ly_coincidence_interrupt {
-                interrupt |= INT_LCDSTAT;
+                self.request_stat(StatCause::LyCoincidence, interrupts);
             }
 
             self.ly += 1;
             if self.ly == 154 { // ly = 154: end of V-Blank Period
                 self.lcdstat.mode_flag = Mode::Oam;
                 self.ly = 0;
+                self.scanline_started();
                 
                 if self.lcdstat.mode_2_oam_interrupt {
-                    interrupt |= INT_LCDSTAT;
+                    self.request_stat(StatCause::Oam, interrupts);
                 }
             }
 
         }
-
-        interrupt
     }
 
-    pub fn oam_flush(&mut self, cycle_count: u32) -> Interrupts {
+    pub fn oam_flush(&mut self, cycle_count: u32) {
         // Add cycle_count to LCD Clock (cycle)
         self.cycles += cycle_count;
         
@@ -399,11 +724,9 @@ impl Ppu {
             self.mode_cycles -= OAM_CYCLES;
             self.lcdstat.mode_flag = Mode::Vram;
         }
-
-        Interrupts::empty()
     }
 
-    pub fn vram_flush(&mut self, cycle_count: u32) -> Interrupts {
+    pub fn vram_flush(&mut self, cycle_count: u32) {
         // Add cycle_count to LCD Clock (cycle)
         self.cycles += cycle_count;
         
@@ -412,209 +735,398 @@ impl Ppu {
             self.mode_cycles -= VRAM_CYCLES;
             self.lcdstat.mode_flag = Mode::HBlank;
         }
-
-        Interrupts::empty()
     }
 
 
+    // The DMG OAM bug: while the PPU scans OAM (mode 2), the CPU putting an address in
+    // 0xFE00 - 0xFEFF on the bus scrambles the 8 byte row the PPU is reading. The row's first
+    // word is mixed with the row before it, and the other three are copied from it. The first
+    // row is never hit. See PanDocs: https://gbdev.io/pandocs/OAM_Corruption_Bug.html
+    pub fn corrupt_oam(&mut self, addr: u16) {
+        if !(0xFE00..= 0xFEFF).contains(&addr) || !self.lcdc.lcd_display_enable
+            || !matches!(self.lcdstat.mode_flag, Mode::Oam) {
+            return;
+        }
+        // The PPU reads a row every 4 cycles
+        let row = (self.mode_cycles / 4) as usize;
+        if row == 0 || row >= OAM_SIZE / 8 {
+            return;
+        }
+
+        let mut oam = self.oam;
+        let (cur, prev) = (row * 8, row * 8 - 8);
+        let word = |i: usize| u16::from_le_bytes([oam[i], oam[i + 1]]);
+        let (a, b, c) = (word(cur), word(prev), word(prev + 4));
+        oam[cur..cur + 2].copy_from_slice(&(((a ^ c) & (b ^ c)) ^ c).to_le_bytes());
+        oam.copy_within(prev + 2..prev + 8, cur + 2);
+        self.oam_dma_transfer(oam);
+    }
+
     pub fn oam_dma_transfer(&mut self, oam: [u8; OAM_SIZE]) {
+        if self.events.is_some() {
+            self.send_event(PpuEvent::OamDma { cycle: self.clock, oam: Box::new(oam) });
+        }
         self.oam = oam;
     }
 
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    // Cycles since power on
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    // Used by the render worker to replay a scanline marker on its replica
+    pub fn set_ly(&mut self, ly: u8) {
+        self.ly = ly;
+    }
+
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    // For graphics dumps, see gfx.rs
+    pub fn vram_snapshot(&self) -> VramSnapshot {
+        let banks = if self.cgb_mode { 2 } else { 1 };
+        let mut oam = [0; 0xA0];
+        oam.copy_from_slice(&self.oam[..0xA0]);
+        VramSnapshot {
+            vram: self.vram[..banks * VRAM_BANK_SIZE].into(),
+            oam,
+            lcdc: self.lcdc.get_flags(),
+            bgp: self.bgp,
+            obp0: self.obp0,
+            obp1: self.obp1,
+            cgb: self.cgb_mode,
+            shades: self.palette.map(Color::to_argb),
+        }
+    }
+
     pub fn draw_scanline(&mut self) {
-        if self.lcdc.bg_window_display_priority {
-            self.render_tiles();
+        // Back to the registers the line started with, render_tiles redoes the writes as it
+        // gets to them
+        let writes = mem::take(&mut self.line_writes);
+        for write in writes.iter().rev() {
+            self.set_line_register(write.addr, write.old);
+        }
+        let window = self.window_span();
+        self.line_ids = [0; DISPLAY_WIDTH];
+        self.line_priority = [false; DISPLAY_WIDTH];
+        if self.lcdc.bg_window_display_priority || self.cgb_mode {
+            self.render_tiles(window, &writes);
+        }
+        for write in &writes {
+            self.set_line_register(write.addr, write.val);
         }
 
-        if self.lcdc.sprite_display_enable {
+        if self.lcdc.sprite_display_enable && self.layers.sprites {
             self.render_sprites();
         }
     }
 
-    pub fn render_tiles(&mut self) {
-        let scanline = self.ly;
-        let scroll_x = self.scx;
-        let scroll_y = self.scy;
-        let window_x = self.wx.wrapping_sub(7); // fixed difference
-        let window_y = self.wy;
-
-        // Window used if the flag in LCDC is true and the window is below scanline
-        let use_window = self.lcdc.window_display_enable && window_y <= scanline;
-
-        // Check which VRAM tile data is used
-        // Based on LCDC flag
-        // See VRAM Tile Data in PanDocs
-        let (tile_data, signed): (u16, bool) = if self.lcdc.bg_window_tile_data_select {
-            (0x8000, false)
-        } else {
-            (0x8800, true)
-        };   
+    // Starts (or stops) recording ScanlineRegs at the start of every line
+    pub fn record_scanlines(&mut self, enabled: bool) {
+        self.scanlines = if enabled { Some(Vec::new()) } else { None };
+    }
 
-        // See VRAM Background Maps in PanDocs
-        let background_mem = if use_window {
-            // Window used. Background defaults to window tiles.
-            if self.lcdc.window_tile_map_display_select {
-                0x9c00
-            } else {
-                0x9800
-            }
-        } else {
-            // Window not used. Background tiles used.
-            if self.lcdc.bg_tile_map_display_select {
-                0x9c00
-            } else {
-                0x9800
+    pub fn take_scanlines(&mut self) -> Vec<ScanlineRegs> {
+        match self.scanlines {
+            Some(ref mut scanlines) => mem::take(scanlines),
+            None => Vec::new(),
+        }
+    }
+
+    // Starts (or stops) recording a timeline of mode changes and STAT requests
+    pub fn record_timeline(&mut self, enabled: bool) {
+        self.timeline = if enabled { Some(self.empty_timeline()) } else { None };
+    }
+
+    // Everything recorded since recording started or the last call, and starts over from here
+    pub fn take_timeline(&mut self) -> PpuTimeline {
+        let next = self.empty_timeline();
+        match self.timeline {
+            Some(ref mut timeline) => {
+                timeline.end = self.clock;
+                mem::replace(timeline, next)
             }
-        };
+            None => next,
+        }
+    }
 
-        // set the y-position (top row)
-        let y_pos = if use_window {
-            scanline.wrapping_sub(window_y)
-        } else {
-            scroll_y.wrapping_add(scanline)
-        };
+    fn empty_timeline(&self) -> PpuTimeline {
+        PpuTimeline {
+            start: self.clock,
+            start_mode: self.lcdstat.mode_flag.get_flags(),
+            start_ly: self.ly,
+            end: self.clock,
+            events: Vec::new(),
+        }
+    }
 
-        // 32 tiles per row, 8 pixels each
-        let tile_row: u16 = (y_pos / 8) as u16 * 32;
+    fn timeline_event(&mut self, event: TimelineEvent) {
+        if let Some(ref mut timeline) = self.timeline {
+            timeline.events.push(event);
+        }
+    }
 
-        // Display: 160 x 144 on the screen
-        // We do line by line
-        for pixel in 0..160 {
-            let pixel = pixel as u8;
-            
-            // Window used?
-            let x_pos = if use_window && pixel >= window_x {
-                pixel.wrapping_sub(window_x)
-            } else {
-                pixel.wrapping_add(scroll_x)
+    // Cycle accurate, the STAT interrupt only fires when the line goes from low to high, so a
+    // condition that comes true while another still holds the line high is swallowed ("STAT
+    // blocking"). Otherwise every condition fires on its own.
+    fn request_stat(&mut self, cause: StatCause, interrupts: &mut InterruptController) {
+        if self.accuracy == AccuracyLevel::CycleAccurate && self.stat_line {
+            return;
+        }
+        interrupts.request(Interrupt::LcdStat);
+        self.timeline_event(TimelineEvent::Stat { cycle: self.clock, ly: self.ly, cause });
+    }
+
+    fn stat_conditions(&self) -> bool {
+        let stat = &self.lcdstat;
+        (stat.lcd_ly_coincidence_interrupt && stat.coincidence_flag)
+            || match stat.mode_flag {
+                Mode::HBlank => stat.mode_0_hblank_interrupt,
+                Mode::VBlank => stat.mode_1_vblank_interupt,
+                Mode::Oam => stat.mode_2_oam_interrupt,
+                Mode::Vram => false,
+            }
+    }
+
+    fn scanline_started(&mut self) {
+        // Writes from a line that was not drawn
+        self.line_writes.clear();
+        // Only lines that are drawn, this PPU also runs an OAM search for line 144
+        if self.scanlines.is_some() && (self.ly as usize) < DISPLAY_HEIGHT {
+            let regs = ScanlineRegs {
+                ly: self.ly,
+                scx: self.scx,
+                scy: self.scy,
+                wx: self.wx,
+                wy: self.wy,
+                lcdc: self.lcdc.get_flags(),
             };
+            if let Some(ref mut scanlines) = self.scanlines {
+                scanlines.push(regs);
+            }
+        }
+    }
 
-            let tile_col: u16 = (x_pos / 8) as u16;
+    pub fn layers(&self) -> Layers {
+        self.layers
+    }
 
-            // Base address of the tile
-            let tile_address = background_mem + tile_row + tile_col;
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.layers = layers;
+    }
 
-            // sets the offset from the base address
-            let tile_num: i16 = if !signed {
-                // u8 -> u16 (still unsigned) -> i16 (no op)
-                self.read(tile_address) as u16 as i16 
-            } else {
-                // u8 -> i8 (sign) -> i16
-                self.read(tile_address) as i8 as i16
-            };
+    // Whether scanlines are drawn. Timing and interrupts are the same either way.
+    pub fn set_rendering(&mut self, enabled: bool) {
+        self.rendering = enabled;
+    }
 
-            // Actual tile location address
-            let tile_location: u16 = if !signed {
-                tile_data + (tile_num as u16 * 16)
-            } else {
-                tile_data + ((tile_num + 128) * 16) as u16
-            };
+    // Where the window is on this line, as (first x on screen, first x in the window, window
+    // row), and moves the window on a line. As on hardware:
+    //   - the window shows from the first line where LY matched WY until the end of the frame,
+    //     even if WY changes after, and not at all if WY is only set below LY
+    //   - it draws its rows in order, lines without the window do not count
+    //   - WX 0 - 6 start it left of the screen. At 0 the fine scroll (SCX % 8) shifts it further.
+    //   - WX 166 (x 159) on the DMG also spills over into the whole next line
+    // See PanDocs: https://gbdev.io/pandocs/Scrolling.html#window
+    fn window_span(&mut self) -> Option<(usize, u8, u8)> {
+        if self.ly == 0 {
+            self.window = WindowState::default();
+        }
+        if self.ly == self.wy {
+            self.window.triggered = true;
+        }
+        let wrap = mem::take(&mut self.window.wrap);
+        if !self.window.triggered || !self.lcdc.window_display_enable
+            || !(self.lcdc.bg_window_display_priority || self.cgb_mode) {
+            return None;
+        }
+        let (screen_x, window_x) = match self.wx {
+            _ if wrap => (0, 0),
+            0 => (0, 7 + (self.scx & 7)),
+            1..=6 => (0, 7 - self.wx),
+            7..=166 => ((self.wx - 7) as usize, 0),
+            _ => return None,
+        };
+        self.window.wrap = self.wx == 166;
+        let row = self.window.line;
+        self.window.line = row.wrapping_add(1);
+        Some((screen_x, window_x, row))
+    }
 
-            // Color code (position in memory): base address
-            let line = (y_pos as u16 % 8) * 2;
+    fn set_line_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF42 => self.scy = val,
+            0xFF43 => self.scx = val,
+            0xFF47 => self.bgp = val,
+            _ => unreachable!("0x{:x} is not read mid-line", addr),
+        }
+    }
 
-            // Get a line of bytes that signifies the y-coordinate lsb/msb color
-            let lsb_line = self.read(line + tile_location);
-            let msb_line = self.read(line + tile_location + 1);
+    // Draws the background and the window (see window_span) for the line. The color ids are
+    // fetched a tile row at a time (see decode_tile_row), then the palette is applied to all of
+    // them in one pass. Both are done in spans, from one of the line's writes to the next.
+    fn render_tiles(&mut self, window: Option<(usize, u8, u8)>, writes: &[LineWrite]) {
+        let scanline = self.ly;
+        // See VRAM Background Maps in PanDocs
+        let map = |high: bool| if high { 0x9c00 } else { 0x9800 };
+
+        // Color ids for the 160 pixels, BLANK where a layer is hidden, and priority over sprites
+        let mut ids = [0u8; DISPLAY_WIDTH];
+        let mut priority = [false; DISPLAY_WIDTH];
+        let window_start = window.map_or(DISPLAY_WIDTH, |(screen_x, _, _)| screen_x);
+        let (background, window_ids) = ids.split_at_mut(window_start);
+        if !self.layers.background {
+            background.fill(BLANK);
+        }
+        if let Some((_, window_x, row)) = window {
+            if self.layers.window {
+                let map_base = map(self.lcdc.window_tile_map_display_select);
+                self.fetch_tiles(window_ids, &mut priority[window_start..], map_base, window_x, row);
+            } else {
+                window_ids.fill(BLANK);
+            }
+        }
 
-            // See how many bits needed to locate the actual pixel's msb/lsb
-            // i.e. the pixel's location in the line
-            let color_bit = ((x_pos as i32 % 8) - 7) * -1;
+        let map_base = map(self.lcdc.bg_tile_map_display_select);
+        let start = scanline as usize * DISPLAY_WIDTH;
+        let mut writes = writes.iter().peekable();
+        let mut x = 0;
+        while x < DISPLAY_WIDTH {
+            while let Some(write) = writes.next_if(|write| write.x as usize <= x) {
+                self.set_line_register(write.addr, write.val);
+            }
+            let end = writes.peek().map_or(DISPLAY_WIDTH, |write| write.x as usize);
+            if self.layers.background && x < window_start {
+                let (span, y) = (x..end.min(window_start), self.scy.wrapping_add(scanline));
+                self.fetch_tiles(&mut ids[span.clone()], &mut priority[span], map_base, self.scx.wrapping_add(x as u8), y);
+            }
 
-            // 0, 1, 2, or 3: white, light grey, dark grey, black
-            let color_num = (((msb_line >> color_bit) & 0b1) << 1) | ((lsb_line >> color_bit) & 0b1);
+            let mut shades = [self.palette[0].to_argb(); 5];
+            shades[..4].copy_from_slice(&self.shades(self.bgp).map(Color::to_argb));
+            for (pixel, &id) in self.framebuffer[start + x..start + end].iter_mut().zip(&ids[x..end]) {
+                *pixel = shades[id as usize];
+            }
+            x = end;
+        }
+        self.line_ids = ids;
+        self.line_priority = priority;
+    }
 
-            // get color from color enum
-            let color = self.get_color(color_num, self.bgp);
+    // Fills ids with the color ids of the pixels from x on row y of the tile map at map_base,
+    // wrapping around at the end of the map, and priority with the tiles' priority bit
+    fn fetch_tiles(&self, ids: &mut [u8], priority: &mut [bool], map_base: u16, x: u8, y: u8) {
+        // 32 tiles per row, 8 pixels each, 2 bytes per row of a tile
+        let map_row = map_base + (y / 8) as u16 * 32;
+        let mut x = x;
+        let mut filled = 0;
+        while filled < ids.len() {
+            let map_addr = map_row + (x / 8) as u16;
+            // Bank 1 holds the tile's attributes on the CGB: priority, y flip, x flip, tile bank
+            let attributes = if self.cgb_mode { self.vram_bank_byte(1, map_addr) } else { 0 };
+            let line = if attributes & 0b0100_0000 != 0 { 7 - y % 8 } else { y % 8 };
+            let tile_address = self.tile_address(self.vram_byte(map_addr)) + line as u16 * 2;
+            let bank = (attributes >> 3) & 0x01;
+            let row = decode_tile_row(self.vram_bank_byte(bank, tile_address),
+                                      self.vram_bank_byte(bank, tile_address + 1));
+            let row = if attributes & 0b0010_0000 != 0 { row.swap_bytes() } else { row };
+            let fine_x = (x % 8) as usize;
+            let count = (8 - fine_x).min(ids.len() - filled);
+            ids[filled..filled + count].copy_from_slice(&row.to_le_bytes()[fine_x..fine_x + count]);
+            priority[filled..filled + count].fill(attributes & 0b1000_0000 != 0);
+            filled += count;
+            x = x.wrapping_add(count as u8);
+        }
+    }
 
-            // set the pixel
-            self.set_pixel(pixel as u32, scanline as u32, color)
+    // Where a background or window tile is, by its number in the map. See VRAM Tile Data in
+    // PanDocs: numbers are unsigned from 0x8000, or signed around 0x9000.
+    fn tile_address(&self, tile: u8) -> u16 {
+        if self.lcdc.bg_window_tile_data_select {
+            TILE_BASE_ADDR + tile as u16 * TILE_BYTES
+        } else {
+            0x8800 + (tile as i8 as i16 + 128) as u16 * TILE_BYTES
         }
     }
-    
-    pub fn render_sprites(&mut self) {
-        let is_size_8x16: bool = self.lcdc.sprite_size;
-        
-        // maximum 40 sprites in the screen
-        for sprite in 0..40 { 
-            // sprite information takes up 4 bytes in OAM
-            let index: u8 = sprite * 4;
-            // y-coordinate of top left corner
-            let y_pos = self.oam[index as usize].wrapping_sub(16); 
-            // x_coord of top left corner
-            let x_pos = self.oam[(index + 1) as usize].wrapping_sub(8);
-            // address of tile
-            let sprite_tile_addr = self.oam[(index + 2) as usize] as u16; 
-            // flags that represent attributes of sprite
-            let attributes = self.oam[(index + 3) as usize];
-            // extract info from attributes flag
-            let obj_to_bg_priority = (attributes & 0b1000_0000) >> 7;
-            let y_flip = (attributes & 0b0100_0000) >> 6;
-            let x_flip = (attributes & 0b0010_0000) >> 5;
-            let palette_bit = (attributes & 0b0001_0000) >> 4;
-           
-            // will display the first 10 sprites appearing on this line
-            let scanline = self.ly;
-
-            let y_size = if is_size_8x16 { 16 } else { 8 };
-
-            // Compares scanline to self.ly to find the 10 sprites on the line that appear first
-            // on OAM. (FE00-FE03 = first sprite, FE04 - FE07 2nd sprite and so on. Rank is used to
-            // store order of appearance
-            if scanline >= y_pos && scanline < (y_pos.wrapping_add(y_size)) {
-                // Finding out which line sprite is at in the OAM.
-                let rank: i32 = scanline as i32 - y_pos as i32;
-                // if y_flip: mirror the line over the y-axis, so find in the other direction.
-                let rank = if y_flip > 0 {
-                    (rank - y_size as i32) * (-1)
-                } else {
-                    rank
-                };
-                // tile data is stored in Vram at base addr 0x8000, each tile is 16-byte long.
-                // From base addr, go to specified 16-byte tile, then identify the exact starting addr of sprite color info.
-                let sprite_addr = TILE_BASE_ADDR + (sprite_tile_addr * TILE_BYTES) + (rank as u16) * 2;
-                let lsb_line = self.read(sprite_addr as u16);
-                let msb_line = self.read((sprite_addr + 1) as u16);
-
-                // looking at every pair of bit from 7 to 0, if x_flip we look at them from 0 to 7.
-                for tile_pixel in (0..8).rev() {
-                    let color_bit = tile_pixel as i32;
-                    let color_bit = if x_flip > 0 {
-                        (color_bit - 7) * (-1)
-                    } else {
-                        color_bit
-                    };
-
-                    // Put together the color bits
-                    let color_num = (((msb_line >> color_bit) & 0b01) << 1) | ((lsb_line >> color_bit) & 0b01);
-                    
-                    // get sprite color
-                    let palette_num = if palette_bit == 0 {
-                        self.obp0
-                    } else {
-                        self.obp1
-                    };
 
-                    if color_num == 0 { // transparent, do not draw
-                        continue;
-                    }
+    fn vram_byte(&self, addr: u16) -> u8 {
+        self.vram_bank_byte(0, addr)
+    }
 
-                    let color = self.get_color(color_num, palette_num);
-                    
-                    // x_pix goes opposite direction with tile_pixel (if tile_pixel goes from 7 to
-                    // 0, x_pix goes from 0 to 7 (FIFO)
-                    let x_pix = (0 as u8).wrapping_sub(tile_pixel as u8).wrapping_add(7);
-                    // Go to the specific pixel's x-coordinate, y-coordinate is the scanline
-                    let pixel_x = x_pos.wrapping_add(x_pix);
-                   
-                    // scanline > 143 => VBlank => Nothing in background
-                    // pixel_x > 159 => not drawn
-                    if scanline > 143 || pixel_x > 159 {
-                        continue;
-                    }
+    fn vram_bank_byte(&self, bank: u8, addr: u16) -> u8 {
+        self.vram[bank as usize * VRAM_BANK_SIZE + (addr - TILE_BASE_ADDR) as usize]
+    }
+
+    // The 4 shades a palette register maps color ids 0 - 3 to
+    fn shades(&self, palette: u8) -> [Color; 4] {
+        [0, 1, 2, 3].map(|id| self.palette[(palette >> (id * 2)) as usize & 0b11])
+    }
+
+    // Draws the sprites on the line. The OAM search takes the first SPRITES_PER_LINE in OAM
+    // order, and where they overlap the one with priority gives the pixel, even when it is
+    // behind the background. On the DMG, or the CGB with OPRI set, that is the one furthest
+    // left, then the first in OAM. On the CGB otherwise, the first in OAM.
+    // See PanDocs: https://gbdev.io/pandocs/OAM.html#drawing-priority
+    pub fn render_sprites(&mut self) {
+        let scanline = self.ly;
+        if scanline as usize >= DISPLAY_HEIGHT {
+            return;
+        }
+        let height = if self.lcdc.sprite_size { 16 } else { 8 };
+        // Sprites are 4 bytes in OAM: y + 16, x + 8, tile and attributes. The OAM search keeps
+        // their indexes in OAM, on the stack as it runs every line.
+        let mut sprites = [0; SPRITES_PER_LINE];
+        let mut count = 0;
+        for index in (0..40).map(|sprite| sprite * 4) {
+            if count == SPRITES_PER_LINE {
+                break;
+            }
+            if scanline.wrapping_sub(self.oam[index].wrapping_sub(16)) < height {
+                sprites[count] = index as u8;
+                count += 1;
+            }
+        }
+        let sprites = &mut sprites[..count];
+        if self.model == Model::Dmg || self.opri & 0x01 != 0 {
+            // Stable, so OAM order settles ties
+            sprites.sort_by_key(|&index| self.oam[index as usize + 1]);
+        }
 
-                    self.set_sprite_pixel(pixel_x as u32, scanline as u32, obj_to_bg_priority > 0, color);
+        let mut drawn = [false; DISPLAY_WIDTH];
+        for index in sprites.iter().map(|&index| index as usize) {
+            let x_pos = self.oam[index + 1].wrapping_sub(8);
+            let attributes = self.oam[index + 3];
+            let behind_background = attributes & 0b1000_0000 != 0;
+            let y_flip = attributes & 0b0100_0000 != 0;
+            let x_flip = attributes & 0b0010_0000 != 0;
+            let palette = if attributes & 0b0001_0000 == 0 { self.obp0 } else { self.obp1 };
+
+            // The row of the sprite on this line, counted from the bottom when flipped. 8x16
+            // sprites take an even tile and the one after it.
+            let row = scanline.wrapping_sub(self.oam[index].wrapping_sub(16));
+            let row = if y_flip { height - 1 - row } else { row };
+            let tile = if height == 16 { self.oam[index + 2] & 0xFE } else { self.oam[index + 2] };
+            let sprite_addr = TILE_BASE_ADDR + tile as u16 * TILE_BYTES + row as u16 * 2;
+            // CGB games can take sprite tiles from VRAM bank 1
+            let bank = if self.cgb_mode { (attributes >> 3) & 0x01 } else { 0 };
+            let ids = decode_tile_row(self.vram_bank_byte(bank, sprite_addr),
+                                      self.vram_bank_byte(bank, sprite_addr + 1));
+            // x_flip mirrors the row, pixel 7 comes first
+            let ids = if x_flip { ids.swap_bytes() } else { ids };
+
+            let shades = self.shades(palette);
+            for (x, &id) in ids.to_le_bytes().iter().enumerate() {
+                let pixel_x = x_pos.wrapping_add(x as u8) as usize;
+                // 0 is transparent, and lets sprites with less priority through
+                if id == 0 || pixel_x >= DISPLAY_WIDTH || drawn[pixel_x] {
+                    continue;
                 }
+                drawn[pixel_x] = true;
+                self.set_sprite_pixel(pixel_x as u32, scanline as u32, behind_background, shades[id as usize]);
             }
         }
     }
@@ -633,41 +1145,33 @@ impl Ppu {
         let color = (((palette_num >> msb) & 0x01) << 1) | ((palette_num >> lsb) & 0x01);
         
         // Return color based on specified number in color
-        match color  {
-            0 => WHITE,
-            1 => LIGHT_GRAY,
-            2 => DARK_GRAY,
-            3 => BLACK,
-            _ => panic!("Invalid color"),
-        }
+        self.palette[color as usize]
     }
 
-    pub fn set_sprite_pixel(&mut self, pixel_x: u32, y_line: u32, priority: bool, color: Color) {
-        // tile_index: from coordinates, derive index of tile in array of bytes representing lcd_screen. 
-        // Each y coordinate can contain 160 (display width) tiles
-        let tile_index = ((y_line * DISPLAY_WIDTH as u32) + pixel_x) as usize;
-
-        let prev_pixel = Color {
-            a: (self.framebuffer[tile_index] >> 24) as u8,
-            r: (self.framebuffer[tile_index] >> 16) as u8,
-            g: (self.framebuffer[tile_index] >> 8) as u8,
-            b: self.framebuffer[tile_index] as u8,
-        };
+    // Colors for the 4 shades as 0xAARRGGBB, lightest first
+    pub fn set_palette(&mut self, palette: [u32; 4]) {
+        for (shade, &argb) in self.palette.iter_mut().zip(palette.iter()) {
+            *shade = Color::from_argb(argb);
+        }
+    }
 
-        // if color of previous tile is not white and it has higher priority, don't draw next tile
-        if prev_pixel != WHITE && priority {
+    // A sprite pixel loses to background and window colors 1 - 3 when the sprite is behind the
+    // background, or on the CGB when the tile's attributes put the tile in front. CGB games
+    // clear LCDC bit 0 to put sprites in front of everything.
+    pub fn set_sprite_pixel(&mut self, pixel_x: u32, y_line: u32, behind_background: bool, color: Color) {
+        let x = pixel_x as usize;
+        let background = self.line_ids[x];
+        let master_priority = !self.cgb_mode || self.lcdc.bg_window_display_priority;
+        if background != 0 && background != BLANK && master_priority
+            && (behind_background || self.line_priority[x]) {
             return;
-        } else {
-            self.set_pixel(pixel_x, y_line, color)
         }
+        self.set_pixel(pixel_x, y_line, color)
     }
 
     pub fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
         let tile_index = ((y * DISPLAY_WIDTH as u32) + x) as usize;
-        
-        let c = ((color.a as u32) << 24) | ((color.r as u32) << 16) | ((color.g as u32) << 8) | (color.b as u32);
-
-        self.framebuffer[tile_index] = c;
+        self.framebuffer[tile_index] = color.to_argb();
     }
 
 }
@@ -679,7 +1183,7 @@ mod test {
     #[test]
     fn init_test() {
         // Test lcdc initiation
-        let mut lcdc = Lcdc::new();
+        let lcdc = Lcdc::new();
         assert!(lcdc.lcd_display_enable); // true
         assert!(!lcdc.window_tile_map_display_select); // false
         assert!(!lcdc.window_display_enable); // false
@@ -690,7 +1194,7 @@ mod test {
         assert!(lcdc.bg_window_display_priority); // true
         assert_eq!(lcdc.get_flags(), 0x91);
 
-        let mut lcdstat = LCDStat::new();
+        let lcdstat = LCDStat::new();
         assert!(!lcdstat.lcd_ly_coincidence_interrupt); // false
         assert!(!lcdstat.mode_2_oam_interrupt); // false
         assert!(!lcdstat.mode_1_vblank_interupt); // false
@@ -702,7 +1206,7 @@ mod test {
         }
         assert_eq!(lcdstat.get_flags(), 0b0000_0001);
 
-        let mut ppu = Ppu::new();
+        let ppu = Ppu::new();
         assert_eq!(ppu.scx, 0);
         assert_eq!(ppu.scy, 0);
         assert_eq!(ppu.ly, 144);
@@ -717,3 +1221,360 @@ mod test {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::console::NoVideo;
+
+    #[test]
+    fn oam_bug_scrambles_the_row_being_scanned() {
+        let mut ppu = Ppu::new();
+        for (i, byte) in ppu.oam.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let before = ppu.oam;
+        ppu.lcdc.lcd_display_enable = true;
+        ppu.lcdstat.mode_flag = Mode::Oam;
+
+        // Outside OAM, or on the first row: nothing happens
+        ppu.corrupt_oam(0xC000);
+        ppu.mode_cycles = 2;
+        ppu.corrupt_oam(0xFE00);
+        assert_eq!(&ppu.oam[..], &before[..]);
+
+        ppu.mode_cycles = 9; // third row
+        ppu.corrupt_oam(0xFE10);
+        let (a, b, c) = (0x1110u16, 0x0908u16, 0x0D0Cu16);
+        assert_eq!(&ppu.oam[16..18], &(((a ^ c) & (b ^ c)) ^ c).to_le_bytes());
+        assert_eq!(&ppu.oam[18..24], &before[10..16]);
+        assert_eq!(&ppu.oam[24..], &before[24..]);
+    }
+
+    #[test]
+    fn tile_rows_decode_to_the_bit_planes() {
+        for lsb in 0..=255u8 {
+            for msb in 0..=255u8 {
+                let row = decode_tile_row(lsb, msb).to_le_bytes();
+                for (pixel, &id) in row.iter().enumerate() {
+                    let bit = 7 - pixel;
+                    assert_eq!(id, ((msb >> bit) & 1) << 1 | ((lsb >> bit) & 1), "{:02x} {:02x}", lsb, msb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scrolled_lines_start_mid_tile() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x8010, 0xF0); // tile 1, ids 3 3 1 1 2 2 0 0
+        ppu.write(0x8011, 0xCC);
+        ppu.write(0x981F, 1); // the last tile on the row
+        ppu.write(0x9800, 1); // and the first, wrapped around to
+        ppu.write(0xFF47, 0xE4); // ids as they are
+        ppu.write(0xFF43, 250);
+        ppu.ly = 0;
+        ppu.draw_scanline();
+        let palette = ppu.palette;
+        let shade = |id: usize| palette[id].to_argb();
+        let line: Vec<u32> = [1, 1, 2, 2, 0, 0, 3, 3, 1, 1, 2, 2, 0, 0].iter().map(|&id| shade(id)).collect();
+        assert_eq!(&ppu.framebuffer[..14], &line[..]);
+        assert!(ppu.framebuffer[14..160].iter().all(|&pixel| pixel == shade(0)));
+
+        ppu.write(0xFF47, 0x1B); // reversed
+        ppu.set_layers(Layers { background: false, ..Layers::default() });
+        ppu.draw_scanline();
+        assert!(ppu.framebuffer[..160].iter().all(|&pixel| pixel == shade(0)));
+    }
+
+    // Window tile 1: row 0 has ids 3 3 1 1 2 2 0 0, the other rows are all 3. The background is
+    // all 0.
+    fn window_ppu(wx: u8, wy: u8) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.write(0x8010, 0xF0);
+        ppu.write(0x8011, 0xCC);
+        for addr in 0x8012..0x8020 {
+            ppu.write(addr, 0xFF);
+        }
+        for addr in 0x9C00..0xA000 {
+            ppu.write(addr, 1);
+        }
+        ppu.write(0xFF40, 0xF1); // window on, from the 0x9c00 map
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF4A, wy);
+        ppu.write(0xFF4B, wx);
+        ppu
+    }
+
+    // Draws line ly, and gives back its color ids
+    fn draw_line(ppu: &mut Ppu, ly: u8) -> Vec<u8> {
+        ppu.ly = ly;
+        ppu.draw_scanline();
+        let shades = ppu.palette.map(Color::to_argb);
+        let start = ly as usize * DISPLAY_WIDTH;
+        ppu.framebuffer[start..start + DISPLAY_WIDTH].iter()
+            .map(|pixel| shades.iter().position(|shade| shade == pixel).unwrap() as u8)
+            .collect()
+    }
+
+    #[test]
+    fn window_position_edge_cases() {
+        const ROW0: [u8; 8] = [3, 3, 1, 1, 2, 2, 0, 0];
+        let mut ppu = window_ppu(15, 1);
+        assert!(draw_line(&mut ppu, 0).iter().all(|&id| id == 0));
+        let line = draw_line(&mut ppu, 1);
+        assert_eq!(line[..8], [0; 8]);
+        assert_eq!(line[8..16], ROW0);
+        // Rows follow on from lines that had the window only
+        ppu.write(0xFF40, 0xD1);
+        assert!(draw_line(&mut ppu, 2).iter().all(|&id| id == 0));
+        ppu.write(0xFF40, 0xF1);
+        assert_eq!(draw_line(&mut ppu, 3)[8..16], [3; 8]);
+        // Moving WY down after the window started does not stop it
+        ppu.write(0xFF4A, 100);
+        assert_eq!(draw_line(&mut ppu, 4)[8..16], [3; 8]);
+
+        // WY only ever set below LY: no window
+        let mut ppu = window_ppu(7, 200);
+        draw_line(&mut ppu, 0);
+        ppu.write(0xFF4A, 0);
+        assert!(draw_line(&mut ppu, 1).iter().all(|&id| id == 0));
+
+        // WX under 7 cuts the window's left edge off, at 0 by the fine scroll too
+        let mut ppu = window_ppu(3, 0);
+        assert_eq!(draw_line(&mut ppu, 0)[..4], ROW0[4..]);
+        let mut ppu = window_ppu(0, 0);
+        assert_eq!(draw_line(&mut ppu, 0)[..2], [0, 3]);
+        ppu.write(0xFF43, 2);
+        assert_eq!(draw_line(&mut ppu, 0)[..5], [3, 1, 1, 2, 2]);
+
+        // WX 166 shows one column, then all of the next line
+        let mut ppu = window_ppu(166, 0);
+        let line = draw_line(&mut ppu, 0);
+        assert_eq!((line[158], line[159]), (0, 3));
+        ppu.write(0xFF4B, 200);
+        assert_eq!(draw_line(&mut ppu, 1)[..8], [3; 8]);
+        assert!(draw_line(&mut ppu, 2).iter().all(|&id| id == 0));
+    }
+
+    // STAT requests in one frame, with HBlank and LY=LYC (line 10) interrupts on
+    fn stat_requests(accuracy: AccuracyLevel) -> Vec<StatCause> {
+        let mut ppu = Ppu::new();
+        let mut interrupts = InterruptController::new();
+        ppu.set_accuracy(accuracy);
+        ppu.write(0xFF41, 0x48);
+        ppu.write(0xFF45, 10);
+        ppu.record_timeline(true);
+        for _ in 0..CLKS_SCREEN_REFRESH / 4 {
+            ppu.cycle_flush(4, &mut NoVideo, &mut interrupts);
+        }
+        ppu.take_timeline().events.iter().filter_map(|event| match *event {
+            TimelineEvent::Stat { cause, .. } => Some(cause),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn stat_blocking_when_cycle_accurate() {
+        let balanced = stat_requests(AccuracyLevel::Balanced);
+        assert!(balanced.contains(&StatCause::LyCoincidence));
+        // LY=LYC comes true at the end of HBlank, which holds the line high already
+        let accurate = stat_requests(AccuracyLevel::CycleAccurate);
+        assert!(!accurate.contains(&StatCause::LyCoincidence));
+        assert!(accurate.len() < balanced.len());
+    }
+
+    // STAT requests from writing stat to STAT in line 5's OAM search, then in its HBlank
+    fn stat_write_requests(model: Model, accuracy: AccuracyLevel, stat: u8) -> Vec<StatCause> {
+        let mut ppu = Ppu::new();
+        let mut interrupts = InterruptController::new();
+        ppu.set_model(model);
+        ppu.set_accuracy(accuracy);
+        ppu.write(0xFF41, stat);
+        while ppu.ly != 5 || !matches!(ppu.lcdstat.mode_flag, Mode::Oam) {
+            ppu.cycle_flush(4, &mut NoVideo, &mut interrupts);
+        }
+        ppu.record_timeline(true);
+        ppu.write(0xFF41, stat);
+        ppu.cycle_flush(4, &mut NoVideo, &mut interrupts);
+        while !matches!(ppu.lcdstat.mode_flag, Mode::HBlank) {
+            ppu.cycle_flush(4, &mut NoVideo, &mut interrupts);
+        }
+        ppu.write(0xFF41, stat);
+        ppu.cycle_flush(4, &mut NoVideo, &mut interrupts);
+        ppu.take_timeline().events.iter().filter_map(|event| match *event {
+            TimelineEvent::Stat { cause, .. } => Some(cause),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn stat_writes_fire_on_the_dmg_only() {
+        use StatCause::StatWrite;
+        // In HBlank, not in the OAM search
+        assert_eq!(stat_write_requests(Model::Dmg, AccuracyLevel::Balanced, 0), [StatWrite]);
+        assert_eq!(stat_write_requests(Model::Dmg, AccuracyLevel::CycleAccurate, 0), [StatWrite]);
+        assert!(stat_write_requests(Model::Cgb, AccuracyLevel::Balanced, 0).is_empty());
+        assert!(stat_write_requests(Model::Dmg, AccuracyLevel::Fast, 0).is_empty());
+
+        // With the HBlank interrupt on the line is high already, and cycle accurate that blocks it
+        assert_eq!(stat_write_requests(Model::Dmg, AccuracyLevel::Balanced, 0x08), [StatWrite]);
+        assert!(stat_write_requests(Model::Dmg, AccuracyLevel::CycleAccurate, 0x08).is_empty());
+    }
+
+    #[test]
+    fn stat_writes_carry_over_save_states() {
+        let dmg = || {
+            let mut ppu = Ppu::new();
+            ppu.set_model(Model::Dmg);
+            ppu.set_accuracy(AccuracyLevel::Balanced);
+            ppu
+        };
+        let mut ppu = dmg();
+        let mut interrupts = InterruptController::new();
+        while !matches!(ppu.lcdstat.mode_flag, Mode::HBlank) {
+            ppu.cycle_flush(4, &mut NoVideo, &mut interrupts);
+        }
+        // Saved between the write and the cycle it fires on
+        ppu.write(0xFF41, 0);
+        let mut state = StateWriter::new();
+        ppu.save_state(&mut state);
+        let state = state.finish();
+
+        let mut restored = dmg();
+        restored.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+        restored.record_timeline(true);
+        restored.cycle_flush(4, &mut NoVideo, &mut interrupts);
+        assert!(restored.take_timeline().events.iter()
+            .any(|event| matches!(*event, TimelineEvent::Stat { cause: StatCause::StatWrite, .. })));
+    }
+
+    #[test]
+    fn writes_split_the_line_being_drawn() {
+        const ROW0: [u8; 8] = [3, 3, 1, 1, 2, 2, 0, 0];
+        let mut ppu = window_ppu(0, 0);
+        for addr in 0x9800..0x9C00 {
+            ppu.write(addr, 1);
+        }
+        ppu.write(0xFF40, 0x91); // background only
+        let write_at = |ppu: &mut Ppu, mode: Mode, cycles: u32, addr: u16, val: u8| {
+            ppu.lcdstat.mode_flag = mode;
+            ppu.mode_cycles = cycles;
+            ppu.write(addr, val);
+        };
+        ppu.ly = 0;
+        write_at(&mut ppu, Mode::Vram, PIXEL_DELAY + 16, 0xFF43, 2);
+        write_at(&mut ppu, Mode::Vram, PIXEL_DELAY + 32, 0xFF42, 1);
+        write_at(&mut ppu, Mode::Vram, PIXEL_DELAY + 40, 0xFF47, 0x1B);
+        // Too late for this line
+        write_at(&mut ppu, Mode::HBlank, 10, 0xFF47, 0xE4);
+        let line = draw_line(&mut ppu, 0);
+        assert_eq!(line[..8], ROW0);
+        assert_eq!(line[16..24], [1, 1, 2, 2, 0, 0, 3, 3]);
+        assert_eq!(line[32..40], [3; 8]);
+        assert_eq!(line[40..48], [0; 8]);
+        assert_eq!((ppu.scx, ppu.scy, ppu.bgp), (2, 1, 0xE4));
+
+        // Nothing left over for the next line, which starts with the last values
+        assert_eq!(draw_line(&mut ppu, 1)[..8], [3; 8]);
+    }
+
+    // Sprites from the 0x8000 tiles: tile 1 all id 1, tile 2 all id 2, tile 3 id 3 on its
+    // first row only. Each sprite is (y, x, tile, attributes) as in OAM.
+    fn sprite_ppu(model: Model, sprites: &[(u8, u8, u8, u8)]) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.set_model(model);
+        for addr in 0x8010..0x8020 {
+            ppu.write(addr, if addr & 1 == 0 { 0xFF } else { 0x00 });
+        }
+        for addr in 0x8020..0x8030 {
+            ppu.write(addr, if addr & 1 == 0 { 0x00 } else { 0xFF });
+        }
+        ppu.write(0x8030, 0xFF);
+        ppu.write(0x8031, 0xFF);
+        for (n, &(y, x, tile, attributes)) in sprites.iter().enumerate() {
+            let index = 0xFE00 + n as u16 * 4;
+            ppu.write(index, y);
+            ppu.write(index + 1, x);
+            ppu.write(index + 2, tile);
+            ppu.write(index + 3, attributes);
+        }
+        ppu.write(0xFF40, 0x93); // sprites on, 8x8
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0xE4);
+        ppu
+    }
+
+    #[test]
+    fn sprite_priority_by_model_and_opri() {
+        // The first sprite in OAM overlaps the right half of the second, which is further left
+        let sprites = [(16, 20, 1, 0), (16, 16, 2, 0)];
+        let mut dmg = sprite_ppu(Model::Dmg, &sprites);
+        assert_eq!(draw_line(&mut dmg, 0)[8..20], [2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1]);
+        let mut cgb = sprite_ppu(Model::Cgb, &sprites);
+        assert_eq!(draw_line(&mut cgb, 0)[8..20], [2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(cgb.read(0xFF6C), 0xFE);
+        cgb.write(0xFF6C, 0x01);
+        assert_eq!(cgb.read(0xFF6C), 0xFF);
+        assert_eq!(draw_line(&mut cgb, 0)[8..20], [2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1]);
+
+        // The same x goes by OAM order everywhere
+        let mut dmg = sprite_ppu(Model::Dmg, &[(16, 16, 1, 0), (16, 16, 2, 0)]);
+        assert!(draw_line(&mut dmg, 0)[8..16].iter().all(|&id| id == 1));
+    }
+
+    #[test]
+    fn sprite_lines_and_flips() {
+        // Only the first 10 sprites on a line show, whatever their x
+        let sprites: Vec<_> = (0..11).map(|n| (16, 160 - n * 8, 1, 0)).collect();
+        let line = draw_line(&mut sprite_ppu(Model::Dmg, &sprites), 0);
+        assert!(line[..80].iter().all(|&id| id == 0));
+        assert!(line[80..].iter().all(|&id| id == 1));
+
+        // A flipped sprite shows its first row on its last line, and sprites can hang over the top
+        let mut ppu = sprite_ppu(Model::Dmg, &[(16, 8, 3, 0x40), (12, 16, 3, 0)]);
+        assert_eq!(draw_line(&mut ppu, 0)[..16], [0; 16]);
+        assert_eq!(draw_line(&mut ppu, 7)[..8], [3; 8]);
+        let mut ppu = sprite_ppu(Model::Dmg, &[(12, 8, 3, 0x40)]);
+        assert_eq!(draw_line(&mut ppu, 3)[..8], [3; 8]);
+    }
+
+    #[test]
+    fn cgb_tile_attributes_and_master_priority() {
+        let mut ppu = sprite_ppu(Model::Cgb, &[(16, 8, 1, 0)]);
+        ppu.set_cgb_mode(true);
+        ppu.write(0x9800, 2);
+        ppu.write(0x9801, 3);
+        // Bank 1: tile 0 in front of sprites, tile 1 from bank 1 and mirrored
+        ppu.write(0xFF4F, 1);
+        ppu.write(0x8030, 0xF0);
+        ppu.write(0x9800, 0x80);
+        ppu.write(0x9801, 0x28);
+        ppu.write(0xFF4F, 0);
+        assert_eq!(ppu.read(0x8030), 0xFF);
+
+        let line = draw_line(&mut ppu, 0);
+        assert_eq!(line[..8], [2; 8]);
+        assert_eq!(line[8..16], [0, 0, 0, 0, 1, 1, 1, 1]);
+        // LCDC bit 0 clear puts sprites in front, the background still shows
+        ppu.write(0xFF40, 0x92);
+        let line = draw_line(&mut ppu, 0);
+        assert_eq!(line[..8], [1; 8]);
+        assert_eq!(line[8..16], [0, 0, 0, 0, 1, 1, 1, 1]);
+
+        // Outside CGB mode the attributes are not there, and only the OAM bit counts
+        ppu.set_cgb_mode(false);
+        ppu.write(0xFF40, 0x93);
+        assert_eq!(draw_line(&mut ppu, 0)[..8], [1; 8]);
+    }
+
+    #[test]
+    fn sprites_behind_the_background_go_by_color_id() {
+        // Background color 2, shown as the lightest shade, still hides the sprite
+        let mut ppu = sprite_ppu(Model::Dmg, &[(16, 8, 1, 0x80), (16, 16, 1, 0x80)]);
+        ppu.write(0x9800, 2);
+        ppu.write(0xFF47, 0xC0);
+        let line = draw_line(&mut ppu, 0);
+        assert_eq!(line[..16], [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1]);
+    }
+}
@@ -0,0 +1,99 @@
+// Cycle profiler.
+// While Console::start_profiling is on, the cycles of every instruction go to the call stack it
+// leaves (see debugger.rs), so each chain of calls adds up the time spent in it. An instruction
+// that calls or returns counts for where it lands, a few cycles per call off at most.
+//
+// The report comes out as folded stacks, one chain per line, outermost first, with its cycles:
+//
+//     top;MainLoop;UpdateSprites 18344
+//
+// which flamegraph.pl, inferno and speedscope all read. Functions are named from the symbols,
+// or by address without them; "top" is code outside any call.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::debugger::{BankedAddr, StackFrame};
+use super::symbols::Symbols;
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    // By the targets of the frames on the call stack, outermost first
+    stacks: HashMap<Vec<BankedAddr>, u64>,
+    key: Vec<BankedAddr>, // reused to look up stacks
+}
+
+impl Profile {
+    pub fn add(&mut self, frames: &[StackFrame], cycles: u32) {
+        self.key.clear();
+        self.key.extend(frames.iter().map(|frame| frame.target));
+        match self.stacks.get_mut(&self.key) {
+            Some(total) => *total += cycles as u64,
+            None => {
+                self.stacks.insert(self.key.clone(), cycles as u64);
+            }
+        }
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    // Cycles spent in each function itself, not counting what it called, most first
+    pub fn by_function(&self, symbols: &Symbols) -> Vec<(String, u64)> {
+        let mut functions: HashMap<String, u64> = HashMap::new();
+        for (stack, &cycles) in &self.stacks {
+            let name = stack.last().map_or("top".to_string(), |&target| name(symbols, target));
+            *functions.entry(name).or_default() += cycles;
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        functions
+    }
+
+    // The folded stacks, sorted so the same run gives the same file
+    pub fn to_folded(&self, symbols: &Symbols) -> String {
+        let mut lines: Vec<String> = self.stacks.iter().map(|(stack, cycles)| {
+            let mut line = "top".to_string();
+            for &target in stack {
+                write!(line, ";{}", name(symbols, target)).unwrap();
+            }
+            write!(line, " {}", cycles).unwrap();
+            line
+        }).collect();
+        lines.sort();
+        lines.iter().map(|line| line.clone() + "\n").collect()
+    }
+}
+
+fn name(symbols: &Symbols, target: BankedAddr) -> String {
+    symbols.label(target).unwrap_or_else(|| target.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::debugger::CallKind;
+
+    fn frame(addr: u16) -> StackFrame {
+        let target = BankedAddr::new(0, addr);
+        StackFrame { kind: CallKind::Call, target, return_to: target, sp: 0, name: None }
+    }
+
+    #[test]
+    fn folds_stacks_and_names_them() {
+        let mut profile = Profile::default();
+        profile.add(&[], 4);
+        profile.add(&[frame(0x0200)], 8);
+        profile.add(&[frame(0x0200), frame(0x0100)], 12);
+        profile.add(&[frame(0x0200)], 8);
+        profile.add(&[frame(0x0100)], 4);
+        assert_eq!(profile.total_cycles(), 36);
+
+        let mut symbols = Symbols::default();
+        symbols.insert(BankedAddr::new(0, 0x0200), "Main");
+        assert_eq!(profile.to_folded(&symbols), "top 4\ntop;00:0100 4\ntop;Main 16\ntop;Main;00:0100 12\n");
+        assert_eq!(profile.by_function(&symbols), [("00:0100".to_string(), 16), ("Main".to_string(), 16),
+                                                   ("top".to_string(), 4)]);
+    }
+}
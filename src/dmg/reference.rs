@@ -0,0 +1,230 @@
+// Reference image tests for the PPU.
+// assert_frame_matches runs a ROM headless for a number of frames and compares the last one
+// pixel by pixel against a PNG in testcase/reference. A pixel matches when each of its red, green
+// and blue values is within the tolerance of the reference's.
+// On a mismatch, two files are written next to the reference before the test fails:
+// <name>.actual.png, the frame as rendered, and <name>.diff.png, the frame dimmed with every
+// differing pixel in red.
+// assert_frame_hash checks a frame against a known-good CRC32 in HASHES instead, a line
+// "<name> <crc>" per frame, for exact checks without a PNG each.
+// Set GBRUST_BLESS=1 to write (or overwrite) references and hashes from what the emulator renders
+// now, after checking by eye that it is right.
+// Test ROMs that cannot be shipped here (see the acid2 tests below) are #[ignore]d tests that expect the
+// ROM in testcase/, run them with `cargo test -- --ignored`.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use super::compare::CrcSink;
+use super::console::{Console, Frame, LastFrame, VideoSink};
+use super::ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+pub const REFERENCE_DIR: &str = "testcase/reference";
+pub const HASHES: &str = "testcase/reference/hashes.txt";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameDiff {
+    // Pixels off by more than the tolerance
+    pub differing: usize,
+    // Largest difference in any channel, of any pixel
+    pub max_delta: u8,
+    pub diff_image: Box<[u32]>,
+}
+
+// The last of the next `frames` frames
+pub fn render_frame(console: &mut Console, frames: usize) -> Box<[u32]> {
+    let mut sink = LastFrame(None);
+    for _ in 0..frames {
+        console.run_frame(&mut sink);
+    }
+    sink.0.expect("the LCD stayed off")
+}
+
+fn channels(pixel: u32) -> [u8; 3] {
+    [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]
+}
+
+pub fn compare(frame: &[u32], reference: &[u32], tolerance: u8) -> FrameDiff {
+    assert_eq!(frame.len(), reference.len(), "frame and reference differ in size");
+    let mut differing = 0;
+    let mut max_delta = 0;
+    let diff_image = frame.iter().zip(reference).map(|(&pixel, &expected)| {
+        let delta = channels(pixel).iter().zip(&channels(expected))
+            .map(|(a, b)| a.abs_diff(*b))
+            .max()
+            .unwrap();
+        max_delta = max_delta.max(delta);
+        if delta > tolerance {
+            differing += 1;
+            0xFFFF_0000
+        } else {
+            // A quarter of the brightness
+            0xFF00_0000 | ((pixel >> 2) & 0x003F_3F3F)
+        }
+    }).collect();
+    FrameDiff { differing, max_delta, diff_image }
+}
+
+// 160x144 RGB(A) PNG, as 0xAARRGGBB pixels
+pub fn load_png<P: AsRef<Path>>(path: P) -> Box<[u32]> {
+    let path = path.as_ref();
+    let file = File::open(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().expect("not a PNG");
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).expect("broken PNG");
+    assert_eq!((info.width as usize, info.height as usize), (DISPLAY_WIDTH, DISPLAY_HEIGHT),
+               "{} is not a screenshot", path.display());
+    let rgb = |r: u8, g: u8, b: u8| 0xFF00_0000 | (r as u32) << 16 | (g as u32) << 8 | b as u32;
+    let step = info.color_type.samples();
+    buf[..info.buffer_size()].chunks(step)
+        .map(|p| if step < 3 { rgb(p[0], p[0], p[0]) } else { rgb(p[0], p[1], p[2]) })
+        .collect()
+}
+
+pub fn save_png<P: AsRef<Path>>(path: P, frame: &[u32]) {
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    let data: Vec<u8> = frame.iter().flat_map(|&pixel| channels(pixel)).collect();
+    encoder.write_header().unwrap().write_image_data(&data).unwrap();
+}
+
+fn sibling(reference: &Path, suffix: &str) -> PathBuf {
+    let stem = reference.file_stem().unwrap().to_string_lossy();
+    reference.with_file_name(format!("{}.{}.png", stem, suffix))
+}
+
+// Fails the test if frame `frames` of the ROM (counting from power on, without boot ROM) is not
+// the reference, a file in REFERENCE_DIR
+pub fn assert_frame_matches<P: AsRef<Path>>(rom_path: P, frames: usize, reference: &str, tolerance: u8) {
+    let mut console = Console::builder().rom_path(rom_path).build().expect("ROM does not load");
+    assert_console_matches(&mut console, frames, reference, tolerance);
+}
+
+// Same, for a console set up by the test, running `frames` more frames
+pub fn assert_console_matches(console: &mut Console, frames: usize, reference: &str, tolerance: u8) {
+    let frame = render_frame(console, frames);
+    let reference = Path::new(REFERENCE_DIR).join(reference);
+    if env::var_os("GBRUST_BLESS").is_some() {
+        fs::create_dir_all(REFERENCE_DIR).unwrap();
+        save_png(&reference, &frame);
+        return;
+    }
+
+    let diff = compare(&frame, &load_png(&reference), tolerance);
+    if diff.differing > 0 {
+        let (actual, diff_path) = (sibling(&reference, "actual"), sibling(&reference, "diff"));
+        save_png(&actual, &frame);
+        save_png(&diff_path, &diff.diff_image);
+        panic!("{} pixels differ from {} (by up to {}), see {} and {}", diff.differing,
+               reference.display(), diff.max_delta, actual.display(), diff_path.display());
+    }
+}
+
+fn known_hash(name: &str) -> Option<u32> {
+    let hashes = fs::read_to_string(HASHES).unwrap_or_default();
+    hashes.lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(known, _)| *known == name)
+        .map(|(_, hash)| u32::from_str_radix(hash.trim(), 16).expect("bad hash"))
+}
+
+fn bless_hash(name: &str, hash: u32) {
+    let hashes = fs::read_to_string(HASHES).unwrap_or_default();
+    let mut lines: Vec<String> = hashes.lines()
+        .filter(|line| line.split(' ').next() != Some(name))
+        .map(str::to_string)
+        .collect();
+    lines.push(format!("{} {:08x}", name, hash));
+    lines.sort();
+    fs::create_dir_all(REFERENCE_DIR).unwrap();
+    fs::write(HASHES, lines.join("\n") + "\n").unwrap();
+}
+
+// Fails the test if the console's frame `frames` frames on does not hash to the one known as
+// name. On a mismatch the frame is written to <name>.actual.png in REFERENCE_DIR.
+pub fn assert_frame_hash(console: &mut Console, frames: usize, name: &str) {
+    let frame = render_frame(console, frames);
+    let mut crc = CrcSink(0);
+    crc.frame_available(&Frame::dmg(&frame));
+    let hash = crc.0;
+    if env::var_os("GBRUST_BLESS").is_some() {
+        bless_hash(name, hash);
+        return;
+    }
+    let expected = known_hash(name)
+        .unwrap_or_else(|| panic!("no hash for {} in {}, check the frame and bless it", name, HASHES));
+    if hash != expected {
+        let actual = Path::new(REFERENCE_DIR).join(format!("{}.actual.png", name));
+        save_png(&actual, &frame);
+        panic!("{} hashes to {:08x}, not {:08x}, see {}", name, hash, expected, actual.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::config::Palette;
+
+    #[test]
+    fn compare_respects_tolerance() {
+        let reference = [0xFF10_2030, 0xFF10_2030, 0xFF10_2030];
+        let frame = [0xFF10_2030, 0xFF12_2030, 0xFF10_2050];
+        let diff = compare(&frame, &reference, 2);
+        assert_eq!((diff.differing, diff.max_delta), (1, 0x20));
+        assert_eq!(diff.diff_image[2], 0xFFFF_0000);
+        assert_eq!(diff.diff_image[0], 0xFF04_080C);
+    }
+
+    #[test]
+    fn tetris_title_screen() {
+        assert_frame_matches("tetris.gb", 300, "tetris_300.png", 0);
+    }
+
+    #[test]
+    fn tetris_title_screen_hash() {
+        let mut console = Console::builder().rom_path("tetris.gb").build().unwrap();
+        assert_frame_hash(&mut console, 300, "tetris_300");
+    }
+
+    // dmg-acid2 (https://github.com/mattcurrie/dmg-acid2) draws a face out of PPU edge cases:
+    // window start and restart, sprite priority and X ordering, 8x16 sprites and more. Put
+    // dmg-acid2.gb in testcase/acid2 and its reference-dmg.png as testcase/reference/dmg-acid2.png.
+    // The reference uses plain greys, so the test does too. Once the frame matches the reference,
+    // GBRUST_BLESS=1 records its hash in HASHES and the test checks that from then on.
+    #[test]
+    #[ignore = "needs testcase/acid2/dmg-acid2.gb, which is not vendored"]
+    fn dmg_acid2() {
+        let mut console = Console::builder()
+            .rom_path("testcase/acid2/dmg-acid2.gb")
+            .build()
+            .expect("testcase/acid2/dmg-acid2.gb is missing");
+        console.set_palette(Palette([0xFFFF_FFFF, 0xFFAA_AAAA, 0xFF55_5555, 0xFF00_0000]));
+        // The face is done well before this, the ROM then loops on LD B, B
+        match known_hash("dmg-acid2") {
+            Some(_) => assert_frame_hash(&mut console, 60, "dmg-acid2"),
+            None => assert_console_matches(&mut console, 60, "dmg-acid2.png", 0),
+        }
+    }
+
+    // cgb-acid2 (https://github.com/mattcurrie/cgb-acid2) is the same face for the CGB: tile
+    // attributes, VRAM banks and the master priority bit. Put cgb-acid2.gbc in testcase/acid2 and
+    // its reference.png as testcase/reference/cgb-acid2.png. It also needs the CGB's colour
+    // palettes, which the PPU does not draw with yet, so the face will not match until it does.
+    #[test]
+    #[ignore = "needs testcase/acid2/cgb-acid2.gbc, which is not vendored, and CGB colour palettes"]
+    fn cgb_acid2() {
+        let mut console = Console::builder()
+            .rom_path("testcase/acid2/cgb-acid2.gbc")
+            .build()
+            .expect("testcase/acid2/cgb-acid2.gbc is missing");
+        match known_hash("cgb-acid2") {
+            Some(_) => assert_frame_hash(&mut console, 60, "cgb-acid2"),
+            None => assert_console_matches(&mut console, 60, "cgb-acid2.png", 0),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// Remote control.
+// A ConsoleHandle lets other threads (a GUI, the remote debugger) drive a console while the
+// emulation loop runs on its own thread. Requests go down a channel and are served by
+// Console::process_commands, which run_frame calls before every frame, paused or not. Replies
+// are therefore always taken between frames, never halfway through one.
+// Queries block until they are served, so they only return while something keeps calling
+// run_frame (or process_commands). Once the console is dropped every call fails with
+// Disconnected.
+// The debug protocol (rpc.rs) sends whole debugger operations as closures, run on the console's
+// thread in the same place, and listens for run_frame stopping at breakpoints.
+
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+
+#[cfg(feature = "server")]
+use super::console::Console;
+use super::console::{Frame, VideoSink};
+use super::debugger::BankedAddr;
+use super::error::Disconnected;
+use super::gamepad::{Button, ButtonState, InputEvent};
+
+pub(crate) enum Command {
+    Pause,
+    Resume,
+    Step,
+    Input(InputEvent),
+    Screenshot(Sender<Option<Box<[u32]>>>),
+    Subscribe(SyncSender<Box<[u32]>>),
+    ReadMemory { addr: u16, len: usize, reply: Sender<Vec<u8>> },
+    WriteMemory { addr: u16, bytes: Vec<u8> },
+    #[cfg(feature = "server")]
+    Debug(Box<dyn FnOnce(&mut Console) + Send>), // for rpc.rs and dap.rs
+    SubscribeStops(Sender<BankedAddr>),
+}
+
+// Console side of the channel, plus the last frame shown, for screenshots
+pub(crate) struct Remote {
+    pub commands: Receiver<Command>,
+    sender: Sender<Command>,
+    pub last_frame: Option<Box<[u32]>>,
+    pub subscribers: Vec<SyncSender<Box<[u32]>>>,
+    pub stop_subscribers: Vec<Sender<BankedAddr>>,
+    // Frames to advance while paused
+    pub steps: u32,
+}
+
+impl Remote {
+    pub fn new() -> Remote {
+        let (sender, commands) = channel();
+        Remote { commands, sender, last_frame: None, subscribers: Vec::new(), stop_subscribers: Vec::new(), steps: 0 }
+    }
+
+    pub fn handle(&self) -> ConsoleHandle {
+        ConsoleHandle { commands: self.sender.clone() }
+    }
+
+    // run_frame stopped at a breakpoint
+    pub fn stopped(&mut self, at: BankedAddr) {
+        self.stop_subscribers.retain(|subscriber| subscriber.send(at).is_ok());
+    }
+
+    pub fn take_step(&mut self) -> bool {
+        if self.steps == 0 {
+            return false;
+        }
+        self.steps -= 1;
+        true
+    }
+}
+
+// Keeps a copy of every frame on its way to the sink
+pub(crate) struct RemoteSink<'a> {
+    pub remote: &'a mut Remote,
+    pub video_sink: &'a mut dyn VideoSink,
+}
+
+impl<'a> VideoSink for RemoteSink<'a> {
+    fn frame_available(&mut self, frame: &Frame) {
+        let pixels = frame.to_packed();
+        // A subscriber still busy with the last frame misses this one
+        self.remote.subscribers.retain(|subscriber| {
+            !matches!(subscriber.try_send(pixels.clone()), Err(TrySendError::Disconnected(_)))
+        });
+        self.remote.last_frame = Some(pixels);
+        self.video_sink.frame_available(frame);
+    }
+}
+
+// Cheap to clone, one per thread that needs it
+#[derive(Clone)]
+pub struct ConsoleHandle {
+    commands: Sender<Command>,
+}
+
+impl ConsoleHandle {
+    fn send(&self, command: Command) -> Result<(), Disconnected> {
+        self.commands.send(command).map_err(|_| Disconnected)
+    }
+
+    pub fn pause(&self) -> Result<(), Disconnected> {
+        self.send(Command::Pause)
+    }
+
+    pub fn resume(&self) -> Result<(), Disconnected> {
+        self.send(Command::Resume)
+    }
+
+    // Frame advance, for a paused console: the next run_frame runs one frame anyway. Ignored
+    // while running.
+    pub fn step(&self) -> Result<(), Disconnected> {
+        self.send(Command::Step)
+    }
+
+    pub fn handle_event(&self, input_event: InputEvent) -> Result<(), Disconnected> {
+        self.send(Command::Input(input_event))
+    }
+
+    pub fn press(&self, button: Button) -> Result<(), Disconnected> {
+        self.handle_event(InputEvent::new(button, ButtonState::Down))
+    }
+
+    pub fn release(&self, button: Button) -> Result<(), Disconnected> {
+        self.handle_event(InputEvent::new(button, ButtonState::Up))
+    }
+
+    // The last frame the console showed, debug overlay included. None before the first frame,
+    // or before the first frame since the console handed out its first handle.
+    pub fn screenshot(&self) -> Result<Option<Box<[u32]>>, Disconnected> {
+        let (reply, frame) = channel();
+        self.send(Command::Screenshot(reply))?;
+        frame.recv().map_err(|_| Disconnected)
+    }
+
+    // len bytes starting at addr, as the CPU would read them. Wraps around at 0xFFFF.
+    pub fn read_memory(&self, addr: u16, len: usize) -> Result<Vec<u8>, Disconnected> {
+        let (reply, bytes) = channel();
+        self.send(Command::ReadMemory { addr, len, reply })?;
+        bytes.recv().map_err(|_| Disconnected)
+    }
+
+    // Writes bytes from addr on, as the CPU would. Wraps around at 0xFFFF.
+    pub fn write_memory(&self, addr: u16, bytes: Vec<u8>) -> Result<(), Disconnected> {
+        self.send(Command::WriteMemory { addr, bytes })
+    }
+
+    // Runs f on the console's thread, between frames, and returns what it gives back
+    #[cfg(feature = "server")]
+    pub(crate) fn with_console<R, F>(&self, f: F) -> Result<R, Disconnected>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Console) -> R + Send + 'static,
+    {
+        let (reply, result) = channel();
+        self.send(Command::Debug(Box::new(move |console| {
+            let _ = reply.send(f(console));
+        })))?;
+        result.recv().map_err(|_| Disconnected)
+    }
+
+    // Where run_frame stops at a breakpoint, from now on. Like frames, the console stops
+    // sending once the receiver is dropped.
+    pub fn stops(&self) -> Result<Receiver<BankedAddr>, Disconnected> {
+        let (sender, stops) = channel();
+        self.send(Command::SubscribeStops(sender))?;
+        Ok(stops)
+    }
+
+    // The frames the console shows from now on. The stream ends with the console, and the
+    // console stops sending once the receiver is dropped. At most one frame waits to be
+    // collected; the ones shown while it waits are dropped.
+    pub fn frames(&self) -> Result<Receiver<Box<[u32]>>, Disconnected> {
+        let (sender, frames) = sync_channel(1);
+        self.send(Command::Subscribe(sender))?;
+        Ok(frames)
+    }
+}
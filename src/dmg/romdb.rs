@@ -0,0 +1,189 @@
+// ROM identification.
+// No-Intro publishes DAT files listing every known good dump of every cartridge with its CRC32
+// and SHA-1. Given one (the XML kind, as DAT-o-MATIC hands them out), a ROM can be matched to
+// its canonical name, and dumps the DAT marks as bad can be warned about. No DAT ships with
+// gbrust; point the config's rom_database at one.
+
+use std::fs;
+use std::path::Path;
+
+use super::error::RomDbError;
+use super::patch::crc32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RomHashes {
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
+
+impl RomHashes {
+    pub fn of(rom: &[u8]) -> RomHashes {
+        RomHashes { size: rom.len(), crc32: crc32(rom), sha1: sha1(rom) }
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        self.sha1.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatEntry {
+    pub name: String, // the game's canonical name, e.g. "Tetris (World) (Rev 1)"
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub bad_dump: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RomDb {
+    entries: Vec<DatEntry>,
+}
+
+impl RomDb {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RomDb, RomDbError> {
+        RomDb::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(xml: &str) -> Result<RomDb, RomDbError> {
+        if !xml.contains("<datafile") {
+            return Err(RomDbError::Parse("not a No-Intro XML DAT".to_string()));
+        }
+        let mut entries = Vec::new();
+        let mut game = None;
+        for tag in xml.split('<').skip(1) {
+            let tag = tag.split('>').next().unwrap_or("");
+            if tag.starts_with("game ") || tag.starts_with("machine ") {
+                game = attribute(tag, "name");
+            } else if let Some(rom) = tag.strip_prefix("rom ") {
+                let missing = |what: &str| RomDbError::Parse(format!("rom without {}: <{}>", what, tag));
+                let hex = |key: &str| attribute(rom, key).map(|value| value.to_ascii_lowercase());
+                let crc = hex("crc").ok_or_else(|| missing("crc"))?;
+                entries.push(DatEntry {
+                    name: game.clone().or_else(|| attribute(rom, "name")).ok_or_else(|| missing("name"))?,
+                    size: attribute(rom, "size").and_then(|size| size.parse().ok())
+                        .ok_or_else(|| missing("size"))?,
+                    crc32: u32::from_str_radix(&crc, 16).map_err(|_| missing("valid crc"))?,
+                    sha1: hex("sha1").and_then(|sha1| parse_sha1(&sha1)),
+                    bad_dump: attribute(rom, "status").is_some_and(|status| status == "baddump"),
+                });
+            }
+        }
+        Ok(RomDb { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Matched by SHA-1 where the DAT has one, by size and CRC32 otherwise
+    pub fn lookup(&self, hashes: &RomHashes) -> Option<&DatEntry> {
+        self.entries.iter().find(|entry| match entry.sha1 {
+            Some(sha1) => sha1 == hashes.sha1,
+            None => entry.size == hashes.size && entry.crc32 == hashes.crc32,
+        })
+    }
+}
+
+// The unescaped value of key="..." in an XML tag
+fn attribute(tag: &str, key: &str) -> Option<String> {
+    let start = tag.find(&format!(" {}=\"", key))? + key.len() + 3;
+    let len = tag[start..].find('"')?;
+    Some(tag[start..start + len]
+        .replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'")
+        .replace("&amp;", "&"))
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut sha1 = [0; 20];
+    for (i, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(sha1)
+}
+
+pub fn sha1(bytes: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+    let mut message = bytes.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(bytes.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(h.iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifies_roms_from_a_dat() {
+        assert_eq!(RomHashes::of(b"abc").sha1_hex(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+
+        let good = RomHashes::of(b"good rom");
+        let bad = RomHashes::of(b"bad rom");
+        let dat = format!(r#"<?xml version="1.0"?>
+<datafile>
+    <header><name>Nintendo - Game Boy</name></header>
+    <game name="Good &amp; Proper (World)">
+        <description>Good &amp; Proper (World)</description>
+        <rom name="Good &amp; Proper (World).gb" size="8" crc="{:08X}" sha1="{}"/>
+    </game>
+    <game name="Broken (Japan)">
+        <rom name="Broken (Japan).gb" size="7" crc="{:08x}" status="baddump"/>
+    </game>
+</datafile>"#, good.crc32, good.sha1_hex().to_uppercase(), bad.crc32);
+
+        let db = RomDb::parse(&dat).unwrap();
+        assert_eq!(db.len(), 2);
+        let entry = db.lookup(&good).unwrap();
+        assert_eq!(entry.name, "Good & Proper (World)");
+        assert!(!entry.bad_dump);
+        assert!(db.lookup(&bad).unwrap().bad_dump);
+        assert!(db.lookup(&RomHashes::of(b"unknown")).is_none());
+        assert!(RomDb::parse("game ( name \"x\" )").is_err());
+    }
+}
@@ -0,0 +1,458 @@
+// Debug protocol for external front ends, needs the `server` feature.
+// JSON-RPC 2.0 over TCP, one message per line, so editor plugins (a VS Code debug adapter, say)
+// can drive the debugger of a running console through a ConsoleHandle (see remote.rs):
+//
+//     {"jsonrpc":"2.0","id":1,"method":"readMemory","params":{"addr":"c000","len":4}}
+//     {"jsonrpc":"2.0","id":1,"result":{"data":"00a0ff12"}}
+//
+// Methods, with their params:
+//
+//     pause, continue              run_frame stops or goes on running frames
+//     status                       {paused, breakpoint, frame}
+//     step, stepBack               one instruction, forward or back; result {pc}
+//     stepOver, stepOut            see debugger.rs; result {pc, reason}: done, breakpoint or limit
+//     registers                    a f b c d e h l sp pc as numbers, and ime
+//     readMemory {addr, len}       result {data} in hex; len defaults to 1
+//     writeMemory {addr, data}     data in hex
+//     disassemble {addr, count}    [{addr, bytes, text}]; from PC and 8 instructions by default
+//     setBreakpoint {addr}         or {condition}, irq, irq:48 or rst:38
+//     removeBreakpoint {addr}      or {condition}
+//     breakpoints                  {addrs, conditions}
+//     callStack                    [{target, returnTo, kind, name}], innermost first
+//
+// Addresses go both ways as strings in BankedAddr's syntax ("12:4abc", "c000"); plain numbers
+// are taken as addresses in any bank. Whenever running frames stops at a breakpoint, every
+// connection gets a notification: {"jsonrpc":"2.0","method":"stopped","params":{"pc":"00:0150"}}.
+// Operations run on the console's thread between frames, so each one sees a whole frame done.
+// The console tracks calls from the first connection on, for callStack.
+// A connection may sit idle for as long as it likes, but a message, once started, must arrive
+// within READ_TIMEOUT and stay under MAX_MESSAGE, or the connection is dropped.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::console::{Console, NoVideo};
+use super::debugger::{BankedAddr, BreakOn, CallKind, StepEnd};
+use super::json::Json;
+use super::remote::ConsoleHandle;
+
+// Error codes from the JSON-RPC spec, and one of ours for a console that went away
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const DISCONNECTED: i64 = -32000;
+
+// Enough for writeMemory of the whole address space in hex
+const MAX_MESSAGE: u64 = 0x40000;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct RpcServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl RpcServer {
+    // Starts listening on addr, as Server::bind does, with a thread per connection
+    pub fn bind<A: ToSocketAddrs>(addr: A, handle: ConsoleHandle) -> io::Result<RpcServer> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let acceptor = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!(target: "gbrust::rpc", "accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &handle) {
+                        debug!(target: "gbrust::rpc", "connection dropped: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(RpcServer { addr, stop, acceptor: Some(acceptor) })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        // The acceptor only looks at the flag when a connection comes in, so make one
+        self.stop.store(true, Ordering::SeqCst);
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let _ = TcpStream::connect(wake);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+fn serve(stream: TcpStream, handle: &ConsoleHandle) -> io::Result<()> {
+    let out = Arc::new(Mutex::new(stream.try_clone()?));
+    // Stop notifications come from a thread of their own, which ends at the first one after
+    // the connection closes, or with the console
+    if let Ok(stops) = handle.stops() {
+        let out = out.clone();
+        thread::spawn(move || {
+            for at in stops {
+                let params = Json::object(vec![("pc", Json::addr(at))]);
+                let notification = Json::object(vec![
+                    ("jsonrpc", Json::from("2.0")),
+                    ("method", Json::from("stopped")),
+                    ("params", params),
+                ]);
+                if send(&out, &notification).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    let _ = handle.with_console(|console| console.track_calls(true));
+
+    let served = answer(stream, &out, handle);
+    // Closes the connection now, rather than when the notification thread next wakes up
+    let _ = out.lock().unwrap().shutdown(Shutdown::Both);
+    served
+}
+
+// Replies to messages until the client hangs up
+fn answer(stream: TcpStream, out: &Mutex<TcpStream>, handle: &ConsoleHandle) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        reader.get_ref().set_read_timeout(None)?;
+        if reader.fill_buf()?.is_empty() {
+            break;
+        }
+        reader.get_ref().set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut line = String::new();
+        reader.by_ref().take(MAX_MESSAGE).read_line(&mut line)?;
+        if line.len() as u64 == MAX_MESSAGE && !line.ends_with('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message too long"));
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = respond(&line, handle) {
+            send(out, &response)?;
+        }
+    }
+    Ok(())
+}
+
+fn send(out: &Mutex<TcpStream>, message: &Json) -> io::Result<()> {
+    let mut out = out.lock().unwrap();
+    writeln!(out, "{}", message)?;
+    out.flush()
+}
+
+// The reply to one line, None for notifications, which get none
+fn respond(line: &str, handle: &ConsoleHandle) -> Option<Json> {
+    let (id, result) = match Json::parse(line) {
+        Err(()) => (Json::Null, Err(Failure::new(PARSE_ERROR, "parse error"))),
+        Ok(request) => {
+            let id = request.get("id").cloned();
+            let method = request.get("method").and_then(Json::as_str);
+            let result = match method {
+                Some(method) if request.get("jsonrpc").and_then(Json::as_str) == Some("2.0") => {
+                    let params = request.get("params").cloned().unwrap_or(Json::Object(BTreeMap::new()));
+                    call(method, &params, handle)
+                }
+                _ => Err(Failure::new(INVALID_REQUEST, "invalid request")),
+            };
+            match id {
+                Some(id) => (id, result),
+                None if method.is_some() => return None,
+                None => (Json::Null, result),
+            }
+        }
+    };
+    let mut response = vec![("jsonrpc", Json::from("2.0")), ("id", id)];
+    match result {
+        Ok(result) => response.push(("result", result)),
+        Err(failure) => response.push(("error", Json::object(vec![
+            ("code", Json::Number(failure.code as f64)),
+            ("message", Json::String(failure.message)),
+        ]))),
+    }
+    Some(Json::object(response))
+}
+
+struct Failure {
+    code: i64,
+    message: String,
+}
+
+impl Failure {
+    fn new(code: i64, message: &str) -> Failure {
+        Failure { code, message: message.to_string() }
+    }
+
+    fn params(message: &str) -> Failure {
+        Failure::new(INVALID_PARAMS, message)
+    }
+}
+
+// Runs f on the console's thread
+fn on_console<F>(handle: &ConsoleHandle, f: F) -> Result<Json, Failure>
+where
+    F: FnOnce(&mut Console) -> Json + Send + 'static,
+{
+    handle.with_console(f).map_err(|_| Failure::new(DISCONNECTED, "the console is gone"))
+}
+
+fn call(method: &str, params: &Json, handle: &ConsoleHandle) -> Result<Json, Failure> {
+    match method {
+        "pause" => on_console(handle, |console| {
+            console.pause();
+            Json::Null
+        }),
+        "continue" => on_console(handle, |console| {
+            console.resume();
+            Json::Null
+        }),
+        "status" => on_console(handle, |console| Json::object(vec![
+            ("paused", Json::Bool(console.is_paused())),
+            ("breakpoint", console.breakpoint_hit().map_or(Json::Null, Json::addr)),
+            ("frame", Json::Number(console.frame_count() as f64)),
+        ])),
+        "step" => on_console(handle, |console| {
+            console.advance_instruction(&mut NoVideo);
+            Json::object(vec![("pc", Json::addr(console.pc()))])
+        }),
+        "stepBack" => on_console(handle, |console| {
+            let undone = console.step_back();
+            Json::object(vec![("pc", Json::addr(console.pc())), ("undone", Json::Bool(undone))])
+        }),
+        "stepOver" | "stepOut" => {
+            let over = method == "stepOver";
+            on_console(handle, move |console| {
+                let end = if over { console.step_over(&mut NoVideo) } else { console.step_out(&mut NoVideo) };
+                let reason = match end {
+                    StepEnd::Done => "done",
+                    StepEnd::Breakpoint => "breakpoint",
+                    StepEnd::Limit => "limit",
+                };
+                Json::object(vec![("pc", Json::addr(console.pc())), ("reason", Json::from(reason))])
+            })
+        }
+        "registers" => on_console(handle, |console| {
+            let regs = console.registers();
+            let number = |val: u16| Json::Number(val as f64);
+            Json::object(vec![
+                ("a", number(regs.a as u16)), ("f", number(regs.f as u16)),
+                ("b", number(regs.b as u16)), ("c", number(regs.c as u16)),
+                ("d", number(regs.d as u16)), ("e", number(regs.e as u16)),
+                ("h", number(regs.h as u16)), ("l", number(regs.l as u16)),
+                ("sp", number(regs.sp)), ("pc", number(regs.pc)),
+                ("ime", Json::Bool(regs.ime)),
+            ])
+        }),
+        "readMemory" => {
+            let addr = addr_param(params)?.addr;
+            let len = number_param(params, "len", 1)?.min(0x10000);
+            let bytes = handle.read_memory(addr, len).map_err(|_| Failure::new(DISCONNECTED, "the console is gone"))?;
+            Ok(Json::object(vec![("data", Json::String(hex(&bytes)))]))
+        }
+        "writeMemory" => {
+            let addr = addr_param(params)?.addr;
+            let data = params.get("data").and_then(Json::as_str).and_then(unhex)
+                .ok_or_else(|| Failure::params("data must be hex bytes"))?;
+            handle.write_memory(addr, data).map_err(|_| Failure::new(DISCONNECTED, "the console is gone"))?;
+            Ok(Json::Null)
+        }
+        "disassemble" => {
+            let addr = match params.get("addr") {
+                Some(_) => Some(addr_param(params)?.addr),
+                None => None,
+            };
+            let count = number_param(params, "count", 8)?.min(0x1000);
+            on_console(handle, move |console| {
+                let addr = addr.unwrap_or(console.registers().pc);
+                Json::Array(console.disassemble(addr, count).into_iter().map(|instruction| Json::object(vec![
+                    ("addr", Json::addr(instruction.at)),
+                    ("bytes", Json::String(hex(&instruction.bytes))),
+                    ("text", Json::String(instruction.text)),
+                ])).collect())
+            })
+        }
+        "setBreakpoint" | "removeBreakpoint" => {
+            let set = method == "setBreakpoint";
+            match params.get("condition") {
+                Some(condition) => {
+                    let condition: BreakOn = condition.as_str().and_then(|condition| condition.parse().ok())
+                        .ok_or_else(|| Failure::params("condition must be irq, irq:48 or rst:38"))?;
+                    on_console(handle, move |console| {
+                        if set {
+                            console.add_break_condition(condition);
+                        } else {
+                            console.remove_break_condition(condition);
+                        }
+                        Json::Null
+                    })
+                }
+                None => {
+                    let addr = addr_param(params)?;
+                    on_console(handle, move |console| {
+                        if set {
+                            console.add_breakpoint(addr);
+                        } else {
+                            console.remove_breakpoint(addr);
+                        }
+                        Json::Null
+                    })
+                }
+            }
+        }
+        "breakpoints" => on_console(handle, |console| Json::object(vec![
+            ("addrs", Json::Array(console.breakpoints().iter().map(|&addr| Json::addr(addr)).collect())),
+            ("conditions", Json::Array(console.break_conditions().iter()
+                .map(|condition| Json::String(condition.to_string())).collect())),
+        ])),
+        "callStack" => on_console(handle, |console| {
+            Json::Array(console.call_stack().into_iter().map(|frame| {
+                let kind = match frame.kind {
+                    CallKind::Call => "call".to_string(),
+                    CallKind::Rst => "rst".to_string(),
+                    CallKind::Interrupt(interrupt) => format!("{:?}", interrupt).to_ascii_lowercase(),
+                };
+                Json::object(vec![
+                    ("target", Json::addr(frame.target)),
+                    ("returnTo", Json::addr(frame.return_to)),
+                    ("kind", Json::String(kind)),
+                    ("name", frame.name.map_or(Json::Null, Json::String)),
+                ])
+            }).collect())
+        }),
+        _ => Err(Failure::new(METHOD_NOT_FOUND, "method not found")),
+    }
+}
+
+fn addr_param(params: &Json) -> Result<BankedAddr, Failure> {
+    match params.get("addr") {
+        Some(Json::String(addr)) => addr.parse().map_err(|_| Failure::params("invalid addr")),
+        Some(&Json::Number(addr)) if (0.0..=65535.0).contains(&addr) && addr.fract() == 0.0 => {
+            Ok(BankedAddr::unbanked(addr as u16))
+        }
+        _ => Err(Failure::params("addr is needed")),
+    }
+}
+
+fn number_param(params: &Json, name: &str, default: usize) -> Result<usize, Failure> {
+    match params.get(name) {
+        None => Ok(default),
+        Some(&Json::Number(n)) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        Some(_) => Err(Failure::params(&format!("{} must be a whole number", name))),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|at| u8::from_str_radix(&text[at..at + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use super::super::cart::Cart;
+
+    struct Client {
+        lines: io::Lines<BufReader<TcpStream>>,
+        out: TcpStream,
+    }
+
+    impl Client {
+        fn next(&mut self) -> Json {
+            Json::parse(&self.lines.next().unwrap().unwrap()).unwrap()
+        }
+
+        fn call(&mut self, request: &str) -> Json {
+            writeln!(self.out, "{}", request).unwrap();
+            self.next()
+        }
+    }
+
+    #[test]
+    fn drives_the_debugger() {
+        let rom = fs::read("tetris.gb").unwrap();
+        let mut console = Console::new(Cart::new(rom.into_boxed_slice(), None).unwrap());
+        let server = RpcServer::bind("127.0.0.1:0", console.handle()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let emulation = thread::spawn(move || {
+            while !stop_flag.load(Ordering::SeqCst) {
+                console.run_frame(&mut NoVideo);
+            }
+        });
+
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut client = Client { lines: BufReader::new(stream.try_clone().unwrap()).lines(), out: stream };
+        let mut call = |request: &str| client.call(request);
+        let result = |response: Json| response.get("result").cloned().unwrap();
+
+        let read = call(r#"{"jsonrpc":"2.0","id":1,"method":"readMemory","params":{"addr":"0104","len":2}}"#);
+        assert_eq!(read.get("id"), Some(&Json::Number(1.0)));
+        assert_eq!(result(read).get("data"), Some(&Json::from("ceed")));
+
+        // Paused at the breakpoint, with nothing else to come
+        call(r#"{"jsonrpc":"2.0","id":2,"method":"setBreakpoint","params":{"condition":"irq:40"}}"#);
+        let stopped = client.next();
+        assert_eq!(stopped.get("method"), Some(&Json::from("stopped")));
+        assert_eq!(stopped.get("params").and_then(|params| params.get("pc")), Some(&Json::from("00:0040")));
+        let mut call = |request: &str| client.call(request);
+        let status = result(call(r#"{"jsonrpc":"2.0","id":3,"method":"status"}"#));
+        assert_eq!(status.get("paused"), Some(&Json::Bool(true)));
+
+        let step = call(r#"{"jsonrpc":"2.0","id":4,"method":"step"}"#);
+        assert_eq!(result(step).get("pc"), Some(&Json::from("00:017e")));
+        let disasm = result(call(r#"{"jsonrpc":"2.0","id":5,"method":"disassemble","params":{"count":1}}"#));
+        assert_eq!(disasm, Json::Array(vec![Json::object(vec![
+            ("addr", Json::from("00:017e")), ("bytes", Json::from("f5")), ("text", Json::from("push af")),
+        ])]));
+        let calls = result(call(r#"{"jsonrpc":"2.0","id":6,"method":"callStack"}"#));
+        assert!(matches!(calls, Json::Array(ref frames) if frames[0].get("kind") == Some(&Json::from("vblank"))));
+
+        let error = call(r#"{"jsonrpc":"2.0","id":7,"method":"explode"}"#);
+        assert_eq!(error.get("error").and_then(|error| error.get("code")), Some(&Json::Number(-32601.0)));
+        let error = call(r#"{"jsonrpc":"2.0","id":8,"method":"readMemory","params":{"addr":"zz"}}"#);
+        assert_eq!(error.get("error").and_then(|error| error.get("code")), Some(&Json::Number(-32602.0)));
+        assert!(call("{oops").get("error").is_some());
+
+        // A message that never ends is cut off unanswered
+        let mut endless = TcpStream::connect(server.local_addr()).unwrap();
+        let _ = endless.write_all("a".repeat(2 * MAX_MESSAGE as usize).as_bytes());
+        let mut response = Vec::new();
+        let _ = endless.read_to_end(&mut response);
+        assert!(response.is_empty());
+
+        stop.store(true, Ordering::SeqCst);
+        emulation.join().unwrap();
+    }
+}
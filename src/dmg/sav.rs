@@ -0,0 +1,185 @@
+// Battery saves (.sav), as other emulators and flashcarts write them: the cartridge RAM, then
+// for MBC3 games with a clock the RTC registers in the footer BGB and VBA-M use:
+//
+//     5 x u32   sec min hrs days_lo days_hi, as they are running
+//     5 x u32   the same, as last latched
+//     u64/u32   unix time the save was written, 8 bytes (48 byte footer) or 4 (44 byte footer)
+//
+// all little endian. RAM always comes in multiples of 512 bytes, so the footer is told apart by
+// the length left over. The time is when the save was written, and the clock is run on from it
+// when the save loads, see mbc3.rs.
+// Saves are written to a .tmp next to the .sav and renamed over it, so a crash or a full disk
+// mid-write leaves the old save as it was rather than half of the new one.
+
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::error::SaveFileError;
+
+// The RTC registers of MBC3, see mbc3.rs
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub sec: u8,
+    pub min: u8,
+    pub hrs: u8,
+    pub days_lo: u8,
+    pub days_hi: u8, // bit 0: msb of day counter, bit 6: halt, bit 7: day counter overflow
+}
+
+impl RtcRegisters {
+    fn to_bytes(self) -> Vec<u8> {
+        [self.sec, self.min, self.hrs, self.days_lo, self.days_hi].iter()
+            .flat_map(|&reg| (reg as u32).to_le_bytes())
+            .collect()
+    }
+
+    // 20 bytes, 5 x u32
+    pub(crate) fn from_bytes(bytes: &[u8]) -> RtcRegisters {
+        let reg = |index: usize| bytes[index * 4];
+        RtcRegisters { sec: reg(0), min: reg(1), hrs: reg(2), days_lo: reg(3), days_hi: reg(4) }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Rtc {
+    pub current: RtcRegisters,
+    pub latched: RtcRegisters,
+    pub timestamp: u64, // unix time
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RtcFooter {
+    Bytes48, // BGB and current VBA-M, 64 bit time
+    Bytes44, // older VBA-M, 32 bit time
+}
+
+impl RtcFooter {
+    fn len(self) -> usize {
+        match self {
+            RtcFooter::Bytes48 => 48,
+            RtcFooter::Bytes44 => 44,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveFile {
+    pub ram: Box<[u8]>,
+    pub rtc: Option<Rtc>,
+}
+
+impl SaveFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SaveFile, SaveFileError> {
+        SaveFile::parse(&fs::read(path)?)
+    }
+
+    // The clock is written with footer if there is one
+    pub fn save<P: AsRef<Path>>(&self, path: P, footer: RtcFooter) -> Result<(), SaveFileError> {
+        let path = path.as_ref();
+        let temp_path = temp_path(path);
+        let written = fs::File::create(&temp_path).and_then(|mut file| {
+            file.write_all(&self.to_bytes(footer))?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&temp_path, path)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<SaveFile, SaveFileError> {
+        let footer = match bytes.len() % 512 {
+            0 => None,
+            44 => Some(RtcFooter::Bytes44),
+            48 => Some(RtcFooter::Bytes48),
+            _ => return Err(SaveFileError::BadLength(bytes.len())),
+        };
+        let ram_len = bytes.len() - footer.map_or(0, RtcFooter::len);
+        let (ram, footer_bytes) = bytes.split_at(ram_len);
+        let rtc = footer.map(|footer| {
+            let timestamp = match footer {
+                RtcFooter::Bytes48 => u64::from_le_bytes(footer_bytes[40..48].try_into().unwrap()),
+                RtcFooter::Bytes44 => u32::from_le_bytes(footer_bytes[40..44].try_into().unwrap()) as u64,
+            };
+            Rtc {
+                current: RtcRegisters::from_bytes(&footer_bytes[0..20]),
+                latched: RtcRegisters::from_bytes(&footer_bytes[20..40]),
+                timestamp,
+            }
+        });
+        Ok(SaveFile { ram: ram.into(), rtc })
+    }
+
+    pub fn to_bytes(&self, footer: RtcFooter) -> Vec<u8> {
+        let mut bytes = self.ram.to_vec();
+        if let Some(rtc) = self.rtc {
+            bytes.extend(rtc.current.to_bytes());
+            bytes.extend(rtc.latched.to_bytes());
+            match footer {
+                RtcFooter::Bytes48 => bytes.extend(rtc.timestamp.to_le_bytes()),
+                RtcFooter::Bytes44 => bytes.extend((rtc.timestamp as u32).to_le_bytes()),
+            }
+        }
+        bytes
+    }
+}
+
+// Where save writes before renaming, e.g. pokemon.sav.tmp
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+// For stamping exported clocks
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_writes_both_footers() {
+        let rtc = Rtc {
+            current: RtcRegisters { sec: 12, min: 34, hrs: 5, days_lo: 0x2A, days_hi: 0x41 },
+            latched: RtcRegisters { sec: 10, ..RtcRegisters::default() },
+            timestamp: 1_700_000_000,
+        };
+        let save = SaveFile { ram: vec![0xAB; 0x8000].into(), rtc: Some(rtc) };
+        for &footer in &[RtcFooter::Bytes48, RtcFooter::Bytes44] {
+            let bytes = save.to_bytes(footer);
+            assert_eq!(bytes.len(), 0x8000 + footer.len());
+            assert_eq!(&bytes[0x8000..0x8008], &[12, 0, 0, 0, 34, 0, 0, 0]);
+            assert_eq!(SaveFile::parse(&bytes).unwrap(), save);
+        }
+
+        let plain = SaveFile { ram: vec![1; 0x2000].into(), rtc: None };
+        assert_eq!(SaveFile::parse(&plain.to_bytes(RtcFooter::Bytes48)).unwrap(), plain);
+        assert!(matches!(SaveFile::parse(&[0; 0x2001]), Err(SaveFileError::BadLength(0x2001))));
+    }
+
+    #[test]
+    fn saves_replace_the_old_file_whole() {
+        let path = std::env::temp_dir().join(format!("gbrust-sav-{}.sav", std::process::id()));
+        fs::write(&path, vec![0x11; 0x2000]).unwrap();
+        let save = SaveFile { ram: vec![0x22; 0x2000].into(), rtc: None };
+        save.save(&path, RtcFooter::Bytes48).unwrap();
+        assert_eq!(SaveFile::load(&path).unwrap(), save);
+        assert!(!temp_path(&path).exists());
+
+        // A save that cannot be written, here because a directory is in the way, leaves the old one
+        fs::create_dir(temp_path(&path)).unwrap();
+        let failed = SaveFile { ram: vec![0x33; 0x2000].into(), rtc: None };
+        assert!(failed.save(&path, RtcFooter::Bytes48).is_err());
+        assert_eq!(SaveFile::load(&path).unwrap(), save);
+        fs::remove_dir(temp_path(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+// Upscaling on the CPU.
+// For frontends that can only copy pixels (terminals, simple blitters), ScaleSink sits in front
+// of the video sink and hands it a bigger Frame:
+//
+//     let mut sink = ScaleSink::new(Scaler::Scale2x, &mut window_sink);
+//     console.run_frame(&mut sink);
+//
+// Nearest repeats every pixel n times each way. Fit goes to any size, so factors like 2.5x work,
+// at the price of uneven pixels. Scale2x (EPX) rounds off diagonal edges without adding colors.
+// LcdGrid draws every pixel as an n x n block with a darker right and bottom edge, like the gaps
+// between pixels on the real LCD.
+
+use super::console::{Frame, VideoSink};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Scaler {
+    Nearest(usize),
+    Fit { width: usize, height: usize },
+    Scale2x,
+    LcdGrid(usize),
+}
+
+impl Scaler {
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        match *self {
+            Scaler::Nearest(n) | Scaler::LcdGrid(n) => (width * n, height * n),
+            Scaler::Fit { width, height } => (width, height),
+            Scaler::Scale2x => (width * 2, height * 2),
+        }
+    }
+
+    // Scales frame into out, which is resized to fit. Returns the size of the result.
+    pub fn scale(&self, frame: &Frame, out: &mut Vec<u32>) -> (usize, usize) {
+        let (width, height) = self.output_size(frame.width, frame.height);
+        out.clear();
+        out.resize(width * height, 0);
+        match *self {
+            Scaler::Nearest(_) | Scaler::Fit { .. } => {
+                for y in 0..height {
+                    let row = frame.row(y * frame.height / height);
+                    for x in 0..width {
+                        out[y * width + x] = row[x * frame.width / width];
+                    }
+                }
+            }
+            Scaler::Scale2x => scale2x(frame, out),
+            Scaler::LcdGrid(n) => {
+                for y in 0..height {
+                    let row = frame.row(y / n);
+                    for x in 0..width {
+                        let pixel = row[x / n];
+                        let gap = n > 1 && (x % n == n - 1 || y % n == n - 1);
+                        out[y * width + x] = if gap { darken(pixel) } else { pixel };
+                    }
+                }
+            }
+        }
+        (width, height)
+    }
+}
+
+// Three quarters of the brightness, alpha kept
+fn darken(pixel: u32) -> u32 {
+    let channel = |shift: u32| ((pixel >> shift & 0xFF) * 3 / 4) << shift;
+    (pixel & 0xFF00_0000) | channel(16) | channel(8) | channel(0)
+}
+
+// Every pixel P becomes 4. With A above, B right, C left and D below, the top left one is A if
+// C == A and C != D and A != B, otherwise P, and likewise for the other corners. Pixels past the
+// edges count as the edge.
+fn scale2x(frame: &Frame, out: &mut [u32]) {
+    let (width, height) = (frame.width, frame.height);
+    for y in 0..height {
+        for x in 0..width {
+            let p = frame.pixel(x, y);
+            let a = frame.pixel(x, y.saturating_sub(1));
+            let b = frame.pixel((x + 1).min(width - 1), y);
+            let c = frame.pixel(x.saturating_sub(1), y);
+            let d = frame.pixel(x, (y + 1).min(height - 1));
+
+            let (mut e0, mut e1, mut e2, mut e3) = (p, p, p, p);
+            if c == a && c != d && a != b {
+                e0 = a;
+            }
+            if a == b && a != c && b != d {
+                e1 = b;
+            }
+            if d == c && d != b && c != a {
+                e2 = c;
+            }
+            if b == d && b != a && d != c {
+                e3 = d;
+            }
+
+            let top = 2 * y * 2 * width + 2 * x;
+            out[top] = e0;
+            out[top + 1] = e1;
+            out[top + 2 * width] = e2;
+            out[top + 2 * width + 1] = e3;
+        }
+    }
+}
+
+// Scales every frame on its way to the sink. Keep it around between frames, it reuses its
+// buffer.
+pub struct ScaleSink<'a> {
+    scaler: Scaler,
+    buffer: Vec<u32>,
+    video_sink: &'a mut dyn VideoSink,
+}
+
+impl<'a> ScaleSink<'a> {
+    pub fn new(scaler: Scaler, video_sink: &'a mut dyn VideoSink) -> Self {
+        ScaleSink {
+            scaler,
+            buffer: Vec::new(),
+            video_sink,
+        }
+    }
+
+    pub fn set_scaler(&mut self, scaler: Scaler) {
+        self.scaler = scaler;
+    }
+}
+
+impl<'a> VideoSink for ScaleSink<'a> {
+    fn frame_available(&mut self, frame: &Frame) {
+        let (width, height) = self.scaler.scale(frame, &mut self.buffer);
+        self.video_sink.frame_available(&Frame::new(width, height, width, &self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scaled(scaler: Scaler, frame: &Frame) -> Vec<u32> {
+        let mut out = Vec::new();
+        scaler.scale(frame, &mut out);
+        out
+    }
+
+    #[test]
+    fn nearest_and_fit() {
+        let pixels = [1, 2, 3, 4];
+        let frame = Frame::new(2, 2, 2, &pixels);
+        assert_eq!(scaled(Scaler::Nearest(2), &frame), [1, 1, 2, 2, 1, 1, 2, 2, 3, 3, 4, 4, 3, 3, 4, 4]);
+        assert_eq!(scaled(Scaler::Fit { width: 3, height: 1 }, &frame), [1, 1, 2]);
+    }
+
+    #[test]
+    fn scale2x_rounds_diagonals() {
+        // A diagonal line of 1s
+        let pixels = [1, 0, 0, 1];
+        let frame = Frame::new(2, 2, 2, &pixels);
+        assert_eq!(scaled(Scaler::Scale2x, &frame), [1, 1, 0, 0, 1, 0, 1, 0, 0, 1, 0, 1, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn lcd_grid_darkens_gaps() {
+        let pixels = [0xFF40_8000];
+        let frame = Frame::new(1, 1, 1, &pixels);
+        assert_eq!(scaled(Scaler::LcdGrid(2), &frame), [0xFF40_8000, 0xFF30_6000, 0xFF30_6000, 0xFF30_6000]);
+    }
+}
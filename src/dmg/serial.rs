@@ -0,0 +1,376 @@
+// Serial port (link cable).
+// FF01 - SB - the byte to send, replaced bit by bit with the byte received
+// FF02 - SC - bit 7: transfer requested / in progress, bit 1: clock speed (CGB mode only, 1 =
+//             fast), bit 0: clock source (1 = this Game Boy)
+// With its own clock the Game Boy shifts a bit every 512 cycles (8192 Hz), so a byte takes 4096
+// cycles, and whatever SerialDevice is plugged in (see Console::attach_serial) sends one back.
+// With nothing plugged in it receives 0xFF, like a Game Boy with no cable. A CGB game can set the
+// fast clock, a bit every 16 cycles (262144 Hz); everywhere else the bit cannot be written and
+// reads as 1, which is how games tell which they are linked from.
+// The speed is only the clock's: on the external clock the other end sets the pace, so a DMG
+// keeps up with a CGB on the fast clock, and a CGB that set the bit while waiting for a DMG is
+// still clocked at the DMG's speed.
+// With the external clock the other end (another Game Boy, or a peripheral such as the 4 player
+// adapter, see four_player.rs) decides when the byte moves, through exchange.
+// Test ROMs such as Blargg's print their results through the port too, which record_output
+// collects.
+// See PanDocs: https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::console::Console;
+use super::interrupts::{Interrupt, InterruptController};
+use super::state::{StateError, StateReader, StateWriter};
+
+const BYTE_CYCLES: u32 = 8 * 512;
+const FAST_BYTE_CYCLES: u32 = 8 * 16;
+const TRANSFER: u8 = 0x80;
+const FAST_CLOCK: u8 = 0x02;
+const INTERNAL_CLOCK: u8 = 0x01;
+// How long StreamLink::tcp waits for the other end to answer a byte before unplugging
+pub const LINK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// What is plugged into the link port
+pub trait SerialDevice: Send {
+    // The Game Boy sent byte on its own clock, and gets back what this returns
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    // For devices with a clock of their own, such as barcode readers (see peripheral.rs): called
+    // as the console runs, with the cycles since the last call and whether the game is waiting
+    // for a byte on the external clock. A byte returned is clocked in, and what the game sent in
+    // its place goes to received. A byte clocked while the game is not waiting is lost.
+    fn tick(&mut self, _cycles: u32, _waiting: bool) -> Option<u8> {
+        None
+    }
+
+    fn received(&mut self, _byte: u8) {}
+}
+
+impl fmt::Debug for dyn SerialDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SerialDevice")
+    }
+}
+
+// Another console in the same process, run by whoever else holds it, on the external clock.
+// Plug them together one way only, the console whose game drives the clock getting the other:
+// plugged both ways, two transfers at once would each wait for the other's lock.
+impl SerialDevice for Arc<Mutex<Console>> {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut other = self.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        other.serial_exchange(byte).unwrap_or(0xFF)
+    }
+}
+
+// A link over any byte stream, usually a TcpStream: every byte sent is answered with one from
+// the other end. The first error unplugs it, and it receives 0xFF from then on. The console
+// waits for each answer, so over TCP use StreamLink::tcp, which counts an answer that takes
+// longer than LINK_TIMEOUT as an error.
+pub struct StreamLink<S: Read + Write + Send> {
+    stream: Option<S>,
+}
+
+impl<S: Read + Write + Send> StreamLink<S> {
+    pub fn new(stream: S) -> StreamLink<S> {
+        StreamLink { stream: Some(stream) }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+}
+
+impl StreamLink<TcpStream> {
+    pub fn tcp(stream: TcpStream) -> io::Result<StreamLink<TcpStream>> {
+        stream.set_read_timeout(Some(LINK_TIMEOUT))?;
+        Ok(StreamLink::new(stream))
+    }
+}
+
+impl<S: Read + Write + Send> SerialDevice for StreamLink<S> {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => return 0xFF,
+        };
+        let mut received = [0];
+        let exchanged = stream.write_all(&[byte])
+            .and_then(|_| stream.flush())
+            .and_then(|_| stream.read_exact(&mut received));
+        match exchanged {
+            Ok(()) => received[0],
+            Err(e) => {
+                warn!(target: "gbrust::serial", "link cable disconnected: {}", e);
+                self.stream = None;
+                0xFF
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Serial {
+    data: u8,
+    control: u8,
+    cycles: u32, // into the transfer in progress, internal clock only
+    output: Option<Vec<u8>>, // bytes sent with the internal clock, as they start, while recording
+    device: Option<Box<dyn SerialDevice>>, // not part of save states, like the cable
+    cgb_mode: bool, // the fast clock can be chosen
+    transferred: Option<(u8, u8)>, // sent and received, by the last transfer, for the event log
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial::default()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+        state.write_u32(self.cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()?;
+        self.cycles = state.read_u32()?;
+        Ok(())
+    }
+
+    // Registers back to power on, still recording if it was and with the device still plugged in
+    pub fn reset(&mut self) {
+        *self = Serial {
+            output: self.output.take(),
+            device: self.device.take(),
+            cgb_mode: self.cgb_mode,
+            ..Serial::new()
+        };
+    }
+
+    // A CGB running a game made for it, see Ppu::set_cgb_mode
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+        if !enabled {
+            self.control &= !FAST_CLOCK;
+        }
+    }
+
+    fn byte_cycles(&self) -> u32 {
+        if self.control & FAST_CLOCK != 0 { FAST_BYTE_CYCLES } else { BYTE_CYCLES }
+    }
+
+    fn transferring_on(&self, clock: u8) -> bool {
+        self.control & (TRANSFER | INTERNAL_CLOCK) == TRANSFER | clock
+    }
+
+    // Plugs device in, returning the one it replaces
+    pub fn attach(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        self.device.replace(device)
+    }
+
+    pub fn detach(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.device.is_some()
+    }
+
+    pub fn record_output(&mut self, enabled: bool) {
+        self.output = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        match self.output {
+            Some(ref mut output) => mem::take(output),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff01 => self.data,
+            // Unused bits read as 1
+            _ if self.cgb_mode => self.control | 0x7C,
+            _ => self.control | 0x7E,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff01 => self.data = val,
+            _ => {
+                let speed = if self.cgb_mode { FAST_CLOCK } else { 0 };
+                self.control = val & (TRANSFER | speed | INTERNAL_CLOCK);
+                self.cycles = 0;
+                if self.transferring_on(INTERNAL_CLOCK) {
+                    if let Some(ref mut output) = self.output {
+                        output.push(self.data);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn cycle_flush(&mut self, cycle_count: u32, interrupts: &mut InterruptController) {
+        if !self.transferring_on(INTERNAL_CLOCK) {
+            self.device_clock(cycle_count, interrupts);
+            return;
+        }
+        self.cycles += cycle_count;
+        if self.cycles >= self.byte_cycles() {
+            let data = self.data;
+            let received = self.device.as_mut().map_or(0xFF, |device| device.transfer(data));
+            self.finish(received, interrupts);
+        }
+    }
+
+    // Lets a device with its own clock move a byte
+    fn device_clock(&mut self, cycle_count: u32, interrupts: &mut InterruptController) {
+        let waiting = self.transferring_on(0);
+        let clocked = match self.device {
+            Some(ref mut device) => device.tick(cycle_count, waiting),
+            None => return,
+        };
+        let sent = clocked.and_then(|byte| self.exchange(byte, interrupts));
+        if let (Some(sent), Some(device)) = (sent, self.device.as_mut()) {
+            device.received(sent);
+        }
+    }
+
+    // The other end clocks a byte through. Returns the byte sent, or None if no transfer with
+    // the external clock was requested, in which case nothing happens here.
+    pub fn exchange(&mut self, byte: u8, interrupts: &mut InterruptController) -> Option<u8> {
+        if !self.transferring_on(0) {
+            return None;
+        }
+        let sent = self.data;
+        self.finish(byte, interrupts);
+        Some(sent)
+    }
+
+    // The byte sent and the byte received by the transfer that finished last, once
+    pub fn take_transfer(&mut self) -> Option<(u8, u8)> {
+        self.transferred.take()
+    }
+
+    fn finish(&mut self, received: u8, interrupts: &mut InterruptController) {
+        self.transferred = Some((self.data, received));
+        self.data = received;
+        self.control &= !TRANSFER;
+        self.cycles = 0;
+        interrupts.request(Interrupt::Serial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfers_with_either_clock() {
+        let mut interrupts = InterruptController::new();
+        let mut serial = Serial::new();
+        serial.write(0xff01, 0x42);
+        serial.write(0xff02, 0x81);
+        serial.cycle_flush(BYTE_CYCLES - 4, &mut interrupts);
+        assert_eq!(serial.read(0xff02), 0xFF);
+        serial.cycle_flush(4, &mut interrupts);
+        assert_eq!((serial.read(0xff01), serial.read(0xff02)), (0xFF, 0x7F));
+        assert_eq!(interrupts.flags(), Interrupt::Serial.mask());
+
+        // The other end waits for the game to ask
+        assert_eq!(serial.exchange(0x10, &mut interrupts), None);
+        serial.write(0xff01, 0x42);
+        serial.write(0xff02, 0x80);
+        serial.cycle_flush(BYTE_CYCLES, &mut interrupts);
+        assert_eq!(serial.exchange(0x10, &mut interrupts), Some(0x42));
+        assert_eq!(serial.read(0xff01), 0x10);
+    }
+
+    struct Increment;
+
+    impl SerialDevice for Increment {
+        fn transfer(&mut self, byte: u8) -> u8 {
+            byte.wrapping_add(1)
+        }
+    }
+
+    fn transfer(serial: &mut Serial, byte: u8) -> u8 {
+        let mut interrupts = InterruptController::new();
+        serial.write(0xff01, byte);
+        serial.write(0xff02, 0x81);
+        serial.cycle_flush(BYTE_CYCLES, &mut interrupts);
+        serial.read(0xff01)
+    }
+
+    #[test]
+    fn cgb_games_can_clock_fast() {
+        let mut interrupts = InterruptController::new();
+        let mut serial = Serial::new();
+        serial.write(0xff02, 0x83);
+        assert_eq!(serial.read(0xff02), 0xFF); // the DMG has no fast clock, and says so
+        serial.cycle_flush(FAST_BYTE_CYCLES, &mut interrupts);
+        assert_eq!(serial.read(0xff02) & TRANSFER, TRANSFER);
+
+        serial.set_cgb_mode(true);
+        serial.write(0xff02, 0x81);
+        assert_eq!(serial.read(0xff02), 0xFD);
+        serial.write(0xff02, 0x83);
+        serial.cycle_flush(FAST_BYTE_CYCLES, &mut interrupts);
+        assert_eq!(serial.read(0xff02), 0x7F);
+
+        // Waiting on the other end's clock with the fast bit set still takes its bytes
+        serial.write(0xff01, 0x42);
+        serial.write(0xff02, 0x82);
+        assert_eq!(serial.exchange(0x10, &mut interrupts), Some(0x42));
+        assert_eq!(serial.read(0xff02), 0x7E);
+    }
+
+    #[test]
+    fn devices_plug_in_and_out() {
+        let mut serial = Serial::new();
+        assert!(serial.attach(Box::new(Increment)).is_none());
+        assert_eq!(transfer(&mut serial, 0x41), 0x42);
+        serial.reset();
+        assert_eq!(transfer(&mut serial, 0x41), 0x42);
+        assert!(serial.detach().is_some());
+        assert_eq!(transfer(&mut serial, 0x41), 0xFF);
+    }
+
+    #[test]
+    fn links_over_tcp_until_the_other_end_hangs_up() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let other_end = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            stream.write_all(&[!byte[0]]).unwrap();
+        });
+
+        let mut serial = Serial::new();
+        serial.attach(Box::new(StreamLink::tcp(TcpStream::connect(addr).unwrap()).unwrap()));
+        assert_eq!(transfer(&mut serial, 0x0F), 0xF0);
+        other_end.join().unwrap();
+        assert_eq!(transfer(&mut serial, 0x0F), 0xFF);
+    }
+
+    #[test]
+    fn gives_up_on_an_end_that_stops_answering() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut link = StreamLink::tcp(TcpStream::connect(listener.local_addr().unwrap()).unwrap()).unwrap();
+        let (_silent, _) = listener.accept().unwrap();
+        assert_eq!(link.transfer(0x0F), 0xFF);
+        assert!(!link.is_connected());
+    }
+}
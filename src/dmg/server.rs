@@ -0,0 +1,478 @@
+// Remote control server, needs the `server` feature.
+// Plain HTTP over a ConsoleHandle (see remote.rs), so browser tooling can inspect and drive a
+// running emulator. One request per connection.
+//
+//     GET  /screenshot                 PNG of the last frame
+//     GET  /memory?addr=c000&len=16    raw bytes (len defaults to 1)
+//     POST /memory?addr=c000           writes the request body
+//     POST /press?button=start         and /release: a, b, start, select, up, down, left, right
+//     POST /pause, /resume, /step      step advances one frame while paused
+//     GET  /frames                     WebSocket, one binary message per frame, 160x144 RGBA
+//
+// Addresses are hex, with or without 0x. Errors come back as 400 (bad request), 403 (from a web
+// page whose origin is not allowed), 404 (no such endpoint, or no frame yet) and 503 (the
+// console is gone).
+//
+// Any web page open in a browser can send requests to localhost, so requests that carry an
+// Origin header are refused unless Server::bind_allowing was given that origin. Those get CORS
+// headers, and an answer to their preflight OPTIONS request. Tools outside the browser send no
+// Origin and are always served.
+// The frame stream skips frames when the client cannot keep up, and ends with the console.
+// Requests must arrive whole within READ_TIMEOUT, and heads (the request line and headers) stay
+// under MAX_HEAD, so a client that stops sending, or never ends a line, only ties up its thread
+// for a while.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::error::Disconnected;
+use super::gamepad::Button;
+use super::gfx::encode_png;
+use super::ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use super::remote::ConsoleHandle;
+use super::romdb::sha1;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Nothing we serve needs more: the whole address space, or a few dozen headers
+const MAX_BODY: usize = 0x10000;
+const MAX_HEADERS: usize = 64;
+const MAX_HEAD: u64 = 0x4000;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Server {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    // Starts serving on addr, e.g. "127.0.0.1:8080" (port 0 picks a free one). Every connection
+    // gets its own thread, the server stops accepting when dropped.
+    pub fn bind<A: ToSocketAddrs>(addr: A, handle: ConsoleHandle) -> io::Result<Server> {
+        Server::bind_allowing(addr, handle, Vec::new())
+    }
+
+    // As bind, also serving web pages from origins, e.g. "http://localhost:3000"
+    pub fn bind_allowing<A: ToSocketAddrs>(addr: A, handle: ConsoleHandle, origins: Vec<String>)
+                                           -> io::Result<Server> {
+        let origins: Arc<[String]> = origins.into();
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let acceptor = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!(target: "gbrust::server", "accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let (handle, origins) = (handle.clone(), origins.clone());
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &handle, &origins) {
+                        debug!(target: "gbrust::server", "connection dropped: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Server { addr, stop, acceptor: Some(acceptor) })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // The acceptor only looks at the flag when a connection comes in, so make one
+        self.stop.store(true, Ordering::SeqCst);
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let _ = TcpStream::connect(wake);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn read<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+        let mut head = reader.by_ref().take(MAX_HEAD);
+        let mut line = String::new();
+        read_head_line(&mut head, &mut line)?;
+        let mut parts = line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+            _ => return Err(invalid("malformed request line")),
+        };
+        let (path, query) = match target.find('?') {
+            Some(at) => (target[..at].to_string(), &target[at + 1..]),
+            None => (target.clone(), ""),
+        };
+        let query = query.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.find('=') {
+                Some(at) => (pair[..at].to_string(), pair[at + 1..].to_string()),
+                None => (pair.to_string(), String::new()),
+            })
+            .collect();
+
+        let mut headers = Vec::new();
+        loop {
+            read_head_line(&mut head, &mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                return Err(invalid("too many headers"));
+            }
+            if let Some(at) = header.find(':') {
+                headers.push((header[..at].trim().to_ascii_lowercase(), header[at + 1..].trim().to_string()));
+            }
+        }
+
+        let mut request = Request { method, path, query, headers, body: Vec::new() };
+        let length = match request.header("content-length") {
+            Some(length) => length.parse::<usize>().map_err(|_| invalid("bad content-length"))?,
+            None => 0,
+        };
+        if length > MAX_BODY {
+            return Err(invalid("body too large"));
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body)?;
+        Ok(request)
+    }
+
+    // name must be lowercase
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn param(&self, name: &str) -> Result<&str, Failure> {
+        self.query.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .ok_or_else(|| Failure::BadRequest(format!("missing {}", name)))
+    }
+
+    fn addr(&self) -> Result<u16, Failure> {
+        let addr = self.param("addr")?;
+        let hex = addr.trim_start_matches("0x").trim_start_matches("0X");
+        u16::from_str_radix(hex, 16).map_err(|_| Failure::BadRequest(format!("bad address {}", addr)))
+    }
+
+    fn button(&self) -> Result<Button, Failure> {
+        let name = self.param("button")?;
+        parse_button(name).ok_or_else(|| Failure::BadRequest(format!("no such button {}", name)))
+    }
+
+    fn wants_websocket(&self) -> bool {
+        self.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+// Into line, which must end before the head's limit does
+fn read_head_line<R: BufRead>(head: &mut R, line: &mut String) -> io::Result<()> {
+    line.clear();
+    head.read_line(line)?;
+    if !line.ends_with('\n') {
+        return Err(invalid("request head too long, or cut short"));
+    }
+    Ok(())
+}
+
+enum Failure {
+    BadRequest(String),
+    Forbidden,
+    NotFound(&'static str),
+    Disconnected,
+}
+
+impl From<Disconnected> for Failure {
+    fn from(_: Disconnected) -> Failure {
+        Failure::Disconnected
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: Vec<u8>) -> Response {
+        Response { status: "200 OK", content_type, body }
+    }
+
+    fn empty() -> Response {
+        Response::ok("text/plain", Vec::new())
+    }
+
+    // With CORS headers for origin, once it is known to be allowed
+    fn write_to<W: Write>(&self, out: &mut W, origin: Option<&str>) -> io::Result<()> {
+        write!(out, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+               self.status, self.content_type, self.body.len())?;
+        if let Some(origin) = origin {
+            write!(out, "Access-Control-Allow-Origin: {}\r\nAccess-Control-Allow-Methods: GET, POST\r\n\
+                         Access-Control-Allow-Headers: Content-Type\r\nVary: Origin\r\n", origin)?;
+        }
+        write!(out, "Connection: close\r\n\r\n")?;
+        out.write_all(&self.body)?;
+        out.flush()
+    }
+}
+
+impl From<Failure> for Response {
+    fn from(failure: Failure) -> Response {
+        let (status, message) = match failure {
+            Failure::BadRequest(message) => ("400 Bad Request", message),
+            Failure::Forbidden => ("403 Forbidden", "origin not allowed".to_string()),
+            Failure::NotFound(message) => ("404 Not Found", message.to_string()),
+            Failure::Disconnected => ("503 Service Unavailable", Disconnected.to_string()),
+        };
+        Response { status, content_type: "text/plain", body: message.into_bytes() }
+    }
+}
+
+fn serve(stream: TcpStream, handle: &ConsoleHandle, origins: &[String]) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = Request::read(&mut BufReader::new(stream.try_clone()?))?;
+    trace!(target: "gbrust::server", "{} {}", request.method, request.path);
+    let mut stream = stream;
+    let origin = request.header("origin");
+    if let Some(origin) = origin {
+        if !origins.iter().any(|allowed| allowed == origin) {
+            debug!(target: "gbrust::server", "refused a request from {}", origin);
+            return Response::from(Failure::Forbidden).write_to(&mut stream, None);
+        }
+    }
+    if request.path == "/frames" && request.wants_websocket() {
+        return stream_frames(stream, &request, handle);
+    }
+    let response = match request.method.as_str() {
+        "OPTIONS" => Response { status: "204 No Content", content_type: "text/plain", body: Vec::new() },
+        _ => respond(&request, handle).unwrap_or_else(Response::from),
+    };
+    response.write_to(&mut stream, origin)
+}
+
+fn respond(request: &Request, handle: &ConsoleHandle) -> Result<Response, Failure> {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/screenshot") => match handle.screenshot()? {
+            Some(frame) => Ok(Response::ok("image/png", encode_png(DISPLAY_WIDTH, DISPLAY_HEIGHT, &frame))),
+            None => Err(Failure::NotFound("no frame yet")),
+        },
+        ("GET", "/memory") => {
+            let len = match request.param("len") {
+                Ok(len) => len.parse::<usize>().ok()
+                    .filter(|&len| len <= MAX_BODY)
+                    .ok_or_else(|| Failure::BadRequest(format!("bad length {}", len)))?,
+                Err(_) => 1,
+            };
+            Ok(Response::ok("application/octet-stream", handle.read_memory(request.addr()?, len)?))
+        }
+        ("POST", "/memory") => {
+            handle.write_memory(request.addr()?, request.body.clone())?;
+            Ok(Response::empty())
+        }
+        ("POST", "/press") => {
+            handle.press(request.button()?)?;
+            Ok(Response::empty())
+        }
+        ("POST", "/release") => {
+            handle.release(request.button()?)?;
+            Ok(Response::empty())
+        }
+        ("POST", "/pause") => {
+            handle.pause()?;
+            Ok(Response::empty())
+        }
+        ("POST", "/resume") => {
+            handle.resume()?;
+            Ok(Response::empty())
+        }
+        ("POST", "/step") => {
+            handle.step()?;
+            Ok(Response::empty())
+        }
+        _ => Err(Failure::NotFound("no such endpoint")),
+    }
+}
+
+fn stream_frames(mut stream: TcpStream, request: &Request, handle: &ConsoleHandle) -> io::Result<()> {
+    let frames = match (request.header("sec-websocket-key"), handle.frames()) {
+        (Some(key), Ok(frames)) => {
+            write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                            Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                   websocket_accept(key))?;
+            frames
+        }
+        (None, _) => {
+            return Response::from(Failure::BadRequest("missing sec-websocket-key".to_string())).write_to(&mut stream, None)
+        }
+        (_, Err(e)) => return Response::from(Failure::from(e)).write_to(&mut stream, None),
+    };
+
+    // Ends when the console goes away, or when the client does and the write fails
+    for frame in frames.iter() {
+        let frame = frames.try_iter().last().unwrap_or(frame);
+        let mut message = Vec::with_capacity(10 + frame.len() * 4);
+        message.push(0x82); // final fragment, binary
+        message.push(127); // 64 bit length follows
+        message.extend_from_slice(&(frame.len() as u64 * 4).to_be_bytes());
+        for &pixel in frame.iter() {
+            message.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8, 0xFF]);
+        }
+        stream.write_all(&message)?;
+    }
+    Ok(())
+}
+
+fn parse_button(name: &str) -> Option<Button> {
+    let button = match name.to_ascii_lowercase().as_str() {
+        "a" => Button::A,
+        "b" => Button::B,
+        "start" => Button::Start,
+        "select" => Button::Select,
+        "up" => Button::Up,
+        "down" => Button::Down,
+        "left" => Button::Left,
+        "right" => Button::Right,
+        _ => return None,
+    };
+    Some(button)
+}
+
+fn websocket_accept(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()))
+}
+
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use super::super::cart::Cart;
+    use super::super::console::{Console, NoVideo};
+
+    fn request(server: &Server, request: &str) -> Vec<u8> {
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    fn body(response: &[u8]) -> &[u8] {
+        let at = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        &response[at + 4..]
+    }
+
+    #[test]
+    fn websocket_handshake() {
+        // From RFC 6455
+        assert_eq!(websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+    }
+
+    #[test]
+    fn serves_a_running_console() {
+        let rom = fs::read("tetris.gb").unwrap();
+        let mut console = Console::new(Cart::new(rom.clone().into_boxed_slice(), None).unwrap());
+        let origins = vec!["http://localhost:3000".to_string()];
+        let server = Server::bind_allowing("127.0.0.1:0", console.handle(), origins).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let emulation = thread::spawn(move || {
+            while !stop_flag.load(Ordering::SeqCst) {
+                console.run_frame(&mut NoVideo);
+            }
+            console
+        });
+
+        let memory = request(&server, "GET /memory?addr=0x0104&len=8 HTTP/1.1\r\n\r\n");
+        assert!(memory.starts_with(b"HTTP/1.1 200 OK"));
+        assert_eq!(body(&memory), &rom[0x0104..0x010C]);
+
+        let screenshot = loop {
+            let response = request(&server, "GET /screenshot HTTP/1.1\r\n\r\n");
+            if response.starts_with(b"HTTP/1.1 200 OK") {
+                break response;
+            }
+        };
+        assert!(body(&screenshot).starts_with(b"\x89PNG\r\n\x1a\n"));
+
+        assert!(request(&server, "POST /pause HTTP/1.1\r\n\r\n").starts_with(b"HTTP/1.1 200 OK"));
+        request(&server, "POST /memory?addr=c000 HTTP/1.1\r\nContent-Length: 2\r\n\r\n\x12\x34");
+        assert_eq!(body(&request(&server, "GET /memory?addr=c000&len=2 HTTP/1.1\r\n\r\n")), &[0x12, 0x34]);
+        assert!(request(&server, "POST /press?button=turbo HTTP/1.1\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+        assert!(request(&server, "GET /nothing HTTP/1.1\r\n\r\n").starts_with(b"HTTP/1.1 404"));
+
+        // Web pages only from the allowed origin
+        let poke = "POST /memory?addr=c000 HTTP/1.1\r\nOrigin: http://evil.example\r\nContent-Length: 1\r\n\r\n\x66";
+        assert!(request(&server, poke).starts_with(b"HTTP/1.1 403"));
+        assert_eq!(body(&request(&server, "GET /memory?addr=c000 HTTP/1.1\r\n\r\n")), &[0x12]);
+        let allowed = request(&server, "GET /memory?addr=c000 HTTP/1.1\r\nOrigin: http://localhost:3000\r\n\r\n");
+        let headers = String::from_utf8_lossy(&allowed[..allowed.len() - 1]).to_string();
+        assert!(headers.starts_with("HTTP/1.1 200 OK") && headers.contains("Access-Control-Allow-Origin: http://localhost:3000\r\n"));
+        let preflight = request(&server, "OPTIONS /memory HTTP/1.1\r\nOrigin: http://localhost:3000\r\n\r\n");
+        assert!(preflight.starts_with(b"HTTP/1.1 204"));
+        assert!(!String::from_utf8_lossy(&memory).contains("Access-Control"));
+
+        // A head that never ends is cut off unanswered
+        let mut endless = TcpStream::connect(server.local_addr()).unwrap();
+        let _ = endless.write_all("a".repeat(2 * MAX_HEAD as usize).as_bytes());
+        let mut response = Vec::new();
+        let _ = endless.read_to_end(&mut response);
+        assert!(response.is_empty());
+
+        stop.store(true, Ordering::SeqCst);
+        let console = emulation.join().unwrap();
+        assert!(console.is_paused());
+        drop(console);
+        assert!(request(&server, "POST /resume HTTP/1.1\r\n\r\n").starts_with(b"HTTP/1.1 503"));
+    }
+}
@@ -0,0 +1,325 @@
+// Emulation speed.
+// Slow motion and fast forward, for speedrun practice and for watching fast sequences: the
+// frontend runs a frame every Speed::frame_time, and a TimeStretch between the console and its
+// audio sink stretches or squeezes the sound to the same speed without changing its pitch. Both
+// read one SpeedControl, so the speed can change while the game runs. At 100% the sound goes
+// through untouched.
+//
+// Time stretching is WSOLA: the output is overlapping Hann-windowed grains of GRAIN samples, one
+// every HOP. Each grain is read `speed` hops further into the input than the last, then moved
+// by up to TOLERANCE samples to where it best lines up with how the last one went on, so the
+// waveforms join without beating. The sound lags by about a grain.
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::apu::AudioSample;
+use super::console::AudioSink;
+use super::error::SpeedParseError;
+
+// What faster and slower step through
+const STEPS: [f64; 8] = [0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 3.0, 4.0];
+const GRAIN: usize = 1024;
+const HOP: usize = GRAIN / 2;
+const TOLERANCE: usize = 128;
+// Lining grains up looks at every this many samples, plenty for Game Boy frequencies
+const CORRELATION_STRIDE: usize = 4;
+// left, right and the 4 channels
+const VALUES: usize = 6;
+
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct Speed(f64);
+
+impl Speed {
+    pub const MIN: Speed = Speed(0.25);
+    pub const MAX: Speed = Speed(4.0);
+    pub const NORMAL: Speed = Speed(1.0);
+
+    // Clamped to MIN - MAX
+    pub fn new(factor: f64) -> Speed {
+        Speed(factor.clamp(Speed::MIN.0, Speed::MAX.0))
+    }
+
+    pub fn factor(self) -> f64 {
+        self.0
+    }
+
+    // How long a frame takes at this speed, when it takes normal at 100%
+    pub fn frame_time(self, normal: Duration) -> Duration {
+        normal.div_f64(self.0)
+    }
+
+    pub fn faster(self) -> Speed {
+        STEPS.iter().find(|&&step| step > self.0).map_or(Speed::MAX, |&step| Speed(step))
+    }
+
+    pub fn slower(self) -> Speed {
+        STEPS.iter().rev().find(|&&step| step < self.0).map_or(Speed::MIN, |&step| Speed(step))
+    }
+}
+
+impl Default for Speed {
+    fn default() -> Speed {
+        Speed::NORMAL
+    }
+}
+
+// "50%", "2x" or "0.5"
+impl FromStr for Speed {
+    type Err = SpeedParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = s.trim();
+        let factor = match (text.strip_suffix('%'), text.strip_suffix(['x', 'X'])) {
+            (Some(percent), _) => percent.trim().parse::<f64>().map(|percent| percent / 100.0),
+            (None, Some(times)) => times.trim().parse::<f64>(),
+            (None, None) => text.parse::<f64>(),
+        };
+        match factor {
+            Ok(factor) if (Speed::MIN.0..=Speed::MAX.0).contains(&factor) => Ok(Speed(factor)),
+            _ => Err(SpeedParseError(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}%", (self.0 * 100.0).round())
+    }
+}
+
+// One speed shared between the frontend's pacing and a TimeStretch
+#[derive(Debug, Clone)]
+pub struct SpeedControl(Arc<AtomicU64>);
+
+impl SpeedControl {
+    pub fn new(speed: Speed) -> SpeedControl {
+        SpeedControl(Arc::new(AtomicU64::new(speed.0.to_bits())))
+    }
+
+    pub fn get(&self) -> Speed {
+        Speed(f64::from_bits(self.0.load(Ordering::Relaxed)))
+    }
+
+    pub fn set(&self, speed: Speed) {
+        self.0.store(speed.0.to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl Default for SpeedControl {
+    fn default() -> SpeedControl {
+        SpeedControl::new(Speed::NORMAL)
+    }
+}
+
+// An AudioSink that passes the sound on to sink at the speed control's speed
+pub struct TimeStretch {
+    sink: Box<dyn AudioSink + Send>,
+    speed: SpeedControl,
+    stretcher: Option<Stretcher>, // while not at 100%
+    out: Vec<AudioSample>,
+}
+
+impl TimeStretch {
+    pub fn new(sink: Box<dyn AudioSink + Send>, speed: SpeedControl) -> TimeStretch {
+        TimeStretch { sink, speed, stretcher: None, out: Vec::new() }
+    }
+
+    pub fn into_inner(self) -> Box<dyn AudioSink + Send> {
+        self.sink
+    }
+}
+
+impl AudioSink for TimeStretch {
+    fn samples_available(&mut self, samples: &[AudioSample]) {
+        let speed = self.speed.get();
+        if speed == Speed::NORMAL {
+            self.stretcher = None;
+            self.sink.samples_available(samples);
+            return;
+        }
+        self.out.clear();
+        self.stretcher.get_or_insert_with(Stretcher::new).push(samples, speed.0, &mut self.out);
+        if !self.out.is_empty() {
+            self.sink.samples_available(&self.out);
+        }
+    }
+}
+
+struct Stretcher {
+    input: Vec<[f32; VALUES]>,
+    position: f64,          // in input, where the next grain would be read without lining up
+    natural: Option<usize>, // in input, how the last grain went on
+    tail: Vec<[f32; VALUES]>, // the last grain's second half, windowed
+    window: Vec<f32>,
+    like: AudioSample, // the last sample in, for what values do not say (the sample width)
+}
+
+impl Stretcher {
+    fn new() -> Stretcher {
+        Stretcher {
+            input: Vec::new(),
+            position: 0.0,
+            natural: None,
+            tail: vec![[0.0; VALUES]; HOP],
+            window: (0..GRAIN).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / GRAIN as f32).cos()).collect(),
+            like: AudioSample::default(),
+        }
+    }
+
+    // Adds samples, and puts out a hop of samples for every grain there is enough input for
+    fn push(&mut self, samples: &[AudioSample], speed: f64, out: &mut Vec<AudioSample>) {
+        self.input.extend(samples.iter().map(to_values));
+        if let Some(&last) = samples.last() {
+            self.like = last;
+        }
+        loop {
+            let start = self.position.round() as usize;
+            let latest = start.max(self.natural.unwrap_or(0)) + TOLERANCE;
+            if latest + GRAIN > self.input.len() {
+                return;
+            }
+            let chosen = match self.natural {
+                None => start,
+                Some(natural) => self.best_match(natural, start),
+            };
+
+            for i in 0..HOP {
+                let value = self.windowed(chosen, i);
+                let mixed = std::array::from_fn(|v| self.tail[i][v] + value[v]);
+                out.push(from_values(mixed, &self.like));
+            }
+            self.tail = (HOP..GRAIN).map(|i| self.windowed(chosen, i)).collect();
+            self.position += HOP as f64 * speed;
+
+            // Nothing before the next grain's earliest start is read again
+            let next = chosen + HOP;
+            let keep = (self.position.round() as usize).saturating_sub(TOLERANCE).min(next);
+            self.input.drain(..keep);
+            self.position -= keep as f64;
+            self.natural = Some(next - keep);
+        }
+    }
+
+    fn windowed(&self, grain: usize, i: usize) -> [f32; VALUES] {
+        self.input[grain + i].map(|value| value * self.window[i])
+    }
+
+    // The grain start within TOLERANCE of start that correlates best with the one at natural
+    fn best_match(&self, natural: usize, start: usize) -> usize {
+        let mono = |at: usize| self.input[at][0] + self.input[at][1];
+        let correlation = |candidate: usize| -> f32 {
+            (0..GRAIN).step_by(CORRELATION_STRIDE).map(|i| mono(natural + i) * mono(candidate + i)).sum()
+        };
+        let mut best = (start, f32::MIN);
+        for candidate in start.saturating_sub(TOLERANCE)..=start + TOLERANCE {
+            let score = correlation(candidate);
+            if score > best.1 {
+                best = (candidate, score);
+            }
+        }
+        best.0
+    }
+}
+
+#[cfg(not(feature = "fixed-point-audio"))]
+fn to_values(sample: &AudioSample) -> [f32; VALUES] {
+    let [a, b, c, d] = sample.channels;
+    [sample.left, sample.right, a, b, c, d]
+}
+
+#[cfg(not(feature = "fixed-point-audio"))]
+fn from_values(values: [f32; VALUES], _like: &AudioSample) -> AudioSample {
+    let [left, right, a, b, c, d] = values;
+    AudioSample { left, right, channels: [a, b, c, d] }
+}
+
+#[cfg(feature = "fixed-point-audio")]
+fn to_values(sample: &AudioSample) -> [f32; VALUES] {
+    let [a, b, c, d] = sample.channels;
+    [sample.left, sample.right, a, b, c, d].map(|value| value as f32)
+}
+
+#[cfg(feature = "fixed-point-audio")]
+fn from_values(values: [f32; VALUES], like: &AudioSample) -> AudioSample {
+    let [left, right, a, b, c, d] = values.map(|value| value.round() as i32);
+    AudioSample { left, right, channels: [a, b, c, d], bits: like.bits }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn speeds() {
+        assert_eq!("50%".parse(), Ok(Speed(0.5)));
+        assert_eq!("2x".parse(), Ok(Speed(2.0)));
+        assert_eq!(" 0.25 ".parse(), Ok(Speed::MIN));
+        assert!("500%".parse::<Speed>().is_err());
+        assert!("fast".parse::<Speed>().is_err());
+        assert_eq!(Speed(0.5).to_string(), "50%");
+        assert_eq!(Speed::NORMAL.faster(), Speed(1.5));
+        assert_eq!(Speed(1.2).slower(), Speed::NORMAL);
+        assert_eq!(Speed::MIN.slower(), Speed::MIN);
+        assert_eq!(Speed::new(9.0), Speed::MAX);
+        assert_eq!(Speed(0.5).frame_time(Duration::from_millis(16)), Duration::from_millis(32));
+    }
+
+    struct Collect(Arc<Mutex<Vec<f32>>>);
+
+    impl AudioSink for Collect {
+        fn samples_available(&mut self, samples: &[AudioSample]) {
+            self.0.lock().unwrap().extend(samples.iter().map(|sample| to_values(sample)[0]));
+        }
+    }
+
+    // Upward zero crossings per sample
+    fn frequency(samples: &[f32]) -> f32 {
+        let crossings = samples.windows(2).filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0).count();
+        crossings as f32 / samples.len() as f32
+    }
+
+    // Half of full scale, in the sample type's units
+    #[cfg(not(feature = "fixed-point-audio"))]
+    const AMPLITUDE: f32 = 0.5;
+    #[cfg(feature = "fixed-point-audio")]
+    const AMPLITUDE: f32 = 16_384.0;
+
+    // A 440 Hz tone at 44.1 kHz, fed a frame (735 samples) at a time at speed. The tone holds
+    // only values a sample can, so it comes back exactly at normal speed.
+    fn stretch(speed: Speed, frames: usize) -> (Vec<f32>, Vec<f32>) {
+        let tone: Vec<f32> = (0..735 * frames)
+            .map(|i| (2.0 * PI * 440.0 * i as f32 / 44_100.0).sin() * AMPLITUDE)
+            .map(|value| to_values(&from_values([value; VALUES], &AudioSample::default()))[0])
+            .collect();
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut stretch = TimeStretch::new(Box::new(Collect(out.clone())), SpeedControl::new(speed));
+        for frame in tone.chunks(735) {
+            let samples: Vec<AudioSample> = frame.iter()
+                .map(|&value| from_values([value, value, 0.0, 0.0, 0.0, 0.0], &AudioSample::default()))
+                .collect();
+            stretch.samples_available(&samples);
+        }
+        let out = out.lock().unwrap().clone();
+        (tone, out)
+    }
+
+    #[test]
+    fn stretches_without_changing_pitch() {
+        let (tone, normal) = stretch(Speed::NORMAL, 60);
+        assert_eq!(normal, tone);
+
+        for &speed in &[Speed(0.5), Speed(2.0)] {
+            let (tone, out) = stretch(speed, 60);
+            let expected = tone.len() as f64 / speed.0;
+            assert!((out.len() as f64 - expected).abs() < 2.0 * GRAIN as f64 / speed.0, "{} samples at {}", out.len(), speed);
+            let (pitch, stretched) = (frequency(&tone), frequency(&out[GRAIN..]));
+            assert!((stretched - pitch).abs() < pitch * 0.02, "{} against {} at {}", stretched, pitch, speed);
+        }
+    }
+}
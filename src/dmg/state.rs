@@ -0,0 +1,191 @@
+// Save states.
+// A save state is a flat little-endian byte stream. Every component writes its fields in a fixed
+// order in save_state, and reads them back in the same order in load_state. There are no field
+// names or lengths in the stream, so the order on both sides must always match, and VERSION must
+// be bumped whenever a component adds, removes or reorders a field.
+
+use thiserror::Error;
+
+const MAGIC: &[u8; 4] = b"GBRS";
+pub const VERSION: u8 = 11;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum StateError {
+    #[error("not a gbrust save state")]
+    BadMagic,
+    #[error("unsupported save state version {0}")]
+    UnsupportedVersion(u8),
+    #[error("save state belongs to another ROM")]
+    WrongRom,
+    #[error("save state is truncated")]
+    Truncated,
+    // A block's size does not match the component loading it, e.g. a RAM size mismatch
+    #[error("save state {0} has the wrong size")]
+    SizeMismatch(&'static str),
+}
+
+// Found by Console::check_state: running from a reloaded save state went differently than the
+// first time, so something is missing from the state
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Divergence {
+    #[error("save state does not load back: {0}")]
+    Load(StateError),
+    #[error("frame {0} differs after reloading the save state")]
+    Frame(usize),
+    #[error("frames match, but the save states taken after them differ from byte {0}")]
+    State(usize),
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        StateWriter { buf }
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.buf.push(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // Length-prefixed, so blocks whose size depends on the cart (external RAM) can be checked
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Box<[u8]> {
+        self.buf.into_boxed_slice()
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        if buf.len() < MAGIC.len() + 1 || &buf[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let version = buf[MAGIC.len()];
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        Ok(StateReader {
+            buf,
+            pos: MAGIC.len() + 1,
+        })
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.buf.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+        let slice = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    // Reads a length-prefixed block into dest, which must be exactly the same size
+    pub fn read_into(&mut self, dest: &mut [u8], what: &'static str) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != dest.len() {
+            return Err(StateError::SizeMismatch(what));
+        }
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0xAB);
+        writer.write_bool(true);
+        writer.write_u16(0x1234);
+        writer.write_u32(0xDEAD_BEEF);
+        writer.write_u64(1 << 40);
+        writer.write_bytes(&[1, 2, 3]);
+        let state = writer.finish();
+
+        let mut reader = StateReader::new(&state).unwrap();
+        assert_eq!(reader.read_u8(), Ok(0xAB));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x1234));
+        assert_eq!(reader.read_u32(), Ok(0xDEAD_BEEF));
+        assert_eq!(reader.read_u64(), Ok(1 << 40));
+        let mut bytes = [0; 3];
+        assert_eq!(reader.read_into(&mut bytes, "bytes"), Ok(()));
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(reader.read_u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn rejects_foreign_data() {
+        assert_eq!(StateReader::new(b"nope!").err(), Some(StateError::BadMagic));
+        assert_eq!(StateReader::new(b"GBRS\x7f").err(), Some(StateError::UnsupportedVersion(0x7f)));
+    }
+}
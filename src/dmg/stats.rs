@@ -0,0 +1,27 @@
+// Per-frame counters, for perf overlays and for spotting where a frame's time went.
+// The interconnect collects them as the frame runs and the console hands them out once the
+// frame is done.
+
+use super::Interrupt;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FrameStats {
+    pub cycles: u64,
+    pub instructions: u64,
+    // Interrupts taken by the CPU, indexed by bit in IF: vblank, lcdstat, timer, serial, joypad
+    pub interrupts: [u32; 5],
+    pub dma_transfers: u32,
+    // Only counted while an audio sink is attached
+    pub audio_samples: u64,
+}
+
+impl FrameStats {
+    // How often one kind of interrupt was taken, e.g. interrupt_count(Interrupt::VBlank)
+    pub fn interrupt_count(&self, interrupt: Interrupt) -> u32 {
+        self.interrupts[interrupt.bit() as usize]
+    }
+
+    pub fn interrupts_total(&self) -> u32 {
+        self.interrupts.iter().sum()
+    }
+}
@@ -0,0 +1,102 @@
+// Labels for the debugger.
+// Assemblers and disassemblers write the addresses of a game's labels to a .sym file, one
+// "bank:addr name" per line with ; for comments, as RGBDS and BGB do:
+//
+//     00:0150 Start
+//     01:4abc PlayerUpdate
+//
+// The call stack and the debug prompt name addresses with them. An address past a label in the
+// same bank and the same quarter of the address space reads as the label plus an offset,
+// "PlayerUpdate+12".
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use super::debugger::BankedAddr;
+use super::error::SymbolError;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    // By bank, then address. Addresses outside ROM, which have no bank, go in bank 0.
+    labels: BTreeMap<(u16, u16), String>,
+}
+
+impl Symbols {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Symbols, SymbolError> {
+        Symbols::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, SymbolError> {
+        let mut symbols = Symbols::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || SymbolError::Parse { line: index + 1 };
+            let (addr, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let addr: BankedAddr = addr.parse().map_err(|_| invalid())?;
+            symbols.insert(addr, name.trim());
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, addr: BankedAddr, name: &str) {
+        self.labels.insert(key(addr), name.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    // Where the label called name is. ROM addresses come with their bank.
+    pub fn find(&self, name: &str) -> Option<BankedAddr> {
+        let (&(bank, addr), _) = self.labels.iter().find(|(_, label)| label.as_str() == name)?;
+        Some(if addr < 0x8000 { BankedAddr::new(bank, addr) } else { BankedAddr::unbanked(addr) })
+    }
+
+    // The label at addr, or the closest one before it plus the distance
+    pub fn label(&self, addr: BankedAddr) -> Option<String> {
+        let (bank, addr) = key(addr);
+        let (&(label_bank, label_addr), name) = self.labels.range(..=(bank, addr)).next_back()?;
+        if label_bank != bank || label_addr & 0xC000 != addr & 0xC000 {
+            return None;
+        }
+        match addr - label_addr {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+{:x}", name, offset)),
+        }
+    }
+}
+
+fn key(addr: BankedAddr) -> (u16, u16) {
+    (addr.bank.unwrap_or(0), addr.addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_and_offsets() {
+        let symbols = Symbols::parse("; made by hand\n00:0150 Start\n01:4abc PlayerUpdate ; moves\n\n00:c000 wBuffer\n").unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.label(BankedAddr::new(0, 0x0150)).as_deref(), Some("Start"));
+        assert_eq!(symbols.label(BankedAddr::new(1, 0x4ace)).as_deref(), Some("PlayerUpdate+12"));
+        assert_eq!(symbols.label(BankedAddr::new(2, 0x4ace)), None);
+        assert_eq!(symbols.label(BankedAddr::new(0, 0x0100)), None);
+        assert_eq!(symbols.label(BankedAddr::unbanked(0xC010)).as_deref(), Some("wBuffer+10"));
+        assert_eq!(symbols.label(BankedAddr::unbanked(0x8000)), None);
+        assert_eq!(symbols.find("PlayerUpdate"), Some(BankedAddr::new(1, 0x4ABC)));
+        assert_eq!(symbols.find("wBuffer"), Some(BankedAddr::unbanked(0xC000)));
+        assert_eq!(symbols.find("Nowhere"), None);
+
+        assert!(matches!(Symbols::parse("00:0150\n"), Err(SymbolError::Parse { line: 1 })));
+        assert!(matches!(Symbols::parse("Start 00:0150\n"), Err(SymbolError::Parse { line: 1 })));
+    }
+}
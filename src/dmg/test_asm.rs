@@ -0,0 +1,380 @@
+// A small assembler for CPU tests.
+// Builds a flat 32 KiB ROM only cart: the entry point at 0x0100 jumps over the header to 0x0150,
+// where the program goes. Every method emits one instruction and returns the assembler, so a
+// test reads like a listing:
+//
+//     let cpu = Asm::new()
+//         .ld_r_n(R8::A, 0x0F)
+//         .alu_n(Alu::Add, 0x01)
+//         .run();
+//     assert_eq!(cpu.registers().f, 0x20); // half carry
+//
+// Jumps take labels, placed with label(), before or after the jump. run() ends the program with
+// a jr to itself and steps the CPU until it gets there; cpu() and step() are for stepping through
+// by hand. Opcodes without a method go in with bytes().
+
+use std::collections::HashMap;
+
+use super::cart::Cart;
+use super::console::NoVideo;
+use super::dmg_cpu::Cpu;
+use super::interconnect::Interconnect;
+
+const ROM_SIZE: usize = 0x8000;
+const CODE_START: u16 = 0x0150;
+const MAX_STEPS: usize = 100_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum R8 {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    HlInd, // (hl)
+    A,
+}
+
+impl R8 {
+    pub const ALL: [R8; 8] = [R8::B, R8::C, R8::D, R8::E, R8::H, R8::L, R8::HlInd, R8::A];
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum R16 {
+    BC,
+    DE,
+    HL,
+    SP, // AF for push and pop
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Cond {
+    NZ,
+    Z,
+    NC,
+    C,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Alu {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+// The 0xcb rotates and shifts
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rot {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Swap,
+    Srl,
+}
+
+enum Fixup {
+    Absolute(usize, &'static str),
+    Relative(usize, &'static str),
+}
+
+pub struct Asm {
+    rom: Vec<u8>,
+    pc: u16,
+    labels: HashMap<&'static str, u16>,
+    fixups: Vec<Fixup>,
+}
+
+impl Default for Asm {
+    fn default() -> Self {
+        Asm::new()
+    }
+}
+
+impl Asm {
+    pub fn new() -> Asm {
+        let mut rom = vec![0; ROM_SIZE];
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, CODE_START as u8, (CODE_START >> 8) as u8]);
+        Asm { rom, pc: CODE_START, labels: HashMap::new(), fixups: Vec::new() }
+    }
+
+    // Where the next instruction goes
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn bytes(mut self, bytes: &[u8]) -> Asm {
+        let start = self.pc as usize;
+        assert!(start + bytes.len() <= ROM_SIZE, "program runs past the end of the ROM");
+        self.rom[start..start + bytes.len()].copy_from_slice(bytes);
+        self.pc += bytes.len() as u16;
+        self
+    }
+
+    pub fn label(mut self, name: &'static str) -> Asm {
+        assert!(self.labels.insert(name, self.pc).is_none(), "label {} placed twice", name);
+        self
+    }
+
+    fn op(self, opcode: u8) -> Asm {
+        self.bytes(&[opcode])
+    }
+
+    fn op_n(self, opcode: u8, n: u8) -> Asm {
+        self.bytes(&[opcode, n])
+    }
+
+    fn op_nn(self, opcode: u8, nn: u16) -> Asm {
+        self.bytes(&[opcode, nn as u8, (nn >> 8) as u8])
+    }
+
+    fn op_label(mut self, opcode: u8, label: &'static str) -> Asm {
+        self.fixups.push(Fixup::Absolute(self.pc as usize + 1, label));
+        self.op_nn(opcode, 0)
+    }
+
+    fn op_relative(mut self, opcode: u8, label: &'static str) -> Asm {
+        self.fixups.push(Fixup::Relative(self.pc as usize + 1, label));
+        self.op_n(opcode, 0)
+    }
+
+    pub fn nop(self) -> Asm {
+        self.op(0x00)
+    }
+
+    pub fn ld_r_n(self, r: R8, n: u8) -> Asm {
+        self.op_n(0x06 | (r as u8) << 3, n)
+    }
+
+    pub fn ld_r_r(self, to: R8, from: R8) -> Asm {
+        assert!(!(to == R8::HlInd && from == R8::HlInd), "ld (hl),(hl) is halt");
+        self.op(0x40 | (to as u8) << 3 | from as u8)
+    }
+
+    pub fn ld_rr_nn(self, rr: R16, nn: u16) -> Asm {
+        self.op_nn(0x01 | (rr as u8) << 4, nn)
+    }
+
+    // ld a,(nn)
+    pub fn ld_a_mem(self, addr: u16) -> Asm {
+        self.op_nn(0xFA, addr)
+    }
+
+    // ld (nn),a
+    pub fn ld_mem_a(self, addr: u16) -> Asm {
+        self.op_nn(0xEA, addr)
+    }
+
+    pub fn inc(self, r: R8) -> Asm {
+        self.op(0x04 | (r as u8) << 3)
+    }
+
+    pub fn dec(self, r: R8) -> Asm {
+        self.op(0x05 | (r as u8) << 3)
+    }
+
+    pub fn inc16(self, rr: R16) -> Asm {
+        self.op(0x03 | (rr as u8) << 4)
+    }
+
+    pub fn dec16(self, rr: R16) -> Asm {
+        self.op(0x0B | (rr as u8) << 4)
+    }
+
+    pub fn add_hl(self, rr: R16) -> Asm {
+        self.op(0x09 | (rr as u8) << 4)
+    }
+
+    pub fn alu(self, op: Alu, r: R8) -> Asm {
+        self.op(0x80 | (op as u8) << 3 | r as u8)
+    }
+
+    pub fn alu_n(self, op: Alu, n: u8) -> Asm {
+        self.op_n(0xC6 | (op as u8) << 3, n)
+    }
+
+    pub fn rot(self, op: Rot, r: R8) -> Asm {
+        self.bytes(&[0xCB, (op as u8) << 3 | r as u8])
+    }
+
+    pub fn bit(self, bit: u8, r: R8) -> Asm {
+        self.bytes(&[0xCB, 0x40 | bit << 3 | r as u8])
+    }
+
+    pub fn res(self, bit: u8, r: R8) -> Asm {
+        self.bytes(&[0xCB, 0x80 | bit << 3 | r as u8])
+    }
+
+    pub fn set(self, bit: u8, r: R8) -> Asm {
+        self.bytes(&[0xCB, 0xC0 | bit << 3 | r as u8])
+    }
+
+    // R16::SP stands for AF
+    pub fn push(self, rr: R16) -> Asm {
+        self.op(0xC5 | (rr as u8) << 4)
+    }
+
+    pub fn pop(self, rr: R16) -> Asm {
+        self.op(0xC1 | (rr as u8) << 4)
+    }
+
+    pub fn jp(self, label: &'static str) -> Asm {
+        self.op_label(0xC3, label)
+    }
+
+    pub fn jp_cc(self, cond: Cond, label: &'static str) -> Asm {
+        self.op_label(0xC2 | (cond as u8) << 3, label)
+    }
+
+    pub fn jr(self, label: &'static str) -> Asm {
+        self.op_relative(0x18, label)
+    }
+
+    pub fn jr_cc(self, cond: Cond, label: &'static str) -> Asm {
+        self.op_relative(0x20 | (cond as u8) << 3, label)
+    }
+
+    pub fn call(self, label: &'static str) -> Asm {
+        self.op_label(0xCD, label)
+    }
+
+    pub fn call_cc(self, cond: Cond, label: &'static str) -> Asm {
+        self.op_label(0xC4 | (cond as u8) << 3, label)
+    }
+
+    pub fn ret(self) -> Asm {
+        self.op(0xC9)
+    }
+
+    pub fn ret_cc(self, cond: Cond) -> Asm {
+        self.op(0xC0 | (cond as u8) << 3)
+    }
+
+    pub fn di(self) -> Asm {
+        self.op(0xF3)
+    }
+
+    pub fn ei(self) -> Asm {
+        self.op(0xFB)
+    }
+
+    // The ROM, with every label filled in
+    pub fn assemble(mut self) -> Box<[u8]> {
+        for fixup in &self.fixups {
+            let (at, label) = match *fixup {
+                Fixup::Absolute(at, label) | Fixup::Relative(at, label) => (at, label),
+            };
+            let target = *self.labels.get(label).unwrap_or_else(|| panic!("no label {}", label));
+            match fixup {
+                Fixup::Absolute(..) => self.rom[at..at + 2].copy_from_slice(&target.to_le_bytes()),
+                Fixup::Relative(..) => {
+                    let offset = target as i32 - (at as i32 + 1);
+                    assert!((-128..=127).contains(&offset), "jr to {} is out of range", label);
+                    self.rom[at] = offset as i8 as u8;
+                }
+            }
+        }
+        self.rom.into_boxed_slice()
+    }
+
+    pub fn cart(self) -> Cart {
+        Cart::new(self.assemble(), None).unwrap()
+    }
+
+    // A CPU at the entry point with the program in, ended with a jr to itself and the registers
+    // as the boot ROM leaves them. For tests that step through the program themselves.
+    pub fn cpu(self) -> Cpu {
+        Cpu::new(Interconnect::new(self.label("end").jr("end").cart()))
+    }
+
+    // Runs the program to its end
+    pub fn run(self) -> Cpu {
+        let end = self.pc;
+        let mut cpu = self.cpu();
+        run_to(&mut cpu, end);
+        cpu
+    }
+}
+
+// One instruction, and the interrupt after it if one is taken
+pub fn step(cpu: &mut Cpu) {
+    cpu.step(&mut NoVideo);
+}
+
+// Steps until pc reaches addr
+pub fn run_to(cpu: &mut Cpu, addr: u16) {
+    for _ in 0..MAX_STEPS {
+        if cpu.registers().pc == addr {
+            return;
+        }
+        step(cpu);
+    }
+    panic!("pc did not reach {:04x} after {} instructions, at {:04x}", addr, MAX_STEPS, cpu.registers().pc);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_with_labels_either_side() {
+        let rom = Asm::new()
+            .ld_r_n(R8::B, 0x12)
+            .jr("skip")
+            .label("back")
+            .ret()
+            .label("skip")
+            .call("back")
+            .jp_cc(Cond::NZ, "back")
+            .assemble();
+        assert_eq!(&rom[0x0100..0x0104], &[0x00, 0xC3, 0x50, 0x01]);
+        assert_eq!(&rom[0x0150..0x015E], &[
+            0x06, 0x12, 0x18, 0x01, 0xC9, 0xCD, 0x54, 0x01, 0xC2, 0x54, 0x01, 0x00, 0x00, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn runs_loops_and_calls() {
+        // 5 + 4 + 3 + 2 + 1, in a subroutine
+        let mut cpu = Asm::new()
+            .ld_rr_nn(R16::SP, 0xDFFF)
+            .call("sum")
+            .ld_mem_a(0xC000)
+            .jr("done")
+            .label("sum")
+            .alu(Alu::Xor, R8::A)
+            .ld_r_n(R8::B, 5)
+            .label("loop")
+            .alu(Alu::Add, R8::B)
+            .dec(R8::B)
+            .jr_cc(Cond::NZ, "loop")
+            .ret()
+            .label("done")
+            .run();
+        let regs = cpu.registers();
+        assert_eq!((regs.a, regs.b, regs.sp), (15, 0, 0xDFFF));
+        assert_eq!(cpu.interconnect.read(0xC000), 15);
+    }
+
+    #[test]
+    fn runs_cb_opcodes_on_memory() {
+        let mut cpu = Asm::new()
+            .ld_rr_nn(R16::HL, 0xC100)
+            .ld_r_n(R8::HlInd, 0x81)
+            .rot(Rot::Swap, R8::HlInd)
+            .set(1, R8::HlInd)
+            .res(7, R8::HlInd)
+            .bit(4, R8::HlInd)
+            .run();
+        assert_eq!(cpu.interconnect.read(0xC100), 0x1A);
+        assert_eq!(cpu.registers().f & 0x80, 0); // bit 4 is set, so no Z
+    }
+}
@@ -0,0 +1,68 @@
+// Test ROM suites, run at every accuracy level.
+// Blargg's ROMs print their name and then "Passed" or "Failed" over the serial port, which is
+// what these tests read. Each runs until it says either, or gives up after TIMEOUT_FRAMES.
+// Only the parts that pass today are asserted, so a regression fails the build. The rest are
+// listed with what they print, and fail the same way at every level: the CPU bugs behind them
+// do not depend on the accuracy level.
+
+use std::path::Path;
+
+use super::config::AccuracyLevel;
+use super::console::{Console, NoVideo};
+
+const CPU_INSTRS: &str = "testcase/blargg/cpu_instrs/cpu_instrs/individual";
+const TIMEOUT_FRAMES: usize = 60 * 60;
+
+const LEVELS: [AccuracyLevel; 3] = [
+    AccuracyLevel::Fast,
+    AccuracyLevel::Balanced,
+    AccuracyLevel::CycleAccurate,
+];
+
+// Passing at every level
+const CPU_INSTRS_PASSING: [&str; 4] = [
+    "04-op r,imm.gb",
+    "05-op rp.gb",
+    "06-ld r,r.gb",
+    "10-bit ops.gb",
+];
+
+// Failing at every level, for now:
+//   01-special                DAA, "Failed #6"
+//   02-interrupts             EI, "Failed #2"
+//   03-op sp,hl               E8
+//   07-jr,jp,call,ret,rst     every opcode tested
+//   08-misc instrs            PUSH and POP
+//   09-op r,r                 RLCA, RLA, RRCA, RRA
+//   11-op a,(hl)              DAA
+
+// What the ROM printed, up to and including its verdict
+pub fn run_blargg(path: &Path, accuracy: AccuracyLevel) -> String {
+    let mut console = Console::builder().rom_path(path).accuracy(accuracy).build().unwrap();
+    console.record_serial_output(true);
+    let mut output = Vec::new();
+    for _ in 0..TIMEOUT_FRAMES {
+        console.run_frame(&mut NoVideo);
+        output.extend(console.take_serial_output());
+        let text = String::from_utf8_lossy(&output);
+        if text.contains("Passed") || text.contains("Failed") {
+            break;
+        }
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blargg_cpu_instrs() {
+        for &accuracy in LEVELS.iter() {
+            for name in CPU_INSTRS_PASSING.iter() {
+                let output = run_blargg(&Path::new(CPU_INSTRS).join(name), accuracy);
+                assert!(output.contains("Passed"), "{} at {:?}: {:?}", name, accuracy, output);
+            }
+        }
+    }
+}
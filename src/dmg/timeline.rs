@@ -0,0 +1,161 @@
+// PPU timing timelines.
+// For debugging raster effects that fire on the wrong line: Console::record_timeline runs a
+// frame while the PPU logs every mode change and every STAT interrupt it requests, stamped with
+// its clock. Timestamps are only as fine as the CPU's steps, the PPU catches up after each
+// instruction.
+// Exported as JSON for scripts, or as an SVG that draws the frame 456 cycles (one scanline) per
+// row, so every row shows a line's OAM search, transfer and HBlank, with STAT interrupts as
+// red marks.
+
+use std::fmt::Write;
+
+// What made the PPU request a STAT interrupt, following the enable bits in STAT
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatCause {
+    LyCoincidence,
+    HBlank,
+    VBlank,
+    Oam,
+    StatWrite, // the DMG's STAT write bug, see Ppu::write
+}
+
+impl StatCause {
+    fn name(self) -> &'static str {
+        match self {
+            StatCause::LyCoincidence => "lyc",
+            StatCause::HBlank => "hblank",
+            StatCause::VBlank => "vblank",
+            StatCause::Oam => "oam",
+            StatCause::StatWrite => "write",
+        }
+    }
+}
+
+// cycle: PPU clock (cycles since power on). mode: the number in STAT, 0 HBlank, 1 VBlank,
+// 2 OAM search, 3 transfer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimelineEvent {
+    Mode { cycle: u64, ly: u8, mode: u8 },
+    Stat { cycle: u64, ly: u8, cause: StatCause },
+}
+
+impl TimelineEvent {
+    pub fn cycle(&self) -> u64 {
+        match *self {
+            TimelineEvent::Mode { cycle, .. } | TimelineEvent::Stat { cycle, .. } => cycle,
+        }
+    }
+}
+
+const LINE_CYCLES: u64 = 456;
+const ROW_HEIGHT: u64 = 6;
+const LABEL_WIDTH: u64 = 24;
+// HBlank, VBlank, OAM search, transfer
+const MODE_COLORS: [&str; 4] = ["#9ecae1", "#bdbdbd", "#a1d99b", "#fdae6b"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PpuTimeline {
+    // PPU clock when recording started, and mode and LY at that point
+    pub start: u64,
+    pub start_mode: u8,
+    pub start_ly: u8,
+    pub end: u64,
+    pub events: Vec<TimelineEvent>,
+}
+
+impl PpuTimeline {
+    // Cycles in the JSON are relative to start
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write!(json, "{{\"start\":{},\"cycles\":{},\"start_mode\":{},\"start_ly\":{},\"events\":[",
+               self.start, self.end - self.start, self.start_mode, self.start_ly).unwrap();
+        for (i, event) in self.events.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            match *event {
+                TimelineEvent::Mode { cycle, ly, mode } => write!(
+                    json, "{{\"cycle\":{},\"ly\":{},\"mode\":{}}}", cycle - self.start, ly, mode),
+                TimelineEvent::Stat { cycle, ly, cause } => write!(
+                    json, "{{\"cycle\":{},\"ly\":{},\"stat\":\"{}\"}}", cycle - self.start, ly, cause.name()),
+            }.unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+
+    pub fn to_svg(&self) -> String {
+        let cycles = self.end - self.start;
+        let rows = cycles.div_ceil(LINE_CYCLES);
+        let mut svg = String::new();
+        writeln!(svg, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-size=\"{}\" \
+                       font-family=\"monospace\">",
+                 LABEL_WIDTH + LINE_CYCLES, rows * ROW_HEIGHT, ROW_HEIGHT).unwrap();
+
+        // Mode spans, wrapped into rows
+        let mut mode = (0, self.start_mode);
+        for event in &self.events {
+            if let TimelineEvent::Mode { cycle, ly, mode: next } = *event {
+                self.svg_span(&mut svg, mode.0, cycle - self.start, mode.1);
+                mode = (cycle - self.start, next);
+                if next == 2 {
+                    writeln!(svg, "<text x=\"0\" y=\"{}\">{}</text>",
+                             (mode.0 / LINE_CYCLES + 1) * ROW_HEIGHT, ly).unwrap();
+                }
+            }
+        }
+        self.svg_span(&mut svg, mode.0, cycles, mode.1);
+
+        for event in &self.events {
+            if let TimelineEvent::Stat { cycle, ly, cause } = *event {
+                let offset = cycle - self.start;
+                writeln!(svg, "<rect x=\"{}\" y=\"{}\" width=\"2\" height=\"{}\" fill=\"red\">\
+                               <title>STAT {} at LY {}, cycle {}</title></rect>",
+                         LABEL_WIDTH + offset % LINE_CYCLES, offset / LINE_CYCLES * ROW_HEIGHT, ROW_HEIGHT,
+                         cause.name(), ly, offset).unwrap();
+            }
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    fn svg_span(&self, svg: &mut String, mut from: u64, to: u64, mode: u8) {
+        while from < to {
+            let row = from / LINE_CYCLES;
+            let end = to.min((row + 1) * LINE_CYCLES);
+            writeln!(svg, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+                     LABEL_WIDTH + from % LINE_CYCLES, row * ROW_HEIGHT, end - from, ROW_HEIGHT,
+                     MODE_COLORS[(mode & 0b11) as usize]).unwrap();
+            from = end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_wrap_at_line_boundaries() {
+        let timeline = PpuTimeline {
+            start: 1000,
+            start_mode: 1,
+            start_ly: 153,
+            end: 1000 + 2 * LINE_CYCLES,
+            events: vec![
+                TimelineEvent::Mode { cycle: 1400, ly: 0, mode: 2 },
+                TimelineEvent::Stat { cycle: 1400, ly: 0, cause: StatCause::Oam },
+            ],
+        };
+        assert_eq!(timeline.to_json(),
+                   "{\"start\":1000,\"cycles\":912,\"start_mode\":1,\"start_ly\":153,\"events\":[\
+                    {\"cycle\":400,\"ly\":0,\"mode\":2},{\"cycle\":400,\"ly\":0,\"stat\":\"oam\"}]}");
+
+        // VBlank until cycle 400, then OAM search across the end of the first row
+        let svg = timeline.to_svg();
+        assert!(svg.contains("<rect x=\"24\" y=\"0\" width=\"400\" height=\"6\" fill=\"#bdbdbd\"/>"));
+        assert!(svg.contains("<rect x=\"424\" y=\"0\" width=\"56\" height=\"6\" fill=\"#a1d99b\"/>"));
+        assert!(svg.contains("<rect x=\"24\" y=\"6\" width=\"456\" height=\"6\" fill=\"#a1d99b\"/>"));
+        assert!(svg.contains("STAT oam at LY 0, cycle 400"));
+    }
+}
@@ -1,8 +1,6 @@
 use std::u8;
-use super::Interrupts;
-// use super::INT_TIMEROVERFLOW;
-
-const INT_TIMEROVERFLOW: Interrupts = Interrupts::INT_TIMEROVERFLOW;
+use super::interrupts::{Interrupt, InterruptController};
+use super::state::{StateError, StateReader, StateWriter};
 
 // Clock speed
 // See PanDocs: https://gbdev.io/pandocs/#timer-and-divider-registers
@@ -57,6 +55,32 @@ impl Timer {
         }
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.div);
+        state.write_u8(self.div_cycles);
+        state.write_u8(self.tima);
+        state.write_u32(self.tima_cycles);
+        state.write_u8(self.tma);
+        state.write_u8(self.read(0xff07));
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.div = state.read_u8()?;
+        self.div_cycles = state.read_u8()?;
+        self.tima = state.read_u8()?;
+        self.tima_cycles = state.read_u32()?;
+        self.tma = state.read_u8()?;
+        let tac = state.read_u8()?;
+        self.write(0xff07, tac);
+        Ok(())
+    }
+
+    // Where the divider is in its count, DIV in the high byte. Power on starts it at 0.
+    pub fn set_divider(&mut self, counter: u16) {
+        self.div = (counter >> 8) as u8;
+        self.div_cycles = counter as u8;
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
             0xff04 => self.div,
@@ -83,14 +107,12 @@ impl Timer {
         }
     }
 
-     //Interrupt
-     pub fn cycle_flush(&mut self, cycle_count: u32) -> Interrupts {
+     // Requests the timer interrupt when TIMA overflows
+     pub fn cycle_flush(&mut self, cycle_count: u32, interrupts: &mut InterruptController) {
          self.flush_div(cycle_count);
 
          if self.flush_tima(cycle_count) {
-             INT_TIMEROVERFLOW
-         } else {
-             Interrupts::empty()
+             interrupts.request(Interrupt::Timer);
          }
      }
 
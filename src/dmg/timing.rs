@@ -0,0 +1,153 @@
+// Instruction timings from PanDocs, in machine cycles (4 clocks each, as the CPU counts them),
+// to check the cycles each instruction reports against. Conditional jumps, calls and returns
+// take longer when the condition holds, see taken_timing.
+//
+// With the check on, the CPU looks up every instruction it runs. An opcode that reports other
+// cycles than the table is logged to gbrust::cpu the first time and kept in the list of
+// mismatches, so a run through a game shows every constant that needs fixing at once.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use super::debugger::BankedAddr;
+
+// 0 for opcodes that do not exist, and 0xcb, which is timed by cb_timing
+const TIMINGS: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, // 0x
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1, // 1x
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 2x
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 3x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 4x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 5x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 6x
+    2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1, // 7x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 8x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 9x
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // ax
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // bx
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4, // cx
+    2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4, // dx
+    3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4, // ex
+    3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4, // fx
+];
+
+// Conditional instructions whose condition holds
+fn taken_timing(opcode: u8) -> Option<u8> {
+    match opcode {
+        0x20 | 0x28 | 0x30 | 0x38 => Some(3), // jr cc
+        0xC0 | 0xC8 | 0xD0 | 0xD8 => Some(5), // ret cc
+        0xC2 | 0xCA | 0xD2 | 0xDA => Some(4), // jp cc
+        0xC4 | 0xCC | 0xD4 | 0xDC => Some(6), // call cc
+        _ => None,
+    }
+}
+
+// Including the 0xcb prefix
+fn cb_timing(suffix: u8) -> u8 {
+    match (suffix >> 6, suffix & 0b111) {
+        (0b01, 0b110) => 3, // bit b, (hl)
+        (_, 0b110) => 4,
+        _ => 2,
+    }
+}
+
+// Whether the condition of a conditional instruction holds with flags f
+pub fn condition_holds(opcode: u8, f: u8) -> bool {
+    let (z, c) = (f & 0x80 != 0, f & 0x10 != 0);
+    match (opcode >> 3) & 0b11 {
+        0 => !z,
+        1 => z,
+        2 => !c,
+        _ => c,
+    }
+}
+
+// Cycles the instruction takes, None for opcodes that do not exist. suffix is the byte after a
+// 0xcb prefix, taken whether a conditional instruction's condition held.
+pub fn expected_cycles(opcode: u8, suffix: u8, taken: bool) -> Option<u32> {
+    let cycles = match (opcode, taken_timing(opcode)) {
+        (0xCB, _) => cb_timing(suffix),
+        (_, Some(cycles)) if taken => cycles,
+        _ => TIMINGS[opcode as usize],
+    };
+    if cycles == 0 { None } else { Some(cycles as u32) }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimingMismatch {
+    pub opcode: u16, // 0xcbxx for prefixed instructions
+    pub taken: bool,
+    pub expected: u32,
+    pub reported: u32,
+    pub at: BankedAddr, // where it was first seen
+}
+
+impl fmt::Display for TimingMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = if self.opcode > 0xFF { 4 } else { 2 };
+        write!(f, "opcode 0x{:0width$x}{} at {} takes {} cycles, should be {}", self.opcode,
+               if self.taken { " (taken)" } else { "" }, self.at, self.reported, self.expected,
+               width = width)
+    }
+}
+
+// Mismatches so far, each opcode (and taken or not) once
+#[derive(Debug, Default)]
+pub struct TimingCheck {
+    seen: HashSet<(u16, bool)>,
+    mismatches: Vec<TimingMismatch>,
+}
+
+impl TimingCheck {
+    pub fn new() -> TimingCheck {
+        TimingCheck::default()
+    }
+
+    // f is the flags register as the instruction started
+    pub fn check(&mut self, opcode: u8, suffix: u8, f: u8, reported: u32, at: BankedAddr) {
+        let taken = taken_timing(opcode).is_some() && condition_holds(opcode, f);
+        let expected = match expected_cycles(opcode, suffix, taken) {
+            Some(expected) => expected,
+            None => return,
+        };
+        let opcode = if opcode == 0xCB { 0xCB00 | suffix as u16 } else { opcode as u16 };
+        if expected == reported || !self.seen.insert((opcode, taken)) {
+            return;
+        }
+        let mismatch = TimingMismatch { opcode, taken, expected, reported, at };
+        warn!(target: "gbrust::cpu", "{}", mismatch);
+        self.mismatches.push(mismatch);
+    }
+
+    pub fn mismatches(&self) -> &[TimingMismatch] {
+        &self.mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_and_reports_once() {
+        assert_eq!(expected_cycles(0x00, 0, false), Some(1));
+        assert_eq!(expected_cycles(0x20, 0, false), Some(2));
+        assert_eq!(expected_cycles(0x20, 0, true), Some(3));
+        assert_eq!(expected_cycles(0xCB, 0x46, false), Some(3));
+        assert_eq!(expected_cycles(0xCB, 0x16, false), Some(4));
+        assert_eq!(expected_cycles(0xD3, 0, false), None);
+        assert!(condition_holds(0xC8, 0x80));
+        assert!(!condition_holds(0xD0, 0x10));
+
+        let mut check = TimingCheck::new();
+        let at = BankedAddr::new(0, 0x150);
+        check.check(0x00, 0, 0, 1, at);
+        check.check(0xC2, 0, 0x00, 3, at); // jp nz taken, reported as not
+        check.check(0xC2, 0, 0x00, 3, at);
+        check.check(0xCB, 0x46, 0, 2, at);
+        assert_eq!(check.mismatches().len(), 2);
+        assert_eq!(check.mismatches()[0].to_string(), "opcode 0xc2 (taken) at 00:0150 takes 3 cycles, should be 4");
+        assert_eq!(check.mismatches()[1].opcode, 0xCB46);
+    }
+}
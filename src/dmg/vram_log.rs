@@ -0,0 +1,92 @@
+// VRAM write capture.
+// For tile animation debugging: Console::record_vram_writes runs a frame while every write the
+// CPU makes to 0x8000 - 0x9fff is kept with the PPU clock, the line the PPU was on and the
+// instruction that made it, so artists and ROM hackers can see when a tile got replaced and
+// which code did it. Exported as JSON for scripts.
+
+use std::fmt::Write;
+
+use super::debugger::BankedAddr;
+
+// cycle: PPU clock (cycles since power on). pc: the instruction that wrote, with its ROM bank.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VramWrite {
+    pub cycle: u64,
+    pub ly: u8,
+    pub addr: u16,
+    pub val: u8,
+    pub pc: BankedAddr,
+}
+
+impl VramWrite {
+    // The tile whose data changed, 0 - 383 from 0x8000. None for writes to the tile maps.
+    pub fn tile(&self) -> Option<u16> {
+        if self.addr < 0x9800 {
+            Some((self.addr - 0x8000) / 16)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VramLog {
+    // PPU clock when recording started and stopped
+    pub start: u64,
+    pub end: u64,
+    pub writes: Vec<VramWrite>,
+}
+
+impl VramLog {
+    // Tiles written to during the log, each once, in the order they were first hit
+    pub fn tiles(&self) -> Vec<u16> {
+        let mut tiles = Vec::new();
+        for tile in self.writes.iter().filter_map(VramWrite::tile) {
+            if !tiles.contains(&tile) {
+                tiles.push(tile);
+            }
+        }
+        tiles
+    }
+
+    // Cycles in the JSON are relative to start, addresses in hex. bank is null for code
+    // outside ROM.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write!(json, "{{\"start\":{},\"cycles\":{},\"writes\":[", self.start, self.end - self.start).unwrap();
+        for (i, write) in self.writes.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let bank = write.pc.bank.map_or("null".to_string(), |bank| bank.to_string());
+            write!(json, "{{\"cycle\":{},\"ly\":{},\"addr\":\"{:04x}\",\"val\":\"{:02x}\",\"pc\":\"{:04x}\",\"bank\":{}}}",
+                   write.cycle - self.start, write.ly, write.addr, write.val, write.pc.addr, bank).unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_and_json() {
+        let write = |cycle, addr, pc| VramWrite { cycle, ly: 3, addr, val: 0x7e, pc };
+        let log = VramLog {
+            start: 100,
+            end: 500,
+            writes: vec![
+                write(110, 0x8021, BankedAddr::new(1, 0x4abc)),
+                write(120, 0x9800, BankedAddr::unbanked(0xc000)),
+                write(130, 0x8010, BankedAddr::new(0, 0x0150)),
+                write(140, 0x802f, BankedAddr::new(1, 0x4abc)),
+            ],
+        };
+        assert_eq!(log.tiles(), [2, 1]);
+        assert!(log.to_json().starts_with("{\"start\":100,\"cycles\":400,\"writes\":[{\"cycle\":10,\"ly\":3,\
+                                           \"addr\":\"8021\",\"val\":\"7e\",\"pc\":\"4abc\",\"bank\":1},"));
+        assert!(log.to_json().contains("\"pc\":\"c000\",\"bank\":null}"));
+    }
+}
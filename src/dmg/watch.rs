@@ -0,0 +1,180 @@
+// RAM watch.
+// Users register memory addresses or registers to keep an eye on. Watches are only evaluated
+// once per frame (at vblank), so they cost nothing per instruction; the console then reports
+// which of them changed since the previous frame.
+//
+// Expressions, case-insensitive:
+//     a, f, b, c, d, e, h, l, af, bc, de, hl, sp, pc   a register
+//     0xc0a0 / $c0a0                                    the byte at an address
+//     0xc0a0:16                                         the little-endian word at an address
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::dmg_cpu::{Cpu, RegisterSnapshot};
+use super::error::WatchParseError;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Register {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+
+impl Register {
+    const NAMES: [(&'static str, Register); 14] = [
+        ("a", Register::A), ("f", Register::F), ("b", Register::B), ("c", Register::C),
+        ("d", Register::D), ("e", Register::E), ("h", Register::H), ("l", Register::L),
+        ("af", Register::AF), ("bc", Register::BC), ("de", Register::DE), ("hl", Register::HL),
+        ("sp", Register::SP), ("pc", Register::PC),
+    ];
+
+    pub fn read(self, regs: &RegisterSnapshot) -> u16 {
+        match self {
+            Register::A => regs.a as u16,
+            Register::F => regs.f as u16,
+            Register::B => regs.b as u16,
+            Register::C => regs.c as u16,
+            Register::D => regs.d as u16,
+            Register::E => regs.e as u16,
+            Register::H => regs.h as u16,
+            Register::L => regs.l as u16,
+            Register::AF => regs.af(),
+            Register::BC => regs.bc(),
+            Register::DE => regs.de(),
+            Register::HL => regs.hl(),
+            Register::SP => regs.sp,
+            Register::PC => regs.pc,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        Register::NAMES.iter().find(|(_, reg)| *reg == self).map(|(name, _)| *name).unwrap_or("?")
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchExpr {
+    Register(Register),
+    Byte(u16),
+    Word(u16),
+}
+
+impl WatchExpr {
+    pub fn eval(&self, cpu: &mut Cpu) -> u16 {
+        match *self {
+            WatchExpr::Register(reg) => reg.read(&cpu.registers()),
+            WatchExpr::Byte(addr) => cpu.interconnect.peek(addr) as u16,
+            WatchExpr::Word(addr) => {
+                let lo = cpu.interconnect.peek(addr) as u16;
+                let hi = cpu.interconnect.peek(addr.wrapping_add(1)) as u16;
+                (hi << 8) | lo
+            }
+        }
+    }
+}
+
+impl FromStr for WatchExpr {
+    type Err = WatchParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr = s.trim().to_ascii_lowercase();
+        if let Some(&(_, reg)) = Register::NAMES.iter().find(|(name, _)| *name == expr) {
+            return Ok(WatchExpr::Register(reg));
+        }
+
+        let (addr, word) = match expr.strip_suffix(":16") {
+            Some(addr) => (addr, true),
+            None => (expr.strip_suffix(":8").unwrap_or(&expr), false),
+        };
+        let hex = addr.strip_prefix("0x").or_else(|| addr.strip_prefix('$'))
+            .ok_or_else(|| WatchParseError(s.to_string()))?;
+        let addr = u16::from_str_radix(hex, 16).map_err(|_| WatchParseError(s.to_string()))?;
+
+        Ok(if word { WatchExpr::Word(addr) } else { WatchExpr::Byte(addr) })
+    }
+}
+
+impl fmt::Display for WatchExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchExpr::Register(reg) => write!(f, "{}", reg.name()),
+            WatchExpr::Byte(addr) => write!(f, "0x{:04x}", addr),
+            WatchExpr::Word(addr) => write!(f, "0x{:04x}:16", addr),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WatchHandle(u32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WatchChange {
+    pub handle: WatchHandle,
+    pub expr: WatchExpr,
+    pub old: u16,
+    pub new: u16,
+}
+
+struct Watch {
+    handle: WatchHandle,
+    expr: WatchExpr,
+    value: u16,
+}
+
+#[derive(Default)]
+pub struct WatchList {
+    watches: Vec<Watch>,
+    next_handle: u32,
+    changes: Vec<WatchChange>,
+}
+
+impl WatchList {
+    // Remembers the current value, so the first change reported is relative to now
+    pub fn add(&mut self, expr: WatchExpr, cpu: &mut Cpu) -> WatchHandle {
+        let handle = WatchHandle(self.next_handle);
+        self.next_handle += 1;
+        let value = expr.eval(cpu);
+        self.watches.push(Watch { handle, expr, value });
+        handle
+    }
+
+    pub fn remove(&mut self, handle: WatchHandle) {
+        self.watches.retain(|watch| watch.handle != handle);
+    }
+
+    // Current values, in the order the watches were added
+    pub fn values(&self) -> impl Iterator<Item = (WatchHandle, WatchExpr, u16)> + '_ {
+        self.watches.iter().map(|watch| (watch.handle, watch.expr, watch.value))
+    }
+
+    // Changes found by the last update
+    pub fn changes(&self) -> &[WatchChange] {
+        &self.changes
+    }
+
+    pub fn update(&mut self, cpu: &mut Cpu) {
+        self.changes.clear();
+        for watch in self.watches.iter_mut() {
+            let new = watch.expr.eval(cpu);
+            if new != watch.value {
+                self.changes.push(WatchChange { handle: watch.handle, expr: watch.expr, old: watch.value, new });
+                watch.value = new;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_expressions() {
+        assert_eq!("HL".parse(), Ok(WatchExpr::Register(Register::HL)));
+        assert_eq!("0xC0A0".parse(), Ok(WatchExpr::Byte(0xC0A0)));
+        assert_eq!("$ff80:16".parse(), Ok(WatchExpr::Word(0xFF80)));
+        assert!("c0a0".parse::<WatchExpr>().is_err());
+        assert!("0x10000".parse::<WatchExpr>().is_err());
+        assert_eq!(WatchExpr::Word(0xFF80).to_string(), "0xff80:16");
+    }
+}
@@ -0,0 +1,179 @@
+// WAV export.
+// WavSink is an AudioSink that rips what the APU plays into a 16 bit PCM WAV file, optionally
+// with one mono file per channel next to it ("song.ch1.wav" to "song.ch4.wav") for pulling
+// music apart. The sizes in a WAV header are only known at the end, so they are filled in by
+// finish(), or when the sink is dropped.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::apu::AudioSample;
+use super::console::AudioSink;
+
+const HEADER_SIZE: u32 = 44;
+
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    channels: u16,
+    data_len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        let block_align = channels * 2;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { out, channels, data_len: 0 })
+    }
+
+    // One value per channel, -1.0 to 1.0. Anything louder is clipped.
+    pub fn write_frame(&mut self, values: &[f32]) -> io::Result<()> {
+        debug_assert_eq!(values.len(), self.channels as usize);
+        for &value in values {
+            let pcm = (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_all(&pcm.to_le_bytes())?;
+        }
+        self.data_len += self.channels as u32 * 2;
+        Ok(())
+    }
+
+    // Fills in the header sizes. More frames can still be written afterwards.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(HEADER_SIZE - 8 + self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+pub struct WavSink {
+    mixed: WavWriter<BufWriter<File>>,
+    stems: Vec<WavWriter<BufWriter<File>>>,
+    // The first write error. Writing stops there, and finish() reports it.
+    error: Option<io::Error>,
+    finished: bool,
+}
+
+impl WavSink {
+    // Stereo mix in path. With stems, each channel also goes to its own file next to it.
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, stems: bool) -> io::Result<WavSink> {
+        let path = path.as_ref();
+        let create = |path: &Path, channels| {
+            WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+        };
+
+        let mixed = create(path, 2)?;
+        let mut stem_writers = Vec::new();
+        if stems {
+            let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("audio");
+            for n in 1..= 4 {
+                stem_writers.push(create(&path.with_file_name(format!("{}.ch{}.wav", stem, n)), 1)?);
+            }
+        }
+
+        Ok(WavSink { mixed, stems: stem_writers, error: None, finished: false })
+    }
+
+    #[cfg(not(feature = "fixed-point-audio"))]
+    fn write(&mut self, samples: &[AudioSample]) -> io::Result<()> {
+        for sample in samples {
+            self.mixed.write_frame(&[sample.left, sample.right])?;
+            for (stem, &value) in self.stems.iter_mut().zip(sample.channels.iter()) {
+                stem.write_frame(&[value])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Integer samples scaled back, see apu_fixed.rs
+    #[cfg(feature = "fixed-point-audio")]
+    fn write(&mut self, samples: &[AudioSample]) -> io::Result<()> {
+        for sample in samples {
+            self.mixed.write_frame(&[sample.to_float(sample.left), sample.to_float(sample.right)])?;
+            for (stem, &value) in self.stems.iter_mut().zip(sample.channels.iter()) {
+                stem.write_frame(&[sample.to_float(value)])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_all(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.mixed.finish()?;
+        for stem in self.stems.iter_mut() {
+            stem.finish()?;
+        }
+        Ok(())
+    }
+
+    // Completes the files, reporting any error hit along the way
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.finish_all()
+    }
+}
+
+impl AudioSink for WavSink {
+    fn samples_available(&mut self, samples: &[AudioSample]) {
+        if self.error.is_none() {
+            if let Err(err) = self.write(samples) {
+                warn!(target: "gbrust::wav", "WAV export stopped: {}", err);
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(err) = self.finish_all() {
+                warn!(target: "gbrust::wav", "Could not finish WAV export: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn writes_pcm_header_and_data() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44_100, 2).unwrap();
+        wav.write_frame(&[1.0, -1.0]).unwrap();
+        wav.write_frame(&[0.0, 2.0]).unwrap();
+        wav.finish().unwrap();
+        let bytes = wav.into_inner().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[4..8], &(36u32 + 8).to_le_bytes());
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(&bytes[22..24], &2u16.to_le_bytes());
+        assert_eq!(&bytes[24..28], &44_100u32.to_le_bytes());
+        assert_eq!(&bytes[40..44], &8u32.to_le_bytes());
+        assert_eq!(&bytes[44..46], &i16::MAX.to_le_bytes());
+        assert_eq!(&bytes[50..52], &i16::MAX.to_le_bytes()); // clipped
+    }
+}
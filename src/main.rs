@@ -1,64 +1,381 @@
-#[macro_use]
-extern crate bitflags;
-
+extern crate clap;
+extern crate gbrust;
 extern crate minifb;
+extern crate tracing_subscriber;
 
+use clap::{Args, CommandFactory, Parser, Subcommand};
 use minifb::{Key, WindowOptions, Window};
 
 use std::env;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
 use std::boxed::Box;
-use std::fs::File;
-use std::io::Read;  // {Read, Write}
-use std::{thread, time};
-
-mod dmg;
-pub use dmg::*;
-pub use dmg::mbc::*;
+#[cfg(feature = "server")]
+use std::sync::mpsc;
+use std::{process, thread, time};
 
-use dmg::console::{Console, Button,ButtonState,InputEvent, Cart}; 
+use tracing_subscriber::EnvFilter;
 
-fn load_bin(path: &PathBuf) -> Box<[u8]> {
-    let mut bytes = Vec::new();
-    let mut file = File::open(path).unwrap();
-    file.read_to_end(&mut bytes).unwrap();
-    bytes.into_boxed_slice()
-}
+use gbrust::dmg::console::{Console, Button, ButtonState, Frame, InputEvent, ResetKind, AUTOSAVE_FRAMES_PER_SECOND};
+use gbrust::dmg::config::{AccuracyLevel, EmuConfig, KeyBindings};
+use gbrust::dmg::cart::Cart;
+use gbrust::dmg::compare::{self, Mismatch};
+use gbrust::dmg::compat;
+use gbrust::dmg::debugger::{BankedAddr, BreakOn, StepEnd, DEFAULT_STEP_HISTORY};
+use gbrust::dmg::symbols::Symbols;
+use gbrust::dmg::disasm;
+use gbrust::dmg::dmg_cpu::RegisterSnapshot;
+use gbrust::dmg::movie::Movie;
+use gbrust::dmg::error::CartError;
+use gbrust::dmg::overlay::Overlay;
+use gbrust::dmg::peripheral::{PeripheralScript, ScriptedDevice};
+use gbrust::dmg::wav::WavSink;
+use gbrust::dmg::speed::{Speed, SpeedControl, TimeStretch};
+use gbrust::dmg::gbs::{GbsFile, GbsPlayer};
+use gbrust::dmg::header::CartReport;
+use gbrust::dmg::romdb::RomDb;
+use gbrust::dmg::console::AudioSink;
+#[cfg(feature = "server")]
+use gbrust::dmg::dap::{self, Connect, DapServer, LaunchArgs};
+#[cfg(feature = "server")]
+use gbrust::dmg::rpc::RpcServer;
+#[cfg(feature = "server")]
+use gbrust::dmg::server::Server;
 
 // fn save_bin(path: &PathBuf, bytes: Box<[u8]>) {
 //     let mut file = File::create(path).unwrap();
 //     file.write_all(&bytes).unwrap();
 // }
 
-fn keycode_to_button(keycode: Key) -> Option<Button> {
-    match keycode {
-        Key::Z => Some(Button::A),
-        Key::X => Some(Button::B),
-        Key::Enter => Some(Button::Start),
-        Key::RightShift => Some(Button::Select),
-        Key::Up => Some(Button::Up),
-        Key::Down => Some(Button::Down),
-        Key::Left => Some(Button::Left),
-        Key::Right => Some(Button::Right),
-        _ => None,
+const DEFAULT_CONFIG: &str = "gbrust.toml";
+
+// gbrust <command> [options], see `gbrust help`. Without a command the arguments go to run, so
+// `gbrust rom.gb` plays the ROM as it always has.
+#[derive(Parser)]
+#[command(name = "gbrust", version, about = "A Game Boy emulator")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Play a ROM, or a .gbs music rip, in a window
+    Run(RunArgs),
+    /// Step through a ROM at a prompt, headless
+    Debug(DebugArgs),
+    /// Disassemble part of a ROM
+    Disasm(DisasmArgs),
+    /// Report on a cartridge: its header, checksums and save type
+    Info(InfoArgs),
+    /// Run a test ROM headless and report whether it passed
+    TestRom(TestRomArgs),
+    /// Play a ROM in a window and record the buttons into a movie
+    Record(RecordArgs),
+    /// Play a movie back in a window
+    PlayMovie(PlayMovieArgs),
+    /// Run a ROM twice, headless, and report the first frame where the runs differ
+    Compare(CompareArgs),
+    /// Run a ROM headless, then print the CPU and IO registers
+    DumpState(DumpStateArgs),
+    /// Boot every ROM in a directory headless and report which ones run
+    Compat(CompatArgs),
+    /// Be a Debug Adapter Protocol server on stdin and stdout, for editors to launch
+    Dap(DapArgs),
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Settings file, created with the defaults if missing
+    #[arg(long, default_value = DEFAULT_CONFIG)]
+    config: PathBuf,
+    /// Render on a second thread
+    #[arg(long)]
+    threaded: bool,
+    /// Show the debug overlay
+    #[arg(long)]
+    hud: bool,
+    /// Run slower or faster, 25% - 400%, with the sound keeping its pitch
+    #[arg(long, default_value = "100%")]
+    speed: Speed,
+    /// Record the sound to a WAV file
+    #[arg(long, value_name = "OUT.WAV")]
+    wav: Option<PathBuf>,
+    /// Also write each channel to a WAV file of its own
+    #[arg(long, requires = "wav")]
+    wav_stems: bool,
+    /// Log the sound chip writes to a VGM file
+    #[arg(long, value_name = "OUT.VGM")]
+    vgm: Option<PathBuf>,
+    /// Log interrupts, DMA, bank switches, serial bytes and input, a JSON line per frame
+    #[arg(long, value_name = "OUT.JSONL")]
+    events: Option<PathBuf>,
+    /// Profile the game's functions, written as folded stacks for flame graphs
+    #[arg(long, value_name = "OUT.FOLDED")]
+    profile: Option<PathBuf>,
+    /// Labels to name functions with, rom.sym next to the ROM if there is one
+    #[arg(long)]
+    sym: Option<PathBuf>,
+    /// Run every batch of this many frames twice from a save state, to check it replays the same
+    #[arg(long, value_name = "FRAMES", value_parser = clap::value_parser!(u32).range(1..))]
+    check_states: Option<u32>,
+    /// Check the cycles every instruction takes against PanDocs
+    #[arg(long)]
+    check_timing: bool,
+    /// Apply an IPS or BPS patch to the ROM
+    #[arg(long, value_name = "HACK.IPS|HACK.BPS")]
+    patch: Option<PathBuf>,
+    /// Start from a BESS save state written by another emulator
+    #[arg(long, value_name = "STATE")]
+    import_state: Option<PathBuf>,
+    /// Carry on from where the game was when it last closed, from its autosave
+    #[arg(long, conflicts_with = "import_state")]
+    resume: bool,
+    /// Serve the remote control API on this address (needs the server feature)
+    #[arg(long, value_name = "ADDR")]
+    serve: Option<String>,
+    /// Let web pages from this origin use the remote control API, e.g. http://localhost:3000
+    #[arg(long, value_name = "ORIGIN", requires = "serve")]
+    allow_origin: Vec<String>,
+    /// Serve the JSON-RPC debug protocol on this address (needs the server feature)
+    #[arg(long, value_name = "ADDR")]
+    debug_rpc: Option<String>,
+    /// Serve the Debug Adapter Protocol on this address, for editors to attach to (needs the
+    /// server feature)
+    #[arg(long, value_name = "ADDR")]
+    dap: Option<String>,
+    /// Put a file over a ROM bank, reloaded whenever it changes, as 3=code.bin
+    #[arg(long, value_name = "BANK=FILE", value_parser = parse_bank_map)]
+    map_bank: Vec<(usize, PathBuf)>,
+    /// Plug a scripted device into the link port, see src/dmg/peripheral.rs
+    #[arg(long, value_name = "SCRIPT")]
+    peripheral: Option<PathBuf>,
+    /// Plug in a Barcode Boy that swipes these barcodes, 13 digits a line
+    #[arg(long, value_name = "FILE", conflicts_with = "peripheral")]
+    barcodes: Option<PathBuf>,
+    #[arg(value_name = "ROM.GB|MUSIC.GBS")]
+    rom: PathBuf,
+}
+
+fn parse_bank_map(arg: &str) -> Result<(usize, PathBuf), String> {
+    let (bank, file) = arg.split_once('=').ok_or("expected BANK=FILE")?;
+    let bank = bank.parse().map_err(|_| format!("invalid bank \"{}\"", bank))?;
+    Ok((bank, PathBuf::from(file)))
+}
+
+#[derive(Args)]
+struct DebugArgs {
+    /// Settings file
+    #[arg(long, default_value = DEFAULT_CONFIG)]
+    config: PathBuf,
+    /// Breakpoints to start with, as 12:4abc or 4abc
+    #[arg(long = "break", value_name = "ADDR")]
+    breakpoints: Vec<BankedAddr>,
+    /// Break conditions to start with: irq (any interrupt), irq:48 (one vector), rst:38 or ldbb
+    /// (after an LD B,B)
+    #[arg(long = "break-on", value_name = "CONDITION")]
+    break_conditions: Vec<BreakOn>,
+    /// Labels to name addresses with, rom.sym next to the ROM if there is one
+    #[arg(long)]
+    sym: Option<PathBuf>,
+    rom: PathBuf,
+}
+
+#[derive(Args)]
+struct DisasmArgs {
+    /// Instructions to list
+    #[arg(short = 'n', long, default_value_t = 32)]
+    count: usize,
+    rom: PathBuf,
+    /// Where to start, as 12:4abc for ROM bank 0x12 or 4abc for bank 1
+    #[arg(default_value = "0100")]
+    start: BankedAddr,
+}
+
+#[derive(Args)]
+struct InfoArgs {
+    /// Settings file, for rom_database
+    #[arg(long, default_value = DEFAULT_CONFIG)]
+    config: PathBuf,
+    /// Print JSON instead of text
+    #[arg(long)]
+    json: bool,
+    rom: PathBuf,
+}
+
+#[derive(Args)]
+struct TestRomArgs {
+    /// Give up after this many frames
+    #[arg(long, default_value_t = 60 * 60)]
+    frames: u64,
+    #[arg(long, default_value = "cycle-accurate")]
+    accuracy: AccuracyLevel,
+    rom: PathBuf,
+}
+
+#[derive(Args)]
+struct RecordArgs {
+    /// Settings file
+    #[arg(long, default_value = DEFAULT_CONFIG)]
+    config: PathBuf,
+    rom: PathBuf,
+    /// Where to write the movie
+    movie: PathBuf,
+}
+
+#[derive(Args)]
+struct PlayMovieArgs {
+    /// Settings file, best the one the movie was recorded with
+    #[arg(long, default_value = DEFAULT_CONFIG)]
+    config: PathBuf,
+    rom: PathBuf,
+    movie: PathBuf,
+}
+
+#[derive(Args)]
+struct CompareArgs {
+    /// Buttons to press, see `gbrust record`
+    #[arg(long, value_name = "INPUTS.TXT")]
+    movie: Option<PathBuf>,
+    /// Frames to run, the movie's length or 600 by default
+    #[arg(long)]
+    frames: Option<u64>,
+    /// The accuracy level to run at, and a second one to compare it with
+    #[arg(long, num_args = 1..=2, value_name = "LEVEL")]
+    accuracy: Vec<AccuracyLevel>,
+    /// Write a trace to compare another build against
+    #[arg(long, value_name = "TRACE.TXT")]
+    write: Option<PathBuf>,
+    /// Compare against a trace written with --write
+    #[arg(long, value_name = "TRACE.TXT")]
+    against: Option<PathBuf>,
+    rom: PathBuf,
+}
+
+#[derive(Args)]
+struct DumpStateArgs {
+    /// Buttons to press, see `gbrust record`
+    #[arg(long, value_name = "INPUTS.TXT")]
+    movie: Option<PathBuf>,
+    /// Frames to run, the movie's length or 60 by default
+    #[arg(long)]
+    frames: Option<u64>,
+    /// Print JSON instead of text
+    #[arg(long)]
+    json: bool,
+    /// Also draw the tiles, tile maps and sprites as PNGs into this directory
+    #[arg(long, value_name = "DIR")]
+    gfx: Option<PathBuf>,
+    rom: PathBuf,
+}
+
+#[derive(Args)]
+struct CompatArgs {
+    /// The directory of ROMs, zipped or not
+    #[arg(long, value_name = "DIR")]
+    dir: PathBuf,
+    /// Frames to run each ROM for
+    #[arg(long, default_value_t = 600)]
+    frames: u64,
+    /// Where crash reports for ROMs that fail go, the current directory by default
+    #[arg(long, value_name = "DIR")]
+    crash_dir: Option<PathBuf>,
+    /// Print JSON instead of text
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct DapArgs {
+    /// Settings file
+    #[arg(long, default_value = DEFAULT_CONFIG)]
+    config: PathBuf,
+}
+
+// Puts run in front of arguments that do not start with a command
+fn with_default_command(args: Vec<OsString>) -> Vec<OsString> {
+    let command = Cli::command();
+    let first = args.get(1).and_then(|arg| arg.to_str()).unwrap_or("");
+    let is_command = first == "help" || command.get_subcommands().any(|command| command.get_name() == first);
+    let is_flag = ["-h", "--help", "-V", "--version"].contains(&first);
+    if args.len() < 2 || is_command || is_flag {
+        return args;
+    }
+    let mut args = args;
+    args.insert(1, OsString::from("run"));
+    args
+}
+
+fn exit_with(message: String) -> ! {
+    eprintln!("gbrust: {}", message);
+    process::exit(1);
+}
+
+// Settings persist in gbrust.toml unless --config points elsewhere. With create, a missing file
+// is created with the defaults so there is something to edit.
+fn load_config(path: &Path, create: bool) -> EmuConfig {
+    if path.exists() {
+        return EmuConfig::load(path)
+            .unwrap_or_else(|e| exit_with(format!("could not load {}: {}", path.display(), e)));
+    }
+    let config = EmuConfig::default();
+    if create {
+        if let Err(e) = config.save(path) {
+            eprintln!("gbrust: could not write {}: {}", path.display(), e);
+        }
+    }
+    config
+}
+
+fn load_console(rom_path: &Path, config: EmuConfig) -> Console {
+    Console::builder().config(config).rom_path(rom_path).build()
+        .unwrap_or_else(|e| exit_with(format!("could not load {}: {}", rom_path.display(), e)))
+}
+
+fn load_movie(path: Option<&Path>) -> Movie {
+    match path {
+        Some(path) => Movie::load(path)
+            .unwrap_or_else(|e| exit_with(format!("could not load {}: {}", path.display(), e))),
+        None => Movie::new(),
     }
 }
 
-fn make_events(current: Vec<Key>, prev: Vec<Key>) -> Vec<InputEvent> {
+// Bindings use minifb's key names, e.g. "Z", "Enter", "RightShift"
+fn keycode_to_button(keycode: Key, bindings: &KeyBindings) -> Option<Button> {
+    let name = format!("{:?}", keycode);
+    let buttons = [
+        (&bindings.a, Button::A),
+        (&bindings.b, Button::B),
+        (&bindings.start, Button::Start),
+        (&bindings.select, Button::Select),
+        (&bindings.up, Button::Up),
+        (&bindings.down, Button::Down),
+        (&bindings.left, Button::Left),
+        (&bindings.right, Button::Right),
+    ];
+    buttons.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(&name))
+        .map(|&(_, button)| button)
+}
+
+fn make_events(current: &[Key], prev: &[Key], bindings: &KeyBindings) -> Vec<InputEvent> {
 
-    let released: Vec<_> = prev.clone().into_iter().filter(|x| !current.contains(x)).collect();
-    let pressed: Vec<_> = current.into_iter().filter(|x| !prev.contains(x)).collect();
+    let released: Vec<_> = prev.iter().filter(|x| !current.contains(x)).collect();
+    let pressed: Vec<_> = current.iter().filter(|x| !prev.contains(x)).collect();
 
     let mut events = Vec::new();
 
-    for r in released {
-        if let Some(button) = keycode_to_button(r) {
+    for &r in released {
+        if let Some(button) = keycode_to_button(r, bindings) {
             events.push(InputEvent::new(button, ButtonState::Up))
         }
     }
 
-    for p in pressed {
-        if let Some(button) = keycode_to_button(p) {
+    for &p in pressed {
+        if let Some(button) = keycode_to_button(p, bindings) {
             events.push(InputEvent::new(button, ButtonState::Down))
         }
     }
@@ -79,73 +396,770 @@ impl<'a> VideoSink<'a> {
 }
 
 
-impl<'a> dmg::console::VideoSink for VideoSink<'a> {
-    fn frame_available(&mut self, frame: &Box<[u32]>) {
-        self.window.update_with_buffer(frame, 160, 144).unwrap()
+impl<'a> gbrust::dmg::console::VideoSink for VideoSink<'a> {
+    fn frame_available(&mut self, frame: &Frame) {
+        self.window.update_with_buffer(&frame.packed(), frame.width, frame.height).unwrap()
     }
 }
 
+struct NoVideo;
 
+impl gbrust::dmg::console::VideoSink for NoVideo {
+    fn frame_available(&mut self, _frame: &Frame) {}
+}
 
-fn main() {
-    let rom_path = PathBuf::from(env::args().nth(1).unwrap());
-    let rom_binary = load_bin(&rom_path);
+fn open_window() -> Window {
+    Window::new("gbrust",
+                160,
+                144,
+                WindowOptions { scale: minifb::Scale::X2, ..Default::default() })
+        .unwrap_or_else(|e| panic!("{}", e))
+}
+
+// Calls update every frames_per_update frames' worth of time (16ms a frame) with the keys held
+// now and at the last update, until the window is closed or Escape is pressed
+fn window_loop<F>(window: &mut Window, frames_per_update: usize, update: F)
+    where F: FnMut(&mut Window, &[Key], &[Key])
+{
+    paced_window_loop(window, frames_per_update, &SpeedControl::default(), update)
+}
+
+// window_loop with frames taking longer or shorter as speed says
+fn paced_window_loop<F>(window: &mut Window, frames_per_update: usize, speed: &SpeedControl, mut update: F)
+    where F: FnMut(&mut Window, &[Key], &[Key])
+{
+    let mut prev_keys = Vec::new();
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let update_time = speed.get().frame_time(time::Duration::from_millis(16)) * frames_per_update as u32;
+        let now = time::Instant::now();
+        let keys = window.get_keys().unwrap_or_else(|| prev_keys.clone());
+        update(window, &keys, &prev_keys);
+        prev_keys = keys;
+
+        let elapsed = now.elapsed();
+        if update_time > elapsed {
+            thread::sleep(update_time - elapsed)
+        }
+    }
+}
 
-    let save_ram_path = {
-        let mut path = rom_path.clone();
-        path.set_extension("sav");
-        path
+// Left and right (as bound) skip between songs
+fn play_gbs(path: &Path, config: EmuConfig, audio_sink: Option<Box<dyn AudioSink + Send>>) {
+    let bindings = config.keybindings.clone();
+    let mut player = fs::read(path)
+        .map_err(CartError::from)
+        .and_then(|bytes| GbsFile::parse(&bytes))
+        .and_then(|gbs| GbsPlayer::new(gbs, config))
+        .unwrap_or_else(|e| exit_with(format!("could not load {}: {}", path.display(), e)));
+    player.console_mut().set_audio_sink(audio_sink);
+
+    window_loop(&mut open_window(), 1, |window, keys, prev_keys| {
+        player.run_frame(&mut VideoSink::new(window));
+
+        let pressed = keys.iter().filter(|&key| !prev_keys.contains(key));
+        for button in pressed.filter_map(|&key| keycode_to_button(key, &bindings)) {
+            let changed = match button {
+                Button::Left => player.prev_song(),
+                Button::Right => player.next_song(),
+                _ => Ok(()),
+            };
+            if let Err(e) = changed {
+                eprintln!("gbrust: could not change song: {}", e);
+            }
+        }
+    });
+}
+
+// Remote control over HTTP, see server.rs. Stops with the returned server.
+#[cfg(feature = "server")]
+fn serve(addr: &str, origins: Vec<String>, console: &mut Console) -> Server {
+    let server = Server::bind_allowing(addr, console.handle(), origins)
+        .unwrap_or_else(|e| exit_with(format!("could not serve on {}: {}", addr, e)));
+    println!("Serving on http://{}", server.local_addr());
+    server
+}
+
+#[cfg(not(feature = "server"))]
+fn serve(_addr: &str, _origins: Vec<String>, _console: &mut Console) {
+    eprintln!("gbrust: --serve needs gbrust built with the `server` feature");
+    process::exit(2);
+}
+
+// The debug protocol for editor plugins, see rpc.rs. Stops with the returned server.
+#[cfg(feature = "server")]
+fn serve_debug_rpc(addr: &str, console: &mut Console) -> RpcServer {
+    let server = RpcServer::bind(addr, console.handle())
+        .unwrap_or_else(|e| exit_with(format!("could not serve the debug protocol on {}: {}", addr, e)));
+    println!("Debug protocol on {}", server.local_addr());
+    server
+}
+
+#[cfg(not(feature = "server"))]
+fn serve_debug_rpc(_addr: &str, _console: &mut Console) {
+    eprintln!("gbrust: --debug-rpc needs gbrust built with the `server` feature");
+    process::exit(2);
+}
+
+// The Debug Adapter Protocol for editors to attach to, see dap.rs. Stops with the returned server.
+#[cfg(feature = "server")]
+fn serve_dap(addr: &str, console: &mut Console) -> DapServer {
+    let server = DapServer::bind(addr, console.handle())
+        .unwrap_or_else(|e| exit_with(format!("could not serve DAP on {}: {}", addr, e)));
+    println!("DAP on {}", server.local_addr());
+    server
+}
+
+#[cfg(not(feature = "server"))]
+fn serve_dap(_addr: &str, _console: &mut Console) {
+    eprintln!("gbrust: --dap needs gbrust built with the `server` feature");
+    process::exit(2);
+}
+
+// Labels from sym, or from the .sym next to the ROM if there is one
+fn load_symbols(console: &mut Console, sym: Option<PathBuf>, rom: &Path) {
+    let sym = sym.or_else(|| Some(rom.with_extension("sym")).filter(|path| path.exists()));
+    if let Some(sym) = sym {
+        match Symbols::load(&sym) {
+            Ok(symbols) => console.set_symbols(symbols),
+            Err(e) => eprintln!("gbrust: could not load {}: {}", sym.display(), e),
+        }
+    }
+}
+
+// LD D,D messages from homebrew, see debugger.rs
+fn print_debug_message(at: BankedAddr, message: &str) {
+    println!("{}: {}", at, message);
+}
+
+// gbrust run: the emulator, in a window
+fn run_main(args: RunArgs) {
+    let config = load_config(&args.config, true);
+    let sample_rate = config.audio_sample_rate;
+
+    // The WAV headers are completed when the console, and with it the sink, goes away
+    let wav_stems = args.wav_stems;
+    let audio_sink = args.wav.as_ref().map(|wav_path| {
+        let sink = WavSink::create(wav_path, sample_rate, wav_stems)
+            .unwrap_or_else(|e| exit_with(format!("could not create {}: {}", wav_path.display(), e)));
+        Box::new(sink) as Box<dyn AudioSink + Send>
+    });
+
+    // .gbs music rips get the player instead of the emulator
+    if fs::read(&args.rom).map(|bytes| GbsFile::is_gbs(&bytes)).unwrap_or(false) {
+        play_gbs(&args.rom, config, audio_sink);
+        return;
+    }
+
+    // The sound follows the speed, see speed.rs
+    let speed = SpeedControl::new(args.speed);
+    let audio_sink = audio_sink.map(|sink| Box::new(TimeStretch::new(sink, speed.clone())) as Box<dyn AudioSink + Send>);
+
+    let mut builder = Console::builder()
+        .config(config)
+        .rom_path(&args.rom)
+        .threaded_rendering(args.threaded);
+    if let Some(ref patch_path) = args.patch {
+        builder = builder.patch_path(patch_path);
+    }
+    let mut console = builder
+        .build()
+        .unwrap_or_else(|e| exit_with(format!("could not load {}: {}", args.rom.display(), e)));
+
+    // The game's profile may bring its own keys
+    let bindings = console.config().keybindings.clone();
+
+    // Only the player's own runs autosave, not the headless commands
+    if console.config().autosave {
+        let interval = console.config().autosave_interval as u64 * AUTOSAVE_FRAMES_PER_SECOND;
+        console.set_autosave_path(Some(console.config().autosave_path(&args.rom)));
+        console.set_autosave_interval(interval);
+    }
+
+    if args.hud {
+        console.set_overlay(Some(Overlay::new()));
+    }
+    if let Some(path) = args.import_state {
+        let imported = fs::read(&path).map_err(|e| e.to_string())
+            .and_then(|state| console.import_state(&state).map_err(|e| e.to_string()));
+        if let Err(e) = imported {
+            exit_with(format!("could not import {}: {}", path.display(), e));
+        }
+    }
+    if args.resume {
+        match console.load_autosave() {
+            Ok(true) => {}
+            Ok(false) => eprintln!("gbrust: no autosave to resume from, starting over"),
+            Err(e) => exit_with(format!("could not resume: {}", e)),
+        }
+    }
+    console.set_timing_check(args.check_timing);
+    for (bank, file) in args.map_bank {
+        if let Err(e) = console.map_rom_bank_file(bank, &file) {
+            exit_with(format!("could not map {} over bank {}: {}", file.display(), bank, e));
+        }
+    }
+    let script = match (&args.peripheral, &args.barcodes) {
+        (Some(path), _) => Some((path, PeripheralScript::load(path))),
+        (None, Some(path)) => Some((path, PeripheralScript::load_barcodes(path))),
+        (None, None) => None,
     };
+    if let Some((path, script)) = script {
+        let script = script.unwrap_or_else(|e| exit_with(format!("could not load {}: {}", path.display(), e)));
+        console.attach_serial(Box::new(ScriptedDevice::new(script)));
+    }
+
+    let origins = args.allow_origin;
+    let _server = args.serve.map(|addr| serve(&addr, origins, &mut console));
+    let _debug_rpc = args.debug_rpc.map(|addr| serve_debug_rpc(&addr, &mut console));
+    let _dap = args.dap.map(|addr| serve_dap(&addr, &mut console));
+
+    console.set_audio_sink(audio_sink);
+    if args.vgm.is_some() {
+        console.start_audio_log();
+    }
+    if let Some(ref path) = args.events {
+        let file = fs::File::create(path)
+            .unwrap_or_else(|e| exit_with(format!("could not create {}: {}", path.display(), e)));
+        console.set_event_log(Some(Box::new(io::BufWriter::new(file))));
+    }
+    load_symbols(&mut console, args.sym.clone(), &args.rom);
+    console.set_debug_message_hook(Some(Box::new(print_debug_message)));
+    if args.profile.is_some() {
+        console.start_profiling();
+    }
+
+    println!("{:?}", console.cart());
+
+    // With --check-states, every batch of frames is run twice from a save state to check that
+    // it replays the same
+    let check_frames = args.check_states.map(|frames| frames as usize);
+    let mut window = open_window();
+    window.set_title(&format!("gbrust - {}", speed.get()));
+    paced_window_loop(&mut window, check_frames.unwrap_or(1), &speed, |window, keys, prev_keys| {
+        if let Some(frames) = check_frames {
+            if let Err(e) = console.check_state(frames, &mut VideoSink::new(window)) {
+                let reason = format!("save state check failed before frame {}: {}", console.frame_count(), e);
+                eprintln!("gbrust: {}", reason);
+                // One report is enough, later checks tend to fail the same way
+                if console.crash_bundle().is_none() {
+                    if let Err(e) = console.write_crash_report(&reason) {
+                        eprintln!("gbrust: could not write a crash report: {}", e);
+                    }
+                }
+            }
+        } else {
+            console.run_frame(&mut VideoSink::new(window));
+        }
+
+        // F5 resets, F6 switches off and on again
+        if keys.contains(&Key::F5) && !prev_keys.contains(&Key::F5) {
+            console.reset(ResetKind::Soft);
+        }
+        if keys.contains(&Key::F6) && !prev_keys.contains(&Key::F6) {
+            console.reset(ResetKind::PowerCycle);
+        }
+        // - and = for slower and faster, 0 back to 100%
+        let pressed = |key: Key| keys.contains(&key) && !prev_keys.contains(&key);
+        let new_speed = if pressed(Key::Minus) {
+            Some(speed.get().slower())
+        } else if pressed(Key::Equal) {
+            Some(speed.get().faster())
+        } else if pressed(Key::Key0) {
+            Some(Speed::NORMAL)
+        } else {
+            None
+        };
+        if let Some(new_speed) = new_speed {
+            speed.set(new_speed);
+            window.set_title(&format!("gbrust - {}", new_speed));
+        }
+        make_events(keys, prev_keys, &bindings)
+            .into_iter()
+            .for_each(|e| console.handle_event(e));
+    });
+
+    println!("Program exited!");
+    if let Some(bundle) = console.crash_bundle() {
+        eprintln!("gbrust: the game crashed, please attach {} to the bug report", bundle.display());
+    }
+
+    if let (Some(vgm_path), Some(log)) = (args.vgm, console.stop_audio_log()) {
+        let written = fs::File::create(&vgm_path)
+            .and_then(|file| log.write_vgm(&mut io::BufWriter::new(file)));
+        if let Err(e) = written {
+            eprintln!("gbrust: could not write {}: {}", vgm_path.display(), e);
+        }
+    }
 
-    let ram = if save_ram_path.exists() {
-        Some(load_bin(&save_ram_path))
+    if let (Some(profile_path), Some(profile)) = (args.profile, console.stop_profiling()) {
+        let total = profile.total_cycles().max(1);
+        for (name, cycles) in profile.by_function(console.symbols()).iter().take(10) {
+            println!("{:5.1}% {}", 100.0 * *cycles as f64 / total as f64, name);
+        }
+        if let Err(e) = fs::write(&profile_path, profile.to_folded(console.symbols())) {
+            eprintln!("gbrust: could not write {}: {}", profile_path.display(), e);
+        }
+    }
+
+    if let Err(e) = console.flush_save() {
+        eprintln!("gbrust: could not write the battery save: {}", e);
+    }
+    // After a lock up the last periodic autosave is the one worth going back to
+    if console.fault().is_none() {
+        if let Err(e) = console.write_autosave() {
+            eprintln!("gbrust: could not write the autosave: {}", e);
+        }
+    }
+}
+
+// gbrust record: plays like run, and writes the buttons held on every frame to a movie when the
+// window closes. Resets are left out, since a movie only replays from power on.
+fn record_main(args: RecordArgs) {
+    let mut console = load_console(&args.rom, load_config(&args.config, false));
+    let bindings = console.config().keybindings.clone();
+    let mut movie = Movie::new();
+    let mut frame = 0;
+    window_loop(&mut open_window(), 1, |window, keys, _| {
+        let buttons = keys.iter()
+            .filter_map(|&key| keycode_to_button(key, &bindings))
+            .fold(0, |buttons, button| buttons | button.mask());
+        movie.record(frame, buttons);
+        movie.apply(frame, &mut console);
+        console.run_frame(&mut VideoSink::new(window));
+        frame += 1;
+    });
+    movie.set_seed(console.entropy_seed());
+    if let Err(e) = movie.save(&args.movie) {
+        exit_with(format!("could not write {}: {}", args.movie.display(), e));
+    }
+    println!("recorded {} frames to {}", frame, args.movie.display());
+}
+
+// gbrust play-movie: plays the movie from power on. The keyboard is ignored; once the movie is
+// over the last buttons stay held.
+fn play_movie_main(args: PlayMovieArgs) {
+    let mut console = load_console(&args.rom, load_config(&args.config, false));
+    let movie = load_movie(Some(&args.movie));
+    let mut frame = 0;
+    window_loop(&mut open_window(), 1, |window, _, _| {
+        movie.apply(frame, &mut console);
+        console.run_frame(&mut VideoSink::new(window));
+        frame += 1;
+        if frame == movie.len() {
+            println!("movie over after {} frames", frame);
+        }
+    });
+}
+
+// gbrust compare: runs the ROM and its movie twice, headless, and reports the first frame where
+// the two runs differ (see compare.rs). The two runs are either two accuracy levels in this
+// build (--accuracy a b), or this build against a trace another build wrote with --write.
+// Default settings are used, not gbrust.toml, so both sides run alike. Exits with 1 on a
+// difference.
+fn compare_main(args: CompareArgs) {
+    let movie = load_movie(args.movie.as_deref());
+    let frames = args.frames.unwrap_or_else(|| movie.len().max(600));
+    let rom_path = &args.rom;
+    let console = |accuracy: AccuracyLevel| {
+        Console::builder().rom_path(rom_path).accuracy(accuracy).build()
+            .unwrap_or_else(|e| exit_with(format!("could not load {}: {}", rom_path.display(), e)))
+    };
+    let levels = args.accuracy;
+    let left_level = levels.first().copied().unwrap_or_default();
+
+    if let Some(path) = args.write {
+        let trace = compare::trace(&mut console(left_level), &movie, frames);
+        if let Err(e) = fs::write(&path, compare::trace_to_text(&trace)) {
+            exit_with(format!("could not write {}: {}", path.display(), e));
+        }
+        println!("wrote {} frames to {}", frames, path.display());
+        if args.against.is_none() && levels.len() < 2 {
+            return;
+        }
+    }
+
+    let mismatch: Option<Mismatch> = if let Some(path) = args.against {
+        let trace = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| compare::parse_trace(&text).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| exit_with(format!("could not read {}: {}", path.display(), e)));
+        println!("comparing {} frames against {}", trace.len(), path.display());
+        compare::compare_trace(&mut console(left_level), &movie, &trace)
+    } else if levels.len() == 2 {
+        println!("comparing {} frames, {:?} against {:?}", frames, levels[0], levels[1]);
+        compare::compare_consoles(&mut console(levels[0]), &mut console(levels[1]), &movie, frames)
     } else {
-        None
+        let mut command = Cli::command();
+        command.build();
+        command.find_subcommand_mut("compare").unwrap()
+            .error(clap::error::ErrorKind::MissingRequiredArgument,
+                   "needs --write, --against or two --accuracy levels")
+            .exit()
     };
 
-    let cart = Cart::new(rom_binary, ram);
+    match mismatch {
+        Some(mismatch) => {
+            println!("{}", mismatch);
+            process::exit(1);
+        }
+        None => println!("no differences"),
+    }
+}
+
+fn print_registers(r: &RegisterSnapshot) {
+    println!("pc={:04x} sp={:04x} af={:04x} bc={:04x} de={:04x} hl={:04x} ime={}",
+             r.pc, r.sp, r.af(), r.bc(), r.de(), r.hl(), r.ime as u8);
+}
 
-    println!("{:?}", cart);
+// gbrust dump-state: runs the ROM headless for a number of frames, 60 or the movie's length by
+// default, then prints the CPU registers and every IO register spelled out (see ioregs.rs).
+fn dump_state_main(args: DumpStateArgs) {
+    let movie = load_movie(args.movie.as_deref());
+    let frames = args.frames.unwrap_or_else(|| if movie.is_empty() { 60 } else { movie.len() });
+    let mut console = load_console(&args.rom, EmuConfig::default());
+    compare::trace(&mut console, &movie, frames);
 
-    let mut console = Console::new(cart);
-    
-    let mut window = Window::new("gbrust",
-                                 160,
-                                 144,
-                                 WindowOptions { scale: minifb::Scale::X2, ..Default::default() })
-        .unwrap_or_else(|e| panic!("{}", e));
+    let r = console.registers();
+    let io = console.io_registers();
+    if args.json {
+        println!("{{\"frame\":{},\"cpu\":{{\"pc\":{},\"sp\":{},\"af\":{},\"bc\":{},\"de\":{},\"hl\":{},\"ime\":{}}},\"io\":{}}}",
+                 frames, r.pc, r.sp, r.af(), r.bc(), r.de(), r.hl(), r.ime, io.to_json());
+    } else {
+        println!("after {} frames", frames);
+        print_registers(&r);
+        print!("{}", io.to_text());
+    }
+    if let Some(dir) = args.gfx {
+        save_graphics(&console, &dir);
+    }
+}
 
-    let sleep_time = std::time::Duration::from_millis(16);
+// gbrust compat: boots every ROM in the directory for a number of frames, with no buttons
+// pressed, and reports whether each shows a moving picture (see compat.rs). Default settings
+// are used, not gbrust.toml, so reports from different days compare.
+fn compat_main(args: CompatArgs) {
+    let config = EmuConfig { crash_dir: args.crash_dir, ..EmuConfig::default() };
+    let dir = &args.dir;
+    let results = compat::check_dir(dir, args.frames, &config)
+        .unwrap_or_else(|e| exit_with(format!("could not read {}: {}", dir.display(), e)));
+    if args.json {
+        println!("{}", compat::report_json(&results));
+    } else {
+        print!("{}", compat::report_text(&results));
+    }
+}
 
-    let mut prev_keys = Vec::new();
+fn save_graphics(console: &Console, dir: &Path) {
+    match console.vram_snapshot().save_pngs(dir) {
+        Ok(files) => files.iter().for_each(|file| eprintln!("wrote {}", file.display())),
+        Err(e) => eprintln!("gbrust: could not write graphics to {}: {}", dir.display(), e),
+    }
+}
 
-    while window.is_open() && !window.is_key_down(Key::Escape) {
+// gbrust disasm: lists instructions straight from the ROM file, whatever bank they are in
+fn disasm_main(args: DisasmArgs) {
+    let rom = fs::read(&args.rom).map_err(CartError::from)
+        .and_then(|bytes| Cart::unpack(&bytes, None))
+        .unwrap_or_else(|e| exit_with(format!("could not load {}: {}", args.rom.display(), e)));
+    for instruction in disasm::disassemble_rom(&rom, args.start, args.count) {
+        println!("{}", instruction);
+    }
+}
 
-        let now = std::time::Instant::now();
+// gbrust info: the cartridge report (see header.rs), with the dump's name if the config has a
+// ROM database. Works on carts gbrust cannot run, too.
+fn info_main(args: InfoArgs) {
+    let config = load_config(&args.config, false);
+    let mut report = fs::read(&args.rom).map_err(CartError::from)
+        .and_then(|bytes| Cart::unpack(&bytes, None))
+        .and_then(|rom| CartReport::new(&rom))
+        .unwrap_or_else(|e| exit_with(format!("could not load {}: {}", args.rom.display(), e)));
+    if let Some(path) = config.rom_database {
+        match RomDb::load(&path) {
+            Ok(db) => report.dat_entry = db.lookup(&report.hashes).cloned(),
+            Err(e) => eprintln!("gbrust: could not read {}: {}", path.display(), e),
+        }
+    }
+    if args.json {
+        println!("{}", report.to_json());
+    } else {
+        print!("{}", report.to_text());
+    }
+}
 
-        console.run_for_one_frame(&mut VideoSink::new(&mut window));
-        
-        // for debugging purposes
-        //thread::sleep(time::Duration::from_millis(1000));
+// How a test ROM ended, if it has
+fn test_verdict(console: &mut Console, serial: &str) -> Option<bool> {
+    // Blargg's ROMs print over the serial port
+    if serial.contains("Passed") {
+        return Some(true);
+    }
+    if serial.contains("Failed") {
+        return Some(false);
+    }
+    // Mooneye's load the Fibonacci numbers into the registers when they pass, and 0x42 into all
+    // of them when they fail, then loop
+    let r = console.registers();
+    match (r.b, r.c, r.d, r.e, r.h, r.l) {
+        (3, 5, 8, 13, 21, 34) => Some(true),
+        (0x42, 0x42, 0x42, 0x42, 0x42, 0x42) => Some(false),
+        _ => None,
+    }
+}
 
-        if let Some(keys) = window.get_keys() {
-            make_events(keys.clone(), prev_keys)
-                .into_iter()
-                .for_each(|e| console.handle_event(e));    
-            prev_keys = keys
+// gbrust test-rom: runs until the ROM says whether it passed, printing what it wrote over
+// serial. Exits with 1 when it failed or never said.
+fn test_rom_main(args: TestRomArgs) {
+    let mut console = Console::builder().rom_path(&args.rom).accuracy(args.accuracy).build()
+        .unwrap_or_else(|e| exit_with(format!("could not load {}: {}", args.rom.display(), e)));
+    console.record_serial_output(true);
+    let mut serial = Vec::new();
+    for frame in 0..args.frames {
+        console.run_frame(&mut NoVideo);
+        serial.extend(console.take_serial_output());
+        let text = String::from_utf8_lossy(&serial).into_owned();
+        if let Some(passed) = test_verdict(&mut console, &text) {
+            print!("{}", text);
+            println!("\n{} after {} frames", if passed { "passed" } else { "failed" }, frame + 1);
+            process::exit(if passed { 0 } else { 1 });
         }
-        
+    }
+    print!("{}", String::from_utf8_lossy(&serial));
+    println!("\nno verdict after {} frames", args.frames);
+    process::exit(1);
+}
 
-        let elapsed = now.elapsed();
-        if sleep_time > elapsed {
-            let sleep = sleep_time - elapsed;
-            std::thread::sleep(sleep)
+const DEBUG_HELP: &str = "\
+s [n]          step n instructions (1)
+n              step over a call or rst
+out            run until the current function returns
+back           undo the last step
+f [n]          run n frames (1)
+c              run until a breakpoint, for at most a minute of frames
+b [addr]       break at addr (12:4abc, or 4abc for any bank), or list breakpoints
+b irq[:vec]    break when any interrupt, or the one at vec (40 - 60), is taken
+b rst:n        break when rst n runs
+b ldbb         break after an ld b,b
+d addr|cond    delete a breakpoint or break condition
+bt             the call stack, innermost first
+r              registers
+io             IO registers
+gfx dir        draw the tiles, tile maps and sprites as PNGs into dir
+x addr [len]   dump len bytes (64)
+l [addr] [n]   list n instructions (8) from addr (pc)
+q              quit";
+
+// gbrust debug: a prompt reading commands from stdin, so it can be scripted too
+fn debug_main(args: DebugArgs) {
+    let mut console = load_console(&args.rom, load_config(&args.config, false));
+    for &breakpoint in &args.breakpoints {
+        console.add_breakpoint(breakpoint);
+    }
+    for &condition in &args.break_conditions {
+        console.add_break_condition(condition);
+    }
+    load_symbols(&mut console, args.sym.clone(), &args.rom);
+    console.set_debug_message_hook(Some(Box::new(print_debug_message)));
+    console.track_calls(true);
+    console.record_steps(Some(DEFAULT_STEP_HISTORY));
+    println!("type h for help");
+    let show_pc = |console: &mut Console| {
+        let pc = console.registers().pc;
+        println!("{}", console.disassemble(pc, 1)[0]);
+    };
+    show_pc(&mut console);
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line.unwrap_or_default();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let addr = |index: usize| words.get(index).map(|word| word.parse::<BankedAddr>());
+        let condition = |index: usize| words.get(index).and_then(|word| word.parse::<BreakOn>().ok());
+        let number = |index: usize, default: usize| words.get(index).map_or(Ok(default), |word| word.parse::<usize>());
+        match words.first().copied() {
+            None => continue,
+            Some("s") => match number(1, 1) {
+                Ok(count) => {
+                    for _ in 0..count {
+                        console.advance_instruction(&mut NoVideo);
+                    }
+                    show_pc(&mut console);
+                }
+                Err(e) => println!("{}", e),
+            },
+            Some(command @ ("n" | "out")) => {
+                let end = if command == "n" { console.step_over(&mut NoVideo) } else { console.step_out(&mut NoVideo) };
+                match end {
+                    StepEnd::Done => {}
+                    StepEnd::Breakpoint => println!("breakpoint at {}", console.pc()),
+                    StepEnd::Limit => println!("no return after a minute"),
+                }
+                show_pc(&mut console);
+            }
+            Some("back") => {
+                if console.step_back() {
+                    show_pc(&mut console);
+                } else {
+                    println!("nothing to undo");
+                }
+            }
+            Some("f") => match number(1, 1) {
+                Ok(count) => {
+                    for _ in 0..count {
+                        console.advance_frame(&mut NoVideo);
+                        if console.breakpoint_hit().is_some() {
+                            break;
+                        }
+                    }
+                    show_pc(&mut console);
+                }
+                Err(e) => println!("{}", e),
+            },
+            Some("c") => {
+                for _ in 0..60 * 60 {
+                    console.advance_frame(&mut NoVideo);
+                    if console.breakpoint_hit().is_some() {
+                        break;
+                    }
+                }
+                match console.breakpoint_hit() {
+                    Some(at) => println!("breakpoint at {}", at),
+                    None => println!("no breakpoint after a minute"),
+                }
+                show_pc(&mut console);
+            }
+            Some("b") => match (condition(1), addr(1)) {
+                (Some(condition), _) => console.add_break_condition(condition),
+                (None, Some(Ok(addr))) => console.add_breakpoint(addr),
+                (None, Some(Err(e))) => println!("{}", e),
+                (None, None) => {
+                    console.breakpoints().iter().for_each(|breakpoint| println!("{}", breakpoint));
+                    console.break_conditions().iter().for_each(|condition| println!("{}", condition));
+                }
+            },
+            Some("d") => match (condition(1), addr(1)) {
+                (Some(condition), _) => console.remove_break_condition(condition),
+                (None, Some(Ok(addr))) => console.remove_breakpoint(addr),
+                (None, Some(Err(e))) => println!("{}", e),
+                (None, None) => println!("d needs an address or condition"),
+            },
+            Some("bt") => {
+                for (depth, frame) in console.call_stack().iter().enumerate() {
+                    println!("#{} {}", depth, frame);
+                }
+            }
+            Some("r") => print_registers(&console.registers()),
+            Some("io") => print!("{}", console.io_registers().to_text()),
+            Some("gfx") => match words.get(1) {
+                Some(dir) => save_graphics(&console, Path::new(dir)),
+                None => println!("gfx needs a directory"),
+            },
+            Some("x") => match (addr(1), number(2, 64)) {
+                (Some(Ok(addr)), Ok(len)) => print!("{}", console.hexdump(addr.addr, len)),
+                (Some(Err(e)), _) => println!("{}", e),
+                (_, Err(e)) => println!("{}", e),
+                (None, _) => println!("x needs an address"),
+            },
+            Some("l") => {
+                let start = match addr(1) {
+                    Some(Ok(addr)) => addr.addr,
+                    Some(Err(e)) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                    None => console.registers().pc,
+                };
+                match number(2, 8) {
+                    Ok(count) => console.disassemble(start, count).iter().for_each(|instruction| println!("{}", instruction)),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Some("h") => println!("{}", DEBUG_HELP),
+            Some("q") => break,
+            Some(other) => println!("unknown command \"{}\", type h for help", other),
         }
     }
+}
 
-    println!("Program exited!");
+// gbrust dap: the editor starts this and talks DAP over stdin and stdout, so nothing else may
+// go to stdout. The session runs on a thread of its own; launch hands the ROM to the main
+// thread, which opens the game's window (minifb wants it there) and hands back the console.
+#[cfg(feature = "server")]
+fn dap_main(args: DapArgs) {
+    let (launches, launched) = mpsc::channel();
+    thread::spawn(move || {
+        // The window is only ever opened for the first launch
+        let mut has_launched = false;
+        let connect: Connect = Box::new(move |launch: &LaunchArgs, is_launch| {
+            if !is_launch {
+                return Err("gbrust dap starts the game itself, use launch".to_string());
+            }
+            if has_launched {
+                return Err("already launched".to_string());
+            }
+            let rom = launch.program.clone().ok_or("launch needs program, the ROM to debug")?;
+            has_launched = true;
+            let (reply, console) = mpsc::channel();
+            launches.send((rom, reply)).map_err(|_| "the game did not start".to_string())?;
+            console.recv().map_err(|_| "the game did not start".to_string())?
+        });
+        let stdin = io::stdin();
+        if let Err(e) = dap::serve(stdin.lock(), Box::new(io::stdout()), connect) {
+            eprintln!("gbrust: DAP session failed: {}", e);
+        }
+        // The window loop does not know about the session, so end it all from here
+        process::exit(0);
+    });
+
+    let (rom, reply) = match launched.recv() {
+        Ok(launch) => launch,
+        Err(_) => return,
+    };
+    let mut console = match Console::builder().config(load_config(&args.config, false)).rom_path(&rom).build() {
+        Ok(console) => console,
+        Err(e) => {
+            let _ = reply.send(Err(format!("could not load {}: {}", rom.display(), e)));
+            // The session goes on to tell the editor, and exits
+            thread::park();
+            return;
+        }
+    };
+    load_symbols(&mut console, None, &rom);
+    let bindings = console.config().keybindings.clone();
+    let _ = reply.send(Ok(console.handle()));
+    window_loop(&mut open_window(), 1, |window, keys, prev_keys| {
+        console.run_frame(&mut VideoSink::new(window));
+        make_events(keys, prev_keys, &bindings)
+            .into_iter()
+            .for_each(|e| console.handle_event(e));
+    });
+}
 
-    // if let Some(ram) = console.copy_cart_ram() {
-    //     save_bin(&save_ram_path, ram)
-    // }
+#[cfg(not(feature = "server"))]
+fn dap_main(_args: DapArgs) {
+    eprintln!("gbrust: dap needs gbrust built with the `server` feature");
+    process::exit(2);
+}
+
+fn main() {
+    // RUST_LOG=gbrust::ppu=trace,gbrust::mbc=debug etc. Only warnings by default. To stderr, as
+    // stdout carries the protocol for `gbrust dap`.
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")))
+        .init();
+
+    let cli = Cli::parse_from(with_default_command(env::args_os().collect()));
+    match cli.command {
+        Command::Run(args) => run_main(args),
+        Command::Debug(args) => debug_main(args),
+        Command::Disasm(args) => disasm_main(args),
+        Command::Info(args) => info_main(args),
+        Command::TestRom(args) => test_rom_main(args),
+        Command::Record(args) => record_main(args),
+        Command::PlayMovie(args) => play_movie_main(args),
+        Command::Compare(args) => compare_main(args),
+        Command::DumpState(args) => dump_state_main(args),
+        Command::Compat(args) => compat_main(args),
+        Command::Dap(args) => dap_main(args),
+    }
 }